@@ -0,0 +1,106 @@
+//! How much interning IDs saves a session reading a connection that
+//! repeats the same few IDs: allocations and time per frame, with
+//! `Session::next` copying each ID and with `Session::next_interned`
+//! sharing one `Arc` per ID. Run with `cargo bench --bench intern`.
+
+extern crate sousveillance_server;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use sousveillance_server::server::TokenServer;
+use sousveillance_server::session::IdInterner;
+use sousveillance_server::test_support::frame;
+use sousveillance_server::{Session, Stream};
+
+struct Counting;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+struct Discard;
+
+impl Stream for Discard {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ();
+    fn extract(self) -> Result<(), (Self, ())> {
+        Ok(())
+    }
+}
+
+const FRAMES: usize = 200000;
+const IDS: usize = 16;
+
+/// Reads `capture` through a session, by `next_interned` if `interned`,
+/// returning the allocations and the time taken.
+fn run(capture: &[u8], interned: bool) -> (usize, Duration) {
+    let mut server = TokenServer::<Discard>::new();
+    server.add_token(b"t");
+    server.set_factory(b"t", |_| Discard);
+    let mut session = Session::new(&mut server, capture);
+    let mut interner = IdInterner::new(IDS);
+    ALLOCATIONS.store(0, Ordering::SeqCst);
+    COUNTING.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    let mut consumed = 0;
+    if interned {
+        while let Some(item) = session.next_interned(&mut interner) {
+            consumed += item.is_ok() as usize;
+        }
+    } else {
+        while let Some(item) = session.next() {
+            consumed += item.is_ok() as usize;
+        }
+    }
+    let elapsed = start.elapsed();
+    COUNTING.store(false, Ordering::SeqCst);
+    assert_eq!(FRAMES, consumed);
+    (ALLOCATIONS.load(Ordering::SeqCst), elapsed)
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e9 + d.subsec_nanos() as f64
+}
+
+fn main() {
+    let ids: Vec<_> = (0..IDS).map(|i| format!("camera-{:02}", i).into_bytes()).collect();
+    let capture: Vec<u8> = (0..FRAMES)
+                               .flat_map(|i| frame(b"t", &ids[i % IDS], i as u64 + 1, b"payload"))
+                               .collect();
+    for &(name, interned) in &[("copied", false), ("interned", true)] {
+        let (allocations, elapsed) = run(&capture, interned);
+        println!("{:>8}: {:.3} allocations/frame, {:.0} ns/frame",
+                 name,
+                 allocations as f64 / FRAMES as f64,
+                 nanos(elapsed) / FRAMES as f64);
+    }
+}
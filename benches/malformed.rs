@@ -0,0 +1,110 @@
+//! How fast a session turns away malformed frames, as a health check
+//! hitting the ingest port sends them, and what each costs in allocations:
+//! by default, when no error allocates, and with a capture window, when
+//! every parse error carries a copy of its frame as errors all used to.
+//! Run with `cargo bench --bench malformed`.
+
+extern crate sousveillance_server;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use sousveillance_server::message::Strictness;
+use sousveillance_server::server::TokenServer;
+use sousveillance_server::{Session, Stream};
+
+struct Counting;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+struct Discard;
+
+impl Stream for Discard {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ();
+    fn extract(self) -> Result<(), (Self, ())> {
+        Ok(())
+    }
+}
+
+const FRAMES: usize = 200000;
+
+/// Frames that will not parse, are refused by strictness, or have a
+/// token the server does not know, in turn.
+fn capture() -> Vec<u8> {
+    let malformed: [&[u8]; 3] = [&[0, 3, 0, 5, b'x'],
+                                 &[0, 13, 0, 0, 0, 1, b'i', 0, 0, 0, 0, 0, 0, 0, 1],
+                                 &[0, 14, 0, 1, b'x', 0, 1, b'i', 0, 0, 0, 0, 0, 0, 0, 1]];
+    (0..FRAMES).flat_map(|i| malformed[i % malformed.len()].iter().cloned()).collect()
+}
+
+/// Reads `capture` through a session capturing up to `window` bytes of
+/// each frame that will not parse, returning the allocations and the time
+/// taken once the session is warmed up.
+fn run(capture: &[u8], window: usize) -> (usize, Duration) {
+    let mut server = TokenServer::<Discard>::new();
+    server.add_token(b"t");
+    let mut session = Session::new(&mut server, capture);
+    session.set_strictness(Strictness::Strict);
+    session.set_capture_window(window);
+    // The first frames size the session's buffer.
+    for _ in 0..3 {
+        session.next();
+    }
+    ALLOCATIONS.store(0, Ordering::SeqCst);
+    COUNTING.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    let mut refused = 3;
+    while let Some(item) = session.next() {
+        refused += item.is_err() as usize;
+    }
+    let elapsed = start.elapsed();
+    COUNTING.store(false, Ordering::SeqCst);
+    assert_eq!(FRAMES, refused);
+    (ALLOCATIONS.load(Ordering::SeqCst), elapsed)
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e9 + d.subsec_nanos() as f64
+}
+
+fn main() {
+    let capture = capture();
+    for &(name, window) in &[("captured", 64), ("default", 0)] {
+        let (allocations, elapsed) = run(&capture, window);
+        println!("{:>8}: {:.3} allocations/frame, {:.0} ns/frame",
+                 name,
+                 allocations as f64 / FRAMES as f64,
+                 nanos(elapsed) / FRAMES as f64);
+    }
+}
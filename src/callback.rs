@@ -0,0 +1,26 @@
+//! Whether the callbacks a component keeps have to be `Send`, chosen by a
+//! type parameter so that one component takes either kind.
+//!
+//! A component over `Local`, the default, takes any callback, and so is not
+//! `Send`. One over `Sendable` takes only `Send` callbacks, and is `Send`
+//! whenever its other parameters are; each such component has a `new_send`
+//! constructor besides `new`.
+
+/// Callbacks that need not be `Send`.
+pub enum Local {}
+
+/// Callbacks that have to be `Send`.
+pub enum Sendable {}
+
+/// A callback taking an `A`, as `Local` or `Sendable` keeps it.
+pub trait Calls<A> {
+    type FnMut: ?Sized + FnMut(A);
+}
+
+impl<A> Calls<A> for Local {
+    type FnMut = FnMut(A);
+}
+
+impl<A> Calls<A> for Sendable {
+    type FnMut = FnMut(A) + Send;
+}
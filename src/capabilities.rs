@@ -0,0 +1,90 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use message::Strictness;
+use session::preamble;
+use FORMAT_VERSION;
+
+/// What this build of the crate can do, for deciding at run time which
+/// peers it can serve.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrateCapabilities {
+    /// Whether replay reads gzip-compressed captures.
+    pub gzip: bool,
+    /// The preamble versions a session accepts.
+    pub preamble_versions: &'static [u8],
+    /// The largest frame the two-byte size prefix can cover.
+    pub max_frame_size: usize,
+    /// The strictness levels a session can run at.
+    pub strictness: &'static [Strictness],
+    /// The version of every frame and error message format; see
+    /// `FORMAT_VERSION`.
+    pub format_version: u32,
+}
+
+const PREAMBLE_VERSIONS: [u8; 1] = [preamble::VERSION];
+const STRICTNESS: [Strictness; 3] = [Strictness::Strict, Strictness::Standard, Strictness::Lenient];
+
+/// The capabilities of this build.
+pub fn capabilities() -> CrateCapabilities {
+    CrateCapabilities {
+        gzip: cfg!(feature = "gzip"),
+        preamble_versions: &PREAMBLE_VERSIONS,
+        max_frame_size: u16::max_value() as usize,
+        strictness: &STRICTNESS,
+        format_version: FORMAT_VERSION,
+    }
+}
+
+/// One `key=value` pair per field, space-separated, with lists
+/// comma-separated.
+impl Display for CrateCapabilities {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "gzip={} preamble=", if self.gzip { "yes" } else { "no" }));
+        for (i, version) in self.preamble_versions.iter().enumerate() {
+            try!(write!(f, "{}{}", if i == 0 { "" } else { "," }, version));
+        }
+        try!(write!(f, " max-frame={} strictness=", self.max_frame_size));
+        for (i, strictness) in self.strictness.iter().enumerate() {
+            let name = match *strictness {
+                Strictness::Strict => "strict",
+                Strictness::Standard => "standard",
+                Strictness::Lenient => "lenient",
+            };
+            try!(write!(f, "{}{}", if i == 0 { "" } else { "," }, name));
+        }
+        write!(f, " format={}", self.format_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::Strictness;
+    use FORMAT_VERSION;
+
+    fn protocol() {
+        let caps = capabilities();
+        assert_eq!(&[1][..], caps.preamble_versions);
+        assert_eq!(65535, caps.max_frame_size);
+        assert_eq!(&[Strictness::Strict, Strictness::Standard, Strictness::Lenient][..],
+                   caps.strictness);
+        assert_eq!(FORMAT_VERSION, caps.format_version);
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    #[test]
+    fn default_features() {
+        protocol();
+        assert!(!capabilities().gzip);
+        assert!(capabilities().to_string().starts_with("gzip=no "));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_feature() {
+        protocol();
+        assert!(capabilities().gzip);
+        assert!(capabilities().to_string().starts_with("gzip=yes "));
+    }
+}
@@ -0,0 +1,545 @@
+//! The sending side: a reporter that keeps what it sent until the server
+//! acknowledges it, to resend after a reconnect.
+//!
+//! An ack frame is a two-byte big-endian length, then a status byte, the
+//! report's timestamp in milliseconds and its hash, both big-endian `u64`s.
+//! The status is one of the codes in `status`.
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use callback::{Local, Sendable};
+use message::{Header, Message};
+use server::protection::fingerprint;
+use status;
+use util::millis;
+use Clock;
+
+pub use status::is_retryable;
+
+pub const ACK_OK: u8 = status::OK;
+pub const ACK_LEN: usize = 17;
+
+/// Names a report for its ack: its timestamp and a hash of its ID and
+/// payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ReportKey {
+    pub millis: u64,
+    pub hash: u64,
+}
+
+impl ReportKey {
+    pub fn of(message: &Message) -> Self {
+        ReportKey {
+            millis: millis(message.header.timestamp),
+            hash: fingerprint(&[message.header.id, message.payload]),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ack {
+    pub key: ReportKey,
+    pub status: u8,
+}
+
+impl Ack {
+    /// The frame, length prefix and all.
+    pub fn to_frame(&self) -> [u8; 2 + ACK_LEN] {
+        let mut frame = [0_u8; 2 + ACK_LEN];
+        BigEndian::write_u16(&mut frame, ACK_LEN as u16);
+        frame[2] = self.status;
+        BigEndian::write_u64(&mut frame[3..11], self.key.millis);
+        BigEndian::write_u64(&mut frame[11..], self.key.hash);
+        frame
+    }
+
+    /// Parses an ack without its length prefix.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != ACK_LEN {
+            return None;
+        }
+        Some(Ack {
+            key: ReportKey {
+                millis: BigEndian::read_u64(&bytes[1..9]),
+                hash: BigEndian::read_u64(&bytes[9..]),
+            },
+            status: bytes[0],
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReporterStats {
+    pub sent: u64,
+    pub resent: u64,
+    pub acked: u64,
+    /// Acks for reports already acked.
+    pub duplicate_acks: u64,
+    /// Acks for no report sent, or whose length was wrong.
+    pub unknown_acks: u64,
+    pub permanent_failures: u64,
+    /// Reports moved to the overflow spool for want of room.
+    pub spilled: u64,
+    /// Reports forgotten for want of room, there being no overflow spool.
+    pub dropped: u64,
+}
+
+struct Pending {
+    key: ReportKey,
+    frame: Vec<u8>,
+    sent_at: Duration,
+    /// Failed transiently, so due for resending whatever its age.
+    failed: bool,
+}
+
+/// How many acked keys are remembered to tell duplicate acks by.
+const ACKED_MEMORY: usize = 1024;
+
+/// Sends reports on `W` and reads their acks from `R`, keeping each report
+/// until it is acked or fails for good.
+///
+/// Unacked reports are held in memory up to a byte cap. Past it, the
+/// oldest are forgotten, once written to the overflow spool if there is
+/// one.
+///
+/// Its overflow spool and callback need not be `Send` unless it is over
+/// `Sendable`, as `new_send` makes it.
+pub struct ReliableReporter<W, R, C, K: ReporterCallbacks = Local> {
+    token: Vec<u8>,
+    writer: W,
+    acks: R,
+    clock: C,
+    spool: VecDeque<Pending>,
+    spool_bytes: usize,
+    spool_cap: usize,
+    overflow: Option<Box<K::Overflow>>,
+    on_permanent: Option<Box<K::OnPermanent>>,
+    read: Vec<u8>,
+    acked: HashSet<ReportKey>,
+    acked_order: VecDeque<ReportKey>,
+    stats: ReporterStats,
+}
+
+/// The overflow spool and callback a `ReliableReporter` keeps, as `Local`
+/// or `Sendable` makes them.
+pub trait ReporterCallbacks {
+    type Overflow: ?Sized + Write;
+    type OnPermanent: ?Sized + FnMut(ReportKey, u8);
+}
+
+impl ReporterCallbacks for Local {
+    type Overflow = Write;
+    type OnPermanent = FnMut(ReportKey, u8);
+}
+
+impl ReporterCallbacks for Sendable {
+    type Overflow = Write + Send;
+    type OnPermanent = FnMut(ReportKey, u8) + Send;
+}
+
+impl<W: Write, R: Read, C: Clock> ReliableReporter<W, R, C> {
+    pub fn new(token: &[u8], writer: W, acks: R, clock: C, spool_cap: usize) -> Self {
+        ReliableReporter::without_callbacks(token, writer, acks, clock, spool_cap)
+    }
+
+    /// Where reports go once the in-memory spool is full, as frames.
+    pub fn set_overflow<O: Write + 'static>(&mut self, overflow: O) {
+        self.overflow = Some(Box::new(overflow));
+    }
+
+    /// Called with each report that failed for good, which is then
+    /// dropped.
+    pub fn set_permanent_failure_callback<F>(&mut self, callback: F)
+        where F: FnMut(ReportKey, u8) + 'static
+    {
+        self.on_permanent = Some(Box::new(callback));
+    }
+}
+
+/// The same as for a `Local` reporter, but for a `Send` spool and callback.
+impl<W: Write, R: Read, C: Clock> ReliableReporter<W, R, C, Sendable> {
+    /// A reporter that is `Send` whenever its writer, reader and clock are.
+    pub fn new_send(token: &[u8], writer: W, acks: R, clock: C, spool_cap: usize) -> Self {
+        ReliableReporter::without_callbacks(token, writer, acks, clock, spool_cap)
+    }
+
+    pub fn set_overflow<O: Write + Send + 'static>(&mut self, overflow: O) {
+        self.overflow = Some(Box::new(overflow));
+    }
+
+    pub fn set_permanent_failure_callback<F>(&mut self, callback: F)
+        where F: FnMut(ReportKey, u8) + Send + 'static
+    {
+        self.on_permanent = Some(Box::new(callback));
+    }
+}
+
+impl<W: Write, R: Read, C: Clock, K: ReporterCallbacks> ReliableReporter<W, R, C, K> {
+    fn without_callbacks(token: &[u8], writer: W, acks: R, clock: C, spool_cap: usize) -> Self {
+        ReliableReporter {
+            token: token.to_owned(),
+            writer: writer,
+            acks: acks,
+            clock: clock,
+            spool: VecDeque::new(),
+            spool_bytes: 0,
+            spool_cap: spool_cap,
+            overflow: None,
+            on_permanent: None,
+            read: vec![],
+            acked: HashSet::new(),
+            acked_order: VecDeque::new(),
+            stats: ReporterStats::default(),
+        }
+    }
+
+    /// Sends a report, keeping it until acked.
+    pub fn report(&mut self,
+                  id: &[u8],
+                  timestamp: Duration,
+                  payload: &[u8])
+                  -> io::Result<ReportKey> {
+        let message = Message {
+            header: Header {
+                token: &self.token,
+                id: id,
+                timestamp: timestamp,
+            },
+            payload: payload,
+        };
+        let key = ReportKey::of(&message);
+        let mut frame = vec![0; 2 + message.header.encoded_len() + payload.len()];
+        try!(message.write_frame_into(&mut frame)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+        try!(self.writer.write_all(&frame));
+        self.stats.sent += 1;
+        self.spool_bytes += frame.len();
+        self.spool.push_back(Pending {
+            key: key,
+            frame: frame,
+            sent_at: self.clock.now(),
+            failed: false,
+        });
+        try!(self.spill());
+        Ok(key)
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        while self.spool_bytes > self.spool_cap {
+            let oldest = self.spool.pop_front().unwrap();
+            self.spool_bytes -= oldest.frame.len();
+            match self.overflow {
+                None => self.stats.dropped += 1,
+                Some(ref mut overflow) => {
+                    try!(overflow.write_all(&oldest.frame));
+                    self.stats.spilled += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles every whole ack readable without blocking, returning how
+    /// many. Reading stops at the end of the input or at `WouldBlock`.
+    pub fn poll_acks(&mut self) -> io::Result<usize> {
+        let mut buf = [0_u8; 256];
+        loop {
+            match self.acks.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.read.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut handled = 0;
+        let mut at = 0;
+        while self.read.len() - at >= 2 {
+            let len = BigEndian::read_u16(&self.read[at..]) as usize;
+            if self.read.len() - at - 2 < len {
+                break;
+            }
+            let ack = Ack::parse(&self.read[at + 2..at + 2 + len]);
+            at += 2 + len;
+            handled += 1;
+            match ack {
+                None => self.stats.unknown_acks += 1,
+                Some(ack) => self.handle(ack),
+            }
+        }
+        self.read.drain(..at);
+        Ok(handled)
+    }
+
+    fn handle(&mut self, ack: Ack) {
+        let i = match self.spool.iter().position(|pending| pending.key == ack.key) {
+            Some(i) => i,
+            None if self.acked.contains(&ack.key) => {
+                self.stats.duplicate_acks += 1;
+                return;
+            }
+            None => {
+                self.stats.unknown_acks += 1;
+                return;
+            }
+        };
+        if is_retryable(ack.status) {
+            self.spool[i].failed = true;
+            return;
+        }
+        let pending = self.spool.remove(i).unwrap();
+        self.spool_bytes -= pending.frame.len();
+        if ack.status == ACK_OK {
+            self.stats.acked += 1;
+            self.remember(ack.key);
+        } else {
+            self.stats.permanent_failures += 1;
+            if let Some(ref mut callback) = self.on_permanent {
+                (**callback)(ack.key, ack.status);
+            }
+        }
+    }
+
+    fn remember(&mut self, key: ReportKey) {
+        if self.acked.insert(key) {
+            self.acked_order.push_back(key);
+        }
+        if self.acked_order.len() > ACKED_MEMORY {
+            let oldest = self.acked_order.pop_front().unwrap();
+            self.acked.remove(&oldest);
+        }
+    }
+
+    /// How many reports await their ack.
+    pub fn pending(&self) -> usize {
+        self.spool.len()
+    }
+
+    /// Resends every report sent at least `older_than` ago, and every one
+    /// that failed transiently, in the order first sent. Returns how many.
+    pub fn resend_unacked(&mut self, older_than: Duration) -> io::Result<usize> {
+        let now = self.clock.now();
+        let mut resent = 0;
+        for pending in &mut self.spool {
+            if !pending.failed && pending.sent_at + older_than > now {
+                continue;
+            }
+            try!(self.writer.write_all(&pending.frame));
+            pending.sent_at = now;
+            pending.failed = false;
+            resent += 1;
+        }
+        self.stats.resent += resent as u64;
+        Ok(resent)
+    }
+
+    pub fn stats(&self) -> &ReporterStats {
+        &self.stats
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> (W, R) {
+        (self.writer, self.acks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::io;
+    use std::io::prelude::*;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::*;
+    use clock::ManualClock;
+    use message::Message;
+
+    /// One end of an in-memory pipe; reads find nothing rather than block.
+    #[derive(Clone, Default)]
+    struct Pipe(Rc<RefCell<VecDeque<u8>>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut bytes = self.0.borrow_mut();
+            let n = ::std::cmp::min(buf.len(), bytes.len());
+            for (b, byte) in buf.iter_mut().zip(bytes.drain(..n)) {
+                *b = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Stores each report once, by key, and acks every frame, except
+    /// that it drops the first ack for every fourth payload, fails every
+    /// third once transiently, acks the fifth twice, and always fails
+    /// "bad" for good.
+    #[derive(Default)]
+    struct Scripted {
+        stored: Vec<Vec<u8>>,
+        stored_keys: HashSet<ReportKey>,
+        seen: HashMap<ReportKey, u32>,
+    }
+
+    impl Scripted {
+        fn serve(&mut self, frames: &mut Pipe, acks: &mut Pipe) {
+            let mut bytes = vec![];
+            frames.read_to_end(&mut bytes).unwrap();
+            let mut at = 0;
+            while at < bytes.len() {
+                let len = BigEndian::read_u16(&bytes[at..]) as usize;
+                let msg = Message::parse(&bytes[at + 2..at + 2 + len]).unwrap();
+                at += 2 + len;
+                let key = ReportKey::of(&msg);
+                let attempt = {
+                    let attempts = self.seen.entry(key).or_insert(0);
+                    *attempts += 1;
+                    *attempts
+                };
+                let n = msg.payload[msg.payload.len() - 1] - b'0';
+                let status = if msg.payload == b"bad" {
+                    status::UNAUTHORIZED
+                } else if n % 3 == 0 && attempt == 1 {
+                    status::BUSY
+                } else {
+                    if self.stored_keys.insert(key) {
+                        self.stored.push(msg.payload.to_vec());
+                    }
+                    ACK_OK
+                };
+                if n % 4 == 0 && attempt == 1 && status == ACK_OK {
+                    continue;
+                }
+                let ack = Ack {
+                    key: key,
+                    status: status,
+                };
+                acks.write_all(&ack.to_frame()).unwrap();
+                if n == 5 {
+                    acks.write_all(&ack.to_frame()).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resends_until_every_payload_is_stored_once() {
+        let clock = ManualClock::new(Duration::from_secs(100));
+        let (mut frames, mut acks) = (Pipe::default(), Pipe::default());
+        let mut reporter =
+            ReliableReporter::new(b"t", frames.clone(), acks.clone(), &clock, 1 << 16);
+        let failed = Rc::new(RefCell::new(vec![]));
+        let log = failed.clone();
+        reporter.set_permanent_failure_callback(move |key, code| {
+            log.borrow_mut().push((key, code))
+        });
+
+        let payloads: Vec<_> = (0..10).map(|i| format!("payload {}", i).into_bytes()).collect();
+        for payload in &payloads {
+            reporter.report(b"cam", clock.now(), payload).unwrap();
+        }
+        let bad = reporter.report(b"cam", clock.now(), b"bad").unwrap();
+        let mut server = Scripted::default();
+        // Nothing the client knows of.
+        acks.write_all(&Ack {
+                              key: ReportKey { millis: 1, hash: 2 },
+                              status: ACK_OK,
+                          }
+                          .to_frame())
+            .unwrap();
+
+        for _ in 0..4 {
+            server.serve(&mut frames, &mut acks);
+            reporter.poll_acks().unwrap();
+            clock.advance(Duration::from_secs(10));
+            reporter.resend_unacked(Duration::from_secs(30)).unwrap();
+        }
+        assert_eq!(0, reporter.pending());
+        server.stored.sort();
+        assert_eq!(payloads, server.stored);
+        assert_eq!(vec![(bad, status::UNAUTHORIZED)], *failed.borrow());
+        let stats = reporter.stats().clone();
+        assert_eq!(ReporterStats {
+                       sent: 11,
+                       // Six: the two unacked, first after a transient
+                       // failure, then after 30 seconds without an ack.
+                       resent: 6,
+                       acked: 10,
+                       duplicate_acks: 1,
+                       unknown_acks: 1,
+                       permanent_failures: 1,
+                       spilled: 0,
+                       dropped: 0,
+                   },
+                   stats);
+    }
+
+    #[test]
+    fn acks_split_across_reads() {
+        let clock = ManualClock::new(Duration::from_secs(1));
+        let acks = Pipe::default();
+        let mut reporter = ReliableReporter::new(b"t", vec![], acks.clone(), &clock, 1 << 16);
+        let key = reporter.report(b"cam", clock.now(), b"x").unwrap();
+        let frame = Ack {
+                        key: key,
+                        status: ACK_OK,
+                    }
+                    .to_frame();
+        acks.0.borrow_mut().extend(&frame[..5]);
+        assert_eq!(0, reporter.poll_acks().unwrap());
+        acks.0.borrow_mut().extend(&frame[5..]);
+        assert_eq!(1, reporter.poll_acks().unwrap());
+        assert_eq!(0, reporter.pending());
+    }
+
+    #[test]
+    fn overflows_oldest_to_spool() {
+        let clock = ManualClock::new(Duration::from_secs(1));
+        let overflow = Pipe::default();
+        let mut reporter = ReliableReporter::new(b"t", vec![], Pipe::default(), &clock, 40);
+        reporter.set_overflow(overflow.clone());
+        for payload in &[b"first", b"secon", b"third"] {
+            reporter.report(b"cam", clock.now(), &payload[..]).unwrap();
+        }
+        // Each frame is 23 bytes, so only the newest fits.
+        assert_eq!((1, 2), (reporter.pending(), reporter.stats().spilled));
+        let spilled: Vec<u8> = overflow.0.borrow().iter().cloned().collect();
+        assert_eq!(&reporter.get_ref()[..46], &spilled[..]);
+    }
+    #[test]
+    fn drops_oldest_without_spool() {
+        let clock = ManualClock::new(Duration::from_secs(1));
+        let mut reporter = ReliableReporter::new(b"t", vec![], Pipe::default(), &clock, 40);
+        for payload in &[b"first", b"secon", b"third"] {
+            reporter.report(b"cam", clock.now(), &payload[..]).unwrap();
+        }
+        let stats = reporter.stats();
+        assert_eq!((1, 0, 2), (reporter.pending(), stats.spilled, stats.dropped));
+    }
+}
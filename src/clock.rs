@@ -0,0 +1,103 @@
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time, measured the same way as header
+/// timestamps: as a `Duration` since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+impl<'a, C: Clock + ?Sized> Clock for &'a C {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_millis(0))
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Cell<Duration>,
+}
+
+impl ManualClock {
+    pub fn new(now: Duration) -> Self {
+        ManualClock { now: Cell::new(now) }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// A `ManualClock` that can be shared between threads; its clones all
+/// tell the same time.
+#[derive(Clone, Debug)]
+pub struct SharedClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl SharedClock {
+    pub fn new(now: Duration) -> Self {
+        SharedClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    pub fn set(&self, now: Duration) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use test_support::*;
+
+    quickcheck_test! {
+    manual_advance(start: u32, steps: Vec<u16>; bool) {
+        let clock = ManualClock::new(Duration::from_millis(start as u64));
+        let mut expected = start as u64;
+        steps.into_iter().all(|step| {
+            clock.advance(Duration::from_millis(step as u64));
+            expected += step as u64;
+            clock.now() == Duration::from_millis(expected)
+        })
+    }}
+
+    quickcheck_test! {
+    manual_set(millis: u64; bool) {
+        let clock = ManualClock::new(Duration::from_millis(0));
+        clock.set(Duration::from_millis(millis));
+        (&clock).now() == Duration::from_millis(millis)
+    }}
+}
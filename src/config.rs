@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use message::{Framing, Strictness};
+use server::Reaper;
+use server::reaper::ReapReport;
+use session::{ReadAhead, VectoredRead};
+use stream::{drain_by, DeadlineExtract, DrainReport};
+use util::millis;
+use {Clock, Server};
+
+/// Every knob, before checking that they make sense together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub strictness: Strictness,
+    /// How the session reads each frame's length prefix.
+    pub framing: Framing,
+    /// The largest frame the session reads instead of skipping, if any.
+    pub max_message_size: Option<usize>,
+    /// Capacity of the session's `ReadAhead` buffer, if any.
+    pub read_ahead: Option<usize>,
+    /// How long a stream may go without pushes before it is reaped, if ever.
+    pub idle_timeout: Option<Duration>,
+    /// Deadline for extracting each stream when draining.
+    pub drain_per_stream: Duration,
+    /// Deadline for extracting all streams when draining.
+    pub drain_budget: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            strictness: Strictness::default(),
+            framing: Framing::default(),
+            max_message_size: None,
+            read_ahead: Some(4096),
+            idle_timeout: None,
+            drain_per_stream: Duration::from_secs(1),
+            drain_budget: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A maximum no frame could reach, since `framing` cannot say a size
+    /// over `ceiling`.
+    MaxMessageSizeExceedsFraming {
+        max: usize,
+        ceiling: usize,
+    },
+    ZeroReadAhead,
+    ZeroIdleTimeout,
+    ZeroDrainBudget,
+    DrainPerStreamExceedsBudget {
+        per_stream: Duration,
+        budget: Duration,
+    },
+    /// From a `server::reload::Policy`, for the token given.
+    ZeroStreamCap {
+        token: Vec<u8>,
+    },
+}
+
+impl ConfigError {
+    /// The path of the offending field, or of the first of the fields in
+    /// conflict.
+    pub fn field(&self) -> &'static str {
+        match *self {
+            ConfigError::MaxMessageSizeExceedsFraming { .. } => "max_message_size",
+            ConfigError::ZeroReadAhead => "read_ahead",
+            ConfigError::ZeroIdleTimeout => "idle_timeout",
+            ConfigError::ZeroDrainBudget => "drain_budget",
+            ConfigError::DrainPerStreamExceedsBudget { .. } => "drain_per_stream",
+            ConfigError::ZeroStreamCap { .. } => "stream_cap",
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::MaxMessageSizeExceedsFraming { max, ceiling } => {
+                write!(f, "{}: {} exceeds framing ceiling of {}", self.field(), max, ceiling)
+            }
+            ConfigError::DrainPerStreamExceedsBudget { per_stream, budget } => {
+                write!(f,
+                       "{}: {}ms exceeds drain_budget of {}ms",
+                       self.field(),
+                       millis(per_stream),
+                       millis(budget))
+            }
+            ConfigError::ZeroStreamCap { ref token } => {
+                write!(f,
+                       "{}: {} for token {:?}",
+                       self.field(),
+                       error::Error::description(self),
+                       String::from_utf8_lossy(token))
+            }
+            _ => write!(f, "{}: {}", self.field(), error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::MaxMessageSizeExceedsFraming { .. } => {
+                "maximum message size exceeds what the framing can say"
+            }
+            ConfigError::ZeroReadAhead => "read-ahead buffer must not be empty",
+            ConfigError::ZeroIdleTimeout => "idle timeout would reap every stream",
+            ConfigError::ZeroDrainBudget => "drain budget would skip every stream",
+            ConfigError::DrainPerStreamExceedsBudget { .. } => {
+                "per-stream drain deadline exceeds overall budget"
+            }
+            ConfigError::ZeroStreamCap { .. } => "stream cap would refuse every new stream",
+        }
+    }
+}
+
+/// A `ServerConfig` that has passed `validate`. `Session::configure`
+/// applies its strictness, framing and maximum message size, and the
+/// methods here the rest of its fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidatedConfig(ServerConfig);
+
+impl ValidatedConfig {
+    pub fn get(&self) -> &ServerConfig {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> ServerConfig {
+        self.0
+    }
+
+    /// `reader`, for a session to read, reading ahead by `read_ahead`
+    /// bytes; with `None`, each read goes straight to `reader`.
+    pub fn read_ahead<R: VectoredRead>(&self, reader: R) -> ReadAhead<R> {
+        ReadAhead::with_capacity(reader, self.0.read_ahead.unwrap_or(0))
+    }
+
+    /// Reaps the streams that have been idle for longer than
+    /// `idle_timeout`, or none if it is `None`.
+    pub fn reap<S: Server>(&self, reaper: &mut Reaper<S>, now: Duration) -> ReapReport<S> {
+        match self.0.idle_timeout {
+            Some(timeout) => reaper.reap(timeout, now),
+            None => {
+                ReapReport {
+                    reaped: vec![],
+                    failed: vec![],
+                    unauthorized: vec![],
+                }
+            }
+        }
+    }
+
+    /// Drains `streams` by `drain_per_stream` and `drain_budget`.
+    pub fn drain<S, C>(&self, streams: &mut HashMap<Vec<u8>, S>, clock: &C) -> DrainReport<S>
+        where S: DeadlineExtract,
+              C: Clock
+    {
+        drain_by(streams, clock, self.0.drain_per_stream, self.0.drain_budget)
+    }
+}
+
+impl ServerConfig {
+    /// Checks every invariant, reporting all violations in field order.
+    pub fn validate(self) -> Result<ValidatedConfig, Vec<ConfigError>> {
+        let mut errors = vec![];
+        match self.max_message_size {
+            Some(max) if max > self.framing.max_size() => {
+                errors.push(ConfigError::MaxMessageSizeExceedsFraming {
+                    max: max,
+                    ceiling: self.framing.max_size(),
+                });
+            }
+            _ => {}
+        }
+        if self.read_ahead == Some(0) {
+            errors.push(ConfigError::ZeroReadAhead);
+        }
+        if self.idle_timeout == Some(Duration::from_millis(0)) {
+            errors.push(ConfigError::ZeroIdleTimeout);
+        }
+        if self.drain_per_stream > self.drain_budget {
+            errors.push(ConfigError::DrainPerStreamExceedsBudget {
+                per_stream: self.drain_per_stream,
+                budget: self.drain_budget,
+            });
+        }
+        if self.drain_budget == Duration::from_millis(0) {
+            errors.push(ConfigError::ZeroDrainBudget);
+        }
+        if errors.is_empty() {
+            Ok(ValidatedConfig(self))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::prelude::*;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use server::Reaper;
+    use test_support;
+    use test_support::stream::Slow;
+    use Server;
+
+    fn errors(config: ServerConfig) -> Vec<ConfigError> {
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn default_is_valid() {
+        let config = ServerConfig::default();
+        assert_eq!(&config, ServerConfig::default().validate().unwrap().get());
+    }
+
+    #[test]
+    fn max_message_size_exceeds_framing() {
+        let config = ServerConfig {
+            max_message_size: Some(65536),
+            ..ServerConfig::default()
+        };
+        let errors = errors(config.clone());
+        assert_eq!(vec![ConfigError::MaxMessageSizeExceedsFraming {
+                            max: 65536,
+                            ceiling: 65535,
+                        }],
+                   errors);
+        assert_eq!("max_message_size", errors[0].field());
+        assert_eq!("max_message_size: 65536 exceeds framing ceiling of 65535",
+                   errors[0].to_string());
+        assert!(ServerConfig { max_message_size: Some(65535), ..config.clone() }
+                    .validate()
+                    .is_ok());
+        assert!(ServerConfig { framing: Framing::U32, ..config }.validate().is_ok());
+    }
+
+    #[test]
+    fn zero_read_ahead() {
+        let config = ServerConfig { read_ahead: Some(0), ..ServerConfig::default() };
+        assert_eq!(vec![ConfigError::ZeroReadAhead], errors(config));
+        assert!(ServerConfig { read_ahead: None, ..ServerConfig::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn zero_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(0)),
+            ..ServerConfig::default()
+        };
+        assert_eq!(vec![ConfigError::ZeroIdleTimeout], errors(config));
+    }
+
+    #[test]
+    fn zero_drain_budget() {
+        let config = ServerConfig {
+            drain_per_stream: Duration::from_millis(0),
+            drain_budget: Duration::from_millis(0),
+            ..ServerConfig::default()
+        };
+        assert_eq!(vec![ConfigError::ZeroDrainBudget], errors(config));
+    }
+
+    #[test]
+    fn drain_per_stream_exceeds_budget() {
+        let config = ServerConfig {
+            drain_per_stream: Duration::from_secs(2),
+            drain_budget: Duration::from_secs(1),
+            ..ServerConfig::default()
+        };
+        let errors = errors(config);
+        assert_eq!(vec![ConfigError::DrainPerStreamExceedsBudget {
+                            per_stream: Duration::from_secs(2),
+                            budget: Duration::from_secs(1),
+                        }],
+                   errors);
+        assert_eq!("drain_per_stream", errors[0].field());
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let config = ServerConfig {
+            max_message_size: Some(1 << 16),
+            read_ahead: Some(0),
+            idle_timeout: Some(Duration::from_millis(0)),
+            drain_per_stream: Duration::from_secs(2),
+            drain_budget: Duration::from_secs(1),
+            ..ServerConfig::default()
+        };
+        let fields: Vec<_> = errors(config).iter().map(ConfigError::field).collect();
+        assert_eq!(vec!["max_message_size", "read_ahead", "idle_timeout", "drain_per_stream"],
+                   fields);
+    }
+
+    #[test]
+    fn applies_every_field() {
+        let config = ServerConfig {
+            read_ahead: None,
+            idle_timeout: Some(Duration::from_millis(50)),
+            drain_per_stream: Duration::from_millis(100),
+            drain_budget: Duration::from_millis(150),
+            ..ServerConfig::default()
+        };
+        let config = config.validate().unwrap();
+
+        let mut read = [0; 2];
+        let mut reader = config.read_ahead(&b"abc"[..]);
+        assert_eq!(2, reader.read(&mut read).unwrap());
+        assert_eq!(&b"c"[..], reader.into_inner());
+
+        let finder = vec![(b"idle".to_vec(), test_support::stream::Ok),
+                          (b"busy".to_vec(), test_support::stream::Ok)]
+                         .into_iter()
+                         .collect();
+        let mut reaper = Reaper::new(test_support::server::Ok(finder));
+        reaper.consume_parts(b"t", b"idle", Duration::from_millis(10), b"").unwrap();
+        reaper.consume_parts(b"t", b"busy", Duration::from_millis(70), b"").unwrap();
+        let report = config.reap(&mut reaper, Duration::from_millis(100));
+        let reaped: Vec<_> = report.reaped.iter().map(|&(_, ref id, _)| &id[..]).collect();
+        assert_eq!(vec![&b"idle"[..]], reaped);
+        let never = ServerConfig::default().validate().unwrap();
+        assert!(never.reap(&mut reaper, Duration::from_secs(1000)).reaped.is_empty());
+
+        let clock = ManualClock::new(Duration::from_millis(0));
+        let mut streams: HashMap<_, _> = vec![(b"a".to_vec(), Slow(Duration::from_millis(120))),
+                                              (b"b".to_vec(), Slow(Duration::from_millis(10)))]
+                                             .into_iter()
+                                             .collect();
+        let report = config.drain(&mut streams, &clock);
+        assert_eq!(vec![(b"a".to_vec(), Duration::from_millis(100))], report.timed_out);
+        assert_eq!(1, report.extracted.len());
+    }
+}
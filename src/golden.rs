@@ -0,0 +1,357 @@
+//! Pins every wire-visible format: canonical frames, what the crate
+//! persists, and the `Display` of every error. Frames and persisted bytes
+//! are hashed together, so changing either on purpose means bumping
+//! `FORMAT_VERSION` and recording the new digest in `DIGEST` in the same
+//! change. Error messages are pinned one by one, and a new or reworded one
+//! only needs its expected string updated.
+
+use std::io;
+use std::time::Duration;
+
+use capabilities::CrateCapabilities;
+use config::ConfigError;
+use message::header::Part;
+use message::payload::{SubRecordError, SubRecordWriteError};
+use message::compat::SourceVersion;
+use message::json::{CaptureError, JsonError, LineError};
+use message::scan::ScanError;
+use message::{CompatError, DiagnosticWindow, ExtensionError, FrameError, Header, Message,
+              Nonconformance, Strictness, WriteIntoError};
+use server::{AuthError, AuthTicket, ConsumeError, Dedup, Finder, HighWaterMark, MemberOutcome,
+             NestedGroup, ProtectionError};
+use session::{protocol, PreambleError, ResumeError};
+use simple::SimpleError;
+use stream::encrypting::{DecryptError, EncryptError};
+use stream::{write_envelope, CapacityExceeded, ExtractEnvelope, FileStream, GuardedError,
+             MigrateError, ReadError, ReassemblyError, ReferencingError, SplitError};
+use util::{fnv, FNV_BASIS};
+use {message, session, test_support, Server, Stream, FORMAT_VERSION};
+
+/// `FORMAT_VERSION` and the digest of the frames and persisted outputs at
+/// that version.
+const DIGEST: (u32, u64) = (34, 0x5d00a03e287662c7);
+
+/// Session frames: a two-byte big-endian length, then the message.
+const FRAMES: &'static [(&'static [u8], &'static [u8], &'static [u8], u64, &'static [u8])] = &[
+    (b"\x00\x13\x00\x03tok\x00\x02id\x00\x00\x00\x00\x00\x00\x03\xe8hi",
+     b"tok", b"id", 1000, b"hi"),
+    (b"\x00\x0c\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+     b"", b"", 0, b""),
+    (b"\x00\x0f\x00\x01t\x00\x01i\xff\xff\xff\xff\xff\xff\xff\xff\x00",
+     b"t", b"i", ::std::u64::MAX, b"\x00"),
+];
+
+type SessionError = session::Error<io::Error, io::Error>;
+
+/// What the crate persists, from fixed inputs: a `FileStream`'s data and
+/// index, an envelope of them, and protection state.
+fn persisted() -> Vec<Vec<u8>> {
+    let mut records = FileStream::with_index(vec![], vec![], 1);
+    records.push(Duration::from_millis(1000), b"hi").unwrap();
+    records.push(Duration::from_millis(2000), b"").unwrap();
+    let (data, index) = records.into_inner();
+    let extract = (data, index);
+    let mut envelope = vec![];
+    write_envelope(&mut envelope, b"id", &extract.describe(), &extract).unwrap();
+
+    let finder: Finder<_> = vec![(b"id".to_vec(), test_support::stream::Ok)].into_iter().collect();
+    let mut protected = Dedup::new(HighWaterMark::new(test_support::server::Ok(finder)), 4);
+    protected.consume_parts(b"tok", b"id", Duration::from_millis(1000), b"hi").unwrap();
+    protected.consume_parts(b"tok", b"id", Duration::from_millis(2000), b"").unwrap();
+    let mut protection = vec![];
+    protected.persist_protection_state(&mut protection).unwrap();
+
+    let (data, index) = extract;
+    vec![data, index.unwrap(), envelope, protection]
+}
+
+fn displays() -> Vec<(String, &'static str)> {
+    let header = |part, remaining, offset| {
+        message::Error { remaining: remaining, part: part, offset: offset, diagnostic: None }
+            .to_string()
+    };
+    let consume = |e: ConsumeError<io::Error, io::Error>| e.to_string();
+    let session = |e: SessionError| e.to_string();
+    vec![
+        (header(Part::TokenSize, 1, 0),
+         "missing token size of 2 bytes at offset 0; 1 bytes remaining"),
+        (header(Part::Token(5), 3, 2), "missing token of 5 bytes at offset 2; 3 bytes remaining"),
+        (header(Part::IdSize, 0, 7), "missing Id size of 2 bytes at offset 7; 0 bytes remaining"),
+        (header(Part::Id(4), 2, 9), "missing Id of 4 bytes at offset 9; 2 bytes remaining"),
+        (header(Part::Timestamp, 7, 13),
+         "missing timestamp of 8 bytes at offset 13; 7 bytes remaining"),
+        (message::Error {
+             remaining: 1,
+             part: Part::IdSize,
+             offset: 5,
+             diagnostic: Some(DiagnosticWindow { offset: 2, bytes: b"tok\x00".to_vec() }),
+         }.to_string(),
+         "missing Id size of 2 bytes at offset 5; 1 bytes remaining; \
+          bytes at offset 2: 74 6f 6b 00"),
+        (WriteIntoError::BufferTooSmall { needed: 20 }.to_string(),
+         "buffer too small; 20 bytes needed"),
+        (WriteIntoError::TokenTooLarge(70000).to_string(), "token of 70000 bytes too large"),
+        (WriteIntoError::IdTooLarge(70000).to_string(), "Id of 70000 bytes too large"),
+        (WriteIntoError::MessageTooLarge(70000).to_string(), "message of 70000 bytes too large"),
+        (FrameError::TooLarge(70000).to_string(), "message of 70000 bytes too large to frame"),
+        (WriteIntoError::ExtensionTooLarge { flag: 0x0004, len: 256 }.to_string(),
+         "extension 0x0004 of 256 bytes too large"),
+        (ExtensionError::TruncatedFlags.to_string(), "truncated extension flags"),
+        (ExtensionError::Truncated { flag: 0x0002, offset: 6 }.to_string(),
+         "extension 0x0002 truncated at offset 6"),
+        (ExtensionError::Rejected(0x0300).to_string(), "unknown extension flags 0x0300"),
+        (ExtensionError::AttributesTooLarge { len: 5000, max: 4096 }.to_string(),
+         "attributes of 5000 bytes exceed 4096 bytes"),
+        (ExtensionError::TooManyAttributes { count: 255, max: 32 }.to_string(),
+         "255 attributes exceed 32"),
+        (ExtensionError::AttributeValueTooLarge { offset: 1, len: 2000, max: 1024 }.to_string(),
+         "attribute value of 2000 bytes at offset 1 exceeds 1024 bytes"),
+        (ExtensionError::MalformedAttributes { offset: 5 }.to_string(),
+         "malformed attribute at offset 5"),
+        (ExtensionError::DuplicateAttribute { offset: 5 }.to_string(),
+         "duplicate attribute at offset 5"),
+        (ExtensionError::TrailingGarbage { offset: 13, len: 2 }.to_string(),
+         "2 bytes of trailing garbage at offset 13"),
+        (JsonError::Syntax { offset: 9 }.to_string(), "invalid JSON at byte 9"),
+        (JsonError::UnknownField("extra".to_owned()).to_string(), "unknown field \"extra\""),
+        (JsonError::MissingField("token").to_string(), "missing field \"token\""),
+        (JsonError::DuplicateField("id".to_owned()).to_string(), "duplicate field \"id\""),
+        (JsonError::WrongType("timestamp_ms".to_owned()).to_string(),
+         "field \"timestamp_ms\" of the wrong type"),
+        (JsonError::Base64("payload".to_owned()).to_string(), "field \"payload\" is not base64"),
+        (JsonError::TooLarge(WriteIntoError::TokenTooLarge(70000)).to_string(),
+         "token of 70000 bytes too large"),
+        (CaptureError::Truncated { frame: 2, offset: 40 }.to_string(),
+         "capture ends within frame 2 at offset 40"),
+        (CaptureError::Parse {
+             frame: 0,
+             offset: 0,
+             error: message::Error {
+                 remaining: 1,
+                 part: Part::TokenSize,
+                 offset: 0,
+                 diagnostic: None,
+             },
+         }.to_string(),
+         "frame 0 at offset 0: missing token size of 2 bytes at offset 0; 1 bytes remaining"),
+        (LineError { line: 3, error: JsonError::MissingField("id") }.to_string(),
+         "line 3: missing field \"id\""),
+        (Nonconformance::EmptyToken.to_string(), "empty token"),
+        (Nonconformance::EmptyId.to_string(), "empty Id"),
+        (Nonconformance::ZeroTimestamp.to_string(), "zero timestamp"),
+        (AuthError::<io::Error>::InvalidToken.to_string(), "invalid token"),
+        (consume(ConsumeError::Auth(AuthError::InvalidToken)), "invalid token"),
+        (AuthError::<io::Error>::Pending(AuthTicket(3)).to_string(),
+         "auth pending on ticket 3"),
+        (AuthError::<io::Error>::Throttled.to_string(), "auth throttled"),
+        (consume(ConsumeError::MissingId), "missing ID"),
+        (consume(ConsumeError::Rejected("stale")), "rejected: stale"),
+        (consume(ConsumeError::StreamCapExceeded { cap: 10 }), "stream cap of 10 exceeded"),
+        (consume(ConsumeError::ExpiredPayload {
+             age: Duration::from_millis(2592000001),
+             max_age: Duration::from_secs(2592000),
+         }),
+         "payload 2592000001ms old, past its retention of 2592000000ms"),
+        (consume(ConsumeError::GroupPartialFailure(vec![
+             (b"a".to_vec(), MemberOutcome::Committed),
+             (b"b".to_vec(), MemberOutcome::Failed(io::Error::new(io::ErrorKind::Other, "x"))),
+             (b"c".to_vec(), MemberOutcome::RolledBack),
+         ])),
+         "group delivered to 1 of 3 members"),
+        (NestedGroup(b"all".to_vec()).to_string(), "all would nest groups"),
+        (ProtectionError::UnknownFormat { offset: 0 }.to_string(),
+         "unknown protection state format at offset 0"),
+        (ProtectionError::UnknownVersion { offset: 4, version: 2 }.to_string(),
+         "unknown protection state version 2 at offset 4"),
+        (ProtectionError::Truncated { offset: 31 }.to_string(),
+         "protection state truncated in record at offset 31"),
+        (ProtectionError::Corrupt { offset: 75 }.to_string(),
+         "corrupt protection state record at offset 75"),
+        (CrateCapabilities {
+             gzip: true,
+             preamble_versions: &[1, 2],
+             max_frame_size: 65535,
+             strictness: &[Strictness::Strict, Strictness::Lenient],
+             format_version: 3,
+         }
+         .to_string(),
+         "gzip=yes preamble=1,2 max-frame=65535 strictness=strict,lenient format=3"),
+        (session(session::Error::Read(io::Error::new(io::ErrorKind::Other, "boom"))), "boom"),
+        (session(session::Error::EofInMessageSize), "input ended within a message size"),
+        (session(session::Error::BadVarint), "malformed varint message size"),
+        (session(session::Error::Truncated { found: 3, remaining: 9 }),
+         "3 bytes of message found; 9 bytes remaining"),
+        (session(session::Error::TooLarge { size: 70000, max: 4096 }),
+         "message of 70000 bytes over the maximum of 4096"),
+        (session(session::Error::Parse {
+             error: message::Error {
+                 remaining: 0,
+                 part: Part::TokenSize,
+                 offset: 0,
+                 diagnostic: None,
+             },
+             prefix_len: 2,
+         }),
+         "missing token size of 2 bytes at offset 0; 0 bytes remaining"),
+        (session(session::Error::Nonconforming(Nonconformance::EmptyId)), "empty Id"),
+        (session(session::Error::Consume(ConsumeError::MissingId)), "missing ID"),
+        (session(session::Error::Preamble(PreambleError::Missing)), "missing preamble"),
+        (session(session::Error::ProtocolViolation {
+             state: protocol::State::Initial,
+             received: protocol::Input::Control,
+         }),
+         "received a control frame in the initial state"),
+        (session(session::Error::Sink(io::Error::new(io::ErrorKind::Other, "full"))),
+         "cannot write payload: full"),
+        (session(session::Error::AuthExpired(AuthTicket(3))), "auth ticket 3 expired unresolved"),
+        (session(session::Error::ParkedFull { cap: 1024, total: false }),
+         "parking the frame would exceed 1024 bytes parked for its token"),
+        (session(session::Error::ParkedFull { cap: 1024, total: true }),
+         "parking the frame would exceed 1024 bytes parked in all"),
+        (PreambleError::Truncated.to_string(), "truncated preamble"),
+        (PreambleError::UnknownVersion(9).to_string(), "unknown preamble version 9"),
+        (PreambleError::UnknownFlags(0x80).to_string(), "unknown preamble flags 0x80"),
+        (PreambleError::Disallowed(Strictness::Lenient).to_string(),
+         "peer requested disallowed Lenient strictness"),
+        (ResumeError::Truncated { offset: 7 }.to_string(),
+         "frame at checkpoint offset 7 is truncated"),
+        (ResumeError::ImplausibleSize { offset: 7, size: 3 }.to_string(),
+         "frame at checkpoint offset 7 has implausible size 3"),
+        (ResumeError::Parse {
+             offset: 7,
+             error: message::Error {
+                 remaining: 0,
+                 part: Part::IdSize,
+                 offset: 4,
+                 diagnostic: None,
+             },
+         }.to_string(),
+         "frame at checkpoint offset 7: missing Id size of 2 bytes at offset 4; 0 bytes remaining"),
+        (SubRecordError::TruncatedLength { offset: 4 }.to_string(),
+         "truncated sub-record length at offset 4"),
+        (SubRecordError::TruncatedRecord { offset: 4, len: 3, remaining: 1 }.to_string(),
+         "sub-record of 3 bytes at offset 4; 1 bytes remaining"),
+        (SubRecordWriteError::RecordTooLarge { len: 256, max: 255 }.to_string(),
+         "sub-record of 256 bytes exceeds 255 bytes"),
+        (SubRecordWriteError::BufferFull { needed: 2, remaining: 1 }.to_string(),
+         "buffer full; 2 bytes needed, 1 remaining"),
+        (SplitError::Push { index: 1, error: io::Error::new(io::ErrorKind::Other, "boom") }
+             .to_string(),
+         "sub-record 1: boom"),
+        (GuardedError::Busy::<io::Error> { intents: 2 }.to_string(),
+         "stream busy with 2 push intents"),
+        (CapacityExceeded { capacity: 3 }.to_string(), "stream full at 3 records"),
+        (ReadError::Truncated { offset: 40 }.to_string(), "truncated record at offset 40"),
+        (ReadError::CorruptIndex { entry: 5, offset: 297 }.to_string(),
+         "index entry 5 does not match the record at offset 297"),
+        (MigrateError::Journal.to_string(), "migration journal names no move"),
+        (MigrateError::Changed { id: b"\x0a\xff".to_vec() }.to_string(),
+         "records for ID 0aff changed in the move"),
+        (ReassemblyError::BadAttribute::<io::Error> { key: "offset" }.to_string(),
+         "malformed offset attribute"),
+        (ReassemblyError::OutOfBounds::<io::Error> { offset: 90, len: 20, total: 100 }.to_string(),
+         "chunk of 20 bytes at offset 90 overruns upload of 100 bytes"),
+        (ReassemblyError::LengthMismatch::<io::Error> { total: 100, got: 50 }.to_string(),
+         "upload of 100 bytes given as 50 bytes"),
+        (ReassemblyError::StagingFull::<io::Error> { needed: 5000, max: 4096 }.to_string(),
+         "staging 5000 more bytes would exceed 4096 bytes"),
+        (ReassemblyError::TooManyUploads::<io::Error> { max: 8 }.to_string(),
+         "too many unfinished uploads; at most 8"),
+        (ReferencingError::Store::<io::Error>(io::Error::new(io::ErrorKind::Other, "boom"))
+             .to_string(),
+         "content store: boom"),
+        (CompatError::Unrecognized.to_string(), "unrecognized message format"),
+        (CompatError::Ambiguous(vec![SourceVersion::Framed, SourceVersion::Bare]).to_string(),
+         "ambiguous message format; could be any of [Framed, Bare]"),
+        (CompatError::NoFrame.to_string(), "no message after preamble"),
+        (ScanError { offset: 42, error: io::Error::new(io::ErrorKind::Other, "boom") }.to_string(),
+         "boom at offset 42"),
+        (ConfigError::ZeroReadAhead.to_string(), "read_ahead: read-ahead buffer must not be empty"),
+        (ConfigError::ZeroIdleTimeout.to_string(),
+         "idle_timeout: idle timeout would reap every stream"),
+        (ConfigError::ZeroDrainBudget.to_string(),
+         "drain_budget: drain budget would skip every stream"),
+        (ConfigError::DrainPerStreamExceedsBudget {
+             per_stream: Duration::from_secs(2),
+             budget: Duration::from_secs(1),
+         }.to_string(),
+         "drain_per_stream: 2000ms exceeds drain_budget of 1000ms"),
+        (ConfigError::ZeroStreamCap { token: b"t".to_vec() }.to_string(),
+         "stream_cap: stream cap would refuse every new stream for token \"t\""),
+        (EncryptError::<io::Error, io::Error>::Cipher(io::Error::new(io::ErrorKind::Other, "key"))
+             .to_string(),
+         "cannot seal payload: key"),
+        (DecryptError::<io::Error>::MissingCipherId.to_string(), "missing cipher ID"),
+        (DecryptError::<io::Error>::CipherMismatch { expected: 2, found: 1 }.to_string(),
+         "sealed by cipher 1; expected cipher 2"),
+        (DecryptError::Cipher(io::Error::new(io::ErrorKind::Other, "key")).to_string(),
+         "cannot open payload: key"),
+        (SimpleError::DataDir(io::Error::new(io::ErrorKind::Other, "denied")).to_string(),
+         "cannot create data directory: denied"),
+        (SimpleError::Bind(io::Error::new(io::ErrorKind::Other, "in use")).to_string(),
+         "cannot listen: in use"),
+    ]
+}
+
+#[test]
+fn frames_parse() {
+    for &(frame, token, id, millis, payload) in FRAMES {
+        assert_eq!((frame[0] as usize) << 8 | frame[1] as usize, frame.len() - 2);
+        assert_eq!(Message {
+                       header: Header {
+                           token: token,
+                           id: id,
+                           timestamp: Duration::from_millis(millis),
+                       },
+                       payload: payload,
+                   },
+                   Message::parse(&frame[2..]).unwrap());
+    }
+}
+
+#[test]
+fn display() {
+    for (actual, expected) in displays() {
+        assert_eq!(expected, actual);
+    }
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn replay_display() {
+    let err = session::ReplayError {
+        compressed: 10,
+        uncompressed: 20,
+        error: io::Error::new(io::ErrorKind::UnexpectedEof, "truncated"),
+    };
+    assert_eq!("truncated at compressed offset 10, uncompressed offset 20",
+               err.to_string());
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn compressed_display() {
+    use stream::{CompressedExtractError, CompressError};
+
+    let compress = CompressError::Compress::<io::Error>(io::Error::new(io::ErrorKind::Other,
+                                                                       "boom"));
+    assert_eq!("cannot compress records: boom", compress.to_string());
+    assert_eq!("cannot flush records: cannot compress records: boom",
+               CompressedExtractError::Flush::<_, io::Error>(compress).to_string());
+}
+
+#[test]
+fn digest() {
+    let mut hash = FNV_BASIS;
+    for &(frame, _, _, _, _) in FRAMES {
+        hash = fnv(fnv(hash, frame), b"\n");
+    }
+    for persisted in persisted() {
+        hash = fnv(fnv(hash, &persisted), b"\n");
+    }
+    assert!(DIGEST == (FORMAT_VERSION, hash),
+            "golden outputs changed; if intended, bump FORMAT_VERSION and set DIGEST to \
+             ({}, {:#x})",
+            FORMAT_VERSION + if DIGEST.0 == FORMAT_VERSION { 1 } else { 0 },
+            hash);
+}
@@ -0,0 +1,205 @@
+//! IDs that are cheap to keep. Real IDs are short, so a `SmallId` holds up
+//! to `INLINE` bytes in itself and only goes to the heap for longer ones,
+//! which it then shares.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// The longest ID held without allocating.
+pub const INLINE: usize = 23;
+
+/// An ID, compared, ordered and hashed by its bytes alone, whether inline
+/// or not, so it can stand in for `Vec<u8>` as a map key and be looked up
+/// by `&[u8]`.
+#[derive(Clone)]
+pub struct SmallId(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline(u8, [u8; INLINE]),
+    Spilled(Arc<[u8]>),
+}
+
+impl SmallId {
+    pub fn new(id: &[u8]) -> Self {
+        if id.len() > INLINE {
+            return SmallId(Repr::Spilled(Arc::from(id)));
+        }
+        let mut bytes = [0; INLINE];
+        bytes[..id.len()].copy_from_slice(id);
+        SmallId(Repr::Inline(id.len() as u8, bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.0 {
+            Repr::Inline(len, ref bytes) => &bytes[..len as usize],
+            Repr::Spilled(ref bytes) => bytes,
+        }
+    }
+
+    /// Whether the ID is held without an allocation.
+    pub fn is_inline(&self) -> bool {
+        match self.0 {
+            Repr::Inline(..) => true,
+            Repr::Spilled(_) => false,
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl<'a> From<&'a [u8]> for SmallId {
+    fn from(id: &'a [u8]) -> Self {
+        SmallId::new(id)
+    }
+}
+
+/// Inline if short enough, dropping `id`, and otherwise without copying
+/// it again.
+impl From<Vec<u8>> for SmallId {
+    fn from(id: Vec<u8>) -> Self {
+        if id.len() > INLINE {
+            SmallId(Repr::Spilled(Arc::from(id)))
+        } else {
+            SmallId::new(&id)
+        }
+    }
+}
+
+/// Shares `id` if it is too long to hold inline, so that IDs already
+/// shared elsewhere, as by `session::IdInterner::intern_small`, are not
+/// copied.
+impl From<Arc<[u8]>> for SmallId {
+    fn from(id: Arc<[u8]>) -> Self {
+        if id.len() > INLINE {
+            SmallId(Repr::Spilled(id))
+        } else {
+            SmallId::new(&id)
+        }
+    }
+}
+
+impl From<SmallId> for Vec<u8> {
+    fn from(id: SmallId) -> Self {
+        id.to_vec()
+    }
+}
+
+impl Deref for SmallId {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for SmallId {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Borrow<[u8]> for SmallId {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq for SmallId {
+    fn eq(&self, other: &SmallId) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for SmallId {}
+
+impl PartialEq<[u8]> for SmallId {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl PartialOrd for SmallId {
+    fn partial_cmp(&self, other: &SmallId) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SmallId {
+    fn cmp(&self, other: &SmallId) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Hash for SmallId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl fmt::Debug for SmallId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_bytes().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{BTreeSet, HashMap};
+    use std::hash::{Hash, Hasher};
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn hash<T: Hash + ?Sized>(t: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        t.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn spills_past_inline() {
+        for &(len, inline) in &[(0, true), (22, true), (23, true), (24, false)] {
+            let bytes: Vec<u8> = (0..len as u8).collect();
+            let ids = [SmallId::new(&bytes),
+                       SmallId::from(bytes.clone()),
+                       SmallId::from(Arc::from(&bytes[..]))];
+            for id in &ids {
+                assert_eq!(inline, id.is_inline(), "{} bytes", len);
+                assert_eq!(&bytes[..], id.as_bytes());
+                assert_eq!(bytes, Vec::from(id.clone()));
+                assert_eq!(hash(&bytes[..]), hash(id));
+                assert_eq!(ids[0], *id);
+            }
+        }
+    }
+
+    #[test]
+    fn compares_by_bytes_across_representations() {
+        let long = [b'a'; 24];
+        let ids: Vec<SmallId> = vec![SmallId::new(&long[..23]),
+                                     SmallId::new(&long),
+                                     SmallId::new(&long[..22]),
+                                     SmallId::new(b"b"),
+                                     SmallId::new(&long)];
+        let sorted: Vec<_> = ids.iter().cloned().collect::<BTreeSet<_>>().into_iter().collect();
+        assert_eq!(vec![&long[..22], &long[..23], &long[..], b"b"],
+                   sorted.iter().map(|id| id.as_bytes()).collect::<Vec<_>>());
+        assert!(SmallId::new(&long[..23]) < SmallId::new(&long));
+        assert!(SmallId::new(&long[..23]) != SmallId::new(&long));
+
+        let mut seen = HashMap::new();
+        for id in ids {
+            *seen.entry(id).or_insert(0) += 1;
+        }
+        assert_eq!(Some(&2), seen.get(&long[..]));
+        assert_eq!(Some(&1), seen.get(&b"b"[..]));
+        assert_eq!(None, seen.get(&long[..21]));
+    }
+}
@@ -1,19 +1,45 @@
 extern crate byteorder;
-#[cfg(test)]
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(any(test, feature = "test-support"))]
 extern crate quickcheck;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "test-support"))]
 #[macro_use]
-mod testing;
+pub mod test_support;
 
+#[cfg(test)]
+mod golden;
+
+pub mod callback;
+pub mod capabilities;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod id;
 pub mod message;
+pub mod metrics;
+pub mod pool;
 pub mod server;
 pub mod session;
+pub mod simple;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod status;
 pub mod stream;
+pub mod sweep;
+pub mod trace;
 mod util;
 
+pub use capabilities::{capabilities, CrateCapabilities};
+pub use clock::Clock;
+pub use id::SmallId;
 pub use message::Message;
 pub use session::Session;
 pub use server::Server;
 pub use stream::Stream;
-pub use util::*;
+pub use util::{absurd, Infallible, Void};
+
+/// Bumped whenever frames or persisted formats change on purpose; see the
+/// golden tests.
+pub const FORMAT_VERSION: u32 = 34;
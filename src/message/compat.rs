@@ -0,0 +1,329 @@
+//! Parsing a message in any of the forms this crate has written one:
+//!
+//! - `Bare`: the header, then the payload, which runs to the end of the
+//!   input; what `Message::parse` reads and `Message::canonicalize` writes.
+//! - `Framed`: prefixed with its two-byte big-endian length, as sessions
+//!   read it.
+//! - `Preambled`: framed, after a connection preamble, which starts with
+//!   `session::preamble::MAGIC`.
+//!
+//! Only a preamble has magic. Bare and framed messages are told apart by
+//! which of them parses, header and all, as no first bytes alone can tell.
+//! If both do, the guess is ambiguous, and `CompatPolicy` says whether to
+//! refuse it or take the first candidate in `PRIORITY`.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use session::preamble::{self, MAGIC, PreambleError, Sniffed};
+use super::wire::FrameAt;
+use super::{wire, Message, Strictness};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SourceVersion {
+    Bare,
+    Framed,
+    /// After a preamble of this version.
+    Preambled(u8),
+}
+
+/// The order ambiguous candidates are tried in: framed, as sessions have
+/// always read messages, then bare.
+pub const PRIORITY: [SourceVersion; 2] = [SourceVersion::Framed, SourceVersion::Bare];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VersionGuess {
+    Known(SourceVersion),
+    /// Every form the input parses as, in `PRIORITY` order.
+    Ambiguous(Vec<SourceVersion>),
+    Unknown,
+}
+
+/// What to do with input that parses as more than one form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ambiguity {
+    Reject,
+    /// Takes the first candidate in `PRIORITY`.
+    TryInOrder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompatPolicy {
+    pub ambiguity: Ambiguity,
+    /// What every candidate's header is checked against.
+    pub strictness: Strictness,
+}
+
+impl Default for CompatPolicy {
+    fn default() -> Self {
+        CompatPolicy {
+            ambiguity: Ambiguity::Reject,
+            strictness: Strictness::default(),
+        }
+    }
+}
+
+/// A message, however it was written, and how that was.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionedMessage<'a> {
+    pub message: Message<'a>,
+    pub source_version: SourceVersion,
+}
+
+impl<'a> From<VersionedMessage<'a>> for Message<'a> {
+    fn from(versioned: VersionedMessage<'a>) -> Self {
+        versioned.message
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompatError {
+    /// The input parses as no form.
+    Unrecognized,
+    Ambiguous(Vec<SourceVersion>),
+    Preamble(PreambleError),
+    /// A preamble is followed by no whole frame that parses.
+    NoFrame,
+}
+
+impl Display for CompatError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CompatError::Unrecognized => f.write_str("unrecognized message format"),
+            CompatError::Ambiguous(ref candidates) => {
+                write!(f, "ambiguous message format; could be any of {:?}", candidates)
+            }
+            CompatError::Preamble(ref e) => e.fmt(f),
+            CompatError::NoFrame => f.write_str("no message after preamble"),
+        }
+    }
+}
+
+impl error::Error for CompatError {
+    fn description(&self) -> &str {
+        match *self {
+            CompatError::Unrecognized => "unrecognized message format",
+            CompatError::Ambiguous(_) => "ambiguous message format",
+            CompatError::Preamble(ref e) => e.description(),
+            CompatError::NoFrame => "no message after preamble",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            CompatError::Preamble(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn checked<'a>(bytes: &'a [u8], strictness: Strictness) -> Result<Message<'a>, ()> {
+    Message::parse(bytes)
+        .map_err(|_| ())
+        .and_then(|msg| msg.header.check(strictness).map(|()| msg).map_err(|_| ()))
+}
+
+/// The framed message at the start of `bytes`, and what follows it.
+fn framed<'a>(bytes: &'a [u8], strictness: Strictness) -> Option<(Message<'a>, &'a [u8])> {
+    match wire::frame_at(bytes, 0) {
+        FrameAt::Whole { message, next } => {
+            checked(&bytes[message], strictness).ok().map(|msg| (msg, &bytes[next..]))
+        }
+        _ => None,
+    }
+}
+
+/// The preamble's version and what follows it, if `bytes` start with one.
+fn preambled(bytes: &[u8]) -> Option<Result<(u8, &[u8]), PreambleError>> {
+    if !bytes.starts_with(&MAGIC) {
+        return None;
+    }
+    let mut rest = bytes;
+    // Lenient, so that any strictness the peer asked for is taken.
+    Some(match preamble::sniff::<_, (), ()>(&mut rest, Strictness::Lenient) {
+        Ok(Sniffed::Preamble(peer)) => Ok((peer.version, rest)),
+        Ok(Sniffed::Absent(_)) => unreachable!("starts with the magic"),
+        Err(::session::Error::Preamble(e)) => Err(e),
+        Err(e) => unreachable!("reading a slice cannot fail: {:?}", e),
+    })
+}
+
+/// Which forms the message at the start of `bytes` could take, each
+/// checked by parsing its whole header under `strictness`.
+pub fn detect_version(bytes: &[u8], strictness: Strictness) -> VersionGuess {
+    if let Some(preambled) = preambled(bytes) {
+        return match preambled {
+            Ok((version, _)) => VersionGuess::Known(SourceVersion::Preambled(version)),
+            Err(_) => VersionGuess::Unknown,
+        };
+    }
+    let fits = |version| match version {
+        SourceVersion::Framed => framed(bytes, strictness).is_some(),
+        SourceVersion::Bare => checked(bytes, strictness).is_ok(),
+        SourceVersion::Preambled(_) => false,
+    };
+    let candidates: Vec<_> = PRIORITY.iter().cloned().filter(|&version| fits(version)).collect();
+    match candidates.len() {
+        0 => VersionGuess::Unknown,
+        1 => VersionGuess::Known(candidates[0]),
+        _ => VersionGuess::Ambiguous(candidates),
+    }
+}
+
+/// Parses the message at the start of `bytes`, whatever its form, returning
+/// what follows it: nothing after a bare message, and the next frame after
+/// a framed one. After a preamble, only the first frame is parsed.
+pub fn parse_any<'a>(bytes: &'a [u8],
+                     policy: &CompatPolicy)
+                     -> Result<(VersionedMessage<'a>, &'a [u8]), CompatError> {
+    let source_version = match detect_version(bytes, policy.strictness) {
+        VersionGuess::Known(version) => version,
+        VersionGuess::Ambiguous(candidates) => {
+            match policy.ambiguity {
+                Ambiguity::Reject => return Err(CompatError::Ambiguous(candidates)),
+                Ambiguity::TryInOrder => candidates[0],
+            }
+        }
+        VersionGuess::Unknown => {
+            return Err(match preambled(bytes) {
+                Some(Err(e)) => CompatError::Preamble(e),
+                _ => CompatError::Unrecognized,
+            })
+        }
+    };
+    let (message, rest) = match source_version {
+        SourceVersion::Bare => (checked(bytes, policy.strictness).unwrap(), &bytes[bytes.len()..]),
+        SourceVersion::Framed => framed(bytes, policy.strictness).unwrap(),
+        SourceVersion::Preambled(_) => {
+            let after = preambled(bytes).unwrap().unwrap().1;
+            match framed(after, policy.strictness) {
+                Some(parsed) => parsed,
+                None => return Err(CompatError::NoFrame),
+            }
+        }
+    };
+    Ok((VersionedMessage {
+        message: message,
+        source_version: source_version,
+    },
+        rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use message::{CanonicalOptions, Header, Message, Strictness};
+    use session::preamble::PeerInfo;
+    use test_support::frame;
+
+    fn message<'a>(token: &'a [u8], id: &'a [u8], millis: u64, payload: &'a [u8]) -> Message<'a> {
+        Message {
+            header: Header {
+                token: token,
+                id: id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: payload,
+        }
+    }
+
+    fn bare(msg: &Message) -> Vec<u8> {
+        msg.canonicalize(&CanonicalOptions::default()).unwrap()
+    }
+
+    fn framed(msg: &Message) -> Vec<u8> {
+        let mut bytes = vec![0; 2 + bare(msg).len()];
+        msg.write_frame_into(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn preambled(msg: &Message) -> Vec<u8> {
+        let mut bytes = PeerInfo {
+                            version: preamble::VERSION,
+                            name: b"client/1.0".to_vec(),
+                            strictness: Some(Strictness::Strict),
+                        }
+                        .to_bytes();
+        bytes.extend(framed(msg));
+        bytes
+    }
+
+    fn policy(ambiguity: Ambiguity) -> CompatPolicy {
+        CompatPolicy { ambiguity: ambiguity, ..CompatPolicy::default() }
+    }
+
+    #[test]
+    fn every_form() {
+        let msg = message(b"tok", b"id", 1000, b"payload");
+        let policy = CompatPolicy::default();
+        let next = framed(&message(b"tok", b"id", 2000, b"next"));
+        for &(ref bytes, version) in &[(bare(&msg), SourceVersion::Bare),
+                                       (framed(&msg), SourceVersion::Framed),
+                                       (preambled(&msg), SourceVersion::Preambled(1))] {
+            assert_eq!(VersionGuess::Known(version),
+                       detect_version(bytes, Strictness::Standard));
+            let (parsed, rest) = parse_any(bytes, &policy).unwrap();
+            assert_eq!(msg, parsed.message);
+            assert_eq!(version, parsed.source_version);
+            assert!(rest.is_empty());
+            if version != SourceVersion::Bare {
+                let mut stream = bytes.clone();
+                stream.extend_from_slice(&next);
+                assert_eq!(&next[..], parse_any(&stream, &policy).unwrap().1);
+            }
+        }
+    }
+
+    #[test]
+    fn ambiguous_policies() {
+        // A frame whose body also parses as a bare message's token: the
+        // header of a bare message with token of 23 bytes, the frame's body,
+        // then an empty ID and a timestamp.
+        let inner = frame(b"t", b"id", 5, b"abcdefgh");
+        assert_eq!(23, inner.len() - 2);
+        let mut bytes = inner.clone();
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+        bytes.extend_from_slice(b"tail");
+        let candidates = vec![SourceVersion::Framed, SourceVersion::Bare];
+        assert_eq!(VersionGuess::Ambiguous(candidates.clone()),
+                   detect_version(&bytes, Strictness::Standard));
+
+        assert_eq!(Err(CompatError::Ambiguous(candidates)),
+                   parse_any(&bytes, &policy(Ambiguity::Reject)));
+        let (parsed, rest) = parse_any(&bytes, &policy(Ambiguity::TryInOrder)).unwrap();
+        assert_eq!(SourceVersion::Framed, parsed.source_version);
+        assert_eq!(message(b"t", b"id", 5, b"abcdefgh"), parsed.message);
+        assert_eq!(&bytes[inner.len()..], rest);
+
+        // Checked strictly, the bare reading's empty ID rules it out.
+        let strict = CompatPolicy { strictness: Strictness::Strict, ..policy(Ambiguity::Reject) };
+        assert_eq!(VersionGuess::Known(SourceVersion::Framed),
+                   detect_version(&bytes, Strictness::Strict));
+        assert_eq!(SourceVersion::Framed, parse_any(&bytes, &strict).unwrap().0.source_version);
+    }
+
+    #[test]
+    fn unrecognized() {
+        let policy = CompatPolicy::default();
+        for bytes in &[&b""[..], b"\x00", b"\x00\x05\x00\x01t", b"\x00\x04SV\x07\x00\x00"] {
+            assert_eq!(VersionGuess::Unknown, detect_version(bytes, Strictness::Standard));
+            assert!(parse_any(bytes, &policy).is_err());
+        }
+        assert_eq!(Err(CompatError::Preamble(PreambleError::UnknownVersion(7))),
+                   parse_any(b"\x00\x04SV\x07\x00\x00", &policy));
+        let mut cut = preambled(&message(b"t", b"i", 1, b""));
+        cut.pop();
+        assert_eq!(Err(CompatError::NoFrame), parse_any(&cut, &policy));
+    }
+
+    quickcheck_test! {
+    frames_detect_as_framed(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>; bool) {
+        let msg = message(&token, &id, millis, &payload);
+        let bytes = framed(&msg);
+        detect_version(&bytes, Strictness::Lenient) == VersionGuess::Known(SourceVersion::Framed) &&
+        parse_any(&bytes, &CompatPolicy::default()).map(|(parsed, _)| parsed.message) == Ok(msg)
+    }}
+}
@@ -0,0 +1,73 @@
+//! What a message costs in bytes, measured one way for everything that
+//! counts bytes, so that their numbers reconcile.
+
+use std::time::Duration;
+
+use super::{Header, Message};
+
+/// The bytes of length prefix in front of each message on the wire.
+pub const PREFIX_LEN: u64 = 2;
+
+/// Which of a message's sizes to count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CostField {
+    Payload,
+    Header,
+    Frame,
+}
+
+/// A message's sizes. For every message,
+/// `frame_bytes == header_bytes + payload_bytes + PREFIX_LEN`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MessageCost {
+    pub payload_bytes: u64,
+    pub header_bytes: u64,
+    /// The whole frame as on the wire, length prefix included.
+    pub frame_bytes: u64,
+}
+
+impl MessageCost {
+    pub fn of(msg: &Message) -> Self {
+        MessageCost::of_parts(msg.header.token, msg.header.id, msg.payload)
+    }
+
+    /// The cost of the message with these parts; the timestamp, being of
+    /// fixed size, does not matter.
+    pub fn of_parts(token: &[u8], id: &[u8], payload: &[u8]) -> Self {
+        let header = Header {
+            token: token,
+            id: id,
+            timestamp: Duration::default(),
+        };
+        let header_bytes = header.encoded_len() as u64;
+        MessageCost {
+            payload_bytes: payload.len() as u64,
+            header_bytes: header_bytes,
+            frame_bytes: header_bytes + payload.len() as u64 + PREFIX_LEN,
+        }
+    }
+
+    pub fn get(&self, field: CostField) -> u64 {
+        match field {
+            CostField::Payload => self.payload_bytes,
+            CostField::Header => self.header_bytes,
+            CostField::Frame => self.frame_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::*;
+
+    quickcheck_test! {
+    agrees_with_the_wire(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>; bool) {
+        let bytes = frame(&token, &id, millis, &payload);
+        let cost = MessageCost::of(&Message::parse(&bytes[2..]).unwrap());
+        cost == MessageCost::of_parts(&token, &id, &payload) &&
+        cost.frame_bytes == bytes.len() as u64 &&
+        cost.payload_bytes == payload.len() as u64 &&
+        cost.frame_bytes == cost.header_bytes + cost.payload_bytes + PREFIX_LEN
+    }}
+}
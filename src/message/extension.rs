@@ -0,0 +1,1004 @@
+//! Optional header sections, announced by a big-endian `u16` of flags so
+//! that new ones can be added without breaking readers that predate them.
+//!
+//! The flags come first. Each set bit then has one section of its own,
+//! and sections follow in ascending bit order, so any subset of them
+//! composes:
+//!
+//! | bit                    | section                          |
+//! |------------------------|----------------------------------|
+//! | `CRC_PRESENT`          | `u32` checksum                   |
+//! | `ATTRS_PRESENT`        | `u16` length, then attributes    |
+//! | `CONTENT_TYPE_PRESENT` | `u8` length, then content type   |
+//! | `PRIORITY_PRESENT`     | `u8` priority                    |
+//! | `PADDING_PRESENT`      | `u16` length, then that much pad |
+//! | `PAYLOAD_LEN_PRESENT`  | `u32` length of the payload      |
+//! | any other              | `u16` length, then its contents  |
+//!
+//! The payload is whatever follows the sections, or with an explicit
+//! length just that much of it; what comes after that is trailing garbage.
+//!
+//! Bits this crate does not know must, by convention, be length-prefixed
+//! like the last row, so that an `ExtensionRegistry` can skip them.
+//!
+//! The attributes section is a `u8` count, then for each attribute a `u8`
+//! key length, the key, a big-endian `u16` value length and the value.
+//! Keys are unique.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::usize;
+
+use super::{Strictness, WriteIntoError};
+
+pub const CRC_PRESENT: u16 = 0x0001;
+pub const ATTRS_PRESENT: u16 = 0x0002;
+pub const CONTENT_TYPE_PRESENT: u16 = 0x0004;
+pub const PRIORITY_PRESENT: u16 = 0x0008;
+pub const PADDING_PRESENT: u16 = 0x0010;
+pub const PAYLOAD_LEN_PRESENT: u16 = 0x0020;
+
+/// Every bit this crate has a section for.
+pub const KNOWN: u16 = CRC_PRESENT | ATTRS_PRESENT | CONTENT_TYPE_PRESENT | PRIORITY_PRESENT |
+                       PADDING_PRESENT | PAYLOAD_LEN_PRESENT;
+
+/// What to do with a set bit this crate does not know.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unknown {
+    Reject,
+    /// Skips its section by its length prefix.
+    Skip,
+}
+
+/// How to treat each unknown flag bit, and trailing garbage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtensionRegistry {
+    skip: u16,
+    reject_trailing: bool,
+}
+
+impl ExtensionRegistry {
+    /// Rejects every unknown bit, and drops trailing garbage.
+    pub fn new() -> Self {
+        ExtensionRegistry {
+            skip: 0,
+            reject_trailing: false,
+        }
+    }
+
+    /// Rejects every unknown bit and trailing garbage under
+    /// `Strictness::Strict`, and otherwise skips and drops them.
+    pub fn for_strictness(strictness: Strictness) -> Self {
+        match strictness {
+            Strictness::Strict => {
+                ExtensionRegistry {
+                    skip: 0,
+                    reject_trailing: true,
+                }
+            }
+            Strictness::Standard | Strictness::Lenient => {
+                ExtensionRegistry {
+                    skip: !KNOWN,
+                    reject_trailing: false,
+                }
+            }
+        }
+    }
+
+    /// Whether bytes past an explicit payload length refuse the message
+    /// rather than being dropped.
+    pub fn set_reject_trailing(&mut self, reject: bool) {
+        self.reject_trailing = reject;
+    }
+
+    pub fn rejects_trailing(&self) -> bool {
+        self.reject_trailing
+    }
+
+    /// Panics unless `bit` is a single bit outside `KNOWN`.
+    pub fn set(&mut self, bit: u16, policy: Unknown) {
+        assert!(bit.count_ones() == 1 && bit & KNOWN == 0,
+                "{:#06x} is not a single unknown bit",
+                bit);
+        match policy {
+            Unknown::Reject => self.skip &= !bit,
+            Unknown::Skip => self.skip |= bit,
+        }
+    }
+
+    /// What becomes of `bit`, a single bit outside `KNOWN`.
+    pub fn policy(&self, bit: u16) -> Unknown {
+        if self.skip & bit == bit {
+            Unknown::Skip
+        } else {
+            Unknown::Reject
+        }
+    }
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        ExtensionRegistry::new()
+    }
+}
+
+/// Malformed extensions, at `offset` bytes into them.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExtensionError {
+    /// Fewer than two bytes remain for the flags.
+    TruncatedFlags,
+    /// The section for `flag` runs past the end of the input.
+    Truncated {
+        flag: u16,
+        offset: usize,
+    },
+    /// These unknown bits were set, and the registry rejects them.
+    Rejected(u16),
+    /// The attributes section, of `len` bytes, is larger than allowed.
+    AttributesTooLarge {
+        len: usize,
+        max: usize,
+    },
+    TooManyAttributes {
+        count: usize,
+        max: usize,
+    },
+    /// The value at `offset` into the attributes section is too long.
+    AttributeValueTooLarge {
+        offset: usize,
+        len: usize,
+        max: usize,
+    },
+    /// The attribute at `offset` into the attributes section runs past its
+    /// end, or bytes follow the last attribute.
+    MalformedAttributes {
+        offset: usize,
+    },
+    /// The attribute at `offset` has the key of one before it.
+    DuplicateAttribute {
+        offset: usize,
+    },
+    /// `len` bytes at `offset` follow the explicit payload length, and the
+    /// registry rejects them.
+    TrailingGarbage {
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl ExtensionError {
+    /// Always `InvalidData`.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        io::ErrorKind::InvalidData
+    }
+
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(self.io_kind(), self)
+    }
+}
+
+impl Display for ExtensionError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ExtensionError::TruncatedFlags => f.write_str("truncated extension flags"),
+            ExtensionError::Truncated { flag, offset } => {
+                write!(f, "extension {:#06x} truncated at offset {}", flag, offset)
+            }
+            ExtensionError::Rejected(flags) => write!(f, "unknown extension flags {:#06x}", flags),
+            ExtensionError::AttributesTooLarge { len, max } => {
+                write!(f, "attributes of {} bytes exceed {} bytes", len, max)
+            }
+            ExtensionError::TooManyAttributes { count, max } => {
+                write!(f, "{} attributes exceed {}", count, max)
+            }
+            ExtensionError::AttributeValueTooLarge { offset, len, max } => {
+                write!(f,
+                       "attribute value of {} bytes at offset {} exceeds {} bytes",
+                       len,
+                       offset,
+                       max)
+            }
+            ExtensionError::MalformedAttributes { offset } => {
+                write!(f, "malformed attribute at offset {}", offset)
+            }
+            ExtensionError::DuplicateAttribute { offset } => {
+                write!(f, "duplicate attribute at offset {}", offset)
+            }
+            ExtensionError::TrailingGarbage { offset, len } => {
+                write!(f, "{} bytes of trailing garbage at offset {}", len, offset)
+            }
+        }
+    }
+}
+
+impl error::Error for ExtensionError {
+    fn description(&self) -> &str {
+        match *self {
+            ExtensionError::TruncatedFlags => "truncated extension flags",
+            ExtensionError::Truncated { .. } => "truncated extension",
+            ExtensionError::Rejected(_) => "unknown extension flags",
+            ExtensionError::AttributesTooLarge { .. } => "attributes too large",
+            ExtensionError::TooManyAttributes { .. } => "too many attributes",
+            ExtensionError::AttributeValueTooLarge { .. } => "attribute value too large",
+            ExtensionError::MalformedAttributes { .. } => "malformed attributes",
+            ExtensionError::DuplicateAttribute { .. } => "duplicate attribute",
+            ExtensionError::TrailingGarbage { .. } => "trailing garbage",
+        }
+    }
+}
+
+impl From<ExtensionError> for io::Error {
+    fn from(e: ExtensionError) -> Self {
+        e.into_io()
+    }
+}
+
+/// How large an attributes section may be, so that a hostile client cannot
+/// make every message costly to look through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttributeLimits {
+    pub max_count: usize,
+    /// Of the whole section, count and lengths included.
+    pub max_total_len: usize,
+    pub max_value_len: usize,
+}
+
+impl AttributeLimits {
+    /// Whatever the encoding can say.
+    pub fn unlimited() -> Self {
+        AttributeLimits {
+            max_count: usize::MAX,
+            max_total_len: usize::MAX,
+            max_value_len: usize::MAX,
+        }
+    }
+}
+
+impl Default for AttributeLimits {
+    fn default() -> Self {
+        AttributeLimits {
+            max_count: 32,
+            max_total_len: 4096,
+            max_value_len: 1024,
+        }
+    }
+}
+
+/// An attributes section, validated in one pass and indexed by key.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Attributes<'a> {
+    /// By key.
+    entries: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> Attributes<'a> {
+    /// Checks the section's length, then its count, against `limits` before
+    /// reading a single attribute, then each value as it is read.
+    pub fn parse(section: &'a [u8], limits: &AttributeLimits) -> Result<Self, ExtensionError> {
+        if section.len() > limits.max_total_len {
+            return Err(ExtensionError::AttributesTooLarge {
+                len: section.len(),
+                max: limits.max_total_len,
+            });
+        }
+        let count = match section.first() {
+            None => return Err(ExtensionError::MalformedAttributes { offset: 0 }),
+            Some(&count) => count as usize,
+        };
+        if count > limits.max_count {
+            return Err(ExtensionError::TooManyAttributes {
+                count: count,
+                max: limits.max_count,
+            });
+        }
+
+        let mut entries = Vec::with_capacity(count);
+        let mut offsets = Vec::with_capacity(count);
+        let mut at = 1;
+        for _ in 0..count {
+            let start = at;
+            let malformed = ExtensionError::MalformedAttributes { offset: start };
+            let key_len = match section.get(at) {
+                None => return Err(malformed),
+                Some(&len) => len as usize,
+            };
+            if section.len() - at < 1 + key_len + 2 {
+                return Err(malformed);
+            }
+            let key = &section[at + 1..at + 1 + key_len];
+            at += 1 + key_len;
+            let value_len = BigEndian::read_u16(&section[at..]) as usize;
+            if value_len > limits.max_value_len {
+                return Err(ExtensionError::AttributeValueTooLarge {
+                    offset: start,
+                    len: value_len,
+                    max: limits.max_value_len,
+                });
+            }
+            if section.len() - at - 2 < value_len {
+                return Err(malformed);
+            }
+            entries.push((key, &section[at + 2..at + 2 + value_len]));
+            offsets.push(start);
+            at += 2 + value_len;
+        }
+        if at != section.len() {
+            return Err(ExtensionError::MalformedAttributes { offset: at });
+        }
+
+        let mut order: Vec<_> = (0..count).collect();
+        // Stable, so that each key's first attribute comes first.
+        order.sort_by(|&a, &b| entries[a].0.cmp(entries[b].0));
+        for pair in order.windows(2) {
+            if entries[pair[0]].0 == entries[pair[1]].0 {
+                return Err(ExtensionError::DuplicateAttribute { offset: offsets[pair[1]] });
+            }
+        }
+        Ok(Attributes { entries: order.into_iter().map(|i| entries[i]).collect() })
+    }
+
+    /// The value for `key`, in logarithmic time.
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.entries
+            .binary_search_by(|&(k, _)| k.cmp(key))
+            .ok()
+            .map(|i| self.entries[i].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every attribute, by key.
+    pub fn iter(&self) -> ::std::slice::Iter<(&'a [u8], &'a [u8])> {
+        self.entries.iter()
+    }
+
+    /// The section with its attributes in key order, which is how
+    /// canonical extensions hold them.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut section = vec![self.entries.len() as u8];
+        for &(key, value) in &self.entries {
+            section.push(key.len() as u8);
+            section.extend_from_slice(key);
+            section.extend_from_slice(&[(value.len() >> 8) as u8, value.len() as u8]);
+            section.extend_from_slice(value);
+        }
+        section
+    }
+}
+
+/// An attributes section as `Extensions::parse_salvaging` found it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AttributesSection<'a> {
+    /// Empty if there was no section.
+    Parsed(Attributes<'a>),
+    /// The section fits in the input, but its attributes are malformed.
+    Corrupt {
+        raw: &'a [u8],
+        error: ExtensionError,
+    },
+}
+
+impl<'a> AttributesSection<'a> {
+    pub fn is_corrupt(&self) -> bool {
+        match *self {
+            AttributesSection::Parsed(_) => false,
+            AttributesSection::Corrupt { .. } => true,
+        }
+    }
+
+    /// The attributes, or none if the section is corrupt.
+    pub fn attributes(&self) -> Option<&Attributes<'a>> {
+        match *self {
+            AttributesSection::Parsed(ref attributes) => Some(attributes),
+            AttributesSection::Corrupt { .. } => None,
+        }
+    }
+}
+
+/// The sections present, each `None` when its bit is clear.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Extensions<'a> {
+    pub crc: Option<u32>,
+    pub attrs: Option<&'a [u8]>,
+    pub content_type: Option<&'a [u8]>,
+    pub priority: Option<u8>,
+    /// How many bytes of padding; their contents mean nothing.
+    pub padding: Option<u16>,
+    /// How long the payload is, if not all that follows.
+    pub payload_len: Option<u32>,
+    /// The unknown bits whose sections were skipped. Never written.
+    pub skipped: u16,
+}
+
+/// Reads the sections of `flags` in order, as `Extensions::parse` does.
+struct Sections<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Sections<'a> {
+    fn take(&mut self, flag: u16, len: usize) -> Result<&'a [u8], ExtensionError> {
+        if self.bytes.len() - self.offset < len {
+            return Err(ExtensionError::Truncated {
+                flag: flag,
+                offset: self.offset,
+            });
+        }
+        let section = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(section)
+    }
+
+    fn prefixed(&mut self, flag: u16, width: usize) -> Result<&'a [u8], ExtensionError> {
+        let start = self.offset;
+        let len = try!(self.take(flag, width));
+        let len = if width == 1 {
+            len[0] as usize
+        } else {
+            BigEndian::read_u16(len) as usize
+        };
+        self.take(flag, len).map_err(|_| {
+            ExtensionError::Truncated {
+                flag: flag,
+                offset: start,
+            }
+        })
+    }
+}
+
+impl<'a> Extensions<'a> {
+    /// The known bits for the sections present.
+    pub fn flags(&self) -> u16 {
+        let bit = |present: bool, bit| if present { bit } else { 0 };
+        bit(self.crc.is_some(), CRC_PRESENT) | bit(self.attrs.is_some(), ATTRS_PRESENT) |
+        bit(self.content_type.is_some(), CONTENT_TYPE_PRESENT) |
+        bit(self.priority.is_some(), PRIORITY_PRESENT) |
+        bit(self.padding.is_some(), PADDING_PRESENT) |
+        bit(self.payload_len.is_some(), PAYLOAD_LEN_PRESENT)
+    }
+
+    /// How many bytes `write_into` writes.
+    pub fn encoded_len(&self) -> usize {
+        2 + self.crc.map_or(0, |_| 4) + self.attrs.map_or(0, |attrs| 2 + attrs.len()) +
+        self.content_type.map_or(0, |content_type| 1 + content_type.len()) +
+        self.priority.map_or(0, |_| 1) + self.padding.map_or(0, |len| 2 + len as usize) +
+        self.payload_len.map_or(0, |_| 4)
+    }
+
+    /// Writes the flags and the sections present to the start of `buf`
+    /// without allocating, returning how many bytes it took. Nothing is
+    /// written on failure.
+    pub fn write_into(&self, buf: &mut [u8]) -> Result<usize, WriteIntoError> {
+        if let Some(attrs) = self.attrs {
+            if attrs.len() > u16::max_value() as usize {
+                return Err(WriteIntoError::ExtensionTooLarge {
+                    flag: ATTRS_PRESENT,
+                    len: attrs.len(),
+                });
+            }
+        }
+        if let Some(content_type) = self.content_type {
+            if content_type.len() > u8::max_value() as usize {
+                return Err(WriteIntoError::ExtensionTooLarge {
+                    flag: CONTENT_TYPE_PRESENT,
+                    len: content_type.len(),
+                });
+            }
+        }
+        let needed = self.encoded_len();
+        if buf.len() < needed {
+            return Err(WriteIntoError::BufferTooSmall { needed: needed });
+        }
+
+        BigEndian::write_u16(buf, self.flags());
+        let mut at = 2;
+        if let Some(crc) = self.crc {
+            BigEndian::write_u32(&mut buf[at..], crc);
+            at += 4;
+        }
+        if let Some(attrs) = self.attrs {
+            BigEndian::write_u16(&mut buf[at..], attrs.len() as u16);
+            buf[at + 2..at + 2 + attrs.len()].copy_from_slice(attrs);
+            at += 2 + attrs.len();
+        }
+        if let Some(content_type) = self.content_type {
+            buf[at] = content_type.len() as u8;
+            buf[at + 1..at + 1 + content_type.len()].copy_from_slice(content_type);
+            at += 1 + content_type.len();
+        }
+        if let Some(priority) = self.priority {
+            buf[at] = priority;
+            at += 1;
+        }
+        if let Some(len) = self.padding {
+            BigEndian::write_u16(&mut buf[at..], len);
+            for b in &mut buf[at + 2..at + 2 + len as usize] {
+                *b = 0;
+            }
+            at += 2 + len as usize;
+        }
+        if let Some(len) = self.payload_len {
+            BigEndian::write_u32(&mut buf[at..], len);
+            at += 4;
+        }
+        Ok(at)
+    }
+
+    /// Parses the flags and their sections from the start of `bytes`,
+    /// returning the payload that follows them. Unknown bits are all
+    /// checked against `registry` before any section is read, as is, at the
+    /// end, anything past an explicit payload length.
+    pub fn parse(bytes: &'a [u8],
+                 registry: &ExtensionRegistry)
+                 -> Result<(Self, &'a [u8]), ExtensionError> {
+        if bytes.len() < 2 {
+            return Err(ExtensionError::TruncatedFlags);
+        }
+        let flags = BigEndian::read_u16(bytes);
+        let rejected = flags & !KNOWN & !registry.skip;
+        if rejected != 0 {
+            return Err(ExtensionError::Rejected(rejected));
+        }
+
+        let mut sections = Sections {
+            bytes: bytes,
+            offset: 2,
+        };
+        let mut extensions = Extensions::default();
+        for shift in 0..16 {
+            let flag = 1 << shift;
+            if flags & flag == 0 {
+                continue;
+            }
+            match flag {
+                CRC_PRESENT => {
+                    extensions.crc = Some(BigEndian::read_u32(try!(sections.take(flag, 4))));
+                }
+                ATTRS_PRESENT => extensions.attrs = Some(try!(sections.prefixed(flag, 2))),
+                CONTENT_TYPE_PRESENT => {
+                    extensions.content_type = Some(try!(sections.prefixed(flag, 1)));
+                }
+                PRIORITY_PRESENT => extensions.priority = Some(try!(sections.take(flag, 1))[0]),
+                PADDING_PRESENT => {
+                    extensions.padding = Some(try!(sections.prefixed(flag, 2)).len() as u16);
+                }
+                PAYLOAD_LEN_PRESENT => {
+                    let len = BigEndian::read_u32(try!(sections.take(flag, 4)));
+                    extensions.payload_len = Some(len);
+                }
+                _ => {
+                    try!(sections.prefixed(flag, 2));
+                    extensions.skipped |= flag;
+                }
+            }
+        }
+        let rest = &bytes[sections.offset..];
+        let len = match extensions.payload_len {
+            None => return Ok((extensions, rest)),
+            Some(len) => len as usize,
+        };
+        if rest.len() < len {
+            return Err(ExtensionError::Truncated {
+                flag: PAYLOAD_LEN_PRESENT,
+                offset: sections.offset,
+            });
+        }
+        if rest.len() > len && registry.reject_trailing {
+            return Err(ExtensionError::TrailingGarbage {
+                offset: sections.offset + len,
+                len: rest.len() - len,
+            });
+        }
+        Ok((extensions, &rest[..len]))
+    }
+
+    /// Like `parse`, also validating and indexing the attributes section,
+    /// if any, within `limits`, so that the whole message is refused
+    /// before anything past its extensions is looked at.
+    pub fn parse_limited(bytes: &'a [u8],
+                         registry: &ExtensionRegistry,
+                         limits: &AttributeLimits)
+                         -> Result<(Self, Attributes<'a>, &'a [u8]), ExtensionError> {
+        let (extensions, rest) = try!(Extensions::parse(bytes, registry));
+        let attributes = match extensions.attrs {
+            None => Attributes::default(),
+            Some(section) => try!(Attributes::parse(section, limits)),
+        };
+        Ok((extensions, attributes, rest))
+    }
+
+    /// The extensions at the start of `bytes`, and what follows them, in
+    /// the one encoding every logically equal variant shares: attributes
+    /// in key order, no empty attributes section, no padding, no explicit
+    /// payload length, and no sections for unknown bits, which `parse`
+    /// skips. Trailing garbage is dropped.
+    pub fn canonicalize(bytes: &[u8],
+                        registry: &ExtensionRegistry)
+                        -> Result<Vec<u8>, ExtensionError> {
+        let (extensions, attributes, rest) =
+            try!(Extensions::parse_limited(bytes, registry, &AttributeLimits::unlimited()));
+        let section = attributes.to_bytes();
+        let canonical = Extensions {
+            attrs: if attributes.is_empty() {
+                None
+            } else {
+                Some(&section)
+            },
+            padding: None,
+            payload_len: None,
+            skipped: 0,
+            ..extensions
+        };
+        let mut canonical_bytes = vec![0; canonical.encoded_len() + rest.len()];
+        // No section grew, so each still fits its length.
+        let at = canonical.write_into(&mut canonical_bytes).unwrap();
+        canonical_bytes[at..].copy_from_slice(rest);
+        Ok(canonical_bytes)
+    }
+
+    pub fn is_canonical(bytes: &[u8], registry: &ExtensionRegistry) -> bool {
+        Extensions::canonicalize(bytes, registry).map_or(false, |canonical| canonical == bytes)
+    }
+
+    /// Like `parse_limited`, but an attributes section malformed within a
+    /// length that still fits is returned as `Corrupt` instead of refusing
+    /// the message, whose other sections and payload are sound. A section
+    /// running past the input, a rejected bit or a section over `limits`
+    /// still refuses it.
+    pub fn parse_salvaging(bytes: &'a [u8],
+                           registry: &ExtensionRegistry,
+                           limits: &AttributeLimits)
+                           -> Result<(Self, AttributesSection<'a>, &'a [u8]), ExtensionError> {
+        let (extensions, rest) = try!(Extensions::parse(bytes, registry));
+        let section = match extensions.attrs {
+            None => AttributesSection::Parsed(Attributes::default()),
+            Some(raw) => {
+                match Attributes::parse(raw, limits) {
+                    Ok(attributes) => AttributesSection::Parsed(attributes),
+                    Err(error @ ExtensionError::MalformedAttributes { .. }) |
+                    Err(error @ ExtensionError::DuplicateAttribute { .. }) => {
+                        AttributesSection::Corrupt {
+                            raw: raw,
+                            error: error,
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        Ok((extensions, section, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::{Strictness, WriteIntoError};
+    use test_support::*;
+
+    type Parts = (Option<u32>, Option<Vec<u8>>, Option<Vec<u8>>, Option<u8>, Option<u8>);
+
+    fn extensions(parts: &Parts) -> Extensions {
+        Extensions {
+            crc: parts.0,
+            attrs: parts.1.as_ref().map(|attrs| &attrs[..]),
+            content_type: parts.2.as_ref().map(|content_type| &content_type[..]),
+            priority: parts.3,
+            padding: parts.4.map(|len| len as u16),
+            payload_len: None,
+            skipped: 0,
+        }
+    }
+
+    fn encode(extensions: &Extensions, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; extensions.encoded_len()];
+        assert_eq!(Ok(bytes.len()), extensions.write_into(&mut bytes));
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    quickcheck_test! {
+    round_trip(parts: Parts, payload: Vec<u8>; bool) {
+        let extensions = extensions(&parts);
+        let bytes = encode(&extensions, &payload);
+        [Strictness::Strict, Strictness::Standard, Strictness::Lenient].iter().all(|&strictness| {
+            let registry = ExtensionRegistry::for_strictness(strictness);
+            Extensions::parse(&bytes, &registry) == Ok((extensions.clone(), &payload[..]))
+        })
+    }}
+
+    /// `parts`, with a section for the unknown `bit` after theirs.
+    fn with_unknown(parts: &Parts, bit: u16, contents: &[u8], payload: &[u8]) -> Vec<u8> {
+        let known = extensions(parts);
+        let mut bytes = encode(&known, &[]);
+        let flags = known.flags() | bit;
+        bytes[0] = (flags >> 8) as u8;
+        bytes[1] = flags as u8;
+        bytes.extend_from_slice(&[(contents.len() >> 8) as u8, contents.len() as u8]);
+        bytes.extend_from_slice(contents);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    quickcheck_test! {
+    unknown_bit(parts: Parts, shift: u8, contents: Vec<u8>, payload: Vec<u8>; bool) {
+        let bit = 1 << (6 + shift % 10);
+        let bytes = with_unknown(&parts, bit, &contents, &payload);
+        let strict = ExtensionRegistry::for_strictness(Strictness::Strict);
+        let lenient = ExtensionRegistry::for_strictness(Strictness::Lenient);
+        let expected = Extensions {
+            skipped: bit,
+            ..extensions(&parts)
+        };
+        Extensions::parse(&bytes, &strict) == Err(ExtensionError::Rejected(bit)) &&
+        Extensions::parse(&bytes, &lenient) == Ok((expected, &payload[..]))
+    }}
+
+    #[test]
+    fn registry_by_bit() {
+        let mut registry = ExtensionRegistry::new();
+        registry.set(0x0100, Unknown::Skip);
+        assert_eq!(Unknown::Skip, registry.policy(0x0100));
+        assert_eq!(Unknown::Reject, registry.policy(0x0200));
+        let bytes = [0x03, 0x00, 0, 1, b'a', 0, 0];
+        assert_eq!(Err(ExtensionError::Rejected(0x0200)),
+                   Extensions::parse(&bytes, &registry));
+        registry.set(0x0200, Unknown::Skip);
+        assert_eq!(Ok((Extensions {
+                           skipped: 0x0300,
+                           ..Extensions::default()
+                       },
+                       &[][..])),
+                   Extensions::parse(&bytes, &registry));
+        registry.set(0x0100, Unknown::Reject);
+        assert_eq!(Err(ExtensionError::Rejected(0x0100)),
+                   Extensions::parse(&bytes, &registry));
+    }
+
+    #[test]
+    #[should_panic]
+    fn registry_refuses_known_bit() {
+        ExtensionRegistry::new().set(CRC_PRESENT, Unknown::Skip);
+    }
+
+    #[test]
+    fn truncated() {
+        let registry = ExtensionRegistry::new();
+        assert_eq!(Err(ExtensionError::TruncatedFlags),
+                   Extensions::parse(&[0], &registry));
+        let cases: Vec<(&[u8], u16, usize)> = vec![
+            (&[0x00, 0x01, 1, 2, 3], CRC_PRESENT, 2),
+            (&[0x00, 0x03, 1, 2, 3, 4, 0], ATTRS_PRESENT, 6),
+            (&[0x00, 0x02, 0, 3, b'a'], ATTRS_PRESENT, 2),
+            (&[0x00, 0x0c, 2, b'a'], CONTENT_TYPE_PRESENT, 2),
+            (&[0x00, 0x0c, 0], PRIORITY_PRESENT, 3),
+            (&[0x00, 0x10, 0, 2, 0], PADDING_PRESENT, 2),
+        ];
+        for (bytes, flag, offset) in cases {
+            assert_eq!(Err(ExtensionError::Truncated {
+                           flag: flag,
+                           offset: offset,
+                       }),
+                       Extensions::parse(bytes, &registry));
+        }
+    }
+
+    #[test]
+    fn payload_len_by_strictness() {
+        let with_len = |len: u32, rest: &[u8]| {
+            let extensions = Extensions {
+                payload_len: Some(len),
+                ..Extensions::default()
+            };
+            encode(&extensions, rest)
+        };
+        let exact = with_len(7, b"payload");
+        let trailing = with_len(7, b"payload\0\0");
+        let short = with_len(8, b"payload");
+        // (input, strict, standard, lenient)
+        let cases = vec![
+            (&exact, "payload", "payload", "payload"),
+            (&trailing, "garbage", "payload", "payload"),
+            (&short, "truncated", "truncated", "truncated"),
+        ];
+        for (bytes, strict, standard, lenient) in cases {
+            let parse = |strictness| {
+                match Extensions::parse(bytes, &ExtensionRegistry::for_strictness(strictness)) {
+                    Ok((_, b"payload")) => "payload",
+                    Err(ExtensionError::TrailingGarbage { offset: 13, len: 2 }) => "garbage",
+                    Err(ExtensionError::Truncated { flag: PAYLOAD_LEN_PRESENT, offset: 6 }) => {
+                        "truncated"
+                    }
+                    _ => "other",
+                }
+            };
+            assert_eq!(strict, parse(Strictness::Strict));
+            assert_eq!(standard, parse(Strictness::Standard));
+            assert_eq!(lenient, parse(Strictness::Lenient));
+        }
+        assert_eq!(Ok(b"\0\0payload".to_vec()),
+                   Extensions::canonicalize(&trailing, &ExtensionRegistry::new()));
+    }
+
+    #[test]
+    fn too_large() {
+        let big = vec![0; u16::max_value() as usize + 1];
+        let extensions = Extensions {
+            attrs: Some(&big),
+            ..Extensions::default()
+        };
+        assert_eq!(Err(WriteIntoError::ExtensionTooLarge {
+                       flag: ATTRS_PRESENT,
+                       len: big.len(),
+                   }),
+                   extensions.write_into(&mut []));
+        let extensions = Extensions {
+            content_type: Some(&big[..256]),
+            ..Extensions::default()
+        };
+        assert_eq!(Err(WriteIntoError::ExtensionTooLarge {
+                       flag: CONTENT_TYPE_PRESENT,
+                       len: 256,
+                   }),
+                   extensions.write_into(&mut []));
+        assert_eq!(Err(WriteIntoError::BufferTooSmall { needed: 2 }),
+                   Extensions::default().write_into(&mut [0]));
+    }
+
+    /// An attributes section of `pairs`, in order.
+    fn attributes(pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut section = vec![pairs.len() as u8];
+        for &(ref key, ref value) in pairs {
+            section.push(key.len() as u8);
+            section.extend_from_slice(key);
+            section.extend_from_slice(&(value.len() as u16).to_bytes());
+            section.extend_from_slice(value);
+        }
+        section
+    }
+
+    quickcheck_test! {
+    attribute_lookup(pairs: Vec<(Vec<u8>, Vec<u8>)>, probe: Vec<u8>; bool) {
+        let mut unique: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+        for (mut key, value) in pairs.into_iter().take(255) {
+            key.truncate(255);
+            if unique.iter().all(|&(ref k, _)| *k != key) {
+                unique.push((key, value));
+            }
+        }
+        let section = attributes(&unique);
+        let parsed = Attributes::parse(&section, &AttributeLimits::unlimited()).unwrap();
+        let scan = |key: &[u8]| {
+            unique.iter().find(|&&(ref k, _)| &k[..] == key).map(|&(_, ref v)| &v[..])
+        };
+        parsed.len() == unique.len() && scan(&probe) == parsed.get(&probe) &&
+        unique.iter().all(|&(ref key, _)| scan(key) == parsed.get(key))
+    }}
+
+    quickcheck_test! {
+    attribute_limits(count: u8, value_len: u8; bool) {
+        let pairs: Vec<_> = (0..count).map(|i| (vec![i], vec![0; value_len as usize])).collect();
+        let section = attributes(&pairs);
+        let exact = AttributeLimits {
+            max_count: count as usize,
+            max_total_len: section.len(),
+            max_value_len: value_len as usize,
+        };
+        let over = |limits: AttributeLimits| Attributes::parse(&section, &limits);
+        over(exact).map(|parsed| parsed.len()) == Ok(count as usize) &&
+        over(AttributeLimits { max_total_len: section.len() - 1, ..exact }) ==
+        Err(ExtensionError::AttributesTooLarge { len: section.len(), max: section.len() - 1 }) &&
+        (count == 0 ||
+         over(AttributeLimits { max_count: count as usize - 1, ..exact }) ==
+         Err(ExtensionError::TooManyAttributes {
+             count: count as usize,
+             max: count as usize - 1,
+         })) &&
+        (count == 0 || value_len == 0 ||
+         over(AttributeLimits { max_value_len: value_len as usize - 1, ..exact }) ==
+         Err(ExtensionError::AttributeValueTooLarge {
+             offset: 1,
+             len: value_len as usize,
+             max: value_len as usize - 1,
+         }))
+    }}
+
+    #[test]
+    fn malformed_attributes() {
+        let limits = AttributeLimits::unlimited();
+        let cases: Vec<(&[u8], ExtensionError)> = vec![
+            (&[], ExtensionError::MalformedAttributes { offset: 0 }),
+            (&[1], ExtensionError::MalformedAttributes { offset: 1 }),
+            (&[1, 1, b'k', 0, 2, b'v'], ExtensionError::MalformedAttributes { offset: 1 }),
+            (&[1, 1, b'k', 0, 0, 9], ExtensionError::MalformedAttributes { offset: 5 }),
+            (&[2, 1, b'k', 0, 0, 1, b'k', 0, 1, b'v'],
+             ExtensionError::DuplicateAttribute { offset: 5 }),
+        ];
+        for (section, error) in cases {
+            assert_eq!(Err(error), Attributes::parse(section, &limits));
+        }
+    }
+
+    #[test]
+    fn rejected_before_payload() {
+        // 255 attributes declared, none of them there, before a payload.
+        let mut bytes = vec![0x00, 0x02, 0, 1, 255];
+        bytes.extend_from_slice(b"payload");
+        let registry = ExtensionRegistry::new();
+        assert_eq!(Err(ExtensionError::TooManyAttributes { count: 255, max: 32 }),
+                   Extensions::parse_limited(&bytes, &registry, &AttributeLimits::default()));
+
+        let section = attributes(&[(b"b".to_vec(), b"2".to_vec()), (b"a".to_vec(), b"1".to_vec())]);
+        let extensions = Extensions { attrs: Some(&section), ..Extensions::default() };
+        let bytes = encode(&extensions, b"payload");
+        let (parsed, attributes, payload) =
+            Extensions::parse_limited(&bytes, &registry, &AttributeLimits::default()).unwrap();
+        assert_eq!((extensions, &b"payload"[..]), (parsed, payload));
+        assert_eq!(vec![(&b"a"[..], &b"1"[..]), (&b"b"[..], &b"2"[..])],
+                   attributes.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(None, attributes.get(b"c"));
+    }
+
+    #[test]
+    fn salvages_confined_corruption() {
+        let registry = ExtensionRegistry::new();
+        let limits = AttributeLimits::default();
+        let corrupt: Vec<&[u8]> = vec![&[1, 1, b'k', 0, 2, b'v'],
+                                       &[1, 1, b'k', 0, 0, 9],
+                                       &[2, 1, b'k', 0, 0, 1, b'k', 0, 1, b'v']];
+        for raw in corrupt {
+            let extensions = Extensions {
+                attrs: Some(raw),
+                priority: Some(3),
+                ..Extensions::default()
+            };
+            let bytes = encode(&extensions, b"\x00payload\xff");
+            assert!(Extensions::parse_limited(&bytes, &registry, &limits).is_err());
+            let (parsed, section, payload) =
+                Extensions::parse_salvaging(&bytes, &registry, &limits).unwrap();
+            assert_eq!(extensions, parsed);
+            assert_eq!(&b"\x00payload\xff"[..], payload);
+            assert!(section.is_corrupt());
+            assert_eq!(None, section.attributes());
+            assert_match!(AttributesSection::Corrupt { raw: r, .. } if r == raw, section);
+        }
+
+        let section = attributes(&[(b"k".to_vec(), b"v".to_vec())]);
+        let extensions = Extensions { attrs: Some(&section), ..Extensions::default() };
+        let bytes = encode(&extensions, b"payload");
+        let (_, section, _) = Extensions::parse_salvaging(&bytes, &registry, &limits).unwrap();
+        assert_eq!(Some(&b"v"[..]), section.attributes().and_then(|a| a.get(b"k")));
+    }
+
+    #[test]
+    fn outer_corruption_still_refused() {
+        let registry = ExtensionRegistry::new();
+        let limits = AttributeLimits::default();
+        // The section claims more bytes than there are.
+        let bytes = [0x00, 0x02, 0, 9, 1, 1, b'k'];
+        let truncated = || {
+            ExtensionError::Truncated {
+                flag: ATTRS_PRESENT,
+                offset: 2,
+            }
+        };
+        assert_eq!(Err(truncated()), Extensions::parse_limited(&bytes, &registry, &limits));
+        assert_eq!(Err(truncated()), Extensions::parse_salvaging(&bytes, &registry, &limits));
+
+        // Over the limits is refused, not salvaged.
+        let bytes = [0x00, 0x02, 0, 1, 255];
+        assert_eq!(Err(ExtensionError::TooManyAttributes { count: 255, max: 32 }),
+                   Extensions::parse_salvaging(&bytes, &registry, &limits));
+        assert_eq!(Err(ExtensionError::TruncatedFlags),
+                   Extensions::parse_salvaging(&[0], &registry, &limits));
+    }
+}
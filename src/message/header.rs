@@ -1,9 +1,17 @@
 use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
 use std::error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Range;
 use std::time::Duration;
 
+use super::wire;
+use super::wire::{Native, WireIndex};
+use util::millis;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Part {
     TokenSize,
@@ -23,6 +31,11 @@ impl Part {
         }
     }
 
+    /// The bytes the part would have taken up had it started at `offset`.
+    pub fn expected_at(&self, offset: usize) -> Range<usize> {
+        offset..offset + self.size() as usize
+    }
+
     fn description(&self) -> &'static str {
         match *self {
             Part::TokenSize => "token size",
@@ -34,26 +47,68 @@ impl Part {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Headers order by token, then ID, then timestamp.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Header<'a> {
     pub token: &'a [u8],
     pub id: &'a [u8],
     pub timestamp: Duration,
 }
 
+/// A copy of the input around a parse failure, for diagnosis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiagnosticWindow {
+    /// Where `bytes` starts in the input.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl DiagnosticWindow {
+    /// Up to `max` bytes of `input`, centred where possible on `at`.
+    pub fn around(input: &[u8], at: usize, max: usize) -> Self {
+        let start = at - cmp::min(at, max / 2);
+        let end = cmp::min(input.len(), start + max);
+        let start = end - cmp::min(end, max);
+        DiagnosticWindow {
+            offset: start,
+            bytes: input[start..end].to_vec(),
+        }
+    }
+}
+
+impl Display for DiagnosticWindow {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "bytes at offset {}:", self.offset));
+        for b in &self.bytes {
+            try!(write!(f, " {:02x}", b));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Error {
     pub remaining: u16,
     pub part: Part,
+    /// How many bytes of the input came before the missing part.
+    pub offset: usize,
+    /// Only captured by `Header::parse_diagnostic`, and the one part of a
+    /// parse error that allocates.
+    pub diagnostic: Option<DiagnosticWindow>,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        write!(f,
-               "missing {} of {} bytes; {} bytes remaining",
-               self.part.description(),
-               self.part.size(),
-               self.remaining)
+        try!(write!(f,
+                    "missing {} of {} bytes at offset {}; {} bytes remaining",
+                    self.part.description(),
+                    self.part.size(),
+                    self.offset,
+                    self.remaining));
+        match self.diagnostic {
+            Some(ref window) => write!(f, "; {}", window),
+            None => Ok(()),
+        }
     }
 }
 
@@ -69,58 +124,374 @@ impl error::Error for Error {
     }
 }
 
+/// How closely input must conform to the protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    /// Additionally rejects empty tokens and IDs and zero timestamps, and,
+    /// through `ExtensionRegistry::for_strictness`, unknown extension bits
+    /// and a payload longer than its extensions say.
+    Strict,
+    Standard,
+    /// Additionally tolerates a connection that ends mid-frame, dropping the
+    /// partial frame, unless it ends exactly a length prefix short: then
+    /// the prefix counted itself, and the frame is taken as whole. Each is
+    /// counted as a repair.
+    Lenient,
+}
+
+impl Default for Strictness {
+    fn default() -> Self {
+        Strictness::Standard
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Nonconformance {
+    EmptyToken,
+    EmptyId,
+    ZeroTimestamp,
+}
+
+impl Nonconformance {
+    fn description(&self) -> &'static str {
+        match *self {
+            Nonconformance::EmptyToken => "empty token",
+            Nonconformance::EmptyId => "empty Id",
+            Nonconformance::ZeroTimestamp => "zero timestamp",
+        }
+    }
+
+    /// Always `InvalidData`.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        io::ErrorKind::InvalidData
+    }
+}
+
+impl Display for Nonconformance {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(self.description())
+    }
+}
+
+impl error::Error for Nonconformance {
+    fn description(&self) -> &str {
+        Nonconformance::description(self)
+    }
+}
+
+impl Error {
+    /// Always `InvalidData`.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        io::ErrorKind::InvalidData
+    }
+
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(self.io_kind(), self)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        e.into_io()
+    }
+}
+
+/// Why a header or frame could not be written.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WriteIntoError {
+    BufferTooSmall {
+        needed: usize,
+    },
+    /// The token is longer than its two-byte size can say.
+    TokenTooLarge(usize),
+    IdTooLarge(usize),
+    /// The message is longer than its two-byte frame prefix can say.
+    MessageTooLarge(usize),
+    /// The section for an extension `flag` is longer than its length can say.
+    ExtensionTooLarge {
+        flag: u16,
+        len: usize,
+    },
+}
+
+impl Display for WriteIntoError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            WriteIntoError::BufferTooSmall { needed } => {
+                write!(f, "buffer too small; {} bytes needed", needed)
+            }
+            WriteIntoError::TokenTooLarge(len) => write!(f, "token of {} bytes too large", len),
+            WriteIntoError::IdTooLarge(len) => write!(f, "Id of {} bytes too large", len),
+            WriteIntoError::MessageTooLarge(len) => {
+                write!(f, "message of {} bytes too large", len)
+            }
+            WriteIntoError::ExtensionTooLarge { flag, len } => {
+                write!(f, "extension {:#06x} of {} bytes too large", flag, len)
+            }
+        }
+    }
+}
+
+impl error::Error for WriteIntoError {
+    fn description(&self) -> &str {
+        match *self {
+            WriteIntoError::BufferTooSmall { .. } => "buffer too small",
+            WriteIntoError::TokenTooLarge(_) => "token too large",
+            WriteIntoError::IdTooLarge(_) => "Id too large",
+            WriteIntoError::MessageTooLarge(_) => "message too large",
+            WriteIntoError::ExtensionTooLarge { .. } => "extension too large",
+        }
+    }
+}
+
 impl<'a> Header<'a> {
-    pub fn parse(mut bytes: &'a [u8]) -> Result<(Self, &'a [u8]), Error> {
-        let mut remaining = bytes.len() as u16;
-        let mut check = |part: Part| {
-            remaining = try!(remaining.checked_sub(part.size()).ok_or(Error {
-                remaining: remaining,
-                part: part,
-            }));
-            Ok(())
-        };
+    /// How many bytes `write_into` writes.
+    pub fn encoded_len(&self) -> usize {
+        12 + self.token.len() + self.id.len()
+    }
 
-        try!(check(Part::TokenSize));
-        let token_size = BigEndian::read_u16(bytes);
-        bytes = &bytes[2..];
+    /// Writes the header to the start of `buf` without allocating,
+    /// returning how many bytes it took. Nothing is written on failure.
+    pub fn write_into(&self, buf: &mut [u8]) -> Result<usize, WriteIntoError> {
+        if self.token.len() > u16::max_value() as usize {
+            return Err(WriteIntoError::TokenTooLarge(self.token.len()));
+        }
+        if self.id.len() > u16::max_value() as usize {
+            return Err(WriteIntoError::IdTooLarge(self.id.len()));
+        }
+        let needed = self.encoded_len();
+        if buf.len() < needed {
+            return Err(WriteIntoError::BufferTooSmall { needed: needed });
+        }
 
-        try!(check(Part::Token(token_size)));
-        let token = &bytes[..token_size as usize];
-        bytes = &bytes[token_size as usize..];
+        let (token_end, id_end) = (2 + self.token.len(), 4 + self.token.len() + self.id.len());
+        BigEndian::write_u16(buf, self.token.len() as u16);
+        buf[2..token_end].copy_from_slice(self.token);
+        BigEndian::write_u16(&mut buf[token_end..], self.id.len() as u16);
+        buf[token_end + 2..id_end].copy_from_slice(self.id);
+        BigEndian::write_u64(&mut buf[id_end..], millis(self.timestamp));
+        Ok(needed)
+    }
 
-        try!(check(Part::IdSize));
-        let id_size = BigEndian::read_u16(bytes);
-        bytes = &bytes[2..];
+    /// The (token, ID) pair identifying the stream this header is for.
+    pub fn key(&self) -> (&'a [u8], &'a [u8]) {
+        (self.token, self.id)
+    }
+
+    /// Checks the oddities that only `Strictness::Strict` rejects.
+    pub fn check(&self, strictness: Strictness) -> Result<(), Nonconformance> {
+        if strictness != Strictness::Strict {
+            Ok(())
+        } else if self.token.is_empty() {
+            Err(Nonconformance::EmptyToken)
+        } else if self.id.is_empty() {
+            Err(Nonconformance::EmptyId)
+        } else if self.timestamp == Duration::from_millis(0) {
+            Err(Nonconformance::ZeroTimestamp)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// How long the header that `prefix` begins is, as far as `prefix` can
+    /// tell; read until `prefix` is that long and the answer stops growing.
+    pub fn extent(prefix: &[u8]) -> usize {
+        if prefix.len() < 2 {
+            return 2;
+        }
+        let token_size = BigEndian::read_u16(prefix) as usize;
+        if prefix.len() < 4 + token_size {
+            return 4 + token_size;
+        }
+        let id_size = BigEndian::read_u16(&prefix[2 + token_size..]) as usize;
+        4 + token_size + id_size + 8
+    }
+
+    /// Like `parse`, but a failure carries a copy of up to `window` bytes
+    /// around where the missing part starts. A zero `window` captures
+    /// nothing and allocates nothing.
+    pub fn parse_diagnostic(bytes: &'a [u8],
+                            window: usize)
+                            -> Result<(Self, &'a [u8]), Error> {
+        Header::parse(bytes).map_err(|mut e| {
+            if window > 0 {
+                e.diagnostic = Some(DiagnosticWindow::around(bytes, e.offset, window));
+            }
+            e
+        })
+    }
 
-        try!(check(Part::Id(id_size)));
-        let id = &bytes[..id_size as usize];
-        bytes = &bytes[id_size as usize..];
+    /// Never panics: every index is checked against what remains of
+    /// `bytes` by `wire`'s arithmetic before it is used.
+    pub fn parse(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), Error> {
+        Header::parse_in::<Native>(bytes)
+    }
 
-        try!(check(Part::Timestamp));
-        let timestamp = Duration::from_millis(BigEndian::read_u64(bytes));
-        bytes = &bytes[8..];
+    /// `parse` by the arithmetic of `I`, the same for every `I` that can
+    /// index `bytes`.
+    fn parse_in<I: WireIndex>(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), Error> {
+        let mut at = Cursor::<I> {
+            bytes: bytes,
+            offset: 0,
+            index: PhantomData,
+        };
+        let token_size = BigEndian::read_u16(try!(at.take(Part::TokenSize)));
+        let token = try!(at.take(Part::Token(token_size)));
+        let id_size = BigEndian::read_u16(try!(at.take(Part::IdSize)));
+        let id = try!(at.take(Part::Id(id_size)));
+        let timestamp = Duration::from_millis(BigEndian::read_u64(try!(at.take(Part::Timestamp))));
 
         let header = Header {
             token: token,
             id: id,
             timestamp: timestamp,
         };
-        Ok((header, bytes))
+        Ok((header, &bytes[at.offset..]))
+    }
+}
+
+/// The longest a header can be, and so all of the input a parse looks at.
+const MAX_LEN: usize = 2 + 0xffff + 2 + 0xffff + 8;
+
+/// Takes header parts off the front of what `Header::parse` has yet to
+/// read.
+struct Cursor<'a, I> {
+    bytes: &'a [u8],
+    offset: usize,
+    index: PhantomData<I>,
+}
+
+impl<'a, I: WireIndex> Cursor<'a, I> {
+    fn take(&mut self, part: Part) -> Result<&'a [u8], Error> {
+        // Whatever `I` is, so that input too long for it to index is only
+        // ever read as far as a header could go.
+        let len = cmp::min(self.bytes.len(), MAX_LEN) as u64;
+        match wire::span::<I>(len, self.offset as u64, part.size() as u64) {
+            Ok(Some(range)) => {
+                self.offset = range.end;
+                Ok(&self.bytes[range])
+            }
+            _ => {
+                // Short of a part of at most `u16::MAX` bytes, so what
+                // remains fits.
+                let remaining = wire::remaining::<I>(len, self.offset as u64)
+                                    .map(|remaining| remaining.to_u64())
+                                    .unwrap_or(0);
+                Err(Error {
+                    remaining: remaining as u16,
+                    part: part,
+                    offset: self.offset,
+                    diagnostic: None,
+                })
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cmp::Ordering;
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::iter;
     use std::time::Duration;
 
     use super::*;
-    use testing::*;
+    use test_support::*;
+
+    type Parts = (Vec<u8>, Vec<u8>, u64);
+
+    fn header(parts: &Parts) -> Header {
+        Header {
+            token: &parts.0,
+            id: &parts.1,
+            timestamp: Duration::from_millis(parts.2),
+        }
+    }
+
+    quickcheck_test! {
+    ord_matches_parts(a: Parts, b: Parts; bool) {
+        header(&a).cmp(&header(&b)) == a.cmp(&b)
+    }}
+
+    quickcheck_test! {
+    ord_consistent_with_eq(a: Parts, b: Parts; bool) {
+        (header(&a) == header(&b)) == (header(&a).cmp(&header(&b)) == Ordering::Equal)
+    }}
+
+    quickcheck_test! {
+    ord_antisymmetric(a: Parts, b: Parts; bool) {
+        header(&a).cmp(&header(&b)) == header(&b).cmp(&header(&a)).reverse()
+    }}
+
+    quickcheck_test! {
+    ord_transitive(a: Parts, b: Parts, c: Parts; TestResult) {
+        let (x, y, z) = (header(&a), header(&b), header(&c));
+        if x <= y && y <= z {
+            TestResult::from_bool(x <= z)
+        } else {
+            TestResult::discard()
+        }
+    }}
+
+    quickcheck_test! {
+    key_is_token_and_id(parts: Parts; bool) {
+        header(&parts).key() == (&parts.0[..], &parts.1[..])
+    }}
+
+    #[test]
+    fn check_by_strictness() {
+        let cases: Vec<(Parts, Option<Nonconformance>)> = vec![
+            ((b"t".to_vec(), b"i".to_vec(), 1), None),
+            ((vec![], b"i".to_vec(), 1), Some(Nonconformance::EmptyToken)),
+            ((b"t".to_vec(), vec![], 1), Some(Nonconformance::EmptyId)),
+            ((b"t".to_vec(), b"i".to_vec(), 0), Some(Nonconformance::ZeroTimestamp)),
+            ((vec![], vec![], 0), Some(Nonconformance::EmptyToken)),
+        ];
+        for (parts, strict) in cases {
+            let header = header(&parts);
+            assert_eq!(strict.map_or(Ok(()), Err), header.check(Strictness::Strict));
+            assert_eq!(Ok(()), header.check(Strictness::Standard));
+            assert_eq!(Ok(()), header.check(Strictness::Lenient));
+        }
+    }
+
+    #[test]
+    fn btree_map_order() {
+        let parts: Vec<Parts> = vec![(b"b".to_vec(), b"a".to_vec(), 0),
+                                     (b"a".to_vec(), b"b".to_vec(), 0),
+                                     (b"a".to_vec(), b"a".to_vec(), 2),
+                                     (b"a".to_vec(), b"a".to_vec(), 1)];
+        let map: BTreeMap<_, _> = parts.iter().enumerate().map(|(i, p)| (header(p), i)).collect();
+        assert_eq!(vec![3, 2, 1, 0], map.values().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn into_io_round_trip() {
+        let err = Error {
+            remaining: 1,
+            part: Part::IdSize,
+            offset: 3,
+            diagnostic: None,
+        };
+        let io_err = err.into_io();
+        assert_eq!(io::ErrorKind::InvalidData, io_err.kind());
+        assert_eq!(Error {
+                       remaining: 1,
+                       part: Part::IdSize,
+                       offset: 3,
+                       diagnostic: None,
+                   },
+                   *io_err.into_inner().unwrap().downcast::<Error>().unwrap());
+    }
 
     #[test]
     fn none_of_token_size() {
         assert_eq!(Err(Error {
                        remaining: 0,
                        part: Part::TokenSize,
+                       offset: 0,
+                       diagnostic: None,
                    }),
                    Header::parse(&[]));
     }
@@ -130,6 +501,8 @@ mod tests {
         Header::parse(&[byte]) == Err(Error {
             remaining: 1,
             part: Part::TokenSize,
+            offset: 0,
+            diagnostic: None,
         })
     }}
 
@@ -146,6 +519,8 @@ mod tests {
                     Header::parse(&buf) == Err(Error {
                         remaining: remaining,
                         part: Part::Token(token_size),
+                        offset: 2,
+                        diagnostic: None,
                     }));
             }
         }
@@ -162,6 +537,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 0,
             part: Part::IdSize,
+            offset: buf.len(),
+            diagnostic: None,
         })
     }}
 
@@ -176,6 +553,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 1,
             part: Part::IdSize,
+            offset: buf.len() - 1,
+            diagnostic: None,
         })
     }}
 
@@ -195,6 +574,8 @@ mod tests {
                     Header::parse(&buf) == Err(Error {
                         remaining: remaining,
                         part: Part::Id(id_size),
+                        offset: buf.len() - remaining as usize,
+                        diagnostic: None,
                     }));
             }
         }
@@ -213,6 +594,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 0,
             part: Part::Timestamp,
+            offset: buf.len(),
+            diagnostic: None,
         })
     }}
 
@@ -229,6 +612,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 1,
             part: Part::Timestamp,
+            offset: buf.len() - 1,
+            diagnostic: None,
         })
     }}
 
@@ -247,6 +632,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 2,
             part: Part::Timestamp,
+            offset: buf.len() - 2,
+            diagnostic: None,
         })
     }}
 
@@ -266,6 +653,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 3,
             part: Part::Timestamp,
+            offset: buf.len() - 3,
+            diagnostic: None,
         })
     }}
 
@@ -285,6 +674,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 4,
             part: Part::Timestamp,
+            offset: buf.len() - 4,
+            diagnostic: None,
         })
     }}
 
@@ -304,6 +695,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 5,
             part: Part::Timestamp,
+            offset: buf.len() - 5,
+            diagnostic: None,
         })
     }}
 
@@ -323,6 +716,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 6,
             part: Part::Timestamp,
+            offset: buf.len() - 6,
+            diagnostic: None,
         })
     }}
 
@@ -342,6 +737,8 @@ mod tests {
         Header::parse(&buf) == Err(Error {
             remaining: 7,
             part: Part::Timestamp,
+            offset: buf.len() - 7,
+            diagnostic: None,
         })
     }}
 
@@ -364,4 +761,142 @@ mod tests {
         };
         Header::parse(&buf) == Ok((header, &payload))
     }}
+
+    quickcheck_test! {
+    extent_grows_to_header(token: Vec<u8>, id: Vec<u8>, timestamp: u64, payload: Vec<u8>;
+                           bool) {
+        let buf: Vec<_> = (token.len() as u16)
+            .to_bytes()
+            .into_copy_iter()
+            .chain(token.into_copy_iter())
+            .chain((id.len() as u16).to_bytes().into_copy_iter())
+            .chain(id.into_copy_iter())
+            .chain(timestamp.to_bytes().into_copy_iter())
+            .chain(payload.into_copy_iter())
+            .collect();
+        let mut read = 0;
+        while Header::extent(&buf[..read]) > read {
+            read = Header::extent(&buf[..read]);
+        }
+        let (_, rest) = Header::parse(&buf).unwrap();
+        read == buf.len() - rest.len()
+    }}
+
+    #[test]
+    fn offsets_at_every_truncation() {
+        let buf = ::test_support::header(b"tok", b"id", 1);
+        let parts = [(0..2, Part::TokenSize),
+                     (2..5, Part::Token(3)),
+                     (5..7, Part::IdSize),
+                     (7..9, Part::Id(2)),
+                     (9..17, Part::Timestamp)];
+        assert_eq!(17, buf.len());
+        for cut in 0..buf.len() {
+            let &(ref range, ref part) = parts.iter().find(|p| p.0.end > cut).unwrap();
+            let e = Header::parse(&buf[..cut]).unwrap_err();
+            assert_eq!((part, range.start, (cut - range.start) as u16),
+                       (&e.part, e.offset, e.remaining));
+            assert_eq!(*range, e.part.expected_at(e.offset));
+        }
+    }
+
+    #[test]
+    fn boundary_sizes() {
+        for &token_size in &[0_u16, 1, 0xffff] {
+            for &id_size in &[0_u16, 1, 0xffff] {
+                let buf: Vec<_> = token_size.to_bytes()
+                                            .into_copy_iter()
+                                            .chain(iter::repeat(b't').take(token_size as usize))
+                                            .chain(id_size.to_bytes().into_copy_iter())
+                                            .chain(iter::repeat(b'i').take(id_size as usize))
+                                            .chain(1_u64.to_bytes().into_copy_iter())
+                                            .collect();
+                let (token_end, id_end) = (2 + token_size as usize,
+                                           4 + token_size as usize + id_size as usize);
+                let parts = vec![(2, Part::TokenSize),
+                                 (token_end, Part::Token(token_size)),
+                                 (token_end + 2, Part::IdSize),
+                                 (id_end, Part::Id(id_size)),
+                                 (id_end + 8, Part::Timestamp)];
+                let mut start = 0;
+                for (end, part) in parts {
+                    if end > start {
+                        let expected = Err(Error {
+                            remaining: (end - 1 - start) as u16,
+                            part: part,
+                            offset: start,
+                            diagnostic: None,
+                        });
+                        assert_eq!(expected, Header::parse_in::<u32>(&buf[..end - 1]));
+                        assert_eq!(expected, Header::parse_in::<u64>(&buf[..end - 1]));
+                    }
+                    start = end;
+                }
+                assert!(Header::parse_in::<u32>(&buf).is_ok());
+                assert_eq!(Header::parse_in::<u32>(&buf), Header::parse_in::<u64>(&buf));
+            }
+        }
+    }
+
+    #[test]
+    fn longer_than_u16() {
+        let mut buf = b"\x00\x05token\x00\x02id\x00\x00\x00\x00\x00\x00\x00\x01".to_vec();
+        // Long enough that its length, cut to 16 bits, is 3.
+        buf.resize(0x10003, 0);
+        let (header, payload) = Header::parse(&buf).unwrap();
+        assert_eq!(b"token", header.token);
+        assert_eq!(0x10003 - 19, payload.len());
+    }
+
+    quickcheck_test! {
+    parse_never_panics(bytes: Vec<u8>; bool) {
+        match Header::parse(&bytes) {
+            Ok((header, rest)) => header.encoded_len() + rest.len() == bytes.len(),
+            Err(e) => e.remaining < e.part.size() && e.remaining as usize <= bytes.len(),
+        }
+    }}
+
+    quickcheck_test! {
+    parse_alike_in_either_width(bytes: Vec<u8>, sizes: (u16, u16); bool) {
+        // Sizes up to the limit, so that long parts are parsed too.
+        let mut framed = sizes.0.to_bytes().into_copy_iter().collect::<Vec<_>>();
+        framed.extend(iter::repeat(b't').take(sizes.0 as usize));
+        framed.extend(sizes.1.to_bytes().into_copy_iter());
+        framed.extend(bytes.iter().cloned().cycle().take(sizes.1 as usize + 8));
+        [&bytes[..], &framed[..]].iter().all(|bytes| {
+            Header::parse_in::<u32>(bytes) == Header::parse_in::<u64>(bytes) &&
+            Header::parse_in::<u32>(bytes) == Header::parse(bytes)
+        })
+    }}
+
+    #[test]
+    fn diagnostic_windows() {
+        let full = b"\x00\x03tok\x00\x02id\x00\x00\x00\x00\x00\x00\x00\x01";
+        let cases: Vec<(usize, Part, usize, &[u8])> = vec![
+            (1, Part::TokenSize, 0, b"\x00"),
+            (3, Part::Token(3), 0, b"\x00\x03t"),
+            (6, Part::IdSize, 2, b"tok\x00"),
+            (8, Part::Id(2), 4, b"k\x00\x02i"),
+            (16, Part::Timestamp, 7, b"id\x00\x00"),
+        ];
+        for (len, part, offset, bytes) in cases {
+            let e = Header::parse_diagnostic(&full[..len], 4).unwrap_err();
+            assert_eq!(part, e.part);
+            assert_eq!(Some(DiagnosticWindow {
+                           offset: offset,
+                           bytes: bytes.to_vec(),
+                       }),
+                       e.diagnostic);
+            assert_eq!(None, Header::parse_diagnostic(&full[..len], 0).unwrap_err().diagnostic);
+        }
+    }
+
+    quickcheck_test! {
+    diagnostic_window_bounded(input: Vec<u8>, at: usize, max: usize; bool) {
+        let at = if input.is_empty() { 0 } else { at % (input.len() + 1) };
+        let window = DiagnosticWindow::around(&input, at, max);
+        window.bytes.len() <= max &&
+        window.bytes.len() == ::std::cmp::min(max, input.len()) &&
+        &input[window.offset..window.offset + window.bytes.len()] == &window.bytes[..]
+    }}
 }
@@ -0,0 +1,912 @@
+//! One JSON form for messages, for tooling that does not speak the wire
+//! format. A message is an object with these fields, written in this order:
+//!
+//! | field          | value                                               |
+//! |----------------|-----------------------------------------------------|
+//! | `token`        | base64                                              |
+//! | `id`           | base64                                              |
+//! | `timestamp_ms` | integer milliseconds since the Unix epoch           |
+//! | `attributes`   | optional; an object of base64 values by key         |
+//! | `payload`      | base64                                              |
+//!
+//! ```text
+//! {"token":"dA==","id":"Y2Ft","timestamp_ms":1500,"payload":"aGk="}
+//! ```
+//!
+//! Base64 is the standard alphabet with padding, as in section 4 of RFC
+//! 4648, and is read only in that one form. Fields may come in any order
+//! when read, but not twice, and a field this format does not define is
+//! refused unless reading leniently.
+//!
+//! Payloads are opaque unless `JsonOptions::attributes` says they may
+//! start with extensions. Then a payload whose extensions are an attributes
+//! section alone, in canonical form and with UTF-8 keys, is written as
+//! `attributes` and the rest of the payload; any other is written whole.
+//! Reading `attributes` puts that section back before the payload, so
+//! either way the message read is the message written.
+//!
+//! A capture, frames back to back each after its two-byte length, converts
+//! to newline-delimited JSON, a message a line, in frame order, and back.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use util::millis;
+use super::extension::ATTRS_PRESENT;
+use super::wire::FrameAt;
+use super::{wire, AttributeLimits, Attributes, Error, ExtensionRegistry, Extensions, Message,
+            MessageBuf, WriteIntoError};
+
+/// How deeply values may nest, so that hostile input cannot exhaust the
+/// stack.
+const MAX_DEPTH: usize = 32;
+
+const ALPHABET: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                      abcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JsonOptions {
+    /// Whether payloads may start with extensions, so that attributes are
+    /// written as an object where nothing is lost by it.
+    pub attributes: bool,
+    /// Whether reading ignores fields this format does not define instead
+    /// of refusing the message.
+    pub lenient: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JsonError {
+    /// Not JSON, or not an object, from byte `offset`.
+    Syntax {
+        offset: usize,
+    },
+    UnknownField(String),
+    MissingField(&'static str),
+    /// A field or attribute given twice, attributes as `attributes.key`.
+    DuplicateField(String),
+    WrongType(String),
+    /// A field or attribute that is not base64 in its one form.
+    Base64(String),
+    /// More than the wire format can hold, of a token, an ID, attributes,
+    /// or for a capture, the whole message.
+    TooLarge(WriteIntoError),
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            JsonError::Syntax { offset } => write!(f, "invalid JSON at byte {}", offset),
+            JsonError::UnknownField(ref field) => write!(f, "unknown field {:?}", field),
+            JsonError::MissingField(field) => write!(f, "missing field {:?}", field),
+            JsonError::DuplicateField(ref field) => write!(f, "duplicate field {:?}", field),
+            JsonError::WrongType(ref field) => write!(f, "field {:?} of the wrong type", field),
+            JsonError::Base64(ref field) => write!(f, "field {:?} is not base64", field),
+            JsonError::TooLarge(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for JsonError {
+    fn description(&self) -> &str {
+        match *self {
+            JsonError::Syntax { .. } => "invalid JSON",
+            JsonError::UnknownField(_) => "unknown field",
+            JsonError::MissingField(_) => "missing field",
+            JsonError::DuplicateField(_) => "duplicate field",
+            JsonError::WrongType(_) => "field of the wrong type",
+            JsonError::Base64(_) => "field is not base64",
+            JsonError::TooLarge(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            JsonError::TooLarge(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where a capture could not be converted.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    /// The capture ends within frame `frame`, counting from zero, which
+    /// starts at `offset`.
+    Truncated {
+        frame: usize,
+        offset: usize,
+    },
+    Parse {
+        frame: usize,
+        offset: usize,
+        error: Error,
+    },
+}
+
+impl Display for CaptureError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CaptureError::Truncated { frame, offset } => {
+                write!(f, "capture ends within frame {} at offset {}", frame, offset)
+            }
+            CaptureError::Parse { frame, offset, ref error } => {
+                write!(f, "frame {} at offset {}: {}", frame, offset, error)
+            }
+        }
+    }
+}
+
+impl error::Error for CaptureError {
+    fn description(&self) -> &str {
+        match *self {
+            CaptureError::Truncated { .. } => "capture ends within a frame",
+            CaptureError::Parse { .. } => "frame does not parse",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            CaptureError::Truncated { .. } => None,
+            CaptureError::Parse { ref error, .. } => Some(error),
+        }
+    }
+}
+
+/// A line of newline-delimited JSON that could not be read.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LineError {
+    /// Counting from one.
+    pub line: usize,
+    pub error: JsonError,
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl error::Error for LineError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.error)
+    }
+}
+
+fn push_base64(bytes: &[u8], out: &mut String) {
+    out.push('"');
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out.push('"');
+}
+
+/// `None` unless `s` is what `push_base64` writes for some bytes.
+fn from_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if s.len() % 4 != 0 {
+        return None;
+    }
+    let quads = s.len() / 4;
+    let mut bytes = Vec::with_capacity(quads * 3);
+    for (i, quad) in s.chunks(4).enumerate() {
+        let pad = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && i + 1 < quads) {
+            return None;
+        }
+        let mut n = 0;
+        for &c in &quad[..4 - pad] {
+            match ALPHABET.iter().position(|&a| a == c) {
+                None => return None,
+                Some(sextet) => n = n << 6 | sextet as u32,
+            }
+        }
+        n <<= 6 * pad;
+        // The bits after the last byte must be zero, for there to be one
+        // form.
+        if n & ((1 << (8 * pad)) - 1) != 0 {
+            return None;
+        }
+        bytes.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - pad]);
+    }
+    Some(bytes)
+}
+
+fn push_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// The attributes a payload starts with, if written as an object they
+/// would be read back into the same bytes, and what follows them.
+fn split_attributes(payload: &[u8]) -> Option<(Vec<(&str, &[u8])>, &[u8])> {
+    let (extensions, rest) = match Extensions::parse(payload, &ExtensionRegistry::new()) {
+        Ok(parsed) => parsed,
+        Err(_) => return None,
+    };
+    let section = match extensions.attrs {
+        Some(section) if extensions.flags() == ATTRS_PRESENT => section,
+        _ => return None,
+    };
+    let attributes = match Attributes::parse(section, &AttributeLimits::unlimited()) {
+        Ok(attributes) => attributes,
+        Err(_) => return None,
+    };
+    if attributes.to_bytes() != section {
+        return None;
+    }
+    let mut split = Vec::with_capacity(attributes.len());
+    for &(key, value) in attributes.iter() {
+        match ::std::str::from_utf8(key) {
+            Ok(key) => split.push((key, value)),
+            Err(_) => return None,
+        }
+    }
+    Some((split, rest))
+}
+
+/// `msg` as JSON, its payload opaque.
+pub fn to_json(msg: &Message) -> String {
+    to_json_with(msg, &JsonOptions::default())
+}
+
+/// `msg` as JSON, on one line. Timestamps count whole milliseconds, as on
+/// the wire.
+pub fn to_json_with(msg: &Message, options: &JsonOptions) -> String {
+    let header = &msg.header;
+    let mut out = String::with_capacity(80 + (header.token.len() + header.id.len() +
+                                              msg.payload.len()) * 4 / 3);
+    out.push_str("{\"token\":");
+    push_base64(header.token, &mut out);
+    out.push_str(",\"id\":");
+    push_base64(header.id, &mut out);
+    out.push_str(",\"timestamp_ms\":");
+    out.push_str(&millis(header.timestamp).to_string());
+    let mut payload = msg.payload;
+    if let Some((attributes, rest)) = if options.attributes {
+        split_attributes(payload)
+    } else {
+        None
+    } {
+        out.push_str(",\"attributes\":{");
+        for (i, &(key, value)) in attributes.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            push_string(key, &mut out);
+            out.push(':');
+            push_base64(value, &mut out);
+        }
+        out.push('}');
+        payload = rest;
+    }
+    out.push_str(",\"payload\":");
+    push_base64(payload, &mut out);
+    out.push('}');
+    out
+}
+
+/// A value read, as much of it as this format needs.
+enum Value {
+    Str(String),
+    /// As written, to read `timestamp_ms` exactly.
+    Num(String),
+    Obj(Vec<(String, Value)>),
+    /// An array, `true`, `false` or `null`, which are only ever ignored.
+    Other,
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> JsonError {
+        JsonError::Syntax { offset: self.at }
+    }
+
+    /// The next byte that is not whitespace, without taking it.
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(&c) = self.bytes.get(self.at) {
+            match c {
+                b' ' | b'\t' | b'\n' | b'\r' => self.at += 1,
+                c => return Some(c),
+            }
+        }
+        None
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        if self.peek() == Some(c) {
+            self.at += 1;
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error());
+        }
+        match self.peek() {
+            Some(b'{') => self.object(depth).map(Value::Obj),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(Value::Str),
+            Some(b't') => self.literal("true"),
+            Some(b'f') => self.literal("false"),
+            Some(b'n') => self.literal("null"),
+            Some(c) if c == b'-' || (c as char).is_digit(10) => self.number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Vec<(String, Value)>, JsonError> {
+        try!(self.expect(b'{'));
+        let mut fields = vec![];
+        if self.peek() == Some(b'}') {
+            self.at += 1;
+            return Ok(fields);
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error());
+            }
+            let key = try!(self.string());
+            try!(self.expect(b':'));
+            fields.push((key, try!(self.value(depth + 1))));
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(b'}') => {
+                    self.at += 1;
+                    return Ok(fields);
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, JsonError> {
+        try!(self.expect(b'['));
+        if self.peek() == Some(b']') {
+            self.at += 1;
+            return Ok(Value::Other);
+        }
+        loop {
+            try!(self.value(depth + 1));
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(b']') => {
+                    self.at += 1;
+                    return Ok(Value::Other);
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn literal(&mut self, literal: &str) -> Result<Value, JsonError> {
+        if self.bytes[self.at..].starts_with(literal.as_bytes()) {
+            self.at += literal.len();
+            Ok(Value::Other)
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.at;
+        while self.bytes.get(self.at).map_or(false, |&c| (c as char).is_digit(10)) {
+            self.at += 1;
+        }
+        self.at - start
+    }
+
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let start = self.at;
+        if self.bytes[self.at] == b'-' {
+            self.at += 1;
+        }
+        let leading_zero = self.bytes.get(self.at) == Some(&b'0');
+        match self.digits() {
+            0 => return Err(self.error()),
+            n if n > 1 && leading_zero => return Err(JsonError::Syntax { offset: start }),
+            _ => {}
+        }
+        if self.bytes.get(self.at) == Some(&b'.') {
+            self.at += 1;
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+        }
+        if let Some(&b'e') | Some(&b'E') = self.bytes.get(self.at) {
+            self.at += 1;
+            if let Some(&b'+') | Some(&b'-') = self.bytes.get(self.at) {
+                self.at += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+        }
+        Ok(Value::Num(String::from_utf8_lossy(&self.bytes[start..self.at]).into_owned()))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut n = 0;
+        for _ in 0..4 {
+            match self.bytes.get(self.at).and_then(|&c| (c as char).to_digit(16)) {
+                None => return Err(self.error()),
+                Some(d) => n = n << 4 | d,
+            }
+            self.at += 1;
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        try!(self.expect(b'"'));
+        let mut bytes = vec![];
+        loop {
+            let c = match self.bytes.get(self.at) {
+                None => return Err(self.error()),
+                Some(&c) => c,
+            };
+            match c {
+                b'"' => {
+                    self.at += 1;
+                    // The input is a `str`, and escapes are of whole chars.
+                    return Ok(String::from_utf8(bytes).unwrap());
+                }
+                b'\\' => {
+                    let escape = self.at;
+                    self.at += 1;
+                    let c = match self.bytes.get(self.at) {
+                        None => return Err(self.error()),
+                        Some(&c) => c,
+                    };
+                    self.at += 1;
+                    let c = match c {
+                        b'"' | b'\\' | b'/' => c as char,
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut n = try!(self.hex4());
+                            if n >= 0xd800 && n < 0xdc00 {
+                                try!(self.expect(b'\\'));
+                                if self.bytes.get(self.at) != Some(&b'u') {
+                                    return Err(self.error());
+                                }
+                                self.at += 1;
+                                let low = try!(self.hex4());
+                                if low < 0xdc00 || low >= 0xe000 {
+                                    return Err(JsonError::Syntax { offset: escape });
+                                }
+                                n = 0x10000 + ((n - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            match ::std::char::from_u32(n) {
+                                None => return Err(JsonError::Syntax { offset: escape }),
+                                Some(c) => c,
+                            }
+                        }
+                        _ => return Err(JsonError::Syntax { offset: escape }),
+                    };
+                    bytes.extend_from_slice(c.to_string().as_bytes());
+                }
+                c if c < 0x20 => return Err(self.error()),
+                c => {
+                    bytes.push(c);
+                    self.at += 1;
+                }
+            }
+        }
+    }
+}
+
+fn set<T>(slot: &mut Option<T>, field: &str, value: T) -> Result<(), JsonError> {
+    if slot.is_some() {
+        return Err(JsonError::DuplicateField(field.to_owned()));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+fn base64_field(field: &str, value: Value) -> Result<Vec<u8>, JsonError> {
+    match value {
+        Value::Str(s) => from_base64(&s).ok_or_else(|| JsonError::Base64(field.to_owned())),
+        _ => Err(JsonError::WrongType(field.to_owned())),
+    }
+}
+
+/// The attributes section holding `attributes`, in key order.
+fn attributes_section(mut attributes: Vec<(String, Vec<u8>)>) -> Result<Vec<u8>, JsonError> {
+    attributes.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    let too_large = |len| {
+        JsonError::TooLarge(WriteIntoError::ExtensionTooLarge {
+            flag: ATTRS_PRESENT,
+            len: len,
+        })
+    };
+    if attributes.len() > u8::max_value() as usize {
+        return Err(too_large(attributes.len()));
+    }
+    let mut section = vec![attributes.len() as u8];
+    for (key, value) in attributes {
+        if key.len() > u8::max_value() as usize {
+            return Err(too_large(key.len()));
+        }
+        if value.len() > u16::max_value() as usize {
+            return Err(too_large(value.len()));
+        }
+        section.push(key.len() as u8);
+        section.extend_from_slice(key.as_bytes());
+        let mut len = [0; 2];
+        BigEndian::write_u16(&mut len, value.len() as u16);
+        section.extend_from_slice(&len);
+        section.extend(value);
+    }
+    Ok(section)
+}
+
+/// Reads a message as `to_json` writes it, strictly.
+pub fn from_json(json: &str) -> Result<MessageBuf, JsonError> {
+    from_json_with(json, &JsonOptions::default())
+}
+
+/// `options.attributes` does not matter here, as `attributes` is read
+/// whenever it is given.
+pub fn from_json_with(json: &str, options: &JsonOptions) -> Result<MessageBuf, JsonError> {
+    let mut parser = Parser {
+        bytes: json.as_bytes(),
+        at: 0,
+    };
+    if parser.peek() != Some(b'{') {
+        return Err(parser.error());
+    }
+    let fields = try!(parser.object(0));
+    if parser.peek().is_some() {
+        return Err(parser.error());
+    }
+
+    let (mut token, mut id, mut timestamp, mut attributes, mut payload) =
+        (None, None, None, None, None);
+    for (field, value) in fields {
+        match &field[..] {
+            "token" => try!(set(&mut token, &field, try!(base64_field(&field, value)))),
+            "id" => try!(set(&mut id, &field, try!(base64_field(&field, value)))),
+            "payload" => try!(set(&mut payload, &field, try!(base64_field(&field, value)))),
+            "timestamp_ms" => {
+                let millis = match value {
+                    Value::Num(ref n) if n.bytes().all(|c| (c as char).is_digit(10)) => {
+                        n.parse::<u64>().ok()
+                    }
+                    _ => None,
+                };
+                match millis {
+                    None => return Err(JsonError::WrongType(field)),
+                    Some(millis) => try!(set(&mut timestamp, &field, millis)),
+                }
+            }
+            "attributes" => {
+                let entries = match value {
+                    Value::Obj(entries) => entries,
+                    _ => return Err(JsonError::WrongType(field)),
+                };
+                let mut read: Vec<(String, Vec<u8>)> = Vec::with_capacity(entries.len());
+                for (key, value) in entries {
+                    let name = format!("attributes.{}", key);
+                    if read.iter().any(|&(ref k, _)| *k == key) {
+                        return Err(JsonError::DuplicateField(name));
+                    }
+                    let value = try!(base64_field(&name, value));
+                    read.push((key, value));
+                }
+                try!(set(&mut attributes, &field, read));
+            }
+            _ if options.lenient => {}
+            _ => return Err(JsonError::UnknownField(field)),
+        }
+    }
+
+    let token = try!(token.ok_or(JsonError::MissingField("token")));
+    let id = try!(id.ok_or(JsonError::MissingField("id")));
+    let timestamp = try!(timestamp.ok_or(JsonError::MissingField("timestamp_ms")));
+    let mut payload = try!(payload.ok_or(JsonError::MissingField("payload")));
+    if token.len() > u16::max_value() as usize {
+        return Err(JsonError::TooLarge(WriteIntoError::TokenTooLarge(token.len())));
+    }
+    if id.len() > u16::max_value() as usize {
+        return Err(JsonError::TooLarge(WriteIntoError::IdTooLarge(id.len())));
+    }
+    if let Some(attributes) = attributes {
+        let section = try!(attributes_section(attributes));
+        let extensions = Extensions { attrs: Some(&section), ..Extensions::default() };
+        let mut bytes = vec![0; extensions.encoded_len()];
+        try!(extensions.write_into(&mut bytes).map_err(JsonError::TooLarge));
+        bytes.extend(payload);
+        payload = bytes;
+    }
+    Ok(MessageBuf {
+        token: token,
+        id: id,
+        timestamp: Duration::from_millis(timestamp),
+        payload: payload,
+    })
+}
+
+/// Each frame of `capture` as a line of JSON, in order, each line ending
+/// in a newline.
+pub fn capture_to_ndjson(capture: &[u8], options: &JsonOptions) -> Result<String, CaptureError> {
+    let mut ndjson = String::new();
+    let mut offset = 0;
+    let mut frame = 0;
+    while offset < capture.len() {
+        let (message, next) = match wire::frame_at(capture, offset) {
+            FrameAt::Whole { message, next } => (message, next),
+            _ => {
+                return Err(CaptureError::Truncated {
+                    frame: frame,
+                    offset: offset,
+                })
+            }
+        };
+        let msg = try!(Message::parse(&capture[message]).map_err(|e| {
+            CaptureError::Parse {
+                frame: frame,
+                offset: offset,
+                error: e,
+            }
+        }));
+        ndjson.push_str(&to_json_with(&msg, options));
+        ndjson.push('\n');
+        offset = next;
+        frame += 1;
+    }
+    Ok(ndjson)
+}
+
+/// The capture whose frames are the lines of `ndjson`, in order, blank
+/// lines aside. If any line cannot be read or framed, returns the error of
+/// every one that cannot.
+pub fn ndjson_to_capture(ndjson: &str, options: &JsonOptions) -> Result<Vec<u8>, Vec<LineError>> {
+    let mut capture = vec![];
+    let mut errors = vec![];
+    for (i, line) in ndjson.split('\n').enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let framed = from_json_with(line, options).and_then(|msg| {
+            let msg = msg.as_message();
+            let mut frame = vec![0; 2 + msg.header.encoded_len() + msg.payload.len()];
+            msg.write_frame_into(&mut frame).map(|_| frame).map_err(JsonError::TooLarge)
+        });
+        match framed {
+            Ok(frame) => capture.extend(frame),
+            Err(e) => {
+                errors.push(LineError {
+                    line: i + 1,
+                    error: e,
+                })
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(capture)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use super::*;
+    use message::{Header, Message, MessageBuf, WriteIntoError};
+    use test_support::*;
+
+    fn msg<'a>(token: &'a [u8], id: &'a [u8], millis: u64, payload: &'a [u8]) -> Message<'a> {
+        Message {
+            header: Header {
+                token: token,
+                id: id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: payload,
+        }
+    }
+
+    /// An attributes section alone, then `rest`.
+    fn with_attributes(attributes: &[(&str, &[u8])], rest: &[u8]) -> Vec<u8> {
+        let mut section = vec![attributes.len() as u8];
+        for &(key, value) in attributes {
+            section.push(key.len() as u8);
+            section.extend_from_slice(key.as_bytes());
+            section.extend_from_slice(&[(value.len() >> 8) as u8, value.len() as u8]);
+            section.extend_from_slice(value);
+        }
+        let mut payload = vec![0, 2, (section.len() >> 8) as u8, section.len() as u8];
+        payload.extend(section);
+        payload.extend_from_slice(rest);
+        payload
+    }
+
+    const ATTRIBUTES: JsonOptions = JsonOptions {
+        attributes: true,
+        lenient: false,
+    };
+
+    #[test]
+    fn pins_field_names_and_encodings() {
+        let plain = msg(b"t", b"cam", 1500, b"hi");
+        let json = r#"{"token":"dA==","id":"Y2Ft","timestamp_ms":1500,"payload":"aGk="}"#;
+        assert_eq!(json, to_json(&plain));
+        assert_eq!(Ok(MessageBuf::from(plain)), from_json(json));
+
+        let payload = with_attributes(&[("k", b"v"), ("z\"", b"")], b"hi");
+        let tagged = msg(b"", b"", ::std::u64::MAX, &payload);
+        assert_eq!(r#"{"token":"","id":"","timestamp_ms":18446744073709551615,"#.to_owned() +
+                   r#""attributes":{"k":"dg==","z\"":""},"payload":"aGk="}"#,
+                   to_json_with(&tagged, &ATTRIBUTES));
+        assert_eq!(r#"{"token":"","id":"","timestamp_ms":18446744073709551615,"#.to_owned() +
+                   r#""payload":"AAIACwIBawABdgJ6IgAAaGk="}"#,
+                   to_json(&tagged));
+
+        // Section 10 of RFC 4648.
+        for &(bytes, encoded) in &[(&b""[..], ""),
+                                   (b"f", "Zg=="),
+                                   (b"fo", "Zm8="),
+                                   (b"foo", "Zm9v"),
+                                   (b"foob", "Zm9vYg=="),
+                                   (b"fooba", "Zm9vYmE="),
+                                   (b"foobar", "Zm9vYmFy")] {
+            let mut out = String::new();
+            push_base64(bytes, &mut out);
+            assert_eq!(format!("\"{}\"", encoded), out);
+            assert_eq!(Some(bytes.to_vec()), from_base64(encoded));
+        }
+    }
+
+    #[test]
+    fn refuses_all_but_one_form() {
+        let field = |json: &str| format!(r#"{{"token":"","id":"","payload":"",{}}}"#, json);
+        assert!(from_json(&field(r#""timestamp_ms":0"#)).is_ok());
+        for (json, expected) in
+            vec![(r#""timestamp_ms":0,"extra":[1,{"a":null}]"#,
+               JsonError::UnknownField("extra".to_owned())),
+              (r#""timestamp_ms":0,"id":"""#, JsonError::DuplicateField("id".to_owned())),
+              (r#""timestamp_ms":1.5"#, JsonError::WrongType("timestamp_ms".to_owned())),
+              (r#""timestamp_ms":-1"#, JsonError::WrongType("timestamp_ms".to_owned())),
+              (r#""timestamp_ms":18446744073709551616"#,
+               JsonError::WrongType("timestamp_ms".to_owned())),
+              (r#""timestamp_ms":"0""#, JsonError::WrongType("timestamp_ms".to_owned())),
+              (r#""nothing":0"#, JsonError::UnknownField("nothing".to_owned())),
+              (r#""timestamp_ms":0,"attributes":{"k":"Zh=="}"#,
+               JsonError::Base64("attributes.k".to_owned())),
+              (r#""timestamp_ms":0,"attributes":{"k":"","k":""}"#,
+               JsonError::DuplicateField("attributes.k".to_owned())),
+              (r#""timestamp_ms":0,"attributes":[]"#,
+               JsonError::WrongType("attributes".to_owned()))] {
+            assert_eq!(Err(expected), from_json(&field(json)).map(|_| ()), "{}", json);
+        }
+
+        let lenient = JsonOptions { lenient: true, ..JsonOptions::default() };
+        assert!(from_json_with(&field(r#""timestamp_ms":0,"extra":[true,-1e5]"#), &lenient)
+                    .is_ok());
+        assert_eq!(Err(JsonError::MissingField("timestamp_ms")),
+                   from_json_with(&field(r#""extra":1"#), &lenient).map(|_| ()));
+
+        for &(base64, ok) in &[("Zg==", true), ("Zg", false), ("Zh==", false), ("Zg=a", false),
+                               ("Z===", false), ("Zg==Zg==", false), ("Zm 9v", false)] {
+            assert_eq!(ok, from_base64(base64).is_some(), "{}", base64);
+        }
+
+        for &(json, offset) in &[("", 0),
+                                 ("[]", 0),
+                                 (r#"{"token":"""#, 11),
+                                 (r#"{"token":01}"#, 9),
+                                 (r#"{"token":"\x"}"#, 10),
+                                 (r#"{"token":""} {}"#, 13),
+                                 (r#"{"token": tru}"#, 10)] {
+            assert_eq!(Err(JsonError::Syntax { offset: offset }),
+                       from_json(json).map(|_| ()),
+                       "{}",
+                       json);
+        }
+        let nested = "[".repeat(100) + &"]".repeat(100);
+        assert_match!(Err(JsonError::Syntax { .. }),
+                      from_json_with(&field(&format!(r#""timestamp_ms":0,"x":{}"#, nested)),
+                                     &lenient));
+
+        let long = format!(r#"{{"token":"{}","id":"","timestamp_ms":0,"payload":""}}"#,
+                           "AAAA".repeat(21846));
+        assert_eq!(Err(JsonError::TooLarge(WriteIntoError::TokenTooLarge(65538))),
+                   from_json(&long).map(|_| ()));
+    }
+
+    quickcheck_test! {
+    message_round_trips(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>,
+                        attributes: Vec<(String, Vec<u8>)>; bool) {
+        let attributes: BTreeMap<_, _> = attributes.into_iter()
+                                                   .filter(|&(ref key, _)| key.len() < 256)
+                                                   .collect();
+        let attributes: Vec<_> = attributes.iter()
+                                           .map(|(key, value)| (&key[..], &value[..]))
+                                           .collect();
+        let tagged = with_attributes(&attributes, &payload);
+        [&payload, &tagged].iter().all(|payload| {
+            let msg = msg(&token, &id, millis, payload);
+            let expected = Ok(MessageBuf::from(msg.clone()));
+            from_json(&to_json(&msg)) == expected &&
+            from_json(&to_json_with(&msg, &ATTRIBUTES)) == expected
+        }) && to_json_with(&msg(&token, &id, millis, &tagged), &ATTRIBUTES).contains("attributes")
+    }}
+
+    quickcheck_test! {
+    capture_round_trips(frames: Vec<(Vec<u8>, Vec<u8>, u64, Vec<u8>)>; bool) {
+        let capture: Vec<u8> = frames.iter()
+                                     .flat_map(|&(ref token, ref id, millis, ref payload)| {
+                                         frame(token, id, millis, payload)
+                                     })
+                                     .collect();
+        let ndjson = capture_to_ndjson(&capture, &ATTRIBUTES).unwrap();
+        ndjson.lines().count() == frames.len() &&
+        ndjson_to_capture(&ndjson, &ATTRIBUTES) == Ok(capture)
+    }}
+
+    #[test]
+    fn reports_lines_and_frames() {
+        let good = to_json(&msg(b"t", b"i", 1, b"x"));
+        let ndjson = format!("{}\n{{}}\n\n{}\r\n{{\"token\":\n", good, good);
+        let errors = ndjson_to_capture(&ndjson, &JsonOptions::default()).unwrap_err();
+        assert_eq!(vec![(2, JsonError::MissingField("token")),
+                        (5, JsonError::Syntax { offset: 9 })],
+                   errors.into_iter().map(|e| (e.line, e.error)).collect::<Vec<_>>());
+
+        let huge = format!(r#"{{"token":"","id":"","timestamp_ms":0,"payload":"{}"}}"#,
+                           "AAAA".repeat(21842));
+        assert_eq!(Err(vec![LineError {
+                       line: 1,
+                       error: JsonError::TooLarge(WriteIntoError::MessageTooLarge(65538)),
+                   }]),
+                   ndjson_to_capture(&huge, &JsonOptions::default()));
+
+        let mut capture = frame(b"t", b"i", 1, b"x");
+        capture.extend_from_slice(&[0, 3, 0, 0, 0]);
+        let second = capture.len() - 5;
+        assert_match!(Err(CaptureError::Parse { frame: 1, offset, .. }) if offset == second,
+                      capture_to_ndjson(&capture, &JsonOptions::default()));
+        capture.truncate(second + 1);
+        assert_eq!(Err(CaptureError::Truncated {
+                       frame: 1,
+                       offset: second,
+                   }),
+                   capture_to_ndjson(&capture, &JsonOptions::default()));
+    }
+}
@@ -1,9 +1,257 @@
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use util::millis;
+
+pub use self::compat::{parse_any, detect_version, CompatError, CompatPolicy, SourceVersion,
+                       VersionGuess, VersionedMessage};
+pub use self::cost::{CostField, MessageCost};
+pub use self::extension::{AttributeLimits, Attributes, AttributesSection, ExtensionError,
+                          ExtensionRegistry, Extensions};
 pub use self::header::Header;
-pub use self::header::Error;
+pub use self::header::{DiagnosticWindow, Error, WriteIntoError};
+pub use self::header::{Nonconformance, Strictness};
+pub use self::scan::scan_frames;
 
+pub mod compat;
+pub mod cost;
+pub mod extension;
 pub mod header;
+pub mod json;
+pub mod payload;
+pub mod scan;
+pub mod wire;
+
+/// How to put messages in canonical form.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CanonicalOptions<'r> {
+    /// If payloads start with extensions, how to read them, so that they
+    /// are put in canonical form too. Otherwise payloads are opaque.
+    pub extensions: Option<&'r ExtensionRegistry>,
+}
 
 #[derive(Debug, PartialEq, Eq)]
+pub enum CanonicalError {
+    Encode(WriteIntoError),
+    Extensions(ExtensionError),
+}
+
+impl Display for CanonicalError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CanonicalError::Encode(ref e) => e.fmt(f),
+            CanonicalError::Extensions(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for CanonicalError {
+    fn description(&self) -> &str {
+        match *self {
+            CanonicalError::Encode(ref e) => e.description(),
+            CanonicalError::Extensions(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            CanonicalError::Encode(ref e) => Some(e),
+            CanonicalError::Extensions(ref e) => Some(e),
+        }
+    }
+}
+
+/// Why a message could not be framed.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The message, of this many bytes, is longer than its frame prefix can
+    /// say.
+    TooLarge(usize),
+    Io(io::Error),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            FrameError::TooLarge(len) => write!(f, "message of {} bytes too large to frame", len),
+            FrameError::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for FrameError {
+    fn description(&self) -> &str {
+        match *self {
+            FrameError::TooLarge(_) => "message too large to frame",
+            FrameError::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            FrameError::TooLarge(_) => None,
+            FrameError::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<FrameError> for io::Error {
+    /// `InvalidInput` for a message too large.
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::TooLarge(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            FrameError::Io(e) => e,
+        }
+    }
+}
+
+/// How a frame's length prefix is written: a big-endian size, of two
+/// bytes unless messages may be longer than 64 KiB less one, or an
+/// unsigned LEB128 varint of as few bytes as the size needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Framing {
+    U16,
+    U32,
+    /// Seven bits a byte, least significant first, each byte but the last
+    /// with its high bit set; at most `MAX_VARINT_LEN` bytes, and no more
+    /// than a size needs.
+    Varint,
+}
+
+/// The longest varint prefix, which holds any `u32`.
+pub const MAX_VARINT_LEN: usize = 5;
+
+/// What the start of a length prefix says, as far as it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefix {
+    /// At least this many more bytes are needed to tell.
+    Partial(usize),
+    /// A size, and how many bytes of prefix said it.
+    Whole {
+        size: usize,
+        len: usize,
+    },
+    /// A varint longer than `MAX_VARINT_LEN`, over a `u32`, or padded
+    /// with bytes it does not need.
+    BadVarint,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::U16
+    }
+}
+
+impl Framing {
+    /// The longest prefix, that a reader may need to hold.
+    pub fn max_prefix_len(self) -> usize {
+        match self {
+            Framing::U16 => 2,
+            Framing::U32 => 4,
+            Framing::Varint => MAX_VARINT_LEN,
+        }
+    }
+
+    /// How many bytes the prefix for `size` takes.
+    pub fn prefix_len(self, size: usize) -> usize {
+        match self {
+            Framing::Varint => {
+                let mut len = 1;
+                let mut rest = size >> 7;
+                while rest > 0 {
+                    len += 1;
+                    rest >>= 7;
+                }
+                len
+            }
+            _ => self.max_prefix_len(),
+        }
+    }
+
+    /// The longest message a prefix can say.
+    pub fn max_size(self) -> usize {
+        match self {
+            Framing::U16 => u16::max_value() as usize,
+            Framing::U32 | Framing::Varint => u32::max_value() as usize,
+        }
+    }
+
+    /// Reads a prefix from the start of `bytes`.
+    pub fn decode(self, bytes: &[u8]) -> Prefix {
+        let len = self.max_prefix_len();
+        match self {
+            Framing::U16 | Framing::U32 if bytes.len() < len => Prefix::Partial(len - bytes.len()),
+            Framing::U16 => {
+                Prefix::Whole {
+                    size: BigEndian::read_u16(bytes) as usize,
+                    len: len,
+                }
+            }
+            Framing::U32 => {
+                Prefix::Whole {
+                    size: BigEndian::read_u32(bytes) as usize,
+                    len: len,
+                }
+            }
+            Framing::Varint => {
+                let mut size = 0_u64;
+                for (i, &byte) in bytes.iter().take(len).enumerate() {
+                    size |= ((byte & 0x7f) as u64) << (7 * i);
+                    if byte & 0x80 != 0 {
+                        continue;
+                    }
+                    if size > u32::max_value() as u64 || (byte == 0 && i > 0) {
+                        return Prefix::BadVarint;
+                    }
+                    return Prefix::Whole {
+                        size: size as usize,
+                        len: i + 1,
+                    };
+                }
+                if bytes.len() >= len {
+                    Prefix::BadVarint
+                } else {
+                    Prefix::Partial(1)
+                }
+            }
+        }
+    }
+
+    /// Writes the prefix for `size`, at most `max_size`, to the start of
+    /// `buf`, returning how many bytes it took.
+    pub fn write_size(self, buf: &mut [u8], size: usize) -> usize {
+        assert!(size <= self.max_size(), "{} bytes too large for {:?}", size, self);
+        match self {
+            Framing::U16 => BigEndian::write_u16(buf, size as u16),
+            Framing::U32 => BigEndian::write_u32(buf, size as u32),
+            Framing::Varint => {
+                let len = self.prefix_len(size);
+                for (i, byte) in buf[..len].iter_mut().enumerate() {
+                    *byte = (size >> (7 * i)) as u8 & 0x7f;
+                    if i + 1 < len {
+                        *byte |= 0x80;
+                    }
+                }
+            }
+        }
+        self.prefix_len(size)
+    }
+}
+
+/// Messages order by header, then payload.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Message<'a> {
     pub header: Header<'a>,
     pub payload: &'a [u8],
@@ -17,4 +265,501 @@ impl<'a> Message<'a> {
             payload: payload,
         })
     }
+
+    /// Like `parse`, capturing as `Header::parse_diagnostic` does.
+    pub fn parse_diagnostic(bytes: &'a [u8], window: usize) -> Result<Self, Error> {
+        let (header, payload) = try!(Header::parse_diagnostic(bytes, window));
+        Ok(Message {
+            header: header,
+            payload: payload,
+        })
+    }
+
+    /// The message, without a length prefix, in the one encoding shared by
+    /// every message logically equal to it, for hashing and storing by
+    /// content. Timestamps count whole milliseconds, as on the wire.
+    pub fn canonicalize(&self, options: &CanonicalOptions) -> Result<Vec<u8>, CanonicalError> {
+        let payload = match options.extensions {
+            None => self.payload.to_vec(),
+            Some(registry) => {
+                try!(Extensions::canonicalize(self.payload, registry)
+                         .map_err(CanonicalError::Extensions))
+            }
+        };
+        let mut bytes = vec![0; self.header.encoded_len()];
+        try!(self.header.write_into(&mut bytes).map_err(CanonicalError::Encode));
+        bytes.extend(payload);
+        Ok(bytes)
+    }
+
+    /// Whether `bytes`, without a length prefix, are a message in canonical
+    /// form. Without extensions, every message that parses is, each of its
+    /// fields having only one encoding.
+    pub fn is_canonical(bytes: &[u8], options: &CanonicalOptions) -> bool {
+        Message::parse(bytes)
+            .ok()
+            .and_then(|msg| msg.canonicalize(options).ok())
+            .map_or(false, |canonical| canonical == bytes)
+    }
+
+    /// The message as `Session` reads it: prefixed with its two-byte
+    /// big-endian length.
+    pub fn to_frame(&self) -> Result<Vec<u8>, FrameError> {
+        self.to_frame_with(Framing::U16)
+    }
+
+    /// The message as a `Session` with `framing` reads it.
+    pub fn to_frame_with(&self, framing: Framing) -> Result<Vec<u8>, FrameError> {
+        let len = self.header.encoded_len() + self.payload.len();
+        if len > framing.max_size() {
+            return Err(FrameError::TooLarge(len));
+        }
+        let prefix_len = framing.prefix_len(len);
+        let mut frame = Vec::with_capacity(prefix_len + len);
+        frame.resize(prefix_len + self.header.encoded_len(), 0);
+        framing.write_size(&mut frame, len);
+        self.header
+            .write_into(&mut frame[prefix_len..])
+            .expect("a buffer of the header's size takes it");
+        frame.extend_from_slice(self.payload);
+        Ok(frame)
+    }
+
+    /// The message prefixed with its length as a varint, as a `Session` with
+    /// `Framing::Varint` reads it.
+    pub fn to_varint_frame(&self) -> Result<Vec<u8>, FrameError> {
+        self.to_frame_with(Framing::Varint)
+    }
+
+    /// Writes `to_frame` to `w` a field at a time, without allocating.
+    /// Nothing is written if the message is too large.
+    pub fn frame_into<W: Write>(&self, w: W) -> Result<(), FrameError> {
+        self.frame_into_with(Framing::U16, w)
+    }
+
+    /// Writes `to_frame_with(framing)` to `w` as `frame_into` does.
+    pub fn frame_into_with<W: Write>(&self, framing: Framing, w: W) -> Result<(), FrameError> {
+        let mut w = w;
+        let len = self.header.encoded_len() + self.payload.len();
+        if len > framing.max_size() {
+            return Err(FrameError::TooLarge(len));
+        }
+        let mut sizes = [0; MAX_VARINT_LEN + 2];
+        let prefix_len = framing.write_size(&mut sizes, len);
+        BigEndian::write_u16(&mut sizes[prefix_len..], self.header.token.len() as u16);
+        try!(w.write_all(&sizes[..prefix_len + 2]));
+        try!(w.write_all(self.header.token));
+        BigEndian::write_u16(&mut sizes, self.header.id.len() as u16);
+        try!(w.write_all(&sizes[..2]));
+        try!(w.write_all(self.header.id));
+        let mut timestamp = [0; 8];
+        BigEndian::write_u64(&mut timestamp, millis(self.header.timestamp));
+        try!(w.write_all(&timestamp));
+        try!(w.write_all(self.payload));
+        Ok(())
+    }
+
+    /// Writes the message, prefixed with its two-byte big-endian length, to
+    /// the start of `buf` without allocating, returning how many bytes it
+    /// took. Nothing is written on failure.
+    pub fn write_frame_into(&self, buf: &mut [u8]) -> Result<usize, WriteIntoError> {
+        let len = self.header.encoded_len() + self.payload.len();
+        if len > u16::max_value() as usize {
+            return Err(WriteIntoError::MessageTooLarge(len));
+        }
+        if buf.len() < 2 + len {
+            return Err(WriteIntoError::BufferTooSmall { needed: 2 + len });
+        }
+        let header_len = try!(self.header.write_into(&mut buf[2..]));
+        BigEndian::write_u16(buf, len as u16);
+        buf[2 + header_len..2 + len].copy_from_slice(self.payload);
+        Ok(2 + len)
+    }
+}
+
+/// A `Message` that owns its fields, ordered as it is.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageBuf {
+    pub token: Vec<u8>,
+    pub id: Vec<u8>,
+    pub timestamp: Duration,
+    pub payload: Vec<u8>,
+}
+
+impl MessageBuf {
+    pub fn as_message(&self) -> Message {
+        Message {
+            header: Header {
+                token: &self.token,
+                id: &self.id,
+                timestamp: self.timestamp,
+            },
+            payload: &self.payload,
+        }
+    }
+}
+
+impl<'a> From<Message<'a>> for MessageBuf {
+    fn from(msg: Message<'a>) -> Self {
+        MessageBuf {
+            token: msg.header.token.to_vec(),
+            id: msg.header.id.to_vec(),
+            timestamp: msg.header.timestamp,
+            payload: msg.payload.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::*;
+    use test_support::*;
+
+    quickcheck_test! {
+    ord_by_header_then_payload(token: Vec<u8>, id: Vec<u8>, millis: u64,
+                               a: Vec<u8>, b: Vec<u8>; bool) {
+        let msg = |payload| Message {
+            header: Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: payload,
+        };
+        msg(&a).cmp(&msg(&b)) == a.cmp(&b)
+    }}
+
+    quickcheck_test! {
+    hash_set_dedups(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>; bool) {
+        let msg = Message {
+            header: Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &payload,
+        };
+        let set: HashSet<_> = vec![msg.clone(), msg.clone()].into_iter().collect();
+        set.len() == 1 && set.contains(&msg)
+    }}
+
+    quickcheck_test! {
+    write_frame_into_matches_encoding(token: Vec<u8>, id: Vec<u8>, millis: u64,
+                                      payload: Vec<u8>, slack: u8; bool) {
+        let msg = Message {
+            header: Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &payload,
+        };
+        let expected = frame(&token, &id, millis, &payload);
+        let mut buf = vec![0xaa; expected.len() + slack as usize];
+        msg.write_frame_into(&mut buf) == Ok(expected.len()) &&
+        buf[..expected.len()] == expected[..] &&
+        buf[expected.len()..].iter().all(|&b| b == 0xaa) &&
+        Message::parse(&buf[2..expected.len()]) == Ok(msg)
+    }}
+
+    quickcheck_test! {
+    to_frame_and_frame_into_match_encoding(token: Vec<u8>, id: Vec<u8>, millis: u64,
+                                           payload: Vec<u8>; bool) {
+        let msg = Message {
+            header: Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &payload,
+        };
+        let expected = frame(&token, &id, millis, &payload);
+        let mut written = vec![];
+        msg.frame_into(&mut written).is_ok() && written == expected &&
+        msg.to_frame().ok() == Some(expected)
+    }}
+
+    quickcheck_test! {
+    framings_differ_only_in_prefix(token: Vec<u8>, id: Vec<u8>, millis: u64,
+                                   payload: Vec<u8>; bool) {
+        let msg = Message {
+            header: Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &payload,
+        };
+        let narrow = frame(&token, &id, millis, &payload);
+        let mut wide = vec![0; 4];
+        BigEndian::write_u32(&mut wide, narrow.len() as u32 - 2);
+        wide.extend_from_slice(&narrow[2..]);
+        let mut written = vec![];
+        msg.frame_into_with(Framing::U32, &mut written).is_ok() && written == wide &&
+        msg.to_frame_with(Framing::U32).ok() == Some(wide) &&
+        msg.to_frame_with(Framing::U16).ok() == Some(narrow)
+    }}
+
+    quickcheck_test! {
+    varint_sizes_round_trip(size: u32; bool) {
+        let size = size as usize;
+        let mut prefix = [0; MAX_VARINT_LEN];
+        let len = Framing::Varint.write_size(&mut prefix, size);
+        len == Framing::Varint.prefix_len(size) &&
+        Framing::Varint.decode(&prefix[..len]) == Prefix::Whole { size: size, len: len } &&
+        (0..len).all(|n| Framing::Varint.decode(&prefix[..n]) == Prefix::Partial(1))
+    }}
+
+    #[test]
+    fn varint_prefixes() {
+        let decode = |bytes: &[u8]| Framing::Varint.decode(bytes);
+        assert_eq!(Prefix::Whole { size: 0, len: 1 }, decode(&[0]));
+        assert_eq!(Prefix::Whole { size: 300, len: 2 }, decode(&[0xac, 0x02, 0xff]));
+        assert_eq!(Prefix::Whole { size: u32::max_value() as usize, len: 5 },
+                   decode(&[0xff, 0xff, 0xff, 0xff, 0x0f]));
+        // Too long, over a u32, and padded.
+        assert_eq!(Prefix::BadVarint, decode(&[0x80; 5]));
+        assert_eq!(Prefix::BadVarint, decode(&[0xff, 0xff, 0xff, 0xff, 0x10]));
+        assert_eq!(Prefix::BadVarint, decode(&[0x81, 0x00]));
+        assert_eq!(Prefix::Partial(1), decode(&[0x80; 4]));
+        assert_eq!(Prefix::Partial(1), Framing::U16.decode(&[0]));
+        assert_eq!(Prefix::Partial(3), Framing::U32.decode(&[0]));
+    }
+
+    #[test]
+    fn varint_frames() {
+        let msg = Message {
+            header: Header {
+                token: b"t",
+                id: b"i",
+                timestamp: Duration::from_millis(1),
+            },
+            payload: b"payload",
+        };
+        let frame = msg.to_varint_frame().unwrap();
+        assert_eq!(21, frame[0]);
+        assert_eq!(&msg.to_frame().unwrap()[2..], &frame[1..]);
+        let payload = vec![0; 70000];
+        let msg = Message { payload: &payload, ..msg };
+        let frame = msg.to_varint_frame().unwrap();
+        assert_eq!(Prefix::Whole { size: 70014, len: 3 }, Framing::Varint.decode(&frame));
+        let mut written = vec![];
+        msg.frame_into_with(Framing::Varint, &mut written).unwrap();
+        assert_eq!(frame, written);
+    }
+
+    #[test]
+    fn u32_framing_takes_messages_over_64_kib() {
+        let payload = vec![0; 70000];
+        let msg = Message {
+            header: Header {
+                token: b"t",
+                id: b"i",
+                timestamp: Duration::from_millis(1),
+            },
+            payload: &payload,
+        };
+        assert_match!(Err(FrameError::TooLarge(70014)), msg.to_frame());
+        let frame = msg.to_frame_with(Framing::U32).unwrap();
+        assert_eq!(Prefix::Whole { size: 70014, len: 4 }, Framing::U32.decode(&frame));
+        assert_eq!(Ok(msg), Message::parse(&frame[4..]));
+    }
+
+    #[test]
+    fn too_large_to_frame() {
+        let payload = vec![0; u16::max_value() as usize - 14];
+        let mut msg = Message {
+            header: Header {
+                token: b"t",
+                id: b"i",
+                timestamp: Duration::from_millis(1),
+            },
+            payload: &payload,
+        };
+        assert_eq!(0x10001, msg.to_frame().unwrap().len());
+        msg.payload = &payload[1..];
+        let mut written = vec![];
+        assert!(msg.frame_into(&mut written).is_ok());
+        assert_eq!(0x10000, written.len());
+
+        let payload = vec![0; u16::max_value() as usize - 13];
+        msg.payload = &payload;
+        assert_match!(Err(FrameError::TooLarge(0x10000)), msg.to_frame());
+        let mut written = vec![];
+        assert_match!(Err(FrameError::TooLarge(0x10000)), msg.frame_into(&mut written));
+        assert!(written.is_empty());
+        let e: io::Error = msg.to_frame().unwrap_err().into();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn one_byte_too_small() {
+        let msg = Message {
+            header: Header {
+                token: b"tok",
+                id: b"id",
+                timestamp: Duration::from_millis(1),
+            },
+            payload: b"payload",
+        };
+        let needed = frame(b"tok", b"id", 1, b"payload").len();
+        // Mid-token, before the timestamp, before the payload, and at the end.
+        for &at in &[5, 11, 19, needed - 1] {
+            let mut buf = vec![0xaa; at];
+            assert_eq!(Err(WriteIntoError::BufferTooSmall { needed: needed }),
+                       msg.write_frame_into(&mut buf));
+            assert!(buf.iter().all(|&b| b == 0xaa));
+        }
+        for &at in &[3, 9, 16] {
+            assert_eq!(Err(WriteIntoError::BufferTooSmall { needed: 17 }),
+                       msg.header.write_into(&mut vec![0; at]));
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let big = vec![0; u16::max_value() as usize + 1];
+        let header = Header {
+            token: &big,
+            id: b"",
+            timestamp: Duration::from_millis(0),
+        };
+        assert_eq!(Err(WriteIntoError::TokenTooLarge(big.len())), header.write_into(&mut []));
+        let header = Header { token: b"", id: &big, ..header };
+        assert_eq!(Err(WriteIntoError::IdTooLarge(big.len())), header.write_into(&mut []));
+        let msg = Message {
+            header: Header { id: b"", ..header },
+            payload: &big[12..],
+        };
+        assert_eq!(Err(WriteIntoError::MessageTooLarge(big.len())),
+                   msg.write_frame_into(&mut []));
+    }
+
+    fn attributes_section(attrs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut section = vec![attrs.len() as u8];
+        for &(ref key, ref value) in attrs {
+            section.push(key.len() as u8);
+            section.extend_from_slice(key);
+            section.extend_from_slice(&[(value.len() >> 8) as u8, value.len() as u8]);
+            section.extend_from_slice(value);
+        }
+        section
+    }
+
+    /// Extensions holding `attrs` in that order, or an empty attributes
+    /// section if there are none and `empty` is set, then `padding` and a
+    /// section for unknown bit 0x0100 if given, then `rest`.
+    fn extended(attrs: &[(Vec<u8>, Vec<u8>)],
+                empty: bool,
+                priority: Option<u8>,
+                padding: Option<u16>,
+                unknown: Option<&[u8]>,
+                rest: &[u8])
+                -> Vec<u8> {
+        let section = attributes_section(attrs);
+        let extensions = Extensions {
+            attrs: if attrs.is_empty() && !empty {
+                None
+            } else {
+                Some(&section)
+            },
+            priority: priority,
+            padding: padding,
+            ..Extensions::default()
+        };
+        let mut bytes = vec![0; extensions.encoded_len()];
+        extensions.write_into(&mut bytes).unwrap();
+        if let Some(unknown) = unknown {
+            let flags = BigEndian::read_u16(&bytes) | 0x0100;
+            BigEndian::write_u16(&mut bytes, flags);
+            bytes.extend_from_slice(&[(unknown.len() >> 8) as u8, unknown.len() as u8]);
+            bytes.extend_from_slice(unknown);
+        }
+        bytes.extend_from_slice(rest);
+        bytes
+    }
+
+    quickcheck_test! {
+    equal_messages_canonicalize_alike(attrs: Vec<(Vec<u8>, Vec<u8>)>, rotate: usize,
+                                      priority: Option<u8>, padding: u8, unknown: Vec<u8>,
+                                      rest: Vec<u8>; bool) {
+        let attrs: Vec<_> = attrs.into_iter()
+                                 .take(32)
+                                 .map(|(mut key, mut value)| {
+                                     key.truncate(255);
+                                     value.truncate(1024);
+                                     (key, value)
+                                 })
+                                 .collect::<BTreeMap<_, _>>()
+                                 .into_iter()
+                                 .collect();
+        let at = if attrs.is_empty() {
+            0
+        } else {
+            rotate % attrs.len()
+        };
+        let mut rotated = attrs[at..].to_vec();
+        rotated.extend_from_slice(&attrs[..at]);
+        rotated.reverse();
+        let variants = vec![extended(&attrs, false, priority, None, None, &rest),
+                            extended(&rotated, true, priority, Some(padding as u16), None, &rest),
+                            extended(&attrs, true, priority, None, Some(&unknown), &rest),
+                            extended(&rotated, false, priority, Some(0), Some(&[]), &rest)];
+        let mut registry = ExtensionRegistry::new();
+        registry.set(0x0100, extension::Unknown::Skip);
+        let options = CanonicalOptions { extensions: Some(&registry) };
+        let canonical: Vec<_> = variants.iter()
+                                        .map(|payload| {
+                                            Message {
+                                                header: Header {
+                                                    token: b"t",
+                                                    id: b"id",
+                                                    timestamp: Duration::from_millis(7),
+                                                },
+                                                payload: payload,
+                                            }
+                                            .canonicalize(&options)
+                                            .unwrap()
+                                        })
+                                        .collect();
+        let first = &canonical[0];
+        let reparsed = Message::parse(first).unwrap();
+        canonical.iter().all(|c| c == first) &&
+        Message::is_canonical(first, &options) &&
+        reparsed.canonicalize(&options).as_ref() == Ok(first) &&
+        reparsed.payload == &variants[0][..] &&
+        variants[1..].iter().all(|variant| {
+            let mut bytes = first[..15].to_vec();
+            bytes.extend_from_slice(variant);
+            !Message::is_canonical(&bytes, &options)
+        })
+    }}
+
+    #[test]
+    fn canonical_without_extensions() {
+        let msg = Message {
+            header: Header {
+                token: b"t",
+                id: b"id",
+                timestamp: Duration::from_millis(1),
+            },
+            payload: b"\x10\x00opaque",
+        };
+        let options = CanonicalOptions::default();
+        let canonical = msg.canonicalize(&options).unwrap();
+        assert_eq!(&frame(b"t", b"id", 1, b"\x10\x00opaque")[2..], &canonical[..]);
+        assert!(Message::is_canonical(&canonical, &options));
+        assert!(!Message::is_canonical(&canonical[..4], &options));
+
+        let registry = ExtensionRegistry::new();
+        let options = CanonicalOptions { extensions: Some(&registry) };
+        assert_match!(Err(CanonicalError::Extensions(ExtensionError::Rejected(0x1000))),
+                      msg.canonicalize(&options));
+        assert!(!Message::is_canonical(&canonical, &options));
+    }
 }
@@ -0,0 +1,322 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// How wide the big-endian length before each sub-record is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+}
+
+impl Width {
+    pub fn size(&self) -> usize {
+        match *self {
+            Width::U8 => 1,
+            Width::U16 => 2,
+            Width::U32 => 4,
+        }
+    }
+
+    /// The longest record the length can say.
+    pub fn max_len(&self) -> usize {
+        match *self {
+            Width::U8 => u8::max_value() as usize,
+            Width::U16 => u16::max_value() as usize,
+            Width::U32 => u32::max_value() as usize,
+        }
+    }
+
+    fn read(&self, bytes: &[u8]) -> usize {
+        match *self {
+            Width::U8 => bytes[0] as usize,
+            Width::U16 => BigEndian::read_u16(bytes) as usize,
+            Width::U32 => BigEndian::read_u32(bytes) as usize,
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8], len: usize) {
+        match *self {
+            Width::U8 => bytes[0] = len as u8,
+            Width::U16 => BigEndian::write_u16(bytes, len as u16),
+            Width::U32 => BigEndian::write_u32(bytes, len as u32),
+        }
+    }
+}
+
+/// Malformed inner framing, at `offset` bytes into the payload.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubRecordError {
+    /// Fewer bytes remain than a length takes.
+    TruncatedLength {
+        offset: usize,
+    },
+    /// The record runs past the end of the payload.
+    TruncatedRecord {
+        offset: usize,
+        len: usize,
+        remaining: usize,
+    },
+}
+
+impl SubRecordError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            SubRecordError::TruncatedLength { offset } => offset,
+            SubRecordError::TruncatedRecord { offset, .. } => offset,
+        }
+    }
+}
+
+impl Display for SubRecordError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SubRecordError::TruncatedLength { offset } => {
+                write!(f, "truncated sub-record length at offset {}", offset)
+            }
+            SubRecordError::TruncatedRecord { offset, len, remaining } => {
+                write!(f,
+                       "sub-record of {} bytes at offset {}; {} bytes remaining",
+                       len,
+                       offset,
+                       remaining)
+            }
+        }
+    }
+}
+
+impl error::Error for SubRecordError {
+    fn description(&self) -> &str {
+        match *self {
+            SubRecordError::TruncatedLength { .. } => "truncated sub-record length",
+            SubRecordError::TruncatedRecord { .. } => "truncated sub-record",
+        }
+    }
+}
+
+/// The length-prefixed records a payload is made of. Stops after the first
+/// error.
+#[derive(Clone, Debug)]
+pub struct SubRecords<'a> {
+    payload: &'a [u8],
+    offset: usize,
+    width: Width,
+    failed: bool,
+}
+
+impl<'a> SubRecords<'a> {
+    pub fn new(payload: &'a [u8], width: Width) -> Self {
+        SubRecords {
+            payload: payload,
+            offset: 0,
+            width: width,
+            failed: false,
+        }
+    }
+}
+
+impl<'a> Iterator for SubRecords<'a> {
+    type Item = Result<&'a [u8], SubRecordError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset == self.payload.len() {
+            return None;
+        }
+        let rest = &self.payload[self.offset..];
+        let size = self.width.size();
+        if rest.len() < size {
+            self.failed = true;
+            return Some(Err(SubRecordError::TruncatedLength { offset: self.offset }));
+        }
+        let len = self.width.read(rest);
+        if rest.len() - size < len {
+            self.failed = true;
+            return Some(Err(SubRecordError::TruncatedRecord {
+                offset: self.offset,
+                len: len,
+                remaining: rest.len() - size,
+            }));
+        }
+        self.offset += size + len;
+        Some(Ok(&rest[size..size + len]))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubRecordWriteError {
+    /// The record is longer than its length can say.
+    RecordTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A fixed buffer cannot hold the record.
+    BufferFull {
+        needed: usize,
+        remaining: usize,
+    },
+}
+
+impl Display for SubRecordWriteError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SubRecordWriteError::RecordTooLarge { len, max } => {
+                write!(f, "sub-record of {} bytes exceeds {} bytes", len, max)
+            }
+            SubRecordWriteError::BufferFull { needed, remaining } => {
+                write!(f, "buffer full; {} bytes needed, {} remaining", needed, remaining)
+            }
+        }
+    }
+}
+
+impl error::Error for SubRecordWriteError {
+    fn description(&self) -> &str {
+        match *self {
+            SubRecordWriteError::RecordTooLarge { .. } => "sub-record too large",
+            SubRecordWriteError::BufferFull { .. } => "buffer full",
+        }
+    }
+}
+
+enum Out<'b> {
+    Vec(&'b mut Vec<u8>),
+    Fixed(&'b mut [u8]),
+}
+
+/// Appends length-prefixed records, as `SubRecords` reads them.
+pub struct SubRecordWriter<'b> {
+    out: Out<'b>,
+    width: Width,
+    written: usize,
+}
+
+impl<'b> SubRecordWriter<'b> {
+    /// Appends to the end of `out`.
+    pub fn to_vec(out: &'b mut Vec<u8>, width: Width) -> Self {
+        SubRecordWriter {
+            out: Out::Vec(out),
+            width: width,
+            written: 0,
+        }
+    }
+
+    /// Writes from the start of `out`, never past its end.
+    pub fn to_slice(out: &'b mut [u8], width: Width) -> Self {
+        SubRecordWriter {
+            out: Out::Fixed(out),
+            width: width,
+            written: 0,
+        }
+    }
+
+    /// How many bytes have been written.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Writes `record` with its length, or nothing on failure.
+    pub fn push(&mut self, record: &[u8]) -> Result<(), SubRecordWriteError> {
+        if record.len() > self.width.max_len() {
+            return Err(SubRecordWriteError::RecordTooLarge {
+                len: record.len(),
+                max: self.width.max_len(),
+            });
+        }
+        let size = self.width.size();
+        let needed = size + record.len();
+        match self.out {
+            Out::Vec(ref mut out) => {
+                let start = out.len();
+                out.resize(start + size, 0);
+                self.width.write(&mut out[start..], record.len());
+                out.extend_from_slice(record);
+            }
+            Out::Fixed(ref mut out) => {
+                let remaining = out.len() - self.written;
+                if remaining < needed {
+                    return Err(SubRecordWriteError::BufferFull {
+                        needed: needed,
+                        remaining: remaining,
+                    });
+                }
+                let start = self.written;
+                self.width.write(&mut out[start..], record.len());
+                out[start + size..start + needed].copy_from_slice(record);
+            }
+        }
+        self.written += needed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::*;
+
+    const WIDTHS: [Width; 3] = [Width::U8, Width::U16, Width::U32];
+
+    quickcheck_test! {
+    round_trip(records: Vec<Vec<u8>>; bool) {
+        WIDTHS.iter().all(|&width| {
+            let records: Vec<_> = records.iter()
+                                         .map(|r| &r[..::std::cmp::min(r.len(), width.max_len())])
+                                         .collect();
+            let mut vec = vec![9];
+            {
+                let mut writer = SubRecordWriter::to_vec(&mut vec, width);
+                for record in &records {
+                    writer.push(record).unwrap();
+                }
+            }
+            let mut fixed = vec![0; vec.len() - 1];
+            {
+                let mut writer = SubRecordWriter::to_slice(&mut fixed, width);
+                for record in &records {
+                    writer.push(record).unwrap();
+                }
+                assert!(writer.written() == fixed.len());
+            }
+            let read: Result<Vec<_>, _> = SubRecords::new(&vec[1..], width).collect();
+            vec[1..] == fixed[..] && read == Ok(records)
+        })
+    }}
+
+    #[test]
+    fn malformed_offsets() {
+        let payload = [2, b'a', b'b', 0, 3, b'c'];
+        let read: Vec<_> = SubRecords::new(&payload, Width::U8).collect();
+        assert_eq!(vec![Ok(&b"ab"[..]),
+                        Ok(&b""[..]),
+                        Err(SubRecordError::TruncatedRecord {
+                            offset: 4,
+                            len: 3,
+                            remaining: 1,
+                        })],
+                   read);
+        let read: Vec<_> = SubRecords::new(&[0, 0, 0], Width::U16).collect();
+        assert_eq!(vec![Ok(&b""[..]), Err(SubRecordError::TruncatedLength { offset: 2 })],
+                   read);
+    }
+
+    #[test]
+    fn writer_overflow() {
+        let mut buf = [0; 5];
+        let mut writer = SubRecordWriter::to_slice(&mut buf, Width::U16);
+        assert_eq!(Ok(()), writer.push(b"ab"));
+        assert_eq!(Err(SubRecordWriteError::BufferFull {
+                       needed: 2,
+                       remaining: 1,
+                   }),
+                   writer.push(b""));
+        assert_eq!(4, writer.written());
+        let mut vec = vec![];
+        assert_eq!(Err(SubRecordWriteError::RecordTooLarge {
+                       len: 256,
+                       max: 255,
+                   }),
+                   SubRecordWriter::to_vec(&mut vec, Width::U8).push(&[0; 256]));
+        assert!(vec.is_empty());
+    }
+}
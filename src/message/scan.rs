@@ -0,0 +1,320 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+
+use util::fill;
+
+/// Readers that can advance past bytes without necessarily reading them.
+pub trait Skip: Read {
+    /// Advances by up to `n` bytes and returns how many were skipped,
+    /// which is less than `n` only at the end of the input.
+    fn skip(&mut self, n: u64) -> io::Result<u64>;
+}
+
+fn seek_forward<S: Seek>(seeker: &mut S, n: u64) -> io::Result<u64> {
+    let pos = try!(seeker.seek(SeekFrom::Current(0)));
+    let end = try!(seeker.seek(SeekFrom::End(0)));
+    let target = cmp::min(pos.saturating_add(n), cmp::max(pos, end));
+    try!(seeker.seek(SeekFrom::Start(target)));
+    Ok(target - pos)
+}
+
+impl<'a> Skip for &'a [u8] {
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let skipped = cmp::min(n, self.len() as u64) as usize;
+        *self = &self[skipped..];
+        Ok(skipped as u64)
+    }
+}
+
+impl<T: AsRef<[u8]>> Skip for Cursor<T> {
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let pos = self.position();
+        let end = cmp::max(pos, self.get_ref().as_ref().len() as u64);
+        let target = cmp::min(pos.saturating_add(n), end);
+        self.set_position(target);
+        Ok(target - pos)
+    }
+}
+
+impl Skip for File {
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        seek_forward(self, n)
+    }
+}
+
+impl<'a, S: Skip + ?Sized> Skip for &'a mut S {
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        (**self).skip(n)
+    }
+}
+
+/// Skips by seeking any `Read + Seek`.
+pub struct Seeking<R>(pub R);
+
+impl<R: Read> Read for Seeking<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read + Seek> Skip for Seeking<R> {
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        seek_forward(&mut self.0, n)
+    }
+}
+
+/// Skips any `Read` by reading into a small stack buffer and discarding.
+pub struct Discarding<R>(pub R);
+
+impl<R: Read> Read for Discarding<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> Skip for Discarding<R> {
+    fn skip(&mut self, n: u64) -> io::Result<u64> {
+        io::copy(&mut self.0.by_ref().take(n), &mut io::sink())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub index: u64,
+    /// Offset of the frame's length prefix.
+    pub offset: u64,
+    /// Length of the frame excluding its prefix.
+    pub len: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanSummary {
+    /// Number of complete frames.
+    pub frames: u64,
+    /// Number of bytes scanned, including any partial final frame.
+    pub bytes: u64,
+    /// Offset of the final frame if it was cut short.
+    pub truncated_at: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct ScanError {
+    pub offset: u64,
+    pub error: io::Error,
+}
+
+impl Display for ScanError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} at offset {}", self.error, self.offset)
+    }
+}
+
+impl error::Error for ScanError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.error)
+    }
+}
+
+/// Walks a stream of u16-length-prefixed frames, reading only the prefixes,
+/// and hands each complete frame's boundaries to `sink`.
+pub fn scan_frames<R: Skip, F: FnMut(FrameInfo)>(mut reader: R,
+                                                 mut sink: F)
+                                                 -> Result<ScanSummary, ScanError> {
+    let mut summary = ScanSummary {
+        frames: 0,
+        bytes: 0,
+        truncated_at: None,
+    };
+    loop {
+        let offset = summary.bytes;
+        let mut prefix = [0_u8; 2];
+        let n = try!(fill(&mut reader, &mut prefix).map_err(|e| {
+            ScanError {
+                offset: offset,
+                error: e,
+            }
+        }));
+        summary.bytes += n as u64;
+        match n {
+            0 => return Ok(summary),
+            1 => {
+                summary.truncated_at = Some(offset);
+                return Ok(summary);
+            }
+            _ => {}
+        }
+
+        let len = BigEndian::read_u16(&prefix);
+        let body_offset = summary.bytes;
+        let skipped = try!(reader.skip(len as u64).map_err(|e| {
+            ScanError {
+                offset: body_offset,
+                error: e,
+            }
+        }));
+        summary.bytes += skipped;
+        if skipped < len as u64 {
+            summary.truncated_at = Some(offset);
+            return Ok(summary);
+        }
+
+        sink(FrameInfo {
+            index: summary.frames,
+            offset: offset,
+            len: len,
+        });
+        summary.frames += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::{Cursor, SeekFrom};
+
+    use super::*;
+    use test_support::*;
+
+    fn capture(bodies: &[Vec<u8>]) -> (Vec<u8>, Vec<FrameInfo>) {
+        let mut bytes = vec![];
+        let mut frames = vec![];
+        for (index, body) in bodies.iter().enumerate() {
+            frames.push(FrameInfo {
+                index: index as u64,
+                offset: bytes.len() as u64,
+                len: body.len() as u16,
+            });
+            bytes.extend((body.len() as u16).to_bytes().into_copy_iter());
+            bytes.extend(body.into_copy_iter());
+        }
+        (bytes, frames)
+    }
+
+    struct Counting<R> {
+        inner: R,
+        read: u64,
+    }
+
+    impl<R: Read> Read for Counting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = try!(self.inner.read(buf));
+            self.read += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl<R: Seek> Seek for Counting<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(ScanSummary {
+                       frames: 0,
+                       bytes: 0,
+                       truncated_at: None,
+                   },
+                   scan_frames(&[] as &[u8], |_| panic!()).unwrap());
+    }
+
+    quickcheck_test! {
+    slice_matches_ground_truth(bodies: Vec<Vec<u8>>; bool) {
+        let (bytes, expected) = capture(&bodies);
+        let mut found = vec![];
+        let summary = scan_frames(&*bytes, |info| found.push(info)).unwrap();
+        found == expected && summary == ScanSummary {
+            frames: expected.len() as u64,
+            bytes: bytes.len() as u64,
+            truncated_at: None,
+        }
+    }}
+
+    quickcheck_test! {
+    discarding_matches_seeking(bodies: Vec<Vec<u8>>; bool) {
+        let (bytes, _) = capture(&bodies);
+        let mut discarded = vec![];
+        let mut sought = vec![];
+        let a = scan_frames(Discarding(&*bytes), |info| discarded.push(info)).unwrap();
+        let b = scan_frames(Seeking(Cursor::new(&bytes)), |info| sought.push(info)).unwrap();
+        a == b && discarded == sought
+    }}
+
+    quickcheck_test! {
+    seeking_skips_bodies(bodies: Vec<Vec<u8>>; bool) {
+        let (bytes, expected) = capture(&bodies);
+        let mut reader = Seeking(Counting {
+            inner: Cursor::new(bytes),
+            read: 0,
+        });
+        let mut found = vec![];
+        scan_frames(&mut reader, |info| found.push(info)).unwrap();
+        found == expected && reader.0.read == 2 * expected.len() as u64
+    }}
+
+    quickcheck_test! {
+    partial_prefix(bodies: Vec<Vec<u8>>, partial: u8; bool) {
+        let (mut bytes, expected) = capture(&bodies);
+        let offset = bytes.len() as u64;
+        bytes.push(partial);
+        let mut found = vec![];
+        let summary = scan_frames(Cursor::new(&bytes), |info| found.push(info)).unwrap();
+        found == expected && summary == ScanSummary {
+            frames: expected.len() as u64,
+            bytes: offset + 1,
+            truncated_at: Some(offset),
+        }
+    }}
+
+    quickcheck_test! {
+    partial_body(bodies: Vec<Vec<u8>>, partial: Vec<u8>, missing: u16; TestResult) {
+        if missing == 0 {
+            return TestResult::discard();
+        }
+        if let Some(len) = (partial.len() as u16).checked_add(missing) {
+            let (mut bytes, expected) = capture(&bodies);
+            let offset = bytes.len() as u64;
+            bytes.extend(len.to_bytes().into_copy_iter());
+            bytes.extend(partial);
+            let mut reader = Seeking(Counting {
+                inner: Cursor::new(&bytes),
+                read: 0,
+            });
+            let mut found = vec![];
+            let summary = scan_frames(&mut reader, |info| found.push(info)).unwrap();
+            TestResult::from_bool(found == expected && summary == ScanSummary {
+                frames: expected.len() as u64,
+                bytes: bytes.len() as u64,
+                truncated_at: Some(offset),
+            } && reader.0.read == 2 * expected.len() as u64 + 2)
+        } else {
+            TestResult::discard()
+        }
+    }}
+
+    #[test]
+    fn read_error() {
+        struct BrokenRead;
+        impl Read for BrokenRead {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, ""))
+            }
+        }
+        let bytes = [0_u8, 1, 42];
+        let reader = Discarding((&bytes as &[_]).chain(BrokenRead));
+        assert_match!(Err(ScanError { offset: 3, .. }), scan_frames(reader, |_| {}));
+    }
+}
@@ -0,0 +1,326 @@
+//! The arithmetic on sizes and offsets read off the wire, kept apart so
+//! that it can be run in either width. Everything else indexes with what
+//! these return, so a size that does not fit the machine's words is an
+//! error here rather than a wrapped index or a panic somewhere else.
+//!
+//! The crate runs it as `Native`, the narrowest index that holds any
+//! `usize`; tests run the `u32` instantiation on 64-bit hosts too, so that
+//! 32-bit gateways are covered by CI that only has x86_64.
+
+use std::fmt::Debug;
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder};
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// An unsigned index as wide as a target's words. Sealed: only `u32` and
+/// `u64` implement it.
+pub trait WireIndex: sealed::Sealed + Copy + Ord + Debug {
+    fn from_u64(n: u64) -> Option<Self>;
+    fn checked_add(self, n: Self) -> Option<Self>;
+    fn checked_sub(self, n: Self) -> Option<Self>;
+    fn to_usize(self) -> Option<usize>;
+    fn to_u64(self) -> u64;
+}
+
+impl WireIndex for u32 {
+    fn from_u64(n: u64) -> Option<Self> {
+        if n > u32::max_value() as u64 {
+            None
+        } else {
+            Some(n as u32)
+        }
+    }
+
+    fn checked_add(self, n: Self) -> Option<Self> {
+        u32::checked_add(self, n)
+    }
+
+    fn checked_sub(self, n: Self) -> Option<Self> {
+        u32::checked_sub(self, n)
+    }
+
+    fn to_usize(self) -> Option<usize> {
+        if self as u64 > usize::max_value() as u64 {
+            None
+        } else {
+            Some(self as usize)
+        }
+    }
+
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+impl WireIndex for u64 {
+    fn from_u64(n: u64) -> Option<Self> {
+        Some(n)
+    }
+
+    fn checked_add(self, n: Self) -> Option<Self> {
+        u64::checked_add(self, n)
+    }
+
+    fn checked_sub(self, n: Self) -> Option<Self> {
+        u64::checked_sub(self, n)
+    }
+
+    fn to_usize(self) -> Option<usize> {
+        if self > usize::max_value() as u64 {
+            None
+        } else {
+            Some(self as usize)
+        }
+    }
+
+    fn to_u64(self) -> u64 {
+        self
+    }
+}
+
+/// The index the crate parses with, chosen here alone.
+#[cfg(target_pointer_width = "64")]
+pub type Native = u64;
+#[cfg(not(target_pointer_width = "64"))]
+pub type Native = u32;
+
+/// A length, offset or size the index cannot hold, or a sum of them that
+/// would not fit it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Overflow;
+
+fn index<I: WireIndex>(n: u64) -> Result<I, Overflow> {
+    I::from_u64(n).ok_or(Overflow)
+}
+
+/// How many of the `len` bytes from `offset` on remain.
+pub fn remaining<I: WireIndex>(len: u64, offset: u64) -> Result<I, Overflow> {
+    let (len, offset) = (try!(index::<I>(len)), try!(index::<I>(offset)));
+    len.checked_sub(offset).ok_or(Overflow)
+}
+
+/// How many of `remaining` bytes remain once `size` are taken from them,
+/// or `None` if they are too few.
+pub fn take<I: WireIndex>(remaining: u64, size: u64) -> Result<Option<I>, Overflow> {
+    let (remaining, size) = (try!(index::<I>(remaining)), try!(index::<I>(size)));
+    Ok(remaining.checked_sub(size))
+}
+
+/// Where the `size` bytes at `offset` are among `len`, for slicing, or
+/// `None` if they run past the end.
+pub fn span<I: WireIndex>(len: u64,
+                          offset: u64,
+                          size: u64)
+                          -> Result<Option<Range<usize>>, Overflow> {
+    let (len, offset, size) = (try!(index::<I>(len)),
+                               try!(index::<I>(offset)),
+                               try!(index::<I>(size)));
+    // Against what remains rather than by their sum, which need not fit.
+    match len.checked_sub(offset) {
+        Some(rest) if size <= rest => {}
+        _ => return Ok(None),
+    }
+    // At most `len`, so it fits.
+    let end = offset.checked_add(size).unwrap_or(len);
+    match (offset.to_usize(), end.to_usize()) {
+        (Some(start), Some(end)) => Ok(Some(start..end)),
+        _ => Err(Overflow),
+    }
+}
+
+/// What starts at an offset in a capture of frames, each after its
+/// two-byte length.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameAt {
+    End,
+    /// The first byte of a length, without the second.
+    PartialPrefix,
+    /// A frame `size` long of which only `found` bytes are there.
+    Short {
+        found: u16,
+        size: u16,
+    },
+    /// A whole frame: its message, then where the next frame starts.
+    Whole {
+        message: Range<usize>,
+        next: usize,
+    },
+}
+
+/// What `frame_at` makes of a frame at `offset` among `len` bytes whose
+/// length prefix says `size`, if the bytes go on far enough to say.
+pub fn frame_extent<I: WireIndex>(len: u64, offset: u64, size: u16) -> Result<FrameAt, Overflow> {
+    let rest: I = try!(remaining(len, offset));
+    let after_prefix = match try!(take::<I>(rest.to_u64(), 2)) {
+        None if rest.to_u64() == 0 => return Ok(FrameAt::End),
+        None => return Ok(FrameAt::PartialPrefix),
+        Some(after_prefix) => after_prefix,
+    };
+    let start = try!(try!(index::<I>(offset)).checked_add(try!(index(2))).ok_or(Overflow));
+    match try!(span::<I>(len, start.to_u64(), size as u64)) {
+        Some(message) => {
+            let next = message.end;
+            Ok(FrameAt::Whole {
+                message: message,
+                next: next,
+            })
+        }
+        // Short of a `u16` size, so what is there fits one too.
+        None => {
+            Ok(FrameAt::Short {
+                found: after_prefix.to_u64() as u16,
+                size: size,
+            })
+        }
+    }
+}
+
+/// The frame at `offset` in `bytes`, by the arithmetic of `I`.
+pub fn frame_at_in<I: WireIndex>(bytes: &[u8], offset: usize) -> Result<FrameAt, Overflow> {
+    let size = if bytes.len() >= 2 && offset <= bytes.len() - 2 {
+        BigEndian::read_u16(&bytes[offset..])
+    } else {
+        0
+    };
+    frame_extent::<I>(bytes.len() as u64, offset as u64, size)
+}
+
+/// The frame at `offset` in `bytes`, which must be no further than their
+/// end.
+pub fn frame_at(bytes: &[u8], offset: usize) -> FrameAt {
+    match frame_at_in::<Native>(bytes, offset) {
+        Ok(frame) => frame,
+        Err(Overflow) => unreachable!("a native index holds any slice's length"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const U32_MAX: u64 = 0xffff_ffff;
+
+    /// Sizes at the edges of a `u16` and of a `u32`.
+    const BOUNDARY_SIZES: [u64; 7] = [0, 1, 0xffff, U32_MAX - 9, U32_MAX - 8, U32_MAX, U32_MAX + 1];
+
+    /// The header's remaining-byte accounting with sizes read off the
+    /// wire, one byte short of the header's end.
+    fn header_rest<I: WireIndex>(token_size: u64, id_size: u64) -> Result<Option<I>, Overflow> {
+        let mut rest = Some(try!(remaining::<I>(2 + token_size + 2 + id_size + 7, 0)));
+        for &size in &[2, token_size, 2, id_size] {
+            rest = match rest {
+                Some(rest) => try!(take::<I>(rest.to_u64(), size)),
+                None => None,
+            };
+        }
+        Ok(rest)
+    }
+
+    #[test]
+    fn take_in_either_width() {
+        for &token_size in &BOUNDARY_SIZES {
+            for &id_size in &BOUNDARY_SIZES {
+                assert_eq!(Ok(Some(7)), header_rest::<u64>(token_size, id_size));
+                // The `u32` sums overflow as soon as the whole header's
+                // length does, and never wrap into a plausible answer.
+                let fits = 2 + token_size + 2 + id_size + 7 <= U32_MAX;
+                assert_eq!(if fits { Ok(Some(7)) } else { Err(Overflow) },
+                           header_rest::<u32>(token_size, id_size));
+                assert_eq!(Ok(None), take::<u64>(token_size, token_size + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn span_in_either_width() {
+        for &len in &BOUNDARY_SIZES {
+            for &offset in &BOUNDARY_SIZES {
+                for &size in &[0, 2, 8, 0xffff] {
+                    let wide = span::<u64>(len, offset, size);
+                    let end = offset + size;
+                    let expected = if end > len {
+                        None
+                    } else {
+                        Some(offset as usize..end as usize)
+                    };
+                    assert_eq!(Ok(expected.clone()), wide);
+                    if len > U32_MAX || offset > U32_MAX {
+                        assert_eq!(Err(Overflow), span::<u32>(len, offset, size));
+                    } else {
+                        assert_eq!(Ok(expected), span::<u32>(len, offset, size));
+                    }
+                }
+            }
+        }
+        // Past the end, however far past, even where the end would not fit.
+        assert_eq!(Ok(None), span::<u64>(u64::max_value(), u64::max_value(), 1));
+        assert_eq!(Ok(None), span::<u32>(U32_MAX, U32_MAX - 1, U32_MAX));
+    }
+
+    #[test]
+    fn frames_at_the_edges_of_either_width() {
+        let whole = |start: u64, size: u64| {
+            FrameAt::Whole {
+                message: start as usize..(start + size) as usize,
+                next: (start + size) as usize,
+            }
+        };
+        for &(len, offset, size, ref expected) in
+            &[(0, 0, 0, FrameAt::End),
+              (U32_MAX, U32_MAX, 0, FrameAt::End),
+              (U32_MAX, U32_MAX - 1, 0, FrameAt::PartialPrefix),
+              (U32_MAX, U32_MAX - 2, 0, whole(U32_MAX, 0)),
+              (U32_MAX, U32_MAX - 2, 1, FrameAt::Short { found: 0, size: 1 }),
+              (U32_MAX, U32_MAX - 0xffff - 2, 0xffff, whole(U32_MAX - 0xffff, 0xffff)),
+              (U32_MAX, U32_MAX - 0xffff, 0xffff, FrameAt::Short { found: 0xfffd, size: 0xffff })] {
+            assert_eq!(Ok(expected.clone()), frame_extent::<u32>(len, offset, size));
+            assert_eq!(Ok(expected.clone()), frame_extent::<u64>(len, offset, size));
+        }
+        // Past what a `u32` can index, only the `u64` arithmetic answers.
+        for &(len, offset) in &[(U32_MAX + 1, 0), (U32_MAX + 2, U32_MAX)] {
+            assert_eq!(Err(Overflow), frame_extent::<u32>(len, offset, 0));
+            assert_match!(Ok(_), frame_extent::<u64>(len, offset, 0));
+        }
+        assert_eq!(Err(Overflow), frame_extent::<u64>(1, 2, 0));
+    }
+
+    quickcheck_test! {
+    frames_alike_in_either_width(bytes: Vec<u8>, offset: usize; bool) {
+        let offset = if bytes.is_empty() { 0 } else { offset % (bytes.len() + 1) };
+        let frame = frame_at_in::<u32>(&bytes, offset);
+        frame == frame_at_in::<u64>(&bytes, offset) && frame == Ok(frame_at(&bytes, offset)) &&
+        match frame {
+            Ok(FrameAt::Whole { message, next }) => {
+                message.start == offset + 2 && message.end == next && next <= bytes.len()
+            }
+            Ok(FrameAt::Short { found, size }) => {
+                found < size && offset + 2 + found as usize == bytes.len()
+            }
+            Ok(FrameAt::PartialPrefix) => offset + 1 == bytes.len(),
+            Ok(FrameAt::End) => offset == bytes.len(),
+            Err(_) => false,
+        }
+    }}
+
+    quickcheck_test! {
+    arithmetic_alike_where_representable(len: u64, offset: u64, size: u64; bool) {
+        // Mostly near the top of a `u32`, where the widths part ways.
+        let near = |n: u64| U32_MAX - n % 0x2_0000 + 0x1_0000;
+        let (len, offset, size) = (near(len), near(offset), size % 0x1_0000);
+        let fits = |ns: &[u64]| ns.iter().all(|&n| n <= U32_MAX);
+        let narrow = span::<u32>(len, offset, size);
+        let wide = span::<u64>(len, offset, size);
+        let spans = if fits(&[len, offset]) { narrow == wide } else { narrow.is_err() };
+        let narrow = take::<u32>(len, size).map(|rest| rest.map(u64::from));
+        let wide = take::<u64>(len, size);
+        let takes = if fits(&[len]) { narrow == wide } else { narrow.is_err() };
+        spans && takes
+    }}
+}
@@ -0,0 +1,652 @@
+//! Every stats struct in the crate as Prometheus text exposition, without
+//! a metrics dependency. A `MetricsRegistry` gathers what an application
+//! registers into one `render` for its scrape handler.
+//!
+//! Labels are only ever bounded: per-worker or per-token breakdowns keep
+//! the top `TOP_K` and fold the rest into `other`.
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use client::ReporterStats;
+use pool::{ConnectionStats, QueueStats, WorkerStats};
+use server::admin::{Counts, ServeStats};
+use server::audit::TopFingerprints;
+use server::{ErrorCounts, OrderingCounts, OutcomeCounts, PeriodSnapshot, RetentionCounts,
+             TokenUsage};
+use session::{DryRunCounts, InternStats, LatencyHistogram, PressureStats, ThrottleStats};
+use simple::ServerStats;
+use stream::{ReassemblyStats, SequenceStats, WriteStats};
+
+/// How many label values a breakdown keeps before folding the rest into
+/// `other`.
+pub const TOP_K: usize = 10;
+
+/// Something that can be rendered for a scrape.
+pub trait Render {
+    /// Appends this in text exposition format, with `prefix` and an
+    /// underscore before every metric name unless it is empty.
+    fn render_into(&self, prefix: &str, out: &mut String);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Count(u64),
+    /// Rendered in seconds.
+    Time(Duration),
+}
+
+impl Value {
+    fn plus(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Count(a), Value::Count(b)) => Value::Count(a + b),
+            (Value::Time(a), Value::Time(b)) => Value::Time(a + b),
+            _ => panic!("adding a count to a time"),
+        }
+    }
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metric {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+    pub value: Value,
+}
+
+fn counter(name: &'static str, help: &'static str, n: u64) -> Metric {
+    Metric {
+        name: name,
+        kind: Kind::Counter,
+        help: help,
+        value: Value::Count(n),
+    }
+}
+
+fn gauge(name: &'static str, help: &'static str, n: u64) -> Metric {
+    Metric {
+        name: name,
+        kind: Kind::Gauge,
+        help: help,
+        value: Value::Count(n),
+    }
+}
+
+fn time(name: &'static str, help: &'static str, d: Duration) -> Metric {
+    Metric {
+        name: name,
+        kind: Kind::Counter,
+        help: help,
+        value: Value::Time(d),
+    }
+}
+
+/// A stats struct as a fixed list of unlabeled metrics, the same names in
+/// the same order whatever its values, so that many can be rendered under
+/// one label by `ByLabel`.
+pub trait Metrics {
+    fn metrics(&self) -> Vec<Metric>;
+}
+
+impl<T: Metrics> Render for T {
+    fn render_into(&self, prefix: &str, out: &mut String) {
+        for metric in self.metrics() {
+            header(prefix, metric.name, metric.kind.name(), metric.help, out);
+            sample(prefix, metric.name, &[], metric.value, out);
+        }
+    }
+}
+
+fn full_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}_{}", prefix, name)
+    }
+}
+
+fn header(prefix: &str, name: &str, kind: &str, help: &str, out: &mut String) {
+    let name = full_name(prefix, name);
+    let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value as the exposition format has it.
+pub fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn sample(prefix: &str, name: &str, labels: &[(&str, &str)], value: Value, out: &mut String) {
+    out.push_str(&full_name(prefix, name));
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels.iter()
+                                   .map(|&(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                                   .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = match value {
+        Value::Count(n) => writeln!(out, " {}", n),
+        Value::Time(d) => writeln!(out, " {}", seconds(d)),
+    };
+}
+
+/// Many of one stats struct under one label, as for each worker of a
+/// pool. Past the bound, the rest are added up under `other`.
+pub struct ByLabel {
+    label: &'static str,
+    entries: Vec<(String, Vec<Metric>)>,
+    bound: usize,
+}
+
+impl ByLabel {
+    /// Bounded by `TOP_K`.
+    pub fn new(label: &'static str) -> Self {
+        ByLabel {
+            label: label,
+            entries: vec![],
+            bound: TOP_K,
+        }
+    }
+
+    /// Entries are kept in the order added, so add the ones that matter
+    /// most first.
+    pub fn add<T: Metrics>(&mut self, value: &str, stats: &T) -> &mut Self {
+        self.entries.push((value.to_owned(), stats.metrics()));
+        self
+    }
+
+    pub fn set_bound(&mut self, bound: usize) -> &mut Self {
+        self.bound = bound;
+        self
+    }
+
+    fn bounded(&self) -> Vec<(&str, Vec<Metric>)> {
+        let mut entries: Vec<_> = self.entries
+                                      .iter()
+                                      .take(self.bound)
+                                      .map(|&(ref value, ref metrics)| {
+                                          (&value[..], metrics.clone())
+                                      })
+                                      .collect();
+        let mut rest = self.entries.iter().skip(self.bound);
+        if let Some(&(_, ref first)) = rest.next() {
+            let other = rest.fold(first.clone(), |mut sum, &(_, ref metrics)| {
+                for (total, metric) in sum.iter_mut().zip(metrics) {
+                    total.value = total.value.plus(metric.value);
+                }
+                sum
+            });
+            entries.push(("other", other));
+        }
+        entries
+    }
+}
+
+impl Render for ByLabel {
+    fn render_into(&self, prefix: &str, out: &mut String) {
+        let entries = self.bounded();
+        let families = match entries.first() {
+            None => return,
+            Some(&(_, ref metrics)) => metrics.clone(),
+        };
+        for (i, family) in families.iter().enumerate() {
+            header(prefix, family.name, family.kind.name(), family.help, out);
+            for &(value, ref metrics) in &entries {
+                sample(prefix, family.name, &[(self.label, value)], metrics[i].value, out);
+            }
+        }
+    }
+}
+
+/// Buckets as a histogram in seconds; without a sum, which the histogram
+/// does not keep.
+impl Render for LatencyHistogram {
+    fn render_into(&self, prefix: &str, out: &mut String) {
+        let name = "latency_seconds";
+        header(prefix,
+               name,
+               "histogram",
+               "Time from each message's header timestamp to its consumption.",
+               out);
+        let bucket = format!("{}_bucket", name);
+        let mut total = 0;
+        for (i, &count) in self.counts().iter().enumerate() {
+            total += count;
+            let le = self.bounds().get(i).map_or("+Inf".to_owned(), |&b| seconds(b).to_string());
+            sample(prefix, &bucket, &[("le", &le)], Value::Count(total), out);
+        }
+        sample(prefix, &format!("{}_count", name), &[], Value::Count(total), out);
+    }
+}
+
+/// Usage by token fingerprint, most stored first, bounded by `TOP_K`.
+impl Render for PeriodSnapshot {
+    fn render_into(&self, prefix: &str, out: &mut String) {
+        let period = [gauge("period_start_seconds", "Start of the accounting period.", 0),
+                      gauge("period_end_seconds", "End of the accounting period.", 0)];
+        for (metric, &at) in period.iter().zip(&[self.start, self.end]) {
+            header(prefix, metric.name, metric.kind.name(), metric.help, out);
+            sample(prefix, metric.name, &[], Value::Count(at.as_secs()), out);
+        }
+        let mut usage: Vec<_> = self.usage.iter().collect();
+        usage.sort_by_key(|&(&fp, usage)| (!usage.stored, fp));
+        let mut by_token = ByLabel::new("token");
+        for (fp, usage) in usage {
+            by_token.add(&format!("{:016x}", fp), usage);
+        }
+        by_token.render_into(prefix, out);
+    }
+}
+
+/// By fingerprint, with the decisions outside the top as `other`.
+impl Render for TopFingerprints {
+    fn render_into(&self, prefix: &str, out: &mut String) {
+        let top = self.top();
+        let mut by_token = ByLabel::new("token");
+        by_token.set_bound(top.len());
+        for &(fp, ref counts) in &top {
+            by_token.add(&format!("{:016x}", fp), counts);
+        }
+        by_token.add("other", &self.others());
+        by_token.render_into(prefix, out);
+    }
+}
+
+impl Metrics for ServerStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("accepted_total", "Connections accepted.", self.accepted),
+             gauge("active_connections", "Connections open.", self.active),
+             counter("stored_total", "Messages stored.", self.stored),
+             counter("failed_total", "Messages refused or whose write failed.", self.failed),
+             counter("malformed_total", "Frames without a whole header.", self.malformed),
+             counter("oversized_total", "Frames over the maximum size.", self.oversized)]
+    }
+}
+
+impl Metrics for OutcomeCounts {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("auth_ok_total", "Tokens accepted.", self.ok),
+             counter("auth_invalid_total", "Tokens refused.", self.invalid),
+             counter("auth_other_total", "Tokens that failed otherwise.", self.other),
+             counter("auth_pending_total", "Tokens left pending.", self.pending)]
+    }
+}
+
+impl Metrics for ErrorCounts {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("errors_auth_total", "Messages failing authentication.", self.auth),
+             counter("errors_missing_id_total", "Messages for no stream.", self.missing_id),
+             counter("errors_rejected_total", "Messages refused by a policy.", self.rejected),
+             counter("errors_push_total", "Messages refused by their stream.", self.push)]
+    }
+}
+
+impl Metrics for RetentionCounts {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("retention_rejected_total",
+                     "Messages refused as past retention.",
+                     self.rejected),
+             counter("retention_diverted_total",
+                     "Messages past retention pushed to quarantine.",
+                     self.diverted)]
+    }
+}
+
+impl Metrics for OrderingCounts {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("ordering_checked_total", "Messages checked.", self.checked),
+             counter("ordering_unsequenced_total",
+                     "Messages without a sequence.",
+                     self.unsequenced),
+             counter("ordering_evicted_total", "Keys forgotten.", self.evicted),
+             counter("ordering_per_id_fifo_total",
+                     "Messages out of their ID's sequence.",
+                     self.per_id_fifo),
+             counter("ordering_per_id_timestamp_total",
+                     "Messages before their ID's last timestamp.",
+                     self.per_id_timestamp),
+             counter("ordering_per_connection_fifo_total",
+                     "Messages out of their connection's sequence.",
+                     self.per_connection_fifo),
+             counter("ordering_global_timestamp_total",
+                     "Messages before the last timestamp.",
+                     self.global_timestamp)]
+    }
+}
+
+impl Metrics for TokenUsage {
+    fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = vec![counter("attempted_total", "Messages attempted.", self.attempted),
+                               counter("stored_total", "Messages stored.", self.stored),
+                               counter("stored_bytes_total", "Bytes stored.", self.bytes)];
+        metrics.extend(self.errors.metrics());
+        metrics
+    }
+}
+
+impl Metrics for Counts {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![gauge("records", "Records held.", self.records),
+             gauge("payload_bytes", "Payload bytes held.", self.bytes)]
+    }
+}
+
+impl Metrics for ServeStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("consumed_total", "Messages consumed.", self.consumed),
+             counter("failed_total", "Messages that failed.", self.failed),
+             counter("controls_total", "Control frames answered.", self.controls),
+             counter("malformed_total", "Frames whose message would not parse.", self.malformed),
+             gauge("truncated", "Whether the input ended mid-frame.", self.truncated as u64)]
+    }
+}
+
+impl Metrics for WorkerStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("consumed_total", "Frames consumed.", self.consumed),
+             counter("failed_total", "Frames refused or unparseable.", self.failed),
+             counter("out_of_order_total", "Frames out of order.", self.out_of_order)]
+    }
+}
+
+impl Metrics for QueueStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![gauge("queue_depth", "Frames waiting their turn.", self.depth as u64),
+             counter("rounds_waited_total",
+                     "Rounds ending with frames still queued.",
+                     self.rounds_waited)]
+    }
+}
+
+impl Metrics for ConnectionStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("dispatched_total", "Frames handed to workers.", self.dispatched),
+             counter("malformed_total", "Frames without a whole header.", self.malformed),
+             gauge("truncated", "Whether the input ended mid-frame.", self.truncated as u64),
+             gauge("oversized",
+                   "Whether reading stopped at a frame over the cap.",
+                   self.oversized.is_some() as u64)]
+    }
+}
+
+impl Metrics for ReporterStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("sent_total", "Reports sent.", self.sent),
+             counter("resent_total", "Reports sent again.", self.resent),
+             counter("acked_total", "Reports acked.", self.acked),
+             counter("duplicate_acks_total",
+                     "Acks for reports acked already.",
+                     self.duplicate_acks),
+             counter("unknown_acks_total", "Acks for no report sent.", self.unknown_acks),
+             counter("permanent_failures_total",
+                     "Reports that failed for good.",
+                     self.permanent_failures),
+             counter("spilled_total", "Reports moved to the overflow spool.", self.spilled),
+             counter("dropped_total", "Reports forgotten for want of room.", self.dropped)]
+    }
+}
+
+impl Metrics for DryRunCounts {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("would_store_total", "Messages that would be stored.", self.would_store),
+             counter("unauthorized_total", "Messages that would fail auth.", self.unauthorized),
+             counter("missing_id_total", "Messages for no stream.", self.missing_id),
+             counter("rejected_total", "Messages a policy would refuse.", self.rejected),
+             counter("stream_cap_exceeded_total",
+                     "Messages over a stream cap.",
+                     self.stream_cap_exceeded)]
+    }
+}
+
+impl Metrics for PressureStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("slowdowns_total", "Frames after which to slow down.", self.slowdowns),
+             time("slept_seconds_total", "Time asleep for pressure.", self.slept),
+             counter("stops_total", "Frames after which to stop.", self.stops)]
+    }
+}
+
+impl Metrics for ThrottleStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("throttle_tokens_total", "Throttle tokens consumed.", self.tokens_consumed),
+             time("throttled_wait_seconds_total", "Time asleep for tokens.", self.throttled_wait),
+             counter("not_ready_total", "Reads put off for want of tokens.", self.not_ready)]
+    }
+}
+
+impl Metrics for InternStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("intern_hits_total", "IDs found interned.", self.hits),
+             counter("intern_misses_total", "IDs interned anew.", self.misses),
+             counter("intern_evictions_total", "IDs let go of.", self.evictions)]
+    }
+}
+
+impl Metrics for ReassemblyStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("completed_total", "Uploads completed.", self.completed),
+             counter("evicted_total", "Uploads evicted.", self.evicted),
+             counter("aborted_total", "Uploads aborted.", self.aborted),
+             counter("duplicate_bytes_total", "Chunk bytes dropped.", self.duplicate_bytes),
+             gauge("staged_bytes", "Bytes staged.", self.staged_bytes),
+             gauge("uploads", "Unfinished uploads.", self.uploads as u64)]
+    }
+}
+
+impl Metrics for SequenceStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("gaps_total", "Gaps in sequence.", self.gaps),
+             counter("missing_total", "Sequence numbers missed.", self.missing),
+             counter("regressions_total", "Sequence numbers going back.", self.regressions),
+             counter("unparseable_total", "Payloads without a sequence number.", self.unparseable),
+             counter("wraparounds_total", "Sequences wrapping around.", self.wraparounds)]
+    }
+}
+
+impl Metrics for WriteStats {
+    fn metrics(&self) -> Vec<Metric> {
+        vec![counter("write_retries_total", "Writes retried.", self.retries),
+             counter("voided_total", "Records voided by a failed push.", self.voided)]
+    }
+}
+
+type Source = Box<Fn(&str, &mut String) + Send + Sync>;
+
+/// What an application renders for each scrape. Give each source its own
+/// prefix, as a metric rendered twice under one name is not valid.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    sources: Vec<(String, Source)>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        MetricsRegistry::default()
+    }
+
+    /// Adds a source rendered afresh by `render` for each scrape.
+    pub fn register<F>(&mut self, prefix: &str, render: F)
+        where F: Fn(&str, &mut String) + Send + Sync + 'static
+    {
+        self.sources.push((prefix.to_owned(), Box::new(render)));
+    }
+
+    /// Adds stats kept up to date behind a lock.
+    pub fn register_shared<R>(&mut self, prefix: &str, stats: Arc<Mutex<R>>)
+        where R: Render + Send + 'static
+    {
+        self.register(prefix, move |prefix, out| stats.lock().unwrap().render_into(prefix, out));
+    }
+
+    /// Every source, in the order registered.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for &(ref prefix, ref render) in &self.sources {
+            render(prefix, &mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use server::TokenUsage;
+
+    #[test]
+    fn renders_exactly() {
+        let stats = PressureStats {
+            slowdowns: 3,
+            slept: Duration::from_millis(1500),
+            stops: 0,
+        };
+        let mut out = String::new();
+        stats.render_into("sv", &mut out);
+        assert_eq!("# HELP sv_slowdowns_total Frames after which to slow down.\n\
+                    # TYPE sv_slowdowns_total counter\n\
+                    sv_slowdowns_total 3\n\
+                    # HELP sv_slept_seconds_total Time asleep for pressure.\n\
+                    # TYPE sv_slept_seconds_total counter\n\
+                    sv_slept_seconds_total 1.5\n\
+                    # HELP sv_stops_total Frames after which to stop.\n\
+                    # TYPE sv_stops_total counter\n\
+                    sv_stops_total 0\n",
+                   out);
+
+        let mut histogram = LatencyHistogram::new(&[Duration::from_millis(10),
+                                                    Duration::from_secs(1)]);
+        for &ms in &[1, 5, 20, 3000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let mut out = String::new();
+        histogram.render_into("", &mut out);
+        assert_eq!("# HELP latency_seconds Time from each message's header timestamp to its \
+                    consumption.\n\
+                    # TYPE latency_seconds histogram\n\
+                    latency_seconds_bucket{le=\"0.01\"} 2\n\
+                    latency_seconds_bucket{le=\"1\"} 3\n\
+                    latency_seconds_bucket{le=\"+Inf\"} 4\n\
+                    latency_seconds_count 4\n",
+                   out);
+    }
+
+    #[test]
+    fn escapes_and_bounds_labels() {
+        let queues: Vec<_> = (1..5)
+                                 .map(|depth| QueueStats { depth: depth, rounds_waited: 1 })
+                                 .collect();
+        let mut by_worker = ByLabel::new("worker");
+        by_worker.set_bound(2)
+                 .add("quote \" and \\", &queues[0])
+                 .add("line\nbreak", &queues[1])
+                 .add("2", &queues[2])
+                 .add("3", &queues[3]);
+        let mut out = String::new();
+        by_worker.render_into("pool", &mut out);
+        assert_eq!("# HELP pool_queue_depth Frames waiting their turn.\n\
+                    # TYPE pool_queue_depth gauge\n\
+                    pool_queue_depth{worker=\"quote \\\" and \\\\\"} 1\n\
+                    pool_queue_depth{worker=\"line\\nbreak\"} 2\n\
+                    pool_queue_depth{worker=\"other\"} 7\n\
+                    # HELP pool_rounds_waited_total Rounds ending with frames still queued.\n\
+                    # TYPE pool_rounds_waited_total counter\n\
+                    pool_rounds_waited_total{worker=\"quote \\\" and \\\\\"} 1\n\
+                    pool_rounds_waited_total{worker=\"line\\nbreak\"} 1\n\
+                    pool_rounds_waited_total{worker=\"other\"} 2\n",
+                   out);
+
+        let mut snapshot = PeriodSnapshot::default();
+        snapshot.usage = (0..TOP_K as u64 + 5)
+                             .map(|fp| {
+                                 (fp, TokenUsage { stored: fp, ..TokenUsage::default() })
+                             })
+                             .collect::<BTreeMap<_, _>>();
+        let mut out = String::new();
+        snapshot.render_into("acct", &mut out);
+        let stored: Vec<_> = out.lines().filter(|l| l.starts_with("acct_stored_total{")).collect();
+        assert_eq!(TOP_K + 1, stored.len());
+        assert_eq!("acct_stored_total{token=\"000000000000000e\"} 14", stored[0]);
+        // The five least stored, 0 to 4.
+        assert_eq!("acct_stored_total{token=\"other\"} 10", stored[TOP_K]);
+    }
+
+    #[test]
+    fn registry_renders_every_source() {
+        let shared = Arc::new(Mutex::new(WorkerStats::default()));
+        let mut registry = MetricsRegistry::new();
+        registry.register_shared("worker", shared.clone());
+        registry.register("", |prefix, out| {
+            SequenceStats { gaps: 1, ..SequenceStats::default() }.render_into(prefix, out)
+        });
+        shared.lock().unwrap().consumed = 7;
+        let out = registry.render();
+        assert!(out.contains("\nworker_consumed_total 7\n"), "{}", out);
+        assert!(out.contains("\ngaps_total 1\n"), "{}", out);
+        let names: Vec<_> = out.lines().filter(|l| l.starts_with("# TYPE")).collect();
+        assert_eq!(3 + 5, names.len());
+    }
+
+    /// Every `pub struct` named for stats or counts anywhere in the crate.
+    fn stats_structs(dir: &Path, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                stats_structs(&path, found);
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            for line in source.lines() {
+                let name: String = match line.trim_left().split("pub struct ").nth(1) {
+                    None => continue,
+                    Some(rest) => rest.chars().take_while(|c| c.is_alphanumeric()).collect(),
+                };
+                if name.ends_with("Stats") || name.ends_with("Counts") {
+                    found.push(name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_stats_struct_renders() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut structs = vec![];
+        stats_structs(&src, &mut structs);
+        assert!(structs.len() >= 14, "{:?}", structs);
+        let this = fs::read_to_string(src.join("metrics.rs")).unwrap();
+        let missing: Vec<_> = structs.iter()
+                                     .filter(|name| {
+                                         !this.contains(&format!("impl Metrics for {} ", name)) &&
+                                         !this.contains(&format!("impl Render for {} ", name))
+                                     })
+                                     .collect();
+        assert!(missing.is_empty(), "no Render for {:?}", missing);
+    }
+}
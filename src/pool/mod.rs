@@ -0,0 +1,1556 @@
+//! A pool of worker threads, each with a server of its own, to which every
+//! frame for an ID goes to the same worker.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SendError, Sender, SyncSender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use message::Header;
+use clock::SystemClock;
+use server::Consumed;
+use {Clock, Message, Server, SmallId, Stream};
+use session::{IdInterner, Labeled};
+use util::fill;
+
+mod shard;
+
+pub use self::shard::{Modulo, Ring, ShardMap};
+
+/// A frame on its way to a worker, numbered within its (connection, ID).
+struct Job {
+    connection: usize,
+    peer: Option<SocketAddr>,
+    /// The message's, as the connection made it.
+    id: SmallId,
+    seq: u64,
+    /// Its place among every frame the connection dispatched.
+    order: u64,
+    message: Vec<u8>,
+    queued_at: Duration,
+}
+
+enum Task {
+    Frame(Job),
+    /// Sent by `resize` behind every frame queued so far: the worker
+    /// consumes everything it holds, then takes its next `Control`.
+    Barrier,
+    /// Sent to every worker by a dropped `Connection`, behind all of its
+    /// frames: the worker forgets the connection once it has consumed them.
+    Closed(usize),
+}
+
+/// An ID's streams on their way between workers, with each connection's
+/// next sequence number for the ID.
+struct Moving<T> {
+    id: Vec<u8>,
+    from: usize,
+    to: usize,
+    streams: Vec<(Vec<u8>, T)>,
+    next_seq: Vec<(usize, u64)>,
+}
+
+impl<T> Moving<T> {
+    fn as_move(&self) -> Move {
+        Move {
+            id: self.id.clone(),
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+enum Control<T> {
+    /// Hand over the streams of every ID the map puts on another worker,
+    /// but for those that are busy.
+    Rebalance(Arc<ShardMap>, SyncSender<(Vec<Moving<T>>, Vec<Move>)>),
+    /// Take these streams in, handing back any the worker cannot.
+    Adopt(Vec<Moving<T>>, SyncSender<Vec<Moving<T>>>),
+}
+
+/// What a worker made of the frames it was handed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub consumed: u64,
+    /// Frames the server refused, or whose message would not parse.
+    pub failed: u64,
+    /// Frames that arrived out of their connection's order for their ID,
+    /// which the queues should make impossible.
+    pub out_of_order: u64,
+}
+
+/// How much of a worker's time one connection gets per scheduling round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    Messages(u64),
+    /// Bytes of message, header and payload: `MessageCost::frame_bytes`
+    /// less the length prefix. A connection with any budget left gets its
+    /// next frame, however large, so every round makes progress.
+    Bytes(u64),
+}
+
+impl Budget {
+    fn cost(&self, message: &[u8]) -> u64 {
+        match *self {
+            Budget::Messages(_) => 1,
+            Budget::Bytes(_) => message.len() as u64,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        match *self {
+            Budget::Messages(n) | Budget::Bytes(n) => n,
+        }
+    }
+}
+
+/// A connection whose oldest queued frame has waited too long.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Starvation {
+    pub worker: usize,
+    pub connection: usize,
+    /// How long the oldest frame has waited since it was dispatched.
+    pub age: Duration,
+    pub depth: usize,
+}
+
+type Alarm = Arc<Mutex<Box<FnMut(Starvation) + Send>>>;
+
+/// What a worker made of a frame, labeled with the peer of the connection
+/// that sent it, if it was made with `AffinityPool::connection_from`, and
+/// numbered among every frame that connection dispatched.
+pub type Handled<S> = Labeled<Option<SocketAddr>, Consumed<S>>;
+
+type Handler<S> = Arc<Mutex<Option<Box<FnMut(Handled<S>) + Send>>>>;
+
+/// How a worker shares itself between the connections sending to it.
+///
+/// Each worker keeps a queue per connection and serves them in rounds,
+/// giving each connection up to its budget before moving on, so that a
+/// connection with a backlog only holds up the others by a budget a round.
+pub struct Fairness {
+    budget: Budget,
+    clock: Arc<Clock + Send + Sync>,
+    alarm: Option<(Duration, Alarm)>,
+}
+
+impl Fairness {
+    /// Panics if `budget` is zero, with which no round would serve anyone.
+    pub fn new(budget: Budget) -> Self {
+        assert!(budget.total() > 0, "a connection must have some budget a round");
+        Fairness {
+            budget: budget,
+            clock: Arc::new(SystemClock),
+            alarm: None,
+        }
+    }
+
+    /// Calls `alarm`, at most once a round per connection, whenever a
+    /// connection's oldest queued frame is older than `max_age` by `clock`.
+    pub fn set_starvation_alarm<C, F>(&mut self, max_age: Duration, clock: C, alarm: F)
+        where C: Clock + Send + Sync + 'static,
+              F: FnMut(Starvation) + Send + 'static
+    {
+        self.clock = Arc::new(clock);
+        self.alarm = Some((max_age, Arc::new(Mutex::new(Box::new(alarm)))));
+    }
+}
+
+/// Every connection its whole queue each round.
+impl Default for Fairness {
+    fn default() -> Self {
+        Fairness::new(Budget::Messages(u64::max_value()))
+    }
+}
+
+/// One connection's queue on a worker.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Frames read off the worker's queue and waiting for their turn.
+    pub depth: usize,
+    /// Rounds that ended with this connection's budget spent and frames
+    /// still queued.
+    pub rounds_waited: u64,
+}
+
+struct Worker<S: Server> {
+    index: usize,
+    server: S,
+    stats: WorkerStats,
+    /// Each connection's next sequence number for each of its IDs.
+    next_seq: HashMap<usize, HashMap<SmallId, u64>>,
+    queues: BTreeMap<usize, VecDeque<Job>>,
+    /// Connections closed with frames still queued.
+    closing: HashSet<usize>,
+    buffered: usize,
+    /// Whether a barrier has been taken off the queue and not yet obeyed.
+    barrier: bool,
+    /// The tokens each ID has been consumed for, which is where its
+    /// streams are to be found when it moves.
+    held: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+    metrics: Arc<Mutex<BTreeMap<usize, QueueStats>>>,
+    handler: Handler<S>,
+}
+
+impl<S: Server> Worker<S> {
+    fn take(&mut self, task: Task) {
+        match task {
+            Task::Frame(job) => {
+                self.buffered += 1;
+                self.queues.entry(job.connection).or_insert_with(VecDeque::new).push_back(job);
+            }
+            Task::Barrier => self.barrier = true,
+            Task::Closed(connection) => {
+                if self.queues.contains_key(&connection) {
+                    self.closing.insert(connection);
+                } else {
+                    self.forget(connection);
+                }
+            }
+        }
+    }
+
+    /// Drops what is kept for a closed connection with nothing queued.
+    fn forget(&mut self, connection: usize) {
+        self.next_seq.remove(&connection);
+        self.metrics.lock().unwrap().remove(&connection);
+    }
+
+    fn consume(&mut self, job: Job) {
+        match Message::parse(&job.message) {
+            Err(_) => self.stats.failed += 1,
+            Ok(msg) => {
+                let (token, id) = (msg.header.token, msg.header.id);
+                let expected = self.next_seq
+                                   .entry(job.connection)
+                                   .or_insert_with(HashMap::new)
+                                   .entry(job.id)
+                                   .or_insert(0);
+                if job.seq != *expected {
+                    self.stats.out_of_order += 1;
+                }
+                *expected = job.seq + 1;
+                let consumed = self.server.consume(msg);
+                match consumed {
+                    Ok(_) => {
+                        self.stats.consumed += 1;
+                        self.hold(token, id);
+                    }
+                    Err(_) => self.stats.failed += 1,
+                }
+                if let Some(ref mut handler) = *self.handler.lock().unwrap() {
+                    handler(Labeled {
+                        label: job.peer,
+                        seq: job.order,
+                        item: consumed,
+                    });
+                }
+            }
+        }
+    }
+
+    fn hold(&mut self, token: &[u8], id: &[u8]) {
+        if self.held.get(id).map_or(false, |tokens| tokens.contains(token)) {
+            return;
+        }
+        self.held.entry(id.to_vec()).or_insert_with(HashSet::new).insert(token.to_vec());
+    }
+
+    /// Takes out the streams of every ID held that `map` puts elsewhere,
+    /// unless one of them is busy.
+    fn release(&mut self, map: &ShardMap) -> (Vec<Moving<S::Stream>>, Vec<Move>) {
+        let index = self.index;
+        let away: Vec<_> = self.held
+                               .keys()
+                               .filter(|id| map.shard_for(id) != index)
+                               .cloned()
+                               .collect();
+        let mut leaving = vec![];
+        let mut deferred = vec![];
+        for id in away {
+            let tokens = self.held.remove(&id).unwrap();
+            let mut moving = Moving {
+                to: map.shard_for(&id),
+                id: id,
+                from: index,
+                streams: vec![],
+                next_seq: vec![],
+            };
+            let busy = tokens.iter().any(|token| {
+                self.server
+                    .auth(token)
+                    .ok()
+                    .and_then(|finder| finder.get(&moving.id[..]))
+                    .map_or(false, Stream::is_busy)
+            });
+            if busy {
+                deferred.push(moving.as_move());
+                self.held.insert(moving.id, tokens);
+                continue;
+            }
+            for token in tokens {
+                if let Some(stream) = self.server
+                                          .auth(&token)
+                                          .ok()
+                                          .and_then(|finder| finder.remove(&moving.id[..])) {
+                    moving.streams.push((token, stream));
+                }
+            }
+            {
+                let (id, next_seq) = (&moving.id, &mut moving.next_seq);
+                for (&connection, seqs) in &mut self.next_seq {
+                    if let Some(seq) = seqs.remove(&id[..]) {
+                        next_seq.push((connection, seq));
+                    }
+                }
+            }
+            leaving.push(moving);
+        }
+        (leaving, deferred)
+    }
+
+    /// Puts each ID's streams in the server, or none of them if any token
+    /// will not authenticate or already has a stream for the ID.
+    fn adopt(&mut self, arriving: Vec<Moving<S::Stream>>) -> Vec<Moving<S::Stream>> {
+        let mut refused = vec![];
+        for moving in arriving {
+            let fits = {
+                let server = &mut self.server;
+                moving.streams.iter().all(|&(ref token, _)| {
+                    server.auth(token).ok().map_or(false, |finder| !finder.contains_key(&moving.id))
+                })
+            };
+            if !fits {
+                refused.push(moving);
+                continue;
+            }
+            let Moving { id, streams, next_seq, .. } = moving;
+            for (token, stream) in streams {
+                if let Ok(finder) = self.server.auth(&token) {
+                    finder.insert(id.clone(), stream);
+                }
+                self.hold(&token, &id);
+            }
+            for (connection, seq) in next_seq {
+                self.next_seq
+                    .entry(connection)
+                    .or_insert_with(HashMap::new)
+                    .insert(SmallId::new(&id), seq);
+            }
+        }
+        refused
+    }
+
+    /// The pool waits on every reply, and only stops waiting if it panics,
+    /// leaving its routing poisoned. Streams it would then never receive
+    /// stay here, while those refused are dropped, as nowhere is left to
+    /// place them.
+    fn obey(&mut self, control: Control<S::Stream>) {
+        match control {
+            Control::Rebalance(map, reply) => {
+                if let Err(SendError((leaving, _))) = reply.send(self.release(&*map)) {
+                    self.adopt(leaving);
+                }
+            }
+            Control::Adopt(arriving, reply) => {
+                let refused = self.adopt(arriving);
+                if let Err(SendError(refused)) = reply.send(refused) {
+                    drop(refused);
+                }
+            }
+        }
+    }
+
+    fn check_starvation(&self, fairness: &Fairness, alarmed: &mut BTreeSet<usize>) {
+        let (max_age, ref alarm) = match fairness.alarm {
+            None => return,
+            Some(ref alarm) => alarm.clone(),
+        };
+        let now = fairness.clock.now();
+        for (&connection, queue) in &self.queues {
+            let oldest = match queue.front() {
+                None => continue,
+                Some(job) => job.queued_at,
+            };
+            if now > oldest && now - oldest > max_age && alarmed.insert(connection) {
+                (&mut *alarm.lock().unwrap())(Starvation {
+                    worker: self.index,
+                    connection: connection,
+                    age: now - oldest,
+                    depth: queue.len(),
+                });
+            }
+        }
+    }
+
+    /// Gives every connection with frames queued up to its budget.
+    fn round(&mut self, fairness: &Fairness) {
+        let mut alarmed = BTreeSet::new();
+        self.check_starvation(fairness, &mut alarmed);
+        let connections: Vec<_> = self.queues.keys().cloned().collect();
+        for connection in connections {
+            let mut left = fairness.budget.total();
+            while left > 0 {
+                let job = match self.queues.get_mut(&connection).unwrap().pop_front() {
+                    None => break,
+                    Some(job) => job,
+                };
+                self.buffered -= 1;
+                left = left.saturating_sub(fairness.budget.cost(&job.message));
+                self.consume(job);
+                self.check_starvation(fairness, &mut alarmed);
+            }
+            let depth = self.queues[&connection].len();
+            if depth == 0 {
+                self.queues.remove(&connection);
+                if self.closing.remove(&connection) {
+                    self.forget(connection);
+                    continue;
+                }
+            }
+            let mut metrics = self.metrics.lock().unwrap();
+            let metrics = metrics.entry(connection).or_insert_with(QueueStats::default);
+            metrics.depth = depth;
+            if depth > 0 {
+                metrics.rounds_waited += 1;
+            }
+        }
+    }
+}
+
+fn work<S: Server>(worker: Worker<S>,
+                   jobs: Receiver<Task>,
+                   control: Receiver<Control<S::Stream>>,
+                   max_buffered: usize,
+                   fairness: Arc<Fairness>)
+                   -> (S, WorkerStats) {
+    let mut worker = worker;
+    loop {
+        if worker.buffered == 0 && !worker.barrier {
+            match jobs.recv() {
+                Ok(task) => worker.take(task),
+                Err(_) => break,
+            }
+        }
+        while worker.buffered < max_buffered && !worker.barrier {
+            match jobs.try_recv() {
+                Ok(task) => worker.take(task),
+                Err(_) => break,
+            }
+        }
+        worker.round(&fairness);
+        if worker.barrier && worker.buffered == 0 {
+            worker.barrier = false;
+            if let Ok(control) = control.recv() {
+                worker.obey(control);
+            }
+        }
+    }
+    (worker.server, worker.stats)
+}
+
+/// An ID that `resize` moved, or would have.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Move {
+    pub id: Vec<u8>,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// The streams of an ID that neither its new worker nor its old one would
+/// take, handed back so that nothing is lost.
+#[derive(Debug)]
+pub struct Unplaced<T> {
+    pub moved: Move,
+    /// Each stream with the token it was held under.
+    pub streams: Vec<(Vec<u8>, T)>,
+}
+
+/// What `resize` did with the IDs whose worker changed. IDs deferred or
+/// refused stay on their old worker, which their frames keep going to
+/// until `retry_deferred` moves them.
+#[derive(Debug)]
+pub struct ResizeReport<T> {
+    /// IDs whose streams went over to their new worker.
+    pub moved: Vec<Move>,
+    /// IDs with a busy stream, which could not be moved yet.
+    pub deferred: Vec<Move>,
+    /// IDs the new worker's server would not take, as a token of theirs
+    /// did not authenticate there or it had a stream for them already.
+    pub refused: Vec<Move>,
+    /// IDs refused by their new worker that their old one would not take
+    /// back either, with their streams. Their frames go to the new worker
+    /// from now on.
+    pub unplaced: Vec<Unplaced<T>>,
+}
+
+impl<T> Default for ResizeReport<T> {
+    fn default() -> Self {
+        ResizeReport {
+            moved: vec![],
+            deferred: vec![],
+            refused: vec![],
+            unplaced: vec![],
+        }
+    }
+}
+
+/// Where a connection sends each frame.
+struct Routing {
+    map: Arc<ShardMap>,
+    queues: Vec<SyncSender<Task>>,
+    /// IDs held on a worker other than the map's until retried.
+    pinned: HashMap<Vec<u8>, usize>,
+}
+
+impl Routing {
+    fn worker_for(&self, id: &[u8]) -> usize {
+        if !self.pinned.is_empty() {
+            if let Some(&worker) = self.pinned.get(id) {
+                return worker;
+            }
+        }
+        self.map.shard_for(id)
+    }
+}
+
+/// Runs one server per worker thread and routes every frame to the worker
+/// its ID maps to, by the pool's `ShardMap`, so that a stream is only ever
+/// touched by one thread at a time.
+///
+/// Each worker has a bounded queue. A connection that sends to a full one
+/// waits inside `Connection::dispatch`, while connections sending to other
+/// workers carry on. Workers share themselves between connections by their
+/// `Fairness`.
+pub struct AffinityPool<S: Server> {
+    routing: Arc<RwLock<Routing>>,
+    controls: Vec<Sender<Control<S::Stream>>>,
+    workers: Vec<JoinHandle<(S, WorkerStats)>>,
+    metrics: Vec<Arc<Mutex<BTreeMap<usize, QueueStats>>>>,
+    /// What the workers stopped by shrinking handed back, for `join`.
+    retired: Vec<(S, WorkerStats)>,
+    connections: Arc<AtomicUsize>,
+    queue_len: usize,
+    fairness: Arc<Fairness>,
+    clock: Arc<Clock + Send + Sync>,
+    handler: Handler<S>,
+}
+
+impl<S> AffinityPool<S>
+    where S: Server + Send + 'static,
+          S::Stream: Send + 'static
+{
+    /// Starts a worker for each of `servers`, each queueing up to
+    /// `queue_len` frames. Panics if there are no servers.
+    pub fn new(servers: Vec<S>, queue_len: usize) -> Self {
+        AffinityPool::with_fairness(servers, queue_len, Fairness::default())
+    }
+
+    /// Like `new`, but each worker also holds up to `queue_len` frames
+    /// taken off its queue to share out by `fairness`.
+    pub fn with_fairness(servers: Vec<S>, queue_len: usize, fairness: Fairness) -> Self {
+        let map = Arc::new(Modulo(servers.len()));
+        AffinityPool::with_shard_map(servers, queue_len, fairness, map)
+    }
+
+    /// Like `with_fairness`, but routing by `map` rather than `Modulo`.
+    /// Panics unless `map` has a shard for each server.
+    pub fn with_shard_map(servers: Vec<S>,
+                          queue_len: usize,
+                          fairness: Fairness,
+                          map: Arc<ShardMap>)
+                          -> Self {
+        assert!(!servers.is_empty(), "a pool needs at least one worker");
+        assert_eq!(servers.len(), map.shards(), "a pool needs a worker for each shard");
+        let mut pool = AffinityPool {
+            routing: Arc::new(RwLock::new(Routing {
+                map: map,
+                queues: vec![],
+                pinned: HashMap::new(),
+            })),
+            controls: vec![],
+            workers: vec![],
+            metrics: vec![],
+            retired: vec![],
+            connections: Arc::new(AtomicUsize::new(0)),
+            queue_len: queue_len,
+            clock: fairness.clock.clone(),
+            fairness: Arc::new(fairness),
+            handler: Arc::new(Mutex::new(None)),
+        };
+        let queues = servers.into_iter().map(|server| pool.spawn(server)).collect();
+        pool.routing.write().unwrap().queues = queues;
+        pool
+    }
+
+    /// Starts a worker for `server`, returning its queue.
+    fn spawn(&mut self, server: S) -> SyncSender<Task> {
+        let (queue, jobs) = sync_channel(self.queue_len);
+        let (control, controls) = channel();
+        let worker = Worker {
+            index: self.workers.len(),
+            server: server,
+            stats: WorkerStats::default(),
+            next_seq: HashMap::new(),
+            queues: BTreeMap::new(),
+            closing: HashSet::new(),
+            buffered: 0,
+            barrier: false,
+            held: HashMap::new(),
+            metrics: Arc::new(Mutex::new(BTreeMap::new())),
+            handler: self.handler.clone(),
+        };
+        self.metrics.push(worker.metrics.clone());
+        self.controls.push(control);
+        let (queue_len, fairness) = (self.queue_len, self.fairness.clone());
+        self.workers.push(thread::spawn(move || work(worker, jobs, controls, queue_len, fairness)));
+        queue
+    }
+
+    /// How many workers frames are routed to. Workers left over from
+    /// shrinking the pool, until the IDs pinned to them move, are not
+    /// counted.
+    pub fn workers(&self) -> usize {
+        self.routing.read().unwrap().map.shards()
+    }
+
+    /// The worker that every frame for `id` goes to.
+    pub fn worker_for(&self, id: &[u8]) -> usize {
+        self.routing.read().unwrap().worker_for(id)
+    }
+
+    pub fn shard_map(&self) -> Arc<ShardMap> {
+        self.routing.read().unwrap().map.clone()
+    }
+
+    /// Routes over `count` workers from now on, by the pool's map with
+    /// `count` shards, moving the streams of every ID whose worker changes.
+    /// Workers are started with `new_server` as needed. Those no longer
+    /// routed to are stopped once no ID is pinned to them, and their
+    /// servers kept for `join`. Panics if `count` is zero.
+    ///
+    /// Dispatching waits while the pool is resized, and every frame
+    /// dispatched before is consumed on its old worker first, so each lands
+    /// in exactly one stream, in order.
+    pub fn resize<F>(&mut self, count: usize, new_server: F) -> ResizeReport<S::Stream>
+        where F: FnMut() -> S
+    {
+        assert!(count > 0, "a pool needs at least one worker");
+        let mut new_server = new_server;
+        let routing = self.routing.clone();
+        let mut routing = routing.write().unwrap();
+        while routing.queues.len() < count {
+            let queue = self.spawn(new_server());
+            routing.queues.push(queue);
+        }
+        let map = routing.map.with_shards(count);
+        self.rebalance(&mut routing, map)
+    }
+
+    /// Tries again to move the IDs a resize deferred or that were refused.
+    pub fn retry_deferred(&mut self) -> ResizeReport<S::Stream> {
+        let routing = self.routing.clone();
+        let mut routing = routing.write().unwrap();
+        let map = routing.map.clone();
+        self.rebalance(&mut routing, map)
+    }
+
+    fn rebalance(&mut self,
+                 routing: &mut Routing,
+                 map: Arc<ShardMap>)
+                 -> ResizeReport<S::Stream> {
+        let mut report = ResizeReport::default();
+        let workers = routing.queues.len();
+        let mut arriving: Vec<_> = (0..workers).map(|_| vec![]).collect();
+        let mut released = vec![];
+        for worker in 0..workers {
+            let (reply, replied) = sync_channel(1);
+            self.order(routing, worker, Control::Rebalance(map.clone(), reply));
+            released.push(replied);
+        }
+        for replied in released {
+            let (leaving, deferred) = replied.recv().expect("worker has stopped");
+            report.deferred.extend(deferred);
+            for moving in leaving {
+                arriving[moving.to].push(moving);
+            }
+        }
+        let mut returning: Vec<_> = (0..workers).map(|_| vec![]).collect();
+        for moving in self.adopt(routing, arriving, &mut report.moved) {
+            report.refused.push(moving.as_move());
+            returning[moving.from].push(moving);
+        }
+        for moving in self.adopt(routing, returning, &mut vec![]) {
+            report.refused.retain(|m| m.id != moving.id);
+            report.unplaced.push(Unplaced {
+                moved: moving.as_move(),
+                streams: moving.streams,
+            });
+        }
+        routing.pinned = report.deferred
+                               .iter()
+                               .chain(&report.refused)
+                               .map(|m| (m.id.clone(), m.from))
+                               .collect();
+        routing.map = map;
+        self.retire(routing);
+        report
+    }
+
+    /// Stops the workers past the map's last shard that no ID is pinned
+    /// to, keeping what they hand back.
+    fn retire(&mut self, routing: &mut Routing) {
+        let mut retired = vec![];
+        while routing.queues.len() > routing.map.shards() {
+            let last = routing.queues.len() - 1;
+            if routing.pinned.values().any(|&worker| worker == last) {
+                break;
+            }
+            routing.queues.pop();
+            self.controls.pop();
+            self.metrics.pop();
+            let worker = self.workers.pop().expect("a worker per queue");
+            retired.push(worker.join().unwrap());
+        }
+        self.retired.extend(retired.into_iter().rev());
+    }
+
+    /// Has every worker adopt the streams listed for it, noting the moves
+    /// made and returning the streams refused.
+    fn adopt(&self,
+             routing: &Routing,
+             arriving: Vec<Vec<Moving<S::Stream>>>,
+             moved: &mut Vec<Move>)
+             -> Vec<Moving<S::Stream>> {
+        let mut replies = vec![];
+        for (worker, arriving) in arriving.into_iter().enumerate() {
+            if arriving.is_empty() {
+                continue;
+            }
+            let moves: Vec<_> = arriving.iter().map(Moving::as_move).collect();
+            let (reply, replied) = sync_channel(1);
+            self.order(routing, worker, Control::Adopt(arriving, reply));
+            replies.push((moves, replied));
+        }
+        let mut refused = vec![];
+        for (moves, replied) in replies {
+            let mut refusals = replied.recv().expect("worker has stopped");
+            moved.extend(moves.into_iter().filter(|m| refusals.iter().all(|r| r.id != m.id)));
+            refused.append(&mut refusals);
+        }
+        refused
+    }
+
+    /// Has `worker` obey `control` once it has consumed every frame queued.
+    fn order(&self, routing: &Routing, worker: usize, control: Control<S::Stream>) {
+        let stopped = routing.queues[worker].send(Task::Barrier).is_err() ||
+                      self.controls[worker].send(control).is_err();
+        assert!(!stopped, "worker has stopped");
+    }
+
+    /// Every connection's queue on `worker`, as of its last round. A closed
+    /// connection is dropped once its frames are consumed.
+    pub fn queue_stats(&self, worker: usize) -> BTreeMap<usize, QueueStats> {
+        self.metrics[worker].lock().unwrap().clone()
+    }
+
+    /// Has every worker call `handler` with what it makes of each frame from
+    /// then on, from whichever worker's thread consumed it.
+    pub fn set_handler<F>(&mut self, handler: F)
+        where F: FnMut(Handled<S>) + Send + 'static
+    {
+        *self.handler.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    /// A handle for one connection to hand its frames over with, typically
+    /// moved to the thread reading that connection.
+    pub fn connection(&self) -> Connection {
+        Connection {
+            id: self.connections.fetch_add(1, Ordering::SeqCst),
+            peer: None,
+            routing: self.routing.clone(),
+            next_seq: HashMap::new(),
+            dispatched: 0,
+            interner: None,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// `connection`, for one from `peer`, which labels what the handler is
+    /// given of its frames.
+    pub fn connection_from(&self, peer: SocketAddr) -> Connection {
+        let mut connection = self.connection();
+        connection.peer = Some(peer);
+        connection
+    }
+
+    /// Waits for the workers to finish every frame queued, once every
+    /// `Connection` is dropped, and hands back their servers in order,
+    /// followed by those of the workers shrinking the pool stopped.
+    pub fn join(self) -> Vec<(S, WorkerStats)> {
+        drop(self.routing);
+        drop(self.controls);
+        let mut servers: Vec<_> =
+            self.workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+        servers.extend(self.retired);
+        servers
+    }
+}
+
+/// What was read from a connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub dispatched: u64,
+    /// Frames without a whole header, which no worker could route.
+    pub malformed: u64,
+    /// Whether the input ended mid-frame.
+    pub truncated: bool,
+    /// The length of the frame over the cap at which reading stopped.
+    pub oversized: Option<usize>,
+}
+
+/// One connection's way into an `AffinityPool`.
+pub struct Connection {
+    id: usize,
+    peer: Option<SocketAddr>,
+    routing: Arc<RwLock<Routing>>,
+    next_seq: HashMap<SmallId, u64>,
+    dispatched: u64,
+    interner: Option<IdInterner>,
+    clock: Arc<Clock + Send + Sync>,
+}
+
+impl Connection {
+    /// Has IDs too long to hold inline share an allocation through
+    /// `interner` from the next frame on, rather than each frame making
+    /// its own.
+    pub fn set_interner(&mut self, interner: Option<IdInterner>) {
+        self.interner = interner;
+    }
+
+    pub fn interner(&self) -> Option<&IdInterner> {
+        self.interner.as_ref()
+    }
+
+    /// Hands a message, without its length prefix, to the worker for its
+    /// ID, waiting while that worker's queue is full. Returns the worker,
+    /// or `None` if the header would not parse.
+    pub fn dispatch(&mut self, message: Vec<u8>) -> io::Result<Option<usize>> {
+        // Held until the frame is queued, so that no resize comes between.
+        let routing = self.routing.read().unwrap();
+        let (worker, id, seq) = match Header::parse(&message) {
+            Err(_) => return Ok(None),
+            Ok((header, _)) => {
+                let worker = routing.worker_for(header.id);
+                let id = match self.interner {
+                    Some(ref mut interner) => interner.intern_small(header.id),
+                    None => SmallId::new(header.id),
+                };
+                let seq = self.next_seq.entry(id.clone()).or_insert(0);
+                *seq += 1;
+                (worker, id, *seq - 1)
+            }
+        };
+        let job = Job {
+            connection: self.id,
+            peer: self.peer,
+            id: id,
+            seq: seq,
+            order: self.dispatched,
+            message: message,
+            queued_at: self.clock.now(),
+        };
+        match routing.queues[worker].send(Task::Frame(job)) {
+            Ok(()) => {
+                self.dispatched += 1;
+                Ok(Some(worker))
+            }
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "worker has stopped")),
+        }
+    }
+
+    /// Dispatches every frame of `reader` until it ends.
+    pub fn read_from<R: Read>(&mut self, reader: R) -> io::Result<ConnectionStats> {
+        self.read_capped(reader, u16::max_value() as usize)
+    }
+
+    /// The same as `read_from`, except that reading stops at the first
+    /// message, without its length prefix, of more than `max` bytes.
+    pub fn read_capped<R: Read>(&mut self, reader: R, max: usize) -> io::Result<ConnectionStats> {
+        let mut reader = reader;
+        let mut stats = ConnectionStats::default();
+        loop {
+            let mut prefix = [0_u8; 2];
+            match try!(fill(&mut reader, &mut prefix)) {
+                0 => return Ok(stats),
+                2 => {}
+                _ => {
+                    stats.truncated = true;
+                    return Ok(stats);
+                }
+            }
+            let len = BigEndian::read_u16(&prefix) as usize;
+            if len > max {
+                stats.oversized = Some(len);
+                return Ok(stats);
+            }
+            let mut message = vec![0; len];
+            if try!(fill(&mut reader, &mut message)) < len {
+                stats.truncated = true;
+                return Ok(stats);
+            }
+            match try!(self.dispatch(message)) {
+                Some(_) => stats.dispatched += 1,
+                None => stats.malformed += 1,
+            }
+        }
+    }
+}
+
+/// Has every worker forget the connection once it has consumed its frames.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let routing = match self.routing.read() {
+            Ok(routing) => routing,
+            Err(_) => return,
+        };
+        for queue in &routing.queues {
+            let _ = queue.send(Task::Closed(self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::SocketAddr;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::protection::fingerprint;
+    use server::{AuthError, AuthResult, Consumed, Finder};
+    use stream::Guarded;
+    use test_support::frame;
+    use test_support::server::{CountingServer, Ok};
+    use test_support::stream::ScriptedStream;
+    use trace::Spans;
+    use {Server, Stream};
+
+    type Recorder = CountingServer<Ok<ScriptedStream<::Void, ::Void>>>;
+
+    fn recorders(ids: &[Vec<u8>], n: usize) -> Vec<Recorder> {
+        (0..n)
+            .map(|_| {
+                CountingServer::new(Ok(ids.iter()
+                                          .map(|id| (id.clone(), ScriptedStream::default()))
+                                          .collect()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ids_stay_on_their_worker_in_order() {
+        let ids: Vec<_> = (0..12).map(|i| format!("id{}", i).into_bytes()).collect();
+        let pool = AffinityPool::new(recorders(&ids, 4), 2);
+        let connections: Vec<_> = (0..3_usize)
+                                      .map(|c| {
+                                          let mut connection = pool.connection();
+                                          let mut capture = vec![];
+                                          for seq in 0..20_usize {
+                                              let id = &ids[(seq * 5 + c) % ids.len()];
+                                              let payload = format!("{} {}", c, seq);
+                                              let payload = payload.as_bytes();
+                                              capture.extend(frame(b"t", id, 1, payload));
+                                          }
+                                          thread::spawn(move || {
+                                              connection.read_from(&capture[..]).unwrap()
+                                          })
+                                      })
+                                      .collect();
+        for connection in connections {
+            assert_eq!(20, connection.join().unwrap().dispatched);
+        }
+        let workers: Vec<_> = ids.iter().map(|id| pool.worker_for(id)).collect();
+        let results = pool.join();
+
+        let mut total = 0;
+        for (worker, &(ref server, ref stats)) in results.iter().enumerate() {
+            let consumed = server.calls().len() as u64;
+            assert_eq!(WorkerStats { consumed: consumed, ..WorkerStats::default() }, *stats);
+            total += stats.consumed;
+            // Per (connection, ID), payloads arrive in the order sent.
+            let mut last: HashMap<(Vec<u8>, String), usize> = HashMap::new();
+            for call in server.calls() {
+                let i = ids.iter().position(|id| *id == call.id).unwrap();
+                assert_eq!(worker, workers[i]);
+                let payload = String::from_utf8(call.payload.clone()).unwrap();
+                let mut parts = payload.split(' ');
+                let connection = parts.next().unwrap().to_owned();
+                let seq: usize = parts.next().unwrap().parse().unwrap();
+                if let Some(&previous) = last.get(&(call.id.clone(), connection.clone())) {
+                    assert!(previous < seq);
+                }
+                last.insert((call.id.clone(), connection), seq);
+            }
+        }
+        assert_eq!(60, total);
+    }
+
+    #[test]
+    fn handler_gets_labeled_results() {
+        let ids: Vec<_> = (0..4).map(|i| format!("id{}", i).into_bytes()).collect();
+        let mut pool = AffinityPool::new(recorders(&ids, 3), 2);
+        let handled = Arc::new(Mutex::new(vec![]));
+        let keep = handled.clone();
+        pool.set_handler(move |h: Handled<Recorder>| {
+            keep.lock().unwrap().push((h.label, h.seq, h.item.is_ok()));
+        });
+        let peers: Vec<SocketAddr> = vec!["10.0.0.1:1000".parse().unwrap(),
+                                          "10.0.0.2:2000".parse().unwrap()];
+        let connections: Vec<_> = peers.iter()
+                                       .map(|&peer| {
+                                           let mut connection = pool.connection_from(peer);
+                                           let mut capture = vec![];
+                                           for seq in 0..12 {
+                                               let id = if seq % 5 == 4 {
+                                                   b"unknown".to_vec()
+                                               } else {
+                                                   ids[seq % ids.len()].clone()
+                                               };
+                                               capture.extend(frame(b"t", &id, 1, b""));
+                                           }
+                                           thread::spawn(move || {
+                                               connection.read_from(&capture[..]).unwrap()
+                                           })
+                                       })
+                                       .collect();
+        for connection in connections {
+            connection.join().unwrap();
+        }
+        pool.join();
+
+        let mut by_peer: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (label, seq, ok) in handled.lock().unwrap().drain(..) {
+            by_peer.entry(label).or_insert_with(Vec::new).push((seq, ok));
+        }
+        let sent: Vec<_> = (0..12).map(|seq| (seq, seq % 5 != 4)).collect();
+        for peer in &peers {
+            let mut results = by_peer.remove(&Some(*peer)).unwrap();
+            results.sort();
+            assert_eq!(sent, results);
+        }
+        assert!(by_peer.is_empty());
+    }
+
+    #[test]
+    fn workers_forget_closed_connections() {
+        let ids: Vec<_> = (0..4).map(|i| format!("id{}", i).into_bytes()).collect();
+        let pool = AffinityPool::new(recorders(&ids, 2), 4);
+        let metrics: Vec<_> = pool.metrics.iter().cloned().collect();
+        for _ in 0..200 {
+            let mut connection = pool.connection();
+            for id in &ids {
+                connection.dispatch(frame(b"t", id, 1, b"")[2..].to_vec()).unwrap();
+            }
+        }
+        let consumed: u64 = pool.join().iter().map(|&(_, ref stats)| stats.consumed).sum();
+        assert_eq!(800, consumed);
+        assert!(metrics.iter().all(|metrics| metrics.lock().unwrap().is_empty()));
+
+        let mut worker = Worker {
+            index: 0,
+            server: recorders(&ids, 1).pop().unwrap(),
+            stats: WorkerStats::default(),
+            next_seq: HashMap::new(),
+            queues: BTreeMap::new(),
+            closing: HashSet::new(),
+            buffered: 0,
+            barrier: false,
+            held: HashMap::new(),
+            metrics: Arc::new(Mutex::new(BTreeMap::new())),
+            handler: Arc::new(Mutex::new(None)),
+        };
+        for connection in 0..200 {
+            for id in &ids {
+                worker.take(Task::Frame(Job {
+                    connection: connection,
+                    peer: None,
+                    id: SmallId::new(id),
+                    seq: 0,
+                    order: 0,
+                    message: frame(b"t", id, 1, b"")[2..].to_vec(),
+                    queued_at: Duration::from_secs(0),
+                }));
+            }
+            // Closed with its frames queued, or once they are consumed.
+            if connection % 2 == 0 {
+                worker.take(Task::Closed(connection));
+                worker.round(&Fairness::default());
+            } else {
+                worker.round(&Fairness::default());
+                worker.take(Task::Closed(connection));
+            }
+            assert!(worker.next_seq.is_empty() && worker.queues.is_empty());
+            assert!(worker.closing.is_empty() && worker.metrics.lock().unwrap().is_empty());
+        }
+        assert_eq!(800, worker.stats.consumed);
+    }
+
+    /// Lets pushes through only once opened.
+    #[derive(Clone)]
+    struct Gate(Arc<(Mutex<bool>, Condvar)>);
+
+    impl Gate {
+        fn open(&self) {
+            *(self.0).0.lock().unwrap() = true;
+            (self.0).1.notify_all();
+        }
+    }
+
+    impl Stream for Gate {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ::Void> {
+            let mut open = (self.0).0.lock().unwrap();
+            while !*open {
+                open = (self.0).1.wait(open).unwrap();
+            }
+            Result::Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<(), (Self, ::Void)> {
+            Result::Ok(())
+        }
+    }
+
+    #[test]
+    fn full_queue_stalls_only_its_senders() {
+        let ids: Vec<_> = (0..16).map(|i| format!("id{}", i).into_bytes()).collect();
+        let gates = [Gate(Arc::new((Mutex::new(false), Condvar::new()))),
+                     Gate(Arc::new((Mutex::new(true), Condvar::new())))];
+        // Worker 0 holds its first push until its gate opens.
+        let servers = gates.iter()
+                           .map(|gate| {
+                               Ok(ids.iter().map(|id| (id.clone(), gate.clone())).collect())
+                           })
+                           .collect();
+        let pool = AffinityPool::new(servers, 1);
+        let (stalled, free): (Vec<_>, Vec<_>) = ids.iter().partition(|id| pool.worker_for(id) == 0);
+
+        let (done, finished) = mpsc::channel();
+        let senders: Vec<_> = [(stalled[0].clone(), "stalled"), (free[0].clone(), "free")]
+                                  .iter()
+                                  .cloned()
+                                  .map(|(id, name)| {
+                                      // More frames than the worker can hold
+                                      // and queue between them.
+                                      let capture: Vec<_> =
+                                          (0..5).flat_map(|_| frame(b"t", &id, 1, b"x")).collect();
+                                      let mut connection = pool.connection();
+                                      let done = done.clone();
+                                      thread::spawn(move || {
+                                          let stats = connection.read_from(&capture[..]).unwrap();
+                                          done.send(name).unwrap();
+                                          stats
+                                      })
+                                  })
+                                  .collect();
+
+        assert_eq!("free", finished.recv().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(Err(mpsc::TryRecvError::Empty), finished.try_recv());
+        gates[0].open();
+        assert_eq!("stalled", finished.recv().unwrap());
+        for sender in senders {
+            assert_eq!(5, sender.join().unwrap().dispatched);
+        }
+        let stats: Vec<_> = pool.join().into_iter().map(|(_, stats)| stats.consumed).collect();
+        assert_eq!(vec![5, 5], stats);
+    }
+
+    /// Takes a second of `clock` per push, once `gate` opens, and logs each
+    /// payload with when it was done.
+    #[derive(Clone)]
+    struct Paced {
+        gate: Gate,
+        clock: SharedClock,
+        log: Arc<Mutex<Vec<(Vec<u8>, Duration)>>>,
+    }
+
+    impl Stream for Paced {
+        type PushErr = ::Void;
+        fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), ::Void> {
+            try!(self.gate.push(ts, payload));
+            self.clock.advance(Duration::from_secs(1));
+            self.log.lock().unwrap().push((payload.to_vec(), self.clock.now()));
+            Result::Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<(), (Self, ::Void)> {
+            Result::Ok(())
+        }
+    }
+
+    /// One heavy connection sends 50 frames, then two light ones send 3
+    /// each, all to a single worker held up on the first frame. Returns the
+    /// log of what was consumed, the starvation alarms, and the queues.
+    fn heavy_and_light(budget: Budget,
+                       max_age: Duration)
+                       -> (Vec<(Vec<u8>, Duration)>, Vec<Starvation>, BTreeMap<usize, QueueStats>) {
+        let paced = Paced {
+            gate: Gate(Arc::new((Mutex::new(false), Condvar::new()))),
+            clock: SharedClock::new(Duration::from_secs(0)),
+            log: Arc::new(Mutex::new(vec![])),
+        };
+        let alarms = Arc::new(Mutex::new(vec![]));
+        let mut fairness = Fairness::new(budget);
+        let raised = alarms.clone();
+        fairness.set_starvation_alarm(max_age, paced.clock.clone(), move |starvation| {
+            raised.lock().unwrap().push(starvation)
+        });
+        let ids = [b"heavy".to_vec(), b"light1".to_vec(), b"light2".to_vec()];
+        let server = Ok(ids.iter().map(|id| (id.clone(), paced.clone())).collect());
+        let pool = AffinityPool::with_fairness(vec![server], 100, fairness);
+
+        let mut connections: Vec<_> = ids.iter().map(|_| pool.connection()).collect();
+        for (i, (connection, id)) in connections.iter_mut().zip(ids.iter()).enumerate() {
+            for seq in 0..if i == 0 { 50 } else { 3 } {
+                let payload = format!("{} {}", i, seq);
+                let message = frame(b"t", id, 1, payload.as_bytes())[2..].to_vec();
+                assert_eq!(Some(0), connection.dispatch(message).unwrap());
+            }
+        }
+        paced.gate.open();
+        // Taken before the connections close, which has the worker forget
+        // their queues.
+        let queues = loop {
+            let queues = pool.queue_stats(0);
+            if paced.log.lock().unwrap().len() == 56 && queues.len() == 3 &&
+               queues.values().all(|queue| queue.depth == 0) {
+                break queues;
+            }
+            thread::yield_now();
+        };
+        drop(connections);
+        assert_eq!(56, pool.join()[0].1.consumed);
+        let log = paced.log.lock().unwrap().clone();
+        let alarms = alarms.lock().unwrap().clone();
+        (log, alarms, queues)
+    }
+
+    fn light(log: &[(Vec<u8>, Duration)]) -> Vec<(String, Duration)> {
+        log.iter()
+           .map(|&(ref payload, done)| (String::from_utf8(payload.clone()).unwrap(), done))
+           .filter(|&(ref payload, _)| !payload.starts_with("0 "))
+           .collect()
+    }
+
+    #[test]
+    fn budget_bounds_light_latency() {
+        let (log, alarms, queues) = heavy_and_light(Budget::Messages(2), Duration::from_secs(25));
+        let light = light(&log);
+        for connection in &["1", "2"] {
+            let payloads: Vec<_> = light.iter()
+                                        .map(|&(ref payload, _)| payload.clone())
+                                        .filter(|payload| payload.starts_with(connection))
+                                        .collect();
+            assert_eq!((0..3).map(|seq| format!("{} {}", connection, seq)).collect::<Vec<_>>(),
+                       payloads);
+        }
+        // At most two heavy frames before the light ones are read off the
+        // queue, then three rounds of two frames from each connection.
+        assert!(light.iter().all(|&(_, done)| done <= Duration::from_secs(20)),
+                "{:?}",
+                light);
+        assert!(alarms.iter().all(|alarm| alarm.connection == 0), "{:?}", alarms);
+        assert!(queues[&0].rounds_waited >= 24);
+        assert!(queues.values().all(|queue| queue.depth == 0));
+    }
+
+    #[test]
+    fn starvation_alarm_without_budget() {
+        let (log, alarms, queues) = heavy_and_light(Budget::Bytes(u64::max_value()),
+                                                    Duration::from_secs(10));
+        assert!(light(&log).iter().all(|&(_, done)| done > Duration::from_secs(48)));
+        let starved: BTreeSet<_> = alarms.iter().map(|alarm| alarm.connection).collect();
+        assert!(starved.contains(&1) && starved.contains(&2), "{:?}", alarms);
+        assert!(alarms.iter()
+                      .all(|alarm| alarm.worker == 0 && alarm.age > Duration::from_secs(10)));
+        assert!(queues.values().all(|queue| queue.rounds_waited == 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "a connection must have some budget a round")]
+    fn zero_message_budget() {
+        Fairness::new(Budget::Messages(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "a connection must have some budget a round")]
+    fn zero_byte_budget() {
+        Fairness::new(Budget::Bytes(0));
+    }
+
+    #[test]
+    fn routes_by_fingerprint_modulo_workers_by_default() {
+        let ids: Vec<_> = (0..50).map(|i| format!("id{}", i).into_bytes()).collect();
+        let pool = AffinityPool::new(recorders(&ids, 3), 1);
+        for id in &ids {
+            assert_eq!((fingerprint(&[id]) % 3) as usize, pool.worker_for(id));
+        }
+    }
+
+    /// A server for each of `map`'s shards, with streams for the IDs in it.
+    fn sharded(ids: &[Vec<u8>], map: &ShardMap) -> Vec<Recorder> {
+        (0..map.shards())
+            .map(|shard| {
+                CountingServer::new(Ok(ids.iter()
+                                          .filter(|id| map.shard_for(id) == shard)
+                                          .map(|id| (id.clone(), ScriptedStream::default()))
+                                          .collect()))
+            })
+            .collect()
+    }
+
+    fn empty() -> Recorder {
+        CountingServer::new(Ok(Finder::new()))
+    }
+
+    /// The IDs that `a` and `b` put in different shards.
+    fn changed(ids: &[Vec<u8>], a: &ShardMap, b: &ShardMap) -> BTreeSet<Vec<u8>> {
+        ids.iter().filter(|id| a.shard_for(id) != b.shard_for(id)).cloned().collect()
+    }
+
+    #[test]
+    fn resize_moves_streams_between_their_frames() {
+        let ids: Vec<_> = (0..40).map(|i| format!("id{}", i).into_bytes()).collect();
+        let ring = Ring::new(3, 64);
+        let mut pool = AffinityPool::with_shard_map(sharded(&ids, &ring),
+                                                    2,
+                                                    Fairness::default(),
+                                                    Arc::new(ring.clone()));
+        let (started, underway) = mpsc::channel();
+        let senders: Vec<_> = (0..3_usize)
+                                  .map(|c| {
+                                      let mut connection = pool.connection();
+                                      let (ids, started) = (ids.clone(), started.clone());
+                                      thread::spawn(move || {
+                                          // Ten frames for each ID.
+                                          for seq in 0..400_usize {
+                                              if seq == 100 {
+                                                  started.send(()).unwrap();
+                                              }
+                                              let id = &ids[(seq * 7 + c) % ids.len()];
+                                              let payload = format!("{} {}", c, seq);
+                                              let message = frame(b"t", id, 1, payload.as_bytes());
+                                              connection.dispatch(message[2..].to_vec()).unwrap();
+                                          }
+                                      })
+                                  })
+                                  .collect();
+        for _ in 0..3 {
+            underway.recv().unwrap();
+        }
+        let grown = pool.resize(4, empty);
+        let shrunk = pool.resize(2, empty);
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        // The two workers shrunk away are stopped.
+        assert_eq!((2, 2), (pool.workers(), pool.workers.len()));
+
+        // Every ID was consumed for before the first resize.
+        let (three, four, two) = (ring.with_shards(3), ring.with_shards(4), ring.with_shards(2));
+        let ids_of = |moves: &[Move]| moves.iter().map(|m| m.id.clone()).collect::<BTreeSet<_>>();
+        assert_eq!(changed(&ids, &*three, &*four), ids_of(&grown.moved));
+        assert!(grown.moved.iter().all(|m| m.to == 3));
+        assert_eq!(changed(&ids, &*four, &*two), ids_of(&shrunk.moved));
+        assert!(shrunk.moved.iter().all(|m| m.from >= 2 && m.to < 2));
+        for report in &[grown, shrunk] {
+            assert!(report.deferred.is_empty() && report.refused.is_empty() &&
+                    report.unplaced.is_empty());
+        }
+
+        let results = pool.join();
+        assert_eq!(4, results.len());
+        let consumed: u64 = results.iter().map(|&(_, ref stats)| stats.consumed).sum();
+        assert_eq!(1200, consumed);
+        assert!(results.iter().all(|&(_, ref stats)| stats.failed == 0 && stats.out_of_order == 0));
+        for id in &ids {
+            let holders: Vec<_> = results.iter()
+                                         .enumerate()
+                                         .filter(|&(_, &(ref server, _))| {
+                                             server.get_ref().0.contains_key(id)
+                                         })
+                                         .map(|(worker, _)| worker)
+                                         .collect();
+            assert_eq!(vec![two.shard_for(id)], holders);
+            // Every frame for the ID, in each connection's order.
+            let pushed = results[holders[0]].0.get_ref().0[id].pushed();
+            assert_eq!(30, pushed.len());
+            let mut last: HashMap<String, usize> = HashMap::new();
+            for &(_, ref payload) in pushed {
+                let payload = String::from_utf8(payload.clone()).unwrap();
+                let mut parts = payload.split(' ');
+                let connection = parts.next().unwrap().to_owned();
+                let seq: usize = parts.next().unwrap().parse().unwrap();
+                assert!(last.get(&connection).map_or(true, |&previous| previous < seq));
+                last.insert(connection, seq);
+            }
+        }
+    }
+
+    #[test]
+    fn busy_streams_stay_until_retried() {
+        let ids: Vec<_> = (0..20).map(|i| format!("id{}", i).into_bytes()).collect();
+        let ring = Ring::new(1, 64);
+        let grown = ring.with_shards(2);
+        let busy = ids.iter().find(|id| grown.shard_for(id) == 1).unwrap().clone();
+        let streams: Finder<Guarded<ScriptedStream<::Void, ::Void>>> =
+            ids.iter().map(|id| (id.clone(), Guarded::new(ScriptedStream::default()))).collect();
+        let intent = streams[&busy].begin_push_intent();
+        let mut pool = AffinityPool::with_shard_map(vec![CountingServer::new(Ok(streams))],
+                                                    4,
+                                                    Fairness::default(),
+                                                    Arc::new(ring));
+        let mut connection = pool.connection();
+        let mut send = |payload: &[u8]| {
+            for id in &ids {
+                connection.dispatch(frame(b"t", id, 1, payload)[2..].to_vec()).unwrap();
+            }
+        };
+
+        send(b"0");
+        let report = pool.resize(2, || CountingServer::new(Ok(Finder::new())));
+        let deferred = Move {
+            id: busy.clone(),
+            from: 0,
+            to: 1,
+        };
+        assert_eq!(vec![deferred.clone()], report.deferred);
+        assert!(!report.moved.is_empty() && !report.moved.contains(&deferred));
+        assert_eq!(0, pool.worker_for(&busy));
+        send(b"1");
+        drop(intent);
+        let retried = pool.retry_deferred();
+        assert_eq!(vec![deferred], retried.moved);
+        assert!(retried.deferred.is_empty() && retried.refused.is_empty() &&
+                retried.unplaced.is_empty());
+        assert_eq!(1, pool.worker_for(&busy));
+        send(b"2");
+        drop(send);
+        drop(connection);
+
+        let results = pool.join();
+        assert!(results.iter().all(|&(_, ref stats)| stats.failed == 0 && stats.out_of_order == 0));
+        for id in &ids {
+            let (ref server, _) = results[grown.shard_for(id)];
+            let payloads: Vec<_> = server.get_ref().0[id]
+                                         .get_ref()
+                                         .pushed()
+                                         .iter()
+                                         .map(|&(_, ref payload)| payload.clone())
+                                         .collect();
+            assert_eq!(vec![b"0".to_vec(), b"1".to_vec(), b"2".to_vec()], payloads);
+            assert!(!results[1 - grown.shard_for(id)].0.get_ref().0.contains_key(id));
+        }
+    }
+
+    #[test]
+    fn refused_streams_stay_on_their_worker() {
+        let ids: Vec<_> = (0..20).map(|i| format!("id{}", i).into_bytes()).collect();
+        let mut pool = AffinityPool::new(recorders(&ids, 1), 4);
+        let mut connection = pool.connection();
+        for id in &ids {
+            connection.dispatch(frame(b"t", id, 1, b"x")[2..].to_vec()).unwrap();
+        }
+        // The new worker has a stream for every ID already.
+        let report = pool.resize(2, || recorders(&ids, 1).pop().unwrap());
+        assert!(report.moved.is_empty() && report.unplaced.is_empty());
+        assert_eq!(ids.iter().filter(|id| Modulo(2).shard_for(id) == 1).count(),
+                   report.refused.len());
+        for id in &ids {
+            assert_eq!(0, pool.worker_for(id));
+            connection.dispatch(frame(b"t", id, 1, b"y")[2..].to_vec()).unwrap();
+        }
+        drop(connection);
+        let results = pool.join();
+        assert_eq!(WorkerStats { consumed: 40, ..WorkerStats::default() }, results[0].1);
+        assert_eq!(WorkerStats::default(), results[1].1);
+    }
+
+    /// Authenticates only while it holds a stream, so takes none back once
+    /// its last is released.
+    struct Emptying(Finder<ScriptedStream<::Void, ::Void>>);
+
+    impl Server for Emptying {
+        type Stream = ScriptedStream<::Void, ::Void>;
+        type AuthErr = ::Void;
+        fn auth(&mut self, _: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+            if self.0.is_empty() {
+                Err(AuthError::InvalidToken)
+            } else {
+                Result::Ok(&mut self.0)
+            }
+        }
+
+        type ConsumeOk = ();
+        fn consume_parts_traced(&mut self,
+                                token: &[u8],
+                                id: &[u8],
+                                timestamp: Duration,
+                                payload: &[u8],
+                                _: &mut Spans)
+                                -> Consumed<Self> {
+            self.push_parts(token, id, timestamp, payload)
+        }
+    }
+
+    #[test]
+    fn unplaceable_streams_are_handed_back() {
+        let id = (0..)
+                     .map(|i| format!("id{}", i).into_bytes())
+                     .find(|id| Modulo(2).shard_for(id) == 1)
+                     .unwrap();
+        let holding = || {
+            Emptying(vec![(id.clone(), ScriptedStream::default())].into_iter().collect())
+        };
+        let mut pool = AffinityPool::new(vec![holding()], 4);
+        let mut connection = pool.connection();
+        connection.dispatch(frame(b"t", &id, 1, b"x")[2..].to_vec()).unwrap();
+        // The new worker has a stream for the ID, and the old one no longer
+        // authenticates once it has handed its stream over.
+        let report = pool.resize(2, &holding);
+        assert!(report.moved.is_empty() && report.deferred.is_empty() &&
+                report.refused.is_empty());
+        assert_eq!(1, report.unplaced.len());
+        let unplaced = &report.unplaced[0];
+        assert_eq!(Move {
+                       id: id.clone(),
+                       from: 0,
+                       to: 1,
+                   },
+                   unplaced.moved);
+        assert_eq!(1, unplaced.streams.len());
+        assert_eq!(b"t", &unplaced.streams[0].0[..]);
+        assert_eq!(1, unplaced.streams[0].1.pushed().len());
+        assert_eq!(1, pool.worker_for(&id));
+        drop(connection);
+        pool.join();
+    }
+}
@@ -0,0 +1,174 @@
+//! Which worker of an `AffinityPool` each ID goes to.
+
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use server::protection::fingerprint;
+
+/// Spreads IDs over shards, the same way every time for the same IDs and
+/// shard count. Called for every frame, so it should be quick.
+pub trait ShardMap: Send + Sync {
+    fn shards(&self) -> usize;
+
+    /// The shard for `id`, below `shards()`.
+    fn shard_for(&self, id: &[u8]) -> usize;
+
+    /// A map of the same kind across `shards` shards.
+    fn with_shards(&self, shards: usize) -> Arc<ShardMap>;
+}
+
+/// An ID's fingerprint modulo the shard count, which is what a pool maps
+/// by unless told otherwise. Changing the count moves nearly every ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Modulo(pub usize);
+
+impl ShardMap for Modulo {
+    fn shards(&self) -> usize {
+        self.0
+    }
+
+    fn shard_for(&self, id: &[u8]) -> usize {
+        (fingerprint(&[id]) % self.0 as u64) as usize
+    }
+
+    fn with_shards(&self, shards: usize) -> Arc<ShardMap> {
+        Arc::new(Modulo(shards))
+    }
+}
+
+/// Where `parts` go on a ring: their fingerprint, with its bits mixed as
+/// by MurmurHash3's finalizer, since fingerprints of like inputs are alike
+/// in their high bits.
+fn position(parts: &[&[u8]]) -> u64 {
+    let mut hash = fingerprint(parts);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ hash >> 33
+}
+
+/// Consistent hashing: each shard owns the arcs of a ring ending at its
+/// virtual nodes, and an ID goes to the shard owning its fingerprint.
+/// Adding or removing a shard only moves the IDs on the arcs it gains or
+/// loses, about one in the new count. Lookups take logarithmic time in
+/// the virtual nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ring {
+    vnodes: usize,
+    shards: usize,
+    /// By position on the ring.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// Panics if `shards` or `vnodes` is zero.
+    pub fn new(shards: usize, vnodes: usize) -> Self {
+        assert!(shards > 0 && vnodes > 0, "a ring needs a shard and a virtual node");
+        let mut points = Vec::with_capacity(shards * vnodes);
+        for shard in 0..shards {
+            for vnode in 0..vnodes {
+                let mut name = [0; 16];
+                BigEndian::write_u64(&mut name, shard as u64);
+                BigEndian::write_u64(&mut name[8..], vnode as u64);
+                points.push((position(&[b"shard", &name]), shard));
+            }
+        }
+        points.sort();
+        Ring {
+            vnodes: vnodes,
+            shards: shards,
+            points: points,
+        }
+    }
+
+    pub fn vnodes(&self) -> usize {
+        self.vnodes
+    }
+}
+
+impl ShardMap for Ring {
+    fn shards(&self) -> usize {
+        self.shards
+    }
+
+    fn shard_for(&self, id: &[u8]) -> usize {
+        let hash = position(&[id]);
+        let at = match self.points.binary_search_by(|&(point, _)| point.cmp(&hash)) {
+            Ok(at) | Err(at) => at,
+        };
+        self.points[at % self.points.len()].1
+    }
+
+    fn with_shards(&self, shards: usize) -> Arc<ShardMap> {
+        Arc::new(Ring::new(shards, self.vnodes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `n` IDs of scattered bytes, the same every run.
+    fn ids(n: usize) -> Vec<Vec<u8>> {
+        (0..n as u64)
+            .map(|i| {
+                let mut id = vec![0; 8];
+                BigEndian::write_u64(&mut id, i.wrapping_mul(0x9e3779b97f4a7c15));
+                id
+            })
+            .collect()
+    }
+
+    /// The fraction of `ids` that `a` and `b` put on different shards.
+    fn moved(a: &ShardMap, b: &ShardMap, ids: &[Vec<u8>]) -> f64 {
+        let moved = ids.iter().filter(|id| a.shard_for(id) != b.shard_for(id)).count();
+        moved as f64 / ids.len() as f64
+    }
+
+    #[test]
+    fn ring_moves_about_one_in_the_count() {
+        let ids = ids(20000);
+        for &shards in &[2, 5, 8] {
+            let ring = Ring::new(shards, 160);
+            let grown = ring.with_shards(shards + 1);
+            let shrunk = ring.with_shards(shards - 1 + (shards == 1) as usize);
+            // Expected 1/(n+1) and 1/n; allow half again for the spread of
+            // virtual nodes.
+            let up = moved(&ring, &*grown, &ids);
+            assert!(up < 1.5 / (shards + 1) as f64, "{} -> {}: {}", shards, shards + 1, up);
+            assert!(up > 0.5 / (shards + 1) as f64, "{} -> {}: {}", shards, shards + 1, up);
+            let down = moved(&ring, &*shrunk, &ids);
+            assert!(down < 1.5 / shards as f64, "{} -> {}: {}", shards, shards - 1, down);
+            // Only IDs on the new shard move.
+            assert!(ids.iter()
+                       .filter(|id| ring.shard_for(id) != grown.shard_for(id))
+                       .all(|id| grown.shard_for(id) == shards));
+            let modulo = moved(&Modulo(shards), &Modulo(shards + 1), &ids);
+            assert!(modulo > 0.4, "{}", modulo);
+        }
+    }
+
+    #[test]
+    fn ring_spreads_ids_evenly() {
+        let ids = ids(20000);
+        let ring = Ring::new(8, 160);
+        let mut counts = vec![0; 8];
+        for id in &ids {
+            counts[ring.shard_for(id)] += 1;
+        }
+        for &count in &counts {
+            assert!(count > 20000 / 8 / 2 && count < 20000 / 8 * 3 / 2, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn modulo_is_the_fingerprint_modulo_the_count() {
+        for id in &ids(100) {
+            assert_eq!((fingerprint(&[id]) % 7) as usize, Modulo(7).shard_for(id));
+            assert_eq!(0, Modulo(1).shard_for(id));
+        }
+        assert_eq!(3, Modulo(7).with_shards(3).shards());
+    }
+}
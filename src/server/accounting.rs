@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::time::Duration;
+
+use callback::{Calls, Local, Sendable};
+use {Clock, Server};
+use message::{CostField, MessageCost};
+use stream::Pressure;
+use trace::Spans;
+use util::millis;
+use super::protection::fingerprint;
+use super::{AuthResult, ConsumeError, ConsumeResult, Consumed, DryRunOutcome};
+
+/// Failed messages, by what became of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub auth: u64,
+    pub missing_id: u64,
+    /// Refused by a wrapper's policy or a stream cap.
+    pub rejected: u64,
+    /// Refused by the stream, wholly or, for a group, in part.
+    pub push: u64,
+}
+
+/// One token's traffic in one period.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    /// Only kept with `Accounting::set_raw_tokens`.
+    pub token: Option<Vec<u8>>,
+    pub attempted: u64,
+    pub stored: u64,
+    /// The bytes of the messages stored, as `Accounting::set_cost_field`
+    /// says to count them: by default, their payloads.
+    pub bytes: u64,
+    pub errors: ErrorCounts,
+}
+
+/// The traffic of one period, `start` inclusive to `end` exclusive, by
+/// token fingerprint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeriodSnapshot {
+    pub start: Duration,
+    pub end: Duration,
+    pub usage: BTreeMap<u64, TokenUsage>,
+}
+
+/// Counts each token's traffic for billing, by periods of fixed length
+/// counted from the Unix epoch. A message belongs to the period that the
+/// clock is in when it is consumed, so one consumed exactly at a boundary
+/// is the next period's first.
+///
+/// Its rollover callback need not be `Send` unless it is over `Sendable`,
+/// as `new_send` makes it.
+pub struct Accounting<C, S, K: Calls<PeriodSnapshot> = Local> {
+    server: S,
+    clock: C,
+    period: u64,
+    current: PeriodSnapshot,
+    raw_tokens: bool,
+    cost_field: CostField,
+    on_rollover: Option<Box<K::FnMut>>,
+}
+
+impl<C: Clock, S: Server> Accounting<C, S> {
+    /// Panics if `period` is under a millisecond.
+    pub fn new(server: S, clock: C, period: Duration) -> Self {
+        Accounting::without_callbacks(server, clock, period)
+    }
+
+    /// Hands each completed period to `f`, in order. Periods without
+    /// traffic are skipped.
+    pub fn on_rollover<F: FnMut(PeriodSnapshot) + 'static>(&mut self, f: F) {
+        self.on_rollover = Some(Box::new(f));
+    }
+}
+
+impl<C: Clock, S: Server> Accounting<C, S, Sendable> {
+    /// A wrapper that is `Send` whenever its server and clock are.
+    pub fn new_send(server: S, clock: C, period: Duration) -> Self {
+        Accounting::without_callbacks(server, clock, period)
+    }
+
+    pub fn on_rollover<F: FnMut(PeriodSnapshot) + Send + 'static>(&mut self, f: F) {
+        self.on_rollover = Some(Box::new(f));
+    }
+}
+
+impl<C: Clock, S: Server, K: Calls<PeriodSnapshot>> Accounting<C, S, K> {
+    fn without_callbacks(server: S, clock: C, period: Duration) -> Self {
+        let period = millis(period);
+        assert!(period > 0, "accounting period must be at least a millisecond");
+        let mut accounting = Accounting {
+            server: server,
+            clock: clock,
+            period: period,
+            current: PeriodSnapshot::default(),
+            raw_tokens: false,
+            cost_field: CostField::Payload,
+            on_rollover: None,
+        };
+        accounting.current = accounting.period_at(accounting.clock.now());
+        accounting
+    }
+
+    /// Whether usage keeps the raw token alongside its fingerprint.
+    pub fn set_raw_tokens(&mut self, raw_tokens: bool) {
+        self.raw_tokens = raw_tokens;
+    }
+
+    /// Which of each stored message's sizes usage counts in `bytes`;
+    /// `CostField::Payload` by default.
+    pub fn set_cost_field(&mut self, field: CostField) {
+        self.cost_field = field;
+    }
+
+    /// The period in progress so far.
+    pub fn current_period(&self) -> PeriodSnapshot {
+        self.current.clone()
+    }
+
+    /// Completes the period in progress if the clock has left it. Consuming
+    /// does this too; call it to deliver a period that ends quietly.
+    pub fn roll_over(&mut self) {
+        let now = self.clock.now();
+        if now < self.current.end {
+            return;
+        }
+        let next = self.period_at(now);
+        let done = mem::replace(&mut self.current, next);
+        if let (false, Some(on_rollover)) = (done.usage.is_empty(), self.on_rollover.as_mut()) {
+            (**on_rollover)(done);
+        }
+    }
+
+    fn period_at(&self, now: Duration) -> PeriodSnapshot {
+        let start = millis(now) / self.period * self.period;
+        PeriodSnapshot {
+            start: Duration::from_millis(start),
+            end: Duration::from_millis(start + self.period),
+            usage: BTreeMap::new(),
+        }
+    }
+
+    fn account<A, P, O>(&mut self,
+                        token: &[u8],
+                        cost: MessageCost,
+                        result: &ConsumeResult<A, P, O>) {
+        self.roll_over();
+        let raw_tokens = self.raw_tokens;
+        let usage = self.current.usage.entry(fingerprint(&[token])).or_insert_with(|| {
+            TokenUsage {
+                token: if raw_tokens { Some(token.to_vec()) } else { None },
+                ..TokenUsage::default()
+            }
+        });
+        usage.attempted += 1;
+        match *result {
+            Ok(_) => {
+                usage.stored += 1;
+                usage.bytes += cost.get(self.cost_field);
+            }
+            Err(ConsumeError::Auth(_)) => usage.errors.auth += 1,
+            Err(ConsumeError::MissingId) => usage.errors.missing_id += 1,
+            Err(ConsumeError::Rejected(_)) |
+            Err(ConsumeError::StreamCapExceeded { .. }) |
+            Err(ConsumeError::ExpiredPayload { .. }) => usage.errors.rejected += 1,
+            Err(ConsumeError::Push(_)) |
+            Err(ConsumeError::GroupPartialFailure(_)) => usage.errors.push += 1,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+}
+
+impl<C: Clock, S: Server, K: Calls<PeriodSnapshot>> Server for Accounting<C, S, K> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        let (result, pressure) =
+            self.server.consume_parts_pressed(token, id, timestamp, payload, spans);
+        self.account(token, MessageCost::of_parts(token, id, payload), &result);
+        (result, pressure)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        let result = self.server.backfill_parts(token, id, timestamp, payload);
+        self.account(token, MessageCost::of_parts(token, id, payload), &result);
+        result
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::iter;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use test_support::server as mocks;
+    use message::cost::PREFIX_LEN;
+    use server::protection::fingerprint;
+    use {test_support, Server};
+
+    #[test]
+    fn two_tokens_across_a_boundary() {
+        let clock = ManualClock::new(Duration::from_secs(3590));
+        let finder = iter::once((b"id".to_vec(), test_support::stream::Ok)).collect();
+        let mut server = Accounting::new(mocks::Ok(finder), &clock, Duration::from_secs(3600));
+        server.set_raw_tokens(true);
+        let delivered = Rc::new(RefCell::new(vec![]));
+        let sink = delivered.clone();
+        server.on_rollover(move |snapshot| sink.borrow_mut().push(snapshot));
+
+        let ts = Duration::from_secs(1);
+        assert!(server.consume_parts(b"a", b"id", ts, b"12345").is_ok());
+        assert!(server.consume_parts(b"a", b"nope", ts, b"12").is_err());
+        assert!(server.consume_parts(b"b", b"id", ts, b"123").is_ok());
+        clock.set(Duration::from_secs(3600));
+        assert!(delivered.borrow().is_empty());
+        assert!(server.consume_parts(b"b", b"id", ts, b"1234567").is_ok());
+
+        let (a, b) = (fingerprint(&[b"a"]), fingerprint(&[b"b"]));
+        let usage = |token: &[u8], attempted, stored, bytes, missing_id| {
+            TokenUsage {
+                token: Some(token.to_vec()),
+                attempted: attempted,
+                stored: stored,
+                bytes: bytes,
+                errors: ErrorCounts { missing_id: missing_id, ..ErrorCounts::default() },
+            }
+        };
+        assert_eq!(vec![PeriodSnapshot {
+                            start: Duration::from_secs(0),
+                            end: Duration::from_secs(3600),
+                            usage: vec![(a, usage(b"a", 2, 1, 5, 1)), (b, usage(b"b", 1, 1, 3, 0))]
+                                       .into_iter()
+                                       .collect(),
+                        }],
+                   *delivered.borrow());
+        assert_eq!(PeriodSnapshot {
+                       start: Duration::from_secs(3600),
+                       end: Duration::from_secs(7200),
+                       usage: iter::once((b, usage(b"b", 1, 1, 7, 0))).collect(),
+                   },
+                   server.current_period());
+
+        // A quiet period is skipped, and one ending quietly is delivered
+        // on request.
+        clock.set(Duration::from_secs(11000));
+        server.roll_over();
+        assert_eq!(2, delivered.borrow().len());
+        assert_eq!(Duration::from_secs(10800), server.current_period().start);
+        clock.set(Duration::from_secs(15000));
+        server.roll_over();
+        assert_eq!(2, delivered.borrow().len());
+    }
+
+    #[test]
+    fn fingerprints_only_by_default() {
+        let clock = ManualClock::new(Duration::from_secs(0));
+        let mut server = Accounting::new(mocks::RefuseToAuth, &clock, Duration::from_secs(60));
+        assert!(server.consume_parts(b"t", b"id", Duration::from_secs(1), b"").is_err());
+        let usage = &server.current_period().usage[&fingerprint(&[b"t"])];
+        assert_eq!(None, usage.token);
+        assert_eq!((1, 0, 1), (usage.attempted, usage.stored, usage.errors.auth));
+    }
+
+    type Stack<'c> = Accounting<&'c ManualClock,
+                                Accounting<&'c ManualClock,
+                                           Accounting<&'c ManualClock,
+                                                      mocks::Ok<test_support::stream::Ok>>>>;
+
+    type Usage = BTreeMap<u64, TokenUsage>;
+
+    fn stack(clock: &ManualClock, fields: [CostField; 3]) -> Stack {
+        let finder = iter::once((b"id".to_vec(), test_support::stream::Ok)).collect();
+        let period = Duration::from_secs(60);
+        let mut inner = Accounting::new(mocks::Ok(finder), clock, period);
+        inner.set_cost_field(fields[2]);
+        let mut middle = Accounting::new(inner, clock, period);
+        middle.set_cost_field(fields[1]);
+        let mut outer = Accounting::new(middle, clock, period);
+        outer.set_cost_field(fields[0]);
+        outer
+    }
+
+    /// Consumes for tokens by the first of each triple, to stream "id" if
+    /// the second is set and a missing one otherwise.
+    fn drive(server: &mut Stack, msgs: &[(u8, bool, Vec<u8>)]) -> (Usage, Usage, Usage) {
+        for &(token, known, ref payload) in msgs {
+            let id: &[u8] = if known { b"id" } else { b"nope" };
+            let _ = server.consume_parts(&[token % 3], id, Duration::from_secs(1), payload);
+        }
+        (server.current_period().usage,
+         server.get_ref().current_period().usage,
+         server.get_ref().get_ref().current_period().usage)
+    }
+
+    quickcheck_test! {
+    same_field_same_totals(msgs: Vec<(u8, bool, Vec<u8>)>; bool) {
+        let clock = ManualClock::new(Duration::from_secs(0));
+        [CostField::Payload, CostField::Header, CostField::Frame].iter().all(|&field| {
+            let (outer, middle, inner) = drive(&mut stack(&clock, [field; 3]), &msgs);
+            outer == middle && middle == inner
+        })
+    }}
+
+    quickcheck_test! {
+    frame_is_header_and_payload_and_prefix(msgs: Vec<(u8, bool, Vec<u8>)>; bool) {
+        let clock = ManualClock::new(Duration::from_secs(0));
+        let fields = [CostField::Frame, CostField::Header, CostField::Payload];
+        let (frames, headers, payloads) = drive(&mut stack(&clock, fields), &msgs);
+        frames.len() == payloads.len() &&
+        frames.iter().all(|(fingerprint, usage)| {
+            let (h, p) = (&headers[fingerprint], &payloads[fingerprint]);
+            usage.stored == p.stored &&
+            usage.bytes == h.bytes + p.bytes + PREFIX_LEN * usage.stored
+        })
+    }}
+}
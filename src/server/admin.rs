@@ -0,0 +1,687 @@
+//! Control frames, by which an admin token extracts its streams over the
+//! wire.
+//!
+//! A control frame is an ordinary frame for the ID `CONTROL_ID`, whose
+//! payload is an opcode and its operand:
+//!
+//! | opcode         | operand       |
+//! |----------------|---------------|
+//! | `OP_EXTRACT`   | the stream ID |
+//!
+//! Each control frame gets a response frame back: a two-byte big-endian
+//! length, then a `Response`.
+
+use std::fmt::Display;
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use session::{Error, Session};
+use stream::{ExtractEnvelope, FileStream, Guarded, GuardedError, Index, ReadError,
+             RecordReader, write_envelope};
+use status;
+use trace::Spans;
+use {Server, Stream};
+use super::{AuthResult, Consumed, Permission, Reloading, TokenServer};
+
+pub const CONTROL_ID: &'static [u8] = b"\0control";
+pub const OP_EXTRACT: u8 = 1;
+
+/// How many bytes of summary or failure a response carries at most.
+pub const MAX_RESPONSE_BODY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Control<'a> {
+    Extract {
+        id: &'a [u8],
+    },
+}
+
+impl<'a> Control<'a> {
+    /// `None` for an unknown opcode or an empty payload.
+    pub fn parse(payload: &'a [u8]) -> Option<Self> {
+        match payload.split_first() {
+            Some((&OP_EXTRACT, id)) => Some(Control::Extract { id: id }),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            Control::Extract { id } => {
+                let mut payload = vec![OP_EXTRACT];
+                payload.extend_from_slice(id);
+                payload
+            }
+        }
+    }
+}
+
+/// Why a control frame did nothing, coded as in `status`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The token may not extract, or is not registered. Whether the ID
+    /// exists is not said.
+    Unauthorized,
+    MissingId,
+    /// The stream had pushes on their way and is still registered.
+    Busy,
+    /// The stream could not be extracted, or its extract summarized, by
+    /// the error rendered.
+    Extract(String),
+    UnknownControl,
+}
+
+impl Failure {
+    pub fn code(&self) -> u8 {
+        match *self {
+            Failure::Unauthorized => status::UNAUTHORIZED,
+            Failure::MissingId => status::MISSING_ID,
+            Failure::Busy => status::BUSY,
+            Failure::Extract(_) => status::EXTRACT_FAILED,
+            Failure::UnknownControl => status::UNKNOWN_CONTROL,
+        }
+    }
+}
+
+/// The answer to a control frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The stream was extracted and unregistered; its summary by the
+    /// `ExtractSummarizer`.
+    Extracted(Vec<u8>),
+    Failed(Failure),
+}
+
+fn bounded(mut body: Vec<u8>) -> Vec<u8> {
+    body.truncate(MAX_RESPONSE_BODY);
+    body
+}
+
+/// `bounded` for text, cut at a char boundary so that it stays text.
+fn bounded_str(mut body: String) -> String {
+    if body.len() > MAX_RESPONSE_BODY {
+        let mut end = MAX_RESPONSE_BODY;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        body.truncate(end);
+    }
+    body
+}
+
+impl Response {
+    /// A code byte, `status::OK` on success, then the summary or the
+    /// rendered error.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (code, body) = match *self {
+            Response::Extracted(ref summary) => (status::OK, &summary[..]),
+            Response::Failed(Failure::Extract(ref e)) => (status::EXTRACT_FAILED, e.as_bytes()),
+            Response::Failed(ref failure) => (failure.code(), &[][..]),
+        };
+        let mut bytes = vec![code];
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// `None` for an unknown code, or a body where none belongs.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&code, body) = match bytes.split_first() {
+            None => return None,
+            Some(split) => split,
+        };
+        let failure = match code {
+            status::OK => return Some(Response::Extracted(body.to_vec())),
+            status::EXTRACT_FAILED => Failure::Extract(String::from_utf8_lossy(body).into_owned()),
+            status::UNAUTHORIZED => Failure::Unauthorized,
+            status::MISSING_ID => Failure::MissingId,
+            status::BUSY => Failure::Busy,
+            status::UNKNOWN_CONTROL => Failure::UnknownControl,
+            _ => return None,
+        };
+        if code != status::EXTRACT_FAILED && !body.is_empty() {
+            return None;
+        }
+        Some(Response::Failed(failure))
+    }
+}
+
+/// Turns what a stream extracts into a summary small enough for a
+/// response; anything past `MAX_RESPONSE_BODY` bytes is cut.
+pub trait ExtractSummarizer<S: Stream> {
+    /// An error fails the control frame, though the stream is extracted
+    /// and unregistered all the same.
+    fn summarize(&mut self, extract: S::Extract) -> io::Result<Vec<u8>>;
+
+    /// Whether `e` means the stream was busy rather than broken.
+    fn is_busy(&self, _e: &S::ExtractErr) -> bool {
+        false
+    }
+}
+
+/// What the built-in streams summarize to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub records: u64,
+    /// Of payload.
+    pub bytes: u64,
+}
+
+impl Counts {
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0_u8; 16];
+        BigEndian::write_u64(&mut bytes[..8], self.records);
+        BigEndian::write_u64(&mut bytes[8..], self.bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 16 {
+            return None;
+        }
+        Some(Counts {
+            records: BigEndian::read_u64(&bytes[..8]),
+            bytes: BigEndian::read_u64(&bytes[8..]),
+        })
+    }
+}
+
+/// Summarizes the built-in streams as their `Counts`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CountSummarizer;
+
+/// Counts the records of a `FileStream`'s data from its start. A record
+/// cut short or voided is not counted.
+fn count_records<R: Read + Seek>(data: &mut R) -> io::Result<Counts> {
+    let mut counts = Counts::default();
+    let mut reader = RecordReader::new(data, Index::default());
+    for record in reader.range(Duration::from_secs(0), Duration::from_secs(u64::max_value())) {
+        match record {
+            Ok((_, payload)) => {
+                counts.records += 1;
+                counts.bytes += payload.len() as u64;
+            }
+            Err(ReadError::Truncated { .. }) => break,
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+    Ok(counts)
+}
+
+impl<W: Read + Write + Seek> ExtractSummarizer<FileStream<W>> for CountSummarizer {
+    fn summarize(&mut self, extract: (W, Option<W>)) -> io::Result<Vec<u8>> {
+        let (mut data, _) = extract;
+        count_records(&mut data).map(|counts| counts.to_bytes().to_vec())
+    }
+}
+
+/// Summarizes a `Guarded` stream by what it guards, telling busy apart.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuardedSummarizer<Z>(pub Z);
+
+impl<S: Stream, Z: ExtractSummarizer<S>> ExtractSummarizer<Guarded<S>> for GuardedSummarizer<Z> {
+    fn summarize(&mut self, extract: S::Extract) -> io::Result<Vec<u8>> {
+        self.0.summarize(extract)
+    }
+
+    fn is_busy(&self, e: &GuardedError<S::ExtractErr>) -> bool {
+        e.is_busy()
+    }
+}
+
+/// Writes each extract in an envelope to `sink`, for one archive to hold
+/// whatever is extracted remotely, and summarizes it as its descriptor.
+pub struct EnvelopeSummarizer<W> {
+    sink: W,
+}
+
+impl<W: Write> EnvelopeSummarizer<W> {
+    pub fn new(sink: W) -> Self {
+        EnvelopeSummarizer { sink: sink }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+}
+
+impl<S, W> ExtractSummarizer<S> for EnvelopeSummarizer<W>
+    where S: Stream,
+          S::Extract: ExtractEnvelope,
+          W: Write
+{
+    fn summarize(&mut self, extract: S::Extract) -> io::Result<Vec<u8>> {
+        let descriptor = extract.describe();
+        try!(write_envelope(&mut self.sink, &[], &descriptor, &extract));
+        Ok(descriptor.to_string().into_bytes())
+    }
+}
+
+impl<S: Stream> TokenServer<S>
+    where S::ExtractErr: Display
+{
+    /// Extracts the stream `id` under `token`, which must be an admin
+    /// token, and unregisters it. A busy or failed stream stays
+    /// registered; one whose extract cannot be summarized does not.
+    pub fn admin_extract<Z>(&mut self, token: &[u8], id: &[u8], summarizer: &mut Z) -> Response
+        where Z: ExtractSummarizer<S>
+    {
+        if self.permission(token) != Some(Permission::Admin) {
+            return Response::Failed(Failure::Unauthorized);
+        }
+        let finder = match self.auth(token) {
+            Ok(finder) => finder,
+            Err(_) => return Response::Failed(Failure::Unauthorized),
+        };
+        let stream = match finder.remove(id) {
+            None => return Response::Failed(Failure::MissingId),
+            Some(stream) => stream,
+        };
+        match stream.extract() {
+            Ok(extract) => {
+                match summarizer.summarize(extract) {
+                    Ok(summary) => Response::Extracted(bounded(summary)),
+                    Err(e) => Response::Failed(Failure::Extract(bounded_str(e.to_string()))),
+                }
+            }
+            Err((stream, e)) => {
+                let busy = summarizer.is_busy(&e);
+                finder.insert(id.to_vec(), stream);
+                Response::Failed(if busy {
+                    Failure::Busy
+                } else {
+                    Failure::Extract(bounded_str(e.to_string()))
+                })
+            }
+        }
+    }
+}
+
+/// What `serve` made of its input.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServeStats {
+    pub consumed: u64,
+    pub failed: u64,
+    /// Control frames answered, however they were answered.
+    pub controls: u64,
+    /// Frames whose message would not parse.
+    pub malformed: u64,
+    /// Whether the input ended mid-frame.
+    pub truncated: bool,
+}
+
+/// What `serve` runs over: a server whose streams are a `TokenServer`'s,
+/// which control frames extract from directly.
+pub trait Administered: Server {
+    fn token_server(&mut self) -> &mut TokenServer<Self::Stream>;
+}
+
+impl<S: Stream> Administered for TokenServer<S> {
+    fn token_server(&mut self) -> &mut TokenServer<S> {
+        self
+    }
+}
+
+/// Its `ServerAdmin` goes on publishing to it while it serves.
+impl<S: Stream> Administered for Reloading<S> {
+    fn token_server(&mut self) -> &mut TokenServer<S> {
+        self.get_mut()
+    }
+}
+
+/// A server that answers control frames on its writer rather than
+/// consuming them, for `serve` to run a session over.
+struct Answering<'a, T: 'a, W, Z: 'a> {
+    server: &'a mut T,
+    writer: W,
+    summarizer: &'a mut Z,
+    stats: ServeStats,
+    /// Why a response could not be written, which ends `serve`.
+    error: Option<io::Error>,
+}
+
+impl<'a, T, W, Z> Answering<'a, T, W, Z>
+    where T: Administered,
+          <T::Stream as Stream>::ExtractErr: Display,
+          W: Write,
+          Z: ExtractSummarizer<T::Stream>
+{
+    fn answer(&mut self, token: &[u8], payload: &[u8]) -> io::Result<()> {
+        let response = match Control::parse(payload) {
+            None => Response::Failed(Failure::UnknownControl),
+            Some(Control::Extract { id }) => {
+                self.server.token_server().admin_extract(token, id, self.summarizer)
+            }
+        };
+        let response = response.to_bytes();
+        let mut prefix = [0_u8; 2];
+        BigEndian::write_u16(&mut prefix, response.len() as u16);
+        try!(self.writer.write_all(&prefix));
+        self.writer.write_all(&response)
+    }
+}
+
+impl<'a, T, W, Z> Server for Answering<'a, T, W, Z>
+    where T: Administered,
+          <T::Stream as Stream>::ExtractErr: Display,
+          W: Write,
+          Z: ExtractSummarizer<T::Stream>
+{
+    type Stream = T::Stream;
+    type AuthErr = T::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<T::Stream, T::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = ();
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        if id != CONTROL_ID {
+            let result = self.server
+                             .consume_parts_traced(token, id, timestamp, payload, spans)
+                             .map(|_| ());
+            match result {
+                Ok(()) => self.stats.consumed += 1,
+                Err(_) => self.stats.failed += 1,
+            }
+            return result;
+        }
+        if let Err(e) = self.answer(token, payload) {
+            self.error = Some(e);
+        }
+        self.stats.controls += 1;
+        Ok(())
+    }
+}
+
+/// Consumes every frame of `reader`, answering each control frame on
+/// `writer`, until the input ends. A `Reloading` server takes up what its
+/// `ServerAdmin` publishes meanwhile.
+pub fn serve<T, R, W, Z>(server: &mut T,
+                         reader: R,
+                         writer: W,
+                         summarizer: &mut Z)
+                         -> io::Result<ServeStats>
+    where T: Administered,
+          <T::Stream as Stream>::ExtractErr: Display,
+          R: Read,
+          W: Write,
+          Z: ExtractSummarizer<T::Stream>
+{
+    let mut answering = Answering {
+        server: server,
+        writer: writer,
+        summarizer: summarizer,
+        stats: ServeStats::default(),
+        error: None,
+    };
+    let mut truncated = false;
+    let mut malformed = 0;
+    {
+        let mut session = Session::new(&mut answering, reader);
+        while let Some(result) = session.next() {
+            match result {
+                Ok(_) | Err(Error::Consume(_)) => {}
+                Err(Error::Read(e)) => return Err(e),
+                Err(Error::EofInMessageSize) |
+                Err(Error::Truncated { .. }) => truncated = true,
+                Err(_) => malformed += 1,
+            }
+            if let Some(e) = session.server_mut().error.take() {
+                return Err(e);
+            }
+        }
+    }
+    answering.stats.truncated = truncated;
+    answering.stats.malformed = malformed;
+    Ok(answering.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::*;
+    use server::{Permission, TokenServer};
+    use stream::{FileStream, Guarded};
+    use test_support::frame;
+    use Server;
+
+    type File = FileStream<Cursor<Vec<u8>>>;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn server() -> TokenServer<File> {
+        let mut server = TokenServer::new();
+        server.add_token_with(b"admin", Permission::Admin)
+              .insert(b"cam".to_vec(), FileStream::new(Cursor::new(vec![])));
+        server.add_token_with(b"data", Permission::Data)
+              .insert(b"cam".to_vec(), FileStream::new(Cursor::new(vec![])));
+        server
+    }
+
+    fn extract(id: &[u8]) -> Vec<u8> {
+        Control::Extract { id: id }.to_bytes()
+    }
+
+    /// Every response frame in `output`.
+    fn responses(output: &[u8]) -> Vec<Response> {
+        let mut responses = vec![];
+        let mut at = 0;
+        while at < output.len() {
+            let len = BigEndian::read_u16(&output[at..]) as usize;
+            responses.push(Response::parse(&output[at + 2..at + 2 + len]).unwrap());
+            at += 2 + len;
+        }
+        responses
+    }
+
+    #[test]
+    fn only_admin_tokens_extract() {
+        let mut server = server();
+        assert_eq!(Some(Permission::Data), server.permission(b"data"));
+        assert_eq!(Response::Failed(Failure::Unauthorized),
+                   server.admin_extract(b"data", b"cam", &mut CountSummarizer));
+        assert!(server.auth(b"data").unwrap().contains_key(&b"cam"[..]));
+        assert_eq!(Response::Extracted(Counts::default().to_bytes().to_vec()),
+                   server.admin_extract(b"admin", b"cam", &mut CountSummarizer));
+        assert!(server.auth(b"admin").unwrap().is_empty());
+        assert_eq!(Response::Failed(Failure::MissingId),
+                   server.admin_extract(b"admin", b"cam", &mut CountSummarizer));
+
+        server.remove_token(b"admin");
+        server.add_token(b"admin");
+        assert_eq!(Some(Permission::Data), server.permission(b"admin"));
+    }
+
+    #[test]
+    fn remote_extract_to_envelopes() {
+        use stream::{PortableBody, PortableReader};
+
+        let mut server = server();
+        let capture = [frame(b"admin", b"cam", 1000, b"one"),
+                       frame(b"admin", CONTROL_ID, 3000, &extract(b"cam"))]
+                          .concat();
+        let mut output = vec![];
+        let mut summarizer = EnvelopeSummarizer::new(vec![]);
+        serve(&mut server, &capture[..], &mut output, &mut summarizer).unwrap();
+        assert_eq!(vec![Response::Extracted(b"records, 1 records, 16 bytes".to_vec())],
+                   responses(&output));
+        let archive = summarizer.into_inner();
+        let read: Vec<_> = PortableReader::new(&archive[..]).map(Result::unwrap).collect();
+        assert_eq!(PortableBody::Records(vec![(secs(1), b"one".to_vec())]), read[0].body);
+    }
+
+    #[test]
+    fn unwritable_envelopes_fail_the_extract() {
+        let mut server = server();
+        let mut sink = [0_u8; 4];
+        let mut summarizer = EnvelopeSummarizer::new(&mut sink[..]);
+        assert_match!(Response::Failed(Failure::Extract(_)),
+                      server.admin_extract(b"admin", b"cam", &mut summarizer));
+        assert!(server.auth(b"admin").unwrap().is_empty());
+    }
+
+    #[test]
+    fn remote_extract() {
+        let mut server = server();
+        let capture = [frame(b"admin", b"cam", 1000, b"one"),
+                       frame(b"admin", b"cam", 2000, b"three"),
+                       frame(b"admin", CONTROL_ID, 3000, &extract(b"cam")),
+                       frame(b"admin", CONTROL_ID, 3000, &extract(b"cam")),
+                       frame(b"admin", CONTROL_ID, 3000, &[9]),
+                       frame(b"data", b"cam", 4000, b"kept")]
+                          .concat();
+        let mut output = vec![];
+        let stats = serve(&mut server, &capture[..], &mut output, &mut CountSummarizer).unwrap();
+        assert_eq!(ServeStats {
+                       consumed: 3,
+                       controls: 3,
+                       ..ServeStats::default()
+                   },
+                   stats);
+        let responses = responses(&output);
+        assert_eq!(vec![Response::Failed(Failure::MissingId),
+                        Response::Failed(Failure::UnknownControl)],
+                   responses[1..].to_vec());
+        match responses[0] {
+            Response::Extracted(ref summary) => {
+                assert_eq!(Some(Counts {
+                               records: 2,
+                               bytes: 8,
+                           }),
+                           Counts::from_bytes(summary))
+            }
+            ref response => panic!("expected a summary; got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn busy_stays_registered() {
+        let cam = Guarded::new(FileStream::new(Cursor::new(vec![])));
+        let intent = cam.begin_push_intent();
+        let mut server = TokenServer::new();
+        server.add_token_with(b"admin", Permission::Admin).insert(b"cam".to_vec(), cam);
+        assert!(server.consume_parts(b"admin", b"cam", secs(1), b"one").is_ok());
+        let mut summarizer = GuardedSummarizer(CountSummarizer);
+        assert_eq!(Response::Failed(Failure::Busy),
+                   server.admin_extract(b"admin", b"cam", &mut summarizer));
+        assert!(server.auth(b"admin").unwrap().contains_key(&b"cam"[..]));
+        intent.end();
+        let summary = Counts {
+            records: 1,
+            bytes: 3,
+        };
+        assert_eq!(Response::Extracted(summary.to_bytes().to_vec()),
+                   server.admin_extract(b"admin", b"cam", &mut summarizer));
+    }
+
+    #[test]
+    fn serves_what_the_admin_publishes() {
+        use config::ServerConfig;
+        use server::{Policy, ServerAdmin, TokenPolicy};
+
+        let mut policy = Policy::default();
+        let admin_policy = TokenPolicy { permission: Permission::Admin, ..TokenPolicy::default() };
+        policy.tokens.insert(b"admin".to_vec(), admin_policy);
+        policy.tokens.insert(b"data".to_vec(), TokenPolicy::default());
+        let admin = ServerAdmin::new(ServerConfig::default(), policy).unwrap();
+        let mut server = admin.server(server());
+        admin.remove_token(b"data").unwrap();
+        let capture = [frame(b"admin", b"cam", 1000, b"one"),
+                       frame(b"data", b"cam", 2000, b"two"),
+                       frame(b"admin", CONTROL_ID, 3000, &extract(b"cam"))]
+                          .concat();
+        let mut output = vec![];
+        let stats = serve(&mut server, &capture[..], &mut output, &mut CountSummarizer).unwrap();
+        assert_eq!(ServeStats {
+                       consumed: 1,
+                       failed: 1,
+                       controls: 1,
+                       ..ServeStats::default()
+                   },
+                   stats);
+        let summary = Counts {
+            records: 1,
+            bytes: 3,
+        };
+        assert_eq!(vec![Response::Extracted(summary.to_bytes().to_vec())], responses(&output));
+        assert_eq!(None, server.get_ref().permission(b"data"));
+    }
+
+    #[test]
+    fn unauthorized_reveals_nothing() {
+        let mut server = server();
+        server.add_token_with(b"revoked", Permission::Admin);
+        server.add_token_with(b"revoked", Permission::Data);
+        let attempts = [(&b"data"[..], &b"cam"[..]),
+                        (b"data", b"missing"),
+                        (b"revoked", b"cam"),
+                        (b"unknown", b"cam")];
+        let capture: Vec<_> = attempts.iter()
+                                      .flat_map(|&(token, id)| {
+                                          frame(token, CONTROL_ID, 1000, &extract(id))
+                                      })
+                                      .collect();
+        let mut output = vec![];
+        serve(&mut server, &capture[..], &mut output, &mut CountSummarizer).unwrap();
+        let unauthorized = Response::Failed(Failure::Unauthorized).to_bytes();
+        let expected: Vec<_> = attempts.iter()
+                                       .flat_map(|_| {
+                                           let mut frame = vec![0, unauthorized.len() as u8];
+                                           frame.extend_from_slice(&unauthorized);
+                                           frame
+                                       })
+                                       .collect();
+        assert_eq!(expected, output);
+        assert!(server.auth(b"data").unwrap().contains_key(&b"cam"[..]));
+    }
+
+    #[test]
+    fn extract_errors_cut_at_a_char_boundary() {
+        // Each "é" is two bytes, so the bound falls within one.
+        let long = format!("x{}", "é".repeat(MAX_RESPONSE_BODY));
+        let cut = bounded_str(long);
+        assert_eq!(MAX_RESPONSE_BODY - 1, cut.len());
+        let response = Response::Failed(Failure::Extract(cut));
+        assert_eq!(Some(response.clone()), Response::parse(&response.to_bytes()));
+        assert!(response.to_bytes().len() <= 1 + MAX_RESPONSE_BODY);
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let responses = [Response::Extracted(vec![1, 2, 3]),
+                         Response::Failed(Failure::Unauthorized),
+                         Response::Failed(Failure::MissingId),
+                         Response::Failed(Failure::Busy),
+                         Response::Failed(Failure::Extract("broken".to_owned())),
+                         Response::Failed(Failure::UnknownControl)];
+        for response in &responses {
+            assert_eq!(Some(response.clone()), Response::parse(&response.to_bytes()));
+        }
+        assert_eq!(None, Response::parse(&[]));
+        assert_eq!(None, Response::parse(&[status::BUSY, 0]));
+        assert_eq!(None, Response::parse(&[6]));
+    }
+}
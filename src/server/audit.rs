@@ -0,0 +1,553 @@
+//! A trail of authentication decisions, kept in bounded memory, for telling
+//! which tokens tried their luck lately and how they fared without logging
+//! every message. Tokens are only ever kept as fingerprints.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::vec_deque;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use {Clock, Server};
+use stream::Pressure;
+use trace::Spans;
+use util::millis;
+use super::protection::keyed_fingerprint;
+use super::{AuthError, AuthResult, ConsumeError, ConsumeResult, Consumed, DryRunOutcome};
+
+const MAGIC: [u8; 4] = *b"SVAA";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AuthOutcome {
+    Ok,
+    Invalid,
+    Other,
+    /// Deferred until the token is known to be valid or invalid.
+    Pending,
+    /// Refused for now, whether or not the token is valid.
+    Throttled,
+}
+
+impl AuthOutcome {
+    pub fn of<T, A>(result: &Result<T, AuthError<A>>) -> Self {
+        match *result {
+            Ok(_) => AuthOutcome::Ok,
+            Err(AuthError::InvalidToken) => AuthOutcome::Invalid,
+            Err(AuthError::Other(_)) => AuthOutcome::Other,
+            Err(AuthError::Pending(_)) => AuthOutcome::Pending,
+            Err(AuthError::Throttled) => AuthOutcome::Throttled,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            AuthOutcome::Ok => 0,
+            AuthOutcome::Invalid => 1,
+            AuthOutcome::Other => 2,
+            AuthOutcome::Pending => 3,
+            AuthOutcome::Throttled => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// As `protection::keyed_fingerprint` takes it of the token alone, so
+    /// it is only good for this process.
+    pub fingerprint: u64,
+    pub outcome: AuthOutcome,
+    /// The clock's time of the decision.
+    pub at: Duration,
+    /// The connection's, if one was set.
+    pub label: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutcomeCounts {
+    pub ok: u64,
+    pub invalid: u64,
+    pub other: u64,
+    pub pending: u64,
+    pub throttled: u64,
+}
+
+impl OutcomeCounts {
+    pub fn get(&self, outcome: AuthOutcome) -> u64 {
+        match outcome {
+            AuthOutcome::Ok => self.ok,
+            AuthOutcome::Invalid => self.invalid,
+            AuthOutcome::Other => self.other,
+            AuthOutcome::Pending => self.pending,
+            AuthOutcome::Throttled => self.throttled,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.ok + self.invalid + self.other + self.pending + self.throttled
+    }
+
+    fn plus(self, other: OutcomeCounts) -> OutcomeCounts {
+        OutcomeCounts {
+            ok: self.ok + other.ok,
+            invalid: self.invalid + other.invalid,
+            other: self.other + other.other,
+            pending: self.pending + other.pending,
+            throttled: self.throttled + other.throttled,
+        }
+    }
+
+    fn count(&mut self, outcome: AuthOutcome) {
+        match outcome {
+            AuthOutcome::Ok => self.ok += 1,
+            AuthOutcome::Invalid => self.invalid += 1,
+            AuthOutcome::Other => self.other += 1,
+            AuthOutcome::Pending => self.pending += 1,
+            AuthOutcome::Throttled => self.throttled += 1,
+        }
+    }
+}
+
+/// The `k` fingerprints seen most. Twice as many are tracked, so that those
+/// seen once or twice churn through the spare room rather than through the
+/// top. When a new one comes with no room, the least seen is let go, so a
+/// count is of the decisions since its fingerprint was last taken on. The
+/// top counts and `others` always add up to every decision.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopFingerprints {
+    k: usize,
+    counts: HashMap<u64, OutcomeCounts>,
+    others: OutcomeCounts,
+}
+
+impl TopFingerprints {
+    /// Most seen first, ties by fingerprint.
+    pub fn top(&self) -> Vec<(u64, OutcomeCounts)> {
+        let mut top = self.tracked();
+        top.truncate(self.k);
+        top
+    }
+
+    pub fn get(&self, fingerprint: u64) -> Option<OutcomeCounts> {
+        self.counts.get(&fingerprint).cloned()
+    }
+
+    /// Decisions not counted under a fingerprint in the top.
+    pub fn others(&self) -> OutcomeCounts {
+        self.tracked().iter().skip(self.k).fold(self.others, |sum, &(_, counts)| sum.plus(counts))
+    }
+
+    fn tracked(&self) -> Vec<(u64, OutcomeCounts)> {
+        let mut tracked: Vec<_> = self.counts.iter().map(|(&fp, &counts)| (fp, counts)).collect();
+        tracked.sort_by_key(|&(fp, counts)| (!counts.total(), fp));
+        tracked
+    }
+
+    fn count(&mut self, fingerprint: u64, outcome: AuthOutcome) {
+        if !self.counts.contains_key(&fingerprint) {
+            if self.k == 0 {
+                self.others.count(outcome);
+                return;
+            }
+            if self.counts.len() == 2 * self.k {
+                let least = *self.counts
+                                 .iter()
+                                 .min_by_key(|&(&fp, counts)| (counts.total(), fp))
+                                 .unwrap()
+                                 .0;
+                let evicted = self.counts.remove(&least).unwrap();
+                self.others = self.others.plus(evicted);
+            }
+        }
+        self.counts.entry(fingerprint).or_insert(OutcomeCounts::default()).count(outcome);
+    }
+}
+
+/// Records each authentication decision made through it: when `auth` is
+/// called, and for each message consumed or backfilled, from how its
+/// inner server fared. Dry runs and pressure queries go unrecorded. Each
+/// decision costs the same however long the trail: the newest `capacity`
+/// are kept in order, overwriting the oldest, and the top fingerprints
+/// take a pass over at most `2 * top_k` counts.
+pub struct AuthAudit<C, S> {
+    server: S,
+    trail: Trail<C>,
+}
+
+struct Trail<C> {
+    clock: C,
+    capacity: usize,
+    entries: VecDeque<AuditEntry>,
+    label: Option<u64>,
+    outcomes: OutcomeCounts,
+    fingerprints: TopFingerprints,
+}
+
+impl<C: Clock> Trail<C> {
+    fn record(&mut self, token: &[u8], outcome: AuthOutcome) {
+        let fingerprint = keyed_fingerprint(&[token]);
+        if self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries.push_back(AuditEntry {
+                fingerprint: fingerprint,
+                outcome: outcome,
+                at: self.clock.now(),
+                label: self.label,
+            });
+        }
+        self.outcomes.count(outcome);
+        self.fingerprints.count(fingerprint, outcome);
+    }
+
+    /// Records the decision behind `result`, if it got that far.
+    fn consumed<A, P, K>(&mut self, token: &[u8], result: &ConsumeResult<A, P, K>) {
+        let outcome = match *result {
+            Err(ConsumeError::Auth(AuthError::InvalidToken)) => AuthOutcome::Invalid,
+            Err(ConsumeError::Auth(AuthError::Other(_))) => AuthOutcome::Other,
+            Err(ConsumeError::Auth(AuthError::Pending(_))) => AuthOutcome::Pending,
+            Err(ConsumeError::Auth(AuthError::Throttled)) => AuthOutcome::Throttled,
+            _ => AuthOutcome::Ok,
+        };
+        self.record(token, outcome);
+    }
+}
+
+impl<C: Clock, S: Server> AuthAudit<C, S> {
+    /// Keeps 1024 entries and the top 16 fingerprints.
+    pub fn new(server: S, clock: C) -> Self {
+        AuthAudit::with_capacity(server, clock, 1024, 16)
+    }
+
+    pub fn with_capacity(server: S, clock: C, capacity: usize, top_k: usize) -> Self {
+        AuthAudit {
+            server: server,
+            trail: Trail {
+                clock: clock,
+                capacity: capacity,
+                entries: VecDeque::with_capacity(capacity),
+                label: None,
+                outcomes: OutcomeCounts::default(),
+                fingerprints: TopFingerprints {
+                    k: top_k,
+                    ..TopFingerprints::default()
+                },
+            },
+        }
+    }
+
+    /// Tags the decisions from then on, say with the number of the
+    /// connection about to be served.
+    pub fn set_label(&mut self, label: Option<u64>) {
+        self.trail.label = label;
+    }
+
+    /// The entries kept from `since` on, inclusive, oldest first.
+    pub fn entries_since(&self, since: Duration) -> EntriesSince {
+        EntriesSince {
+            entries: self.trail.entries.iter(),
+            since: since,
+        }
+    }
+
+    /// Every decision, however long ago.
+    pub fn outcomes(&self) -> OutcomeCounts {
+        self.trail.outcomes
+    }
+
+    pub fn fingerprints(&self) -> &TopFingerprints {
+        &self.trail.fingerprints
+    }
+
+    /// Writes what is kept: a four-byte magic, a version byte, a big-endian
+    /// `u32` count, then per entry its fingerprint, outcome byte, time in
+    /// milliseconds as a `u64`, and label as a `u64` after a presence byte.
+    pub fn write_to<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut w = w;
+        let mut header = [0_u8; 9];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        BigEndian::write_u32(&mut header[5..], self.trail.entries.len() as u32);
+        try!(w.write_all(&header));
+        for entry in &self.trail.entries {
+            let mut fixed = [0_u8; 26];
+            BigEndian::write_u64(&mut fixed[..8], entry.fingerprint);
+            fixed[8] = entry.outcome.to_byte();
+            BigEndian::write_u64(&mut fixed[9..17], millis(entry.at));
+            if let Some(label) = entry.label {
+                fixed[17] = 1;
+                BigEndian::write_u64(&mut fixed[18..], label);
+            }
+            try!(w.write_all(&fixed));
+        }
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+}
+
+/// The entries of `AuthAudit::entries_since`.
+pub struct EntriesSince<'a> {
+    entries: vec_deque::Iter<'a, AuditEntry>,
+    since: Duration,
+}
+
+impl<'a> Iterator for EntriesSince<'a> {
+    type Item = &'a AuditEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        let since = self.since;
+        self.entries.by_ref().find(|entry| entry.at >= since)
+    }
+}
+
+impl<C: Clock, S: Server> Server for AuthAudit<C, S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        let result = self.server.auth(token);
+        self.trail.record(token, AuthOutcome::of(&result));
+        result
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        let (result, pressure) =
+            self.server.consume_parts_pressed(token, id, timestamp, payload, spans);
+        self.trail.consumed(token, &result);
+        (result, pressure)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        let result = self.server.backfill_parts(token, id, timestamp, payload);
+        self.trail.consumed(token, &result);
+        result
+    }
+
+    fn pressure(&mut self, token: &[u8], id: &[u8]) -> Pressure {
+        self.server.pressure(token, id)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use server::{AuthError, AuthResult, Finder};
+    use test_support::stream;
+    use Server;
+
+    /// Lets in tokens starting with "ok", refuses ones starting with "no"
+    /// as invalid and ones starting with "slow" as throttled, and fails on
+    /// the rest.
+    struct ByPrefix(Finder<stream::Ok>);
+
+    impl Server for ByPrefix {
+        type Stream = stream::Ok;
+        type AuthErr = ();
+        fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+            if token.starts_with(b"ok") {
+                Ok(&mut self.0)
+            } else if token.starts_with(b"no") {
+                Err(AuthError::InvalidToken)
+            } else if token.starts_with(b"slow") {
+                Err(AuthError::Throttled)
+            } else {
+                Err(AuthError::Other(()))
+            }
+        }
+
+        type ConsumeOk = ();
+        fn consume_parts_traced(&mut self,
+                                token: &[u8],
+                                id: &[u8],
+                                timestamp: Duration,
+                                payload: &[u8],
+                                _: &mut Spans)
+                                -> Consumed<Self> {
+            self.push_parts(token, id, timestamp, payload)
+        }
+    }
+
+    fn audit(clock: &ManualClock,
+             capacity: usize,
+             top_k: usize)
+             -> AuthAudit<&ManualClock, ByPrefix> {
+        let finder = vec![(b"id".to_vec(), stream::Ok)].into_iter().collect();
+        AuthAudit::with_capacity(ByPrefix(finder), clock, capacity, top_k)
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn consume(audit: &mut AuthAudit<&ManualClock, ByPrefix>, token: &[u8]) {
+        let _ = audit.consume_parts(token, b"id", secs(0), b"");
+    }
+
+    #[test]
+    fn outcomes_over_time() {
+        let clock = ManualClock::new(secs(100));
+        let mut audit = audit(&clock, 8, 4);
+        audit.set_label(Some(7));
+        consume(&mut audit, b"ok-a");
+        clock.set(secs(200));
+        consume(&mut audit, b"no-b");
+        audit.set_label(None);
+        clock.set(secs(300));
+        consume(&mut audit, b"bad-c");
+        consume(&mut audit, b"slow-d");
+        assert!(audit.auth(b"ok-a").is_ok());
+        // A missing ID is decided after auth.
+        assert!(audit.consume_parts(b"ok-a", b"nope", secs(0), b"").is_err());
+
+        let entries: Vec<_> = audit.entries_since(secs(0)).cloned().collect();
+        assert_eq!(AuditEntry {
+                       fingerprint: keyed_fingerprint(&[b"ok-a"]),
+                       outcome: AuthOutcome::Ok,
+                       at: secs(100),
+                       label: Some(7),
+                   },
+                   entries[0]);
+        let outcomes: Vec<_> = entries.iter().map(|entry| entry.outcome).collect();
+        assert_eq!(vec![AuthOutcome::Ok,
+                        AuthOutcome::Invalid,
+                        AuthOutcome::Other,
+                        AuthOutcome::Throttled,
+                        AuthOutcome::Ok,
+                        AuthOutcome::Ok],
+                   outcomes);
+        assert_eq!(vec![Some(7), Some(7), None, None, None, None],
+                   entries.iter().map(|entry| entry.label).collect::<Vec<_>>());
+        assert_eq!(OutcomeCounts {
+                       ok: 3,
+                       invalid: 1,
+                       other: 1,
+                       pending: 0,
+                       throttled: 1,
+                   },
+                   audit.outcomes());
+        assert_eq!(3, audit.fingerprints().get(keyed_fingerprint(&[b"ok-a"])).unwrap().ok);
+    }
+
+    #[test]
+    fn since_is_inclusive() {
+        let clock = ManualClock::new(secs(0));
+        let mut audit = audit(&clock, 8, 4);
+        for t in 0..4 {
+            clock.set(secs(t * 10));
+            consume(&mut audit, b"ok");
+        }
+        let since = |t| audit.entries_since(secs(t)).map(|entry| entry.at).collect::<Vec<_>>();
+        assert_eq!(vec![secs(20), secs(30)], since(20));
+        assert_eq!(vec![secs(30)], since(21));
+        assert_eq!(Vec::<Duration>::new(), since(31));
+    }
+
+    #[test]
+    fn overwrites_oldest_at_capacity() {
+        let clock = ManualClock::new(secs(0));
+        let mut audit = audit(&clock, 3, 4);
+        for t in 0..5 {
+            clock.set(secs(t));
+            consume(&mut audit, b"ok");
+        }
+        let kept: Vec<_> = audit.entries_since(secs(0)).map(|entry| entry.at).collect();
+        assert_eq!(vec![secs(2), secs(3), secs(4)], kept);
+        assert_eq!(5, audit.outcomes().ok);
+    }
+
+    #[test]
+    fn top_k_of_a_skewed_distribution() {
+        let clock = ManualClock::new(secs(0));
+        let mut audit = audit(&clock, 0, 3);
+        // Three heavy tokens, a hundred, fifty and twenty times each, with
+        // forty light ones seen once each in among them.
+        let heavy: [&[u8]; 3] = [b"ok-heavy", b"no-heavy", b"bad-heavy"];
+        let mut light = 0;
+        for round in 0..100 {
+            for (i, &times) in [100, 50, 20].iter().enumerate() {
+                if round < times {
+                    consume(&mut audit, heavy[i]);
+                }
+            }
+            if round % 5 == 0 || round % 7 == 0 {
+                consume(&mut audit, format!("ok-light-{}", light).as_bytes());
+                light += 1;
+            }
+        }
+        let top = audit.fingerprints().top();
+        let fingerprints: Vec<_> = top.iter().map(|&(fp, _)| fp).collect();
+        let expected: Vec<_> = heavy.iter().map(|token| keyed_fingerprint(&[token])).collect();
+        assert_eq!(expected, fingerprints);
+        assert_eq!(100, top[0].1.ok);
+        assert_eq!(50, top[1].1.invalid);
+        assert_eq!(20, top[2].1.other);
+        let counted: u64 = top.iter().map(|&(_, counts)| counts.total()).sum();
+        assert_eq!(audit.outcomes().total(),
+                   counted + audit.fingerprints().others().total());
+    }
+
+    #[test]
+    fn keeps_no_raw_tokens() {
+        let clock = ManualClock::new(secs(0));
+        let mut audit = audit(&clock, 16, 4);
+        audit.set_label(Some(1));
+        let secret = b"ok-\xde\xad\xbe\xef-secret";
+        for _ in 0..3 {
+            consume(&mut audit, secret);
+            consume(&mut audit, b"no-\xde\xad\xbe\xef");
+        }
+        let mut state = vec![];
+        audit.write_to(&mut state).unwrap();
+        state.extend_from_slice(format!("{:?}", audit.fingerprints()).as_bytes());
+        state.extend_from_slice(format!("{:?}", audit.entries_since(secs(0)).collect::<Vec<_>>())
+                                    .as_bytes());
+        assert!(state.len() > 9 + 6 * 26);
+        assert!(!state.windows(4).any(|w| w == b"\xde\xad\xbe\xef"));
+        assert!(!state.windows(6).any(|w| w == b"secret"));
+    }
+}
@@ -0,0 +1,691 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::Duration;
+
+use stream::Pressure;
+use trace::Spans;
+use util::millis;
+use {Stream, Message};
+
+pub use self::accounting::{Accounting, ErrorCounts, PeriodSnapshot, TokenUsage};
+pub use self::audit::{AuditEntry, AuthAudit, AuthOutcome, EntriesSince, OutcomeCounts,
+                      TopFingerprints};
+pub use self::ordering::{Contract, OrderingChecker, OrderingCounts, OrderingKey,
+                         OrderingViolation, Sequencing};
+pub use self::protection::{Dedup, HighWaterMark, ProtectionError, RestoreReport};
+pub use self::reaper::Reaper;
+pub use self::reload::{Policy, Reloading, ServerAdmin, SharedConfig, Snapshot, TokenPolicy};
+pub use self::shadow::{Divergence, OutcomeCode, ShadowReport, Shadowed};
+pub use self::snapshot::{SnapshotReport, SnapshotServer};
+pub use self::token::{CapPolicy, Delivery, HookHandle, IdPattern, NestedGroup, Permission,
+                      RetentionAction, RetentionCounts, RetentionPolicy, TokenServer};
+
+pub mod accounting;
+pub mod audit;
+pub mod admin;
+pub mod ordering;
+pub mod protection;
+pub mod reaper;
+pub mod reload;
+pub mod shadow;
+pub mod snapshot;
+pub mod token;
+
+/// Names an auth decision a server has put off, for the application to
+/// hand back through `Session::resolve_auth` once it is made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AuthTicket(pub u64);
+
+impl Display for AuthTicket {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "auth ticket {}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError<E> {
+    InvalidToken,
+    Other(E),
+    /// The token is not known to be valid or invalid yet. Only a session
+    /// with deferred auth waits for the decision; to any other caller,
+    /// this is an error like the rest.
+    Pending(AuthTicket),
+    /// The token was refused for now, say after too many attempts lately,
+    /// whether or not it is valid.
+    Throttled,
+}
+
+impl<E> From<E> for AuthError<E> {
+    fn from(e: E) -> Self {
+        AuthError::Other(e)
+    }
+}
+
+impl<E: Display> Display for AuthError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AuthError::InvalidToken => f.write_str("invalid token"),
+            AuthError::Other(ref e) => e.fmt(f),
+            AuthError::Pending(ticket) => write!(f, "auth pending on ticket {}", ticket.0),
+            AuthError::Throttled => f.write_str("auth throttled"),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for AuthError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::InvalidToken => "invalid token",
+            AuthError::Other(ref e) => e.description(),
+            AuthError::Pending(_) => "auth pending",
+            AuthError::Throttled => "auth throttled",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            AuthError::InvalidToken | AuthError::Pending(_) | AuthError::Throttled => None,
+            AuthError::Other(ref e) => Some(e),
+        }
+    }
+}
+
+impl<E> AuthError<E> {
+    /// `PermissionDenied` for an invalid or throttled token, `WouldBlock`
+    /// for a pending one, and `Other` otherwise.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            AuthError::InvalidToken | AuthError::Throttled => io::ErrorKind::PermissionDenied,
+            AuthError::Pending(_) => io::ErrorKind::WouldBlock,
+            AuthError::Other(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConsumeError<A, P> {
+    Auth(AuthError<A>),
+    MissingId,
+    /// A wrapper's policy refused the message, for the given reason.
+    Rejected(&'static str),
+    /// Provisioning the ID would exceed the token's stream cap.
+    StreamCapExceeded {
+        cap: usize,
+    },
+    /// The message is older by its header timestamp than its token's
+    /// retention policy allows, and was stored nowhere.
+    ExpiredPayload {
+        age: Duration,
+        max_age: Duration,
+    },
+    Push(P),
+    /// A group message reached some of the group's members but not all,
+    /// listed by member ID.
+    GroupPartialFailure(Vec<(Vec<u8>, MemberOutcome<P>)>),
+}
+
+/// What became of a group message at one member.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MemberOutcome<P> {
+    Committed,
+    Failed(P),
+    RolledBack,
+}
+
+impl<P> MemberOutcome<P> {
+    pub fn is_committed(&self) -> bool {
+        match *self {
+            MemberOutcome::Committed => true,
+            _ => false,
+        }
+    }
+}
+
+impl<A, P> From<AuthError<A>> for ConsumeError<A, P> {
+    fn from(err: AuthError<A>) -> Self {
+        ConsumeError::Auth(err)
+    }
+}
+
+impl<A: Display, P: Display> Display for ConsumeError<A, P> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConsumeError::Auth(ref e) => e.fmt(f),
+            ConsumeError::MissingId => f.write_str("missing ID"),
+            ConsumeError::Rejected(reason) => write!(f, "rejected: {}", reason),
+            ConsumeError::StreamCapExceeded { cap } => write!(f, "stream cap of {} exceeded", cap),
+            ConsumeError::ExpiredPayload { age, max_age } => {
+                write!(f,
+                       "payload {}ms old, past its retention of {}ms",
+                       millis(age),
+                       millis(max_age))
+            }
+            ConsumeError::Push(ref e) => e.fmt(f),
+            ConsumeError::GroupPartialFailure(ref outcomes) => {
+                let committed = outcomes.iter()
+                                        .filter(|&&(_, ref o)| o.is_committed())
+                                        .count();
+                write!(f, "group delivered to {} of {} members", committed, outcomes.len())
+            }
+        }
+    }
+}
+
+impl<A: error::Error, P: error::Error> error::Error for ConsumeError<A, P> {
+    fn description(&self) -> &str {
+        match *self {
+            ConsumeError::Auth(ref e) => e.description(),
+            ConsumeError::MissingId => "missing ID",
+            ConsumeError::Rejected(reason) => reason,
+            ConsumeError::StreamCapExceeded { .. } => "stream cap exceeded",
+            ConsumeError::ExpiredPayload { .. } => "payload past retention",
+            ConsumeError::Push(ref e) => e.description(),
+            ConsumeError::GroupPartialFailure(_) => "group partially delivered",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ConsumeError::Auth(ref e) => Some(e),
+            ConsumeError::MissingId |
+            ConsumeError::Rejected(_) |
+            ConsumeError::StreamCapExceeded { .. } |
+            ConsumeError::ExpiredPayload { .. } |
+            ConsumeError::GroupPartialFailure(_) => None,
+            ConsumeError::Push(ref e) => Some(e),
+        }
+    }
+}
+
+impl<A, P> ConsumeError<A, P> {
+    /// The kind of the authentication error, `NotFound` for a missing ID,
+    /// `InvalidInput` for a rejection or an expired payload, and `Other` for
+    /// an exceeded stream cap, a push error, or a partly delivered group.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            ConsumeError::Auth(ref e) => e.io_kind(),
+            ConsumeError::MissingId => io::ErrorKind::NotFound,
+            ConsumeError::Rejected(_) => io::ErrorKind::InvalidInput,
+            ConsumeError::StreamCapExceeded { .. } => io::ErrorKind::Other,
+            ConsumeError::ExpiredPayload { .. } => io::ErrorKind::InvalidInput,
+            ConsumeError::Push(_) => io::ErrorKind::Other,
+            ConsumeError::GroupPartialFailure(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl<A, P> ConsumeError<A, P>
+    where A: error::Error + Send + Sync + 'static,
+          P: error::Error + Send + Sync + 'static
+{
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(self.io_kind(), self)
+    }
+}
+
+impl<A, P> From<ConsumeError<A, P>> for io::Error
+    where A: error::Error + Send + Sync + 'static,
+          P: error::Error + Send + Sync + 'static
+{
+    fn from(e: ConsumeError<A, P>) -> Self {
+        e.into_io()
+    }
+}
+
+/// Finders iterate in no particular order. Every report, listing and
+/// saved state built from one is sorted by (token, ID) as it is built
+/// instead, so that its output depends only on what it describes; consuming
+/// a message never sorts anything.
+pub type Finder<S> = HashMap<Vec<u8>, S>;
+pub type AuthResult<'a, S, A> = Result<&'a mut Finder<S>, AuthError<A>>;
+pub type ConsumeResult<A, P, K = ()> = Result<K, ConsumeError<A, P>>;
+/// What `Server::consume` and the methods it delegates to return for `S`.
+pub type Consumed<S> = ConsumeResult<<S as Server>::AuthErr,
+                                     <<S as Server>::Stream as Stream>::PushErr,
+                                     <S as Server>::ConsumeOk>;
+
+/// What consuming a message would do, by `Server::dry_run`.
+#[derive(Debug)]
+pub enum DryRunOutcome<A> {
+    WouldStore,
+    Unauthorized(AuthError<A>),
+    MissingId,
+    /// A wrapper's policy would refuse the message, for the given reason.
+    Rejected(&'static str),
+    StreamCapExceeded {
+        cap: usize,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackfillReport {
+    pub accepted: u64,
+    /// Records refused by authentication, a missing ID, or a policy.
+    pub rejected: u64,
+    /// Records whose push failed.
+    pub failed: u64,
+    /// Timestamp of the first accepted record.
+    pub first: Option<Duration>,
+    /// Timestamp of the last accepted record.
+    pub last: Option<Duration>,
+}
+
+pub trait Server {
+    type Stream: Stream;
+
+    type AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr>;
+
+    /// What a consumed message is acknowledged with, such as a sequence
+    /// number, a storage offset or a dedup verdict. Wrappers pass on their
+    /// inner server's.
+    type ConsumeOk;
+    fn consume(&mut self, msg: Message) -> Consumed<Self> {
+        self.consume_parts(msg.header.token, msg.header.id, msg.header.timestamp, msg.payload)
+    }
+
+    /// What `consume` delegates to: `consume_parts_traced`, tracing
+    /// nothing.
+    fn consume_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> Consumed<Self> {
+        self.consume_parts_traced(token, id, timestamp, payload, &mut Spans::off())
+    }
+
+    /// Pushes to the stream `resolve` finds, with nothing to acknowledge
+    /// the message with but that it was pushed.
+    fn push_parts(&mut self,
+                  token: &[u8],
+                  id: &[u8],
+                  timestamp: Duration,
+                  payload: &[u8])
+                  -> ConsumeResult<Self::AuthErr, <Self::Stream as Stream>::PushErr> {
+        self.resolve(token, id)
+            .and_then(move |stream| stream.push(timestamp, payload).map_err(ConsumeError::Push))
+    }
+
+    /// Consumes a message, adding this server's phases to `spans`; a
+    /// wrapper passes `spans` on to its inner server, so that a session
+    /// tracing its messages sees every layer. A server acknowledging with
+    /// `()` can implement this by `push_parts`.
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self>;
+
+    /// `consume_parts_traced`, along with the pressure on the stream the
+    /// message went to, or `Pressure::None` if it was not consumed. Asks
+    /// `pressure` after consuming unless overridden; servers that know
+    /// where a message went, and wrappers passing their inner server's on,
+    /// should override this so that a session need not authorize it again.
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        let consumed = self.consume_parts_traced(token, id, timestamp, payload, spans);
+        let pressure = match consumed {
+            Ok(_) => self.pressure(token, id),
+            Err(_) => Pressure::None,
+        };
+        (consumed, pressure)
+    }
+
+    /// The stream a message for `token` and `id` would be pushed to, for
+    /// callers that push to it themselves. Policies a wrapper applies in
+    /// `consume_parts` are bypassed unless it overrides this too.
+    fn resolve(&mut self,
+               token: &[u8],
+               id: &[u8])
+               -> Result<&mut Self::Stream,
+                         ConsumeError<Self::AuthErr, <Self::Stream as Stream>::PushErr>> {
+        self.auth(token)
+            .map_err(Into::into)
+            .and_then(|finder| finder.get_mut(id).ok_or(ConsumeError::MissingId))
+    }
+
+    /// How pressed the stream for `token` and `id` is, for a session to
+    /// slow down or stop reading by. Wrappers that delegate `auth` pass the
+    /// stream's own hint on without overriding this.
+    fn pressure(&mut self, token: &[u8], id: &[u8]) -> Pressure {
+        self.auth(token)
+            .ok()
+            .and_then(|finder| finder.get(id))
+            .map_or(Pressure::None, Stream::pressure)
+    }
+
+    /// What `consume` would do with `msg`, short of pushing it. Nothing is
+    /// changed: no stream is pushed to or provisioned, and no policy state
+    /// is updated.
+    fn dry_run(&mut self, msg: Message) -> DryRunOutcome<Self::AuthErr> {
+        self.dry_run_parts(msg.header.token, msg.header.id, msg.header.timestamp, msg.payload)
+    }
+
+    /// What `dry_run` delegates to. Wrappers with policies should check
+    /// them here without applying them, then forward.
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     _: Duration,
+                     _: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        match self.auth(token) {
+            Err(e) => DryRunOutcome::Unauthorized(e),
+            Ok(finder) if finder.contains_key(id) => DryRunOutcome::WouldStore,
+            Ok(_) => DryRunOutcome::MissingId,
+        }
+    }
+
+    /// Pushes historical records, in order, to one stream.
+    fn backfill<'r, I>(&mut self, token: &[u8], id: &[u8], records: I) -> BackfillReport
+        where I: IntoIterator<Item = (Duration, &'r [u8])>
+    {
+        let mut report = BackfillReport::default();
+        for (timestamp, payload) in records {
+            match self.backfill_parts(token, id, timestamp, payload) {
+                Ok(_) => {
+                    report.accepted += 1;
+                    if report.first.is_none() {
+                        report.first = Some(timestamp);
+                    }
+                    report.last = Some(timestamp);
+                }
+                Err(ConsumeError::Push(_)) => report.failed += 1,
+                Err(_) => report.rejected += 1,
+            }
+        }
+        report
+    }
+
+    /// What `backfill` calls for each record. Wrappers whose policies
+    /// assume live, in-order timestamps should relax them here and forward
+    /// to the inner server's `backfill_parts`.
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        self.consume_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::io;
+    use std::iter;
+    use std::time::Duration;
+
+    use super::*;
+    use {message, test_support, Message};
+    use test_support::server as mocks;
+    use test_support::*;
+
+    #[test]
+    fn io_kinds() {
+        type E = ConsumeError<io::Error, io::Error>;
+        let cases: Vec<(E, io::ErrorKind)> = vec![
+            (ConsumeError::Auth(AuthError::InvalidToken), io::ErrorKind::PermissionDenied),
+            (ConsumeError::Auth(AuthError::Other(io::Error::new(io::ErrorKind::Other, ""))),
+             io::ErrorKind::Other),
+            (ConsumeError::MissingId, io::ErrorKind::NotFound),
+            (ConsumeError::Rejected("policy"), io::ErrorKind::InvalidInput),
+            (ConsumeError::StreamCapExceeded { cap: 1 }, io::ErrorKind::Other),
+            (ConsumeError::ExpiredPayload {
+                 age: Duration::from_secs(2),
+                 max_age: Duration::from_secs(1),
+             },
+             io::ErrorKind::InvalidInput),
+            (ConsumeError::Push(io::Error::new(io::ErrorKind::Other, "")), io::ErrorKind::Other),
+        ];
+        for (err, kind) in cases {
+            assert_eq!(kind, err.io_kind());
+            assert_eq!(kind, err.into_io().kind());
+        }
+    }
+
+    #[test]
+    fn into_io_round_trip() {
+        let err: ConsumeError<io::Error, io::Error> = ConsumeError::MissingId;
+        let io_err: io::Error = err.into();
+        assert_match!(ConsumeError::MissingId,
+                      *io_err.into_inner()
+                             .unwrap()
+                             .downcast::<ConsumeError<io::Error, io::Error>>()
+                             .unwrap());
+    }
+
+    fn same_outcome<S: Server>(mut a: S, mut b: S, msg: Message) -> bool
+        where S::AuthErr: fmt::Debug,
+              S::ConsumeOk: fmt::Debug,
+              <S::Stream as Stream>::PushErr: fmt::Debug
+    {
+        let parts = b.consume_parts(msg.header.token, msg.header.id, msg.header.timestamp,
+                                    msg.payload);
+        format!("{:?}", a.consume(msg)) == format!("{:?}", parts)
+    }
+
+    quickcheck_test! {
+    consume_matches_parts(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>;
+                          bool) {
+        let msg = Message {
+            header: message::Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &*payload,
+        };
+        let ok = || mocks::Ok(iter::once((id.clone(), test_support::stream::Ok)).collect());
+        let broken = || mocks::Ok(iter::once((id.clone(), test_support::stream::Broken)).collect());
+        let missing = || mocks::Ok(Finder::<test_support::stream::Ok>::new());
+        same_outcome(mocks::RefuseToAuth, mocks::RefuseToAuth, msg.clone()) &&
+        same_outcome(mocks::CannotAuth, mocks::CannotAuth, msg.clone()) &&
+        same_outcome(ok(), ok(), msg.clone()) &&
+        same_outcome(broken(), broken(), msg.clone()) &&
+        same_outcome(missing(), missing(), msg)
+    }}
+
+    quickcheck_test! {
+    missing_token(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>;
+                  TestResult) {
+        let msg = Message {
+            header: message::Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &*payload,
+        };
+        test_result_match!(Err(ConsumeError::Auth(AuthError::InvalidToken)),
+                           mocks::RefuseToAuth.consume(msg))
+    }}
+
+    quickcheck_test! {
+    other_auth_error(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>;
+                     TestResult) {
+        let msg = Message {
+            header: message::Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &*payload,
+        };
+        test_result_match!(Err(ConsumeError::Auth(AuthError::Other(_))),
+                           mocks::CannotAuth.consume(msg))
+    }}
+
+    quickcheck_test! {
+    missing_id(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>;
+               TestResult) {
+        let msg = Message {
+            header: message::Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &*payload,
+        };
+        let finder: Finder<test_support::stream::Impossible> = Finder::new();
+        test_result_match!(Err(ConsumeError::MissingId), mocks::Ok(finder).consume(msg))
+    }}
+
+    quickcheck_test! {
+    push_error(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>;
+               TestResult) {
+        let finder: Finder<_> = iter::once(
+            (id.clone(), test_support::stream::Broken)).collect();
+        let msg = Message {
+            header: message::Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &*payload,
+        };
+        test_result_match!(Err(ConsumeError::Push(_)), mocks::Ok(finder).consume(msg))
+    }}
+
+    quickcheck_test! {
+    ok_consume(token: Vec<u8>, id: Vec<u8>, millis: u64, payload: Vec<u8>;
+               TestResult) {
+        let finder: Finder<_> = iter::once(
+            (id.clone(), test_support::stream::Ok)).collect();
+        let msg = Message {
+            header: message::Header {
+                token: &token,
+                id: &id,
+                timestamp: Duration::from_millis(millis),
+            },
+            payload: &*payload,
+        };
+        test_result_match!(Ok(_), mocks::Ok(finder).consume(msg))
+    }}
+
+    #[derive(Debug, Default)]
+    struct Recording(Vec<(Duration, Vec<u8>)>);
+
+    impl Stream for Recording {
+        type PushErr = ::Void;
+        fn push(&mut self, timestamp: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+            self.0.push((timestamp, payload.to_owned()));
+            Ok(())
+        }
+
+        type Extract = Vec<(Duration, Vec<u8>)>;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Result::Ok(self.0)
+        }
+    }
+
+    /// Rejects live timestamps that do not advance, but lets backfill
+    /// through.
+    struct Monotonic<S> {
+        server: S,
+        latest: Option<Duration>,
+    }
+
+    impl<S: Server> Server for Monotonic<S> {
+        type Stream = S::Stream;
+        type AuthErr = S::AuthErr;
+        fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+            self.server.auth(token)
+        }
+
+        type ConsumeOk = S::ConsumeOk;
+        fn consume_parts_traced(&mut self,
+                                token: &[u8],
+                                id: &[u8],
+                                timestamp: Duration,
+                                payload: &[u8],
+                                spans: &mut Spans)
+                                -> Consumed<Self> {
+            if self.latest.map_or(false, |latest| timestamp <= latest) {
+                return Err(ConsumeError::Rejected("timestamp regressed"));
+            }
+            let ok = try!(self.server.consume_parts_traced(token, id, timestamp, payload, spans));
+            self.latest = Some(timestamp);
+            Result::Ok(ok)
+        }
+
+        fn backfill_parts(&mut self,
+                          token: &[u8],
+                          id: &[u8],
+                          timestamp: Duration,
+                          payload: &[u8])
+                          -> Consumed<Self> {
+            self.server.backfill_parts(token, id, timestamp, payload)
+        }
+    }
+
+    fn wrapped() -> Reaper<Monotonic<mocks::Ok<Recording>>> {
+        let finder = iter::once((b"id".to_vec(), Recording::default())).collect();
+        let mut server = Reaper::new(Monotonic {
+            server: mocks::Ok(finder),
+            latest: None,
+        });
+        assert!(server.consume_parts(b"token", b"id", Duration::from_secs(100), b"live")
+                      .is_ok());
+        server
+    }
+
+    fn contents(server: Reaper<Monotonic<mocks::Ok<Recording>>>) -> Vec<(Duration, Vec<u8>)> {
+        let mocks::Ok(mut finder) = server.into_inner().server;
+        finder.remove(&b"id"[..]).unwrap().0
+    }
+
+    #[test]
+    fn backfill_bypasses_policy() {
+        let old = vec![(Duration::from_secs(1), &b"a"[..]), (Duration::from_secs(2), &b"b"[..])];
+
+        let mut live = wrapped();
+        for &(timestamp, payload) in &old {
+            assert_match!(Err(ConsumeError::Rejected(_)),
+                          live.consume_parts(b"token", b"id", timestamp, payload));
+        }
+        assert_eq!(vec![(Duration::from_secs(100), b"live".to_vec())], contents(live));
+
+        let mut backfilled = wrapped();
+        let report = backfilled.backfill(b"token", b"id", old.iter().cloned());
+        assert_eq!(BackfillReport {
+                       accepted: 2,
+                       rejected: 0,
+                       failed: 0,
+                       first: Some(Duration::from_secs(1)),
+                       last: Some(Duration::from_secs(2)),
+                   },
+                   report);
+        assert_eq!(Some(Duration::from_secs(100)), backfilled.last_seen(b"token", b"id"));
+        assert_eq!(vec![(Duration::from_secs(100), b"live".to_vec()),
+                        (Duration::from_secs(1), b"a".to_vec()),
+                        (Duration::from_secs(2), b"b".to_vec())],
+                   contents(backfilled));
+    }
+
+    #[test]
+    fn backfill_counts_failures() {
+        let records = vec![(Duration::from_secs(1), &b""[..]); 3];
+        let mut broken = mocks::Ok(iter::once((b"id".to_vec(), test_support::stream::Broken))
+                                       .collect());
+        assert_eq!(BackfillReport { failed: 3, ..BackfillReport::default() },
+                   broken.backfill(b"token", b"id", records.iter().cloned()));
+        assert_eq!(BackfillReport { rejected: 3, ..BackfillReport::default() },
+                   broken.backfill(b"token", b"other", records.iter().cloned()));
+        assert_eq!(BackfillReport { rejected: 3, ..BackfillReport::default() },
+                   mocks::RefuseToAuth.backfill(b"token", b"id", records.iter().cloned()));
+    }
+}
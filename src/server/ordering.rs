@@ -0,0 +1,426 @@
+//! Checking that messages reach a server in the order some contract
+//! promises, for tests and staging, and briefly in production: each
+//! feature that queues, retries or spreads messages out has its own idea of
+//! what order it keeps, and this says whether the whole stack still keeps
+//! the order an application depends on.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use stream::sequence::Extractor;
+use stream::Pressure;
+use trace::Spans;
+use util::millis;
+use Server;
+use super::{AuthResult, Consumed, DryRunOutcome};
+
+/// An order messages are promised to arrive in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Contract {
+    /// Each ID's sequence increases, message by message.
+    PerIdFifo,
+    /// No ID's timestamps go back.
+    PerIdTimestamp,
+    /// Each connection's sequence increases, message by message.
+    PerConnectionFifo,
+    /// No timestamp is earlier than one before it, whatever its ID.
+    GlobalTimestamp,
+}
+
+/// Where the sequence the FIFO contracts check comes from. The timestamp
+/// contracts always check timestamps.
+#[derive(Clone, Copy)]
+pub enum Sequencing {
+    /// The header timestamp in milliseconds, which should then be unique
+    /// within a key.
+    Timestamp,
+    /// A number the sender puts in each payload. Messages it cannot be
+    /// read from are not checked against FIFO contracts.
+    Extracted(Extractor),
+}
+
+impl fmt::Debug for Sequencing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Sequencing::Timestamp => f.write_str("Timestamp"),
+            Sequencing::Extracted(_) => f.write_str("Extracted"),
+        }
+    }
+}
+
+/// What a contract orders messages within.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OrderingKey {
+    Id {
+        token: Vec<u8>,
+        id: Vec<u8>,
+    },
+    Connection(u64),
+    Global,
+}
+
+/// A message out of the order its contract promises.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrderingViolation {
+    pub key: OrderingKey,
+    /// The last value seen for the key, which `got` should have been
+    /// after: a sequence number, or milliseconds for a timestamp contract.
+    pub expected_after: u64,
+    pub got: u64,
+    pub contract: Contract,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OrderingCounts {
+    /// Messages consumed through the checker.
+    pub checked: u64,
+    /// Messages with no sequence to check FIFO contracts against.
+    pub unsequenced: u64,
+    /// Keys forgotten to stay within capacity; a key's first message after
+    /// is checked against nothing.
+    pub evicted: u64,
+    pub per_id_fifo: u64,
+    pub per_id_timestamp: u64,
+    pub per_connection_fifo: u64,
+    pub global_timestamp: u64,
+}
+
+impl OrderingCounts {
+    /// Violations of `contract`.
+    pub fn violations(&self, contract: Contract) -> u64 {
+        match contract {
+            Contract::PerIdFifo => self.per_id_fifo,
+            Contract::PerIdTimestamp => self.per_id_timestamp,
+            Contract::PerConnectionFifo => self.per_connection_fifo,
+            Contract::GlobalTimestamp => self.global_timestamp,
+        }
+    }
+
+    fn violated(&mut self, contract: Contract) {
+        match contract {
+            Contract::PerIdFifo => self.per_id_fifo += 1,
+            Contract::PerIdTimestamp => self.per_id_timestamp += 1,
+            Contract::PerConnectionFifo => self.per_connection_fifo += 1,
+            Contract::GlobalTimestamp => self.global_timestamp += 1,
+        }
+    }
+}
+
+/// Checks `contracts` on the messages consumed through it, on arrival,
+/// then consumes them with the inner server whatever it finds, so that
+/// nothing a checked stack does changes. Backfills and dry runs are passed
+/// on unchecked.
+///
+/// The last value is kept for each key, up to a capacity, past which the
+/// key first seen longest ago is forgotten. A server sees no
+/// connections, so messages are taken to come from the connection last
+/// given to `set_connection`, 0 until then.
+pub struct OrderingChecker<S> {
+    server: S,
+    contracts: Vec<Contract>,
+    sequencing: Sequencing,
+    connection: u64,
+    capacity: usize,
+    last: HashMap<(Contract, OrderingKey), u64>,
+    order: VecDeque<(Contract, OrderingKey)>,
+    on_violation: Option<Box<FnMut(&OrderingViolation) + Send>>,
+    counts: OrderingCounts,
+}
+
+impl<S: Server> OrderingChecker<S> {
+    /// Keeps up to 4096 keys.
+    pub fn new(server: S, contracts: &[Contract], sequencing: Sequencing) -> Self {
+        OrderingChecker::with_capacity(server, contracts, sequencing, 4096)
+    }
+
+    pub fn with_capacity(server: S,
+                         contracts: &[Contract],
+                         sequencing: Sequencing,
+                         capacity: usize)
+                         -> Self {
+        OrderingChecker {
+            server: server,
+            contracts: contracts.to_vec(),
+            sequencing: sequencing,
+            connection: 0,
+            capacity: capacity,
+            last: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            on_violation: None,
+            counts: OrderingCounts::default(),
+        }
+    }
+
+    /// Calls `f` on each violation, before the message is consumed.
+    pub fn set_on_violation<F>(&mut self, f: F)
+        where F: FnMut(&OrderingViolation) + Send + 'static
+    {
+        self.on_violation = Some(Box::new(f));
+    }
+
+    /// The connection the messages from now on come from.
+    pub fn set_connection(&mut self, connection: u64) {
+        self.connection = connection;
+    }
+
+    pub fn counts(&self) -> OrderingCounts {
+        self.counts.clone()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+
+    fn check(&mut self, token: &[u8], id: &[u8], timestamp: Duration, payload: &[u8]) {
+        self.counts.checked += 1;
+        let sequence = match self.sequencing {
+            Sequencing::Timestamp => Some(millis(timestamp)),
+            Sequencing::Extracted(extract) => extract(payload).map(u64::from),
+        };
+        let fifo = self.contracts
+                       .iter()
+                       .any(|&c| c == Contract::PerIdFifo || c == Contract::PerConnectionFifo);
+        if fifo && sequence.is_none() {
+            self.counts.unsequenced += 1;
+        }
+        for i in 0..self.contracts.len() {
+            let contract = self.contracts[i];
+            let (key, got, strict) = match contract {
+                Contract::PerIdFifo | Contract::PerIdTimestamp => {
+                    let key = OrderingKey::Id {
+                        token: token.to_owned(),
+                        id: id.to_owned(),
+                    };
+                    if contract == Contract::PerIdFifo {
+                        (key, sequence, true)
+                    } else {
+                        (key, Some(millis(timestamp)), false)
+                    }
+                }
+                Contract::PerConnectionFifo => {
+                    (OrderingKey::Connection(self.connection), sequence, true)
+                }
+                Contract::GlobalTimestamp => (OrderingKey::Global, Some(millis(timestamp)), false),
+            };
+            if let Some(got) = got {
+                self.observe(contract, key, got, strict);
+            }
+        }
+    }
+
+    /// Records `got` as the last value for `key`, reporting it if it is
+    /// before the one it follows, or, if `strict`, no later.
+    fn observe(&mut self, contract: Contract, key: OrderingKey, got: u64, strict: bool) {
+        let slot = (contract, key);
+        let previous = self.last.get(&slot).cloned();
+        match previous {
+            Some(last) => {
+                if got < last || (strict && got == last) {
+                    self.counts.violated(contract);
+                    let violation = OrderingViolation {
+                        key: slot.1.clone(),
+                        expected_after: last,
+                        got: got,
+                        contract: contract,
+                    };
+                    if let Some(ref mut f) = self.on_violation {
+                        f(&violation);
+                    }
+                }
+                self.last.insert(slot, got);
+                return;
+            }
+            None => {
+                if self.capacity == 0 {
+                    return;
+                }
+                if self.order.len() == self.capacity {
+                    let oldest = self.order.pop_front().unwrap();
+                    self.last.remove(&oldest);
+                    self.counts.evicted += 1;
+                }
+            }
+        }
+        self.last.insert(slot.clone(), got);
+        self.order.push_back(slot);
+    }
+}
+
+impl<S: Server> Server for OrderingChecker<S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        spans.begin("ordering");
+        self.check(token, id, timestamp, payload);
+        spans.end();
+        self.server.consume_parts_pressed(token, id, timestamp, payload, spans)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        self.server.backfill_parts(token, id, timestamp, payload)
+    }
+
+    fn pressure(&mut self, token: &[u8], id: &[u8]) -> Pressure {
+        self.server.pressure(token, id)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use stream::sequence::be_prefix;
+    use test_support::server::{CountingServer, Ok};
+    use test_support::stream;
+    use Server;
+
+    type Checked = OrderingChecker<CountingServer<Ok<stream::Ok>>>;
+
+    const ALL: [Contract; 4] = [Contract::PerIdFifo,
+                                Contract::PerIdTimestamp,
+                                Contract::PerConnectionFifo,
+                                Contract::GlobalTimestamp];
+
+    fn checker(contracts: &[Contract], sequencing: Sequencing, capacity: usize) -> Checked {
+        let finder = vec![(b"a".to_vec(), stream::Ok), (b"b".to_vec(), stream::Ok)]
+                         .into_iter()
+                         .collect();
+        let server = CountingServer::new(Ok(finder));
+        OrderingChecker::with_capacity(server, contracts, sequencing, capacity)
+    }
+
+    /// Consumes (connection, ID, timestamp, sequence) in order, returning
+    /// the violations of each contract in `ALL`'s order.
+    fn violations(messages: &[(u64, &[u8], u64, u32)]) -> [u64; 4] {
+        let mut checker = checker(&ALL, Sequencing::Extracted(be_prefix), 16);
+        for &(connection, id, millis, seq) in messages {
+            checker.set_connection(connection);
+            let payload = [(seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8];
+            checker.consume_parts(b"t", id, Duration::from_millis(millis), &payload).unwrap();
+        }
+        let counts = checker.counts();
+        assert_eq!(messages.len() as u64, counts.checked);
+        [counts.violations(ALL[0]),
+         counts.violations(ALL[1]),
+         counts.violations(ALL[2]),
+         counts.violations(ALL[3])]
+    }
+
+    #[test]
+    fn flags_each_contract_alone() {
+        assert_eq!([0, 0, 0, 0],
+                   violations(&[(0, b"a", 1, 0), (0, b"b", 1, 1), (0, b"a", 2, 2)]));
+        // An ID's frames cross over between connections.
+        assert_eq!([1, 0, 0, 0], violations(&[(0, b"a", 1, 5), (1, b"a", 2, 0)]));
+        // A connection's frames cross over between IDs.
+        assert_eq!([0, 0, 1, 0], violations(&[(0, b"a", 1, 1), (0, b"b", 2, 0)]));
+        // One ID's clock runs behind another's.
+        assert_eq!([0, 0, 0, 1], violations(&[(0, b"a", 10, 0), (1, b"b", 5, 0)]));
+        // An ID's clock goes back, which a connection's sequence does not
+        // notice.
+        assert_eq!([0, 1, 0, 1], violations(&[(0, b"a", 10, 0), (0, b"a", 5, 1)]));
+        // Repeats break FIFO contracts but not timestamp ones.
+        assert_eq!([1, 0, 1, 0], violations(&[(0, b"a", 10, 3), (0, b"a", 10, 3)]));
+    }
+
+    #[test]
+    fn reports_violations_and_consumes_regardless() {
+        let mut checker = checker(&ALL, Sequencing::Timestamp, 16);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let reported = seen.clone();
+        checker.set_on_violation(move |v| reported.lock().unwrap().push(v.clone()));
+        for &(id, millis) in &[(b"a", 20), (b"b", 30), (b"a", 10)] {
+            checker.consume_parts(b"t", id, Duration::from_millis(millis), b"").unwrap();
+        }
+        let id = OrderingKey::Id {
+            token: b"t".to_vec(),
+            id: b"a".to_vec(),
+        };
+        let violation = |key, expected_after, contract| {
+            OrderingViolation {
+                key: key,
+                expected_after: expected_after,
+                got: 10,
+                contract: contract,
+            }
+        };
+        assert_eq!(vec![violation(id.clone(), 20, Contract::PerIdFifo),
+                        violation(id, 20, Contract::PerIdTimestamp),
+                        violation(OrderingKey::Connection(0), 30, Contract::PerConnectionFifo),
+                        violation(OrderingKey::Global, 30, Contract::GlobalTimestamp)],
+                   *seen.lock().unwrap());
+        assert_eq!(3, checker.get_ref().stored());
+
+        checker.backfill_parts(b"t", b"a", Duration::from_millis(1), b"").unwrap();
+        assert_eq!(3, checker.counts().checked);
+        assert_eq!(4, checker.get_ref().calls().len());
+    }
+
+    #[test]
+    fn forgets_keys_past_capacity() {
+        let mut checker = checker(&[Contract::PerIdFifo], Sequencing::Extracted(be_prefix), 1);
+        for &(id, payload) in &[(b"a", &b""[..]),
+                                (b"a", b"\0\0\0\x05"),
+                                (b"b", b"\0\0\0\x09"),
+                                (b"a", b"\0\0\0\x01"),
+                                (b"a", b"\0\0\0\x00")] {
+            checker.consume_parts(b"t", id, Duration::from_millis(1), payload).unwrap();
+        }
+        // `a` is forgotten for `b`, and `b` for `a`, so only the last
+        // message is out of order as far as the checker knows.
+        assert_eq!(OrderingCounts {
+                       checked: 5,
+                       unsequenced: 1,
+                       evicted: 2,
+                       per_id_fifo: 1,
+                       ..OrderingCounts::default()
+                   },
+                   checker.counts());
+    }
+}
@@ -0,0 +1,751 @@
+//! Wrappers that refuse replayed and stale messages, with state that can be
+//! saved before a restart and loaded after.
+//!
+//! Both save the same versioned layout: a four-byte magic, a version byte,
+//! a big-endian `u32` count, then per entry a `u64` fingerprint, the token
+//! and ID each after a `u16` length, a `u64` of state, and a `u64` check
+//! over the rest of the entry. Version 1 had no check, so its dedup entries
+//! could not be verified, and is no longer loaded.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Once, ONCE_INIT};
+use std::time::Duration;
+
+use stream::Pressure;
+use trace::Spans;
+use util::{fnv, millis, FNV_BASIS};
+use Server;
+use super::{AuthResult, ConsumeError, Consumed, DryRunOutcome};
+
+const HIGH_WATER_MAGIC: [u8; 4] = *b"SVHW";
+const DEDUP_MAGIC: [u8; 4] = *b"SVDD";
+const VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub enum ProtectionError {
+    Io(io::Error),
+    /// The state does not start with the magic for what is loading it.
+    UnknownFormat {
+        offset: u64,
+    },
+    UnknownVersion {
+        offset: u64,
+        version: u8,
+    },
+    /// The state ends partway through the record at `offset`.
+    Truncated {
+        offset: u64,
+    },
+    /// The record at `offset` does not match its check.
+    Corrupt {
+        offset: u64,
+    },
+}
+
+impl From<io::Error> for ProtectionError {
+    fn from(e: io::Error) -> Self {
+        ProtectionError::Io(e)
+    }
+}
+
+impl ProtectionError {
+    /// The kind of an I/O error, and `InvalidData` otherwise.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            ProtectionError::Io(ref e) => e.kind(),
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(self.io_kind(), self)
+    }
+}
+
+impl Display for ProtectionError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ProtectionError::Io(ref e) => e.fmt(f),
+            ProtectionError::UnknownFormat { offset } => {
+                write!(f, "unknown protection state format at offset {}", offset)
+            }
+            ProtectionError::UnknownVersion { offset, version } => {
+                write!(f, "unknown protection state version {} at offset {}", version, offset)
+            }
+            ProtectionError::Truncated { offset } => {
+                write!(f, "protection state truncated in record at offset {}", offset)
+            }
+            ProtectionError::Corrupt { offset } => {
+                write!(f, "corrupt protection state record at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl error::Error for ProtectionError {
+    fn description(&self) -> &str {
+        match *self {
+            ProtectionError::Io(ref e) => e.description(),
+            ProtectionError::UnknownFormat { .. } => "unknown protection state format",
+            ProtectionError::UnknownVersion { .. } => "unknown protection state version",
+            ProtectionError::Truncated { .. } => "protection state truncated",
+            ProtectionError::Corrupt { .. } => "corrupt protection state record",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ProtectionError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// How many loaded entries were kept, and how many were skipped because
+/// their token no longer authenticates or their ID has no stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    pub restored: u64,
+    pub skipped: u64,
+}
+
+/// FNV-1a, which unlike the standard hasher is the same in every build, so
+/// fingerprints survive an upgrade.
+pub fn fingerprint(parts: &[&[u8]]) -> u64 {
+    let mut hash = FNV_BASIS;
+    for part in parts {
+        let mut len = [0_u8; 8];
+        BigEndian::write_u64(&mut len, part.len() as u64);
+        hash = fnv(fnv(hash, &len), part);
+    }
+    hash
+}
+
+/// Like `fingerprint`, but keyed with a secret drawn afresh in each
+/// process, so that a dump of them cannot be checked against guessed
+/// tokens. They mean nothing to another process.
+pub fn keyed_fingerprint(parts: &[&[u8]]) -> u64 {
+    let mut hasher = secret().build_hasher();
+    for part in parts {
+        hasher.write_u64(part.len() as u64);
+        hasher.write(part);
+    }
+    hasher.finish()
+}
+
+/// The key of `keyed_fingerprint`, drawn on first use.
+fn secret() -> &'static RandomState {
+    static INIT: Once = ONCE_INIT;
+    static mut SECRET: *const RandomState = 0 as *const RandomState;
+    unsafe {
+        INIT.call_once(|| SECRET = Box::into_raw(Box::new(RandomState::new())));
+        &*SECRET
+    }
+}
+
+struct Entry {
+    fingerprint: u64,
+    token: Vec<u8>,
+    id: Vec<u8>,
+    state: u64,
+}
+
+fn write_header<W: Write>(w: &mut W, magic: &[u8; 4], count: usize) -> io::Result<()> {
+    let mut header = [0_u8; 9];
+    header[..4].copy_from_slice(magic);
+    header[4] = VERSION;
+    BigEndian::write_u32(&mut header[5..], count as u32);
+    w.write_all(&header)
+}
+
+/// What an entry's check must be.
+fn check(fingerprint: u64, token: &[u8], id: &[u8], state: u64) -> u64 {
+    let mut fixed = [0_u8; 16];
+    BigEndian::write_u64(&mut fixed[..8], fingerprint);
+    BigEndian::write_u64(&mut fixed[8..], state);
+    self::fingerprint(&[&fixed, token, id])
+}
+
+fn write_entry<W: Write>(w: &mut W,
+                         fingerprint: u64,
+                         token: &[u8],
+                         id: &[u8],
+                         state: u64)
+                         -> io::Result<()> {
+    let mut fixed = [0_u8; 8];
+    BigEndian::write_u64(&mut fixed, fingerprint);
+    try!(w.write_all(&fixed));
+    for part in &[token, id] {
+        let mut len = [0_u8; 2];
+        BigEndian::write_u16(&mut len, part.len() as u16);
+        try!(w.write_all(&len));
+        try!(w.write_all(part));
+    }
+    BigEndian::write_u64(&mut fixed, state);
+    try!(w.write_all(&fixed));
+    BigEndian::write_u64(&mut fixed, check(fingerprint, token, id, state));
+    w.write_all(&fixed)
+}
+
+/// A reader that knows how far into the state it is.
+struct Counting<R> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: Read> Counting<R> {
+    /// Fills `buf`, or fails as truncated at `start`.
+    fn fill(&mut self, buf: &mut [u8], start: u64) -> Result<(), ProtectionError> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.reader.read(&mut buf[n..]) {
+                Ok(0) => return Err(ProtectionError::Truncated { offset: start }),
+                Ok(k) => {
+                    n += k;
+                    self.offset += k as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the header for `magic`, returning the entry count. The count is
+    /// only as good as the entries that follow, so room is made for them as
+    /// they are read rather than up front.
+    fn header(&mut self, magic: &[u8; 4]) -> Result<u32, ProtectionError> {
+        let start = self.offset;
+        let mut header = [0_u8; 9];
+        try!(self.fill(&mut header, start));
+        if header[..4] != magic[..] {
+            return Err(ProtectionError::UnknownFormat { offset: start });
+        }
+        if header[4] != VERSION {
+            return Err(ProtectionError::UnknownVersion {
+                offset: start + 4,
+                version: header[4],
+            });
+        }
+        Ok(BigEndian::read_u32(&header[5..]))
+    }
+
+    /// Reads an entry and verifies its check, returning where it started
+    /// too.
+    fn entry(&mut self) -> Result<(u64, Entry), ProtectionError> {
+        let start = self.offset;
+        let mut fixed = [0_u8; 8];
+        try!(self.fill(&mut fixed, start));
+        let fingerprint = BigEndian::read_u64(&fixed);
+        let mut parts = vec![];
+        for _ in 0..2 {
+            let mut len = [0_u8; 2];
+            try!(self.fill(&mut len, start));
+            let mut part = vec![0; BigEndian::read_u16(&len) as usize];
+            try!(self.fill(&mut part, start));
+            parts.push(part);
+        }
+        try!(self.fill(&mut fixed, start));
+        let state = BigEndian::read_u64(&fixed);
+        try!(self.fill(&mut fixed, start));
+        let id = parts.pop().unwrap();
+        let token = parts.pop().unwrap();
+        if BigEndian::read_u64(&fixed) != check(fingerprint, &token, &id, state) {
+            return Err(ProtectionError::Corrupt { offset: start });
+        }
+        Ok((start,
+            Entry {
+                fingerprint: fingerprint,
+                token: token,
+                id: id,
+                state: state,
+            }))
+    }
+}
+
+fn has_stream<S: Server>(server: &mut S, token: &[u8], id: &[u8]) -> bool {
+    server.auth(token).map(|finder| finder.contains_key(id)).unwrap_or(false)
+}
+
+/// Refuses live messages older than the newest yet consumed for their token
+/// and ID. Backfill is let through, though it still raises the mark.
+pub struct HighWaterMark<S> {
+    server: S,
+    marks: HashMap<(Vec<u8>, Vec<u8>), Duration>,
+}
+
+impl<S: Server> HighWaterMark<S> {
+    pub fn new(server: S) -> Self {
+        HighWaterMark {
+            server: server,
+            marks: HashMap::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+
+    pub fn mark(&self, token: &[u8], id: &[u8]) -> Option<Duration> {
+        self.marks.get(&(token.to_owned(), id.to_owned())).cloned()
+    }
+
+    /// Whether a live message with this timestamp would be refused.
+    pub fn is_stale(&self, token: &[u8], id: &[u8], timestamp: Duration) -> bool {
+        self.mark(token, id).map_or(false, |mark| timestamp < mark)
+    }
+
+    fn raise(&mut self, token: &[u8], id: &[u8], timestamp: Duration) {
+        let mark = self.marks.entry((token.to_owned(), id.to_owned())).or_insert(timestamp);
+        if *mark < timestamp {
+            *mark = timestamp;
+        }
+    }
+
+    /// Writes every mark, in (token, ID) order.
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut marks: Vec<_> = self.marks.iter().collect();
+        marks.sort();
+        try!(write_header(&mut w, &HIGH_WATER_MAGIC, marks.len()));
+        for (&(ref token, ref id), &mark) in marks {
+            try!(write_entry(&mut w, fingerprint(&[token, id]), token, id, millis(mark)));
+        }
+        Ok(())
+    }
+
+    /// Raises marks to those saved, skipping any whose stream is gone.
+    pub fn load<R: Read>(&mut self, r: R) -> Result<RestoreReport, ProtectionError> {
+        self.load_from(&mut Counting {
+            reader: r,
+            offset: 0,
+        })
+    }
+
+    fn load_from<R: Read>(&mut self,
+                          r: &mut Counting<R>)
+                          -> Result<RestoreReport, ProtectionError> {
+        let count = try!(r.header(&HIGH_WATER_MAGIC));
+        let mut entries = vec![];
+        for _ in 0..count {
+            let (offset, entry) = try!(r.entry());
+            if entry.fingerprint != fingerprint(&[&entry.token, &entry.id]) {
+                return Err(ProtectionError::Corrupt { offset: offset });
+            }
+            entries.push(entry);
+        }
+        let mut report = RestoreReport::default();
+        for entry in entries {
+            if has_stream(&mut self.server, &entry.token, &entry.id) {
+                self.raise(&entry.token, &entry.id, Duration::from_millis(entry.state));
+                report.restored += 1;
+            } else {
+                report.skipped += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<S: Server> Server for HighWaterMark<S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        spans.begin("high_water_mark");
+        let stale = self.is_stale(token, id, timestamp);
+        spans.end();
+        if stale {
+            return (Err(ConsumeError::Rejected("stale timestamp")), Pressure::None);
+        }
+        let (result, pressure) =
+            self.server.consume_parts_pressed(token, id, timestamp, payload, spans);
+        if result.is_ok() {
+            self.raise(token, id, timestamp);
+        }
+        (result, pressure)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        let ok = try!(self.server.backfill_parts(token, id, timestamp, payload));
+        self.raise(token, id, timestamp);
+        Ok(ok)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        if self.is_stale(token, id, timestamp) {
+            return DryRunOutcome::Rejected("stale timestamp");
+        }
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+fn message_fingerprint(token: &[u8], id: &[u8], timestamp: Duration, payload: &[u8]) -> u64 {
+    let mut ts = [0_u8; 8];
+    BigEndian::write_u64(&mut ts, millis(timestamp));
+    fingerprint(&[token, id, &ts, payload])
+}
+
+/// Refuses messages identical to one of the last `window` consumed. Messages
+/// are told apart by a 64-bit fingerprint of their token, ID, timestamp,
+/// and payload.
+pub struct Dedup<S> {
+    server: S,
+    window: usize,
+    recent: VecDeque<Entry>,
+    seen: HashSet<u64>,
+}
+
+impl<S: Server> Dedup<S> {
+    pub fn new(server: S, window: usize) -> Self {
+        Dedup {
+            server: server,
+            window: window,
+            recent: VecDeque::with_capacity(window),
+            seen: HashSet::with_capacity(window),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+
+    fn remember(&mut self, entry: Entry) {
+        if self.window == 0 || !self.seen.insert(entry.fingerprint) {
+            return;
+        }
+        if self.recent.len() == self.window {
+            let oldest = self.recent.pop_front().unwrap();
+            self.seen.remove(&oldest.fingerprint);
+        }
+        self.recent.push_back(entry);
+    }
+
+    /// Whether a message just like this one is in the window.
+    pub fn is_duplicate(&self,
+                        token: &[u8],
+                        id: &[u8],
+                        timestamp: Duration,
+                        payload: &[u8])
+                        -> bool {
+        self.seen.contains(&message_fingerprint(token, id, timestamp, payload))
+    }
+
+    fn filter(&mut self,
+              backfill: bool,
+              token: &[u8],
+              id: &[u8],
+              timestamp: Duration,
+              payload: &[u8],
+              spans: &mut Spans)
+              -> (Consumed<S>, Pressure) {
+        spans.begin("dedup");
+        let fingerprint = message_fingerprint(token, id, timestamp, payload);
+        let seen = self.seen.contains(&fingerprint);
+        spans.end();
+        if seen {
+            return (Err(ConsumeError::Rejected("duplicate")), Pressure::None);
+        }
+        let (result, pressure) = if backfill {
+            (self.server.backfill_parts(token, id, timestamp, payload), Pressure::None)
+        } else {
+            self.server.consume_parts_pressed(token, id, timestamp, payload, spans)
+        };
+        if result.is_ok() {
+            self.remember(Entry {
+                fingerprint: fingerprint,
+                token: token.to_owned(),
+                id: id.to_owned(),
+                state: millis(timestamp),
+            });
+        }
+        (result, pressure)
+    }
+
+    /// Writes the window, oldest first.
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        try!(write_header(&mut w, &DEDUP_MAGIC, self.recent.len()));
+        for entry in &self.recent {
+            try!(write_entry(&mut w, entry.fingerprint, &entry.token, &entry.id, entry.state));
+        }
+        Ok(())
+    }
+
+    /// Adds the saved window to this one, skipping entries whose stream is
+    /// gone.
+    pub fn load<R: Read>(&mut self, r: R) -> Result<RestoreReport, ProtectionError> {
+        self.load_from(&mut Counting {
+            reader: r,
+            offset: 0,
+        })
+    }
+
+    fn load_from<R: Read>(&mut self,
+                          r: &mut Counting<R>)
+                          -> Result<RestoreReport, ProtectionError> {
+        let count = try!(r.header(&DEDUP_MAGIC));
+        let mut entries = vec![];
+        for _ in 0..count {
+            entries.push(try!(r.entry()).1);
+        }
+        let mut report = RestoreReport::default();
+        for entry in entries {
+            if has_stream(&mut self.server, &entry.token, &entry.id) {
+                self.remember(entry);
+                report.restored += 1;
+            } else {
+                report.skipped += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<S: Server> Dedup<HighWaterMark<S>> {
+    /// Saves the dedup window and then the marks.
+    pub fn persist_protection_state<W: Write>(&self, mut w: W) -> io::Result<()> {
+        try!(self.save(&mut w));
+        self.server.save(w)
+    }
+
+    /// Loads what `persist_protection_state` saved, with both reports
+    /// added together. Offsets in errors are from the start of `r`.
+    pub fn restore_protection_state<R: Read>(&mut self,
+                                             r: R)
+                                             -> Result<RestoreReport, ProtectionError> {
+        let mut r = Counting {
+            reader: r,
+            offset: 0,
+        };
+        let dedup = try!(self.load_from(&mut r));
+        let marks = try!(self.server.load_from(&mut r));
+        Ok(RestoreReport {
+            restored: dedup.restored + marks.restored,
+            skipped: dedup.skipped + marks.skipped,
+        })
+    }
+}
+
+impl<S: Server> Server for Dedup<S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        self.filter(false, token, id, timestamp, payload, spans)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        self.filter(true, token, id, timestamp, payload, &mut Spans::off()).0
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        if self.is_duplicate(token, id, timestamp, payload) {
+            return DryRunOutcome::Rejected("duplicate");
+        }
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use server::{ConsumeError, ConsumeResult, Finder};
+    use test_support::server as mocks;
+    use {test_support, Server};
+
+    type Protected = Dedup<HighWaterMark<mocks::Ok<test_support::stream::Ok>>>;
+
+    fn protected(ids: &[&[u8]]) -> Protected {
+        let finder: Finder<_> = ids.iter()
+                                   .map(|id| (id.to_vec(), test_support::stream::Ok))
+                                   .collect();
+        Dedup::new(HighWaterMark::new(mocks::Ok(finder)), 16)
+    }
+
+    fn consume(server: &mut Protected,
+               id: &[u8],
+               secs: u64,
+               payload: &[u8])
+               -> ConsumeResult<::Void, ::Void> {
+        server.consume_parts(b"t", id, Duration::from_secs(secs), payload)
+    }
+
+    fn persisted() -> Vec<u8> {
+        let mut server = protected(&[b"a", b"b"]);
+        assert!(consume(&mut server, b"a", 10, b"x").is_ok());
+        assert!(consume(&mut server, b"a", 20, b"y").is_ok());
+        assert!(consume(&mut server, b"b", 5, b"z").is_ok());
+        let mut state = vec![];
+        server.persist_protection_state(&mut state).unwrap();
+        state
+    }
+
+    quickcheck_test! {
+    marks_saved_in_id_order(marks: Vec<(u8, u8)>; bool) {
+        let ids: Vec<_> = (0..16_u8).map(|i| vec![i]).collect();
+        let id_refs: Vec<&[u8]> = ids.iter().map(|id| &id[..]).collect();
+        let saved = |marks: &mut Iterator<Item = &(u8, u8)>| {
+            let mut server = protected(&id_refs);
+            for &(id, secs) in marks {
+                let _ = consume(&mut server, &[id % 16], secs as u64, &[secs]);
+            }
+            let mut state = vec![];
+            server.get_ref().save(&mut state).unwrap();
+            state
+        };
+        saved(&mut marks.iter()) == saved(&mut marks.iter().rev())
+    }}
+
+    #[test]
+    fn survives_restart() {
+        let state = persisted();
+        let mut server = protected(&[b"a", b"b"]);
+        assert_eq!(RestoreReport {
+                       restored: 5,
+                       skipped: 0,
+                   },
+                   server.restore_protection_state(&state[..]).unwrap());
+        assert_match!(Err(ConsumeError::Rejected("duplicate")),
+                      consume(&mut server, b"a", 20, b"y"));
+        assert_match!(Err(ConsumeError::Rejected("stale timestamp")),
+                      consume(&mut server, b"a", 15, b"w"));
+        assert_match!(Ok(()), consume(&mut server, b"b", 5, b"w"));
+        assert_match!(Ok(()), consume(&mut server, b"a", 30, b"y"));
+    }
+
+    #[test]
+    fn unknown_ids_skipped() {
+        let state = persisted();
+        let mut server = protected(&[b"a"]);
+        assert_eq!(RestoreReport {
+                       restored: 3,
+                       skipped: 2,
+                   },
+                   server.restore_protection_state(&state[..]).unwrap());
+        assert_eq!(None, server.get_ref().mark(b"t", b"b"));
+        assert_eq!(Some(Duration::from_secs(20)), server.get_ref().mark(b"t", b"a"));
+    }
+
+    #[test]
+    fn corrupt_state() {
+        let state = persisted();
+        // Three dedup entries of 30 bytes follow the 9-byte header.
+        let marks = 9 + 3 * 30;
+
+        let mut corrupt = state.clone();
+        corrupt[marks + 9 + 8 + 2] ^= 1;
+        assert_match!(Err(ProtectionError::Corrupt { offset }) if offset == (marks + 9) as u64,
+                      protected(&[b"a"]).restore_protection_state(&corrupt[..]));
+
+        // A dedup entry's fingerprint cannot be recomputed without the
+        // payload, but its check can.
+        for &at in &[9, 9 + 8 + 2, 9 + 8 + 4 + 1] {
+            let mut corrupt = state.clone();
+            corrupt[at] ^= 1;
+            assert_match!(Err(ProtectionError::Corrupt { offset: 9 }),
+                          protected(&[b"a"]).restore_protection_state(&corrupt[..]));
+        }
+
+        assert_match!(Err(ProtectionError::Truncated { offset: 39 }),
+                      protected(&[b"a"]).restore_protection_state(&state[..50]));
+
+        // A count no input could back is found out by the entries missing.
+        let mut inflated = state[..9 + 30].to_vec();
+        inflated[5..9].copy_from_slice(&[0xff; 4]);
+        assert_match!(Err(ProtectionError::Truncated { offset: 39 }),
+                      protected(&[b"a"]).restore_protection_state(&inflated[..]));
+
+        let mut future = state.clone();
+        future[4] = 3;
+        assert_match!(Err(ProtectionError::UnknownVersion { offset: 4, version: 3 }),
+                      protected(&[b"a"]).restore_protection_state(&future[..]));
+
+        assert_match!(Err(ProtectionError::UnknownFormat { offset: 0 }),
+                      protected(&[b"a"]).get_mut().load(&state[..]));
+    }
+}
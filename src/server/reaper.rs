@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+use stream;
+use stream::{ExtractEnvelope, Pressure};
+use trace::Spans;
+use {Server, Stream};
+use super::{AuthError, AuthResult, Consumed, DryRunOutcome};
+
+pub type Extract<S> = <<S as Server>::Stream as Stream>::Extract;
+pub type ExtractErr<S> = <<S as Server>::Stream as Stream>::ExtractErr;
+
+pub struct ReapReport<S: Server> {
+    /// (token, ID, extract) for each stream removed.
+    pub reaped: Vec<(Vec<u8>, Vec<u8>, Extract<S>)>,
+    /// (token, ID, error) for each stream that failed to extract and so
+    /// remains registered.
+    pub failed: Vec<(Vec<u8>, Vec<u8>, ExtractErr<S>)>,
+    pub unauthorized: Vec<(Vec<u8>, AuthError<S::AuthErr>)>,
+}
+
+impl<S, T> ReapReport<S>
+    where S: Server<Stream = stream::Guarded<T>>,
+          T: Stream
+{
+    /// Takes the (token, ID) pairs that failed only for being busy out of
+    /// `failed`; they are still registered, to be reaped again later.
+    pub fn take_deferred(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let (deferred, failed) = self.failed.drain(..).partition(|&(_, _, ref e)| e.is_busy());
+        self.failed = failed;
+        deferred.into_iter().map(|(token, id, _)| (token, id)).collect()
+    }
+}
+
+impl<S> ReapReport<S>
+    where S: Server,
+          Extract<S>: ExtractEnvelope
+{
+    /// Writes each extract in an envelope labeled with its ID and carrying
+    /// its token's fingerprint, not the token, as `token`. Returns how many
+    /// bytes were written.
+    pub fn write_portable<W: Write>(&self, w: W) -> io::Result<u64> {
+        let mut w = w;
+        let mut written = 0;
+        for &(ref token, ref id, ref extract) in &self.reaped {
+            let mut descriptor = extract.describe();
+            let fingerprint = super::protection::fingerprint(&[token]);
+            descriptor.metadata.push(("token".to_owned(), fingerprint));
+            written += try!(stream::write_envelope(&mut w, id, &descriptor, extract));
+        }
+        Ok(written)
+    }
+}
+
+/// Remembers the last pushed timestamp of every stream so that idle ones can
+/// be extracted.
+pub struct Reaper<S> {
+    server: S,
+    last_seen: HashMap<(Vec<u8>, Vec<u8>), Duration>,
+}
+
+impl<S: Server> Reaper<S> {
+    pub fn new(server: S) -> Self {
+        Reaper {
+            server: server,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+
+    pub fn last_seen(&self, token: &[u8], id: &[u8]) -> Option<Duration> {
+        self.last_seen.get(&(token.to_owned(), id.to_owned())).cloned()
+    }
+
+    fn saw(&mut self, token: &[u8], id: &[u8], timestamp: Duration) {
+        let last = self.last_seen.entry((token.to_owned(), id.to_owned())).or_insert(timestamp);
+        if *last < timestamp {
+            *last = timestamp;
+        }
+    }
+
+    /// Extracts every stream whose last push is more than `idle_longer_than`
+    /// before `now`, in (token, ID) order.
+    pub fn reap(&mut self, idle_longer_than: Duration, now: Duration) -> ReapReport<S> {
+        let mut report = ReapReport {
+            reaped: vec![],
+            failed: vec![],
+            unauthorized: vec![],
+        };
+        let mut idle: Vec<_> = self.last_seen
+                                   .iter()
+                                   .filter(|&(_, &last)| last + idle_longer_than < now)
+                                   .map(|(key, _)| key.clone())
+                                   .collect();
+        idle.sort();
+
+        let mut idle = idle.into_iter().peekable();
+        while let Some((token, id)) = idle.next() {
+            match self.server.auth(&token) {
+                Err(e) => {
+                    while idle.peek().map_or(false, |&(ref t, _)| t == &token) {
+                        idle.next();
+                    }
+                    report.unauthorized.push((token, e));
+                }
+                Ok(finder) => {
+                    match stream::Finder::extract(finder, &id) {
+                        None => {
+                            self.last_seen.remove(&(token, id));
+                        }
+                        Some(Ok(extract)) => {
+                            self.last_seen.remove(&(token.clone(), id.clone()));
+                            report.reaped.push((token, id, extract));
+                        }
+                        Some(Err(e)) => report.failed.push((token, id, e)),
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+impl<S: Server> Server for Reaper<S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        let (result, pressure) =
+            self.server.consume_parts_pressed(token, id, timestamp, payload, spans);
+        if result.is_ok() {
+            self.saw(token, id, timestamp);
+        }
+        (result, pressure)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        let result = self.server.backfill_parts(token, id, timestamp, payload);
+        if result.is_ok() {
+            self.saw(token, id, timestamp);
+        }
+        result
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use message::Header;
+    use server::{AuthError, Finder};
+    use test_support::server as mocks;
+    use {Message, Server, Stream};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Mock {
+        Ok,
+        Broken,
+    }
+
+    impl Stream for Mock {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+            Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ();
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            match self {
+                Mock::Ok => Ok(()),
+                Mock::Broken => Err((self, ())),
+            }
+        }
+    }
+
+    fn consume<S: Server>(reaper: &mut Reaper<S>, id: &[u8], millis: u64) -> bool {
+        reaper.consume(Message {
+                  header: Header {
+                      token: b"token",
+                      id: id,
+                      timestamp: Duration::from_millis(millis),
+                  },
+                  payload: b"",
+              })
+              .is_ok()
+    }
+
+    #[test]
+    fn reaps_only_idle() {
+        let finder: Finder<_> = vec![(b"active".to_vec(), Mock::Ok),
+                                     (b"idle".to_vec(), Mock::Ok),
+                                     (b"failing".to_vec(), Mock::Broken),
+                                     (b"silent".to_vec(), Mock::Ok)]
+                                    .into_iter()
+                                    .collect();
+        let mut reaper = Reaper::new(mocks::Ok(finder));
+        assert!(consume(&mut reaper, b"idle", 100));
+        assert!(consume(&mut reaper, b"failing", 100));
+        assert!(consume(&mut reaper, b"active", 100));
+        assert!(consume(&mut reaper, b"active", 900));
+        assert!(!consume(&mut reaper, b"unregistered", 100));
+
+        let report = reaper.reap(Duration::from_millis(500), Duration::from_millis(1000));
+        assert_eq!(vec![(b"token".to_vec(), b"idle".to_vec(), ())],
+                   report.reaped);
+        assert_eq!(vec![(b"token".to_vec(), b"failing".to_vec(), ())],
+                   report.failed);
+        assert!(report.unauthorized.is_empty());
+
+        let mocks::Ok(finder) = reaper.into_inner();
+        let mut remaining: Vec<_> = finder.keys().cloned().collect();
+        remaining.sort();
+        assert_eq!(vec![b"active".to_vec(), b"failing".to_vec(), b"silent".to_vec()],
+                   remaining);
+    }
+
+    #[test]
+    fn report_ignores_insertion_order() {
+        let ids: Vec<_> = (0..32_u8).map(|i| vec![i]).collect();
+        let report = |ids: &mut Iterator<Item = &Vec<u8>>| {
+            let ids: Vec<_> = ids.cloned().collect();
+            let mock = |id: &Vec<u8>| if id[0] % 3 == 0 { Mock::Broken } else { Mock::Ok };
+            let finder: Finder<_> = ids.iter().map(|id| (id.clone(), mock(id))).collect();
+            let mut reaper = Reaper::new(mocks::Ok(finder));
+            for id in &ids {
+                // Odd IDs stay active.
+                assert!(consume(&mut reaper, id, 100 + (id[0] % 2) as u64 * 800));
+            }
+            let report = reaper.reap(Duration::from_millis(500), Duration::from_millis(1000));
+            format!("{:?} {:?}", report.reaped, report.failed)
+        };
+        assert_eq!(report(&mut ids.iter()), report(&mut ids.iter().rev()));
+    }
+
+    #[test]
+    fn tracks_latest_timestamp() {
+        let finder: Finder<_> = vec![(b"id".to_vec(), Mock::Ok)].into_iter().collect();
+        let mut reaper = Reaper::new(mocks::Ok(finder));
+        assert!(consume(&mut reaper, b"id", 300));
+        assert!(consume(&mut reaper, b"id", 200));
+        assert_eq!(Some(Duration::from_millis(300)), reaper.last_seen(b"token", b"id"));
+        let report = reaper.reap(Duration::from_millis(100), Duration::from_millis(400));
+        assert!(report.reaped.is_empty());
+        assert_eq!(Some(Duration::from_millis(300)), reaper.last_seen(b"token", b"id"));
+    }
+
+    #[test]
+    fn parts_record_like_messages() {
+        let finder = || -> Finder<_> { vec![(b"id".to_vec(), Mock::Ok)].into_iter().collect() };
+        let mut a = Reaper::new(mocks::Ok(finder()));
+        let mut b = Reaper::new(mocks::Ok(finder()));
+        assert!(consume(&mut a, b"id", 300));
+        assert!(b.consume_parts(b"token", b"id", Duration::from_millis(300), b"").is_ok());
+        assert_eq!(a.last_seen, b.last_seen);
+    }
+
+    #[test]
+    fn failed_auth_is_reported() {
+        let mut server = Reaper::new(mocks::RefuseToAuth);
+        server.last_seen.insert((b"token".to_vec(), b"a".to_vec()), Duration::from_millis(0));
+        server.last_seen.insert((b"token".to_vec(), b"b".to_vec()), Duration::from_millis(0));
+        let report = server.reap(Duration::from_millis(0), Duration::from_millis(1));
+        assert_eq!(1, report.unauthorized.len());
+        assert_match!(&(ref token, AuthError::InvalidToken) if token == b"token",
+                      &report.unauthorized[0]);
+    }
+
+    #[test]
+    fn reaped_extracts_as_envelopes() {
+        use server::protection::fingerprint;
+        use server::TokenServer;
+        use stream::{FileStream, PortableBody, PortableReader};
+
+        let mut server = TokenServer::new();
+        server.add_token(b"token").insert(b"id".to_vec(), FileStream::new(vec![]));
+        let mut reaper = Reaper::new(server);
+        assert!(consume(&mut reaper, b"id", 100));
+        let report = reaper.reap(Duration::from_millis(0), Duration::from_millis(1000));
+        let mut archive = vec![];
+        let written = report.write_portable(&mut archive).unwrap();
+        assert_eq!(archive.len() as u64, written);
+
+        let read: Vec<_> = PortableReader::new(&archive[..]).map(Result::unwrap).collect();
+        assert_eq!(1, read.len());
+        assert_eq!(b"id", &read[0].label[..]);
+        assert_eq!(Some(fingerprint(&[b"token"])), read[0].descriptor.get("token"));
+        assert_eq!(PortableBody::Records(vec![(Duration::from_millis(100), vec![])]),
+                   read[0].body);
+    }
+}
@@ -0,0 +1,623 @@
+//! Changing which tokens and IDs a server takes, and its policy, while it
+//! runs. A `ServerAdmin` publishes whole snapshots to a `SharedConfig`; a
+//! `Reloading` server picks up the newest snapshot at the start of each
+//! message, so every message sees one snapshot throughout, and those after
+//! a publish see the new one on every session.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use config::{ConfigError, ServerConfig, ValidatedConfig};
+use stream::Finder as FinderExt;
+use stream::Pressure;
+use trace::Spans;
+use {Server, Stream};
+use super::{AuthError, AuthResult, CapPolicy, ConsumeError, Consumed, Delivery, DryRunOutcome,
+            Permission, RetentionPolicy, TokenServer};
+
+/// A value replaced whole, read by cloning the `Arc` in force.
+///
+/// Readers hold the lock only to clone the `Arc`: an uncontended read lock
+/// is a pair of atomic operations, cheap beside a message, and needs no
+/// dependency as a lock-free swap would. A publish waits only for those
+/// clones, never for a message being consumed.
+pub struct SharedConfig<T> {
+    current: Arc<RwLock<(u64, Arc<T>)>>,
+}
+
+impl<T> Clone for SharedConfig<T> {
+    fn clone(&self) -> Self {
+        SharedConfig { current: self.current.clone() }
+    }
+}
+
+impl<T> SharedConfig<T> {
+    /// At epoch 0.
+    pub fn new(value: T) -> Self {
+        SharedConfig { current: Arc::new(RwLock::new((0, Arc::new(value)))) }
+    }
+
+    /// The value in force and its epoch, which each publish bumps.
+    pub fn load(&self) -> (u64, Arc<T>) {
+        let current = self.current.read().unwrap();
+        (current.0, current.1.clone())
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.current.read().unwrap().0
+    }
+
+    /// Returns the new epoch.
+    pub fn publish(&self, value: T) -> u64 {
+        self.update(|_| Ok::<T, ::Void>(value)).unwrap_or_else(|e| ::absurd(e))
+    }
+
+    /// Publishes what `update` makes of the value in force, unless it
+    /// fails, with no other publish in between.
+    pub fn update<F, E>(&self, update: F) -> Result<u64, E>
+        where F: FnOnce(&T) -> Result<T, E>
+    {
+        let mut current = self.current.write().unwrap();
+        let value = try!(update(&current.1));
+        *current = (current.0 + 1, Arc::new(value));
+        Ok(current.0)
+    }
+}
+
+/// What a token may do under a `Policy`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenPolicy {
+    pub permission: Permission,
+    pub stream_cap: Option<(usize, CapPolicy)>,
+    /// The IDs the token may store under, or any if `None`. Streams for
+    /// IDs no longer allowed are extracted.
+    pub ids: Option<BTreeSet<Vec<u8>>>,
+    /// A diverting policy's quarantine stream is swept like any other,
+    /// so `ids` must allow its ID.
+    pub retention: Option<RetentionPolicy>,
+}
+
+impl Default for TokenPolicy {
+    fn default() -> Self {
+        TokenPolicy {
+            permission: Permission::Data,
+            stream_cap: None,
+            ids: None,
+            retention: None,
+        }
+    }
+}
+
+/// The registered tokens. A token left out is unregistered, once its
+/// streams are extracted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub tokens: BTreeMap<Vec<u8>, TokenPolicy>,
+}
+
+impl Policy {
+    fn validate(&self) -> Vec<ConfigError> {
+        self.tokens
+            .iter()
+            .filter(|&(_, policy)| policy.stream_cap.map_or(false, |(max, _)| max == 0))
+            .map(|(token, _)| ConfigError::ZeroStreamCap { token: token.clone() })
+            .collect()
+    }
+}
+
+/// What a `ServerAdmin` publishes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// For sessions to `configure` themselves by.
+    pub config: ValidatedConfig,
+    pub policy: Policy,
+}
+
+fn validate(config: ServerConfig, policy: Policy) -> Result<Snapshot, Vec<ConfigError>> {
+    let mut errors = policy.validate();
+    match config.validate() {
+        Ok(config) if errors.is_empty() => {
+            Ok(Snapshot {
+                config: config,
+                policy: policy,
+            })
+        }
+        Ok(_) => Err(errors),
+        Err(mut config_errors) => {
+            config_errors.append(&mut errors);
+            Err(config_errors)
+        }
+    }
+}
+
+/// Publishes snapshots to every `Reloading` server made from it. Nothing
+/// is published that does not validate.
+#[derive(Clone)]
+pub struct ServerAdmin {
+    shared: SharedConfig<Snapshot>,
+}
+
+impl ServerAdmin {
+    pub fn new(config: ServerConfig, policy: Policy) -> Result<Self, Vec<ConfigError>> {
+        validate(config, policy).map(|snapshot| ServerAdmin { shared: SharedConfig::new(snapshot) })
+    }
+
+    pub fn current(&self) -> Arc<Snapshot> {
+        self.shared.load().1
+    }
+
+    /// The snapshot in force and its epoch, under one lock.
+    pub fn load(&self) -> (u64, Arc<Snapshot>) {
+        self.shared.load()
+    }
+
+    /// Returns the new epoch, or every error, leaving the snapshot in force.
+    pub fn publish(&self, config: ServerConfig, policy: Policy) -> Result<u64, Vec<ConfigError>> {
+        self.modify(|c, p| {
+            *c = config;
+            *p = policy;
+        })
+    }
+
+    /// Publishes the snapshot in force as changed by `modify`, if it still
+    /// validates.
+    pub fn modify<F>(&self, modify: F) -> Result<u64, Vec<ConfigError>>
+        where F: FnOnce(&mut ServerConfig, &mut Policy)
+    {
+        self.shared.update(|snapshot| {
+            let (mut config, mut policy) = (snapshot.config.get().clone(),
+                                            snapshot.policy.clone());
+            modify(&mut config, &mut policy);
+            validate(config, policy)
+        })
+    }
+
+    /// Registers `token`, or replaces its policy.
+    pub fn add_token(&self, token: &[u8], policy: TokenPolicy) -> Result<u64, Vec<ConfigError>> {
+        self.modify(|_, p| {
+            p.tokens.insert(token.to_vec(), policy);
+        })
+    }
+
+    pub fn remove_token(&self, token: &[u8]) -> Result<u64, Vec<ConfigError>> {
+        self.modify(|_, p| {
+            p.tokens.remove(token);
+        })
+    }
+
+    /// Limits `token` to the IDs it may already store under less `id`. Does
+    /// nothing to a token that may store under any ID.
+    pub fn remove_id(&self, token: &[u8], id: &[u8]) -> Result<u64, Vec<ConfigError>> {
+        self.modify(|_, p| {
+            if let Some(&mut TokenPolicy { ids: Some(ref mut ids), .. }) = p.tokens.get_mut(token) {
+                ids.remove(id);
+            }
+        })
+    }
+
+    pub fn server<S: Stream>(&self, server: TokenServer<S>) -> Reloading<S> {
+        Reloading::new(server, self.shared.clone())
+    }
+}
+
+/// A `TokenServer` whose tokens, permissions, caps, IDs and retention
+/// follow a `SharedConfig`. Factories and hooks stay the server's own.
+///
+/// A stream whose ID or token is removed is refused messages from the
+/// publish on, and extracted; one that will not extract yet, as a
+/// `Guarded` stream with pushes intended, is queued and tried again with
+/// each message until it does. Only a publish scans every stream; a retry
+/// tries just those queued.
+pub struct Reloading<S: Stream> {
+    server: TokenServer<S>,
+    shared: SharedConfig<Snapshot>,
+    epoch: u64,
+    current: Arc<Snapshot>,
+    /// Whether a publish has yet to be swept for.
+    rescan: bool,
+    /// The (token, ID) of each stream removed but busy when last tried.
+    pending: Vec<(Vec<u8>, Vec<u8>)>,
+    removed: Vec<(Vec<u8>, Vec<u8>, S::Extract)>,
+}
+
+impl<S: Stream> Reloading<S> {
+    /// Registers what the snapshot in force says on `server`.
+    pub fn new(server: TokenServer<S>, shared: SharedConfig<Snapshot>) -> Self {
+        let (epoch, current) = shared.load();
+        let mut reloading = Reloading {
+            server: server,
+            shared: shared,
+            epoch: epoch,
+            current: current.clone(),
+            rescan: false,
+            pending: vec![],
+            removed: vec![],
+        };
+        reloading.apply(&Policy::default(), &current.policy);
+        reloading.refresh();
+        reloading
+    }
+
+    pub fn get_ref(&self) -> &TokenServer<S> {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut TokenServer<S> {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> TokenServer<S> {
+        self.server
+    }
+
+    /// The snapshot the last message saw.
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.current
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Streams removed but not yet extracted.
+    pub fn pending_removals(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The extracts of removed streams, as (token, ID, extract), since the
+    /// last call.
+    pub fn take_removed(&mut self) -> Vec<(Vec<u8>, Vec<u8>, S::Extract)> {
+        ::std::mem::replace(&mut self.removed, vec![])
+    }
+
+    /// Takes up the newest snapshot if it is not already in force, and
+    /// retries the removals still pending.
+    fn refresh(&mut self) {
+        let (epoch, next) = self.shared.load();
+        if epoch != self.epoch {
+            let previous = ::std::mem::replace(&mut self.current, next.clone());
+            self.epoch = epoch;
+            self.apply(&previous.policy, &next.policy);
+        }
+        if self.rescan {
+            self.sweep();
+        } else if !self.pending.is_empty() {
+            self.retry();
+        }
+    }
+
+    fn apply(&mut self, previous: &Policy, next: &Policy) {
+        for (token, policy) in &next.tokens {
+            self.server.add_token_with(token, policy.permission);
+            let unchanged = previous.tokens
+                                    .get(token)
+                                    .map_or(false, |p| p.stream_cap == policy.stream_cap);
+            if !unchanged {
+                match policy.stream_cap {
+                    Some((max, cap)) => self.server.set_stream_cap(token, max, cap),
+                    None => self.server.clear_stream_cap(token),
+                }
+            }
+            match policy.retention {
+                Some(ref retention) => self.server.set_retention(token, retention.clone()),
+                None => self.server.clear_retention(token),
+            }
+        }
+        self.rescan = true;
+    }
+
+    /// Queues every stream the policy no longer allows, extracts what it
+    /// can, and unregisters the tokens it no longer has that are empty.
+    fn sweep(&mut self) {
+        let mut doomed = vec![];
+        for (token, finder) in self.server.finders() {
+            for id in finder.keys() {
+                if !self.allows(token, id) {
+                    doomed.push((token.to_vec(), id.clone()));
+                }
+            }
+        }
+        self.pending = doomed;
+        self.rescan = false;
+        self.retry();
+        let emptied: Vec<_> = self.server
+                                  .finders()
+                                  .into_iter()
+                                  .filter(|&(token, ref finder)| {
+                                      finder.is_empty() &&
+                                      !self.current.policy.tokens.contains_key(token)
+                                  })
+                                  .map(|(token, _)| token.to_vec())
+                                  .collect();
+        for token in emptied {
+            self.server.remove_token(&token);
+        }
+    }
+
+    /// Tries to extract each pending stream, keeping those still busy, and
+    /// unregisters the tokens the policy no longer has that this empties.
+    fn retry(&mut self) {
+        let pending = ::std::mem::replace(&mut self.pending, vec![]);
+        let mut settled = BTreeSet::new();
+        for (token, id) in pending {
+            if self.server.permission(&token).is_none() {
+                continue;
+            }
+            match FinderExt::extract(self.server.add_token(&token), &id) {
+                Some(Ok(extract)) => {
+                    settled.insert(token.clone());
+                    self.removed.push((token, id, extract));
+                }
+                Some(Err(_)) => self.pending.push((token, id)),
+                None => {
+                    settled.insert(token);
+                }
+            }
+        }
+        for token in settled {
+            if !self.current.policy.tokens.contains_key(&token) &&
+               self.server.add_token(&token).is_empty() {
+                self.server.remove_token(&token);
+            }
+        }
+    }
+
+    fn allows(&self, token: &[u8], id: &[u8]) -> bool {
+        match self.current.policy.tokens.get(token) {
+            None => false,
+            Some(&TokenPolicy { ids: None, .. }) => true,
+            Some(&TokenPolicy { ids: Some(ref ids), .. }) => ids.contains(id),
+        }
+    }
+
+    fn check(&self, token: &[u8], id: &[u8]) -> Result<(), ConsumeError<::Void, S::PushErr>> {
+        if !self.current.policy.tokens.contains_key(token) {
+            Err(ConsumeError::Auth(AuthError::InvalidToken))
+        } else if !self.allows(token, id) {
+            Err(ConsumeError::MissingId)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: Stream> Server for Reloading<S> {
+    type Stream = S;
+    type AuthErr = ::Void;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.refresh();
+        if !self.current.policy.tokens.contains_key(token) {
+            return Err(AuthError::InvalidToken);
+        }
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = Delivery;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        self.refresh();
+        if let Err(e) = self.check(token, id) {
+            return (Err(e), Pressure::None);
+        }
+        self.server.consume_parts_pressed(token, id, timestamp, payload, spans)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        self.refresh();
+        try!(self.check(token, id));
+        self.server.backfill_parts(token, id, timestamp, payload)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        match self.check(token, id) {
+            Err(ConsumeError::Auth(e)) => DryRunOutcome::Unauthorized(e),
+            Err(_) => DryRunOutcome::MissingId,
+            Ok(()) => self.server.dry_run_parts(token, id, timestamp, payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use config::ServerConfig;
+    use server::{AuthError, ConsumeError, RetentionAction};
+    use session::Error;
+    use stream::Guarded;
+    use test_support::{frame, stream};
+    use Session;
+
+    fn ids(ids: &[&[u8]]) -> Option<BTreeSet<Vec<u8>>> {
+        Some(ids.iter().map(|id| id.to_vec()).collect())
+    }
+
+    fn admin(tokens: &[(&[u8], Option<BTreeSet<Vec<u8>>>)]) -> ServerAdmin {
+        let mut policy = Policy::default();
+        for &(token, ref ids) in tokens {
+            policy.tokens.insert(token.to_vec(),
+                                 TokenPolicy { ids: ids.clone(), ..TokenPolicy::default() });
+        }
+        ServerAdmin::new(ServerConfig::default(), policy).unwrap()
+    }
+
+    fn server(admin: &ServerAdmin) -> Reloading<Guarded<stream::Ok>> {
+        let mut server = TokenServer::new();
+        server.set_fallback_factory(|_| Guarded::new(stream::Ok));
+        admin.server(server)
+    }
+
+    /// Runs a session over `frames`, calling `between` before each with
+    /// its index, and sorts the results into what was stored and how the
+    /// rest failed.
+    fn run<F>(server: &mut Reloading<Guarded<stream::Ok>>,
+              frames: &[(&[u8], &[u8])],
+              mut between: F)
+              -> Vec<&'static str>
+        where F: FnMut(usize)
+    {
+        let input: Vec<u8> = frames.iter()
+                                   .enumerate()
+                                   .flat_map(|(i, &(token, id))| frame(token, id, i as u64, b"x"))
+                                   .collect();
+        let mut session = Session::new(server, &input[..]);
+        let mut outcomes = vec![];
+        for i in 0..frames.len() {
+            between(i);
+            outcomes.push(match session.next().unwrap() {
+                Ok(_) => "ok",
+                Err(Error::Consume(ConsumeError::MissingId)) => "missing",
+                Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken))) => "token",
+                Err(e) => panic!("{:?}", e),
+            });
+        }
+        assert!(session.next().is_none());
+        outcomes
+    }
+
+    #[test]
+    fn changes_take_effect_at_the_publish() {
+        let admin = admin(&[(b"a", None)]);
+        let mut server = server(&admin);
+        let frames: &[(&[u8], &[u8])] = &[(b"a", b"x"), (b"b", b"y"), (b"b", b"y"), (b"a", b"x"),
+                                          (b"a", b"z"), (b"a", b"x")];
+        let outcomes = run(&mut server, frames, |i| {
+            match i {
+                2 => assert_eq!(Ok(1), admin.add_token(b"b", TokenPolicy::default())),
+                3 => {
+                    admin.modify(|_, policy| {
+                             policy.tokens.get_mut(&b"a"[..]).unwrap().ids = ids(&[b"x"]);
+                             policy.tokens.remove(&b"b"[..]);
+                         })
+                         .unwrap();
+                }
+                _ => {}
+            }
+        });
+        assert_eq!(vec!["ok", "token", "ok", "ok", "missing", "ok"], outcomes);
+        assert_eq!(2, server.epoch());
+        let removed: Vec<_> = server.take_removed().into_iter().map(|(t, i, _)| (t, i)).collect();
+        assert_eq!(vec![(b"b".to_vec(), b"y".to_vec())], removed);
+        assert_eq!(None, server.get_ref().permission(b"b"));
+    }
+
+    #[test]
+    fn busy_stream_is_removed_once_idle() {
+        let admin = admin(&[(b"a", ids(&[b"x", b"y"]))]);
+        let mut server = server(&admin);
+        server.consume_parts(b"a", b"x", Duration::from_millis(0), b"x").unwrap();
+        let intent = server.get_ref().finders()[0].1[&b"x"[..]].begin_push_intent();
+        let mut intent = Some(intent);
+
+        let frames: &[(&[u8], &[u8])] = &[(b"a", b"x"), (b"a", b"x"), (b"a", b"y"), (b"a", b"x")];
+        let outcomes = run(&mut server, frames, |i| {
+            if i == 1 {
+                admin.remove_id(b"a", b"x").unwrap();
+            }
+            if i == 3 {
+                intent.take().unwrap().end();
+            }
+        });
+        // Refused from the publish on, while it could not be extracted.
+        assert_eq!(vec!["ok", "missing", "ok", "missing"], outcomes);
+        assert_eq!(0, server.pending_removals());
+        assert_eq!(1, server.take_removed().len());
+        assert!(!server.get_ref().finders()[0].1.contains_key(&b"x"[..]));
+    }
+
+    #[test]
+    fn readmitted_stream_leaves_the_queue() {
+        let admin = admin(&[(b"a", ids(&[b"x", b"y"]))]);
+        let mut server = server(&admin);
+        server.consume_parts(b"a", b"x", Duration::from_millis(0), b"x").unwrap();
+        let intent = server.get_ref().finders()[0].1[&b"x"[..]].begin_push_intent();
+
+        admin.remove_id(b"a", b"x").unwrap();
+        assert!(server.consume_parts(b"a", b"y", Duration::from_millis(1), b"y").is_ok());
+        assert_eq!(1, server.pending_removals());
+        admin.add_token(b"a", TokenPolicy { ids: ids(&[b"x", b"y"]), ..TokenPolicy::default() })
+             .unwrap();
+        assert!(server.consume_parts(b"a", b"y", Duration::from_millis(2), b"y").is_ok());
+        assert_eq!(0, server.pending_removals());
+        intent.end();
+        assert!(server.consume_parts(b"a", b"x", Duration::from_millis(3), b"x").is_ok());
+        assert!(server.take_removed().is_empty());
+    }
+
+    #[test]
+    fn invalid_publish_changes_nothing() {
+        let admin = admin(&[(b"a", None)]);
+        let mut server = server(&admin);
+        let frames: &[(&[u8], &[u8])] = &[(b"a", b"x"), (b"a", b"x"), (b"b", b"x")];
+        let outcomes = run(&mut server, frames, |i| {
+            if i == 1 {
+                let mut policy = Policy::default();
+                policy.tokens.insert(b"b".to_vec(),
+                                     TokenPolicy {
+                                         stream_cap: Some((0, CapPolicy::Reject)),
+                                         ..TokenPolicy::default()
+                                     });
+                let config = ServerConfig {
+                    drain_budget: Duration::from_millis(0),
+                    drain_per_stream: Duration::from_millis(0),
+                    ..ServerConfig::default()
+                };
+                assert_eq!(Err(vec![ConfigError::ZeroDrainBudget,
+                                    ConfigError::ZeroStreamCap { token: b"b".to_vec() }]),
+                           admin.publish(config, policy));
+            }
+        });
+        assert_eq!(vec!["ok", "ok", "token"], outcomes);
+        assert_eq!(0, server.epoch());
+        assert_eq!(ServerConfig::default(), *admin.current().config.get());
+    }
+    #[test]
+    fn retention_follows_publishes() {
+        let admin = admin(&[(b"a", None)]);
+        let mut server = server(&admin);
+        server.get_mut().set_clock(SharedClock::new(Duration::from_secs(100)));
+        let old = Duration::from_secs(10);
+        assert!(server.consume_parts(b"a", b"x", old, b"").is_ok());
+        let retention = RetentionPolicy {
+            max_age: Duration::from_secs(60),
+            action: RetentionAction::Reject,
+            in_backfill: false,
+        };
+        admin.add_token(b"a", TokenPolicy { retention: Some(retention), ..TokenPolicy::default() })
+             .unwrap();
+        assert_match!(Err(ConsumeError::ExpiredPayload { .. }),
+                      server.consume_parts(b"a", b"x", old, b""));
+        assert!(server.consume_parts(b"a", b"x", Duration::from_secs(50), b"").is_ok());
+        admin.add_token(b"a", TokenPolicy::default()).unwrap();
+        assert!(server.consume_parts(b"a", b"x", old, b"").is_ok());
+    }
+}
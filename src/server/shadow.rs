@@ -0,0 +1,442 @@
+//! Running a second server on the same traffic for comparison only, to
+//! check a migration against live messages before switching over.
+
+use std::collections::vec_deque;
+use std::collections::{BTreeMap, VecDeque};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use {Clock, Server};
+use stream::Pressure;
+use trace::Spans;
+use super::protection::fingerprint;
+use super::{AuthError, AuthResult, ConsumeError, ConsumeResult, Consumed, DryRunOutcome};
+
+/// What became of a message, alike for servers whose error types differ.
+/// Codes are stable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OutcomeCode {
+    Stored,
+    InvalidToken,
+    AuthFailed,
+    AuthPending,
+    MissingId,
+    Rejected,
+    StreamCapExceeded,
+    PushFailed,
+    PartialFailure,
+    /// The server panicked.
+    Panicked,
+    AuthThrottled,
+}
+
+impl OutcomeCode {
+    pub fn of<A, P, K>(result: &ConsumeResult<A, P, K>) -> Self {
+        match *result {
+            Ok(_) => OutcomeCode::Stored,
+            Err(ConsumeError::Auth(AuthError::InvalidToken)) => OutcomeCode::InvalidToken,
+            Err(ConsumeError::Auth(AuthError::Other(_))) => OutcomeCode::AuthFailed,
+            Err(ConsumeError::Auth(AuthError::Pending(_))) => OutcomeCode::AuthPending,
+            Err(ConsumeError::Auth(AuthError::Throttled)) => OutcomeCode::AuthThrottled,
+            Err(ConsumeError::MissingId) => OutcomeCode::MissingId,
+            Err(ConsumeError::Rejected(_)) |
+            Err(ConsumeError::ExpiredPayload { .. }) => OutcomeCode::Rejected,
+            Err(ConsumeError::StreamCapExceeded { .. }) => OutcomeCode::StreamCapExceeded,
+            Err(ConsumeError::Push(_)) => OutcomeCode::PushFailed,
+            Err(ConsumeError::GroupPartialFailure(_)) => OutcomeCode::PartialFailure,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            OutcomeCode::Stored => 0,
+            OutcomeCode::InvalidToken => 1,
+            OutcomeCode::AuthFailed => 2,
+            OutcomeCode::AuthPending => 3,
+            OutcomeCode::MissingId => 4,
+            OutcomeCode::Rejected => 5,
+            OutcomeCode::StreamCapExceeded => 6,
+            OutcomeCode::PushFailed => 7,
+            OutcomeCode::PartialFailure => 8,
+            OutcomeCode::Panicked => 9,
+            OutcomeCode::AuthThrottled => 10,
+        }
+    }
+}
+
+/// A message on which the servers disagreed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// As `protection::fingerprint` takes it of the token alone.
+    pub token_fingerprint: u64,
+    pub id: Vec<u8>,
+    /// The message's own.
+    pub timestamp: Duration,
+    pub primary: OutcomeCode,
+    pub shadow: OutcomeCode,
+    /// The clock's time of the comparison.
+    pub at: Duration,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShadowReport {
+    /// Messages the primary consumed or backfilled.
+    pub messages: u64,
+    pub agreed: u64,
+    /// By primary and shadow outcome, including divergences since dropped
+    /// from the log.
+    pub diverged: BTreeMap<(OutcomeCode, OutcomeCode), u64>,
+    /// Messages the shadow took longer than the budget on, not compared.
+    pub over_budget: u64,
+    /// Messages not fed to the shadow because it overran on the one before.
+    pub skipped: u64,
+    pub shadow_panics: u64,
+    /// The shadow's time on the messages it was fed, by the clock.
+    pub shadow_time: Duration,
+    pub max_shadow_latency: Duration,
+}
+
+impl ShadowReport {
+    pub fn diverged_total(&self) -> u64 {
+        self.diverged.values().sum()
+    }
+}
+
+/// Consumes with `primary`, whose results it returns as they are, then
+/// feeds each message to `shadow` and compares how the two fared. Nothing
+/// the shadow does, not even panicking, reaches the primary or the caller.
+///
+/// The shadow runs after the primary on the same thread, so it adds its
+/// latency to each message. With a budget, a message the shadow overruns
+/// it on goes uncompared, and the shadow sits out the next message.
+pub struct Shadowed<C, P, S> {
+    primary: P,
+    shadow: S,
+    clock: C,
+    budget: Option<Duration>,
+    overran: bool,
+    capacity: usize,
+    divergences: VecDeque<Divergence>,
+    report: ShadowReport,
+}
+
+impl<C: Clock, P: Server, S: Server> Shadowed<C, P, S> {
+    /// Keeps the last 256 divergences.
+    pub fn new(primary: P, shadow: S, clock: C) -> Self {
+        Shadowed::with_capacity(primary, shadow, clock, 256)
+    }
+
+    pub fn with_capacity(primary: P, shadow: S, clock: C, capacity: usize) -> Self {
+        Shadowed {
+            primary: primary,
+            shadow: shadow,
+            clock: clock,
+            budget: None,
+            overran: false,
+            capacity: capacity,
+            divergences: VecDeque::with_capacity(capacity),
+            report: ShadowReport::default(),
+        }
+    }
+
+    /// How long the shadow may take on a message; unlimited by default.
+    pub fn set_budget(&mut self, budget: Option<Duration>) {
+        self.budget = budget;
+    }
+
+    /// The newest divergences, oldest first.
+    pub fn divergences(&self) -> vec_deque::Iter<Divergence> {
+        self.divergences.iter()
+    }
+
+    pub fn report(&self) -> ShadowReport {
+        self.report.clone()
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &S {
+        &self.shadow
+    }
+
+    /// The primary, then the shadow.
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.shadow)
+    }
+
+    /// Runs `f` on the shadow, timing it and comparing its outcome to the
+    /// primary's.
+    fn compare<F>(&mut self,
+                  token: &[u8],
+                  id: &[u8],
+                  timestamp: Duration,
+                  primary: OutcomeCode,
+                  f: F)
+        where F: FnOnce(&mut S) -> Consumed<S>
+    {
+        self.report.messages += 1;
+        if self.overran {
+            self.overran = false;
+            self.report.skipped += 1;
+            return;
+        }
+        let start = self.clock.now();
+        let shadow = {
+            let server = &mut self.shadow;
+            match panic::catch_unwind(AssertUnwindSafe(|| f(server))) {
+                Ok(result) => OutcomeCode::of(&result),
+                Err(_) => OutcomeCode::Panicked,
+            }
+        };
+        let end = self.clock.now();
+        let latency = if end > start { end - start } else { Duration::from_millis(0) };
+        self.report.shadow_time += latency;
+        if latency > self.report.max_shadow_latency {
+            self.report.max_shadow_latency = latency;
+        }
+        if shadow == OutcomeCode::Panicked {
+            self.report.shadow_panics += 1;
+        }
+        if self.budget.map_or(false, |budget| latency > budget) {
+            self.overran = true;
+            self.report.over_budget += 1;
+            return;
+        }
+        if shadow == primary {
+            self.report.agreed += 1;
+            return;
+        }
+        *self.report.diverged.entry((primary, shadow)).or_insert(0) += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.divergences.len() == self.capacity {
+            self.divergences.pop_front();
+        }
+        self.divergences.push_back(Divergence {
+            token_fingerprint: fingerprint(&[token]),
+            id: id.to_vec(),
+            timestamp: timestamp,
+            primary: primary,
+            shadow: shadow,
+            at: end,
+        });
+    }
+}
+
+impl<C: Clock, P: Server, S: Server> Server for Shadowed<C, P, S> {
+    type Stream = P::Stream;
+    type AuthErr = P::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.primary.auth(token)
+    }
+
+    type ConsumeOk = P::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    /// Traces the primary only.
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        let (result, pressure) =
+            self.primary.consume_parts_pressed(token, id, timestamp, payload, spans);
+        let code = OutcomeCode::of(&result);
+        self.compare(token, id, timestamp, code, |shadow| {
+            shadow.consume_parts(token, id, timestamp, payload)
+        });
+        (result, pressure)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        let result = self.primary.backfill_parts(token, id, timestamp, payload);
+        let code = OutcomeCode::of(&result);
+        self.compare(token, id, timestamp, code, |shadow| {
+            shadow.backfill_parts(token, id, timestamp, payload)
+        });
+        result
+    }
+
+    fn pressure(&mut self, token: &[u8], id: &[u8]) -> Pressure {
+        self.primary.pressure(token, id)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.primary.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use server::Finder;
+    use server::protection::fingerprint;
+    use test_support::server as mocks;
+    use test_support::stream;
+    use test_support::stream::ScriptedStream;
+    use Stream;
+
+    /// Stores what its finder has streams for, panics on "boom", and takes
+    /// a second on IDs starting with "slow".
+    struct Shadow<'c> {
+        finder: Finder<stream::Ok>,
+        clock: &'c ManualClock,
+    }
+
+    impl<'c> Server for Shadow<'c> {
+        type Stream = stream::Ok;
+        type AuthErr = ();
+        fn auth(&mut self, _: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+            Ok(&mut self.finder)
+        }
+
+        type ConsumeOk = ();
+        fn consume_parts_traced(&mut self,
+                                _: &[u8],
+                                id: &[u8],
+                                timestamp: Duration,
+                                payload: &[u8],
+                                _: &mut Spans)
+                                -> Consumed<Self> {
+            if id == b"boom" {
+                panic!("shadow bug");
+            }
+            if id.starts_with(b"slow") {
+                self.clock.set(self.clock.now() + Duration::from_secs(1));
+            }
+            self.finder
+                .get_mut(id)
+                .ok_or(ConsumeError::MissingId)
+                .and_then(|stream| stream.push(timestamp, payload).map_err(ConsumeError::Push))
+        }
+    }
+
+    const IDS: &'static [&'static [u8]] = &[b"a", b"b", b"c", b"d", b"boom", b"slow1", b"slow2",
+                                             b"slow3", b"a", b"a"];
+
+    fn primary() -> mocks::Ok<ScriptedStream<(), ()>> {
+        let mut finder = Finder::new();
+        for &id in &[&b"a"[..], b"b", b"boom", b"slow1", b"slow2", b"slow3"] {
+            finder.insert(id.to_vec(), ScriptedStream::default());
+        }
+        finder.insert(b"a".to_vec(), ScriptedStream::new(vec![Ok(()), Err(())]));
+        mocks::Ok(finder)
+    }
+
+    fn shadow(clock: &ManualClock) -> Shadow {
+        let finder = [&b"a"[..], b"c", b"slow1", b"slow2", b"slow3"]
+                         .iter()
+                         .map(|id| (id.to_vec(), stream::Ok))
+                         .collect();
+        Shadow {
+            finder: finder,
+            clock: clock,
+        }
+    }
+
+    /// Consumes `IDS` in order, returning each result as debugged and what
+    /// was pushed to each of the primary's streams.
+    fn run<S>(server: &mut S) -> Vec<String>
+        where S: Server<Stream = ScriptedStream<(), ()>>,
+              S::AuthErr: fmt::Debug,
+              S::ConsumeOk: fmt::Debug
+    {
+        let mut results: Vec<_> = IDS.iter()
+                                     .enumerate()
+                                     .map(|(i, id)| {
+                                         let ts = Duration::from_millis(i as u64);
+                                         format!("{:?}", server.consume_parts(b"t", id, ts, b"x"))
+                                     })
+                                     .collect();
+        let finder = server.auth(b"t").ok().unwrap();
+        for id in IDS {
+            results.push(format!("{:?}", finder.get(*id).map(ScriptedStream::pushed)));
+        }
+        results
+    }
+
+    #[test]
+    fn compares_without_touching_the_primary() {
+        let clock = ManualClock::new(Duration::from_secs(0));
+        let mut shadowed = Shadowed::with_capacity(primary(), shadow(&clock), &clock, 2);
+        shadowed.set_budget(Some(Duration::from_millis(500)));
+        assert_eq!(run(&mut primary()), run(&mut shadowed));
+
+        let code = |(primary, shadow)| ((primary, shadow), 1);
+        let stored = OutcomeCode::Stored;
+        let missing = OutcomeCode::MissingId;
+        assert_eq!(ShadowReport {
+                       messages: 10,
+                       agreed: 3,
+                       diverged: vec![code((stored, missing)),
+                                      code((stored, OutcomeCode::Panicked)),
+                                      code((missing, stored))]
+                                     .into_iter()
+                                     .collect(),
+                       over_budget: 2,
+                       skipped: 2,
+                       shadow_panics: 1,
+                       shadow_time: Duration::from_secs(2),
+                       max_shadow_latency: Duration::from_secs(1),
+                   },
+                   shadowed.report());
+        assert_eq!(3, shadowed.report().diverged_total());
+
+        // The push that failed on the primary came right after the shadow
+        // overran, so went uncompared.
+        let divergence = |id: &[u8], ts, primary, shadow| {
+            Divergence {
+                token_fingerprint: fingerprint(&[b"t"]),
+                id: id.to_vec(),
+                timestamp: Duration::from_millis(ts),
+                primary: primary,
+                shadow: shadow,
+                at: Duration::from_secs(0),
+            }
+        };
+        assert_eq!(vec![divergence(b"c", 2, missing, stored),
+                        divergence(b"boom", 4, stored, OutcomeCode::Panicked)],
+                   shadowed.divergences().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let clock = ManualClock::new(Duration::from_secs(0));
+        let mut shadowed = Shadowed::new(primary(), shadow(&clock), &clock);
+        run(&mut shadowed);
+        let report = shadowed.report();
+        assert_eq!((0, 0), (report.over_budget, report.skipped));
+        assert_eq!(Duration::from_secs(3), report.shadow_time);
+        assert_eq!(10, report.agreed + report.diverged_total());
+        let logged: Vec<_> = shadowed.divergences().map(|d| d.id.clone()).collect();
+        assert_eq!(vec![b"b".to_vec(), b"c".to_vec(), b"boom".to_vec(), b"a".to_vec()], logged);
+    }
+}
@@ -0,0 +1,282 @@
+//! Exporting every stream of a live server without extracting any. Each
+//! stream that can be snapshotted is written in an envelope labeled with
+//! its ID and carrying its token's fingerprint as `token`, as a reaped
+//! extract would be; `read_snapshots` reads them back.
+
+use std::io;
+use std::io::prelude::*;
+
+use stream::{ExtractDescriptor, ExtractEnvelope, PortableError, PortableExtract, PortableReader,
+             SnapshotStream};
+use util::millis;
+use {stream, Server, Stream};
+use super::protection::fingerprint;
+use super::{HighWaterMark, Reaper, TokenServer};
+
+/// One stream's part in `SnapshotServer::visit_snapshots`.
+pub enum Visited<'b> {
+    /// The snapshot's body and its descriptor.
+    Taken(&'b [u8], ExtractDescriptor),
+    /// The stream could not be snapshotted now.
+    Skipped,
+    Failed(io::Error),
+}
+
+#[derive(Debug, Default)]
+pub struct SnapshotReport {
+    /// Streams written.
+    pub written: u64,
+    /// Bytes written, envelopes and all.
+    pub bytes: u64,
+    pub skipped: u64,
+    /// (token, ID, error) for each stream whose snapshot failed.
+    pub failed: Vec<(Vec<u8>, Vec<u8>, io::Error)>,
+    /// The error writing to the output, after which nothing more was.
+    pub error: Option<io::Error>,
+}
+
+/// A server whose streams can be snapshotted through a shared borrow.
+pub trait SnapshotServer {
+    /// Calls `visit` with every stream's token, ID and snapshot, in token
+    /// and then ID order. Wrappers add their metadata for the stream to
+    /// each descriptor.
+    fn visit_snapshots(&self, visit: &mut FnMut(&[u8], &[u8], Visited));
+
+    /// Writes a snapshot of every stream that can be snapshotted, skipping
+    /// the rest, and leaves them all as they were.
+    fn snapshot_all<W: Write>(&self, w: W) -> SnapshotReport
+        where Self: Sized
+    {
+        let mut w = w;
+        let mut report = SnapshotReport::default();
+        self.visit_snapshots(&mut |token, id, visited| {
+            if report.error.is_some() {
+                return;
+            }
+            match visited {
+                Visited::Taken(body, mut descriptor) => {
+                    descriptor.metadata.push(("token".to_owned(), fingerprint(&[token])));
+                    let body = Body {
+                        body: body,
+                        descriptor: &descriptor,
+                    };
+                    match stream::write_envelope(&mut w, id, &descriptor, &body) {
+                        Ok(bytes) => {
+                            report.written += 1;
+                            report.bytes += bytes;
+                        }
+                        Err(e) => report.error = Some(e),
+                    }
+                }
+                Visited::Skipped => report.skipped += 1,
+                Visited::Failed(e) => report.failed.push((token.to_owned(), id.to_owned(), e)),
+            }
+        });
+        report
+    }
+}
+
+/// A snapshot buffered to be put in its envelope.
+struct Body<'b> {
+    body: &'b [u8],
+    descriptor: &'b ExtractDescriptor,
+}
+
+impl<'b> ExtractEnvelope for Body<'b> {
+    fn describe(&self) -> ExtractDescriptor {
+        self.descriptor.clone()
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        try!(w.write_all(self.body));
+        Ok(self.body.len() as u64)
+    }
+}
+
+/// The snapshots in `r`, in the order they were written.
+pub fn read_snapshots<R: Read>(r: R) -> Result<Vec<PortableExtract>, PortableError> {
+    PortableReader::new(r).collect()
+}
+
+impl<S: Stream + SnapshotStream> SnapshotServer for TokenServer<S> {
+    fn visit_snapshots(&self, visit: &mut FnMut(&[u8], &[u8], Visited)) {
+        let mut buffer = vec![];
+        for (token, finder) in self.finders() {
+            let mut ids: Vec<_> = finder.keys().collect();
+            ids.sort();
+            for id in ids {
+                let stream = &finder[id];
+                if !stream.can_snapshot() {
+                    visit(token, id, Visited::Skipped);
+                    continue;
+                }
+                buffer.clear();
+                match stream.snapshot(&mut buffer) {
+                    Ok(info) => visit(token, id, Visited::Taken(&buffer, info.descriptor)),
+                    Err(e) => visit(token, id, Visited::Failed(e)),
+                }
+            }
+        }
+    }
+}
+
+/// With the stream's mark, if it has one, as `high_water_mark` in
+/// milliseconds.
+impl<S: Server + SnapshotServer> SnapshotServer for HighWaterMark<S> {
+    fn visit_snapshots(&self, visit: &mut FnMut(&[u8], &[u8], Visited)) {
+        self.get_ref().visit_snapshots(&mut |token, id, visited| {
+            let visited = match visited {
+                Visited::Taken(body, mut descriptor) => {
+                    if let Some(mark) = self.mark(token, id) {
+                        descriptor.metadata.push(("high_water_mark".to_owned(), millis(mark)));
+                    }
+                    Visited::Taken(body, descriptor)
+                }
+                visited => visited,
+            };
+            visit(token, id, visited)
+        })
+    }
+}
+
+/// With when the stream was last pushed to, if it has been, as
+/// `last_seen` in milliseconds.
+impl<S: Server + SnapshotServer> SnapshotServer for Reaper<S> {
+    fn visit_snapshots(&self, visit: &mut FnMut(&[u8], &[u8], Visited)) {
+        self.get_ref().visit_snapshots(&mut |token, id, visited| {
+            let visited = match visited {
+                Visited::Taken(body, mut descriptor) => {
+                    if let Some(last) = self.last_seen(token, id) {
+                        descriptor.metadata.push(("last_seen".to_owned(), millis(last)));
+                    }
+                    Visited::Taken(body, descriptor)
+                }
+                visited => visited,
+            };
+            visit(token, id, visited)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use server::{HighWaterMark, TokenServer};
+    use stream::{FileStream, PortableBody, PreviewMode, SnapshotInfo, TextLog};
+    use {Server, Stream};
+
+    enum Mixed {
+        Records(FileStream<Vec<u8>>),
+        Text(TextLog<Vec<u8>>),
+        Opaque,
+    }
+
+    impl Stream for Mixed {
+        type PushErr = io::Error;
+        fn push(&mut self, timestamp: Duration, payload: &[u8]) -> io::Result<()> {
+            match *self {
+                Mixed::Records(ref mut stream) => stream.push(timestamp, payload),
+                Mixed::Text(ref mut stream) => stream.push(timestamp, payload),
+                Mixed::Opaque => Ok(()),
+            }
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(())
+        }
+    }
+
+    impl SnapshotStream for Mixed {
+        fn can_snapshot(&self) -> bool {
+            match *self {
+                Mixed::Opaque => false,
+                _ => true,
+            }
+        }
+
+        fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+            match *self {
+                Mixed::Records(ref stream) => stream.snapshot(w),
+                Mixed::Text(ref stream) => stream.snapshot(w),
+                Mixed::Opaque => unreachable!(),
+            }
+        }
+    }
+
+    fn server() -> HighWaterMark<TokenServer<Mixed>> {
+        let mut server = TokenServer::new();
+        for token in &[b"b", b"a"] {
+            server.add_token(&token[..]);
+            server.set_factory(&token[..], |id| {
+                match id[0] {
+                    b'r' => Mixed::Records(FileStream::new(vec![])),
+                    b't' => Mixed::Text(TextLog::new(vec![], PreviewMode::None)),
+                    _ => Mixed::Opaque,
+                }
+            });
+        }
+        HighWaterMark::new(server)
+    }
+
+    fn consume(server: &mut HighWaterMark<TokenServer<Mixed>>,
+               token: &[u8],
+               id: &[u8],
+               millis: u64) {
+        let payload = format!("{}", millis);
+        server.consume_parts(token, id, Duration::from_millis(millis), payload.as_bytes())
+              .unwrap();
+    }
+
+    fn records(extract: &PortableExtract) -> Vec<Duration> {
+        match extract.body {
+            PortableBody::Records(ref records) => records.iter().map(|r| r.0).collect(),
+            ref body => panic!("not records: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn snapshots_between_consumes() {
+        let mut server = server();
+        consume(&mut server, b"b", b"records", 10);
+        consume(&mut server, b"a", b"records", 20);
+        consume(&mut server, b"a", b"text", 30);
+        consume(&mut server, b"a", b"opaque", 40);
+        let mut first = vec![];
+        let report = server.snapshot_all(&mut first);
+        assert_eq!((3, 1, first.len() as u64), (report.written, report.skipped, report.bytes));
+        assert!(report.failed.is_empty() && report.error.is_none());
+
+        consume(&mut server, b"a", b"records", 50);
+        consume(&mut server, b"b", b"opaque", 60);
+        let mut second = vec![];
+        let report = server.snapshot_all(&mut second);
+        assert_eq!((3, 2), (report.written, report.skipped));
+
+        let first = read_snapshots(&first[..]).unwrap();
+        let labels: Vec<_> = first.iter()
+                                  .map(|s| (s.descriptor.get("token"), &s.label[..]))
+                                  .collect();
+        let (a, b) = (Some(fingerprint(&[b"a"])), Some(fingerprint(&[b"b"])));
+        assert_eq!(vec![(a, &b"records"[..]), (a, b"text"), (b, b"records")], labels);
+        assert_eq!(vec![Duration::from_millis(20)], records(&first[0]));
+        assert_eq!(Some(20), first[0].descriptor.get("high_water_mark"));
+        assert_eq!(Some(1), first[1].descriptor.records);
+
+        let second = read_snapshots(&second[..]).unwrap();
+        assert_eq!(vec![Duration::from_millis(20), Duration::from_millis(50)],
+                   records(&second[0]));
+        assert_eq!(Some(50), second[0].descriptor.get("high_water_mark"));
+        assert_eq!(first[1..], second[1..]);
+
+        // Nothing was taken.
+        consume(&mut server, b"a", b"records", 70);
+        match server.get_ref().finders()[0].1[&b"records"[..]] {
+            Mixed::Records(ref stream) => assert_eq!(3 * (13 + 2), stream.get_ref().len()),
+            _ => unreachable!(),
+        }
+    }
+}
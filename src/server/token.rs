@@ -0,0 +1,1507 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use callback::{Local, Sendable};
+use clock::SystemClock;
+use stream::{FoundResult, Pressure, TransactionalStream};
+use trace::Spans;
+use {stream, Clock, Stream};
+use super::{AuthError, AuthResult, ConsumeError, ConsumeResult, Consumed, DryRunOutcome, Finder,
+            MemberOutcome, Server};
+
+/// Creates the stream for an ID seen for the first time.
+pub type Factory<S> = Box<FnMut(&[u8]) -> S>;
+
+/// Receives the token, ID, and extraction result of each evicted stream.
+pub type EvictionCallback<S> = Box<FnMut(&[u8], &[u8], FoundResult<S>)>;
+
+/// Called after a successful push with the ID, timestamp, and payload
+/// length.
+pub type ConsumeHook = Box<FnMut(&[u8], Duration, usize)>;
+
+/// `Factory`, for a `Sendable` server.
+pub type SendFactory<S> = Box<FnMut(&[u8]) -> S + Send>;
+
+/// `EvictionCallback`, for a `Sendable` server.
+pub type SendEvictionCallback<S> = Box<FnMut(&[u8], &[u8], FoundResult<S>) + Send>;
+
+/// `ConsumeHook`, for a `Sendable` server.
+pub type SendConsumeHook = Box<FnMut(&[u8], Duration, usize) + Send>;
+
+/// The callbacks a `TokenServer` keeps, as `Local` or `Sendable` makes
+/// them.
+pub trait TokenCallbacks<S: Stream> {
+    type Factory: ?Sized + FnMut(&[u8]) -> S;
+    type Eviction: ?Sized + FnMut(&[u8], &[u8], FoundResult<S>);
+    type Hook: ?Sized + FnMut(&[u8], Duration, usize);
+}
+
+impl<S: Stream> TokenCallbacks<S> for Local {
+    type Factory = FnMut(&[u8]) -> S;
+    type Eviction = FnMut(&[u8], &[u8], FoundResult<S>);
+    type Hook = FnMut(&[u8], Duration, usize);
+}
+
+impl<S: Stream> TokenCallbacks<S> for Sendable {
+    type Factory = FnMut(&[u8]) -> S + Send;
+    type Eviction = FnMut(&[u8], &[u8], FoundResult<S>) + Send;
+    type Hook = FnMut(&[u8], Duration, usize) + Send;
+}
+
+/// Each member's outcome of committing a group message, or the error of the
+/// member that failed to prepare it, with every other member rolled back.
+type Transacted<P> = Result<Vec<(Vec<u8>, MemberOutcome<P>)>, P>;
+
+/// Delivers a group message to members that all have a stream.
+type Transact<S> = fn(&mut Finder<S>, &[Vec<u8>], Duration, &[u8])
+                      -> Transacted<<S as Stream>::PushErr>;
+
+fn transact<S: TransactionalStream>(finder: &mut Finder<S>,
+                                    members: &[Vec<u8>],
+                                    timestamp: Duration,
+                                    payload: &[u8])
+                                    -> Transacted<S::PushErr> {
+    let mut prepared = Vec::with_capacity(members.len());
+    for member in members {
+        match finder.get_mut(member).unwrap().prepare(timestamp, payload) {
+            Ok(p) => prepared.push(p),
+            Err(e) => {
+                for (member, p) in members.iter().zip(prepared) {
+                    finder.get_mut(member).unwrap().rollback(p);
+                }
+                return Err(e);
+            }
+        }
+    }
+    let mut outcomes = Vec::with_capacity(members.len());
+    let mut failed = false;
+    for (member, p) in members.iter().zip(prepared) {
+        let stream = finder.get_mut(member).unwrap();
+        let outcome = if failed {
+            stream.rollback(p);
+            MemberOutcome::RolledBack
+        } else {
+            match stream.commit(p) {
+                Ok(()) => MemberOutcome::Committed,
+                Err(e) => {
+                    failed = true;
+                    MemberOutcome::Failed(e)
+                }
+            }
+        };
+        outcomes.push((member.clone(), outcome));
+    }
+    Ok(outcomes)
+}
+
+/// Which IDs a hook is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdPattern {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl IdPattern {
+    pub fn matches(&self, id: &[u8]) -> bool {
+        match *self {
+            IdPattern::Exact(ref exact) => id == &exact[..],
+            IdPattern::Prefix(ref prefix) => id.starts_with(prefix),
+        }
+    }
+}
+
+/// Removes a hook with `TokenServer::remove_hook`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HookHandle(u64);
+
+struct Hook<H: ?Sized> {
+    handle: HookHandle,
+    token: Vec<u8>,
+    pattern: IdPattern,
+    hook: Box<H>,
+}
+
+/// What to do with a new ID under a token already at its stream cap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapPolicy {
+    Reject,
+    /// Extracts the least recently pushed stream to make room, unless its
+    /// extraction fails, in which case the new ID is rejected.
+    EvictIdle,
+}
+
+/// A group could not be registered because this ID already names a group
+/// or belongs to one, and groups do not nest.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NestedGroup(pub Vec<u8>);
+
+impl Display for NestedGroup {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} would nest groups", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl error::Error for NestedGroup {
+    fn description(&self) -> &str {
+        "nested group"
+    }
+}
+
+/// IDs from least to most recently touched. Touching pushes a new entry and
+/// leaves the old one to be skipped, so both operations are amortized O(1).
+struct Recency {
+    queue: VecDeque<(Vec<u8>, u64)>,
+    latest: HashMap<Vec<u8>, u64>,
+    next: u64,
+}
+
+impl Recency {
+    fn new() -> Self {
+        Recency {
+            queue: VecDeque::new(),
+            latest: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    fn touch(&mut self, id: &[u8]) {
+        let seq = self.next;
+        self.next += 1;
+        self.latest.insert(id.to_owned(), seq);
+        self.queue.push_back((id.to_owned(), seq));
+        if self.queue.len() > 2 * self.latest.len() + 16 {
+            let latest = &self.latest;
+            self.queue = self.queue
+                             .drain(..)
+                             .filter(|&(ref id, seq)| latest.get(id) == Some(&seq))
+                             .collect();
+        }
+    }
+
+    /// Touches, in ID order, each of `finder`'s IDs not yet tracked.
+    fn adopt<S>(&mut self, finder: &Finder<S>) {
+        let mut ids: Vec<_> = finder.keys().filter(|id| !self.latest.contains_key(*id)).collect();
+        ids.sort();
+        for id in ids {
+            self.touch(id);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<Vec<u8>> {
+        while let Some((id, seq)) = self.queue.pop_front() {
+            if self.latest.get(&id) == Some(&seq) {
+                self.latest.remove(&id);
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+struct Cap {
+    max: usize,
+    policy: CapPolicy,
+    recency: Recency,
+}
+
+/// What becomes of a message past its token's retention.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RetentionAction {
+    /// Refuses it with `ConsumeError::ExpiredPayload`.
+    Reject,
+    /// Pushes it, stamped as sent, to the stream of this ID under the same
+    /// token instead of its own, for review before deletion. The stream is
+    /// provisioned like any other, and the message is consumed as
+    /// `Delivery::Diverted`.
+    Divert(Vec<u8>),
+}
+
+/// How old a token's messages may be by their header timestamp, as of the
+/// server's clock. A message exactly `max_age` old is still taken; one
+/// stamped in the future always is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age: Duration,
+    pub action: RetentionAction,
+    /// Whether `backfill` is held to the policy too, rather than storing
+    /// historical records regardless of age.
+    pub in_backfill: bool,
+}
+
+/// Where a `TokenServer` put a message it consumed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// To the stream, or group, of its own ID.
+    Stored,
+    /// To its token's quarantine stream, being past retention.
+    Diverted,
+}
+
+/// Messages each retention action was taken on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionCounts {
+    pub rejected: u64,
+    pub diverted: u64,
+}
+
+/// What a token may do besides store data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Data,
+    /// May also extract its streams over the wire; see `server::admin`.
+    Admin,
+}
+
+/// A server holding one `Finder` per registered token. Unknown IDs are
+/// provisioned by the token's own factory if it has one, then by the
+/// fallback factory; without either, they are still missing.
+///
+/// An ID registered as a group under a token stands for its members, and a
+/// message for it reaches either every member or none, as far as their
+/// `TransactionalStream` commits allow.
+///
+/// Its callbacks need not be `Send` unless it is over `Sendable`, as
+/// `new_send` makes it.
+pub struct TokenServer<S: Stream, K: TokenCallbacks<S> = Local> {
+    tokens: HashMap<Vec<u8>, Finder<S>>,
+    factories: HashMap<Vec<u8>, Box<K::Factory>>,
+    fallback: Option<Box<K::Factory>>,
+    caps: HashMap<Vec<u8>, Cap>,
+    on_evict: Option<Box<K::Eviction>>,
+    groups: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<Vec<u8>>>>,
+    /// Set by `register_group`, which alone knows `S` to be transactional.
+    transact: Option<Transact<S>>,
+    hooks: Vec<Hook<K::Hook>>,
+    next_hook: u64,
+    hook_panics: u64,
+    admins: HashSet<Vec<u8>>,
+    retention: HashMap<Vec<u8>, RetentionPolicy>,
+    retention_counts: RetentionCounts,
+    clock: Box<Clock + Send>,
+}
+
+impl<S: Stream> TokenServer<S> {
+    pub fn new() -> Self {
+        TokenServer::without_callbacks()
+    }
+
+    /// Sets the factory for new IDs under `token`, which need not be
+    /// registered yet.
+    pub fn set_factory<F>(&mut self, token: &[u8], factory: F)
+        where F: FnMut(&[u8]) -> S + 'static
+    {
+        self.factories.insert(token.to_owned(), Box::new(factory));
+    }
+
+    /// Sets the factory for new IDs under tokens without their own.
+    pub fn set_fallback_factory<F>(&mut self, factory: F)
+        where F: FnMut(&[u8]) -> S + 'static
+    {
+        self.fallback = Some(Box::new(factory));
+    }
+
+    /// Sets what receives streams evicted by `CapPolicy::EvictIdle`.
+    pub fn set_eviction_callback<F>(&mut self, callback: F)
+        where F: FnMut(&[u8], &[u8], FoundResult<S>) + 'static
+    {
+        self.on_evict = Some(Box::new(callback));
+    }
+
+    /// Calls `hook` after every successful push under `token` to an ID
+    /// matching `pattern`, including each member a group message reaches.
+    /// Hooks for the same ID run in the order they were added. A hook that
+    /// panics is counted by `hook_panics` and does not stop the others.
+    pub fn on_consume<F>(&mut self, token: &[u8], pattern: IdPattern, hook: F) -> HookHandle
+        where F: FnMut(&[u8], Duration, usize) + 'static
+    {
+        self.add_hook(token, pattern, Box::new(hook))
+    }
+}
+
+/// The same as for a `Local` server, but for `Send` callbacks.
+impl<S: Stream> TokenServer<S, Sendable> {
+    /// A server that is `Send` whenever its streams are.
+    pub fn new_send() -> Self {
+        TokenServer::without_callbacks()
+    }
+
+    pub fn set_factory<F>(&mut self, token: &[u8], factory: F)
+        where F: FnMut(&[u8]) -> S + Send + 'static
+    {
+        self.factories.insert(token.to_owned(), Box::new(factory));
+    }
+
+    pub fn set_fallback_factory<F>(&mut self, factory: F)
+        where F: FnMut(&[u8]) -> S + Send + 'static
+    {
+        self.fallback = Some(Box::new(factory));
+    }
+
+    pub fn set_eviction_callback<F>(&mut self, callback: F)
+        where F: FnMut(&[u8], &[u8], FoundResult<S>) + Send + 'static
+    {
+        self.on_evict = Some(Box::new(callback));
+    }
+
+    pub fn on_consume<F>(&mut self, token: &[u8], pattern: IdPattern, hook: F) -> HookHandle
+        where F: FnMut(&[u8], Duration, usize) + Send + 'static
+    {
+        self.add_hook(token, pattern, Box::new(hook))
+    }
+}
+
+impl<S: Stream, K: TokenCallbacks<S>> TokenServer<S, K> {
+    fn without_callbacks() -> Self {
+        TokenServer {
+            tokens: HashMap::new(),
+            factories: HashMap::new(),
+            fallback: None,
+            caps: HashMap::new(),
+            on_evict: None,
+            groups: HashMap::new(),
+            transact: None,
+            hooks: vec![],
+            next_hook: 0,
+            hook_panics: 0,
+            admins: HashSet::new(),
+            retention: HashMap::new(),
+            retention_counts: RetentionCounts::default(),
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Registers `token` if it is not already, returning its streams.
+    pub fn add_token(&mut self, token: &[u8]) -> &mut Finder<S> {
+        self.tokens.entry(token.to_owned()).or_insert_with(HashMap::new)
+    }
+
+    /// Registers `token` if it is not already, with `permission`, returning
+    /// its streams.
+    pub fn add_token_with(&mut self, token: &[u8], permission: Permission) -> &mut Finder<S> {
+        match permission {
+            Permission::Data => self.admins.remove(token),
+            Permission::Admin => self.admins.insert(token.to_owned()),
+        };
+        self.add_token(token)
+    }
+
+    /// Every token's streams, in token order, for reading them all
+    /// through a shared borrow.
+    pub fn finders(&self) -> Vec<(&[u8], &Finder<S>)> {
+        let mut finders: Vec<_> = self.tokens
+                                      .iter()
+                                      .map(|(token, finder)| (&token[..], finder))
+                                      .collect();
+        finders.sort_by(|a, b| a.0.cmp(b.0));
+        finders
+    }
+
+    /// `None` for an unregistered token. Tokens are `Data` unless
+    /// registered otherwise.
+    pub fn permission(&self, token: &[u8]) -> Option<Permission> {
+        if !self.tokens.contains_key(token) {
+            None
+        } else if self.admins.contains(token) {
+            Some(Permission::Admin)
+        } else {
+            Some(Permission::Data)
+        }
+    }
+
+    /// Unregisters `token` along with its factory, cap, groups, retention
+    /// and permission, returning its streams.
+    pub fn remove_token(&mut self, token: &[u8]) -> Option<Finder<S>> {
+        self.admins.remove(token);
+        self.retention.remove(token);
+        self.factories.remove(token);
+        self.caps.remove(token);
+        self.groups.remove(token);
+        self.tokens.remove(token)
+    }
+
+    pub fn clear_factory(&mut self, token: &[u8]) {
+        self.factories.remove(token);
+    }
+
+    pub fn clear_fallback_factory(&mut self) {
+        self.fallback = None;
+    }
+
+    /// Limits how many streams provisioning may leave under `token`. Streams
+    /// already registered count toward the cap; for eviction, they are
+    /// treated as pushed, in ID order, when the cap is set. One provisioned
+    /// afterwards is touched as it is inserted and on every message for it,
+    /// pushed or not. One registered through the `Finder` directly counts
+    /// too, but is only tracked from its first message, and until then is
+    /// never evicted.
+    pub fn set_stream_cap(&mut self, token: &[u8], max: usize, policy: CapPolicy) {
+        let mut recency = Recency::new();
+        if let Some(finder) = self.tokens.get(token) {
+            recency.adopt(finder);
+        }
+        self.caps.insert(token.to_owned(),
+                         Cap {
+                             max: max,
+                             policy: policy,
+                             recency: recency,
+                         });
+    }
+
+    pub fn clear_stream_cap(&mut self, token: &[u8]) {
+        self.caps.remove(token);
+    }
+
+    /// Holds messages under `token`, which need not be registered yet, to
+    /// `policy` from the next one on.
+    pub fn set_retention(&mut self, token: &[u8], policy: RetentionPolicy) {
+        self.retention.insert(token.to_owned(), policy);
+    }
+
+    pub fn clear_retention(&mut self, token: &[u8]) {
+        self.retention.remove(token);
+    }
+
+    pub fn retention(&self, token: &[u8]) -> Option<&RetentionPolicy> {
+        self.retention.get(token)
+    }
+
+    pub fn retention_counts(&self) -> &RetentionCounts {
+        &self.retention_counts
+    }
+
+    /// Sets the clock message ages are measured by, the system's unless
+    /// set.
+    pub fn set_clock<C: Clock + Send + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// The action to take on a message for `token` stamped `timestamp`, if
+    /// it is past retention, with its age and the policy's maximum.
+    fn expired(&self,
+               token: &[u8],
+               timestamp: Duration,
+               backfill: bool)
+               -> Option<(&RetentionAction, Duration, Duration)> {
+        let policy = match self.retention.get(token) {
+            Some(policy) if policy.in_backfill || !backfill => policy,
+            _ => return None,
+        };
+        let now = self.clock.now();
+        if now <= timestamp || now - timestamp <= policy.max_age {
+            return None;
+        }
+        Some((&policy.action, now - timestamp, policy.max_age))
+    }
+
+    pub fn remove_group(&mut self, token: &[u8], group_id: &[u8]) -> Option<Vec<Vec<u8>>> {
+        self.groups.get_mut(token).and_then(|groups| groups.remove(group_id))
+    }
+
+    fn add_hook(&mut self, token: &[u8], pattern: IdPattern, hook: Box<K::Hook>) -> HookHandle {
+        let handle = HookHandle(self.next_hook);
+        self.next_hook += 1;
+        self.hooks.push(Hook {
+            handle: handle,
+            token: token.to_owned(),
+            pattern: pattern,
+            hook: hook,
+        });
+        handle
+    }
+
+    /// Returns whether the hook was still registered.
+    pub fn remove_hook(&mut self, handle: HookHandle) -> bool {
+        let before = self.hooks.len();
+        self.hooks.retain(|hook| hook.handle != handle);
+        self.hooks.len() < before
+    }
+
+    /// How many times a hook has panicked.
+    pub fn hook_panics(&self) -> u64 {
+        self.hook_panics
+    }
+
+    fn run_hooks(&mut self, token: &[u8], id: &[u8], timestamp: Duration, len: usize) {
+        for hook in &mut self.hooks {
+            if hook.token != token || !hook.pattern.matches(id) {
+                continue;
+            }
+            let hook = &mut hook.hook;
+            if panic::catch_unwind(AssertUnwindSafe(|| (**hook)(id, timestamp, len))).is_err() {
+                self.hook_panics += 1;
+            }
+        }
+    }
+
+    /// Makes sure `id` has a stream under the registered `token`.
+    fn provision(&mut self,
+                 token: &[u8],
+                 id: &[u8])
+                 -> Result<(), ConsumeError<::Void, <S as Stream>::PushErr>> {
+        let finder = self.tokens.get_mut(token).unwrap();
+        if finder.contains_key(id) {
+            return Ok(());
+        }
+        let factory = match self.factories.get_mut(token) {
+            Some(factory) => Some(factory),
+            None => self.fallback.as_mut(),
+        };
+        let factory = match factory {
+            Some(factory) => factory,
+            None => return Err(ConsumeError::MissingId),
+        };
+        if let Some(cap) = self.caps.get_mut(token) {
+            if finder.len() >= cap.max {
+                let exceeded = ConsumeError::StreamCapExceeded { cap: cap.max };
+                if cap.policy == CapPolicy::Reject {
+                    return Err(exceeded);
+                }
+                loop {
+                    let oldest = match cap.recency.pop_oldest() {
+                        Some(oldest) => oldest,
+                        None => return Err(exceeded),
+                    };
+                    if let Some(result) = stream::Finder::extract(finder, &oldest) {
+                        let failed = result.is_err();
+                        if let Some(ref mut on_evict) = self.on_evict {
+                            (**on_evict)(token, &oldest, result);
+                        }
+                        if failed {
+                            cap.recency.touch(&oldest);
+                            return Err(exceeded);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        let stream = (**factory)(id);
+        finder.insert(id.to_owned(), stream);
+        if let Some(cap) = self.caps.get_mut(token) {
+            cap.recency.touch(id);
+        }
+        Ok(())
+    }
+
+    /// What consuming a message for `token` and `id` would do, short of
+    /// pushing it, without provisioning or evicting anything. Eviction under
+    /// `CapPolicy::EvictIdle` is taken to succeed unless a group has more
+    /// members than the cap.
+    pub fn route(&self, token: &[u8], id: &[u8]) -> DryRunOutcome<::Void> {
+        let finder = match self.tokens.get(token) {
+            Some(finder) => finder,
+            None => return DryRunOutcome::Unauthorized(AuthError::InvalidToken),
+        };
+        let single = [id.to_owned()];
+        let ids = self.groups
+                      .get(token)
+                      .and_then(|groups| groups.get(id))
+                      .map_or(&single[..], |members| &members[..]);
+        let new = ids.iter().filter(|id| !finder.contains_key(&id[..])).count();
+        if new == 0 {
+            return DryRunOutcome::WouldStore;
+        }
+        if !self.factories.contains_key(token) && self.fallback.is_none() {
+            return DryRunOutcome::MissingId;
+        }
+        match self.caps.get(token) {
+            Some(cap) if (cap.policy == CapPolicy::Reject && finder.len() + new > cap.max) ||
+                         ids.len() > cap.max => {
+                DryRunOutcome::StreamCapExceeded { cap: cap.max }
+            }
+            _ => DryRunOutcome::WouldStore,
+        }
+    }
+
+    fn touch(&mut self, token: &[u8], id: &[u8]) {
+        if let Some(cap) = self.caps.get_mut(token) {
+            cap.recency.touch(id);
+        }
+    }
+
+    /// Inserts a stream for each member under `token` without one, so that
+    /// none is evicted to make room before the message is prepared,
+    /// returning the members that were new.
+    fn provision_group(&mut self,
+                       token: &[u8],
+                       members: &[Vec<u8>])
+                       -> Result<Vec<Vec<u8>>, ConsumeError<::Void, <S as Stream>::PushErr>> {
+        let finder = self.tokens.get_mut(token).unwrap();
+        let fresh: Vec<_> = members.iter()
+                                   .filter(|m| !finder.contains_key(&m[..]))
+                                   .cloned()
+                                   .collect();
+        if fresh.is_empty() {
+            return Ok(fresh);
+        }
+        let factory = match self.factories.get_mut(token) {
+            Some(factory) => Some(factory),
+            None => self.fallback.as_mut(),
+        };
+        let factory = match factory {
+            Some(factory) => factory,
+            None => return Err(ConsumeError::MissingId),
+        };
+        if let Some(cap) = self.caps.get(token) {
+            if members.len() > cap.max ||
+               (cap.policy == CapPolicy::Reject && finder.len() + fresh.len() > cap.max) {
+                return Err(ConsumeError::StreamCapExceeded { cap: cap.max });
+            }
+        }
+        for id in &fresh {
+            let stream = (**factory)(id);
+            finder.insert(id.clone(), stream);
+        }
+        Ok(fresh)
+    }
+
+    /// Evicts the least recently pushed streams under `token` until it is
+    /// within its cap again, stopping at one whose extraction fails.
+    fn evict_over_cap(&mut self, token: &[u8]) {
+        let cap = match self.caps.get_mut(token) {
+            Some(cap) => cap,
+            None => return,
+        };
+        let finder = self.tokens.get_mut(token).unwrap();
+        while finder.len() > cap.max {
+            let oldest = match cap.recency.pop_oldest() {
+                Some(oldest) => oldest,
+                None => return,
+            };
+            if let Some(result) = stream::Finder::extract(finder, &oldest) {
+                let failed = result.is_err();
+                if let Some(ref mut on_evict) = self.on_evict {
+                    (**on_evict)(token, &oldest, result);
+                }
+                if failed {
+                    cap.recency.touch(&oldest);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Prepares the message for every member before committing it to any.
+    /// Nothing is delivered, provisioned or evicted if a member cannot be
+    /// provisioned or prepared, or the first commit fails; a commit failing
+    /// after another has succeeded leaves the rest rolled back and the group
+    /// partly delivered. Streams are evicted for new members only once the
+    /// message is committed. The pressure of a delivered group is that of
+    /// its most pressed member.
+    fn consume_group(&mut self,
+                     token: &[u8],
+                     members: &[Vec<u8>],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> ConsumeResult<::Void, <S as Stream>::PushErr, Pressure> {
+        let transact = self.transact.expect("a group registered without TransactionalStream");
+        let fresh = try!(self.provision_group(token, members));
+        let transacted = transact(self.tokens.get_mut(token).unwrap(), members, timestamp, payload);
+        let nothing_committed = match transacted {
+            Ok(ref outcomes) => outcomes.first().map_or(false, |o| !o.1.is_committed()),
+            Err(_) => true,
+        };
+        if nothing_committed {
+            let finder = self.tokens.get_mut(token).unwrap();
+            for id in &fresh {
+                finder.remove(id);
+            }
+        }
+        let mut outcomes = match transacted {
+            Ok(outcomes) => outcomes,
+            Err(e) => return Err(ConsumeError::Push(e)),
+        };
+        if nothing_committed {
+            match outcomes.swap_remove(0).1 {
+                MemberOutcome::Failed(e) => return Err(ConsumeError::Push(e)),
+                _ => unreachable!(),
+            }
+        }
+        for &(ref member, ref outcome) in &outcomes {
+            self.touch(token, member);
+            if outcome.is_committed() {
+                self.run_hooks(token, member, timestamp, payload.len());
+            }
+        }
+        self.evict_over_cap(token);
+        if outcomes.iter().all(|o| o.1.is_committed()) {
+            let finder = &self.tokens[token];
+            Ok(members.iter()
+                      .filter_map(|member| finder.get(member))
+                      .map(Stream::pressure)
+                      .fold(Pressure::None, stronger))
+        } else {
+            Err(ConsumeError::GroupPartialFailure(outcomes))
+        }
+    }
+
+    /// Pushes to `id`, or where its token's retention sends it instead,
+    /// returning the pressure on the stream pushed to.
+    fn retain(&mut self,
+              backfill: bool,
+              token: &[u8],
+              id: &[u8],
+              timestamp: Duration,
+              payload: &[u8],
+              spans: &mut Spans)
+              -> ConsumeResult<::Void, <S as Stream>::PushErr, (Delivery, Pressure)> {
+        spans.begin("auth");
+        let authorized = self.tokens.contains_key(token);
+        spans.end();
+        if !authorized {
+            return Err(ConsumeError::Auth(AuthError::InvalidToken));
+        }
+        let mut expired = None;
+        if self.retention.contains_key(token) {
+            spans.begin("retention");
+            expired = self.expired(token, timestamp, backfill)
+                          .map(|(action, age, max_age)| (action.clone(), age, max_age));
+            spans.end();
+        }
+        match expired {
+            None => {
+                self.deliver(token, id, timestamp, payload, spans)
+                    .map(|pressure| (Delivery::Stored, pressure))
+            }
+            Some((RetentionAction::Reject, age, max_age)) => {
+                self.retention_counts.rejected += 1;
+                Err(ConsumeError::ExpiredPayload {
+                    age: age,
+                    max_age: max_age,
+                })
+            }
+            Some((RetentionAction::Divert(quarantine), _, _)) => {
+                let pressure = try!(self.deliver(token, &quarantine, timestamp, payload, spans));
+                self.retention_counts.diverted += 1;
+                Ok((Delivery::Diverted, pressure))
+            }
+        }
+    }
+
+    /// Pushes to `id` under the registered `token`, or to its group.
+    fn deliver(&mut self,
+               token: &[u8],
+               id: &[u8],
+               timestamp: Duration,
+               payload: &[u8],
+               spans: &mut Spans)
+               -> ConsumeResult<::Void, <S as Stream>::PushErr, Pressure> {
+        spans.begin("route");
+        let members = self.groups.get(token).and_then(|groups| groups.get(id)).cloned();
+        if let Some(members) = members {
+            spans.end();
+            spans.begin("group");
+            let result = self.consume_group(token, &members, timestamp, payload);
+            spans.end();
+            return result;
+        }
+        let provisioned = self.provision(token, id);
+        spans.end();
+        try!(provisioned);
+        spans.begin("push");
+        let pushed = {
+            let stream = self.tokens.get_mut(token).unwrap().get_mut(id).unwrap();
+            stream.push(timestamp, payload).map(|()| stream.pressure()).map_err(ConsumeError::Push)
+        };
+        self.touch(token, id);
+        if pushed.is_ok() {
+            self.run_hooks(token, id, timestamp, payload.len());
+        }
+        spans.end();
+        pushed
+    }
+}
+
+/// The stronger of two hints: stopping over slowing down, and the longer of
+/// two delays.
+fn stronger(a: Pressure, b: Pressure) -> Pressure {
+    match (a, b) {
+        (Pressure::Stop, _) | (_, Pressure::Stop) => Pressure::Stop,
+        (Pressure::SlowDown { suggested_delay: a }, Pressure::SlowDown { suggested_delay: b }) => {
+            Pressure::SlowDown { suggested_delay: cmp::max(a, b) }
+        }
+        (Pressure::None, p) | (p, Pressure::None) => p,
+    }
+}
+
+impl<S: TransactionalStream, K: TokenCallbacks<S>> TokenServer<S, K> {
+    /// Makes `group_id` under `token` stand for `member_ids`, replacing any
+    /// group of that ID. The group shadows a stream of the same ID. Members
+    /// are provisioned like any other ID when a message first reaches them.
+    pub fn register_group(&mut self,
+                          token: &[u8],
+                          group_id: &[u8],
+                          member_ids: Vec<Vec<u8>>)
+                          -> Result<(), NestedGroup> {
+        let groups = self.groups.entry(token.to_owned()).or_insert_with(HashMap::new);
+        for (id, members) in groups.iter() {
+            if &id[..] != group_id && members.iter().any(|m| &m[..] == group_id) {
+                return Err(NestedGroup(group_id.to_owned()));
+            }
+        }
+        for member in &member_ids {
+            if &member[..] == group_id || groups.contains_key(member) {
+                return Err(NestedGroup(member.clone()));
+            }
+        }
+        groups.insert(group_id.to_owned(), member_ids);
+        self.transact = Some(transact::<S>);
+        Ok(())
+    }
+}
+
+impl<S: Stream, K: TokenCallbacks<S>> Server for TokenServer<S, K> {
+    type Stream = S;
+    type AuthErr = ::Void;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.tokens.get_mut(token).ok_or(AuthError::InvalidToken)
+    }
+
+    type ConsumeOk = Delivery;
+    /// Adds `auth`, `route` (provisioning included) and `push` spans, or
+    /// `group` for a whole group's push, with `retention` between the
+    /// first two for a token with a policy.
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.consume_parts_pressed(token, id, timestamp, payload, spans).0
+    }
+
+    /// The pressure is read off the stream just pushed to, so that of a
+    /// diverted message is its quarantine's.
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        match self.retain(false, token, id, timestamp, payload, spans) {
+            Ok((delivery, pressure)) => (Ok(delivery), pressure),
+            Err(e) => (Err(e), Pressure::None),
+        }
+    }
+
+    /// Holds records to their token's retention only if its policy says so.
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        self.retain(true, token, id, timestamp, payload, &mut Spans::off())
+            .map(|(delivery, _)| delivery)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     _: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        if !self.tokens.contains_key(token) {
+            return self.route(token, id);
+        }
+        match self.expired(token, timestamp, false) {
+            None => self.route(token, id),
+            Some((&RetentionAction::Reject, _, _)) => {
+                DryRunOutcome::Rejected("payload past retention")
+            }
+            Some((&RetentionAction::Divert(ref quarantine), _, _)) => self.route(token, quarantine),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::{Accounting, AuthError, ConsumeError, Dedup, MemberOutcome};
+    use stream::TransactionalStream;
+    use test_support::stream::ScriptedStream;
+    use {Server, Stream};
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Labeled {
+        label: String,
+        pushes: usize,
+    }
+
+    impl Labeled {
+        fn new(prefix: &str, id: &[u8]) -> Self {
+            Labeled {
+                label: format!("{}/{}", prefix, String::from_utf8_lossy(id)),
+                pushes: 0,
+            }
+        }
+    }
+
+    impl Stream for Labeled {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+            self.pushes += 1;
+            Ok(())
+        }
+
+        type Extract = String;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(self.label)
+        }
+    }
+
+    fn consume(server: &mut TokenServer<Labeled>,
+               token: &[u8],
+               id: &[u8])
+               -> ConsumeResult<::Void, ::Void, Delivery> {
+        server.consume_parts(token, id, Duration::from_millis(0), b"")
+    }
+
+    fn labels(server: &mut TokenServer<Labeled>, token: &[u8]) -> Vec<(String, usize)> {
+        let mut labels: Vec<_> = server.auth(token)
+                                       .unwrap()
+                                       .values()
+                                       .map(|s| (s.label.clone(), s.pushes))
+                                       .collect();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn per_token_factories() {
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.add_token(b"b");
+        server.set_factory(b"a", |id| Labeled::new("/data/a", id));
+        server.set_factory(b"b", |id| Labeled::new("channel:b", id));
+        server.set_fallback_factory(|id| Labeled::new("fallback", id));
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert!(consume(&mut server, b"b", b"x").is_ok());
+        assert_eq!(vec![("/data/a/x".to_owned(), 1)], labels(&mut server, b"a"));
+        assert_eq!(vec![("channel:b/x".to_owned(), 1)], labels(&mut server, b"b"));
+    }
+
+    #[test]
+    fn fallback_factory() {
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.add_token(b"b");
+        server.set_factory(b"a", |id| Labeled::new("/data/a", id));
+        server.set_fallback_factory(|id| Labeled::new("fallback", id));
+        assert!(consume(&mut server, b"b", b"y").is_ok());
+        assert_eq!(vec![("fallback/y".to_owned(), 1)], labels(&mut server, b"b"));
+    }
+
+    #[test]
+    fn no_factory() {
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.add_token(b"b");
+        server.set_factory(b"a", |id| Labeled::new("/data/a", id));
+        assert_match!(Err(ConsumeError::MissingId), consume(&mut server, b"b", b"y"));
+        assert!(labels(&mut server, b"b").is_empty());
+        server.clear_factory(b"a");
+        assert_match!(Err(ConsumeError::MissingId), consume(&mut server, b"a", b"y"));
+    }
+
+    #[test]
+    fn unregistered_token() {
+        let mut server = TokenServer::new();
+        server.set_factory(b"a", |id| Labeled::new("/data/a", id));
+        server.set_fallback_factory(|id| Labeled::new("fallback", id));
+        assert_match!(Err(ConsumeError::Auth(AuthError::InvalidToken)),
+                      consume(&mut server, b"a", b"x"));
+    }
+
+    #[test]
+    fn factory_called_once_per_id() {
+        let calls = Rc::new(Cell::new(0));
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        {
+            let calls = calls.clone();
+            server.set_factory(b"a", move |id| {
+                calls.set(calls.get() + 1);
+                Labeled::new("/data/a", id)
+            });
+        }
+        for _ in 0..3 {
+            assert!(consume(&mut server, b"a", b"x").is_ok());
+            assert!(consume(&mut server, b"a", b"y").is_ok());
+        }
+        assert_eq!(2, calls.get());
+        assert_eq!(vec![("/data/a/x".to_owned(), 3), ("/data/a/y".to_owned(), 3)],
+                   labels(&mut server, b"a"));
+    }
+
+    fn capped(policy: CapPolicy) -> TokenServer<Labeled> {
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.add_token(b"b");
+        server.set_fallback_factory(|id| Labeled::new("", id));
+        server.set_stream_cap(b"a", 2, policy);
+        server
+    }
+
+    #[test]
+    fn cap_rejects() {
+        let mut server = capped(CapPolicy::Reject);
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert!(consume(&mut server, b"a", b"y").is_ok());
+        assert_match!(Err(ConsumeError::StreamCapExceeded { cap: 2 }),
+                      consume(&mut server, b"a", b"z"));
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert!(consume(&mut server, b"b", b"z").is_ok());
+        assert_eq!(vec![("/x".to_owned(), 2), ("/y".to_owned(), 1)],
+                   labels(&mut server, b"a"));
+    }
+
+    #[test]
+    fn cap_evicts_least_recently_pushed() {
+        let evicted = Rc::new(RefCell::new(vec![]));
+        let mut server = capped(CapPolicy::EvictIdle);
+        {
+            let evicted = evicted.clone();
+            server.set_eviction_callback(move |token, id, result| {
+                evicted.borrow_mut().push((token.to_vec(), id.to_vec(), result));
+            });
+        }
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert!(consume(&mut server, b"a", b"y").is_ok());
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert!(consume(&mut server, b"a", b"z").is_ok());
+        assert!(consume(&mut server, b"a", b"z").is_ok());
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert_eq!(vec![(b"a".to_vec(), b"y".to_vec(), Ok("/y".to_owned()))],
+                   *evicted.borrow());
+        assert_eq!(vec![("/x".to_owned(), 3), ("/z".to_owned(), 2)],
+                   labels(&mut server, b"a"));
+        for _ in 0..100 {
+            assert!(consume(&mut server, b"a", b"x").is_ok());
+        }
+        assert!(consume(&mut server, b"a", b"w").is_ok());
+        assert_eq!(2, evicted.borrow().len());
+        assert_eq!(b"z".to_vec(), evicted.borrow()[1].1);
+    }
+
+    #[test]
+    fn cap_counts_registered_streams() {
+        let mut server = TokenServer::new();
+        server.add_token(b"a").insert(b"old".to_vec(), Labeled::new("", b"old"));
+        server.set_fallback_factory(|id| Labeled::new("", id));
+        server.set_stream_cap(b"a", 1, CapPolicy::EvictIdle);
+        assert!(consume(&mut server, b"a", b"new").is_ok());
+        assert_eq!(vec![("/new".to_owned(), 1)], labels(&mut server, b"a"));
+    }
+
+    #[test]
+    fn cap_tracks_streams_registered_later_from_their_first_message() {
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.set_fallback_factory(|id| Labeled::new("", id));
+        server.set_stream_cap(b"a", 1, CapPolicy::EvictIdle);
+        server.add_token(b"a").insert(b"late".to_vec(), Labeled::new("", b"late"));
+        assert_match!(Err(ConsumeError::StreamCapExceeded { cap: 1 }),
+                      consume(&mut server, b"a", b"new"));
+        assert!(consume(&mut server, b"a", b"late").is_ok());
+        assert!(consume(&mut server, b"a", b"new").is_ok());
+        assert_eq!(vec![("/new".to_owned(), 1)], labels(&mut server, b"a"));
+    }
+
+    #[test]
+    fn cap_touches_on_a_failed_push() {
+        let mut server: TokenServer<ScriptedStream<&str, &str>> = TokenServer::new();
+        server.add_token(b"a");
+        server.set_fallback_factory(|id| {
+            ScriptedStream::new(if id == b"x" { vec![Err("full")] } else { vec![] })
+        });
+        server.set_stream_cap(b"a", 2, CapPolicy::EvictIdle);
+        let at = Duration::from_millis(0);
+        assert_match!(Err(ConsumeError::Push("full")),
+                      server.consume_parts(b"a", b"x", at, b""));
+        assert!(server.consume_parts(b"a", b"y", at, b"").is_ok());
+        assert!(server.consume_parts(b"a", b"z", at, b"").is_ok());
+        let mut ids: Vec<_> = server.auth(b"a").unwrap().keys().cloned().collect();
+        ids.sort();
+        assert_eq!(vec![b"y".to_vec(), b"z".to_vec()], ids);
+    }
+
+    #[derive(Debug, Default)]
+    struct Member {
+        pushes: usize,
+        rollbacks: usize,
+        refuse: bool,
+        refuse_commit: bool,
+    }
+
+    impl Stream for Member {
+        type PushErr = &'static str;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+            if self.refuse {
+                return Err("refused");
+            }
+            self.pushes += 1;
+            Ok(())
+        }
+
+        type Extract = usize;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(self.pushes)
+        }
+    }
+
+    impl TransactionalStream for Member {
+        type Prepared = ();
+        fn prepare(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+            if self.refuse {
+                return Err("refused");
+            }
+            Ok(())
+        }
+
+        fn commit(&mut self, _: ()) -> Result<(), Self::PushErr> {
+            if self.refuse_commit {
+                return Err("refused commit");
+            }
+            self.pushes += 1;
+            Ok(())
+        }
+
+        fn rollback(&mut self, _: ()) {
+            self.rollbacks += 1;
+        }
+    }
+
+    fn grouped(members: &[&[u8]], refusing: &[u8]) -> TokenServer<Member> {
+        let mut server = TokenServer::new();
+        {
+            let finder = server.add_token(b"a");
+            for id in &[&b"x"[..], b"y", b"z"] {
+                finder.insert(id.to_vec(),
+                              Member {
+                                  refuse: *id == refusing,
+                                  ..Member::default()
+                              });
+            }
+        }
+        server.register_group(b"a", b"all", members.iter().map(|m| m.to_vec()).collect())
+              .unwrap();
+        server
+    }
+
+    fn pushes(server: &mut TokenServer<Member>) -> Vec<usize> {
+        let finder = server.auth(b"a").unwrap();
+        [&b"x"[..], b"y", b"z"].iter().map(|id| finder[*id].pushes).collect()
+    }
+
+    fn rollbacks(server: &mut TokenServer<Member>) -> Vec<usize> {
+        let finder = server.auth(b"a").unwrap();
+        [&b"x"[..], b"y", b"z"].iter().map(|id| finder[*id].rollbacks).collect()
+    }
+
+    fn consume_group(server: &mut TokenServer<Member>,
+                     id: &[u8])
+                     -> ConsumeResult<::Void, &'static str, Delivery> {
+        server.consume_parts(b"a", id, Duration::from_millis(0), b"")
+    }
+
+    #[test]
+    fn group_reaches_every_member() {
+        let mut server = grouped(&[b"x", b"y", b"z"], b"");
+        assert_match!(Ok(Delivery::Stored), consume_group(&mut server, b"all"));
+        assert_eq!(vec![1, 1, 1], pushes(&mut server));
+        assert_eq!(vec![0, 0, 0], rollbacks(&mut server));
+    }
+
+    #[test]
+    fn second_member_missing_rolls_back_first() {
+        let mut server = grouped(&[b"x", b"w", b"z"], b"");
+        assert_match!(Err(ConsumeError::MissingId), consume_group(&mut server, b"all"));
+        assert_eq!(vec![0, 0, 0], pushes(&mut server));
+    }
+
+    #[test]
+    fn second_member_refusing_rolls_back_first() {
+        let mut server = grouped(&[b"x", b"y", b"z"], b"y");
+        assert_match!(Err(ConsumeError::Push("refused")), consume_group(&mut server, b"all"));
+        assert_eq!(vec![0, 0, 0], pushes(&mut server));
+        assert_eq!(vec![1, 0, 0], rollbacks(&mut server));
+    }
+
+    #[test]
+    fn failed_group_neither_provisions_nor_evicts() {
+        let mut server = grouped(&[b"x", b"new", b"y"], b"y");
+        server.set_fallback_factory(|_| Member::default());
+        server.set_stream_cap(b"a", 3, CapPolicy::EvictIdle);
+        let evicted = Rc::new(RefCell::new(vec![]));
+        {
+            let evicted = evicted.clone();
+            server.set_eviction_callback(move |_, id, _| evicted.borrow_mut().push(id.to_vec()));
+        }
+        assert_match!(Err(ConsumeError::Push("refused")), consume_group(&mut server, b"all"));
+        assert_eq!(3, server.auth(b"a").unwrap().len());
+        assert!(evicted.borrow().is_empty());
+
+        server.auth(b"a").unwrap().get_mut(&b"y"[..]).unwrap().refuse = false;
+        assert_match!(Ok(Delivery::Stored), consume_group(&mut server, b"all"));
+        assert_eq!(vec![b"z".to_vec()], *evicted.borrow());
+        assert_eq!(1, server.auth(b"a").unwrap()[&b"new"[..]].pushes);
+    }
+
+    #[test]
+    fn group_commit_failure() {
+        let mut server = grouped(&[b"x", b"y", b"z"], b"");
+        server.auth(b"a").unwrap().get_mut(&b"y"[..]).unwrap().refuse_commit = true;
+        assert_match!(Err(ConsumeError::GroupPartialFailure(ref outcomes)) if *outcomes == vec![
+                          (b"x".to_vec(), MemberOutcome::Committed),
+                          (b"y".to_vec(), MemberOutcome::Failed("refused commit")),
+                          (b"z".to_vec(), MemberOutcome::RolledBack),
+                      ],
+                      consume_group(&mut server, b"all"));
+        assert_eq!(vec![1, 0, 0], pushes(&mut server));
+        assert_eq!(vec![0, 0, 1], rollbacks(&mut server));
+
+        let mut server = grouped(&[b"y", b"x", b"z"], b"");
+        server.auth(b"a").unwrap().get_mut(&b"y"[..]).unwrap().refuse_commit = true;
+        assert_match!(Err(ConsumeError::Push("refused commit")),
+                      consume_group(&mut server, b"all"));
+        assert_eq!(vec![0, 0, 0], pushes(&mut server));
+    }
+
+    #[test]
+    fn non_group_ids_unaffected() {
+        let mut server = grouped(&[b"x", b"y"], b"");
+        assert_match!(Ok(Delivery::Stored), consume_group(&mut server, b"x"));
+        assert_match!(Ok(Delivery::Stored), consume_group(&mut server, b"z"));
+        assert_match!(Err(ConsumeError::MissingId), consume_group(&mut server, b"w"));
+        assert_eq!(vec![1, 0, 1], pushes(&mut server));
+        assert_match!(Err(ConsumeError::Auth(AuthError::InvalidToken)),
+                      server.consume_parts(b"b", b"all", Duration::from_millis(0), b""));
+    }
+
+    #[test]
+    fn nested_groups_rejected() {
+        let mut server = grouped(&[b"x", b"y"], b"");
+        assert_eq!(Err(NestedGroup(b"all".to_vec())),
+                   server.register_group(b"a", b"outer", vec![b"all".to_vec()]));
+        assert_eq!(Err(NestedGroup(b"x".to_vec())),
+                   server.register_group(b"a", b"x", vec![b"z".to_vec()]));
+        assert_eq!(Err(NestedGroup(b"self".to_vec())),
+                   server.register_group(b"a", b"self", vec![b"self".to_vec()]));
+        assert_eq!(Ok(()), server.register_group(b"a", b"all", vec![b"z".to_vec()]));
+        assert_eq!(Some(vec![b"z".to_vec()]), server.remove_group(b"a", b"all"));
+    }
+
+    type Calls = Rc<RefCell<Vec<(&'static str, Vec<u8>, Duration, usize)>>>;
+
+    fn record(calls: &Calls, name: &'static str) -> Box<FnMut(&[u8], Duration, usize)> {
+        let calls = calls.clone();
+        Box::new(move |id: &[u8], ts, len| calls.borrow_mut().push((name, id.to_vec(), ts, len)))
+    }
+
+    #[test]
+    fn hooks_in_registration_order() {
+        let calls: Calls = Rc::new(RefCell::new(vec![]));
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.add_token(b"b");
+        server.set_fallback_factory(|id| Labeled::new("", id));
+        let prefix = server.on_consume(b"a",
+                                       IdPattern::Prefix(b"cam".to_vec()),
+                                       record(&calls, "prefix"));
+        server.on_consume(b"a", IdPattern::Exact(b"cam-1".to_vec()), record(&calls, "exact"));
+        server.on_consume(b"a", IdPattern::Prefix(vec![]), record(&calls, "all"));
+
+        let ms = Duration::from_millis;
+        assert!(server.consume_parts(b"a", b"cam-1", ms(1), b"xy").is_ok());
+        assert!(server.consume_parts(b"a", b"cam-2", ms(2), b"").is_ok());
+        assert!(server.consume_parts(b"a", b"mic", ms(3), b"z").is_ok());
+        assert!(server.consume_parts(b"b", b"cam-1", ms(4), b"z").is_ok());
+        assert!(server.remove_hook(prefix));
+        assert!(!server.remove_hook(prefix));
+        assert!(server.consume_parts(b"a", b"cam-1", ms(5), b"w").is_ok());
+        assert_eq!(vec![("prefix", b"cam-1".to_vec(), ms(1), 2),
+                        ("exact", b"cam-1".to_vec(), ms(1), 2),
+                        ("all", b"cam-1".to_vec(), ms(1), 2),
+                        ("prefix", b"cam-2".to_vec(), ms(2), 0),
+                        ("all", b"cam-2".to_vec(), ms(2), 0),
+                        ("all", b"mic".to_vec(), ms(3), 1),
+                        ("exact", b"cam-1".to_vec(), ms(5), 1),
+                        ("all", b"cam-1".to_vec(), ms(5), 1)],
+                   *calls.borrow());
+    }
+
+    #[test]
+    fn hooks_not_run_on_failure() {
+        let calls: Calls = Rc::new(RefCell::new(vec![]));
+        let mut server = TokenServer::<Labeled>::new();
+        server.add_token(b"a");
+        server.on_consume(b"a", IdPattern::Prefix(vec![]), record(&calls, "all"));
+        assert_match!(Err(ConsumeError::MissingId), consume(&mut server, b"a", b"x"));
+        assert!(calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn panicking_hook_contained() {
+        let calls: Calls = Rc::new(RefCell::new(vec![]));
+        let mut server = TokenServer::new();
+        server.add_token(b"a");
+        server.set_fallback_factory(|id| Labeled::new("", id));
+        server.on_consume(b"a", IdPattern::Exact(b"x".to_vec()), |_: &[u8], _, _| {
+            panic!("hook failed")
+        });
+        server.on_consume(b"a", IdPattern::Exact(b"x".to_vec()), record(&calls, "after"));
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert!(consume(&mut server, b"a", b"x").is_ok());
+        assert_eq!(2, server.hook_panics());
+        assert_eq!(2, calls.borrow().len());
+        assert_eq!(vec![("/x".to_owned(), 2)], labels(&mut server, b"a"));
+    }
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// A server whose token `old` keeps 30 days and `new` everything, with
+    /// streams recording what was pushed to them, as of day 40.
+    fn retaining(action: RetentionAction,
+                 in_backfill: bool)
+                 -> (TokenServer<ScriptedStream<::Void, ::Void>>, SharedClock) {
+        let clock = SharedClock::new(Duration::from_secs(40 * DAY));
+        let mut server = TokenServer::new();
+        server.set_clock(clock.clone());
+        server.add_token(b"old");
+        server.add_token(b"new");
+        server.set_fallback_factory(|_| ScriptedStream::default());
+        server.set_retention(b"old",
+                             RetentionPolicy {
+                                 max_age: Duration::from_secs(30 * DAY),
+                                 action: action,
+                                 in_backfill: in_backfill,
+                             });
+        (server, clock)
+    }
+
+    fn pushed(server: &mut TokenServer<ScriptedStream<::Void, ::Void>>,
+              token: &[u8],
+              id: &[u8])
+              -> Vec<Vec<u8>> {
+        server.auth(token)
+              .unwrap()
+              .get(id)
+              .map_or(vec![], |s| s.pushed().iter().map(|&(_, ref p)| p.clone()).collect())
+    }
+
+    /// Timestamps exactly at, just inside and just past 30 days old, and
+    /// an hour ahead.
+    fn ages() -> [(Duration, &'static [u8]); 4] {
+        let now = Duration::from_secs(40 * DAY);
+        let max_age = Duration::from_secs(30 * DAY);
+        let ms = Duration::from_millis(1);
+        [(now - max_age, b"at"),
+         (now - max_age + ms, b"inside"),
+         (now - max_age - ms, b"past"),
+         (now + Duration::from_secs(3600), b"future")]
+    }
+
+    #[test]
+    fn retention_rejects_only_past_max_age() {
+        let (mut server, _) = retaining(RetentionAction::Reject, false);
+        for &(timestamp, payload) in &ages() {
+            let result = server.consume_parts(b"old", b"x", timestamp, payload);
+            if payload == b"past" {
+                assert_match!(Err(ConsumeError::ExpiredPayload { age, max_age })
+                                  if age == Duration::from_millis(30 * DAY * 1000 + 1) &&
+                                     max_age == Duration::from_secs(30 * DAY),
+                              result);
+            } else {
+                assert!(result.is_ok());
+            }
+            assert!(server.consume_parts(b"new", b"x", timestamp, payload).is_ok());
+        }
+        assert_eq!(vec![b"at".to_vec(), b"inside".to_vec(), b"future".to_vec()],
+                   pushed(&mut server, b"old", b"x"));
+        assert_eq!(4, pushed(&mut server, b"new", b"x").len());
+        assert_eq!(RetentionCounts { rejected: 1, diverted: 0 }, *server.retention_counts());
+
+        let (timestamp, payload) = ages()[2];
+        assert_match!(DryRunOutcome::Rejected("payload past retention"),
+                      server.dry_run_parts(b"old", b"y", timestamp, payload));
+        assert!(!server.auth(b"old").unwrap().contains_key(&b"y"[..]));
+        assert_match!(DryRunOutcome::Unauthorized(AuthError::InvalidToken),
+                      server.dry_run_parts(b"gone", b"y", timestamp, payload));
+    }
+
+    #[test]
+    fn retention_diverts_to_quarantine() {
+        let (mut server, clock) = retaining(RetentionAction::Divert(b"quarantine".to_vec()), false);
+        let deliveries: Vec<_> = ages().iter()
+                                       .map(|&(timestamp, payload)| {
+                                           server.consume_parts(b"old", b"x", timestamp, payload)
+                                                 .unwrap()
+                                       })
+                                       .collect();
+        assert_eq!(vec![Delivery::Stored, Delivery::Stored, Delivery::Diverted, Delivery::Stored],
+                   deliveries);
+        assert_eq!(vec![b"at".to_vec(), b"inside".to_vec(), b"future".to_vec()],
+                   pushed(&mut server, b"old", b"x"));
+        assert_eq!(vec![b"past".to_vec()], pushed(&mut server, b"old", b"quarantine"));
+        assert_eq!(RetentionCounts { rejected: 0, diverted: 1 }, *server.retention_counts());
+
+        // Once a day later, the message just inside is past too.
+        clock.advance(Duration::from_secs(DAY));
+        let (timestamp, payload) = ages()[1];
+        assert_match!(Ok(Delivery::Diverted),
+                      server.consume_parts(b"old", b"x", timestamp, payload));
+        assert_eq!(vec![b"past".to_vec(), b"inside".to_vec()],
+                   pushed(&mut server, b"old", b"quarantine"));
+        server.clear_retention(b"old");
+        assert_match!(Ok(Delivery::Stored),
+                      server.consume_parts(b"old", b"x", timestamp, payload));
+        assert_eq!(4, pushed(&mut server, b"old", b"x").len());
+    }
+
+    #[test]
+    fn backfill_holds_to_retention_only_if_told() {
+        for &in_backfill in &[false, true] {
+            let (mut server, _) = retaining(RetentionAction::Reject, in_backfill);
+            let records: Vec<_> = ages().iter().map(|&(ts, payload)| (ts, payload)).collect();
+            let report = server.backfill(b"old", b"x", records);
+            assert_eq!(if in_backfill { 1 } else { 0 }, report.rejected);
+            assert_eq!(if in_backfill { 3 } else { 4 }, report.accepted);
+        }
+    }
+
+    #[test]
+    fn retention_comes_before_dedup_and_accounting() {
+        let (server, clock) = retaining(RetentionAction::Reject, false);
+        let mut accounting = Accounting::new(Dedup::new(server, 16),
+                                             clock.clone(),
+                                             Duration::from_secs(DAY));
+        let (timestamp, payload) = ages()[2];
+        assert_match!(Err(ConsumeError::ExpiredPayload { .. }),
+                      accounting.consume_parts(b"old", b"x", timestamp, payload));
+        let usage = accounting.current_period().usage.values().next().unwrap().clone();
+        assert_eq!((1, 0, 0), (usage.attempted, usage.stored, usage.bytes));
+        assert_eq!(1, usage.errors.rejected);
+
+        // Nothing was stored, so the same message is no duplicate once the
+        // policy relents.
+        accounting.get_mut().get_mut().clear_retention(b"old");
+        assert!(accounting.consume_parts(b"old", b"x", timestamp, payload).is_ok());
+        assert_match!(Err(ConsumeError::Rejected("duplicate")),
+                      accounting.consume_parts(b"old", b"x", timestamp, payload));
+    }
+}
@@ -0,0 +1,367 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use message;
+use message::Header;
+use util::fill;
+#[cfg(feature = "gzip")]
+use super::CompressedReplay;
+use super::Session;
+
+/// Where a session over a seekable reader can pick up again: the start of
+/// the first frame not yet read in full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionCheckpoint {
+    pub offset: u64,
+    pub frames_consumed: u64,
+}
+
+/// The length of `SessionCheckpoint::encode`.
+pub const ENCODED_LEN: usize = 16;
+
+impl SessionCheckpoint {
+    /// The offset, then the frame count, both big-endian.
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let mut bytes = [0_u8; ENCODED_LEN];
+        BigEndian::write_u64(&mut bytes[..8], self.offset);
+        BigEndian::write_u64(&mut bytes[8..], self.frames_consumed);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; ENCODED_LEN]) -> Self {
+        SessionCheckpoint {
+            offset: BigEndian::read_u64(&bytes[..8]),
+            frames_consumed: BigEndian::read_u64(&bytes[8..]),
+        }
+    }
+}
+
+/// The reader does not hold a plausible frame at the checkpoint, most
+/// likely because it changed since.
+#[derive(Debug)]
+pub enum ResumeError {
+    Io(io::Error),
+    /// The reader ends partway through the frame at the checkpoint.
+    Truncated {
+        offset: u64,
+    },
+    /// The frame at the checkpoint is too short to hold a header.
+    ImplausibleSize {
+        offset: u64,
+        size: u16,
+    },
+    Parse {
+        offset: u64,
+        error: message::Error,
+    },
+}
+
+impl From<io::Error> for ResumeError {
+    fn from(e: io::Error) -> Self {
+        ResumeError::Io(e)
+    }
+}
+
+impl ResumeError {
+    /// The kind of an I/O error, and `InvalidData` otherwise.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            ResumeError::Io(ref e) => e.kind(),
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(self.io_kind(), self)
+    }
+}
+
+impl Display for ResumeError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ResumeError::Io(ref e) => e.fmt(f),
+            ResumeError::Truncated { offset } => {
+                write!(f, "frame at checkpoint offset {} is truncated", offset)
+            }
+            ResumeError::ImplausibleSize { offset, size } => {
+                write!(f, "frame at checkpoint offset {} has implausible size {}", offset, size)
+            }
+            ResumeError::Parse { offset, ref error } => {
+                write!(f, "frame at checkpoint offset {}: {}", offset, error)
+            }
+        }
+    }
+}
+
+impl error::Error for ResumeError {
+    fn description(&self) -> &str {
+        match *self {
+            ResumeError::Io(ref e) => e.description(),
+            ResumeError::Truncated { .. } => "frame at checkpoint is truncated",
+            ResumeError::ImplausibleSize { .. } => "frame at checkpoint has implausible size",
+            ResumeError::Parse { .. } => "frame at checkpoint does not parse",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ResumeError::Io(ref e) => Some(e),
+            ResumeError::Parse { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that `reader` ends or holds a frame whose header parses.
+fn validate<R: Read>(reader: &mut R, offset: u64) -> Result<(), ResumeError> {
+    let mut prefix = [0_u8; 2];
+    match try!(fill(reader, &mut prefix)) {
+        0 => return Ok(()),
+        1 => return Err(ResumeError::Truncated { offset: offset }),
+        _ => {}
+    }
+    let size = BigEndian::read_u16(&prefix);
+    // The token size, ID size and timestamp.
+    if size < 12 {
+        return Err(ResumeError::ImplausibleSize {
+            offset: offset,
+            size: size,
+        });
+    }
+    let mut head = vec![];
+    loop {
+        let wanted = cmp::min(Header::extent(&head), size as usize);
+        if head.len() == wanted {
+            break;
+        }
+        let start = head.len();
+        head.resize(wanted, 0);
+        if try!(fill(reader, &mut head[start..])) < wanted - start {
+            return Err(ResumeError::Truncated { offset: offset });
+        }
+    }
+    Header::parse(&head).map(|_| ()).map_err(|e| {
+        ResumeError::Parse {
+            offset: offset,
+            error: e,
+        }
+    })
+}
+
+impl<'a, S: 'a, R> Session<'a, S, R> {
+    /// Where to resume after the frames read so far. A frame `try_next` has
+    /// only partly read is left for the resumed session to read again.
+    pub fn checkpoint(&self) -> SessionCheckpoint {
+        SessionCheckpoint {
+            offset: self.offset,
+            frames_consumed: self.frames,
+        }
+    }
+}
+
+impl<'a, S: 'a, R: Read + Seek> Session<'a, S, R> {
+    /// A session over `reader` from `checkpoint` on, once the frame there
+    /// looks like one. The preamble, if any, is taken as read; settings
+    /// such as strictness are the new session's defaults.
+    pub fn resume(server: &'a mut S,
+                  mut reader: R,
+                  checkpoint: &SessionCheckpoint)
+                  -> Result<Self, ResumeError> {
+        try!(reader.seek(SeekFrom::Start(checkpoint.offset)));
+        try!(validate(&mut reader, checkpoint.offset));
+        try!(reader.seek(SeekFrom::Start(checkpoint.offset)));
+        let mut session = Session::new(server, reader);
+        session.preamble_read = true;
+        session.offset = checkpoint.offset;
+        session.frames = checkpoint.frames_consumed;
+        Ok(session)
+    }
+}
+
+/// Reads from `reader`, keeping a copy of everything read.
+#[cfg(feature = "gzip")]
+struct Kept<'r, R: 'r> {
+    reader: &'r mut R,
+    kept: Vec<u8>,
+}
+
+#[cfg(feature = "gzip")]
+impl<'r, R: Read> Read for Kept<'r, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.reader.read(buf));
+        self.kept.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl<'a, S: 'a, R: Read> Session<'a, S, CompressedReplay<R>> {
+    /// `resume` for a capture that may be gzipped, taking the checkpoint's
+    /// offset as into the capture once inflated, as a session over a
+    /// `CompressedReplay` counts it. A gzipped capture cannot seek, so
+    /// everything before the checkpoint is inflated and skipped; errors doing
+    /// so are `ReplayError`s, with both offsets.
+    pub fn resume_replay(server: &'a mut S,
+                         reader: R,
+                         checkpoint: &SessionCheckpoint)
+                         -> Result<Self, ResumeError> {
+        let mut replay = CompressedReplay::new(reader);
+        let skipped = try!(io::copy(&mut replay.by_ref().take(checkpoint.offset),
+                                    &mut io::sink()));
+        if skipped < checkpoint.offset {
+            return Err(ResumeError::Truncated { offset: checkpoint.offset });
+        }
+        let kept = {
+            let mut kept = Kept {
+                reader: &mut replay,
+                kept: vec![],
+            };
+            try!(validate(&mut kept, checkpoint.offset));
+            kept.kept
+        };
+        let mut session = Session::new(server, replay);
+        session.unread = kept;
+        session.preamble_read = true;
+        session.offset = checkpoint.offset;
+        session.frames = checkpoint.frames_consumed;
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::iter;
+
+    use super::*;
+    use session::PeerInfo;
+    use session::PreamblePolicy;
+    use test_support::frame;
+    use {test_support, Session};
+
+    fn file() -> Vec<u8> {
+        let mut file = PeerInfo {
+                           version: 1,
+                           name: b"test".to_vec(),
+                           strictness: None,
+                       }
+                       .to_bytes();
+        for i in 0..6 {
+            let id: &[u8] = if i % 3 == 2 { b"unknown" } else { b"id" };
+            file.extend(frame(b"t", id, 1, &[i; 5]));
+        }
+        file
+    }
+
+    fn server() -> test_support::server::Ok<test_support::stream::Ok> {
+        test_support::server::Ok(iter::once((b"id".to_vec(), test_support::stream::Ok)).collect())
+    }
+
+    #[test]
+    fn resume_matches_straight_through() {
+        let file = file();
+        let mut server = server();
+        let straight: Vec<_> = {
+            let mut session = Session::new(&mut server, Cursor::new(&file[..]));
+            session.set_preamble_policy(PreamblePolicy::Optional);
+            session.by_ref().map(|item| format!("{:?}", item)).collect()
+        };
+        assert_eq!(6, straight.len());
+
+        let checkpoint = {
+            let mut session = Session::new(&mut server, Cursor::new(&file[..]));
+            session.set_preamble_policy(PreamblePolicy::Optional);
+            for _ in 0..3 {
+                session.next();
+            }
+            SessionCheckpoint::decode(&session.checkpoint().encode())
+        };
+        assert_eq!(3, checkpoint.frames_consumed);
+
+        let mut session = Session::resume(&mut server, Cursor::new(&file[..]), &checkpoint)
+                              .unwrap();
+        let rest: Vec<_> = session.by_ref().map(|item| format!("{:?}", item)).collect();
+        assert_eq!(&straight[3..], &rest[..]);
+        assert_eq!(6, session.frames_consumed());
+        assert_eq!(file.len() as u64, session.checkpoint().offset);
+    }
+
+    #[test]
+    fn changed_file() {
+        let mut file = file();
+        let mut server = server();
+        let checkpoint = {
+            let mut session = Session::new(&mut server, Cursor::new(&file[..]));
+            session.set_preamble_policy(PreamblePolicy::Optional);
+            session.next();
+            session.checkpoint()
+        };
+        let offset = checkpoint.offset as usize;
+
+        file[offset + 2] = 0xff;
+        match Session::resume(&mut server, Cursor::new(&file[..]), &checkpoint) {
+            Err(ResumeError::Parse { offset: o, .. }) => assert_eq!(offset as u64, o),
+            _ => panic!("expected a parse failure"),
+        }
+
+        file[offset] = 0;
+        file[offset + 1] = 3;
+        match Session::resume(&mut server, Cursor::new(&file[..]), &checkpoint) {
+            Err(ResumeError::ImplausibleSize { size: 3, .. }) => {}
+            _ => panic!("expected an implausible size"),
+        }
+
+        file.truncate(offset + 5);
+        file[offset + 1] = 20;
+        match Session::resume(&mut server, Cursor::new(&file[..]), &checkpoint) {
+            Err(ResumeError::Truncated { .. }) => {}
+            _ => panic!("expected a truncated frame"),
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn resume_replay_matches_straight_through() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use session::CompressedReplay;
+
+        let file = file();
+        let mut encoder = GzEncoder::new(vec![], Compression::Default);
+        encoder.write_all(&file).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut server = server();
+        let straight: Vec<_> = {
+            let mut session = Session::new(&mut server, CompressedReplay::new(&compressed[..]));
+            session.set_preamble_policy(PreamblePolicy::Optional);
+            session.by_ref().map(|item| format!("{:?}", item)).collect()
+        };
+        let checkpoint = {
+            let mut session = Session::new(&mut server, CompressedReplay::new(&compressed[..]));
+            session.set_preamble_policy(PreamblePolicy::Optional);
+            for _ in 0..4 {
+                session.next();
+            }
+            session.checkpoint()
+        };
+
+        for input in vec![&compressed[..], &file[..]] {
+            let mut session = Session::resume_replay(&mut server, input, &checkpoint).unwrap();
+            let rest: Vec<_> = session.by_ref().map(|item| format!("{:?}", item)).collect();
+            assert_eq!(&straight[4..], &rest[..]);
+            assert_eq!(file.len() as u64, session.checkpoint().offset);
+        }
+
+        let short = &file[..checkpoint.offset as usize - 1];
+        match Session::resume_replay(&mut server, short, &checkpoint) {
+            Err(ResumeError::Truncated { offset }) => assert_eq!(checkpoint.offset, offset),
+            _ => panic!("expected a truncated capture"),
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Deferred auth: a session whose server cannot yet decide on a token, as
+//! when it asks a remote policy service, parks that token's frames and
+//! carries on with the rest until the application resolves the server's
+//! ticket.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use message::Message;
+use server::{AuthError, AuthTicket, ConsumeError};
+use stream::Pressure;
+use trace::Spans;
+use {Clock, Server, Stream};
+use super::{parse_frame, Accepted, Error, NextResult, Session};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeferredAuth {
+    /// Bytes of frames, prefixes aside, that may be parked for a token.
+    pub max_parked_bytes: usize,
+    /// Bytes of frames that may be parked for every token together, so
+    /// that many pending tokens cannot hold more than this between them.
+    pub max_total_parked_bytes: usize,
+    /// How long a ticket may go unresolved before its frames expire.
+    pub timeout: Duration,
+}
+
+impl Default for DeferredAuth {
+    fn default() -> Self {
+        DeferredAuth {
+            max_parked_bytes: 64 << 10,
+            max_total_parked_bytes: 1 << 20,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Parked {
+    token: Vec<u8>,
+    since: Duration,
+    bytes: usize,
+    frames: Vec<Vec<u8>>,
+}
+
+/// The frames a session has parked, by ticket.
+pub struct Parking {
+    config: DeferredAuth,
+    clock: Arc<Clock + Send + Sync>,
+    tickets: BTreeMap<AuthTicket, Parked>,
+    /// The ticket of each expired frame yet to be reported.
+    expired: VecDeque<AuthTicket>,
+}
+
+impl Parking {
+    fn ticket_for(&self, token: &[u8]) -> Option<AuthTicket> {
+        self.tickets.iter().find(|&(_, parked)| parked.token == token).map(|(&ticket, _)| ticket)
+    }
+
+    /// Parks `frame` on `ticket`, unless that would exceed either cap.
+    fn park<A, P>(&mut self,
+                  ticket: AuthTicket,
+                  token: &[u8],
+                  frame: &[u8])
+                  -> Result<(), Error<A, P>> {
+        let (mut parked, mut total) = (0, 0);
+        for other in self.tickets.values() {
+            if other.token == token {
+                parked += other.bytes;
+            }
+            total += other.bytes;
+        }
+        if frame.len() > self.config.max_parked_bytes - parked {
+            return Err(Error::ParkedFull {
+                cap: self.config.max_parked_bytes,
+                total: false,
+            });
+        }
+        if frame.len() > self.config.max_total_parked_bytes.saturating_sub(total) {
+            return Err(Error::ParkedFull {
+                cap: self.config.max_total_parked_bytes,
+                total: true,
+            });
+        }
+        let now = self.clock.now();
+        let parked = self.tickets.entry(ticket).or_insert_with(|| {
+            Parked {
+                token: token.to_vec(),
+                since: now,
+                bytes: 0,
+                frames: vec![],
+            }
+        });
+        parked.bytes += frame.len();
+        parked.frames.push(frame.to_vec());
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let now = self.clock.now();
+        let timeout = self.config.timeout;
+        let expired: Vec<_> = self.tickets
+                                  .iter()
+                                  .filter(|&(_, parked)| {
+                                      now >= parked.since && now - parked.since >= timeout
+                                  })
+                                  .map(|(&ticket, _)| ticket)
+                                  .collect();
+        for ticket in expired {
+            let parked = self.tickets.remove(&ticket).unwrap();
+            self.expired.extend(parked.frames.iter().map(|_| ticket));
+        }
+    }
+
+    /// The ticket of the next expired frame to report, expiring any now due.
+    pub fn next_expired(&mut self) -> Option<AuthTicket> {
+        self.expire();
+        self.expired.pop_front()
+    }
+}
+
+/// `handle`, except that with parking, a frame is parked instead if the
+/// server returns `AuthError::Pending` for its token or its token already
+/// has frames parked, and then `None` is returned. A consumed message is
+/// returned as `keep` makes of it and its acknowledgement, with the
+/// pressure on its stream left in `pressure`.
+pub fn handle<'b, S, T, F>(server: &mut S,
+                           parking: &mut Option<Parking>,
+                           timestamp: &mut Option<Duration>,
+                           bytes: &'b [u8],
+                           parsed: Result<Message<'b>,
+                                          Error<S::AuthErr, <S::Stream as Stream>::PushErr>>,
+                           spans: &mut Spans,
+                           pressure: &mut Pressure,
+                           keep: F)
+                           -> Option<Result<T, Error<S::AuthErr, <S::Stream as Stream>::PushErr>>>
+    where S: Server,
+          F: FnOnce(&Message, S::ConsumeOk) -> T
+{
+    let msg = match parsed {
+        Ok(msg) => msg,
+        Err(e) => return Some(Err(e)),
+    };
+    let token = msg.header.token;
+    if let Some(ref mut parking) = *parking {
+        if let Some(ticket) = parking.ticket_for(token) {
+            return parking.park(ticket, token, bytes).err().map(Err);
+        }
+    }
+    match super::consume_parsed(server, timestamp, msg, spans) {
+        Ok((msg, ack, pressed)) => {
+            *pressure = pressed;
+            Some(Ok(keep(&msg, ack)))
+        }
+        Err(Error::Consume(ConsumeError::Auth(AuthError::Pending(ticket))))
+            if parking.is_some() => {
+            parking.as_mut().and_then(|parking| parking.park(ticket, token, bytes).err().map(Err))
+        }
+        Err(e) => Some(Err(e)),
+    }
+}
+
+impl<'a, S: 'a + Server, R> Session<'a, S, R> {
+    /// Parks frames the server returns `AuthError::Pending` for, and any
+    /// later frames for the same token, rather than failing them, for
+    /// `resolve_auth` to consume. Frames of tickets unresolved for the
+    /// timeout, read from `clock`, each come out as `Error::AuthExpired`
+    /// before the next frame is read.
+    pub fn set_deferred_auth(&mut self, config: DeferredAuth, clock: Arc<Clock + Send + Sync>) {
+        self.parking = Some(Parking {
+            config: config,
+            clock: clock,
+            tickets: BTreeMap::new(),
+            expired: VecDeque::new(),
+        });
+    }
+
+    /// How many frames are parked, and how many bytes they take.
+    pub fn parked(&self) -> (usize, usize) {
+        self.parking.as_ref().map_or((0, 0), |parking| {
+            parking.tickets
+                   .values()
+                   .fold((0, 0), |(frames, bytes), parked| {
+                       (frames + parked.frames.len(), bytes + parked.bytes)
+                   })
+        })
+    }
+
+    /// Consumes the frames parked on `ticket` in the order they came, if
+    /// it is approved, and otherwise fails each of them for an invalid
+    /// token, returning an outcome per frame. The server must by now
+    /// decide the token without deferring it again, or the frames are
+    /// parked anew. Expired and unknown tickets have no frames.
+    pub fn resolve_auth(&mut self, ticket: AuthTicket, approved: bool) -> Vec<NextResult<S>> {
+        let parked = match self.parking.as_mut() {
+            None => return vec![],
+            Some(parking) => {
+                parking.expire();
+                match parking.tickets.remove(&ticket) {
+                    None => return vec![],
+                    Some(parked) => parked,
+                }
+            }
+        };
+        let mut results = Vec::with_capacity(parked.frames.len());
+        for frame in &parked.frames {
+            if !approved {
+                results.push(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken))));
+                continue;
+            }
+            let mut pressure = Pressure::None;
+            // Parked only once its header parsed, a frame parses again, so
+            // its prefix has no offset to shift.
+            let parsed = parse_frame(self.strictness,
+                                     self.capture_window,
+                                     0,
+                                     frame,
+                                     &mut Spans::off());
+            if let Some(result) = handle(&mut *self.server,
+                                         &mut self.parking,
+                                         &mut self.timestamp,
+                                         frame,
+                                         parsed,
+                                         &mut Spans::off(),
+                                         &mut pressure,
+                                         |msg, ack| {
+                                             Accepted::of(&msg.header, msg.payload.len(), ack)
+                                         }) {
+                if result.is_ok() {
+                    self.pressed(pressure);
+                }
+                results.push(result);
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::{AuthResult, Consumed, Finder};
+    use session::{Session, TryNext};
+    use test_support::frame;
+    use test_support::stream::ScriptedStream;
+    use Server;
+
+    type Stream = ScriptedStream<(), ()>;
+
+    /// Defers every token starting with "slow" until told what to make of
+    /// it, with one ticket per token.
+    struct Deciding {
+        finder: Finder<Stream>,
+        tickets: HashMap<Vec<u8>, AuthTicket>,
+        decided: HashMap<Vec<u8>, bool>,
+    }
+
+    impl Deciding {
+        fn new() -> Self {
+            let mut finder = Finder::new();
+            finder.insert(b"a".to_vec(), Stream::default());
+            finder.insert(b"b".to_vec(), Stream::default());
+            Deciding {
+                finder: finder,
+                tickets: HashMap::new(),
+                decided: HashMap::new(),
+            }
+        }
+
+        fn pushed(&self, id: &[u8]) -> Vec<Vec<u8>> {
+            self.finder[id].pushed().iter().map(|&(_, ref payload)| payload.clone()).collect()
+        }
+    }
+
+    impl Server for Deciding {
+        type Stream = Stream;
+        type AuthErr = ();
+        fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+            match self.decided.get(token) {
+                Some(&true) => return Ok(&mut self.finder),
+                Some(&false) => return Err(AuthError::InvalidToken),
+                None => {}
+            }
+            if !token.starts_with(b"slow") {
+                return Ok(&mut self.finder);
+            }
+            let next = AuthTicket(self.tickets.len() as u64 + 1);
+            Err(AuthError::Pending(*self.tickets.entry(token.to_vec()).or_insert(next)))
+        }
+
+        type ConsumeOk = ();
+        fn consume_parts_traced(&mut self,
+                                token: &[u8],
+                                id: &[u8],
+                                timestamp: Duration,
+                                payload: &[u8],
+                                _: &mut Spans)
+                                -> Consumed<Self> {
+            self.push_parts(token, id, timestamp, payload)
+        }
+    }
+
+    fn frames(frames: &[(&[u8], &[u8], &[u8])]) -> Cursor<Vec<u8>> {
+        Cursor::new(frames.iter()
+                          .enumerate()
+                          .flat_map(|(i, &(token, id, payload))| {
+                              frame(token, id, i as u64, payload)
+                          })
+                          .collect())
+    }
+
+    fn mixed() -> Cursor<Vec<u8>> {
+        frames(&[(b"slow", b"a", b"1"),
+                 (b"fast", b"b", b"2"),
+                 (b"slow", b"a", b"3"),
+                 (b"fast", b"b", b"4"),
+                 (b"slow", b"b", b"5")])
+    }
+
+    fn deferred(max_parked_bytes: usize) -> DeferredAuth {
+        DeferredAuth {
+            max_parked_bytes: max_parked_bytes,
+            max_total_parked_bytes: 1 << 20,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn pending_then_approved() {
+        let clock = SharedClock::new(Duration::from_secs(0));
+        let mut server = Deciding::new();
+        {
+            let mut session = Session::new(&mut server, mixed());
+            session.set_deferred_auth(deferred(1024), Arc::new(clock.clone()));
+            let mut ids = vec![];
+            loop {
+                match session.try_next() {
+                    TryNext::Ready(result) => ids.push(result.unwrap().id),
+                    TryNext::NotReady => panic!("never blocks"),
+                    TryNext::Closed => break,
+                }
+            }
+            assert_eq!(vec![b"b".to_vec(), b"b".to_vec()], ids);
+            assert_eq!(3, session.parked().0);
+            assert_eq!(Some(Duration::from_millis(3)), session.last_timestamp());
+
+            session.server_mut().decided.insert(b"slow".to_vec(), true);
+            let ids: Vec<_> = session.resolve_auth(AuthTicket(1), true)
+                                     .into_iter()
+                                     .map(|result| result.unwrap().id)
+                                     .collect();
+            assert_eq!(vec![b"a".to_vec(), b"a".to_vec(), b"b".to_vec()], ids);
+            assert_eq!((0, 0), session.parked());
+            assert_eq!(Some(Duration::from_millis(4)), session.last_timestamp());
+            assert!(session.resolve_auth(AuthTicket(1), true).is_empty());
+        }
+        assert_eq!(vec![b"1".to_vec(), b"3".to_vec()], server.pushed(b"a"));
+        assert_eq!(vec![b"2".to_vec(), b"4".to_vec(), b"5".to_vec()], server.pushed(b"b"));
+    }
+
+    #[test]
+    fn pending_then_denied() {
+        let clock = SharedClock::new(Duration::from_secs(0));
+        let mut server = Deciding::new();
+        {
+            let mut session = Session::new(&mut server, mixed());
+            session.set_deferred_auth(deferred(1024), Arc::new(clock.clone()));
+            assert_eq!(2, session.by_ref().map(Result::unwrap).count());
+            session.server_mut().decided.insert(b"slow".to_vec(), false);
+            let denied = session.resolve_auth(AuthTicket(1), false);
+            assert_eq!(3, denied.len());
+            for result in denied {
+                assert_match!(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken))),
+                              result);
+            }
+            assert_eq!((0, 0), session.parked());
+        }
+        assert!(server.pushed(b"a").is_empty());
+        assert_eq!(vec![b"2".to_vec(), b"4".to_vec()], server.pushed(b"b"));
+    }
+
+    #[test]
+    fn unresolved_ticket_expires() {
+        let clock = SharedClock::new(Duration::from_secs(0));
+        let mut server = Deciding::new();
+        let mut session = Session::new(&mut server,
+                                       frames(&[(b"slow", b"a", b"1"), (b"slow", b"a", b"2")]));
+        session.set_deferred_auth(deferred(1024), Arc::new(clock.clone()));
+        assert!(session.next().is_none());
+        clock.advance(Duration::from_secs(29));
+        assert!(session.next().is_none());
+        assert_eq!(2, session.parked().0);
+
+        clock.advance(Duration::from_secs(1));
+        assert_match!(Some(Err(Error::AuthExpired(AuthTicket(1)))), session.next());
+        assert_match!(Some(Err(Error::AuthExpired(AuthTicket(1)))), session.next());
+        assert!(session.next().is_none());
+        assert_eq!((0, 0), session.parked());
+        assert!(session.resolve_auth(AuthTicket(1), true).is_empty());
+    }
+
+    #[test]
+    fn parked_bytes_capped() {
+        let clock = SharedClock::new(Duration::from_secs(0));
+        let mut server = Deciding::new();
+        // Each message is 18 bytes: room for two per token.
+        let mut session = Session::new(&mut server,
+                                       frames(&[(b"slow", b"a", b"1"),
+                                                (b"slow", b"a", b"2"),
+                                                (b"slow", b"a", b"3"),
+                                                (b"slow2", b"a", b"4"),
+                                                (b"fast", b"a", b"5")]));
+        session.set_deferred_auth(deferred(36), Arc::new(clock.clone()));
+        assert_match!(Some(Err(Error::ParkedFull { cap: 36, total: false })), session.next());
+        assert_eq!(Some(b"a".to_vec()), session.next().map(|result| result.unwrap().id));
+        assert!(session.next().is_none());
+        assert_eq!((3, 36 + 19), session.parked());
+    }
+
+    #[test]
+    fn parked_bytes_capped_in_all() {
+        let clock = SharedClock::new(Duration::from_secs(0));
+        let mut server = Deciding::new();
+        // Each message is 18 or 19 bytes: room for two in all, of any token.
+        let mut session = Session::new(&mut server,
+                                       frames(&[(b"slow", b"a", b"1"),
+                                                (b"slow2", b"a", b"2"),
+                                                (b"slow3", b"a", b"3"),
+                                                (b"fast", b"a", b"4")]));
+        let config = DeferredAuth { max_total_parked_bytes: 40, ..deferred(1024) };
+        session.set_deferred_auth(config, Arc::new(clock.clone()));
+        assert_match!(Some(Err(Error::ParkedFull { cap: 40, total: true })), session.next());
+        assert_eq!(Some(b"a".to_vec()), session.next().map(|result| result.unwrap().id));
+        assert!(session.next().is_none());
+        assert_eq!((2, 18 + 19), session.parked());
+    }
+
+    #[test]
+    fn pending_fails_without_deferral() {
+        let mut server = Deciding::new();
+        let mut session = Session::new(&mut server, mixed());
+        assert_match!(Some(Err(Error::Consume(ConsumeError::Auth(AuthError::Pending(ticket))))) if
+                      ticket == AuthTicket(1),
+                      session.next());
+        assert_eq!(Some(b"b".to_vec()), session.next().map(|result| result.unwrap().id));
+        assert_eq!((0, 0), session.parked());
+        assert!(session.resolve_auth(AuthTicket(1), true).is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use {Clock, Server};
+use super::{NextResult, Session, TryNext};
+use super::protocol::Input;
+
+/// Asks sessions to stop at their next frame boundary. Clones share one
+/// signal.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownToken {
+    signaled: Arc<AtomicBool>,
+    deadline: Option<Duration>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> Self {
+        ShutdownToken::default()
+    }
+
+    /// A token whose sessions give up on a frame still in flight `drain_for`
+    /// after they see the signal.
+    pub fn with_deadline(drain_for: Duration) -> Self {
+        ShutdownToken {
+            signaled: Arc::new(AtomicBool::new(false)),
+            deadline: Some(drain_for),
+        }
+    }
+
+    pub fn signal(&self) {
+        self.signaled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_signaled(&self) -> bool {
+        self.signaled.load(Ordering::SeqCst)
+    }
+
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+}
+
+/// How a session ended on a shutdown signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainOutcome {
+    /// A frame was in flight and was read in full.
+    Clean,
+    /// The frame in flight was abandoned at the token's deadline.
+    DeadlineExceeded,
+    /// No frame was in flight.
+    Idle,
+}
+
+/// An item of a `DrainingSession`.
+#[derive(Debug)]
+pub enum Drain<T> {
+    Item(T),
+    /// The last item; no further length prefix is read.
+    Drained(DrainOutcome),
+}
+
+/// A session that stops at a frame boundary once its token is signaled,
+/// finishing the frame in flight first.
+pub struct DrainingSession<'a, C, S: 'a, R> {
+    session: Session<'a, S, R>,
+    token: ShutdownToken,
+    clock: C,
+    /// Whether a frame was in flight when the signal was seen.
+    in_flight: Option<bool>,
+    deadline: Option<Duration>,
+    finished: bool,
+}
+
+impl<'a, S: 'a, R> Session<'a, S, R> {
+    /// Ends the session on `token`'s signal, timing its deadline by `clock`.
+    pub fn draining<C: Clock>(self,
+                              token: ShutdownToken,
+                              clock: C)
+                              -> DrainingSession<'a, C, S, R> {
+        DrainingSession {
+            session: self,
+            token: token,
+            clock: clock,
+            in_flight: None,
+            deadline: None,
+            finished: false,
+        }
+    }
+}
+
+impl<'a, C, S: 'a, R> DrainingSession<'a, C, S, R> {
+    pub fn get_ref(&self) -> &Session<'a, S, R> {
+        &self.session
+    }
+
+    pub fn get_mut(&mut self) -> &mut Session<'a, S, R> {
+        &mut self.session
+    }
+
+    pub fn into_inner(self) -> Session<'a, S, R> {
+        self.session
+    }
+}
+
+impl<'a, C: Clock, S: 'a + Server, R: Read> DrainingSession<'a, C, S, R> {
+    fn drained(&mut self, outcome: DrainOutcome) -> TryNext<Drain<NextResult<S>>> {
+        self.finished = true;
+        self.session.protocol.on(Input::Shutdown);
+        if outcome == DrainOutcome::DeadlineExceeded {
+            self.session.protocol.on(Input::Timeout);
+        }
+        TryNext::Ready(Drain::Drained(outcome))
+    }
+
+    /// Like `Session::try_next`, but ends with `Drain::Drained` once the
+    /// token is signaled and the frame in flight, if any, is read.
+    pub fn try_next(&mut self) -> TryNext<Drain<NextResult<S>>> {
+        if self.finished {
+            return TryNext::Closed;
+        }
+        let signaled = self.token.is_signaled();
+        if signaled {
+            let in_flight = !self.session.pending.is_empty();
+            let caught = *self.in_flight.get_or_insert(in_flight);
+            if !in_flight {
+                return self.drained(if caught { DrainOutcome::Clean } else { DrainOutcome::Idle });
+            }
+            if self.deadline.is_none() {
+                self.deadline = self.token.deadline().map(|d| self.clock.now() + d);
+            }
+            if self.deadline.map_or(false, |deadline| self.clock.now() >= deadline) {
+                self.session.pending.clear();
+                return self.drained(DrainOutcome::DeadlineExceeded);
+            }
+        }
+        match self.session.try_next() {
+            TryNext::Ready(item) => {
+                if !signaled && self.token.is_signaled() {
+                    // The signal came while this frame was being read.
+                    self.in_flight = Some(true);
+                }
+                TryNext::Ready(Drain::Item(item))
+            }
+            TryNext::NotReady => TryNext::NotReady,
+            TryNext::Closed => {
+                self.finished = true;
+                TryNext::Closed
+            }
+        }
+    }
+}
+
+/// For blocking readers: with one that would block, this spins.
+impl<'a, C: Clock, S: 'a + Server, R: Read> Iterator for DrainingSession<'a, C, S, R> {
+    type Item = Drain<NextResult<S>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next() {
+                TryNext::Ready(item) => return Some(item),
+                TryNext::NotReady => {}
+                TryNext::Closed => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::iter;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use session::{Error, TryNext};
+    use test_support::frame;
+    use {test_support, Session};
+
+    enum Step {
+        Bytes(Vec<u8>),
+        Signal,
+        Block,
+    }
+
+    /// Reads through a script, signaling when it says to.
+    struct Scripted {
+        steps: Vec<Step>,
+        token: ShutdownToken,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                if self.steps.is_empty() {
+                    return Ok(0);
+                }
+                match self.steps.remove(0) {
+                    Step::Signal => self.token.signal(),
+                    Step::Block => return Err(io::Error::new(io::ErrorKind::WouldBlock, "")),
+                    Step::Bytes(mut bytes) => {
+                        let n = ::std::cmp::min(buf.len(), bytes.len());
+                        buf[..n].copy_from_slice(&bytes[..n]);
+                        if n < bytes.len() {
+                            self.steps.insert(0, Step::Bytes(bytes.split_off(n)));
+                        }
+                        return Ok(n);
+                    }
+                }
+            }
+        }
+    }
+
+    fn server() -> test_support::server::Ok<test_support::stream::Ok> {
+        test_support::server::Ok(iter::once((b"id".to_vec(), test_support::stream::Ok)).collect())
+    }
+
+    #[test]
+    fn finishes_frame_in_flight() {
+        let (first, second) = (frame(b"t", b"id", 1, &[1, 1]), frame(b"t", b"id", 1, &[2, 2]));
+        let token = ShutdownToken::new();
+        let reader = Scripted {
+            steps: vec![Step::Bytes(first),
+                        Step::Bytes(second[..10].to_vec()),
+                        Step::Signal,
+                        Step::Bytes(second[10..].to_vec()),
+                        Step::Bytes(frame(b"t", b"id", 1, &[3, 3]))],
+            token: token.clone(),
+        };
+        let mut server = server();
+        let clock = ManualClock::new(Duration::from_millis(0));
+        let items: Vec<_> = Session::new(&mut server, reader).draining(token, &clock).collect();
+        assert_eq!(3, items.len());
+        assert_match!(&Drain::Item(Ok(ref accepted)) if accepted.id == b"id", &items[0]);
+        assert_match!(&Drain::Item(Ok(ref accepted)) if accepted.id == b"id", &items[1]);
+        assert_match!(&Drain::Drained(DrainOutcome::Clean), &items[2]);
+    }
+
+    #[test]
+    fn idle_when_signaled() {
+        let token = ShutdownToken::new();
+        let reader = Scripted {
+            steps: vec![Step::Bytes(frame(b"t", b"id", 1, &[1, 1])),
+                        Step::Block,
+                        Step::Bytes(frame(b"t", b"id", 1, &[2, 2]))],
+            token: token.clone(),
+        };
+        let mut server = server();
+        let clock = ManualClock::new(Duration::from_millis(0));
+        let mut session = Session::new(&mut server, reader).draining(token.clone(), &clock);
+        assert_match!(TryNext::Ready(Drain::Item(Ok(_))), session.try_next());
+        assert_match!(TryNext::NotReady, session.try_next());
+        token.signal();
+        assert_match!(TryNext::Ready(Drain::Drained(DrainOutcome::Idle)), session.try_next());
+        assert_match!(TryNext::Closed, session.try_next());
+    }
+
+    #[test]
+    fn deadline_exceeded() {
+        let token = ShutdownToken::with_deadline(Duration::from_secs(5));
+        let reader = Scripted {
+            steps: vec![Step::Bytes(frame(b"t", b"id", 1, &[1, 1])[..10].to_vec()),
+                        Step::Block,
+                        Step::Block],
+            token: token.clone(),
+        };
+        let mut server = server();
+        let clock = ManualClock::new(Duration::from_secs(100));
+        let mut session = Session::new(&mut server, reader).draining(token.clone(), &clock);
+        assert_match!(TryNext::NotReady, session.try_next());
+        token.signal();
+        assert_match!(TryNext::NotReady, session.try_next());
+        clock.advance(Duration::from_secs(5));
+        match session.try_next() {
+            TryNext::Ready(Drain::Drained(DrainOutcome::DeadlineExceeded)) => {}
+            TryNext::Ready(Drain::Item(Err(Error::Truncated { .. }))) => panic!("truncated"),
+            _ => panic!("expected the deadline to pass"),
+        }
+        assert_match!(TryNext::Closed, session.try_next());
+    }
+}
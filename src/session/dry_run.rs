@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use server::DryRunOutcome;
+use util::fill;
+use {Message, Server};
+
+/// Messages by what consuming them would do.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DryRunCounts {
+    pub would_store: u64,
+    pub unauthorized: u64,
+    pub missing_id: u64,
+    pub rejected: u64,
+    pub stream_cap_exceeded: u64,
+}
+
+impl DryRunCounts {
+    pub fn record<A>(&mut self, outcome: &DryRunOutcome<A>) {
+        match *outcome {
+            DryRunOutcome::WouldStore => self.would_store += 1,
+            DryRunOutcome::Unauthorized(_) => self.unauthorized += 1,
+            DryRunOutcome::MissingId => self.missing_id += 1,
+            DryRunOutcome::Rejected(_) => self.rejected += 1,
+            DryRunOutcome::StreamCapExceeded { .. } => self.stream_cap_exceeded += 1,
+        }
+    }
+}
+
+/// What consuming a whole capture would do.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DryRunSummary {
+    pub totals: DryRunCounts,
+    pub by_id: BTreeMap<Vec<u8>, DryRunCounts>,
+    /// Frames whose header would not parse.
+    pub malformed: u64,
+    /// Whether the capture ended mid-frame.
+    pub truncated: bool,
+}
+
+/// Runs every frame of a capture through `Server::dry_run`, changing
+/// nothing, and counts the outcomes.
+pub fn dry_run<S: Server, R: Read>(server: &mut S, reader: R) -> io::Result<DryRunSummary> {
+    let mut reader = reader;
+    let mut summary = DryRunSummary::default();
+    let mut frame = vec![];
+    loop {
+        let mut prefix = [0_u8; 2];
+        match try!(fill(&mut reader, &mut prefix)) {
+            0 => return Ok(summary),
+            1 => {
+                summary.truncated = true;
+                return Ok(summary);
+            }
+            _ => {}
+        }
+        let size = BigEndian::read_u16(&prefix) as usize;
+        frame.resize(size, 0);
+        if try!(fill(&mut reader, &mut frame)) < size {
+            summary.truncated = true;
+            return Ok(summary);
+        }
+        match Message::parse(&frame) {
+            Err(_) => summary.malformed += 1,
+            Ok(msg) => {
+                let id = msg.header.id;
+                let outcome = server.dry_run(msg);
+                summary.totals.record(&outcome);
+                summary.by_id
+                       .entry(id.to_vec())
+                       .or_insert_with(DryRunCounts::default)
+                       .record(&outcome);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use server::{Accounting, CapPolicy, Dedup, HighWaterMark, TokenServer};
+    use test_support::frame;
+    use {Server, Stream};
+
+    /// Counts pushes across every clone.
+    #[derive(Clone, Debug, Default)]
+    struct Pushes(Rc<Cell<u64>>);
+
+    impl Stream for Pushes {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ::Void> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<(), (Self, ::Void)> {
+            Ok(())
+        }
+    }
+
+    type Stack<'c> = Accounting<&'c ManualClock, Dedup<HighWaterMark<TokenServer<Pushes>>>>;
+
+    /// The protection state, the accounting, and the streams under each
+    /// token.
+    fn state(server: &mut Stack) -> (Vec<u8>, String, Vec<usize>) {
+        let mut protection = vec![];
+        server.get_ref().persist_protection_state(&mut protection).unwrap();
+        let streams = [&b"a"[..], b"b"]
+                          .iter()
+                          .map(|token| server.auth(token).unwrap().len())
+                          .collect();
+        (protection, format!("{:?}", server.current_period()), streams)
+    }
+
+    #[test]
+    fn side_effect_free() {
+        let pushes = Pushes::default();
+        let mut tokens = TokenServer::new();
+        tokens.add_token(b"a").insert(b"x".to_vec(), pushes.clone());
+        tokens.add_token(b"b");
+        let factory = pushes.clone();
+        tokens.set_factory(b"b", move |_| factory.clone());
+        tokens.set_stream_cap(b"b", 1, CapPolicy::Reject);
+        let clock = ManualClock::new(Duration::from_secs(0));
+        let mut server = Accounting::new(Dedup::new(HighWaterMark::new(tokens), 8),
+                                         &clock,
+                                         Duration::from_secs(60));
+        assert!(server.consume_parts(b"a", b"x", Duration::from_secs(10), b"one").is_ok());
+        assert!(server.consume_parts(b"b", b"p", Duration::from_secs(10), b"two").is_ok());
+        assert_eq!(2, pushes.0.get());
+
+        let capture = [frame(b"a", b"x", 20000, b"new"),
+                       frame(b"a", b"x", 10000, b"one"),
+                       frame(b"a", b"x", 5000, b"old"),
+                       frame(b"a", b"y", 20000, b"missing"),
+                       frame(b"c", b"x", 20000, b"unknown"),
+                       frame(b"b", b"p", 20000, b"again"),
+                       frame(b"b", b"q", 20000, b"capped"),
+                       vec![0, 3, 0, 9, 0],
+                       frame(b"a", b"x", 30000, b"cut")[..6].to_vec()]
+                          .concat();
+        let before = state(&mut server);
+        let summary = dry_run(&mut server, &capture[..]).unwrap();
+        assert_eq!(before, state(&mut server));
+        assert_eq!(2, pushes.0.get());
+
+        let counts = |would_store, unauthorized, missing_id, rejected, stream_cap_exceeded| {
+            DryRunCounts {
+                would_store: would_store,
+                unauthorized: unauthorized,
+                missing_id: missing_id,
+                rejected: rejected,
+                stream_cap_exceeded: stream_cap_exceeded,
+            }
+        };
+        assert_eq!(DryRunSummary {
+                       totals: counts(2, 1, 1, 2, 1),
+                       by_id: vec![(b"p".to_vec(), counts(1, 0, 0, 0, 0)),
+                                   (b"q".to_vec(), counts(0, 0, 0, 0, 1)),
+                                   (b"x".to_vec(), counts(1, 1, 0, 2, 0)),
+                                   (b"y".to_vec(), counts(0, 0, 1, 0, 0))]
+                                      .into_iter()
+                                      .collect(),
+                       malformed: 1,
+                       truncated: true,
+                   },
+                   summary);
+    }
+}
@@ -0,0 +1,373 @@
+//! Forwarding a session's items to a bounded channel without letting a slow
+//! receiver stall the reads, at the cost of dropping items, counted.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::prelude::*;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use Server;
+use super::{Error, NextResult, Session};
+
+/// What to do with an item when the channel is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Waits for room however long it takes, stalling the reads.
+    Block,
+    /// Waits at most this long for room, then drops the item.
+    BlockFor(Duration),
+    /// Drops the item.
+    DropNewest,
+    /// Holds up to `capacity` items in front of the channel, dropping the
+    /// oldest held to make room.
+    DropOldest {
+        capacity: usize,
+    },
+}
+
+/// Items by whether they were messages or, if not, the `Error` variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ItemKind {
+    Ok,
+    Read,
+    EofInMessageSize,
+    BadVarint,
+    Truncated,
+    TooLarge,
+    Parse,
+    Nonconforming,
+    Consume,
+    Preamble,
+    Sink,
+    AuthExpired,
+    ParkedFull,
+    ProtocolViolation,
+}
+
+impl ItemKind {
+    pub fn of<T, A, P>(item: &Result<T, Error<A, P>>) -> Self {
+        match *item {
+            Ok(_) => ItemKind::Ok,
+            Err(Error::Read(_)) => ItemKind::Read,
+            Err(Error::EofInMessageSize) => ItemKind::EofInMessageSize,
+            Err(Error::BadVarint) => ItemKind::BadVarint,
+            Err(Error::Truncated { .. }) => ItemKind::Truncated,
+            Err(Error::TooLarge { .. }) => ItemKind::TooLarge,
+            Err(Error::Parse { .. }) => ItemKind::Parse,
+            Err(Error::Nonconforming(_)) => ItemKind::Nonconforming,
+            Err(Error::Consume(_)) => ItemKind::Consume,
+            Err(Error::Preamble(_)) => ItemKind::Preamble,
+            Err(Error::Sink(_)) => ItemKind::Sink,
+            Err(Error::AuthExpired(_)) => ItemKind::AuthExpired,
+            Err(Error::ParkedFull { .. }) => ItemKind::ParkedFull,
+            Err(Error::ProtocolViolation { .. }) => ItemKind::ProtocolViolation,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardSummary {
+    /// Items the session yielded.
+    pub items: u64,
+    pub forwarded: u64,
+    pub dropped: BTreeMap<ItemKind, u64>,
+    /// The most items held in front of the channel at once.
+    pub max_backlog: usize,
+    /// Whether forwarding stopped early because the receiver hung up.
+    pub disconnected: bool,
+}
+
+impl ForwardSummary {
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped.values().sum()
+    }
+
+    fn drop<T, A, P>(&mut self, item: &Result<T, Error<A, P>>) {
+        *self.dropped.entry(ItemKind::of(item)).or_insert(0) += 1;
+    }
+}
+
+/// Sends every item of `session` to `sink` until the session ends or the
+/// receiver hangs up, as `policy` says to when the channel is full. Only
+/// `Block` and `BlockFor` ever hold up the reads. Items still held under
+/// `DropOldest` when the session ends are sent, waiting as need be.
+pub fn forward<'a, S, R>(session: Session<'a, S, R>,
+                         sink: SyncSender<NextResult<S>>,
+                         policy: OverflowPolicy)
+                         -> ForwardSummary
+    where S: 'a + Server,
+          R: Read
+{
+    let mut summary = ForwardSummary::default();
+    let mut held = VecDeque::new();
+    for item in session {
+        summary.items += 1;
+        let sent = match policy {
+            OverflowPolicy::Block => {
+                let sent = match sink.try_send(item) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(item)) => {
+                        summary.max_backlog = cmp::max(summary.max_backlog, 1);
+                        sink.send(item).map_err(|_| ())
+                    }
+                    Err(TrySendError::Disconnected(_)) => Err(()),
+                };
+                if sent.is_ok() {
+                    summary.forwarded += 1;
+                }
+                sent
+            }
+            OverflowPolicy::BlockFor(timeout) => send_within(&sink, item, timeout, &mut summary),
+            OverflowPolicy::DropNewest => {
+                match sink.try_send(item) {
+                    Ok(()) => {
+                        summary.forwarded += 1;
+                        Ok(())
+                    }
+                    Err(TrySendError::Full(item)) => {
+                        summary.drop(&item);
+                        Ok(())
+                    }
+                    Err(TrySendError::Disconnected(_)) => Err(()),
+                }
+            }
+            OverflowPolicy::DropOldest { capacity } => {
+                held.push_back(item);
+                let sent = flush(&sink, &mut held, &mut summary);
+                if held.len() > capacity {
+                    let oldest = held.pop_front().unwrap();
+                    summary.drop(&oldest);
+                }
+                summary.max_backlog = cmp::max(summary.max_backlog, held.len());
+                sent
+            }
+        };
+        if sent.is_err() {
+            summary.disconnected = true;
+            return summary;
+        }
+    }
+    for item in held {
+        if sink.send(item).is_err() {
+            summary.disconnected = true;
+            break;
+        }
+        summary.forwarded += 1;
+    }
+    summary
+}
+
+/// Sends as many held items as there is room for, oldest first.
+fn flush<T>(sink: &SyncSender<T>,
+            held: &mut VecDeque<T>,
+            summary: &mut ForwardSummary)
+            -> Result<(), ()> {
+    while let Some(item) = held.pop_front() {
+        match sink.try_send(item) {
+            Ok(()) => summary.forwarded += 1,
+            Err(TrySendError::Full(item)) => {
+                held.push_front(item);
+                break;
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(()),
+        }
+    }
+    Ok(())
+}
+
+/// Sends `item`, polling for room for up to `timeout` and dropping it if
+/// none comes.
+fn send_within<T, A, P>(sink: &SyncSender<Result<T, Error<A, P>>>,
+                        item: Result<T, Error<A, P>>,
+                        timeout: Duration,
+                        summary: &mut ForwardSummary)
+                        -> Result<(), ()> {
+    let start = Instant::now();
+    let mut item = item;
+    let mut pause = Duration::from_millis(0);
+    loop {
+        match sink.try_send(item) {
+            Ok(()) => {
+                summary.forwarded += 1;
+                return Ok(());
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(()),
+            Err(TrySendError::Full(unsent)) => item = unsent,
+        }
+        let waited = start.elapsed();
+        if waited >= timeout {
+            summary.drop(&item);
+            return Ok(());
+        }
+        summary.max_backlog = cmp::max(summary.max_backlog, 1);
+        pause = cmp::min(cmp::max(pause * 2, Duration::from_millis(1)), timeout - waited);
+        thread::sleep(pause);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::sync::mpsc::{Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use server::Finder;
+    use session::Accepted;
+    use test_support::frame;
+    use test_support::server;
+    use test_support::stream::ScriptedStream;
+
+    type Item = Result<Vec<u8>, ItemKind>;
+
+    /// Counts its reads, and says when it first reaches the end.
+    struct Gated<R> {
+        inner: R,
+        reads: Rc<Cell<usize>>,
+        eof: Option<Sender<()>>,
+    }
+
+    impl<R: Read> Read for Gated<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            let n = try!(self.inner.read(buf));
+            if n == 0 {
+                if let Some(eof) = self.eof.take() {
+                    let _ = eof.send(());
+                }
+            }
+            Ok(n)
+        }
+    }
+
+    /// Ten frames for IDs "0" to "9", of which "3" and "7" are missing.
+    fn traffic() -> Cursor<Vec<u8>> {
+        Cursor::new((0..10).flat_map(|i| frame(b"t", i.to_string().as_bytes(), i, b"x")).collect())
+    }
+
+    fn expected(ids: &[u64]) -> Vec<Item> {
+        ids.iter()
+           .map(|&i| match i {
+               3 | 7 => Err(ItemKind::Consume),
+               i => Ok(i.to_string().into_bytes()),
+           })
+           .collect()
+    }
+
+    /// Forwards `traffic()` through a channel of two, received only once
+    /// the reads reach the end, or from the start item by item, slowly,
+    /// unless `gated`. Returns the summary, what was received, and how many
+    /// reads there were.
+    fn run(policy: OverflowPolicy, gated: bool) -> (ForwardSummary, Vec<Item>, usize) {
+        let mut finder = Finder::new();
+        for i in (0..10).filter(|&i| i != 3 && i != 7) {
+            finder.insert(i.to_string().into_bytes(), ScriptedStream::<(), ()>::default());
+        }
+        let mut server = server::Ok(finder);
+        let (eof_tx, eof_rx) = mpsc::channel();
+        let reads = Rc::new(Cell::new(0));
+        let reader = Gated {
+            inner: traffic(),
+            reads: reads.clone(),
+            eof: Some(eof_tx),
+        };
+        let (tx, rx) = mpsc::sync_channel(2);
+        let receiver = thread::spawn(move || receive(rx, if gated { Some(eof_rx) } else { None }));
+        let summary = forward(Session::new(&mut server, reader), tx, policy);
+        (summary, receiver.join().unwrap(), reads.get())
+    }
+
+    fn receive<A, P>(rx: Receiver<Result<Accepted, Error<A, P>>>,
+                     gate: Option<Receiver<()>>)
+                     -> Vec<Item> {
+        let slow = match gate {
+            Some(gate) => {
+                gate.recv().unwrap();
+                false
+            }
+            None => true,
+        };
+        rx.iter()
+          .map(|item| {
+              if slow {
+                  thread::sleep(Duration::from_millis(1));
+              }
+              item.map(|accepted| accepted.id).map_err(|e| ItemKind::of::<(), _, _>(&Err(e)))
+          })
+          .collect()
+    }
+
+    fn dropped(ok: u64, consume: u64) -> BTreeMap<ItemKind, u64> {
+        vec![(ItemKind::Ok, ok), (ItemKind::Consume, consume)]
+            .into_iter()
+            .filter(|&(_, n)| n > 0)
+            .collect()
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (summary, received, _) = run(OverflowPolicy::DropNewest, true);
+        assert_eq!(expected(&[0, 1]), received);
+        assert_eq!(ForwardSummary {
+                       items: 10,
+                       forwarded: 2,
+                       dropped: dropped(6, 2),
+                       max_backlog: 0,
+                       disconnected: false,
+                   },
+                   summary);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (summary, received, _) = run(OverflowPolicy::DropOldest { capacity: 3 }, true);
+        assert_eq!(expected(&[0, 1, 7, 8, 9]), received);
+        assert_eq!(ForwardSummary {
+                       items: 10,
+                       forwarded: 5,
+                       dropped: dropped(4, 1),
+                       max_backlog: 3,
+                       disconnected: false,
+                   },
+                   summary);
+    }
+
+    #[test]
+    fn block_for() {
+        let (summary, received, _) = run(OverflowPolicy::BlockFor(Duration::from_millis(2)), true);
+        assert_eq!(expected(&[0, 1]), received);
+        assert_eq!((2, dropped(6, 2)), (summary.forwarded, summary.dropped));
+    }
+
+    #[test]
+    fn block() {
+        let (summary, received, _) = run(OverflowPolicy::Block, false);
+        assert_eq!(expected(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]), received);
+        assert_eq!((10, 0), (summary.forwarded, summary.dropped_total()));
+    }
+
+    #[test]
+    fn slow_receiver_does_not_change_reads() {
+        let (_, _, unhindered) = run(OverflowPolicy::Block, false);
+        for &policy in &[OverflowPolicy::DropNewest, OverflowPolicy::DropOldest { capacity: 3 }] {
+            assert_eq!(unhindered, run(policy, true).2);
+        }
+    }
+
+    #[test]
+    fn stops_when_receiver_hangs_up() {
+        let mut server = server::Ok(Finder::<ScriptedStream<(), ()>>::new());
+        let (tx, rx) = mpsc::sync_channel(2);
+        drop(rx);
+        let summary = forward(Session::new(&mut server, traffic()), tx, OverflowPolicy::DropNewest);
+        assert_eq!((1, 0, true), (summary.items, summary.forwarded, summary.disconnected));
+    }
+}
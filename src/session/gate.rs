@@ -0,0 +1,254 @@
+//! Authenticating a frame's token before its body is read, so that a
+//! sender without a valid token can make a session allocate no more than
+//! the longest token it lets through.
+
+use std::cmp;
+use std::io::prelude::*;
+
+use message::Header;
+use server::{AuthError, ConsumeError};
+use {Server, Stream};
+use super::{protocol, truncated, Error, Session};
+
+/// The error `next` is to return instead of reading a frame any further,
+/// or `None` if it is to return `None`.
+type Returned<S> = Option<Error<<S as Server>::AuthErr,
+                                <<S as Server>::Stream as Stream>::PushErr>>;
+
+/// Under a token gate, reads the token of a frame of `size` bytes into the
+/// session's buffer and has the server authenticate it, unless it is the
+/// token the gate last let through. Returns how much of the frame the
+/// buffer holds for `next` to read the rest after, or, if the frame was
+/// skipped or cut short, what `next` is to return.
+pub fn read_token<'a, S, R>(session: &mut Session<'a, S, R>,
+                            size: usize)
+                            -> Result<usize, Returned<S>>
+    where S: 'a + Server,
+          R: Read
+{
+    let max_token_len = match session.token_gate {
+        Some(max_token_len) if size >= 2 => max_token_len,
+        _ => return Ok(0),
+    };
+    session.size_buffer(2);
+    try!(fill(session, 0, size));
+    // The header's extent so far covers its token and the ID size after.
+    let token_end = Header::extent(&session.buffer) - 2;
+    // Frames that cannot hold their token fail to parse as usual.
+    if token_end == 2 || token_end > size {
+        return Ok(2);
+    }
+    let (refused, found) = if token_end - 2 > max_token_len {
+        (AuthError::InvalidToken, 2)
+    } else {
+        session.size_buffer(token_end);
+        try!(fill(session, 2, size));
+        if session.buffer[2..] == session.gate_passed[..] {
+            return Ok(token_end);
+        }
+        match session.server.auth(&session.buffer[2..]) {
+            Ok(_) => {
+                session.gate_passed = session.buffer[2..].to_vec();
+                return Ok(token_end);
+            }
+            Err(AuthError::Pending(_)) => return Ok(token_end),
+            Err(e) => (e, token_end),
+        }
+    };
+    try!(discard(session, found, size));
+    session.frame_read(size);
+    session.refused_at_gate += 1;
+    if let Err(e) = session.admit(protocol::Input::Data) {
+        return Err(Some(e));
+    }
+    Err(Some(Error::Consume(ConsumeError::Auth(refused))))
+}
+
+/// Fills the buffer from `start` on with part of a frame of `size` bytes.
+fn fill<'a, S, R>(session: &mut Session<'a, S, R>,
+                  start: usize,
+                  size: usize)
+                  -> Result<(), Returned<S>>
+    where S: 'a + Server,
+          R: Read
+{
+    let wanted = session.buffer.len();
+    match session.fill_buffer(start) {
+        Ok(found) if found < wanted => Err(session.cut_short(truncated(found, size))),
+        Ok(_) => Ok(()),
+        Err(e) => Err(Some(e.into())),
+    }
+}
+
+/// Reads and drops a frame of `size` bytes from `start` on, a little at a
+/// time.
+fn discard<'a, S, R>(session: &mut Session<'a, S, R>,
+                     start: usize,
+                     size: usize)
+                     -> Result<(), Returned<S>>
+    where S: 'a + Server,
+          R: Read
+{
+    let mut scratch = [0_u8; 512];
+    let mut found = start;
+    while found < size {
+        let wanted = cmp::min(size - found, scratch.len());
+        match session.fill_input(&mut scratch[..wanted]) {
+            Ok(n) if n < wanted => return Err(session.cut_short(truncated(found + n, size))),
+            Ok(n) => found += n,
+            Err(e) => return Err(Some(e.into())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use std::time::Duration;
+
+    use server::{AuthError, AuthResult, ConsumeError, Consumed, Delivery, TokenServer};
+    use session::{Error, Session};
+    use stream::{FileStream, Pressure};
+    use test_support::frame;
+    use trace::Spans;
+    use Server;
+
+    fn server() -> TokenServer<FileStream<Vec<u8>>> {
+        let mut server = TokenServer::new();
+        server.add_token(b"t").insert(b"i".to_vec(), FileStream::new(vec![]));
+        server
+    }
+
+    fn stored(server: &mut TokenServer<FileStream<Vec<u8>>>) -> Vec<u8> {
+        server.add_token(b"t")[&b"i"[..]].get_ref().clone()
+    }
+
+    #[test]
+    fn refuses_before_buffering_the_body() {
+        let big = vec![7; 60000];
+        let bytes: Vec<_> = frame(b"x", b"i", 1, &big)
+                                .into_iter()
+                                .chain(frame(b"t", b"i", 2, b"ok"))
+                                .chain(frame(&[b'y'; 100], b"i", 3, &big[..1000]))
+                                .chain(frame(b"t", b"i", 4, &big))
+                                .collect();
+        let mut server = server();
+        {
+            let mut session = Session::new(&mut server, Cursor::new(bytes));
+            session.set_token_gate(16);
+            assert_match!(Some(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)))),
+                          session.next());
+            assert!(session.buffer.capacity() < 64);
+            assert!(session.next().unwrap().is_ok());
+            // Too long to be let through, whatever the server would say.
+            assert_match!(Some(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)))),
+                          session.next());
+            assert!(session.buffer.capacity() < 64);
+            assert_match!(Some(Ok(_)), session.next());
+            assert!(session.next().is_none());
+            assert_eq!(2, session.refused_at_gate());
+            assert_eq!(4, session.frames_consumed());
+        }
+        assert!(!stored(&mut server).is_empty());
+    }
+
+    /// Counts the tokens it is asked to authenticate outside consuming.
+    struct Asked {
+        server: TokenServer<FileStream<Vec<u8>>>,
+        auths: usize,
+    }
+
+    impl Server for Asked {
+        type Stream = FileStream<Vec<u8>>;
+        type AuthErr = ::Void;
+        fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+            self.auths += 1;
+            self.server.auth(token)
+        }
+
+        type ConsumeOk = Delivery;
+        fn consume_parts_traced(&mut self,
+                                token: &[u8],
+                                id: &[u8],
+                                timestamp: Duration,
+                                payload: &[u8],
+                                spans: &mut Spans)
+                                -> Consumed<Self> {
+            self.server.consume_parts_traced(token, id, timestamp, payload, spans)
+        }
+
+        fn consume_parts_pressed(&mut self,
+                                 token: &[u8],
+                                 id: &[u8],
+                                 timestamp: Duration,
+                                 payload: &[u8],
+                                 spans: &mut Spans)
+                                 -> (Consumed<Self>, Pressure) {
+            self.server.consume_parts_pressed(token, id, timestamp, payload, spans)
+        }
+    }
+
+    #[test]
+    fn lets_a_token_through_until_it_fails_auth() {
+        let bytes: Vec<_> = (1..5).flat_map(|ts| frame(b"t", b"i", ts, b"ok")).collect();
+        let mut server = Asked {
+            server: server(),
+            auths: 0,
+        };
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_token_gate(16);
+        assert_match!(Some(Ok(_)), session.next());
+        assert_match!(Some(Ok(_)), session.next());
+        assert_eq!(1, session.server().auths);
+        session.server_mut().server.remove_token(b"t");
+        // Read whole, and refused as it is consumed.
+        assert_match!(Some(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)))),
+                      session.next());
+        assert_eq!((1, 0), (session.server().auths, session.refused_at_gate()));
+        assert_match!(Some(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)))),
+                      session.next());
+        assert_eq!((2, 1), (session.server().auths, session.refused_at_gate()));
+        assert!(session.next().is_none());
+    }
+
+    #[test]
+    fn truncated_while_refusing() {
+        let mut bytes = frame(b"x", b"i", 1, &[0; 100]);
+        bytes.truncate(50);
+        let mut server = server();
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_token_gate(16);
+        assert_match!(Some(Err(Error::Truncated { found: 48, remaining: 66 })),
+                      session.next());
+        assert!(session.next().is_none());
+    }
+
+    quickcheck_test! {
+    same_as_without_the_gate(frames: Vec<(bool, Vec<u8>)>, cut: usize; bool) {
+        let mut bytes: Vec<u8> = frames.iter()
+                                       .enumerate()
+                                       .flat_map(|(i, &(known, ref payload))| {
+                                           let token: &[u8] = if known { b"t" } else { b"x" };
+                                           frame(token, b"i", i as u64, payload)
+                                       })
+                                       .collect();
+        if !bytes.is_empty() {
+            let len = bytes.len();
+            bytes.truncate(len - cut % len);
+        }
+        let run = |gate| {
+            let mut server = server();
+            let results: Vec<_> = {
+                let mut session = Session::new(&mut server, Cursor::new(bytes.clone()));
+                if gate {
+                    session.set_token_gate(4);
+                }
+                session.by_ref().map(|result| format!("{:?}", result)).collect()
+            };
+            (results, stored(&mut server))
+        };
+        run(true) == run(false)
+    }}
+}
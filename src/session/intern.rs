@@ -0,0 +1,235 @@
+//! IDs that repeat sharing one allocation. A connection sends the same few
+//! IDs over and over, so `Session::next_interned` hands out the same
+//! `Arc<[u8]>` for each repeat instead of copying the ID for every frame.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use {Server, SmallId, Stream};
+use id::INLINE;
+use super::{Accepted, Error, Session};
+
+/// How often an `IdInterner` found the ID it was given.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InternStats {
+    pub hits: u64,
+    /// Each one an allocation.
+    pub misses: u64,
+    /// IDs let go of to make room for others.
+    pub evictions: u64,
+}
+
+impl InternStats {
+    /// The fraction of IDs that were found, or zero if none were asked for.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+/// Keeps up to some number of distinct IDs, letting go of the least
+/// recently interned to make room, so that IDs churning cannot grow it
+/// without bound. An ID let go of stays valid wherever it was handed out;
+/// it is only allocated anew the next time it is interned. To share one
+/// between sessions, put it in an `Arc<Mutex<_>>`.
+#[derive(Debug)]
+pub struct IdInterner {
+    capacity: usize,
+    /// Each ID, and when it was last interned.
+    ids: HashMap<Arc<[u8]>, u64>,
+    /// The IDs by when they were last interned.
+    recency: BTreeMap<u64, Arc<[u8]>>,
+    clock: u64,
+    stats: InternStats,
+}
+
+impl IdInterner {
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "an interner needs room for an ID");
+        IdInterner {
+            capacity: capacity,
+            ids: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            stats: InternStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn stats(&self) -> InternStats {
+        self.stats
+    }
+
+    /// The `Arc` kept for `id`, allocating one if there is none.
+    pub fn intern(&mut self, id: &[u8]) -> Arc<[u8]> {
+        self.clock += 1;
+        if let Some(last) = self.ids.get_mut(id) {
+            let id = self.recency.remove(last).expect("interned ID without a recency");
+            *last = self.clock;
+            self.recency.insert(self.clock, id.clone());
+            self.stats.hits += 1;
+            return id;
+        }
+
+        self.stats.misses += 1;
+        if self.ids.len() == self.capacity {
+            let oldest = *self.recency.keys().next().expect("full interner without IDs");
+            let evicted = self.recency.remove(&oldest).unwrap();
+            self.ids.remove(&evicted);
+            self.stats.evictions += 1;
+        }
+        let id: Arc<[u8]> = Arc::from(id);
+        self.ids.insert(id.clone(), self.clock);
+        self.recency.insert(self.clock, id.clone());
+        id
+    }
+
+    /// `id` as a `SmallId`: inline if it is short enough, without touching
+    /// the interner, and otherwise sharing the `Arc` kept for it.
+    pub fn intern_small(&mut self, id: &[u8]) -> SmallId {
+        if id.len() <= INLINE {
+            return SmallId::new(id);
+        }
+        SmallId::from(self.intern(id))
+    }
+}
+
+impl<'a, S: 'a + Server, R: Read> Session<'a, S, R> {
+    /// Like `next`, but with the ID of a consumed message from `interner`,
+    /// so that a repeated ID is not allocated again.
+    pub fn next_interned(&mut self,
+                         interner: &mut IdInterner)
+                         -> Option<Result<Accepted<S::ConsumeOk, Arc<[u8]>>,
+                                          Error<S::AuthErr, <S::Stream as Stream>::PushErr>>> {
+        self.next_with(|msg, ack| {
+            Accepted {
+                id: interner.intern(msg.header.id),
+                timestamp: msg.header.timestamp,
+                payload_len: msg.payload.len(),
+                ack: Some(ack),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use super::*;
+    use {server, test_support, Session};
+
+    /// A frame for each of `ids`, with a token and payload.
+    fn frames(ids: &[&[u8]]) -> Vec<u8> {
+        ids.iter()
+           .enumerate()
+           .flat_map(|(i, id)| test_support::frame(b"token", id, i as u64 + 1, b"payload"))
+           .collect()
+    }
+
+    #[test]
+    fn repeats_share_an_allocation() {
+        let cams: [&[u8]; 3] = [b"cam-a", b"cam-b", b"cam-c"];
+        let ids: Vec<&[u8]> = (0..300).map(|i| cams[i % 3]).collect();
+        let mut finder = server::Finder::new();
+        for cam in &cams {
+            finder.insert(cam.to_vec(), test_support::stream::Ok);
+        }
+        let mut server = test_support::server::Ok(finder);
+        let mut interner = IdInterner::new(8);
+        let interned: Vec<Arc<[u8]>> = {
+            let mut session = Session::new(&mut server, Cursor::new(frames(&ids)));
+            let mut interned = vec![];
+            while let Some(id) = session.next_interned(&mut interner) {
+                interned.push(id.unwrap().id);
+            }
+            interned
+        };
+        assert_eq!(ids, interned.iter().map(|id| &id[..]).collect::<Vec<_>>());
+        for (id, repeat) in interned.iter().zip(&interned[3..]) {
+            assert!(Arc::ptr_eq(id, repeat));
+        }
+        // One allocation for each distinct ID, however many frames.
+        assert_eq!(InternStats {
+                       hits: 297,
+                       misses: 3,
+                       evictions: 0,
+                   },
+                   interner.stats());
+        assert_eq!(0.99, interner.stats().hit_rate());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_interned() {
+        let mut interner = IdInterner::new(2);
+        let a = interner.intern(b"a");
+        interner.intern(b"b");
+        assert!(Arc::ptr_eq(&a, &interner.intern(b"a")));
+        // "b" was interned longest ago, so it goes.
+        let c = interner.intern(b"c");
+        assert_eq!(2, interner.len());
+        assert!(Arc::ptr_eq(&a, &interner.intern(b"a")));
+        assert!(Arc::ptr_eq(&c, &interner.intern(b"c")));
+        // An evicted ID is allocated anew, and what was handed out for it
+        // is unaffected.
+        let b = interner.intern(b"b");
+        assert_eq!(b"b", &b[..]);
+        let a_again = interner.intern(b"a");
+        assert!(!Arc::ptr_eq(&a, &a_again));
+        assert_eq!(&a[..], &a_again[..]);
+        assert_eq!(InternStats {
+                       hits: 3,
+                       misses: 5,
+                       evictions: 3,
+                   },
+                   interner.stats());
+    }
+
+    #[test]
+    fn small_ids_intern_only_when_spilled() {
+        let mut interner = IdInterner::new(4);
+        for len in INLINE - 1..INLINE + 1 {
+            let id = vec![b'i'; len];
+            let small = interner.intern_small(&id);
+            assert!(small.is_inline());
+            assert_eq!(small, interner.intern_small(&id));
+        }
+        assert!(interner.is_empty());
+        let long = vec![b's'; INLINE + 1];
+        let first = interner.intern_small(&long);
+        assert!(!first.is_inline());
+        assert_eq!(&long[..], first.as_bytes());
+        assert_eq!(first, interner.intern_small(&long));
+        assert_eq!(InternStats {
+                       hits: 1,
+                       misses: 1,
+                       evictions: 0,
+                   },
+                   interner.stats());
+    }
+
+    quickcheck_test! {
+    stays_within_capacity(ids: Vec<u8>, capacity: u8; bool) {
+        let capacity = capacity as usize % 5 + 1;
+        let mut interner = IdInterner::new(capacity);
+        ids.iter().all(|&id| {
+            &interner.intern(&[id])[..] == &[id][..] && interner.len() <= capacity
+        }) && interner.stats().hits + interner.stats().misses == ids.len() as u64
+    }}
+}
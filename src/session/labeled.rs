@@ -0,0 +1,119 @@
+use std::io::prelude::*;
+
+use Server;
+use super::{NextResult, Session};
+
+/// An item tagged with the connection it came from and its place among
+/// that connection's items, starting from 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Labeled<L, T> {
+    pub label: L,
+    pub seq: u64,
+    pub item: T,
+}
+
+/// A session whose items are `Labeled`, so results from many sessions can
+/// share a channel and still be told apart and put back in order.
+pub struct LabeledSession<'a, L, S: 'a, R> {
+    session: Session<'a, S, R>,
+    label: L,
+    seq: u64,
+}
+
+impl<'a, S: 'a, R> Session<'a, S, R> {
+    pub fn labeled<L: Clone>(self, label: L) -> LabeledSession<'a, L, S, R> {
+        LabeledSession {
+            session: self,
+            label: label,
+            seq: 0,
+        }
+    }
+}
+
+impl<'a, L, S: 'a, R> LabeledSession<'a, L, S, R> {
+    pub fn label(&self) -> &L {
+        &self.label
+    }
+
+    pub fn get_ref(&self) -> &Session<'a, S, R> {
+        &self.session
+    }
+
+    pub fn get_mut(&mut self) -> &mut Session<'a, S, R> {
+        &mut self.session
+    }
+
+    pub fn into_inner(self) -> Session<'a, S, R> {
+        self.session
+    }
+}
+
+impl<'a, L: Clone, S: 'a + Server, R: Read> Iterator for LabeledSession<'a, L, S, R> {
+    type Item = Labeled<L, NextResult<S>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.session.next().map(|item| {
+            let seq = self.seq;
+            self.seq += 1;
+            Labeled {
+                label: self.label.clone(),
+                seq: seq,
+                item: item,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::iter;
+
+    use super::*;
+    use test_support::frame;
+    use {test_support, Session};
+
+    fn input(ids: &[&[u8]]) -> Vec<u8> {
+        ids.iter().flat_map(|id| frame(b"t", id, 1, b"")).collect()
+    }
+
+    fn server() -> test_support::server::Ok<test_support::stream::Ok> {
+        test_support::server::Ok(iter::once((b"id".to_vec(), test_support::stream::Ok)).collect())
+    }
+
+    #[test]
+    fn regroup_interleaved() {
+        let (a, b) = (input(&[b"id", b"x", b"id"]), input(&[b"y", b"id"]));
+        let (mut server_a, mut server_b) = (server(), server());
+        let expected: Vec<Vec<String>> = [&a, &b]
+                                             .iter()
+                                             .map(|input| {
+                                                 Session::new(&mut server(), &input[..])
+                                                     .map(|item| format!("{:?}", item))
+                                                     .collect()
+                                             })
+                                             .collect();
+
+        let mut sessions = vec![Session::new(&mut server_a, &a[..]).labeled(0),
+                                Session::new(&mut server_b, &b[..]).labeled(1)];
+        let mut merged = vec![];
+        for turn in [1, 0, 0, 1, 0, 1].iter() {
+            merged.extend(sessions[*turn].next());
+        }
+        assert_eq!(5, merged.len());
+
+        merged.reverse();
+        let mut regrouped: BTreeMap<usize, Vec<(u64, String)>> = BTreeMap::new();
+        for labeled in merged {
+            regrouped.entry(labeled.label)
+                     .or_insert_with(Vec::new)
+                     .push((labeled.seq, format!("{:?}", labeled.item)));
+        }
+        for (label, mut items) in regrouped {
+            items.sort();
+            assert_eq!((0..items.len() as u64).collect::<Vec<_>>(),
+                       items.iter().map(|&(seq, _)| seq).collect::<Vec<_>>());
+            assert_eq!(expected[label],
+                       items.into_iter().map(|(_, item)| item).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,489 @@
+//! Consuming a capture already in memory, such as a mapped file, straight
+//! from the borrowed bytes: each message handed to the server points into
+//! them, so no payload is copied.
+
+use std::cmp;
+use std::ops::Range;
+use std::time::Duration;
+
+use message::scan::scan_frames;
+use message::wire::FrameAt;
+use message::{wire, Strictness};
+use trace::Spans;
+use {Server, Stream};
+use super::{consume_frame, Error, SessionCheckpoint, ZeroFrame};
+
+/// The settings a `Session` would take, and where in the bytes to start.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappedOptions {
+    pub strictness: Strictness,
+    pub capture_window: usize,
+    pub zero_frame: ZeroFrame,
+    /// Where the first frame starts, such as a `SessionCheckpoint`'s
+    /// offset. The preamble, if any, is taken to end before it.
+    pub start_offset: u64,
+}
+
+impl Default for MappedOptions {
+    fn default() -> Self {
+        MappedOptions {
+            strictness: Strictness::default(),
+            capture_window: 0,
+            zero_frame: ZeroFrame::default(),
+            start_offset: 0,
+        }
+    }
+}
+
+/// A frame that failed, by the offset of its length prefix.
+#[derive(Debug)]
+pub struct MappedError<A, P> {
+    pub offset: u64,
+    pub error: Error<A, P>,
+}
+
+impl<A, P> MappedError<A, P> {
+    /// For a parse error, where in `bytes` the missing header part would
+    /// have started.
+    pub fn file_offset(&self) -> Option<u64> {
+        self.error.frame_offset().map(|at| self.offset + at as u64)
+    }
+}
+
+pub type MappedResult<'b, S> = Result<
+    &'b [u8],
+    MappedError<<S as Server>::AuthErr, <<S as Server>::Stream as Stream>::PushErr>>;
+
+/// Walks the frames of `bytes` as a `Session` would read them, yielding
+/// the ID of each message consumed, borrowed from `bytes`.
+pub struct Mapped<'a, 'b, S: 'a> {
+    server: &'a mut S,
+    bytes: &'b [u8],
+    options: MappedOptions,
+    offset: usize,
+    frames: u64,
+    consumed: u64,
+    zero_frames: u64,
+    heartbeats: u64,
+    repairs: u64,
+    truncated_at: Option<u64>,
+    timestamp: Option<Duration>,
+    done: bool,
+}
+
+impl<'a, 'b, S: 'a> Mapped<'a, 'b, S> {
+    pub fn new(server: &'a mut S, bytes: &'b [u8], options: MappedOptions) -> Self {
+        let start = cmp::min(options.start_offset, bytes.len() as u64) as usize;
+        Mapped {
+            server: server,
+            bytes: bytes,
+            options: options,
+            offset: start,
+            frames: 0,
+            consumed: 0,
+            zero_frames: 0,
+            heartbeats: 0,
+            repairs: 0,
+            truncated_at: None,
+            timestamp: None,
+            done: false,
+        }
+    }
+
+    pub fn server(&self) -> &S {
+        self.server
+    }
+
+    /// Where to resume after the frames read so far, counting frames from
+    /// the start offset.
+    pub fn checkpoint(&self) -> SessionCheckpoint {
+        SessionCheckpoint {
+            offset: self.offset as u64,
+            frames_consumed: self.frames,
+        }
+    }
+
+    /// The summary of the frames read so far.
+    pub fn summary(&self) -> MappedRunSummary {
+        MappedRunSummary {
+            frames: self.frames,
+            consumed: self.consumed,
+            zero_frames: self.zero_frames,
+            heartbeats: self.heartbeats,
+            repairs: self.repairs,
+            truncated_at: self.truncated_at,
+            last_timestamp: self.timestamp,
+            checkpoint: self.checkpoint(),
+        }
+    }
+}
+
+impl<'a, 'b, S: 'a + Server> Mapped<'a, 'b, S> {
+    /// Ends the walk at the frame cut short at `offset`, failing it unless
+    /// the strictness repairs it, which for a prefix that counted itself
+    /// consumes the frame after all.
+    fn cut_short(&mut self, offset: usize) -> Option<MappedResult<'b, S>> {
+        self.done = true;
+        let frame = wire::frame_at(self.bytes, offset);
+        if self.options.strictness == Strictness::Lenient {
+            self.repairs += 1;
+            if let FrameAt::Short { found, size } = frame {
+                // Its prefix counted itself, as `Session` repairs it.
+                if found > 0 && found as usize + 2 == size as usize {
+                    let end = self.bytes.len();
+                    return self.whole(offset, offset + 2..end, end);
+                }
+            }
+            self.truncated_at = Some(offset as u64);
+            return None;
+        }
+        self.truncated_at = Some(offset as u64);
+        let error = match frame {
+            FrameAt::Short { found, size } => {
+                Error::Truncated {
+                    found: found as u32,
+                    remaining: (size - found) as u32,
+                }
+            }
+            _ => Error::EofInMessageSize,
+        };
+        Some(Err(MappedError {
+            offset: offset as u64,
+            error: error,
+        }))
+    }
+
+    /// Consumes the whole frame at `offset`, whose message is `message`
+    /// and after which the next frame starts. A zero-size frame that is
+    /// skipped comes to `None`.
+    fn whole(&mut self,
+             offset: usize,
+             message: Range<usize>,
+             next: usize)
+             -> Option<MappedResult<'b, S>> {
+        self.offset = next;
+        self.frames += 1;
+        if message.start == message.end {
+            match self.options.zero_frame {
+                ZeroFrame::Error => {}
+                ZeroFrame::Ignore => {
+                    self.zero_frames += 1;
+                    return None;
+                }
+                ZeroFrame::Heartbeat => {
+                    self.heartbeats += 1;
+                    return None;
+                }
+            }
+        }
+        let bytes = self.bytes;
+        match consume_frame(&mut *self.server,
+                            self.options.strictness,
+                            self.options.capture_window,
+                            2,
+                            &mut self.timestamp,
+                            &bytes[message],
+                            &mut Spans::off()) {
+            Ok((msg, _, _)) => {
+                self.consumed += 1;
+                Some(Ok(msg.header.id))
+            }
+            Err(e) => {
+                Some(Err(MappedError {
+                    offset: offset as u64,
+                    error: e,
+                }))
+            }
+        }
+    }
+}
+
+impl<'a, 'b, S: 'a + Server> Iterator for Mapped<'a, 'b, S> {
+    type Item = MappedResult<'b, S>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let offset = self.offset;
+            let result = match wire::frame_at(self.bytes, offset) {
+                FrameAt::End => {
+                    self.done = true;
+                    return None;
+                }
+                FrameAt::PartialPrefix | FrameAt::Short { .. } => self.cut_short(offset),
+                FrameAt::Whole { message, next } => self.whole(offset, message, next),
+            };
+            if result.is_some() {
+                return result;
+            }
+        }
+        None
+    }
+}
+
+/// What consuming a capture came to, counted as a `Session` counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappedRunSummary {
+    /// Frames read in full, whatever became of them.
+    pub frames: u64,
+    pub consumed: u64,
+    pub zero_frames: u64,
+    pub heartbeats: u64,
+    pub repairs: u64,
+    /// Offset of the final frame if it was cut short.
+    pub truncated_at: Option<u64>,
+    pub last_timestamp: Option<Duration>,
+    pub checkpoint: SessionCheckpoint,
+}
+
+/// Consumes every frame of `bytes` from the start offset on, handing each
+/// frame that fails to `on_error` in order, so that memory use beyond
+/// `bytes` is constant.
+pub fn consume_mapped<S, F>(server: &mut S,
+                            bytes: &[u8],
+                            options: MappedOptions,
+                            mut on_error: F)
+                            -> MappedRunSummary
+    where S: Server,
+          F: FnMut(MappedError<S::AuthErr, <S::Stream as Stream>::PushErr>)
+{
+    let mut mapped = Mapped::new(server, bytes, options);
+    let start = mapped.offset;
+    let scanned = scan_frames(&bytes[start..], |frame| {
+        let offset = start + frame.offset as usize;
+        let next = offset + 2 + frame.len as usize;
+        if let Some(Err(e)) = mapped.whole(offset, offset + 2..next, next) {
+            on_error(e);
+        }
+    });
+    match scanned {
+        Ok(scanned) => {
+            if let Some(at) = scanned.truncated_at {
+                if let Some(Err(e)) = mapped.cut_short(start + at as usize) {
+                    on_error(e);
+                }
+            }
+        }
+        Err(e) => unreachable!("a slice cannot fail to be read: {}", e),
+    }
+    mapped.summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use message::Strictness;
+    use server::Finder;
+    use session::{Error, Session, ZeroFrame};
+    use test_support::frame;
+    use {test_support, Stream};
+
+    /// Records where each payload it is pushed lies in memory.
+    #[derive(Clone, Default)]
+    struct Addresses(Rc<RefCell<Vec<(usize, usize)>>>);
+
+    impl Stream for Addresses {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, payload: &[u8]) -> Result<(), ::Void> {
+            self.0.borrow_mut().push((payload.as_ptr() as usize, payload.len()));
+            Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<(), (Self, ::Void)> {
+            Ok(())
+        }
+    }
+
+    fn describe<A, P>(e: &Error<A, P>) -> &'static str {
+        match *e {
+            Error::EofInMessageSize => "one byte",
+            Error::Truncated { .. } => "truncated",
+            Error::Parse { .. } => "parse",
+            Error::Nonconforming(_) => "nonconforming",
+            Error::Consume(_) => "consume",
+            _ => "other",
+        }
+    }
+
+    fn server() -> test_support::server::Ok<test_support::stream::Ok> {
+        let mut finder = Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        finder.insert(vec![], test_support::stream::Ok);
+        test_support::server::Ok(finder)
+    }
+
+    fn capture() -> Vec<u8> {
+        let mut bytes = frame(b"t", b"id", 1000, b"first");
+        bytes.extend(frame(b"t", b"missing", 2000, b"no stream"));
+        bytes.extend_from_slice(&[0, 3, 1, 2, 3]);
+        bytes.extend(frame(b"", b"", 3000, b"nonconforming"));
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend(frame(b"t", b"id", 4000, b"last"));
+        bytes
+    }
+
+    /// Outcomes by offset, then frames, repairs and where to resume.
+    fn by_session(bytes: &[u8],
+                  strictness: Strictness,
+                  zero_frame: ZeroFrame)
+                  -> (Vec<(u64, &'static str)>, u64, u64, u64) {
+        let mut server = server();
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_strictness(strictness);
+        session.set_zero_frame(zero_frame);
+        let mut outcomes = vec![];
+        loop {
+            let offset = session.checkpoint().offset;
+            match session.next() {
+                None => break,
+                Some(Ok(_)) => outcomes.push((offset, "ok")),
+                Some(Err(e)) => outcomes.push((offset, describe(&e))),
+            }
+        }
+        let checkpoint = session.checkpoint();
+        (outcomes, checkpoint.frames_consumed, session.repairs(), checkpoint.offset)
+    }
+
+    fn by_mapping(bytes: &[u8],
+                  strictness: Strictness,
+                  zero_frame: ZeroFrame)
+                  -> (Vec<(u64, &'static str)>, u64, u64, u64) {
+        let mut server = server();
+        let options = MappedOptions {
+            strictness: strictness,
+            zero_frame: zero_frame,
+            ..MappedOptions::default()
+        };
+        let mut mapped = Mapped::new(&mut server, bytes, options);
+        let mut outcomes = vec![];
+        loop {
+            let offset = mapped.checkpoint().offset;
+            match mapped.next() {
+                None => break,
+                Some(Ok(_)) => outcomes.push((offset, "ok")),
+                Some(Err(e)) => outcomes.push((e.offset, describe(&e.error))),
+            }
+        }
+        let summary = mapped.summary();
+        (outcomes, summary.frames, summary.repairs, summary.checkpoint.offset)
+    }
+
+    #[test]
+    fn matches_session() {
+        let whole = capture();
+        let mut one_byte = whole.clone();
+        one_byte.push(0);
+        let mut truncated = whole.clone();
+        truncated.extend_from_slice(&frame(b"t", b"id", 5000, b"cut short")[..8]);
+        let mut counted = whole.clone();
+        let mut last = frame(b"t", b"id", 6000, b"counted");
+        last[1] += 2;
+        counted.extend_from_slice(&last);
+        for bytes in vec![whole, one_byte, truncated, counted] {
+            for &strictness in &[Strictness::Strict, Strictness::Standard, Strictness::Lenient] {
+                for &zero_frame in &[ZeroFrame::Error, ZeroFrame::Ignore, ZeroFrame::Heartbeat] {
+                    assert_eq!(by_session(&bytes, strictness, zero_frame),
+                               by_mapping(&bytes, strictness, zero_frame));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn summary_counts() {
+        let mut bytes = capture();
+        let cut = bytes.len() as u64;
+        bytes.extend_from_slice(&[0, 9, 1]);
+        let mut server = server();
+        let options = MappedOptions {
+            zero_frame: ZeroFrame::Heartbeat,
+            ..MappedOptions::default()
+        };
+        let mut errors = vec![];
+        let summary = consume_mapped(&mut server, &bytes, options, |e| {
+            errors.push((e.offset, describe(&e.error)))
+        });
+        assert_eq!(6, summary.frames);
+        assert_eq!(3, summary.consumed);
+        assert_eq!(1, summary.heartbeats);
+        assert_eq!(Some(cut), summary.truncated_at);
+        assert_eq!(cut, summary.checkpoint.offset);
+        assert_eq!(Some(Duration::from_secs(4)), summary.last_timestamp);
+        let missing = frame(b"t", b"id", 1000, b"first").len() as u64;
+        let corrupt = missing + frame(b"t", b"missing", 2000, b"no stream").len() as u64;
+        assert_eq!(vec![(missing, "consume"), (corrupt, "parse"), (cut, "truncated")],
+                   errors);
+    }
+
+    #[test]
+    fn resumes_from_start_offset() {
+        let bytes = capture();
+        let mut first = server();
+        let checkpoint = {
+            let mut session = Session::new(&mut first, Cursor::new(&bytes));
+            for _ in session.by_ref().take(3) {}
+            session.checkpoint()
+        };
+
+        let mut second = server();
+        let options = MappedOptions {
+            start_offset: checkpoint.offset,
+            zero_frame: ZeroFrame::Ignore,
+            ..MappedOptions::default()
+        };
+        let summary = consume_mapped(&mut second, &bytes, options, |_| {});
+        assert_eq!(3, summary.frames);
+        assert_eq!(2, summary.consumed);
+        assert_eq!(bytes.len() as u64, summary.checkpoint.offset);
+
+        let options = MappedOptions {
+            start_offset: bytes.len() as u64 + 1,
+            ..MappedOptions::default()
+        };
+        assert_eq!(0, consume_mapped(&mut second, &bytes, options, |_| {}).frames);
+    }
+
+    #[test]
+    fn payloads_point_into_bytes() {
+        let addresses = Addresses::default();
+        let mut finder = Finder::new();
+        finder.insert(b"id".to_vec(), addresses.clone());
+        let mut server = test_support::server::Ok(finder);
+        let mut bytes = vec![];
+        for i in 0..10_u8 {
+            bytes.extend(frame(b"t", b"id", 1000, &vec![i; 100 * i as usize]));
+        }
+        let summary = consume_mapped(&mut server, &bytes, MappedOptions::default(), |_| {});
+        assert_eq!(10, summary.consumed);
+
+        let start = bytes.as_ptr() as usize;
+        let end = start + bytes.len();
+        let addresses = addresses.0.borrow();
+        assert_eq!(10, addresses.len());
+        for &(at, len) in addresses.iter() {
+            assert!(start <= at && at + len <= end);
+        }
+    }
+
+    #[test]
+    fn parse_errors_by_file_offset() {
+        let mut bytes = frame(b"t", b"id", 1000, b"first");
+        let second = bytes.len() as u64;
+        // A token size of 5 with one byte of token.
+        bytes.extend_from_slice(&[0, 3, 0, 5, b't']);
+        let third = bytes.len() as u64;
+        // A whole token, then one byte of ID size.
+        bytes.extend_from_slice(&[0, 5, 0, 2, b't', b'u', 0]);
+        let mut offsets = vec![];
+        consume_mapped(&mut server(), &bytes, MappedOptions::default(), |e| {
+            offsets.push((e.offset, e.error.frame_offset(), e.file_offset()))
+        });
+        assert_eq!(vec![(second, Some(4), Some(second + 4)), (third, Some(6), Some(third + 6))],
+                   offsets);
+    }
+}
@@ -0,0 +1,547 @@
+//! Merging captures from several gateways into one order by header
+//! timestamp. Gateways' clocks disagree, so each source can be given a
+//! `Skew`: an offset to correct its timestamps by, and how far out the
+//! corrected ones may still be. Frames from sources whose corrected times
+//! are within their uncertainty of each other cannot be put in order by
+//! time, so a `Tiebreak` decides, and the merge says it had to. Every
+//! source is framed the same way, as `Framing::U16` unless set otherwise.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use message::{Framing, Prefix, MAX_VARINT_LEN};
+use stream::sequence::Extractor;
+use util::{fill, millis};
+use Message;
+
+/// How a source's clock is off.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Skew {
+    /// How far ahead the source's clock is, in milliseconds, or behind if
+    /// negative; it is taken off every timestamp.
+    pub offset: i64,
+    /// How far the corrected timestamps may still be out, either way.
+    pub uncertainty: Duration,
+}
+
+/// How to order frames whose corrected times are too close to go by.
+#[derive(Clone, Copy, Debug)]
+pub enum Tiebreak {
+    /// The earlier source first.
+    Priority,
+    /// Frames for the same ID by the sequence numbers in their payloads,
+    /// as a `SequenceTracker` with the extractor reads them, and otherwise
+    /// by priority.
+    Sequence(Extractor),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedFrame {
+    pub source: usize,
+    /// The header's timestamp less the source's offset, or zero if that
+    /// would be before the epoch.
+    pub corrected: Duration,
+    /// As read, with its size prefix.
+    pub frame: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeEvent {
+    Frame(MergedFrame),
+    /// The next frame, from `first`, goes ahead of the one waiting from
+    /// `second` by the tiebreak alone.
+    Uncertain { first: usize, second: usize },
+}
+
+/// An error reading a source. A frame whose header will not parse is
+/// skipped; after any other error the source is read no further.
+#[derive(Debug)]
+pub struct MergeError {
+    pub source: usize,
+    pub error: io::Error,
+}
+
+impl Display for MergeError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "source {}: {}", self.source, self.error)
+    }
+}
+
+impl error::Error for MergeError {
+    fn description(&self) -> &str {
+        "error reading merge source"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.error)
+    }
+}
+
+/// Reads a frame, returning it with its size prefix and how long the
+/// prefix is, or `None` if the input ends before one starts.
+fn read_frame<R: Read>(reader: &mut R, framing: Framing) -> io::Result<Option<(Vec<u8>, usize)>> {
+    let mut prefix = [0_u8; MAX_VARINT_LEN];
+    let mut n = 0;
+    while let Prefix::Partial(more) = framing.decode(&prefix[..n]) {
+        let k = try!(fill(reader, &mut prefix[n..n + more]));
+        if n + k == 0 {
+            return Ok(None);
+        } else if k < more {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "size prefix cut short"));
+        }
+        n += k;
+    }
+    let (size, len) = match framing.decode(&prefix[..n]) {
+        Prefix::Whole { size, len } => (size, len),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad size prefix")),
+    };
+    let mut frame = vec![0; len + size];
+    frame[..len].copy_from_slice(&prefix[..len]);
+    if try!(fill(reader, &mut frame[len..])) < size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame cut short"));
+    }
+    Ok(Some((frame, len)))
+}
+
+fn timestamp(message: &[u8]) -> io::Result<u64> {
+    match Message::parse(message) {
+        Ok(message) => Ok(millis(message.header.timestamp)),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+struct Head {
+    frame: Vec<u8>,
+    prefix_len: usize,
+    corrected: i64,
+}
+
+struct Source<R> {
+    reader: R,
+    skew: Skew,
+    head: Option<Head>,
+    done: bool,
+}
+
+/// Frames from every source, by corrected timestamp. Each source is taken
+/// to be in order itself.
+pub struct Merge<R> {
+    sources: Vec<Source<R>>,
+    framing: Framing,
+    tiebreak: Tiebreak,
+    uncertain: BTreeMap<(usize, usize), u64>,
+    pending: VecDeque<MergeEvent>,
+}
+
+impl<R: Read> Merge<R> {
+    /// Sources in priority order, each of size-prefixed frames, as a
+    /// capture holds them.
+    pub fn new(sources: Vec<R>) -> Self {
+        Merge {
+            sources: sources.into_iter()
+                            .map(|reader| {
+                                Source {
+                                    reader: reader,
+                                    skew: Skew::default(),
+                                    head: None,
+                                    done: false,
+                                }
+                            })
+                            .collect(),
+            framing: Framing::U16,
+            tiebreak: Tiebreak::Priority,
+            uncertain: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Panics if there is no such source, or once it has been read from.
+    pub fn set_skew(&mut self, source: usize, skew: Skew) {
+        let source = &mut self.sources[source];
+        assert!(source.head.is_none(), "skew set after the merge started");
+        source.skew = skew;
+    }
+
+    /// Panics once any source has been read from.
+    pub fn set_framing(&mut self, framing: Framing) {
+        assert!(self.sources.iter().all(|source| source.head.is_none() && !source.done),
+                "framing set after the merge started");
+        self.framing = framing;
+    }
+
+    pub fn set_tiebreak(&mut self, tiebreak: Tiebreak) {
+        self.tiebreak = tiebreak;
+    }
+
+    /// How many times each pair of sources, lower first, was ordered by
+    /// the tiebreak.
+    pub fn uncertain(&self) -> &BTreeMap<(usize, usize), u64> {
+        &self.uncertain
+    }
+
+    /// Reads the next frame of every source without one waiting.
+    fn fill_heads(&mut self) -> Result<(), MergeError> {
+        let framing = self.framing;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            while source.head.is_none() && !source.done {
+                let (frame, prefix_len) = match read_frame(&mut source.reader, framing) {
+                    Ok(Some(read)) => read,
+                    Ok(None) => {
+                        source.done = true;
+                        break;
+                    }
+                    Err(e) => {
+                        source.done = true;
+                        return Err(MergeError { source: i, error: e });
+                    }
+                };
+                let ts = try!(timestamp(&frame[prefix_len..]).map_err(|e| {
+                    MergeError {
+                        source: i,
+                        error: e,
+                    }
+                }));
+                source.head = Some(Head {
+                    frame: frame,
+                    prefix_len: prefix_len,
+                    corrected: ts as i64 - source.skew.offset,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn corrected(&self, source: usize) -> i64 {
+        self.sources[source].head.as_ref().unwrap().corrected
+    }
+
+    /// Whether the waiting frames of `a` and `b` could be in either order.
+    fn too_close(&self, a: usize, b: usize) -> bool {
+        let slack = millis(self.sources[a].skew.uncertainty) +
+                    millis(self.sources[b].skew.uncertainty);
+        (self.corrected(a) - self.corrected(b)).abs() as u64 <= slack
+    }
+
+    /// The ID and sequence number of the frame waiting from `source`.
+    fn sequence(&self, source: usize, extractor: Extractor) -> Option<(&[u8], u32)> {
+        let head = self.sources[source].head.as_ref().unwrap();
+        Message::parse(&head.frame[head.prefix_len..])
+            .ok()
+            .and_then(|message| extractor(message.payload).map(|seq| (message.header.id, seq)))
+    }
+
+    /// Whether the tiebreak puts the frame waiting from `a` before the one
+    /// from `b`.
+    fn breaks_before(&self, a: usize, b: usize) -> bool {
+        if let Tiebreak::Sequence(extractor) = self.tiebreak {
+            if let (Some((id_a, seq_a)), Some((id_b, seq_b))) = (self.sequence(a, extractor),
+                                                                 self.sequence(b, extractor)) {
+                if id_a == id_b && seq_a != seq_b {
+                    return seq_a < seq_b;
+                }
+            }
+        }
+        a < b
+    }
+
+    /// The source to take from next, and those it was too close to.
+    fn choose(&self) -> Option<(usize, Vec<usize>)> {
+        let waiting: Vec<_> = (0..self.sources.len())
+                                  .filter(|&i| self.sources[i].head.is_some())
+                                  .collect();
+        let earliest = match waiting.iter().min_by_key(|&&i| (self.corrected(i), i)) {
+            None => return None,
+            Some(&earliest) => earliest,
+        };
+        let mut close: Vec<_> = waiting.into_iter()
+                                       .filter(|&i| i != earliest && self.too_close(i, earliest))
+                                       .collect();
+        if close.is_empty() {
+            return Some((earliest, close));
+        }
+        close.push(earliest);
+        let mut chosen = earliest;
+        for &i in &close {
+            if self.breaks_before(i, chosen) {
+                chosen = i;
+            }
+        }
+        close.retain(|&i| i != chosen);
+        close.sort();
+        Some((chosen, close))
+    }
+}
+
+impl<R: Read> Iterator for Merge<R> {
+    type Item = Result<MergeEvent, MergeError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(Ok(event));
+        }
+        if let Err(e) = self.fill_heads() {
+            return Some(Err(e));
+        }
+        let (chosen, close) = match self.choose() {
+            None => return None,
+            Some(choice) => choice,
+        };
+        for second in close {
+            let pair = if chosen < second { (chosen, second) } else { (second, chosen) };
+            *self.uncertain.entry(pair).or_insert(0) += 1;
+            self.pending.push_back(MergeEvent::Uncertain {
+                first: chosen,
+                second: second,
+            });
+        }
+        let head = self.sources[chosen].head.take().unwrap();
+        let corrected = if head.corrected < 0 { 0 } else { head.corrected as u64 };
+        self.pending.push_back(MergeEvent::Frame(MergedFrame {
+            source: chosen,
+            corrected: Duration::from_millis(corrected),
+            frame: head.frame,
+        }));
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// A source's offset as `estimate_offsets` worked it out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OffsetEstimate {
+    /// For `Skew::offset`.
+    pub offset: i64,
+    /// Frames found in both this source and the first.
+    pub samples: usize,
+}
+
+/// Estimates every source's offset from the first's clock, for an operator
+/// to check before setting it: the median difference in timestamp between
+/// copies of the same frame, matched by ID and payload, in both sources.
+/// The first source is at offset zero by definition, and a source with no
+/// frames in common with it gets `None`. Reads every source, each framed by
+/// `framing`, to the end, skipping frames whose headers will not parse.
+pub fn estimate_offsets<R: Read>(sources: Vec<R>,
+                                 framing: Framing)
+                                 -> Result<Vec<Option<OffsetEstimate>>, MergeError> {
+    let mut seen = vec![];
+    for (i, mut reader) in sources.into_iter().enumerate() {
+        let mut frames: HashMap<(Vec<u8>, Vec<u8>), u64> = HashMap::new();
+        while let Some((frame, prefix_len)) = try!(read_frame(&mut reader, framing).map_err(|e| {
+            MergeError {
+                source: i,
+                error: e,
+            }
+        })) {
+            if let Ok(message) = Message::parse(&frame[prefix_len..]) {
+                frames.entry((message.header.id.to_vec(), message.payload.to_vec()))
+                      .or_insert(millis(message.header.timestamp));
+            }
+        }
+        seen.push(frames);
+    }
+    let reference = match seen.first() {
+        None => return Ok(vec![]),
+        Some(reference) => reference,
+    };
+    Ok(seen.iter()
+           .enumerate()
+           .map(|(i, frames)| {
+               if i == 0 {
+                   return Some(OffsetEstimate {
+                       offset: 0,
+                       samples: 0,
+                   });
+               }
+               let mut deltas: Vec<i64> = frames.iter()
+                                                .filter_map(|(key, &ts)| {
+                                                    reference.get(key)
+                                                             .map(|&base| ts as i64 - base as i64)
+                                                })
+                                                .collect();
+               if deltas.is_empty() {
+                   return None;
+               }
+               deltas.sort();
+               let mid = deltas.len() / 2;
+               let median = if deltas.len() % 2 == 0 {
+                   (deltas[mid - 1] + deltas[mid]) / 2
+               } else {
+                   deltas[mid]
+               };
+               Some(OffsetEstimate {
+                   offset: median,
+                   samples: deltas.len(),
+               })
+           })
+           .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use message::Framing;
+    use stream::sequence::be_prefix;
+    use test_support::{frame, message};
+
+    /// Frames stamped `at` plus `offset`, as a gateway whose clock is that
+    /// far ahead would stamp them.
+    fn source(offset: i64, frames: &[(&[u8], u64, &[u8])]) -> Vec<u8> {
+        frames.iter()
+              .flat_map(|&(id, at, payload)| frame(b"t", id, (at as i64 + offset) as u64, payload))
+              .collect()
+    }
+
+    fn merge(merge: &mut Merge<&[u8]>) -> (Vec<(usize, u64, Vec<u8>)>, Vec<(usize, usize)>) {
+        let (mut frames, mut uncertain) = (vec![], vec![]);
+        for event in merge {
+            match event.unwrap() {
+                MergeEvent::Frame(merged) => {
+                    let message = Message::parse(&merged.frame[2..]).unwrap();
+                    frames.push((merged.source, millis(merged.corrected), message.payload.to_vec()))
+                }
+                MergeEvent::Uncertain { first, second } => uncertain.push((first, second)),
+            }
+        }
+        (frames, uncertain)
+    }
+
+    #[test]
+    fn corrects_skewed_sources() {
+        let a = source(2000, &[(b"x", 100, b"a1"), (b"x", 300, b"a2"), (b"y", 500, b"a3")]);
+        let b = source(-100, &[(b"z", 200, b"b1"), (b"z", 400, b"b2")]);
+        let (frames, uncertain) = merge(&mut Merge::new(vec![&a[..], &b[..]]));
+        // Going by the raw timestamps puts all of b first.
+        assert_eq!(vec![1, 1, 0, 0, 0],
+                   frames.iter().map(|f| f.0).collect::<Vec<_>>());
+        assert!(uncertain.is_empty());
+
+        let mut skewed = Merge::new(vec![&a[..], &b[..]]);
+        skewed.set_skew(0, Skew { offset: 2000, uncertainty: Duration::from_millis(0) });
+        skewed.set_skew(1, Skew { offset: -100, uncertainty: Duration::from_millis(0) });
+        let (frames, uncertain) = merge(&mut skewed);
+        let order: Vec<_> = frames.iter().map(|f| (f.0, f.1)).collect();
+        assert_eq!(vec![(0, 100), (1, 200), (0, 300), (1, 400), (0, 500)], order);
+        assert!(uncertain.is_empty());
+    }
+
+    #[test]
+    fn counts_uncertain_decisions() {
+        let a = source(1000, &[(b"x", 100, b"a1"), (b"x", 1000, b"a2")]);
+        let b = source(0, &[(b"y", 150, b"b1"), (b"y", 1020, b"b2"), (b"y", 3000, b"b3")]);
+        let c = source(0, &[(b"z", 120, b"c1")]);
+        let mut merged = Merge::new(vec![&a[..], &b[..], &c[..]]);
+        let uncertainty = Duration::from_millis(30);
+        merged.set_skew(0, Skew { offset: 1000, uncertainty: uncertainty });
+        merged.set_skew(1, Skew { offset: 0, uncertainty: uncertainty });
+        let (frames, uncertain) = merge(&mut merged);
+        // a1 at 100 is within 60ms of b1 at 150 and 30ms of c1 at 120, and
+        // goes first by priority; then b1 and c1 are 30ms apart, and a2 and
+        // b2 20ms.
+        let order: Vec<_> = frames.iter().map(|f| (f.0, f.1)).collect();
+        assert_eq!(vec![(0, 100), (1, 150), (2, 120), (0, 1000), (1, 1020), (1, 3000)], order);
+        assert_eq!(vec![(0, 1), (0, 2), (1, 2), (0, 1)], uncertain);
+        let counts: Vec<_> = merged.uncertain().iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(vec![((0, 1), 2), ((0, 2), 1), ((1, 2), 1)], counts);
+    }
+
+    #[test]
+    fn sequence_breaks_ties_for_the_same_id() {
+        // Two gateways relay the same device, each missing one message; b's
+        // copies are stamped a little early.
+        let a = source(0, &[(b"d", 100, b"\0\0\0\x01"), (b"d", 300, b"\0\0\0\x03")]);
+        let b = source(0, &[(b"d", 290, b"\0\0\0\x02"), (b"e", 310, b"\0\0\0\x09")]);
+        let mut by_sequence = Merge::new(vec![&a[..], &b[..]]);
+        for i in 0..2 {
+            by_sequence.set_skew(i, Skew { offset: 0, uncertainty: Duration::from_millis(20) });
+        }
+        by_sequence.set_tiebreak(Tiebreak::Sequence(be_prefix));
+        let (frames, uncertain) = merge(&mut by_sequence);
+        let payloads: Vec<_> = frames.iter().map(|f| f.2[3]).collect();
+        assert_eq!(vec![1, 2, 3, 9], payloads);
+        assert_eq!(vec![(1, 0), (0, 1)], uncertain);
+
+        // By priority, a's third message goes ahead of b's second.
+        let mut by_priority = Merge::new(vec![&a[..], &b[..]]);
+        for i in 0..2 {
+            by_priority.set_skew(i, Skew { offset: 0, uncertainty: Duration::from_millis(20) });
+        }
+        let payloads: Vec<_> = merge(&mut by_priority).0.iter().map(|f| f.2[3]).collect();
+        assert_eq!(vec![1, 3, 2, 9], payloads);
+    }
+
+    #[test]
+    fn reads_the_framing_it_is_given() {
+        let long = vec![b'x'; 200];
+        let varint = |id: &[u8], at: u64, payload: &[u8]| {
+            let message = message(b"t", id, at, payload);
+            let mut frame = vec![0; Framing::Varint.prefix_len(message.len())];
+            Framing::Varint.write_size(&mut frame, message.len());
+            frame.extend(message);
+            frame
+        };
+        let mut a = varint(b"x", 100, &long);
+        a.extend(varint(b"x", 300, b"a2"));
+        let b = varint(b"y", 200, b"b1");
+        let mut merged = Merge::new(vec![&a[..], &b[..]]);
+        merged.set_framing(Framing::Varint);
+        let frames: Vec<_> = merged.filter_map(|event| {
+                                       match event.unwrap() {
+                                           MergeEvent::Frame(merged) => Some(merged),
+                                           MergeEvent::Uncertain { .. } => None,
+                                       }
+                                   })
+                                   .collect();
+        let order: Vec<_> = frames.iter().map(|f| (f.source, millis(f.corrected))).collect();
+        assert_eq!(vec![(0, 100), (1, 200), (0, 300)], order);
+        // Over 127 bytes, the first frame's size takes two.
+        assert_eq!(varint(b"x", 100, &long), frames[0].frame);
+
+        let estimates = estimate_offsets(vec![&a[..], &a[..]], Framing::Varint).unwrap();
+        assert_eq!(Some(OffsetEstimate { offset: 0, samples: 2 }), estimates[1]);
+    }
+
+    #[test]
+    fn estimates_planted_offsets() {
+        // Every source relays a share of the same traffic, with up to 40ms
+        // of jitter each.
+        let planted = [0_i64, 1500, -700];
+        let sources: Vec<Vec<u8>> = planted.iter()
+                                           .enumerate()
+                                           .map(|(s, &offset)| {
+                                               (0..60_u64)
+                                                   .filter(|i| (i + s as u64) % 3 != 0)
+                                                   .flat_map(|i| {
+                                                       let jitter = (i * 7 + s as u64 * 13) % 41;
+                                                       let at = 10000 + i * 250 + jitter;
+                                                       let payload = format!("m{}", i);
+                                                       frame(b"t",
+                                                             b"dev",
+                                                             (at as i64 + offset) as u64,
+                                                             payload.as_bytes())
+                                                   })
+                                                   .collect()
+                                           })
+                                           .collect();
+        let readers = sources.iter().map(|s| &s[..]).collect();
+        let estimates = estimate_offsets(readers, Framing::U16).unwrap();
+        assert_eq!(Some(OffsetEstimate { offset: 0, samples: 0 }), estimates[0]);
+        for (estimate, &planted) in estimates[1..].iter().zip(&planted[1..]) {
+            let estimate = estimate.unwrap();
+            assert_eq!(20, estimate.samples);
+            assert!((estimate.offset - planted).abs() <= 20,
+                    "estimated {} for {}",
+                    estimate.offset,
+                    planted);
+        }
+        let unrelated = source(0, &[(b"other", 1, b"x")]);
+        let estimates = estimate_offsets(vec![&sources[0][..], &unrelated[..]], Framing::U16)
+            .unwrap();
+        assert_eq!(None, estimates[1]);
+    }
+}
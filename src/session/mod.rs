@@ -0,0 +1,2556 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clock::SystemClock;
+use config::ValidatedConfig;
+use message::{Framing, Header, Prefix, Strictness, MAX_VARINT_LEN};
+use stream::Pressure;
+use trace::{MessageTrace, Outcome, Recorder, Spans};
+use {message, server, util, Clock, Message, Server, Stream};
+
+pub use self::checkpoint::{ResumeError, SessionCheckpoint};
+pub use self::deferred::DeferredAuth;
+pub use self::drain::{Drain, DrainOutcome, DrainingSession, ShutdownToken};
+pub use self::dry_run::{dry_run, DryRunCounts, DryRunSummary};
+pub use self::forward::{forward, ForwardSummary, ItemKind, OverflowPolicy};
+pub use self::intern::{IdInterner, InternStats};
+pub use self::labeled::{Labeled, LabeledSession};
+pub use self::mapped::{consume_mapped, Mapped, MappedError, MappedOptions, MappedRunSummary};
+pub use self::merge::{estimate_offsets, Merge, MergeError, MergeEvent, MergedFrame, OffsetEstimate,
+                      Skew, Tiebreak};
+pub use self::post_mortem::{CaptureEnd, FatalCapture, FatalCaptured};
+pub use self::preamble::{PeerInfo, PreambleError, PreamblePolicy};
+pub use self::pressure::{Backoff, PressureStats};
+pub use self::protocol::{Protocol, Transition};
+pub use self::read_ahead::{ReadAhead, VectoredRead};
+pub use self::throttle::{BucketSpec, Throttle, ThrottleStats, Throttling};
+pub use self::timed::{LatencyHistogram, Timed, TimedSession};
+#[cfg(feature = "gzip")]
+pub use self::replay::{CompressedReplay, ReplayError};
+
+pub mod checkpoint;
+pub mod deferred;
+pub mod drain;
+pub mod dry_run;
+pub mod forward;
+mod gate;
+pub mod intern;
+pub mod labeled;
+pub mod mapped;
+pub mod merge;
+pub mod post_mortem;
+pub mod preamble;
+pub mod pressure;
+pub mod protocol;
+pub mod read_ahead;
+#[cfg(feature = "gzip")]
+pub mod replay;
+pub mod streaming;
+pub mod throttle;
+pub mod timed;
+
+pub struct Session<'a, S: 'a, R> {
+    server: Handle<'a, S>,
+    reader: R,
+    buffer: Vec<u8>,
+    /// The frame, prefix included, that `try_next` has read so far.
+    pending: Vec<u8>,
+    framing: Framing,
+    /// The first `held_prefix_len` bytes of a length prefix whose rest has
+    /// yet to come.
+    held_prefix: [u8; MAX_VARINT_LEN],
+    held_prefix_len: usize,
+    /// How many bytes the length prefix of the frame being read took.
+    frame_prefix_len: usize,
+    strictness: Strictness,
+    repairs: u64,
+    preamble: PreamblePolicy,
+    preamble_read: bool,
+    peer: Option<PeerInfo>,
+    /// Bytes read while looking for a preamble that belong to the frames.
+    unread: Vec<u8>,
+    /// Frames larger than this are skipped as `TooLarge`.
+    max_message_size: usize,
+    /// What is left to read and drop of a frame over the maximum.
+    skipping: usize,
+    /// Frames larger than this go through `StreamingStream`.
+    streaming_threshold: usize,
+    streaming_chunk: usize,
+    /// Where the next unread frame starts.
+    offset: u64,
+    frames: u64,
+    capture_window: usize,
+    /// The header timestamp of the last message consumed.
+    timestamp: Option<Duration>,
+    zero_frame: ZeroFrame,
+    zero_frames: u64,
+    heartbeats: u64,
+    throttling: Option<Throttling>,
+    parking: Option<deferred::Parking>,
+    backoff: Backoff,
+    /// The hint from the stream of the last message consumed.
+    pressure: Pressure,
+    /// How long `next` is to sleep before its next read.
+    delay: Option<Duration>,
+    /// Whether a stream asked to stop and the session has yet to resume.
+    stopped: bool,
+    protocol: Protocol,
+    /// The ID of control frames, if any.
+    control_id: Option<Vec<u8>>,
+    /// The longest token `next` reads before authenticating it, if frames
+    /// are to be authenticated before their bodies are read.
+    token_gate: Option<usize>,
+    /// The token the gate last had authenticated, for it to let through
+    /// again without asking the server until consuming a frame fails auth.
+    gate_passed: Vec<u8>,
+    refused_at_gate: u64,
+    on_fatal: FatalCapture<Box<Write + Send>>,
+    /// What a capture's deadline is measured by.
+    capture_clock: Box<Clock + Send>,
+    fatal_captured: Option<FatalCaptured>,
+    trace: Recorder,
+}
+
+/// A session's server, borrowed or, so that the session can be handed to
+/// another thread with nothing left behind, owned.
+enum Handle<'a, S: 'a> {
+    Borrowed(&'a mut S),
+    Owned(Box<S>),
+}
+
+impl<'a, S: 'a> Deref for Handle<'a, S> {
+    type Target = S;
+    fn deref(&self) -> &S {
+        match *self {
+            Handle::Borrowed(ref server) => server,
+            Handle::Owned(ref server) => server,
+        }
+    }
+}
+
+impl<'a, S: 'a> DerefMut for Handle<'a, S> {
+    fn deref_mut(&mut self) -> &mut S {
+        match *self {
+            Handle::Borrowed(ref mut server) => server,
+            Handle::Owned(ref mut server) => server,
+        }
+    }
+}
+
+/// What to make of a frame of size zero, which holds no message at all.
+/// Other frames too short for a header are parse errors whatever this says.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZeroFrame {
+    /// Tries to parse it, failing for want of a token size.
+    Error,
+    /// Skips it, counting it in `zero_frames`.
+    Ignore,
+    /// Skips it as a keepalive, counting it in `heartbeats`.
+    Heartbeat,
+}
+
+impl Default for ZeroFrame {
+    fn default() -> Self {
+        ZeroFrame::Error
+    }
+}
+
+/// The outcome of `Session::try_next`.
+#[derive(Debug)]
+pub enum TryNext<T> {
+    Ready(T),
+    /// The reader would block before a whole frame arrived; what did arrive
+    /// is kept for the next call.
+    NotReady,
+    Closed,
+}
+
+/// A message the server consumed, as far as callers commonly want it for
+/// logging and metrics, with what the server acknowledged it with. Its
+/// token, a credential, is left out, and its ID is copied, or with
+/// `Session::next_interned`, interned.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Accepted<K = (), I = Vec<u8>> {
+    pub id: I,
+    pub timestamp: Duration,
+    pub payload_len: usize,
+    /// The server's `ConsumeOk`, or `None` for a streamed message, which
+    /// is pushed past `consume`.
+    pub ack: Option<K>,
+}
+
+impl<K> Accepted<K> {
+    fn of(header: &message::Header, payload_len: usize, ack: K) -> Self {
+        Accepted { ack: Some(ack), ..Accepted::streamed(header, payload_len) }
+    }
+
+    fn streamed(header: &message::Header, payload_len: usize) -> Self {
+        Accepted {
+            id: header.id.to_owned(),
+            timestamp: header.timestamp,
+            payload_len: payload_len,
+            ack: None,
+        }
+    }
+}
+
+pub type NextResult<S> = Result<
+    Accepted<<S as Server>::ConsumeOk>,
+    Error<<S as Server>::AuthErr, <<S as Server>::Stream as Stream>::PushErr>>;
+
+/// Consumes a frame as `parse_frame` parsed it, noting its timestamp if it
+/// is consumed.
+fn handle<S: Server>(server: &mut S,
+                     timestamp: &mut Option<Duration>,
+                     parsed: Result<Message, Error<S::AuthErr, <S::Stream as Stream>::PushErr>>,
+                     spans: &mut Spans)
+                     -> NextResult<S> {
+    parsed.and_then(|msg| consume_parsed(server, timestamp, msg, spans))
+          .map(|(msg, ack, _)| Accepted::of(&msg.header, msg.payload.len(), ack))
+}
+
+/// Parses a frame and checks its header against `strictness`.
+fn parse_frame<'b, A, P>(strictness: Strictness,
+                         capture: usize,
+                         prefix_len: usize,
+                         bytes: &'b [u8],
+                         spans: &mut Spans)
+                         -> Result<Message<'b>, Error<A, P>> {
+    spans.begin("parse");
+    let parsed = Message::parse_diagnostic(bytes, capture)
+                     .map_err(|e| {
+                         Error::Parse {
+                             error: e,
+                             prefix_len: prefix_len,
+                         }
+                     })
+                     .and_then(|msg| {
+                         msg.header.check(strictness).map(|()| msg).map_err(Into::into)
+                     });
+    spans.end();
+    parsed
+}
+
+/// Parses and consumes a frame, returning the message as borrowed from
+/// `bytes`, its acknowledgement and the pressure on the stream it went to.
+fn consume_frame<'b, S: Server>(server: &mut S,
+                                strictness: Strictness,
+                                capture: usize,
+                                prefix_len: usize,
+                                timestamp: &mut Option<Duration>,
+                                bytes: &'b [u8],
+                                spans: &mut Spans)
+                                -> Result<(Message<'b>, S::ConsumeOk, Pressure),
+                                          Error<S::AuthErr, <S::Stream as Stream>::PushErr>> {
+    let msg = try!(parse_frame(strictness, capture, prefix_len, bytes, spans));
+    consume_parsed(server, timestamp, msg, spans)
+}
+
+/// `consume_frame`, for a message already parsed.
+fn consume_parsed<'b, S: Server>(server: &mut S,
+                                 timestamp: &mut Option<Duration>,
+                                 msg: Message<'b>,
+                                 spans: &mut Spans)
+                                 -> Result<(Message<'b>, S::ConsumeOk, Pressure),
+                                           Error<S::AuthErr, <S::Stream as Stream>::PushErr>> {
+    let (id, ts) = (msg.header.id, msg.header.timestamp);
+    spans.begin("consume");
+    let (consumed, pressure) =
+        server.consume_parts_pressed(msg.header.token, id, ts, msg.payload, spans);
+    spans.end();
+    let ack = try!(consumed);
+    *timestamp = Some(ts);
+    Ok((msg, ack, pressure))
+}
+
+impl<S: 'static, R> Session<'static, S, R> {
+    /// A session that owns its server, and so borrows nothing: with a
+    /// `Send` server and reader, it is `Send` however it is configured,
+    /// and can be set up on the thread that accepts the connection and run
+    /// on another.
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// use sousveillance_server::clock::SystemClock;
+    /// use sousveillance_server::server::{HighWaterMark, TokenServer};
+    /// use sousveillance_server::session::{Backoff, BucketSpec, Throttle, Throttling};
+    /// use sousveillance_server::stream::FileStream;
+    /// use sousveillance_server::Session;
+    ///
+    /// fn frame(timestamp: u8, payload: &[u8]) -> Vec<u8> {
+    ///     let size = 16 + payload.len();
+    ///     let mut frame = vec![0, size as u8, 0, 1, b't', 0, 3];
+    ///     frame.extend_from_slice(b"cam");
+    ///     frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, timestamp]);
+    ///     frame.extend_from_slice(payload);
+    ///     frame
+    /// }
+    ///
+    /// let mut tokens = TokenServer::new_send();
+    /// tokens.add_token(b"t").insert(b"cam".to_vec(), FileStream::new(vec![]));
+    /// let capture: Vec<u8> = (1..4).flat_map(|ts| frame(ts, b"payload")).collect();
+    ///
+    /// let mut session = Session::owning(HighWaterMark::new(tokens), Cursor::new(capture));
+    /// let spec = BucketSpec {
+    ///     bytes_per_sec: 1 << 20,
+    ///     burst: 1 << 10,
+    /// };
+    /// session.set_throttling(Throttling::new(Some(Throttle::new(spec, SystemClock)), None));
+    /// let mut backoff = Backoff::new();
+    /// backoff.set_max_delay(Duration::from_millis(10));
+    /// session.set_backoff(backoff);
+    /// session.set_capture_window(32);
+    ///
+    /// let worker = thread::spawn(move || {
+    ///     let stored = session.by_ref().filter(|result| result.is_ok()).count();
+    ///     (stored, session.into_server().unwrap())
+    /// });
+    /// let (stored, server) = worker.join().unwrap();
+    /// assert_eq!(3, stored);
+    /// let mut tokens = server.into_inner();
+    /// assert!(tokens.add_token(b"t")[&b"cam"[..]].offset() > 0);
+    /// ```
+    pub fn owning(server: S, reader: R) -> Self {
+        Session::with_handle(Handle::Owned(Box::new(server)), reader)
+    }
+}
+
+impl<'a, S: 'a , R> Session<'a, S, R> {
+    pub fn new(server: &'a mut S, reader: R) -> Self {
+        Session::with_handle(Handle::Borrowed(server), reader)
+    }
+
+    /// A session whose frames are prefixed as `framing` says.
+    pub fn with_framing(server: &'a mut S, reader: R, framing: Framing) -> Self {
+        let mut session = Session::new(server, reader);
+        session.set_framing(framing);
+        session
+    }
+
+    /// A session that skips, as `TooLarge`, any frame declaring more than
+    /// `max` bytes, without buffering any of it, so that a peer cannot
+    /// make it hold more than `max` bytes of a frame at a time.
+    pub fn with_max_message_size(server: &'a mut S, reader: R, max: usize) -> Self {
+        let mut session = Session::new(server, reader);
+        session.max_message_size = max;
+        session
+    }
+
+    fn with_handle(server: Handle<'a, S>, reader: R) -> Self {
+        Session {
+            server: server,
+            reader: reader,
+            buffer: vec![],
+            pending: vec![],
+            framing: Framing::default(),
+            held_prefix: [0; MAX_VARINT_LEN],
+            held_prefix_len: 0,
+            frame_prefix_len: 0,
+            strictness: Strictness::default(),
+            repairs: 0,
+            preamble: PreamblePolicy::default(),
+            preamble_read: false,
+            peer: None,
+            unread: vec![],
+            max_message_size: ::std::usize::MAX,
+            skipping: 0,
+            streaming_threshold: ::std::usize::MAX,
+            streaming_chunk: 4096,
+            offset: 0,
+            frames: 0,
+            capture_window: 0,
+            timestamp: None,
+            zero_frame: ZeroFrame::default(),
+            zero_frames: 0,
+            heartbeats: 0,
+            throttling: None,
+            parking: None,
+            backoff: Backoff::default(),
+            pressure: Pressure::None,
+            delay: None,
+            stopped: false,
+            protocol: Protocol::new(PreamblePolicy::default()),
+            control_id: None,
+            token_gate: None,
+            gate_passed: vec![],
+            refused_at_gate: 0,
+            on_fatal: FatalCapture::None,
+            capture_clock: Box::new(SystemClock),
+            fatal_captured: None,
+            trace: Recorder::off(),
+        }
+    }
+
+    /// Takes effect if set before the first frame is read.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    /// Applies the settings of `config` a session has: its strictness,
+    /// framing and maximum message size. The reader a session is given can
+    /// read ahead as `config` says by `ValidatedConfig::read_ahead`.
+    pub fn configure(&mut self, config: &ValidatedConfig) {
+        let config = config.get();
+        self.strictness = config.strictness;
+        self.framing = config.framing;
+        self.max_message_size = config.max_message_size.unwrap_or(::std::usize::MAX);
+    }
+
+    /// Makes parse errors carry up to `window` bytes of the offending
+    /// message; zero, the default, captures nothing.
+    pub fn set_capture_window(&mut self, window: usize) {
+        self.capture_window = window;
+    }
+
+    /// Makes `next` read only a frame's token, if it is at most
+    /// `max_token_len` bytes, and have the server authenticate it before
+    /// reading the rest. A frame whose token is refused, or longer, is
+    /// skipped without being buffered and comes to an auth error, so a
+    /// sender without a token costs the length of one.
+    ///
+    /// A token the server authenticated is let through again without
+    /// asking it, since consuming each frame authenticates it anyway, until
+    /// a frame with it fails auth. A pending decision lets the frame through
+    /// for deferred auth to wait on. `try_next` reads frames whole
+    /// regardless.
+    pub fn set_token_gate(&mut self, max_token_len: usize) {
+        self.token_gate = Some(max_token_len);
+    }
+
+    /// How many frames the token gate has skipped.
+    pub fn refused_at_gate(&self) -> u64 {
+        self.refused_at_gate
+    }
+
+    /// What `next` does after an error that loses the framing; see
+    /// `post_mortem`. `try_next` never captures.
+    pub fn set_on_fatal<W: Write + Send + 'static>(&mut self, capture: FatalCapture<W>) {
+        self.on_fatal = match capture {
+            FatalCapture::None => FatalCapture::None,
+            FatalCapture::Drain { max_bytes, deadline, sink } => {
+                FatalCapture::Drain {
+                    max_bytes: max_bytes,
+                    deadline: deadline,
+                    sink: Box::new(sink),
+                }
+            }
+        };
+    }
+
+    /// Sets the clock a capture's deadline is measured by, the system's
+    /// unless set. A capture waits on a reader that is not ready through
+    /// the backoff's sleeper.
+    pub fn set_capture_clock<C: Clock + Send + 'static>(&mut self, clock: C) {
+        self.capture_clock = Box::new(clock);
+    }
+
+    /// What was captured after a fatal error, once the session has ended.
+    pub fn fatal_captured(&self) -> Option<FatalCaptured> {
+        self.fatal_captured
+    }
+
+    /// Makes `next` trace each frame it reads, timed by `clock`, keeping
+    /// the last `capacity` traces; see `trace`. `try_next` is not traced.
+    pub fn set_tracing(&mut self, capacity: usize, clock: Arc<Clock + Send + Sync>) {
+        self.trace = Recorder::new(capacity, clock);
+    }
+
+    /// The traces kept, oldest first; none unless tracing is on.
+    pub fn recent_traces(&self) -> Vec<&MessageTrace> {
+        self.trace.recent()
+    }
+
+    pub fn set_zero_frame(&mut self, policy: ZeroFrame) {
+        self.zero_frame = policy;
+    }
+
+    /// How many zero-size frames `ZeroFrame::Ignore` has skipped.
+    pub fn zero_frames(&self) -> u64 {
+        self.zero_frames
+    }
+
+    /// How many zero-size frames `ZeroFrame::Heartbeat` has skipped.
+    pub fn heartbeats(&self) -> u64 {
+        self.heartbeats
+    }
+
+    /// Limits how fast frame bodies are read from then on.
+    pub fn set_throttling(&mut self, throttling: Throttling) {
+        self.throttling = Some(throttling);
+    }
+
+    /// All zeros if the session is not throttled.
+    pub fn throttle_stats(&self) -> ThrottleStats {
+        self.throttling.as_ref().map(Throttling::stats).unwrap_or(ThrottleStats::default())
+    }
+
+    /// How to act on a stream asking to slow down.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    pub fn pressure_stats(&self) -> PressureStats {
+        self.backoff.stats()
+    }
+
+    /// The hint from the stream of the last message consumed. `next` acts
+    /// on it; `try_next` never sleeps, so a caller polling should.
+    pub fn pressure(&self) -> Pressure {
+        self.pressure
+    }
+
+    /// Whether a stream asked to stop. A stopped session yields the frames
+    /// it already holds whole, then ends with `None`, or is `NotReady`,
+    /// until resumed.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Reads again after a stream asked to stop; unlike `resume`, which
+    /// starts a session from a checkpoint.
+    pub fn resume_reading(&mut self) {
+        self.stopped = false;
+        self.pressure = Pressure::None;
+    }
+
+    /// Takes the hint after a message, whether to slow down or stop.
+    fn pressed(&mut self, pressure: Pressure) {
+        self.backoff.note(pressure);
+        self.pressure = pressure;
+        self.delay = match pressure {
+            Pressure::SlowDown { suggested_delay } => Some(suggested_delay),
+            _ => None,
+        };
+        if pressure == Pressure::Stop {
+            self.stopped = true;
+        }
+    }
+
+    /// Whether a whole frame is held from earlier reads, so that a stopped
+    /// session may still yield it.
+    fn frame_held(&self) -> bool {
+        let mut held = self.held_prefix[..self.held_prefix_len]
+                           .iter()
+                           .chain(&self.pending)
+                           .chain(&self.unread);
+        let mut prefix = [0_u8; MAX_VARINT_LEN];
+        let mut n = 0;
+        loop {
+            match self.framing.decode(&prefix[..n]) {
+                Prefix::Partial(more) => {
+                    for byte in &mut prefix[n..n + more] {
+                        match held.next() {
+                            Some(&held) => *byte = held,
+                            None => return false,
+                        }
+                    }
+                    n += more;
+                }
+                Prefix::Whole { size, .. } => return held.count() >= size,
+                Prefix::BadVarint => return false,
+            }
+        }
+    }
+
+    /// How many quirks `Strictness::Lenient` has tolerated.
+    pub fn repairs(&self) -> u64 {
+        self.repairs
+    }
+
+    /// Takes effect if set before the first frame is read.
+    pub fn set_preamble_policy(&mut self, policy: PreamblePolicy) {
+        self.preamble = policy;
+        self.protocol = Protocol::new(policy);
+    }
+
+    /// Where the connection is in the protocol.
+    pub fn protocol_state(&self) -> protocol::State {
+        self.protocol.state()
+    }
+
+    /// Makes frames for `id` control frames to the protocol, which allows
+    /// them only once the peer has authenticated. No ID is a control ID
+    /// unless set; the admin protocol's is `server::admin::CONTROL_ID`.
+    pub fn set_control_id(&mut self, id: &[u8]) {
+        self.control_id = Some(id.to_owned());
+    }
+
+    /// Takes `input`, or fails if the protocol does not allow it now.
+    fn admit<A, P>(&mut self, input: protocol::Input) -> Result<(), Error<A, P>> {
+        admit(&mut self.protocol, input)
+    }
+
+    fn is_control(&self, header: &Header) -> bool {
+        self.control_id.as_ref().map_or(false, |id| header.id == &id[..])
+    }
+
+    /// What a whole frame, parsed as `parsed`, is to the protocol.
+    fn frame_input<T>(&self, bytes: &[u8], parsed: &Result<Message, T>) -> protocol::Input {
+        if bytes.is_empty() && self.zero_frame == ZeroFrame::Heartbeat {
+            return protocol::Input::Heartbeat;
+        }
+        match *parsed {
+            Ok(ref msg) if self.is_control(&msg.header) => protocol::Input::Control,
+            _ => protocol::Input::Data,
+        }
+    }
+
+    /// Moves the protocol on if `result` shows the frame's token
+    /// authenticated.
+    fn dispatched<T, A, P>(&mut self, result: &Result<T, Error<A, P>>) {
+        let authenticated = match *result {
+            Ok(_) => true,
+            Err(Error::Consume(server::ConsumeError::Auth(_))) => {
+                self.gate_passed.clear();
+                false
+            }
+            Err(Error::Consume(_)) => true,
+            Err(_) => false,
+        };
+        if authenticated {
+            self.protocol.on(protocol::Input::Authenticated);
+        }
+    }
+
+    pub fn server(&self) -> &S {
+        &self.server
+    }
+
+    /// The server, for registering streams or reaping between frames
+    /// without ending the session.
+    pub fn server_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    /// The server, if the session owns it.
+    pub fn into_server(self) -> Option<S> {
+        match self.server {
+            Handle::Borrowed(_) => None,
+            Handle::Owned(server) => Some(*server),
+        }
+    }
+
+    /// The peer's preamble, once read.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer.as_ref()
+    }
+
+    /// The header timestamp of the last message consumed.
+    pub fn last_timestamp(&self) -> Option<Duration> {
+        self.timestamp
+    }
+
+    /// How many frames have been read in full, whatever became of them.
+    pub fn frames_consumed(&self) -> u64 {
+        self.frames
+    }
+
+    /// Accounts for a frame of `size` bytes, prefix excluded, read in full.
+    fn frame_read(&mut self, size: usize) {
+        self.offset += (self.frame_prefix_len + size) as u64;
+        self.frames += 1;
+    }
+
+    /// Makes the buffer `size` bytes long, keeping what it holds and its
+    /// allocation. Anything new is zeroed, so a reader never sees bytes no
+    /// one wrote.
+    fn size_buffer(&mut self, size: usize) {
+        self.buffer.resize(size, 0);
+    }
+
+    /// Skips a zero-size frame unless the policy says to parse it, before
+    /// anything is buffered.
+    fn skip_zero_frame(&mut self, size: usize) -> bool {
+        if size != 0 {
+            return false;
+        }
+        match self.zero_frame {
+            ZeroFrame::Error => return false,
+            ZeroFrame::Ignore => self.zero_frames += 1,
+            // Left for `admit` to refuse.
+            ZeroFrame::Heartbeat if !self.protocol.allows(protocol::Input::Heartbeat) => {
+                return false
+            }
+            ZeroFrame::Heartbeat => self.heartbeats += 1,
+        }
+        self.frame_read(0);
+        true
+    }
+}
+
+impl<'a, S: 'a, R: Read> Session<'a, S, R> {
+    /// Reads a length prefix into `prefix`, one read after another, and
+    /// returns how many bytes of it there are: as many as the framing says,
+    /// or fewer at the end of the input. A varint is read a byte at a time,
+    /// so as not to read past it. Bytes that arrive before a failed read are
+    /// kept for the next call rather than lost.
+    fn read_prefix(&mut self, prefix: &mut [u8; MAX_VARINT_LEN]) -> io::Result<usize> {
+        let mut n = self.held_prefix_len;
+        prefix[..n].copy_from_slice(&self.held_prefix[..n]);
+        self.held_prefix_len = 0;
+        while let Prefix::Partial(more) = self.framing.decode(&prefix[..n]) {
+            match read_after(&mut self.unread, &mut self.reader, &mut prefix[n..n + more]) {
+                Ok(0) => break,
+                Ok(k) => n += k,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.held_prefix[..n].copy_from_slice(&prefix[..n]);
+                    self.held_prefix_len = n;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    /// Fills the buffer from `start` on as fast as any throttling allows,
+    /// until it is full or the input ends, returning how much of it is
+    /// filled.
+    fn fill_buffer(&mut self, start: usize) -> io::Result<usize> {
+        let rest = &mut self.buffer[start..];
+        fill_through(&mut self.throttling, &mut self.unread, &mut self.reader, rest)
+            .map(|n| start + n)
+    }
+
+    /// Fills `buf` as fast as any throttling allows, until it is full or
+    /// the input ends, returning how much was read.
+    fn fill_input(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        fill_through(&mut self.throttling, &mut self.unread, &mut self.reader, buf)
+    }
+
+    /// Whether a frame of `size` bytes by its prefix, which the input ended
+    /// `found` bytes into, is whole after all: its prefix counted itself,
+    /// which only `Strictness::Lenient` repairs. Nothing else could explain
+    /// the input ending exactly a prefix short.
+    fn counted_itself(&self, found: usize, size: usize) -> bool {
+        self.strictness == Strictness::Lenient && found > 0 &&
+        found + self.frame_prefix_len == size
+    }
+
+    /// What `next` returns for a frame the input ended partway through:
+    /// `e`, or nothing, counting a repair, if lenient.
+    fn cut_short<A, P>(&mut self, e: Error<A, P>) -> Option<Error<A, P>> {
+        if self.strictness == Strictness::Lenient {
+            self.repairs += 1;
+            return None;
+        }
+        Some(e)
+    }
+
+    /// Keeps the bytes of a prefix after which framing is lost, for a
+    /// post-mortem capture.
+    fn keep_broken_prefix(&mut self, bytes: &[u8]) {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Skips a frame of `size` bytes if it is over the maximum, counting it
+    /// as read, and returns the error to report for it. Its bytes are left
+    /// for the next call to read and drop.
+    fn oversized<A, P>(&mut self, size: usize) -> Option<Error<A, P>> {
+        if size <= self.max_message_size {
+            return None;
+        }
+        self.skipping = size;
+        self.frame_read(size);
+        Some(Error::TooLarge {
+            size: size,
+            max: self.max_message_size,
+        })
+    }
+
+    /// Reads and drops what is left of a skipped frame, as fast as any
+    /// throttling allows, until it is all gone or the input ends.
+    fn skip_rest(&mut self) -> io::Result<()> {
+        let mut scratch = [0_u8; 512];
+        while self.skipping > 0 {
+            let rest = &mut scratch[..cmp::min(self.skipping, 512)];
+            let read = match self.throttling {
+                None => read_after(&mut self.unread, &mut self.reader, rest),
+                Some(ref mut throttling) => {
+                    fill_throttled(throttling, &mut self.unread, &mut self.reader, rest)
+                }
+            };
+            match read {
+                Ok(0) => self.skipping = 0,
+                Ok(n) => self.skipping -= n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What reading a frame came to: an item, or a frame skipped without one.
+enum Step<T> {
+    Done(T),
+    Skipped,
+}
+
+/// What `Session::read_frame` comes to, keeping results as `T`s.
+type Stepped<T, S> = Step<Option<Result<T,
+                                        Error<<S as Server>::AuthErr,
+                                              <<S as Server>::Stream as Stream>::PushErr>>>>;
+
+/// `util::fill` over what sniffing left over, then `reader`: a reader may
+/// hand over less than asked for well before its end.
+fn fill<R: Read>(unread: &mut Vec<u8>, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    util::fill(&mut After(unread, reader), buf)
+}
+
+/// Reads as `read_after` does.
+struct After<'a, R: 'a>(&'a mut Vec<u8>, &'a mut R);
+
+impl<'a, R: Read> Read for After<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_after(self.0, self.1, buf)
+    }
+}
+
+/// Reads into `buf` as fast as `throttling` allows, until it is full or
+/// the input ends, returning how much was read.
+fn fill_throttled<R: Read>(throttling: &mut Throttling,
+                           unread: &mut Vec<u8>,
+                           reader: &mut R,
+                           buf: &mut [u8])
+                           -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        let allowed = throttling.allow(buf.len() - n);
+        match read_after(unread, reader, &mut buf[n..n + allowed]) {
+            Ok(0) => break,
+            Ok(k) => {
+                throttling.consumed(k);
+                n += k;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// The error for a frame of `size` bytes that ended after `found`.
+fn truncated<A, P>(found: usize, size: usize) -> Error<A, P> {
+    Error::Truncated {
+        found: found as u32,
+        remaining: (size - found) as u32,
+    }
+}
+
+/// `fill`, or `fill_throttled` under throttling.
+fn fill_through<R: Read>(throttling: &mut Option<Throttling>,
+                         unread: &mut Vec<u8>,
+                         reader: &mut R,
+                         buf: &mut [u8])
+                         -> io::Result<usize> {
+    match *throttling {
+        None => fill(unread, reader, buf),
+        Some(ref mut throttling) => fill_throttled(throttling, unread, reader, buf),
+    }
+}
+
+/// Takes `input` into `protocol`, or fails if it does not allow it now.
+fn admit<A, P>(protocol: &mut Protocol, input: protocol::Input) -> Result<(), Error<A, P>> {
+    match protocol.on(input) {
+        Transition::To(_) => Ok(()),
+        Transition::Violation { state, received } => {
+            Err(Error::ProtocolViolation {
+                state: state,
+                received: received,
+            })
+        }
+    }
+}
+
+/// Reads what sniffing for a preamble left over, then `reader`. A read
+/// takes from one or the other, never both, so that an error from `reader`
+/// is returned rather than lost behind leftovers already copied; a short
+/// read is no end of input, and every caller reads on.
+fn read_after<R: Read>(unread: &mut Vec<u8>, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    if unread.is_empty() {
+        return reader.read(buf);
+    }
+    let n = cmp::min(unread.len(), buf.len());
+    buf[..n].copy_from_slice(&unread[..n]);
+    unread.drain(..n);
+    Ok(n)
+}
+
+/// Constructing one allocates nothing unless it holds something that does:
+/// an I/O error with a custom payload, a `Parse` error from a session with
+/// a capture window, a group's partial failure, or whatever the server's
+/// own error types allocate. Malformed input alone never does.
+#[derive(Debug)]
+pub enum Error<A, P> {
+    Read(io::Error),
+    /// The input ended partway through a length prefix.
+    EofInMessageSize,
+    /// A varint length prefix was longer than it may be or said more than
+    /// a `u32`.
+    BadVarint,
+    Truncated {
+        found: u32,
+        remaining: u32,
+    },
+    /// The frame declared more bytes than the session takes, and was
+    /// skipped unread; the frames after it can still be read.
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A header that would not parse, in a frame whose length prefix took
+    /// `prefix_len` bytes.
+    Parse {
+        error: message::Error,
+        prefix_len: usize,
+    },
+    Nonconforming(message::Nonconformance),
+    Consume(server::ConsumeError<A, P>),
+    Preamble(PreambleError),
+    /// Writing a streamed payload failed.
+    Sink(io::Error),
+    /// A frame parked on this ticket, which went unresolved too long.
+    AuthExpired(server::AuthTicket),
+    /// Parking the frame would exceed the bytes its token may have parked,
+    /// or with `total`, the bytes every token together may.
+    ParkedFull {
+        cap: usize,
+        total: bool,
+    },
+    /// The frame is not allowed where the connection is in the protocol.
+    ProtocolViolation {
+        state: protocol::State,
+        received: protocol::Input,
+    },
+}
+
+impl<A, P> From<PreambleError> for Error<A, P> {
+    fn from(e: PreambleError) -> Self {
+        Error::Preamble(e)
+    }
+}
+
+impl<A, P> From<message::Nonconformance> for Error<A, P> {
+    fn from(e: message::Nonconformance) -> Self {
+        Error::Nonconforming(e)
+    }
+}
+
+impl<A, P> From<server::ConsumeError<A, P>> for Error<A, P> {
+    fn from(e: server::ConsumeError<A, P>) -> Self {
+        Error::Consume(e)
+    }
+}
+
+impl<A, P> From<io::Error> for Error<A, P> {
+    fn from(e: io::Error) -> Self {
+        Error::Read(e)
+    }
+}
+
+impl<A, P> Error<A, P> {
+    /// The kind of a read, consume or sink error, `UnexpectedEof` for a cut-short
+    /// frame, `InvalidData` for a malformed varint, an oversized frame or a
+    /// parse or preamble error, and `TimedOut` for an expired auth ticket.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Read(ref e) => e.kind(),
+            Error::EofInMessageSize => io::ErrorKind::UnexpectedEof,
+            Error::BadVarint => io::ErrorKind::InvalidData,
+            Error::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            Error::TooLarge { .. } => io::ErrorKind::InvalidData,
+            Error::Parse { ref error, .. } => error.io_kind(),
+            Error::Nonconforming(ref e) => e.io_kind(),
+            Error::Consume(ref e) => e.io_kind(),
+            Error::Preamble(_) => io::ErrorKind::InvalidData,
+            Error::Sink(ref e) => e.kind(),
+            Error::AuthExpired(_) => io::ErrorKind::TimedOut,
+            Error::ParkedFull { .. } => io::ErrorKind::Other,
+            Error::ProtocolViolation { .. } => io::ErrorKind::InvalidData,
+        }
+    }
+
+    /// For a parse error, where in its frame, counting the length prefix
+    /// as the session's `Framing` wrote it, the missing header part would
+    /// have started.
+    pub fn frame_offset(&self) -> Option<usize> {
+        match *self {
+            Error::Parse { ref error, prefix_len } => Some(prefix_len + error.offset),
+            _ => None,
+        }
+    }
+}
+
+impl<A, P> Error<A, P>
+    where A: error::Error + Send + Sync + 'static,
+          P: error::Error + Send + Sync + 'static
+{
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(self.io_kind(), self)
+    }
+}
+
+impl<A, P> From<Error<A, P>> for io::Error
+    where A: error::Error + Send + Sync + 'static,
+          P: error::Error + Send + Sync + 'static
+{
+    fn from(e: Error<A, P>) -> Self {
+        e.into_io()
+    }
+}
+
+impl<A: Display, P: Display> Display for Error<A, P> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::Read(ref e) => e.fmt(f),
+            Error::EofInMessageSize => f.write_str("input ended within a message size"),
+            Error::BadVarint => f.write_str("malformed varint message size"),
+            Error::Truncated { found, remaining } => write!(
+                f, "{} bytes of message found; {} bytes remaining",
+                found, remaining),
+            Error::TooLarge { size, max } => {
+                write!(f, "message of {} bytes over the maximum of {}", size, max)
+            }
+            Error::Parse { ref error, .. } => error.fmt(f),
+            Error::Nonconforming(ref e) => e.fmt(f),
+            Error::Consume(ref e) => e.fmt(f),
+            Error::Preamble(ref e) => e.fmt(f),
+            Error::Sink(ref e) => write!(f, "cannot write payload: {}", e),
+            Error::AuthExpired(ticket) => write!(f, "{} expired unresolved", ticket),
+            Error::ParkedFull { cap, total: false } => {
+                write!(f, "parking the frame would exceed {} bytes parked for its token", cap)
+            }
+            Error::ParkedFull { cap, total: true } => {
+                write!(f, "parking the frame would exceed {} bytes parked in all", cap)
+            }
+            Error::ProtocolViolation { state, received } => {
+                write!(f, "received {} in the {} state", received, state)
+            }
+        }
+    }
+}
+
+impl<A: error::Error, P: error::Error> error::Error for Error<A, P> {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Read(ref e) => e.description(),
+            Error::EofInMessageSize => "input ended within a message size",
+            Error::BadVarint => "malformed varint message size",
+            Error::Truncated { .. } => "truncated message",
+            Error::TooLarge { .. } => "message too large",
+            Error::Parse { ref error, .. } => error.description(),
+            Error::Nonconforming(ref e) => e.description(),
+            Error::Consume(ref e) => e.description(),
+            Error::Preamble(ref e) => e.description(),
+            Error::Sink(_) => "cannot write payload",
+            Error::AuthExpired(_) => "auth ticket expired",
+            Error::ParkedFull { .. } => "too many bytes parked",
+            Error::ProtocolViolation { .. } => "protocol violation",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Read(ref e) => Some(e),
+            Error::Parse { ref error, .. } => Some(error),
+            Error::Nonconforming(ref e) => Some(e),
+            Error::Consume(ref e) => Some(e),
+            Error::Preamble(ref e) => Some(e),
+            Error::Sink(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, S: 'a + Server, R: Read> Session<'a, S, R> {
+    /// Reads the preamble the policy calls for, reconfiguring the session
+    /// from it. The first `next` or `try_next` does this anyway, but only
+    /// `next` may block, so call this first when polling.
+    pub fn read_preamble(&mut self)
+                         -> Result<(), Error<S::AuthErr, <S::Stream as Stream>::PushErr>> {
+        if self.preamble_read || self.preamble == PreamblePolicy::Forbidden {
+            return Ok(());
+        }
+        self.preamble_read = true;
+        match try!(preamble::sniff(&mut self.reader, self.strictness)) {
+            preamble::Sniffed::Preamble(peer) => {
+                try!(self.admit(protocol::Input::Preamble));
+                if let Some(strictness) = peer.strictness {
+                    self.strictness = strictness;
+                }
+                self.offset += peer.to_bytes().len() as u64;
+                self.peer = Some(peer);
+                Ok(())
+            }
+            // Frames with no preamble before them, if one is required, are
+            // refused by the protocol.
+            preamble::Sniffed::Absent(bytes) => {
+                self.unread = bytes;
+                Ok(())
+            }
+        }
+    }
+
+    /// Like `next`, but returns `NotReady` instead of blocking, for readers
+    /// that report `WouldBlock`. Never reads past the current frame, so
+    /// `next` can take over between frames; a partial frame is only seen
+    /// by `try_next`.
+    pub fn try_next(&mut self) -> TryNext<NextResult<S>> {
+        if let Err(e) = self.read_preamble() {
+            return TryNext::Ready(Err(e));
+        }
+        if let Some(ticket) = self.parking.as_mut().and_then(deferred::Parking::next_expired) {
+            return TryNext::Ready(Err(Error::AuthExpired(ticket)));
+        }
+        if self.stopped && !self.frame_held() {
+            return TryNext::NotReady;
+        }
+        self.delay = None;
+        self.pending.extend_from_slice(&self.held_prefix[..self.held_prefix_len]);
+        self.held_prefix_len = 0;
+        let mut chunk = [0_u8; 512];
+        loop {
+            if self.skipping > 0 {
+                let mut wanted = cmp::min(self.skipping, chunk.len());
+                if let Some(throttling) = self.throttling.as_mut() {
+                    wanted = throttling.try_allow(wanted);
+                    if wanted == 0 {
+                        return TryNext::NotReady;
+                    }
+                }
+                match read_after(&mut self.unread, &mut self.reader, &mut chunk[..wanted]) {
+                    Ok(0) => {
+                        self.skipping = 0;
+                        return TryNext::Closed;
+                    }
+                    Ok(n) => {
+                        if let Some(throttling) = self.throttling.as_mut() {
+                            throttling.consumed(n);
+                        }
+                        self.skipping -= n;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return TryNext::NotReady,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return TryNext::Ready(Err(e.into())),
+                }
+                continue;
+            }
+            let (prefix_len, needed) = match self.framing.decode(&self.pending) {
+                Prefix::Partial(more) => (None, self.pending.len() + more),
+                Prefix::Whole { size, len } => {
+                    self.frame_prefix_len = len;
+                    (Some(len), len + size)
+                }
+                Prefix::BadVarint => {
+                    self.pending.clear();
+                    return TryNext::Ready(Err(Error::BadVarint));
+                }
+            };
+            if let Some(prefix_len) = prefix_len {
+                if self.pending.len() == prefix_len && self.skip_zero_frame(needed - prefix_len) {
+                    self.pending.clear();
+                    continue;
+                }
+                if self.pending.len() == prefix_len {
+                    if let Some(e) = self.oversized(needed - prefix_len) {
+                        self.pending.clear();
+                        return TryNext::Ready(Err(e));
+                    }
+                }
+            }
+            if let (Some(prefix_len), true) = (prefix_len, self.pending.len() == needed) {
+                match self.take_pending(prefix_len) {
+                    Some(result) => return TryNext::Ready(result),
+                    None => continue,
+                }
+            }
+
+            let mut wanted = cmp::min(needed - self.pending.len(), chunk.len());
+            let body = prefix_len.is_some();
+            if let (true, Some(throttling)) = (body, self.throttling.as_mut()) {
+                wanted = throttling.try_allow(wanted);
+                if wanted == 0 {
+                    return TryNext::NotReady;
+                }
+            }
+            match read_after(&mut self.unread, &mut self.reader, &mut chunk[..wanted]) {
+                Ok(0) => {
+                    let found = self.pending.len();
+                    if let Some(prefix_len) = prefix_len {
+                        if self.counted_itself(found - prefix_len, needed - prefix_len) {
+                            self.repairs += 1;
+                            match self.take_pending(prefix_len) {
+                                Some(result) => return TryNext::Ready(result),
+                                None => continue,
+                            }
+                        }
+                    }
+                    self.pending.clear();
+                    return match found {
+                        0 => TryNext::Closed,
+                        _ if self.strictness == Strictness::Lenient => {
+                            self.repairs += 1;
+                            TryNext::Closed
+                        }
+                        _ => match prefix_len {
+                            None => TryNext::Ready(Err(Error::EofInMessageSize)),
+                            Some(prefix_len) => {
+                                TryNext::Ready(Err(Error::Truncated {
+                                    found: (found - prefix_len) as u32,
+                                    remaining: (needed - found) as u32,
+                                }))
+                            }
+                        },
+                    };
+                }
+                Ok(n) => {
+                    if let (true, Some(throttling)) = (body, self.throttling.as_mut()) {
+                        throttling.consumed(n);
+                    }
+                    self.pending.extend_from_slice(&chunk[..n]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return TryNext::NotReady,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return TryNext::Ready(Err(e.into())),
+            }
+        }
+    }
+
+    /// Handles the frame `try_next` has gathered in full, whose prefix is
+    /// `prefix_len` bytes, leaving nothing pending. A parked message comes
+    /// to `None`.
+    fn take_pending(&mut self, prefix_len: usize) -> Option<NextResult<S>> {
+        let mut pressure = Pressure::None;
+        let result = {
+            let bytes = &self.pending[prefix_len..];
+            let parsed = parse_frame(self.strictness,
+                                     self.capture_window,
+                                     prefix_len,
+                                     bytes,
+                                     &mut Spans::off());
+            let input = self.frame_input(bytes, &parsed);
+            match admit(&mut self.protocol, input) {
+                Err(e) => Some(Err(e)),
+                Ok(()) => {
+                    deferred::handle(&mut *self.server,
+                                     &mut self.parking,
+                                     &mut self.timestamp,
+                                     bytes,
+                                     parsed,
+                                     &mut Spans::off(),
+                                     &mut pressure,
+                                     |msg, ack| Accepted::of(&msg.header, msg.payload.len(), ack))
+                }
+            }
+        };
+        if let Some(ref result) = result {
+            self.dispatched(result);
+        }
+        if let Some(Ok(_)) = result {
+            self.pressed(pressure);
+        }
+        let size = self.pending.len() - prefix_len;
+        self.pending.clear();
+        self.frame_read(size);
+        result
+    }
+}
+
+impl<'a, S: 'a + Server, R: Read> Iterator for Session<'a, S, R> {
+    type Item = NextResult<S>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|msg, ack| Accepted::of(&msg.header, msg.payload.len(), ack))
+    }
+}
+
+impl<'a, S: 'a + Server, R: Read> Session<'a, S, R> {
+    /// `next`, returning what `keep` makes of a consumed message and its
+    /// acknowledgement.
+    fn next_with<T, F>(&mut self,
+                       keep: F)
+                       -> Option<Result<T, Error<S::AuthErr, <S::Stream as Stream>::PushErr>>>
+        where F: FnMut(&Message, S::ConsumeOk) -> T
+    {
+        if self.fatal_captured.is_some() {
+            return None;
+        }
+        let item = self.next_frame(keep);
+        match item {
+            None => self.trace.abandon(),
+            Some(Ok(_)) => self.trace.finish(Outcome::Consumed),
+            Some(Err(ref e)) => {
+                self.trace.finish(Outcome::Failed(e.io_kind()));
+                post_mortem::capture(self, e);
+            }
+        }
+        item
+    }
+
+    fn next_frame<T, F>(&mut self,
+                        mut keep: F)
+                        -> Option<Result<T, Error<S::AuthErr, <S::Stream as Stream>::PushErr>>>
+        where F: FnMut(&Message, S::ConsumeOk) -> T
+    {
+        if let Err(e) = self.read_preamble() {
+            return Some(Err(e));
+        }
+        if let Err(e) = self.skip_rest() {
+            return Some(Err(e.into()));
+        }
+        if let Some(ticket) = self.parking.as_mut().and_then(deferred::Parking::next_expired) {
+            return Some(Err(Error::AuthExpired(ticket)));
+        }
+        if self.stopped && !self.frame_held() {
+            return None;
+        }
+        if let Some(delay) = self.delay.take() {
+            self.backoff.sleep(delay);
+        }
+        loop {
+            match self.read_frame(&mut keep) {
+                Step::Skipped => {}
+                Step::Done(item) => return item,
+            }
+        }
+    }
+
+    /// Reads and handles the next frame, unless it is one to skip.
+    fn read_frame<T, F>(&mut self, keep: &mut F) -> Stepped<T, S>
+        where F: FnMut(&Message, S::ConsumeOk) -> T
+    {
+        self.trace.start();
+        let mut bytes = [0_u8; MAX_VARINT_LEN];
+        self.trace.spans().begin("read_prefix");
+        let prefix = self.read_prefix(&mut bytes);
+        self.trace.spans().end();
+        Step::Done(match prefix {
+            Err(e) => Some(Err(e.into())),
+            Ok(0) => None,
+            Ok(n) => match self.framing.decode(&bytes[..n]) {
+                Prefix::Partial(_) if self.strictness == Strictness::Lenient => {
+                    self.repairs += 1;
+                    None
+                }
+                Prefix::Partial(_) => {
+                    self.keep_broken_prefix(&bytes[..n]);
+                    Some(Err(Error::EofInMessageSize))
+                }
+                Prefix::BadVarint => {
+                    self.keep_broken_prefix(&bytes[..n]);
+                    Some(Err(Error::BadVarint))
+                }
+                Prefix::Whole { size, len } => {
+                    self.frame_prefix_len = len;
+                    if self.skip_zero_frame(size) {
+                        return Step::Skipped;
+                    }
+                    if let Some(e) = self.oversized(size) {
+                        return Step::Done(Some(Err(e)));
+                    }
+                    self.trace.spans().begin("read_body");
+                    let start = match gate::read_token(self, size) {
+                        Ok(start) => start,
+                        Err(returned) => return Step::Done(returned.map(Err)),
+                    };
+                    self.size_buffer(size);
+                    let read = self.fill_buffer(start);
+                    self.trace.spans().end();
+                    match read {
+                        Err(e) => Some(Err(e.into())),
+                        Ok(n) if n > size => unreachable!("{} should be <= {}", n, size),
+                        Ok(found) if found < size && !self.counted_itself(found, size) => {
+                            self.cut_short(truncated(found, size)).map(Err)
+                        }
+                        Ok(found) => {
+                            if found < size {
+                                self.repairs += 1;
+                                self.buffer.truncate(found);
+                            }
+                            self.frame_read(found);
+                            let mut pressure = Pressure::None;
+                            let parsed = parse_frame(self.strictness,
+                                                     self.capture_window,
+                                                     self.frame_prefix_len,
+                                                     &self.buffer,
+                                                     self.trace.spans());
+                            let input = self.frame_input(&self.buffer, &parsed);
+                            if let Err(e) = admit(&mut self.protocol, input) {
+                                return Step::Done(Some(Err(e)));
+                            }
+                            match deferred::handle(&mut *self.server,
+                                                   &mut self.parking,
+                                                   &mut self.timestamp,
+                                                   &self.buffer,
+                                                   parsed,
+                                                   self.trace.spans(),
+                                                   &mut pressure,
+                                                   keep) {
+                                Some(result) => {
+                                    self.dispatched(&result);
+                                    if result.is_ok() {
+                                        self.pressed(pressure);
+                                    }
+                                    Some(result)
+                                }
+                                None => return Step::Skipped,
+                            }
+                        }
+                    }
+                }
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+
+    use super::*;
+    use config::ServerConfig;
+    use {message, server, test_support};
+    use test_support::*;
+
+    #[derive(Clone, Debug, Default)]
+    struct Packet {
+        token: Vec<u8>,
+        id: Vec<u8>,
+        millis: u64,
+        payload: Vec<u8>,
+    }
+    impl Packet {
+        fn into_bytes(self) -> Vec<u8> {
+            self.into_frame(Framing::U16)
+        }
+
+        fn into_frame(self, framing: Framing) -> Vec<u8> {
+            let msg = Message {
+                header: message::Header {
+                    token: &self.token,
+                    id: &self.id,
+                    timestamp: Duration::from_millis(self.millis),
+                },
+                payload: &self.payload,
+            };
+            msg.to_frame_with(framing).unwrap()
+        }
+    }
+    impl Arbitrary for Packet {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            Packet {
+                token: Arbitrary::arbitrary(g),
+                id: Arbitrary::arbitrary(g),
+                millis: Arbitrary::arbitrary(g),
+                payload: Arbitrary::arbitrary(g),
+            }
+        }
+
+        fn shrink(&self) -> Box<Iterator<Item = Self>> {
+            Box::new(self.token
+                         .shrink()
+                         .zip(self.id.shrink())
+                         .zip(self.millis.shrink())
+                         .zip(self.payload.shrink())
+                         .map(|(((token, id), millis), payload)| {
+                             Packet {
+                                 token: token,
+                                 id: id,
+                                 millis: millis,
+                                 payload: payload,
+                             }
+                         }))
+        }
+    }
+
+    #[test]
+    fn io_kinds() {
+        type E = Error<io::Error, io::Error>;
+        let cases: Vec<(E, io::ErrorKind)> = vec![
+            (Error::Read(io::Error::new(io::ErrorKind::BrokenPipe, "")),
+             io::ErrorKind::BrokenPipe),
+            (Error::EofInMessageSize, io::ErrorKind::UnexpectedEof),
+            (Error::Truncated {
+                found: 1,
+                remaining: 1,
+            },
+             io::ErrorKind::UnexpectedEof),
+            (Error::Parse {
+                error: message::Error {
+                    remaining: 0,
+                    part: message::header::Part::TokenSize,
+                    offset: 0,
+                    diagnostic: None,
+                },
+                prefix_len: 2,
+            },
+             io::ErrorKind::InvalidData),
+            (Error::Consume(server::ConsumeError::MissingId), io::ErrorKind::NotFound),
+            (Error::Sink(io::Error::new(io::ErrorKind::WriteZero, "")), io::ErrorKind::WriteZero),
+        ];
+        for (err, kind) in cases {
+            assert_eq!(kind, err.io_kind());
+            assert_eq!(kind, err.into_io().kind());
+        }
+    }
+
+    #[test]
+    fn into_io_round_trip() {
+        let err: Error<io::Error, io::Error> = Error::Truncated {
+            found: 3,
+            remaining: 4,
+        };
+        let io_err: io::Error = err.into();
+        assert_match!(Error::Truncated { found: 3, remaining: 4 },
+                      *io_err.into_inner()
+                             .unwrap()
+                             .downcast::<Error<io::Error, io::Error>>()
+                             .unwrap());
+    }
+
+    #[test]
+    fn next_none() {
+        let mut server = test_support::server::Unreachable;
+        let mut session = Session::new(&mut server, io::empty());
+        assert_match!(None, session.next());
+    }
+
+    #[test]
+    fn next_some_err_read() {
+        let mut server = test_support::server::Unreachable;
+        struct BrokenRead;
+        impl Read for BrokenRead {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, ""))
+            }
+        }
+        let mut session = Session::new(&mut server, BrokenRead);
+        assert_match!(Some(Err(Error::Read(_))), session.next());
+    }
+
+    quickcheck_test! {
+    next_some_err_one_byte_message_size(partial_message_size: u8; TestResult) {
+        let mut server = test_support::server::Unreachable;
+        let packet = [partial_message_size];
+        let mut session = Session::new(&mut server, &packet as &[_]);
+        test_result_match!(Some(Err(Error::EofInMessageSize)), session.next())
+    }}
+
+    quickcheck_test! {
+    next_some_err_truncated(partial_message: Vec<u8>, expected_remaining: u16; TestResult) {
+        if expected_remaining == 0 {
+            return TestResult::discard();
+        }
+
+        let expected_found = partial_message.len() as u16;
+        if let Some(n) = expected_found.checked_add(expected_remaining) {
+            let mut server = test_support::server::Unreachable;
+            let len_bytes = &n.to_bytes();
+            let bytes = len_bytes.chain(Cursor::new(partial_message));
+            let mut session = Session::new(&mut server, bytes);
+            test_result_match!(Some(Err(Error::Truncated {
+                found,
+                remaining,
+            })) if found == expected_found as u32 &&
+                remaining == expected_remaining as u32, session.next())
+        } else {
+            TestResult::discard()
+        }
+    }}
+
+    quickcheck_test! {
+    next_some_err_parse(partial_token: Vec<u8>, missing: u16; TestResult) {
+        if missing == 0 {
+            return TestResult::discard();
+        }
+
+        let found = partial_token.len() as u16;
+        if let Some(token_len) = found.checked_add(missing) {
+            let mut server = test_support::server::Unreachable;
+            let msg: Vec<_> = token_len.to_bytes()
+                .into_copy_iter()
+                .chain(partial_token)
+                .collect();
+            let msg_len = &(msg.len() as u16).to_bytes();
+            let packet = msg_len.chain(Cursor::new(msg));
+            let mut session = Session::new(&mut server, packet);
+            test_result_match!(Some(Err(Error::Parse { .. })), session.next())
+        } else {
+            TestResult::discard()
+        }
+    }}
+
+    quickcheck_test! {
+    next_some_err_consume(packet: Packet; TestResult) {
+        let mut server = test_support::server::RefuseToAuth;
+        let mut session = Session::new(&mut server, Cursor::new(packet.into_bytes()));
+        test_result_match!(Some(Err(Error::Consume(_))), session.next())
+    }}
+
+    quickcheck_test! {
+    next_some_ok(packet: Packet; TestResult) {
+        let mut finder = server::Finder::new();
+        let expected_id = packet.id.clone();
+        finder.insert(expected_id.clone(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let mut session = Session::new(&mut server, Cursor::new(packet.into_bytes()));
+        test_result_match!(Some(Ok(ref accepted)) if accepted.id == expected_id, session.next())
+    }}
+
+    fn outcomes(bytes: Vec<u8>, strictness: Strictness) -> (Vec<&'static str>, u64) {
+        let mut finder = server::Finder::new();
+        finder.insert(vec![], test_support::stream::Ok);
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_strictness(strictness);
+        let outcomes = session.by_ref()
+                              .take(4)
+                              .map(|item| {
+                                  match item {
+                                      Ok(_) => "ok",
+                                      Err(Error::EofInMessageSize) => "one byte",
+                                      Err(Error::Truncated { .. }) => "truncated",
+                                      Err(Error::Nonconforming(_)) => "nonconforming",
+                                      Err(_) => "other",
+                                  }
+                              })
+                              .collect();
+        (outcomes, session.repairs())
+    }
+
+    #[test]
+    fn strictness_levels() {
+        let packet = |token: &[u8], id: &[u8], millis| {
+            Packet {
+                token: token.to_vec(),
+                id: id.to_vec(),
+                millis: millis,
+                payload: b"payload".to_vec(),
+            }
+            .into_bytes()
+        };
+        let good = packet(b"token", b"id", 1);
+        let with = |tail: &[u8]| good.iter().chain(tail).cloned().collect::<Vec<_>>();
+        // Declaring `more` bytes than the frame holds.
+        let over = |more| {
+            let mut bytes = good.clone();
+            bytes[1] += more;
+            bytes
+        };
+        // (input, strict, standard, lenient)
+        let cases: Vec<(Vec<u8>, (Vec<&str>, u64), (Vec<&str>, u64), (Vec<&str>, u64))> = vec![
+            (good.clone(), (vec!["ok"], 0), (vec!["ok"], 0), (vec!["ok"], 0)),
+            (packet(b"", b"id", 1),
+             (vec!["nonconforming"], 0),
+             (vec!["ok"], 0),
+             (vec!["ok"], 0)),
+            (packet(b"token", b"", 1),
+             (vec!["nonconforming"], 0),
+             (vec!["ok"], 0),
+             (vec!["ok"], 0)),
+            (packet(b"token", b"id", 0),
+             (vec!["nonconforming"], 0),
+             (vec!["ok"], 0),
+             (vec!["ok"], 0)),
+            (with(&[0]),
+             (vec!["ok", "one byte"], 0),
+             (vec!["ok", "one byte"], 0),
+             (vec!["ok"], 1)),
+            (with(&[0, 5, 1, 2]),
+             (vec!["ok", "truncated"], 0),
+             (vec!["ok", "truncated"], 0),
+             (vec!["ok"], 1)),
+            (over(2), (vec!["truncated"], 0), (vec!["truncated"], 0), (vec!["ok"], 1)),
+            (with(&over(2)),
+             (vec!["ok", "truncated"], 0),
+             (vec!["ok", "truncated"], 0),
+             (vec!["ok", "ok"], 1)),
+            (over(1), (vec!["truncated"], 0), (vec!["truncated"], 0), (vec![], 1)),
+            (over(3), (vec!["truncated"], 0), (vec!["truncated"], 0), (vec![], 1)),
+        ];
+        for (input, strict, standard, lenient) in cases {
+            assert_eq!(strict, outcomes(input.clone(), Strictness::Strict));
+            assert_eq!(standard, outcomes(input.clone(), Strictness::Standard));
+            assert_eq!(lenient, outcomes(input, Strictness::Lenient));
+        }
+    }
+
+    fn describe<I, T, E>(items: I) -> Vec<String>
+        where I: Iterator<Item = Result<T, E>>,
+              T: fmt::Debug,
+              E: fmt::Debug
+    {
+        items.map(|item| format!("{:?}", item)).collect()
+    }
+
+    quickcheck_test! {
+    read_ahead_parity(packets: Vec<Packet>, tail: Vec<u8>, capacity: u16; bool) {
+        let mut bytes: Vec<_> = packets.iter().cloned().flat_map(Packet::into_bytes).collect();
+        bytes.extend(tail);
+        let finder = || -> server::Finder<_> {
+            packets.iter().map(|p| (p.id.clone(), test_support::stream::Ok)).collect()
+        };
+        let mut a = test_support::server::Ok(finder());
+        let mut b = test_support::server::Ok(finder());
+        let plain = describe(Session::new(&mut a, Cursor::new(bytes.clone())).take(100));
+        let ahead = describe(Session::new(
+            &mut b, ReadAhead::with_capacity(Cursor::new(bytes), capacity as usize)).take(100));
+        plain == ahead
+    }}
+
+    #[test]
+    fn read_ahead_fewer_calls() {
+        struct Counting {
+            inner: Cursor<Vec<u8>>,
+            calls: usize,
+        }
+        impl Read for Counting {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.calls += 1;
+                self.inner.read(buf)
+            }
+        }
+        impl VectoredRead for Counting {
+            fn read_pair(&mut self, a: &mut [u8], b: &mut [u8]) -> io::Result<usize> {
+                self.calls += 1;
+                self.inner.read_pair(a, b)
+            }
+        }
+
+        let packet = Packet {
+            token: b"token".to_vec(),
+            id: b"id".to_vec(),
+            millis: 0,
+            payload: b"payload".to_vec(),
+        };
+        let bytes: Vec<_> = (0..100).flat_map(|_| packet.clone().into_bytes()).collect();
+        let finder = || -> server::Finder<_> {
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect()
+        };
+
+        let mut plain = Counting {
+            inner: Cursor::new(bytes.clone()),
+            calls: 0,
+        };
+        let mut server = test_support::server::Ok(finder());
+        assert_eq!(100, Session::new(&mut server, &mut plain).filter(Result::is_ok).count());
+
+        let mut ahead = ReadAhead::new(Counting {
+            inner: Cursor::new(bytes),
+            calls: 0,
+        });
+        let mut server = test_support::server::Ok(finder());
+        assert_eq!(100, Session::new(&mut server, &mut ahead).filter(Result::is_ok).count());
+
+        assert_eq!(201, plain.calls);
+        assert!(ahead.get_ref().calls < 10);
+    }
+
+    /// Hands over one byte per read, as a slow socket may.
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = cmp::min(1, buf.len());
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    quickcheck_test! {
+    short_reads_parity(packets: Vec<Packet>, tail: Vec<u8>; bool) {
+        let mut bytes: Vec<_> = packets.iter().cloned().flat_map(Packet::into_bytes).collect();
+        bytes.extend(tail);
+        let finder = || -> server::Finder<_> {
+            packets.iter().map(|p| (p.id.clone(), test_support::stream::Ok)).collect()
+        };
+        let mut a = test_support::server::Ok(finder());
+        let mut b = test_support::server::Ok(finder());
+        let plain = describe(Session::new(&mut a, Cursor::new(bytes.clone())).take(100));
+        let trickled = describe(Session::new(&mut b, Trickle(Cursor::new(bytes))).take(100));
+        plain == trickled
+    }}
+
+    #[test]
+    fn short_reads_are_not_truncation() {
+        let packet = Packet {
+            token: b"token".to_vec(),
+            id: b"id".to_vec(),
+            millis: 0,
+            payload: b"payload".to_vec(),
+        };
+        let mut bytes = packet.clone().into_bytes();
+        bytes.extend(packet.into_bytes());
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        {
+            let mut session = Session::new(&mut server, Trickle(Cursor::new(bytes.clone())));
+            assert_match!(Some(Ok(_)), session.next());
+            assert_match!(Some(Ok(_)), session.next());
+            assert_match!(None, session.next());
+        }
+
+        // Only the end of the input cuts a frame short.
+        let cut = bytes.len() - 3;
+        let mut session = Session::new(&mut server, Trickle(Cursor::new(bytes[..cut].to_vec())));
+        assert_match!(Some(Ok(_)), session.next());
+        let size = bytes.len() / 2 - 2;
+        assert_match!(Some(Err(Error::Truncated { found, remaining: 3 }))
+                          if found as usize == size - 3,
+                      session.next());
+        let mut session = Session::new(&mut server, Trickle(&[0_u8] as &[_]));
+        assert_match!(Some(Err(Error::EofInMessageSize)), session.next());
+    }
+
+    quickcheck_test! {
+    back_to_back_frames_reuse_the_buffer(packets: Vec<Packet>; bool) {
+        let round: Vec<_> = packets.iter().cloned().flat_map(Packet::into_bytes).collect();
+        let mut bytes = round.clone();
+        bytes.extend(round);
+        let finder: server::Finder<_> =
+            packets.iter().map(|p| (p.id.clone(), test_support::stream::Ok)).collect();
+        let mut server = test_support::server::CountingServer::new(
+            test_support::server::Ok(finder));
+        {
+            let mut session = Session::new(&mut server, Cursor::new(bytes));
+            for _ in &packets {
+                if !session.next().map_or(false, |item| item.is_ok()) {
+                    return false;
+                }
+            }
+            // The first round grew the buffer as far as it needs to go.
+            let grown = (session.buffer.as_ptr(), session.buffer.capacity());
+            for _ in &packets {
+                if !session.next().map_or(false, |item| item.is_ok()) {
+                    return false;
+                }
+                if (session.buffer.as_ptr(), session.buffer.capacity()) != grown {
+                    return false;
+                }
+            }
+            if session.next().is_some() {
+                return false;
+            }
+        }
+        let calls = server.calls();
+        calls.len() == 2 * packets.len() &&
+        calls.iter().zip(packets.iter().chain(&packets)).all(|(call, packet)| {
+            call.token == packet.token && call.id == packet.id &&
+            call.timestamp == Duration::from_millis(packet.millis) &&
+            call.payload == packet.payload
+        })
+    }}
+
+    quickcheck_test! {
+    framings_round_trip(packets: Vec<Packet>, framing: u8, trickle: bool; bool) {
+        let framing = [Framing::U16, Framing::U32, Framing::Varint][framing as usize % 3];
+        let bytes: Vec<_> = packets.iter().cloned().flat_map(|p| p.into_frame(framing)).collect();
+        let finder = || -> server::Finder<_> {
+            packets.iter().map(|p| (p.id.clone(), test_support::stream::Ok)).collect()
+        };
+        let mut server = test_support::server::CountingServer::new(
+            test_support::server::Ok(finder()));
+        let consumed = {
+            let reader: Box<Read> = if trickle {
+                Box::new(Trickle(Cursor::new(bytes.clone())))
+            } else {
+                Box::new(Cursor::new(bytes.clone()))
+            };
+            let session = Session::with_framing(&mut server, reader, framing);
+            session.take(packets.len() + 1).filter(Result::is_ok).count()
+        };
+        let calls = server.calls();
+        let same = calls.len() == packets.len() &&
+                   calls.iter().zip(&packets).all(|(call, packet)| {
+                       call.token == packet.token && call.id == packet.id &&
+                       call.timestamp == Duration::from_millis(packet.millis) &&
+                       call.payload == packet.payload
+                   });
+
+        // Without blocking, a few bytes at a time.
+        let mut server = test_support::server::Ok(finder());
+        let script = bytes.chunks(3).flat_map(|bytes| vec![Some(bytes.to_vec()), None]);
+        let mut session = Session::with_framing(&mut server, Scripted(script.collect()), framing);
+        let ready = try_all(&mut session)
+                        .into_iter()
+                        .filter(|next| match *next {
+                            TryNext::Ready(Ok(_)) => true,
+                            _ => false,
+                        })
+                        .count();
+        consumed == packets.len() && same && ready == packets.len()
+    }}
+
+    #[test]
+    fn u32_framing() {
+        let packet = Packet {
+            token: b"token".to_vec(),
+            id: b"id".to_vec(),
+            millis: 1,
+            payload: vec![7; 70000],
+        };
+        let bytes = packet.into_frame(Framing::U32);
+        let size = bytes.len() - 4;
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        {
+            let mut session = Session::with_framing(&mut server,
+                                                    Cursor::new(bytes.clone()),
+                                                    Framing::U32);
+            assert_match!(Some(Ok(_)), session.next());
+            assert_match!(None, session.next());
+            assert_eq!(bytes.len() as u64, session.offset);
+        }
+        {
+            let cut = bytes[..bytes.len() - 5].to_vec();
+            let mut session = Session::with_framing(&mut server, Cursor::new(cut), Framing::U32);
+            assert_match!(Some(Err(Error::Truncated { found: f, remaining: 5 }))
+                              if f as usize == size - 5,
+                          session.next());
+        }
+        for &cut in &[1, 3] {
+            let prefix = bytes[..cut].to_vec();
+            let mut session = Session::with_framing(&mut server,
+                                                    Cursor::new(prefix.clone()),
+                                                    Framing::U32);
+            assert_match!(Some(Err(Error::EofInMessageSize)), session.next());
+            let mut session = Session::with_framing(&mut server,
+                                                    Scripted(vec![Some(prefix)]),
+                                                    Framing::U32);
+            assert_match!(TryNext::Ready(Err(Error::EofInMessageSize)), session.try_next());
+        }
+    }
+
+    #[test]
+    fn bad_varints_lose_the_framing() {
+        let packet = Packet {
+            token: b"token".to_vec(),
+            id: b"id".to_vec(),
+            millis: 1,
+            payload: b"payload".to_vec(),
+        };
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        for bad in &[vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x01], vec![0x81, 0x00]] {
+            let mut bytes = packet.clone().into_frame(Framing::Varint);
+            bytes.extend_from_slice(bad);
+            bytes.extend(packet.clone().into_frame(Framing::Varint));
+            {
+                let mut session = Session::with_framing(&mut server,
+                                                        Trickle(Cursor::new(bytes.clone())),
+                                                        Framing::Varint);
+                assert_match!(Some(Ok(_)), session.next());
+                assert_match!(Some(Err(Error::BadVarint)), session.next());
+                assert_eq!(&bad[..cmp::min(bad.len(), MAX_VARINT_LEN)], &session.buffer[..]);
+            }
+            let script = bytes.chunks(2).map(|bytes| Some(bytes.to_vec())).collect();
+            let mut session = Session::with_framing(&mut server, Scripted(script), Framing::Varint);
+            assert_match!(TryNext::Ready(Ok(_)), session.try_next());
+            assert_match!(TryNext::Ready(Err(Error::BadVarint)), session.try_next());
+        }
+
+        // A varint cut short is only a cut-short size.
+        let mut session = Session::with_framing(&mut server,
+                                                Cursor::new(vec![0x80, 0x80]),
+                                                Framing::Varint);
+        assert_match!(Some(Err(Error::EofInMessageSize)), session.next());
+    }
+
+    /// Serves each chunk as far as the caller's buffer allows, and reports
+    /// `WouldBlock` for each `None`.
+    struct Scripted(Vec<Option<Vec<u8>>>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            match self.0.remove(0) {
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "")),
+                Some(chunk) => {
+                    let n = cmp::min(chunk.len(), buf.len());
+                    buf[..n].copy_from_slice(&chunk[..n]);
+                    if n < chunk.len() {
+                        self.0.insert(0, Some(chunk[n..].to_vec()));
+                    }
+                    Ok(n)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_after_keeps_errors_behind_leftovers() {
+        let mut unread = vec![1, 2];
+        let mut reader = Scripted(vec![None, Some(vec![3])]);
+        let mut buf = [0; 4];
+        assert_eq!(2, read_after(&mut unread, &mut reader, &mut buf).unwrap());
+        assert_eq!(io::ErrorKind::WouldBlock,
+                   read_after(&mut unread, &mut reader, &mut buf[2..]).unwrap_err().kind());
+        assert_eq!(1, read_after(&mut unread, &mut reader, &mut buf[2..]).unwrap());
+        assert_eq!([1, 2, 3, 0], buf);
+    }
+
+    fn try_all<S: Server, R: Read>(session: &mut Session<S, R>) -> Vec<TryNext<NextResult<S>>> {
+        let mut all = vec![];
+        loop {
+            match session.try_next() {
+                TryNext::Closed => return all,
+                next => all.push(next),
+            }
+        }
+    }
+
+    #[test]
+    fn try_next_sequence() {
+        let packet = Packet {
+            token: b"token".to_vec(),
+            id: b"id".to_vec(),
+            millis: 1,
+            payload: b"payload".to_vec(),
+        };
+        let bytes = packet.into_bytes();
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let reader = Scripted(vec![None,
+                                   Some(bytes[..1].to_vec()),
+                                   None,
+                                   Some(bytes[1..5].to_vec()),
+                                   None,
+                                   Some(bytes[5..].to_vec()),
+                                   Some(bytes[..3].to_vec()),
+                                   None]);
+        let mut session = Session::new(&mut server, reader);
+        let all: Vec<_> = try_all(&mut session)
+                              .into_iter()
+                              .map(|next| {
+                                  match next {
+                                      TryNext::Ready(Ok(_)) => "ready",
+                                      TryNext::Ready(Err(Error::Truncated { .. })) => "truncated",
+                                      TryNext::Ready(Err(_)) => "error",
+                                      TryNext::NotReady => "not ready",
+                                      TryNext::Closed => "closed",
+                                  }
+                              })
+                              .collect();
+        assert_eq!(vec!["not ready", "not ready", "not ready", "ready", "not ready", "truncated"],
+                   all);
+    }
+
+    #[test]
+    fn try_next_repairs_a_prefix_that_counted_itself() {
+        let mut bytes = Packet {
+                            token: b"token".to_vec(),
+                            id: b"id".to_vec(),
+                            millis: 1,
+                            payload: b"payload".to_vec(),
+                        }
+                        .into_bytes();
+        bytes[1] += 2;
+        for &(strictness, ready, repairs) in &[(Strictness::Standard, false, 0),
+                                               (Strictness::Lenient, true, 1)] {
+            let mut finder = server::Finder::new();
+            finder.insert(b"id".to_vec(), test_support::stream::Ok);
+            let mut server = test_support::server::Ok(finder);
+            let mut session = Session::new(&mut server, Cursor::new(bytes.clone()));
+            session.set_strictness(strictness);
+            let all = try_all(&mut session);
+            assert_eq!(1, all.len());
+            match all[0] {
+                TryNext::Ready(Ok(_)) => assert!(ready),
+                TryNext::Ready(Err(Error::Truncated { .. })) => assert!(!ready),
+                ref next => panic!("{:?}", next),
+            }
+            assert_eq!(repairs, session.repairs());
+        }
+    }
+
+    /// A small frame for `payload`, a frame of 1000 bytes of payload, and
+    /// another small frame, all for one ID.
+    fn around_oversized() -> (Vec<u8>, usize) {
+        let packet = |payload: Vec<u8>| {
+            Packet {
+                token: b"token".to_vec(),
+                id: b"id".to_vec(),
+                millis: 1,
+                payload: payload,
+            }
+            .into_bytes()
+        };
+        let big = packet(vec![7; 1000]);
+        let mut bytes = packet(b"before".to_vec());
+        bytes.extend_from_slice(&big);
+        bytes.extend(packet(b"after".to_vec()));
+        (bytes, big.len() - 2)
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped_unbuffered() {
+        let (bytes, size) = around_oversized();
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::CountingServer::new(
+            test_support::server::Ok(finder));
+        {
+            let mut session = Session::with_max_message_size(&mut server,
+                                                              Cursor::new(bytes.clone()),
+                                                              64);
+            assert_match!(Some(Ok(_)), session.next());
+            assert_match!(Some(Err(Error::TooLarge { size: s, max: 64 })) if s == size,
+                          session.next());
+            assert_match!(Some(Ok(_)), session.next());
+            assert_match!(None, session.next());
+            assert!(session.buffer.capacity() <= 64);
+            assert_eq!(3, session.frames);
+            assert_eq!(bytes.len() as u64, session.offset);
+        }
+        let payloads: Vec<_> = server.calls().iter().map(|call| call.payload.clone()).collect();
+        assert_eq!(vec![b"before".to_vec(), b"after".to_vec()], payloads);
+
+        // The default takes any size a prefix can declare.
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        let session = Session::new(&mut server, Cursor::new(bytes));
+        assert_eq!(3, session.filter(Result::is_ok).count());
+    }
+
+    #[test]
+    fn oversized_frames_cut_short_end_the_input() {
+        let (bytes, size) = around_oversized();
+        let cut = bytes.len() - 500;
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        let mut session = Session::with_max_message_size(&mut server,
+                                                          Cursor::new(bytes[..cut].to_vec()),
+                                                          64);
+        assert_match!(Some(Ok(_)), session.next());
+        assert_match!(Some(Err(Error::TooLarge { size: s, .. })) if s == size, session.next());
+        assert_match!(None, session.next());
+    }
+
+    #[test]
+    fn configure_applies_framing_and_max_message_size() {
+        let packet = |payload: &[u8]| {
+            Packet {
+                id: b"id".to_vec(),
+                payload: payload.to_vec(),
+                ..Packet::default()
+            }
+            .into_frame(Framing::U32)
+        };
+        let mut bytes = packet(b"small");
+        let large = packet(&[0; 100]);
+        bytes.extend_from_slice(&large);
+        let config = ServerConfig {
+                         framing: Framing::U32,
+                         max_message_size: Some(64),
+                         ..ServerConfig::default()
+                     }
+                     .validate()
+                     .unwrap();
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        let mut session = Session::new(&mut server, &bytes[..]);
+        session.configure(&config);
+        assert_match!(Some(Ok(_)), session.next());
+        assert_match!(Some(Err(Error::TooLarge { size: s, max: 64 })) if s == large.len() - 4,
+                      session.next());
+        assert_match!(None, session.next());
+    }
+
+    #[test]
+    fn try_next_skips_oversized_frames_across_reads() {
+        let (bytes, _) = around_oversized();
+        let finder: server::Finder<_> =
+            Some((b"id".to_vec(), test_support::stream::Ok)).into_iter().collect();
+        let mut server = test_support::server::Ok(finder);
+        let script = bytes.chunks(100).flat_map(|chunk| vec![Some(chunk.to_vec()), None]);
+        let mut session = Session::with_max_message_size(&mut server,
+                                                          Scripted(script.collect()),
+                                                          64);
+        let all: Vec<_> = try_all(&mut session)
+                              .into_iter()
+                              .filter_map(|next| {
+                                  match next {
+                                      TryNext::Ready(Ok(_)) => Some("ready"),
+                                      TryNext::Ready(Err(Error::TooLarge { .. })) => {
+                                          Some("too large")
+                                      }
+                                      TryNext::Ready(Err(_)) => Some("error"),
+                                      TryNext::NotReady => None,
+                                      TryNext::Closed => Some("closed"),
+                                  }
+                              })
+                              .collect();
+        assert_eq!(vec!["ready", "too large", "ready"], all);
+    }
+
+    /// What the one stream stores from `script` read through `next`, or
+    /// through `try_next` if `nonblocking`.
+    fn stored(script: Vec<Option<Vec<u8>>>, nonblocking: bool) -> Vec<(Duration, Vec<u8>)> {
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(),
+                      test_support::stream::ScriptedStream::<::Void, ::Void>::default());
+        let mut server = test_support::server::Ok(finder);
+        {
+            let mut session = Session::new(&mut server, Scripted(script));
+            if nonblocking {
+                let all = try_all(&mut session);
+                assert_eq!(1, all.len());
+                assert_match!(&TryNext::Ready(Ok(_)), &all[0]);
+            } else {
+                assert_match!(Some(Ok(_)), session.next());
+                assert_match!(None, session.next());
+            }
+        }
+        server.0.remove(&b"id"[..]).unwrap().pushed().to_vec()
+    }
+
+    #[test]
+    fn prefix_split_across_reads() {
+        let bytes = Packet {
+                        token: b"token".to_vec(),
+                        id: b"id".to_vec(),
+                        millis: 1000,
+                        payload: b"payload".to_vec(),
+                    }
+                    .into_bytes();
+        let contiguous = stored(vec![Some(bytes.clone())], false);
+        assert_eq!(vec![(Duration::from_secs(1), b"payload".to_vec())], contiguous);
+
+        let split = |at: &[usize]| -> Vec<_> {
+            let mut cuts = vec![0];
+            cuts.extend_from_slice(at);
+            cuts.push(bytes.len());
+            cuts.windows(2).map(|w| Some(bytes[w[0]..w[1]].to_vec())).collect()
+        };
+        assert_eq!(contiguous, stored(split(&[1, 2]), false));
+        assert_eq!(contiguous, stored(split(&[1, 2, 5, 9, 20]), true));
+
+        // A failed read between the prefix bytes loses neither.
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let script = vec![Some(bytes[..1].to_vec()), None, Some(bytes[1..].to_vec())];
+        let mut session = Session::new(&mut server, Scripted(script));
+        assert_match!(Some(Err(Error::Read(ref e))) if e.kind() == io::ErrorKind::WouldBlock,
+                      session.next());
+        assert_match!(Some(Ok(_)), session.next());
+        assert_match!(None, session.next());
+    }
+
+    quickcheck_test! {
+    try_next_parity(packets: Vec<Packet>, tail: Vec<u8>, cuts: Vec<(u8, bool)>; bool) {
+        let mut bytes: Vec<_> = packets.iter().cloned().flat_map(Packet::into_bytes).collect();
+        bytes.extend(tail);
+        let finder = || -> server::Finder<_> {
+            packets.iter().map(|p| (p.id.clone(), test_support::stream::Ok)).collect()
+        };
+
+        let mut script = vec![];
+        let mut rest = &bytes[..];
+        for &(cut, block) in &cuts {
+            let (chunk, tail) = rest.split_at(cmp::min(cut as usize, rest.len()));
+            if !chunk.is_empty() {
+                script.push(Some(chunk.to_vec()));
+            }
+            if block {
+                script.push(None);
+            }
+            rest = tail;
+        }
+        script.push(Some(rest.to_vec()));
+
+        // The blocking path needs every read to fill its buffer, as a
+        // `Cursor` does.
+        let mut a = test_support::server::Ok(finder());
+        let blocking = describe(Session::new(&mut a, Cursor::new(bytes.clone())));
+        let mut b = test_support::server::Ok(finder());
+        let mut session = Session::new(&mut b, Scripted(script));
+        let polled = describe(try_all(&mut session).into_iter().filter_map(|next| {
+            match next {
+                TryNext::Ready(item) => Some(item),
+                _ => None,
+            }
+        }));
+        blocking == polled
+    }}
+
+    /// The first outcome of a session over a preamble and a packet with an
+    /// empty token, which conforms unless the peer asks for strictness.
+    fn with_preamble(policy: PreamblePolicy,
+                     preamble: &[u8])
+                     -> (&'static str, Option<PeerInfo>, Strictness) {
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let packet = Packet {
+            token: vec![],
+            id: b"id".to_vec(),
+            millis: 1,
+            payload: b"payload".to_vec(),
+        };
+        let bytes: Vec<_> = preamble.iter().cloned().chain(packet.into_bytes()).collect();
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_preamble_policy(policy);
+        let outcome = match session.next().unwrap() {
+            Ok(_) => "ok",
+            Err(Error::ProtocolViolation {
+                state: protocol::State::Initial,
+                received: protocol::Input::Data,
+            }) => "missing",
+            Err(Error::Preamble(PreambleError::Truncated)) => "truncated",
+            Err(Error::Preamble(_)) => "malformed",
+            Err(Error::Nonconforming(_)) => "nonconforming",
+            Err(_) => "other",
+        };
+        (outcome, session.peer_info().cloned(), session.strictness)
+    }
+
+    #[test]
+    fn preamble_policies() {
+        let peer = PeerInfo {
+            version: preamble::VERSION,
+            name: b"client/1.0".to_vec(),
+            strictness: Some(Strictness::Strict),
+        };
+        let present = peer.to_bytes();
+        let mut bad_flags = present.clone();
+        *bad_flags.last_mut().unwrap() = 0x80;
+        let mut bad_version = present.clone();
+        bad_version[4] = 99;
+        let truncated = &present[..8];
+        let strict = Strictness::Strict;
+        let standard = Strictness::Standard;
+
+        assert_eq!(("nonconforming", Some(peer.clone()), strict),
+                   with_preamble(PreamblePolicy::Required, &present));
+        assert_eq!(("missing", None, standard),
+                   with_preamble(PreamblePolicy::Required, b""));
+        assert_eq!(("malformed", None, standard),
+                   with_preamble(PreamblePolicy::Required, &bad_flags));
+        assert_eq!(("malformed", None, standard),
+                   with_preamble(PreamblePolicy::Required, &bad_version));
+        let mut server = test_support::server::Unreachable;
+        let mut session = Session::new(&mut server, truncated);
+        session.set_preamble_policy(PreamblePolicy::Required);
+        assert_match!(Some(Err(Error::Preamble(PreambleError::Truncated))), session.next());
+
+        assert_eq!(("nonconforming", Some(peer.clone()), strict),
+                   with_preamble(PreamblePolicy::Optional, &present));
+        assert_eq!(("ok", None, standard), with_preamble(PreamblePolicy::Optional, b""));
+        assert_eq!(("malformed", None, standard),
+                   with_preamble(PreamblePolicy::Optional, &bad_flags));
+
+        // Read as a frame, the magic declares a message too short to parse.
+        assert_eq!(("other", None, standard),
+                   with_preamble(PreamblePolicy::Forbidden, &present));
+        assert_eq!(("ok", None, standard), with_preamble(PreamblePolicy::Forbidden, b""));
+    }
+
+    #[test]
+    fn preamble_cannot_loosen() {
+        let peer = PeerInfo {
+            version: preamble::VERSION,
+            name: vec![],
+            strictness: Some(Strictness::Lenient),
+        };
+        assert_eq!(("malformed", None, Strictness::Standard),
+                   with_preamble(PreamblePolicy::Required, &peer.to_bytes()));
+    }
+
+    #[test]
+    fn short_absent_preamble_is_framed() {
+        let mut server = test_support::server::Unreachable;
+        let mut session = Session::new(&mut server, &[0_u8][..]);
+        session.set_preamble_policy(PreamblePolicy::Optional);
+        assert_match!(Some(Err(Error::EofInMessageSize)), session.next());
+        assert_match!(None, session.next());
+    }
+
+    #[test]
+    fn register_between_frames() {
+        let packet = || {
+            Packet {
+                id: b"new".to_vec(),
+                ..Packet::default()
+            }
+            .into_bytes()
+        };
+        let input = [packet(), packet()].concat();
+        let finder = server::Finder::<test_support::stream::Ok>::new();
+        let mut server = test_support::server::Ok(finder);
+        let mut session = Session::new(&mut server, &input[..]);
+        assert_match!(Some(Err(Error::Consume(server::ConsumeError::MissingId))), session.next());
+        session.server_mut().0.insert(b"new".to_vec(), test_support::stream::Ok);
+        assert_match!(Some(Ok(ref accepted)) if accepted.id == b"new", session.next());
+        assert_eq!(1, session.server().0.len());
+    }
+
+    #[test]
+    fn accepted_describes_the_message() {
+        let input = [test_support::frame(b"t", b"id", 1500, b"four"),
+                     test_support::frame(b"t", b"id", 2, &[0; 100])]
+                        .concat();
+        let expected = [Accepted {
+                            id: b"id".to_vec(),
+                            timestamp: Duration::from_millis(1500),
+                            payload_len: 4,
+                            ack: Some(()),
+                        },
+                        Accepted {
+                            id: b"id".to_vec(),
+                            timestamp: Duration::from_millis(2),
+                            payload_len: 100,
+                            ack: Some(()),
+                        }];
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let accepted: Vec<_> = Session::new(&mut server, &input[..]).map(Result::unwrap).collect();
+        assert_eq!(&expected[..], &accepted[..]);
+    }
+
+    #[test]
+    fn acks_are_passed_on() {
+        let input = [test_support::frame(b"t", b"id", 1, b""),
+                     test_support::frame(b"t", b"missing", 2, b""),
+                     test_support::frame(b"t", b"id", 3, b"")]
+                        .concat();
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = server::HighWaterMark::new(test_support::server::Numbering::new(
+            test_support::server::Ok(finder)));
+        let acks: Vec<_> = Session::new(&mut server, &input[..])
+                               .map(|result| result.ok().and_then(|accepted| accepted.ack))
+                               .collect();
+        assert_eq!(vec![Some(0), None, Some(1)], acks);
+    }
+
+    #[test]
+    fn capture_window() {
+        let input = [0, 3, 0, 5, b't'];
+        let mut server = test_support::server::Unreachable;
+        let mut session = Session::new(&mut server, &input[..]);
+        session.set_capture_window(2);
+        assert_match!(Some(Err(Error::Parse {
+                          error: message::Error {
+                              part: message::header::Part::Token(5),
+                              diagnostic: Some(message::DiagnosticWindow { offset: 1, ref bytes }),
+                              ..
+                          },
+                          ..
+                      })) if bytes == b"\x05t",
+                      session.next());
+
+        let mut session = Session::new(&mut server, &input[..]);
+        assert_match!(Some(Err(Error::Parse {
+                          error: message::Error { diagnostic: None, .. },
+                          ..
+                      })),
+                      session.next());
+    }
+
+    #[test]
+    fn frame_offset_counts_the_framing_prefix() {
+        // A token size of 5 with one byte of token, whose missing rest would
+        // have started two bytes into the body.
+        let body = [0, 5, b't'];
+        for &(framing, prefix) in &[(Framing::U16, &[0, 3][..]),
+                                    (Framing::U32, &[0, 0, 0, 3][..]),
+                                    (Framing::Varint, &[3][..])] {
+            let mut input = prefix.to_vec();
+            input.extend_from_slice(&body);
+            let mut server = test_support::server::Unreachable;
+            let mut session = Session::with_framing(&mut server, &input[..], framing);
+            let e = session.next().unwrap().unwrap_err();
+            assert_eq!(Some(prefix.len() + 2), e.frame_offset());
+
+            let mut session = Session::with_framing(&mut server, Cursor::new(&input), framing);
+            match try_all(&mut session).pop() {
+                Some(TryNext::Ready(Err(e))) => {
+                    assert_eq!(Some(prefix.len() + 2), e.frame_offset())
+                }
+                _ => panic!("no parse error under {:?}", framing),
+            }
+        }
+    }
+
+    fn zero_frame_outcomes<F>(policy: ZeroFrame, mut next: F) -> (Vec<&'static str>, u64, u64)
+        where F: FnMut(&mut Session<test_support::server::Ok<test_support::stream::Ok>, Scripted>)
+                       -> Option<NextResult<test_support::server::Ok<test_support::stream::Ok>>>
+    {
+        let packet = Packet {
+            id: b"id".to_vec(),
+            ..Packet::default()
+        }
+        .into_bytes();
+        let frames = [&[0, 0][..], &packet, &[0, 0], &[0, 0], &packet, &[0, 0]];
+        let mut finder = server::Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        let mut server = test_support::server::Ok(finder);
+        let reader = Scripted(frames.iter().map(|frame| Some(frame.to_vec())).collect());
+        let mut session = Session::new(&mut server, reader);
+        session.set_zero_frame(policy);
+        let mut outcomes = vec![];
+        while let Some(item) = next(&mut session) {
+            outcomes.push(match item {
+                Ok(ref accepted) if accepted.id == b"id" => "ok",
+                Err(Error::Parse { ref error, .. })
+                    if error.part == message::header::Part::TokenSize => {
+                    "zero"
+                }
+                _ => "other",
+            });
+        }
+        assert_eq!(6, session.frames_consumed());
+        (outcomes, session.zero_frames(), session.heartbeats())
+    }
+
+    #[test]
+    fn zero_frame_policies() {
+        let try_next = |session: &mut Session<_, _>| {
+            loop {
+                match session.try_next() {
+                    TryNext::Ready(item) => return Some(item),
+                    TryNext::NotReady => {}
+                    TryNext::Closed => return None,
+                }
+            }
+        };
+        let nexts: [&Fn(&mut Session<_, _>) -> Option<_>; 2] = [&|session| session.next(),
+                                                                &try_next];
+        for next in nexts.iter() {
+            assert_eq!((vec!["zero", "ok", "zero", "zero", "ok", "zero"], 0, 0),
+                       zero_frame_outcomes(ZeroFrame::Error, next));
+            assert_eq!((vec!["ok", "ok"], 4, 0), zero_frame_outcomes(ZeroFrame::Ignore, next));
+            assert_eq!((vec!["ok", "ok"], 0, 4),
+                       zero_frame_outcomes(ZeroFrame::Heartbeat, next));
+        }
+    }
+}
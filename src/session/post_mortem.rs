@@ -0,0 +1,464 @@
+//! Keeping what a connection sends after its framing is lost, for a
+//! misbehaving client to be diagnosed offline. Once `next` returns a fatal
+//! error, a session set to `FatalCapture::Drain` reads on and copies into
+//! its sink:
+//!
+//! | field  | encoding                                                   |
+//! |--------|------------------------------------------------------------|
+//! | magic  | `SVFC`                                                     |
+//! | code   | one byte, `fatal_code`                                     |
+//! | offset | eight bytes, big-endian: where the broken frame started,   |
+//! |        | counting from the start of the connection                  |
+//! | bytes  | the broken frame as far as it was read, then the rest      |
+//!
+//! so that the bytes before `offset`, as recorded elsewhere, followed by
+//! the captured ones make the same error again. Not kept are a preamble
+//! that failed, and the body of a frame a token gate was refusing.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::time::Duration;
+
+use Server;
+use super::{read_after, Error, Session};
+
+pub const MAGIC: [u8; 4] = *b"SVFC";
+pub const HEADER_LEN: usize = 13;
+
+/// What a session does once framing is lost.
+pub enum FatalCapture<W> {
+    /// Nothing: `next` carries on reading frames.
+    None,
+    /// Reads on into `sink` until the input ends, `max_bytes` have been
+    /// read, or `deadline` has passed since the error by the session's
+    /// capture clock, then ends the session. The deadline is checked
+    /// between reads, so a blocking reader wants a read timeout of its own.
+    Drain {
+        max_bytes: u64,
+        deadline: Option<Duration>,
+        sink: W,
+    },
+}
+
+impl<W> Default for FatalCapture<W> {
+    fn default() -> Self {
+        FatalCapture::None
+    }
+}
+
+/// Why a capture stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureEnd {
+    Eof,
+    MaxBytes,
+    Deadline,
+    /// The input failed.
+    Read(io::ErrorKind),
+    /// The sink failed, so what was read after is not kept.
+    Sink(io::ErrorKind),
+}
+
+/// What a capture kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FatalCaptured {
+    pub code: u8,
+    pub offset: u64,
+    /// Read from the input after the error, toward `max_bytes`.
+    pub drained: u64,
+    pub end: CaptureEnd,
+}
+
+/// The capture code of an error after which framing is lost, if it is
+/// one: where the next frame starts can no longer be told.
+pub fn fatal_code<A, P>(e: &Error<A, P>) -> Option<u8> {
+    match *e {
+        Error::EofInMessageSize => Some(1),
+        Error::Truncated { .. } => Some(2),
+        Error::Preamble(_) => Some(3),
+        Error::BadVarint => Some(4),
+        _ => None,
+    }
+}
+
+/// Splits a capture into its code, its offset and the bytes after.
+pub fn parse_header(capture: &[u8]) -> Option<(u8, u64, &[u8])> {
+    if capture.len() < HEADER_LEN || capture[..4] != MAGIC {
+        return None;
+    }
+    Some((capture[4], BigEndian::read_u64(&capture[5..HEADER_LEN]), &capture[HEADER_LEN..]))
+}
+
+/// The broken frame's bytes that `session` still holds after `e`.
+fn held<'s, 'a, S, R>(session: &'s Session<'a, S, R>, e: &Error<S::AuthErr, PushErr<S>>)
+                      -> (Option<Vec<u8>>, &'s [u8])
+    where S: 'a + Server
+{
+    let framing = session.framing;
+    match *e {
+        Error::EofInMessageSize | Error::BadVarint => {
+            (None, &session.buffer[..cmp::min(framing.max_prefix_len(), session.buffer.len())])
+        }
+        Error::Truncated { found, remaining } => {
+            let size = found as usize + remaining as usize;
+            let mut prefix = vec![0; framing.prefix_len(size)];
+            framing.write_size(&mut prefix, size);
+            let found = cmp::min(found as usize, session.buffer.len());
+            (Some(prefix), &session.buffer[..found])
+        }
+        _ => (None, &[]),
+    }
+}
+
+type PushErr<S> = <<S as Server>::Stream as ::Stream>::PushErr;
+
+/// Drains `session`'s input after the fatal error `e`, if it is one and
+/// the session is set to, and ends the session.
+pub fn capture<'a, S, R>(session: &mut Session<'a, S, R>, e: &Error<S::AuthErr, PushErr<S>>)
+    where S: 'a + Server,
+          R: Read
+{
+    let code = match fatal_code(e) {
+        Some(code) => code,
+        None => return,
+    };
+    let (max_bytes, deadline, mut sink) = match mem::replace(&mut session.on_fatal,
+                                                             FatalCapture::None) {
+        FatalCapture::None => return,
+        FatalCapture::Drain { max_bytes, deadline, sink } => (max_bytes, deadline, sink),
+    };
+    let start = session.capture_clock.now();
+    let mut header = MAGIC.to_vec();
+    header.push(code);
+    header.extend_from_slice(&[0; 8]);
+    BigEndian::write_u64(&mut header[5..], session.offset);
+    let (prefix, frame) = held(session, e);
+    if let Some(prefix) = prefix {
+        header.extend_from_slice(&prefix);
+    }
+    header.extend_from_slice(frame);
+
+    let mut captured = FatalCaptured {
+        code: code,
+        offset: session.offset,
+        drained: 0,
+        end: CaptureEnd::Eof,
+    };
+    captured.end = match sink.write_all(&header) {
+        Err(e) => CaptureEnd::Sink(e.kind()),
+        Ok(()) => {
+            let end = drain(session, &mut sink, max_bytes, deadline.map(|d| start + d),
+                            &mut captured.drained);
+            match (end, sink.flush()) {
+                (CaptureEnd::Sink(_), _) | (_, Ok(())) => end,
+                (_, Err(e)) => CaptureEnd::Sink(e.kind()),
+            }
+        }
+    };
+    session.fatal_captured = Some(captured);
+}
+
+/// Copies `session`'s input into `sink`, counting it in `drained`, until
+/// one of the ends is reached.
+fn drain<'a, S, R>(session: &mut Session<'a, S, R>,
+                   sink: &mut Box<Write + Send>,
+                   max_bytes: u64,
+                   deadline: Option<Duration>,
+                   drained: &mut u64)
+                   -> CaptureEnd
+    where S: 'a,
+          R: Read
+{
+    let mut chunk = [0_u8; 4096];
+    loop {
+        if *drained >= max_bytes {
+            return CaptureEnd::MaxBytes;
+        }
+        if deadline.map_or(false, |deadline| session.capture_clock.now() >= deadline) {
+            return CaptureEnd::Deadline;
+        }
+        let wanted = cmp::min(chunk.len() as u64, max_bytes - *drained) as usize;
+        match read_after(&mut session.unread, &mut session.reader, &mut chunk[..wanted]) {
+            Ok(0) => return CaptureEnd::Eof,
+            Ok(n) => {
+                *drained += n as u64;
+                if let Err(e) = sink.write_all(&chunk[..n]) {
+                    return CaptureEnd::Sink(e.kind());
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // A read timeout, or a reader not ready, to wait out up to the
+            // deadline.
+            Err(ref e) if deadline.is_some() &&
+                          (e.kind() == io::ErrorKind::WouldBlock ||
+                           e.kind() == io::ErrorKind::TimedOut) => {
+                session.backoff.pause(Duration::from_millis(1))
+            }
+            Err(e) => return CaptureEnd::Read(e.kind()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::TokenServer;
+    use session::{Backoff, Error, Session};
+    use Clock;
+    use stream::FileStream;
+    use test_support::frame;
+
+    fn server() -> TokenServer<FileStream<Vec<u8>>> {
+        let mut server = TokenServer::new();
+        server.add_token(b"t").insert(b"i".to_vec(), FileStream::new(vec![]));
+        server
+    }
+
+    /// A sink to look into once the session has it, failing after `room`
+    /// bytes.
+    #[derive(Clone)]
+    struct Shared {
+        bytes: Arc<Mutex<Vec<u8>>>,
+        room: usize,
+    }
+
+    impl Shared {
+        fn new(room: usize) -> Self {
+            Shared {
+                bytes: Arc::new(Mutex::new(vec![])),
+                room: room,
+            }
+        }
+
+        fn get(&self) -> Vec<u8> {
+            self.bytes.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut bytes = self.bytes.lock().unwrap();
+            let n = cmp::min(buf.len(), self.room - bytes.len());
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "full"));
+            }
+            bytes.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serves each chunk as far as the caller's buffer allows, an empty one
+    /// as the end of the input, then, if `slow`, a byte every 2ms of its
+    /// clock forever.
+    struct Chunks {
+        chunks: Vec<Vec<u8>>,
+        slow: Option<SharedClock>,
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.chunks.is_empty() {
+                let clock = match self.slow {
+                    None => return Ok(0),
+                    Some(ref clock) => clock,
+                };
+                clock.advance(Duration::from_millis(2));
+                buf[0] = 0;
+                return Ok(1);
+            }
+            let chunk = self.chunks.remove(0);
+            let n = cmp::min(chunk.len(), buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.chunks.insert(0, chunk[n..].to_vec());
+            }
+            Ok(n)
+        }
+    }
+
+    fn drain(max_bytes: u64, deadline: Option<Duration>, sink: &Shared) -> FatalCapture<Shared> {
+        FatalCapture::Drain {
+            max_bytes: max_bytes,
+            deadline: deadline,
+            sink: sink.clone(),
+        }
+    }
+
+    /// A whole frame, then one cut short by the end of the input, then the
+    /// rest of it and `after`.
+    fn broken(after: Vec<u8>, slow: Option<SharedClock>) -> Chunks {
+        let second = frame(b"t", b"i", 2, b"payload");
+        let mut rest = second[10..].to_vec();
+        rest.extend(after);
+        Chunks {
+            chunks: vec![frame(b"t", b"i", 1, b"first"),
+                         second[..2].to_vec(),
+                         second[2..10].to_vec(),
+                         vec![],
+                         rest],
+            slow: slow,
+        }
+    }
+
+    #[test]
+    fn replays_to_the_same_error() {
+        let first = frame(b"t", b"i", 1, b"first");
+        let mut bytes = first.clone();
+        bytes.extend_from_slice(&frame(b"t", b"i", 2, b"payload")[..14]);
+        let sink = Shared::new(1024);
+        let mut original = server();
+        let (error, captured) = {
+            let mut session = Session::new(&mut original, Cursor::new(bytes.clone()));
+            session.set_on_fatal(drain(1024, None, &sink));
+            assert!(session.next().unwrap().is_ok());
+            let error = format!("{:?}", session.next());
+            assert!(session.next().is_none());
+            (error, session.fatal_captured().unwrap())
+        };
+        assert_match!(FatalCaptured { code: 2, drained: 0, end: CaptureEnd::Eof, .. },
+                      captured);
+        assert_eq!(first.len() as u64, captured.offset);
+
+        let capture = sink.get();
+        let (code, offset, rest) = parse_header(&capture).unwrap();
+        assert_eq!((2, captured.offset), (code, offset));
+        let mut replayed = bytes[..offset as usize].to_vec();
+        replayed.extend_from_slice(rest);
+        assert_eq!(bytes, replayed);
+        let mut server = server();
+        let mut session = Session::new(&mut server, Cursor::new(replayed));
+        session.set_on_fatal(drain(1024, None, &Shared::new(1024)));
+        assert!(session.next().unwrap().is_ok());
+        assert_eq!(error, format!("{:?}", session.next()));
+        assert_eq!(Some(captured), session.fatal_captured());
+    }
+
+    #[test]
+    fn lone_prefix_byte_is_kept() {
+        let mut bytes = frame(b"t", b"i", 1, b"first");
+        bytes.push(0);
+        let sink = Shared::new(1024);
+        let mut server = server();
+        let mut session = Session::new(&mut server, Cursor::new(bytes.clone()));
+        session.set_on_fatal(drain(1024, None, &sink));
+        assert!(session.next().unwrap().is_ok());
+        assert_match!(Some(Err(Error::EofInMessageSize)), session.next());
+        let capture = sink.get();
+        assert_eq!(Some((1, bytes.len() as u64 - 1, &[0][..])), parse_header(&capture));
+    }
+
+    #[test]
+    fn capped_at_max_bytes() {
+        let sink = Shared::new(1024);
+        let mut server = server();
+        let mut session = Session::new(&mut server, broken(vec![7; 500], None));
+        session.set_on_fatal(drain(100, None, &sink));
+        // The rest of the broken frame counts toward the cap.
+        assert!(session.next().unwrap().is_ok());
+        assert_match!(Some(Err(Error::Truncated { found: 8, .. })), session.next());
+        assert!(session.next().is_none());
+        let captured = session.fatal_captured().unwrap();
+        assert_eq!((100, CaptureEnd::MaxBytes), (captured.drained, captured.end));
+        assert_eq!(HEADER_LEN + 2 + 8 + 100, sink.get().len());
+    }
+
+    #[test]
+    fn stops_at_the_deadline() {
+        let clock = SharedClock::new(Duration::from_secs(1));
+        let sink = Shared::new(1 << 20);
+        let mut server = server();
+        let mut session = Session::new(&mut server, broken(vec![], Some(clock.clone())));
+        session.set_capture_clock(clock);
+        session.set_on_fatal(drain(1 << 20, Some(Duration::from_millis(20)), &sink));
+        assert!(session.next().unwrap().is_ok());
+        assert!(session.next().unwrap().is_err());
+        let captured = session.fatal_captured().unwrap();
+        assert_eq!(CaptureEnd::Deadline, captured.end);
+        // The rest of the broken frame, then a byte every 2ms.
+        let rest = frame(b"t", b"i", 2, b"payload").len() as u64 - 10;
+        assert_eq!(rest + 10, captured.drained);
+    }
+
+    /// Ends once, then is never ready.
+    struct Blocked {
+        ended: bool,
+    }
+
+    impl Read for Blocked {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            if !self.ended {
+                self.ended = true;
+                return Ok(0);
+            }
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"))
+        }
+    }
+
+    #[test]
+    fn waits_on_a_reader_not_ready_by_the_backoff() {
+        let clock = SharedClock::new(Duration::from_secs(1));
+        let mut backoff = Backoff::new();
+        {
+            let clock = clock.clone();
+            backoff.set_sleeper(move |delay| clock.advance(delay));
+        }
+        let bytes = frame(b"t", b"i", 1, b"first")[..5].to_vec();
+        let sink = Shared::new(1024);
+        let mut server = server();
+        let reader = Cursor::new(bytes).chain(Blocked { ended: false });
+        let mut session = Session::new(&mut server, reader);
+        session.set_capture_clock(clock.clone());
+        session.set_backoff(backoff);
+        session.set_on_fatal(drain(1024, Some(Duration::from_millis(20)), &sink));
+        assert!(session.next().unwrap().is_err());
+        let captured = session.fatal_captured().unwrap();
+        assert_eq!((0, CaptureEnd::Deadline), (captured.drained, captured.end));
+        assert_eq!(Duration::from_millis(1020), clock.now());
+        assert_eq!(Duration::from_millis(0), session.pressure_stats().slept);
+    }
+
+    #[test]
+    fn failing_sink_ends_the_capture() {
+        let sink = Shared::new(HEADER_LEN + 20);
+        let mut server = server();
+        let mut session = Session::new(&mut server, broken(vec![7; 500], None));
+        session.set_on_fatal(drain(1024, None, &sink));
+        assert!(session.next().unwrap().is_ok());
+        assert!(session.next().unwrap().is_err());
+        assert!(session.next().is_none());
+        let captured = session.fatal_captured().unwrap();
+        assert_eq!(CaptureEnd::Sink(io::ErrorKind::Other), captured.end);
+        assert_eq!(HEADER_LEN + 20, sink.get().len());
+    }
+
+    #[test]
+    fn only_fatal_errors_capture() {
+        let bytes: Vec<_> = frame(b"x", b"i", 1, b"unknown token")
+                                .into_iter()
+                                .chain(frame(b"t", b"i", 2, b"ok"))
+                                .collect();
+        let sink = Shared::new(1024);
+        let mut server = server();
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_on_fatal(drain(1024, None, &sink));
+        assert!(session.next().unwrap().is_err());
+        assert!(session.next().unwrap().is_ok());
+        assert!(session.next().is_none());
+        assert!(session.fatal_captured().is_none());
+        assert!(sink.get().is_empty());
+    }
+}
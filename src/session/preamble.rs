@@ -0,0 +1,160 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io::prelude::*;
+
+use message::Strictness;
+use util::fill;
+use super::Error;
+
+/// Starts every preamble. Read as a frame, it declares a four-byte message,
+/// shorter than any header, so no valid frame can begin with it; that is
+/// how `PreamblePolicy::Optional` tells the two apart.
+pub const MAGIC: [u8; 4] = [0, 4, b'S', b'V'];
+
+pub const VERSION: u8 = 1;
+
+/// Flag bits for the strictness the peer asks for; zero leaves the
+/// session's own.
+const STRICTNESS_FLAGS: u8 = 0b11;
+
+/// Whether a connection begins with a preamble.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreamblePolicy {
+    Required,
+    /// Reads a preamble if the connection starts with `MAGIC`.
+    Optional,
+    /// Treats every byte as frames.
+    Forbidden,
+}
+
+impl Default for PreamblePolicy {
+    fn default() -> Self {
+        PreamblePolicy::Forbidden
+    }
+}
+
+/// What the peer said about itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub version: u8,
+    /// The client implementation's name and version.
+    pub name: Vec<u8>,
+    pub strictness: Option<Strictness>,
+}
+
+impl PeerInfo {
+    /// The preamble describing this peer, magic included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.version);
+        let name = &self.name[..cmp::min(self.name.len(), 255)];
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name);
+        bytes.push(match self.strictness {
+            None => 0,
+            Some(Strictness::Strict) => 1,
+            Some(Strictness::Standard) => 2,
+            Some(Strictness::Lenient) => 3,
+        });
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PreambleError {
+    /// No longer returned by a session: frames without a preamble it
+    /// requires are refused as `Error::ProtocolViolation`.
+    Missing,
+    Truncated,
+    UnknownVersion(u8),
+    UnknownFlags(u8),
+    /// The peer asked for a strictness more lenient than the session's.
+    Disallowed(Strictness),
+}
+
+impl Display for PreambleError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PreambleError::UnknownVersion(v) => write!(f, "unknown preamble version {}", v),
+            PreambleError::UnknownFlags(flags) => {
+                write!(f, "unknown preamble flags {:#04x}", flags)
+            }
+            PreambleError::Disallowed(s) => {
+                write!(f, "peer requested disallowed {:?} strictness", s)
+            }
+            _ => f.write_str(error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for PreambleError {
+    fn description(&self) -> &str {
+        match *self {
+            PreambleError::Missing => "missing preamble",
+            PreambleError::Truncated => "truncated preamble",
+            PreambleError::UnknownVersion(_) => "unknown preamble version",
+            PreambleError::UnknownFlags(_) => "unknown preamble flags",
+            PreambleError::Disallowed(_) => "peer requested disallowed strictness",
+        }
+    }
+}
+
+fn leniency(strictness: Strictness) -> u8 {
+    match strictness {
+        Strictness::Strict => 0,
+        Strictness::Standard => 1,
+        Strictness::Lenient => 2,
+    }
+}
+
+/// What `sniff` found.
+pub enum Sniffed {
+    Preamble(PeerInfo),
+    /// The bytes read while looking for `MAGIC`, which belong to the frames.
+    Absent(Vec<u8>),
+}
+
+/// Reads a preamble if `reader` starts with one. `floor` is the session's
+/// strictness, which the peer may tighten but not loosen.
+pub fn sniff<R: Read, A, P>(reader: &mut R, floor: Strictness) -> Result<Sniffed, Error<A, P>> {
+    let mut magic = [0_u8; 4];
+    let n = try!(fill(reader, &mut magic));
+    if magic[..n] != MAGIC[..] {
+        return Ok(Sniffed::Absent(magic[..n].to_vec()));
+    }
+
+    let mut fixed = [0_u8; 2];
+    if try!(fill(reader, &mut fixed)) < fixed.len() {
+        return Err(PreambleError::Truncated.into());
+    }
+    let (version, name_len) = (fixed[0], fixed[1] as usize);
+    let mut rest = vec![0; name_len + 1];
+    if try!(fill(reader, &mut rest)) < rest.len() {
+        return Err(PreambleError::Truncated.into());
+    }
+    if version != VERSION {
+        return Err(PreambleError::UnknownVersion(version).into());
+    }
+    let flags = rest.pop().unwrap();
+    if flags & !STRICTNESS_FLAGS != 0 {
+        return Err(PreambleError::UnknownFlags(flags).into());
+    }
+    let strictness = match flags & STRICTNESS_FLAGS {
+        0 => None,
+        1 => Some(Strictness::Strict),
+        2 => Some(Strictness::Standard),
+        _ => Some(Strictness::Lenient),
+    };
+    if let Some(s) = strictness {
+        if leniency(s) > leniency(floor) {
+            return Err(PreambleError::Disallowed(s).into());
+        }
+    }
+    Ok(Sniffed::Preamble(PeerInfo {
+        version: version,
+        name: rest,
+        strictness: strictness,
+    }))
+}
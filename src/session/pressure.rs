@@ -0,0 +1,283 @@
+//! Acting on the pressure a stream reports after each push: slowing down
+//! the reads, so that the socket buffer fills and the sender is held back
+//! by TCP, or stopping them until the application resumes the session.
+
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+use stream::Pressure;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PressureStats {
+    /// Frames after which a stream asked to slow down.
+    pub slowdowns: u64,
+    /// Time spent asleep before reads on that account.
+    pub slept: Duration,
+    /// Frames after which a stream asked to stop.
+    pub stops: u64,
+}
+
+/// How a session sleeps when a stream asks it to slow down.
+pub struct Backoff {
+    max_delay: Duration,
+    sleep: Box<FnMut(Duration) + Send>,
+    stats: PressureStats,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff {
+            max_delay: Duration::from_secs(1),
+            sleep: Box::new(thread::sleep),
+            stats: PressureStats::default(),
+        }
+    }
+
+    /// The longest a session sleeps before a read, whatever delay a stream
+    /// suggests. Defaults to a second.
+    pub fn set_max_delay(&mut self, max_delay: Duration) {
+        self.max_delay = max_delay;
+    }
+
+    /// How to sleep, for tests with a fake clock; `thread::sleep` by
+    /// default.
+    pub fn set_sleeper<F: FnMut(Duration) + Send + 'static>(&mut self, sleep: F) {
+        self.sleep = Box::new(sleep);
+    }
+
+    pub fn stats(&self) -> PressureStats {
+        self.stats
+    }
+
+    /// Counts a hint from a stream.
+    pub fn note(&mut self, pressure: Pressure) {
+        match pressure {
+            Pressure::None => {}
+            Pressure::SlowDown { .. } => self.stats.slowdowns += 1,
+            Pressure::Stop => self.stats.stops += 1,
+        }
+    }
+
+    /// Sleeps for `delay`, or the maximum if that is shorter.
+    pub fn sleep(&mut self, delay: Duration) {
+        let delay = cmp::min(delay, self.max_delay);
+        (self.sleep)(delay);
+        self.stats.slept += delay;
+    }
+
+    /// Sleeps for `delay` on the session's own account, as when waiting on
+    /// a reader that is not ready, without counting it toward the stats.
+    pub fn pause(&mut self, delay: Duration) {
+        (self.sleep)(delay);
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::{Finder, RetentionAction, RetentionPolicy, TokenServer};
+    use session::{Session, TryNext};
+    use test_support::frame;
+    use test_support::server;
+    use {Server, Stream, Void};
+
+    /// Takes on the next scripted hint with each push.
+    struct Hinting {
+        hints: VecDeque<Pressure>,
+        pressure: Pressure,
+        pushed: Vec<Vec<u8>>,
+    }
+
+    impl Stream for Hinting {
+        type PushErr = Void;
+        fn push(&mut self, _: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+            self.pressure = self.hints.pop_front().unwrap_or(Pressure::None);
+            self.pushed.push(payload.to_vec());
+            Ok(())
+        }
+
+        fn pressure(&self) -> Pressure {
+            self.pressure
+        }
+
+        type Extract = Vec<Vec<u8>>;
+        type ExtractErr = Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(self.pushed)
+        }
+    }
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn hinting(hints: Vec<Pressure>) -> Hinting {
+        Hinting {
+            hints: hints.into_iter().collect(),
+            pressure: Pressure::None,
+            pushed: vec![],
+        }
+    }
+
+    fn server(hints: Vec<Pressure>) -> server::Ok<Hinting> {
+        let mut finder = Finder::new();
+        finder.insert(b"i".to_vec(), hinting(hints));
+        server::Ok(finder)
+    }
+
+    /// Frames with payloads "0", "1" and so on.
+    fn traffic(n: u64) -> Cursor<Vec<u8>> {
+        Cursor::new((0..n).flat_map(|i| frame(b"t", b"i", i, i.to_string().as_bytes())).collect())
+    }
+
+    fn payloads(n: u64) -> Vec<Vec<u8>> {
+        (0..n).map(|i| i.to_string().into_bytes()).collect()
+    }
+
+    fn backoff(sleeps: &Arc<Mutex<Vec<Duration>>>) -> Backoff {
+        let sleeps = sleeps.clone();
+        let mut backoff = Backoff::new();
+        backoff.set_max_delay(millis(100));
+        backoff.set_sleeper(move |d| sleeps.lock().unwrap().push(d));
+        backoff
+    }
+
+    #[test]
+    fn slows_down_before_the_next_read() {
+        let sleeps = Arc::new(Mutex::new(vec![]));
+        let slow = |n| Pressure::SlowDown { suggested_delay: millis(n) };
+        let mut server = server(vec![Pressure::None, slow(10), slow(500)]);
+        let mut session = Session::new(&mut server, traffic(4));
+        session.set_backoff(backoff(&sleeps));
+        let mut slept = vec![];
+        while let Some(result) = session.next() {
+            assert!(result.is_ok());
+            slept.push(sleeps.lock().unwrap().clone());
+        }
+        assert_eq!(vec![vec![], vec![], vec![millis(10)], vec![millis(10), millis(100)]], slept);
+        assert_eq!(PressureStats {
+                       slowdowns: 2,
+                       slept: millis(110),
+                       stops: 0,
+                   },
+                   session.pressure_stats());
+    }
+
+    #[test]
+    fn stops_until_resumed() {
+        let sleeps = Arc::new(Mutex::new(vec![]));
+        let mut server = server(vec![Pressure::None, Pressure::Stop, Pressure::Stop]);
+        {
+            let mut session = Session::new(&mut server, traffic(4));
+            session.set_backoff(backoff(&sleeps));
+            assert_eq!(b"i".to_vec(), session.next().unwrap().unwrap().id);
+            assert_eq!(b"i".to_vec(), session.next().unwrap().unwrap().id);
+            assert!(session.is_stopped());
+            assert!(session.next().is_none());
+            assert!(session.next().is_none());
+            session.resume_reading();
+            assert!(session.next().unwrap().is_ok());
+            assert!(session.next().is_none());
+            session.resume_reading();
+            assert!(session.next().unwrap().is_ok());
+            assert!(session.next().is_none());
+            assert!(!session.is_stopped());
+            assert_eq!(2, session.pressure_stats().stops);
+        }
+        assert_eq!(payloads(4), server.0[&b"i"[..]].pushed);
+        assert!(sleeps.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn try_next_surfaces_hints_without_sleeping() {
+        let sleeps = Arc::new(Mutex::new(vec![]));
+        let slow = Pressure::SlowDown { suggested_delay: millis(10) };
+        let mut server = server(vec![slow, Pressure::Stop]);
+        {
+            let mut session = Session::new(&mut server, traffic(3));
+            session.set_backoff(backoff(&sleeps));
+            assert_match!(TryNext::Ready(Ok(_)), session.try_next());
+            assert_eq!(slow, session.pressure());
+            assert_match!(TryNext::Ready(Ok(_)), session.try_next());
+            assert_eq!(Pressure::Stop, session.pressure());
+            assert_match!(TryNext::NotReady, session.try_next());
+            session.resume_reading();
+            assert_match!(TryNext::Ready(Ok(_)), session.try_next());
+            assert_eq!(Pressure::None, session.pressure());
+            assert_match!(TryNext::Closed, session.try_next());
+            // Switching to `next` after a hint `try_next` surfaced does not
+            // act on it again.
+            assert!(session.next().is_none());
+        }
+        assert_eq!(payloads(3), server.0[&b"i"[..]].pushed);
+        assert!(sleeps.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn takes_the_hint_of_the_stream_a_message_was_diverted_to() {
+        let mut server = TokenServer::new();
+        server.set_clock(SharedClock::new(Duration::from_secs(86400)));
+        {
+            let finder = server.add_token(b"t");
+            finder.insert(b"i".to_vec(), hinting(vec![]));
+            finder.insert(b"quarantine".to_vec(), hinting(vec![Pressure::Stop]));
+        }
+        server.set_retention(b"t",
+                             RetentionPolicy {
+                                 max_age: Duration::from_secs(3600),
+                                 action: RetentionAction::Divert(b"quarantine".to_vec()),
+                                 in_backfill: false,
+                             });
+        {
+            let mut session = Session::new(&mut server, traffic(2));
+            assert!(session.next().unwrap().is_ok());
+            assert!(session.is_stopped());
+            assert!(session.next().is_none());
+        }
+        let finder = server.auth(b"t").unwrap();
+        assert!(finder[&b"i"[..]].pushed.is_empty());
+        assert_eq!(payloads(1), finder[&b"quarantine"[..]].pushed);
+    }
+
+    quickcheck_test! {
+    loses_and_reorders_nothing(hints: Vec<(u8, u8)>; bool) {
+        let hints: Vec<_> = hints.into_iter()
+                                 .map(|(kind, delay)| match kind % 3 {
+                                     0 => Pressure::None,
+                                     1 => Pressure::SlowDown {
+                                         suggested_delay: millis(delay as u64),
+                                     },
+                                     _ => Pressure::Stop,
+                                 })
+                                 .collect();
+        let n = hints.len() as u64 + 1;
+        let sleeps = Arc::new(Mutex::new(vec![]));
+        let mut server = server(hints);
+        let mut ids = 0;
+        {
+            let mut session = Session::new(&mut server, traffic(n));
+            session.set_backoff(backoff(&sleeps));
+            loop {
+                match session.next() {
+                    Some(result) => ids += result.is_ok() as u64,
+                    None if session.is_stopped() => session.resume_reading(),
+                    None => break,
+                }
+            }
+        }
+        ids == n && server.0[&b"i"[..]].pushed == payloads(n)
+    }}
+}
@@ -0,0 +1,284 @@
+//! The orderings a connection's frames may come in, as one table instead of
+//! checks scattered through each feature. A session feeds its `Protocol`
+//! everything that happens on the connection and refuses a frame the table
+//! does not allow with `Error::ProtocolViolation`.
+//!
+//! | state              | may receive                                      |
+//! |--------------------|--------------------------------------------------|
+//! | `Initial`          | a preamble, then data unless one is required     |
+//! | `PreambleReceived` | data                                             |
+//! | `Authenticated`    | data and control frames                          |
+//! | `Draining`         | nothing more                                     |
+//! | `Fused`            | nothing more                                     |
+//!
+//! Heartbeats are allowed until draining, a shutdown drains from any state
+//! short of fused, and a timeout fuses.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use super::PreamblePolicy;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum State {
+    /// Nothing has been received.
+    Initial,
+    PreambleReceived,
+    /// A data frame's token has authenticated.
+    Authenticated,
+    /// Shutting down; no frame is to start.
+    Draining,
+    /// Timed out; nothing more is to happen.
+    Fused,
+}
+
+pub const STATES: [State; 5] = [State::Initial,
+                                State::PreambleReceived,
+                                State::Authenticated,
+                                State::Draining,
+                                State::Fused];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+    Preamble,
+    /// A frame for any ID but the session's control ID, authenticated or
+    /// not.
+    Data,
+    /// A frame for the ID set by `Session::set_control_id`.
+    Control,
+    /// A zero-size frame, under `ZeroFrame::Heartbeat`.
+    Heartbeat,
+    /// The token of the data frame just received authenticated.
+    Authenticated,
+    Timeout,
+    Shutdown,
+}
+
+pub const INPUTS: [Input; 7] = [Input::Preamble,
+                                Input::Data,
+                                Input::Control,
+                                Input::Heartbeat,
+                                Input::Authenticated,
+                                Input::Timeout,
+                                Input::Shutdown];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// Allowed, leading to this state, which may be the same one.
+    To(State),
+    /// Not allowed; the state is unchanged.
+    Violation {
+        state: State,
+        received: Input,
+    },
+}
+
+impl Transition {
+    pub fn is_allowed(&self) -> bool {
+        match *self {
+            Transition::To(_) => true,
+            Transition::Violation { .. } => false,
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            State::Initial => "initial",
+            State::PreambleReceived => "preamble received",
+            State::Authenticated => "authenticated",
+            State::Draining => "draining",
+            State::Fused => "fused",
+        })
+    }
+}
+
+impl Display for Input {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            Input::Preamble => "a preamble",
+            Input::Data => "a data frame",
+            Input::Control => "a control frame",
+            Input::Heartbeat => "a heartbeat",
+            Input::Authenticated => "an authentication",
+            Input::Timeout => "a timeout",
+            Input::Shutdown => "a shutdown",
+        })
+    }
+}
+
+/// A connection's place in the protocol. Pure: it does no I/O and knows
+/// nothing of sessions, so its table can be tested on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    state: State,
+    preamble: PreamblePolicy,
+}
+
+impl Protocol {
+    /// Data before a preamble is a violation only if `preamble` requires
+    /// one, and a preamble is one if it forbids them.
+    pub fn new(preamble: PreamblePolicy) -> Self {
+        Protocol {
+            state: State::Initial,
+            preamble: preamble,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// What `input` would lead to, without taking it.
+    pub fn peek(&self, input: Input) -> Transition {
+        let to = match (self.state, input) {
+            (State::Fused, Input::Timeout) |
+            (State::Fused, Input::Shutdown) => Some(State::Fused),
+            (State::Fused, _) => None,
+            (_, Input::Timeout) => Some(State::Fused),
+            (_, Input::Shutdown) => Some(State::Draining),
+            (State::Draining, _) => None,
+            (state, Input::Heartbeat) => Some(state),
+
+            (State::Initial, Input::Preamble) if self.preamble != PreamblePolicy::Forbidden => {
+                Some(State::PreambleReceived)
+            }
+            (State::Initial, Input::Data) if self.preamble != PreamblePolicy::Required => {
+                Some(State::Initial)
+            }
+            (State::Initial, Input::Authenticated) if self.preamble != PreamblePolicy::Required => {
+                Some(State::Authenticated)
+            }
+            (State::PreambleReceived, Input::Data) => Some(State::PreambleReceived),
+            (State::PreambleReceived, Input::Authenticated) => Some(State::Authenticated),
+            (State::Authenticated, Input::Data) |
+            (State::Authenticated, Input::Control) |
+            (State::Authenticated, Input::Authenticated) => Some(State::Authenticated),
+            _ => None,
+        };
+        match to {
+            Some(state) => Transition::To(state),
+            None => {
+                Transition::Violation {
+                    state: self.state,
+                    received: input,
+                }
+            }
+        }
+    }
+
+    pub fn allows(&self, input: Input) -> bool {
+        self.peek(input).is_allowed()
+    }
+
+    /// Takes `input`, moving to the state it leads to if it is allowed.
+    pub fn on(&mut self, input: Input) -> Transition {
+        let transition = self.peek(input);
+        if let Transition::To(state) = transition {
+            self.state = state;
+        }
+        transition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use session::PreamblePolicy;
+
+    fn to(state: State) -> Option<State> {
+        Some(state)
+    }
+
+    /// Where each input leads from each state, in the order of `INPUTS`,
+    /// `None` for a violation.
+    fn table(preamble: PreamblePolicy) -> Vec<(State, [Option<State>; 7])> {
+        use super::State::*;
+        let required = preamble == PreamblePolicy::Required;
+        let forbidden = preamble == PreamblePolicy::Forbidden;
+        vec![(Initial,
+              [if forbidden { None } else { to(PreambleReceived) },
+               if required { None } else { to(Initial) },
+               None,
+               to(Initial),
+               if required { None } else { to(Authenticated) },
+               to(Fused),
+               to(Draining)]),
+             (PreambleReceived,
+              [None,
+               to(PreambleReceived),
+               None,
+               to(PreambleReceived),
+               to(Authenticated),
+               to(Fused),
+               to(Draining)]),
+             (Authenticated,
+              [None,
+               to(Authenticated),
+               to(Authenticated),
+               to(Authenticated),
+               to(Authenticated),
+               to(Fused),
+               to(Draining)]),
+             (Draining, [None, None, None, None, None, to(Fused), to(Draining)]),
+             (Fused, [None, None, None, None, None, to(Fused), to(Fused)])]
+    }
+
+    /// A protocol in `state`, reached the way a connection would reach it.
+    fn at(state: State, preamble: PreamblePolicy) -> Protocol {
+        let mut protocol = Protocol::new(preamble);
+        let path: &[Input] = match state {
+            State::Initial => &[],
+            State::PreambleReceived => &[Input::Preamble],
+            State::Authenticated if preamble == PreamblePolicy::Forbidden => {
+                &[Input::Data, Input::Authenticated]
+            }
+            State::Authenticated => &[Input::Preamble, Input::Data, Input::Authenticated],
+            State::Draining => &[Input::Shutdown],
+            State::Fused => &[Input::Timeout],
+        };
+        for &input in path {
+            assert_match!(Transition::To(_), protocol.on(input));
+        }
+        assert_eq!(state, protocol.state());
+        protocol
+    }
+
+    #[test]
+    fn every_transition() {
+        let policies = [PreamblePolicy::Required,
+                        PreamblePolicy::Optional,
+                        PreamblePolicy::Forbidden];
+        for &preamble in &policies {
+            let table = table(preamble);
+            assert_eq!(&STATES[..], &table.iter().map(|&(s, _)| s).collect::<Vec<_>>()[..]);
+            for &(state, ref row) in &table {
+                // Forbidding a preamble leaves no way to receive one.
+                if state == State::PreambleReceived && preamble == PreamblePolicy::Forbidden {
+                    continue;
+                }
+                for (&input, &expected) in INPUTS.iter().zip(row.iter()) {
+                    let mut protocol = at(state, preamble);
+                    let expected = match expected {
+                        Some(next) => Transition::To(next),
+                        None => {
+                            Transition::Violation {
+                                state: state,
+                                received: input,
+                            }
+                        }
+                    };
+                    assert_eq!(expected, protocol.peek(input));
+                    assert_eq!(expected.is_allowed(), protocol.allows(input));
+                    assert_eq!(expected, protocol.on(input));
+                    let after = match expected {
+                        Transition::To(next) => next,
+                        Transition::Violation { .. } => state,
+                    };
+                    assert_eq!(after, protocol.state());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::net::TcpStream;
+
+/// Fills `first` and then `second` by one `read_vectored` call.
+fn read_vectored_pair<R: Read + ?Sized>(reader: &mut R,
+                                        first: &mut [u8],
+                                        second: &mut [u8])
+                                        -> io::Result<usize> {
+    reader.read_vectored(&mut [io::IoSliceMut::new(first), io::IoSliceMut::new(second)])
+}
+
+/// Readers that can fill two buffers in one call.
+pub trait VectoredRead: Read {
+    /// Reads into `first` and then, once `first` is full, into `second`,
+    /// returning the total number of bytes read. Defaults to reading into
+    /// `first` alone.
+    fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+        let _ = second;
+        self.read(first)
+    }
+}
+
+impl<'a> VectoredRead for &'a [u8] {
+    fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+        read_vectored_pair(self, first, second)
+    }
+}
+
+impl<T: AsRef<[u8]>> VectoredRead for Cursor<T> {
+    fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+        read_vectored_pair(self, first, second)
+    }
+}
+
+impl VectoredRead for File {
+    fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+        read_vectored_pair(self, first, second)
+    }
+}
+
+impl VectoredRead for TcpStream {
+    fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+        read_vectored_pair(self, first, second)
+    }
+}
+
+impl<'a, R: VectoredRead + ?Sized> VectoredRead for &'a mut R {
+    fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+        (**self).read_pair(first, second)
+    }
+}
+
+/// Whenever buffered bytes run out, makes one vectored read into both the
+/// caller's buffer and its own, so that small back-to-back frames cost one
+/// read call per buffer's worth instead of two per frame.
+///
+/// A read that fails after buffered bytes were handed over returns those,
+/// and its error comes from the next call.
+pub struct ReadAhead<R> {
+    reader: R,
+    ahead: Vec<u8>,
+    start: usize,
+    end: usize,
+    error: Option<io::Error>,
+}
+
+impl<R: VectoredRead> ReadAhead<R> {
+    pub fn new(reader: R) -> Self {
+        ReadAhead::with_capacity(reader, 4096)
+    }
+
+    pub fn with_capacity(reader: R, capacity: usize) -> Self {
+        ReadAhead {
+            reader: reader,
+            ahead: vec![0; capacity],
+            start: 0,
+            end: 0,
+            error: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Any bytes read ahead are lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: VectoredRead> Read for ReadAhead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let buffered = cmp::min(self.end - self.start, buf.len());
+        buf[..buffered].copy_from_slice(&self.ahead[self.start..self.start + buffered]);
+        self.start += buffered;
+        if buffered == buf.len() {
+            return Ok(buffered);
+        }
+
+        self.start = 0;
+        self.end = 0;
+        let wanted = buf.len() - buffered;
+        let n = match self.reader.read_pair(&mut buf[buffered..], &mut self.ahead) {
+            Ok(n) => n,
+            Err(e) if buffered > 0 => {
+                self.error = Some(e);
+                return Ok(buffered);
+            }
+            Err(e) => return Err(e),
+        };
+        if n > wanted {
+            self.end = n - wanted;
+        }
+        Ok(buffered + cmp::min(n, wanted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+
+    use super::*;
+    use test_support::*;
+
+    struct Counting<'a> {
+        bytes: &'a [u8],
+        calls: usize,
+    }
+
+    impl<'a> Read for Counting<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls += 1;
+            self.bytes.read(buf)
+        }
+    }
+
+    impl<'a> VectoredRead for Counting<'a> {
+        fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+            self.calls += 1;
+            self.bytes.read_pair(first, second)
+        }
+    }
+
+    quickcheck_test! {
+    same_bytes(bytes: Vec<u8>, chunks: Vec<u8>, capacity: u8; bool) {
+        let mut reader = ReadAhead::with_capacity(&bytes[..], capacity as usize);
+        let mut read = vec![];
+        for &chunk in chunks.iter().chain(Some(255).iter().cycle().take(bytes.len())) {
+            let mut buf = vec![0; chunk as usize];
+            let n = reader.read(&mut buf).unwrap();
+            read.extend(buf[..n].into_copy_iter());
+        }
+        read == bytes
+    }}
+
+    #[test]
+    fn fewer_calls() {
+        let bytes = [7_u8; 100];
+        let mut reader = ReadAhead::with_capacity(Counting {
+                                                      bytes: &bytes,
+                                                      calls: 0,
+                                                  },
+                                                  64);
+        let mut buf = [0_u8; 2];
+        for _ in 0..50 {
+            assert_eq!(2, reader.read(&mut buf).unwrap());
+        }
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+        // 2 + 64 bytes, then 2 + 32 bytes, then end of input.
+        assert_eq!(3, reader.get_ref().calls);
+    }
+    /// Reads `bytes`, then fails every call after.
+    struct Failing<'a>(&'a [u8]);
+
+    impl<'a> Read for Failing<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf) {
+                Ok(0) => Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+                read => read,
+            }
+        }
+    }
+
+    impl<'a> VectoredRead for Failing<'a> {
+        fn read_pair(&mut self, first: &mut [u8], second: &mut [u8]) -> io::Result<usize> {
+            match self.0.read_pair(first, second) {
+                Ok(0) => Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+                read => read,
+            }
+        }
+    }
+
+    #[test]
+    fn error_after_buffered_bytes_comes_next() {
+        let mut reader = ReadAhead::with_capacity(Failing(&[1, 2, 3, 4]), 8);
+        let mut buf = [0_u8; 2];
+        assert_eq!(2, reader.read(&mut buf).unwrap());
+        let mut buf = [0_u8; 4];
+        assert_eq!(2, reader.read(&mut buf).unwrap());
+        assert_eq!([3, 4], buf[..2]);
+        let e = reader.read(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionReset, e.kind());
+    }
+    #[test]
+    fn file_fills_both_in_one_call() {
+        let path = ::std::env::temp_dir().join("sousveillance-read-ahead-file");
+        File::create(&path).unwrap().write_all(&[1, 2, 3, 4, 5]).unwrap();
+        let (mut first, mut second) = ([0_u8; 2], [0_u8; 8]);
+        let n = File::open(&path).unwrap().read_pair(&mut first, &mut second).unwrap();
+        assert_eq!((5, [1, 2], &[3, 4, 5][..]), (n, first, &second[..3]));
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
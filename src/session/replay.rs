@@ -0,0 +1,284 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+
+use flate2::read::MultiGzDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// An error from the underlying reader or decoder, with how far into the
+/// input it happened.
+#[derive(Debug)]
+pub struct ReplayError {
+    /// Bytes consumed from the underlying reader. The decoder reads ahead,
+    /// so this may be past the exact byte at fault.
+    pub compressed: u64,
+    /// Bytes produced so far.
+    pub uncompressed: u64,
+    pub error: io::Error,
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f,
+               "{} at compressed offset {}, uncompressed offset {}",
+               self.error,
+               self.compressed,
+               self.uncompressed)
+    }
+}
+
+impl error::Error for ReplayError {
+    fn description(&self) -> &str {
+        "error replaying capture"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.error)
+    }
+}
+
+/// The underlying reader, replaying the bytes sniffed for the magic number
+/// first and counting everything it reads.
+struct Sniffed<R> {
+    reader: R,
+    sniffed: [u8; 2],
+    start: usize,
+    end: usize,
+    consumed: u64,
+}
+
+impl<R: Read> Sniffed<R> {
+    fn sniff(&mut self) -> io::Result<()> {
+        while self.end < self.sniffed.len() {
+            match self.reader.read(&mut self.sniffed[self.end..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.end += n;
+                    self.consumed += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_gzip(&self) -> bool {
+        self.sniffed[..self.end] == GZIP_MAGIC
+    }
+}
+
+impl<R: Read> Read for Sniffed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start < self.end {
+            let n = cmp::min(self.end - self.start, buf.len());
+            buf[..n].copy_from_slice(&self.sniffed[self.start..self.start + n]);
+            self.start += n;
+            return Ok(n);
+        }
+        let n = try!(self.reader.read(buf));
+        self.consumed += n as u64;
+        Ok(n)
+    }
+}
+
+enum State<R> {
+    Undetected(Sniffed<R>),
+    Raw(Sniffed<R>),
+    Gzip(MultiGzDecoder<Sniffed<R>>),
+    /// The gzip header could not be read, losing the reader.
+    Failed {
+        consumed: u64,
+    },
+}
+
+/// Reads a capture that may or may not be gzipped, deciding by the magic
+/// number on the first read. Concatenated gzip members are read as one.
+/// Errors are `ReplayError`s wrapped in an `io::Error` of the same kind.
+pub struct CompressedReplay<R> {
+    state: State<R>,
+    uncompressed: u64,
+}
+
+impl<R: Read> CompressedReplay<R> {
+    pub fn new(reader: R) -> Self {
+        CompressedReplay {
+            state: State::Undetected(Sniffed {
+                reader: reader,
+                sniffed: [0; 2],
+                start: 0,
+                end: 0,
+                consumed: 0,
+            }),
+            uncompressed: 0,
+        }
+    }
+
+    /// `None` until the first read.
+    pub fn is_gzip(&self) -> Option<bool> {
+        match self.state {
+            State::Undetected(_) => None,
+            State::Raw(_) => Some(false),
+            State::Gzip(_) | State::Failed { .. } => Some(true),
+        }
+    }
+
+    /// Bytes consumed from the underlying reader.
+    pub fn compressed_offset(&self) -> u64 {
+        match self.state {
+            State::Undetected(ref r) | State::Raw(ref r) => r.consumed,
+            State::Gzip(ref d) => d.get_ref().consumed,
+            State::Failed { consumed } => consumed,
+        }
+    }
+
+    /// Bytes read from this adapter.
+    pub fn uncompressed_offset(&self) -> u64 {
+        self.uncompressed
+    }
+
+    fn wrap(&self, error: io::Error) -> io::Error {
+        io::Error::new(error.kind(),
+                       ReplayError {
+                           compressed: self.compressed_offset(),
+                           uncompressed: self.uncompressed,
+                           error: error,
+                       })
+    }
+
+    fn detect(&mut self) -> io::Result<()> {
+        let mut sniffed = match mem::replace(&mut self.state, State::Failed { consumed: 0 }) {
+            State::Undetected(sniffed) => sniffed,
+            state => {
+                self.state = state;
+                return Ok(());
+            }
+        };
+        if let Err(e) = sniffed.sniff() {
+            self.state = State::Undetected(sniffed);
+            return Err(e);
+        }
+        if !sniffed.is_gzip() {
+            self.state = State::Raw(sniffed);
+            return Ok(());
+        }
+        let consumed = sniffed.consumed;
+        match MultiGzDecoder::new(sniffed) {
+            Ok(decoder) => {
+                self.state = State::Gzip(decoder);
+                Ok(())
+            }
+            Err(e) => {
+                self.state = State::Failed { consumed: consumed };
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for CompressedReplay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Err(e) = self.detect() {
+            return Err(self.wrap(e));
+        }
+        let result = match self.state {
+            State::Undetected(_) => unreachable!(),
+            State::Raw(ref mut r) => r.read(buf),
+            State::Gzip(ref mut d) => d.read(buf),
+            State::Failed { .. } => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "unreadable gzip header"))
+            }
+        };
+        match result {
+            Ok(n) => {
+                self.uncompressed += n as u64;
+                Ok(n)
+            }
+            Err(e) => Err(self.wrap(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::Default);
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all<R: Read>(replay: &mut CompressedReplay<R>) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        try!(replay.read_to_end(&mut bytes));
+        Ok(bytes)
+    }
+
+    #[test]
+    fn plain() {
+        let bytes = b"\x00\x05hello, not gzipped";
+        let mut replay = CompressedReplay::new(&bytes[..]);
+        assert_eq!(None, replay.is_gzip());
+        assert_eq!(&bytes[..], &*read_all(&mut replay).unwrap());
+        assert_eq!(Some(false), replay.is_gzip());
+        assert_eq!(bytes.len() as u64, replay.compressed_offset());
+        assert_eq!(bytes.len() as u64, replay.uncompressed_offset());
+    }
+
+    #[test]
+    fn shorter_than_magic() {
+        let mut replay = CompressedReplay::new(&[0x1f_u8][..]);
+        assert_eq!(vec![0x1f], read_all(&mut replay).unwrap());
+        assert_eq!(Some(false), replay.is_gzip());
+    }
+
+    #[test]
+    fn single_member() {
+        let bytes: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let compressed = gzip(&bytes);
+        let mut replay = CompressedReplay::new(&*compressed);
+        assert_eq!(bytes, read_all(&mut replay).unwrap());
+        assert_eq!(Some(true), replay.is_gzip());
+        assert_eq!(compressed.len() as u64, replay.compressed_offset());
+        assert_eq!(bytes.len() as u64, replay.uncompressed_offset());
+    }
+
+    #[test]
+    fn concatenated_members() {
+        let mut compressed = gzip(b"first capture, ");
+        compressed.extend(gzip(b"second capture"));
+        let mut replay = CompressedReplay::new(&*compressed);
+        assert_eq!(&b"first capture, second capture"[..],
+                   &*read_all(&mut replay).unwrap());
+        assert_eq!(compressed.len() as u64, replay.compressed_offset());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let compressed = gzip(&bytes);
+        let truncated = &compressed[..compressed.len() / 2];
+        let mut replay = CompressedReplay::new(truncated);
+        let err = read_all(&mut replay).unwrap_err();
+        let uncompressed = replay.uncompressed_offset();
+        assert!(uncompressed < bytes.len() as u64);
+        let err = err.into_inner().unwrap().downcast::<ReplayError>().unwrap();
+        assert_eq!(truncated.len() as u64, err.compressed);
+        assert_eq!(uncompressed, err.uncompressed);
+        let message = err.to_string();
+        assert!(message.contains(&truncated.len().to_string()));
+        assert!(message.contains(&uncompressed.to_string()));
+    }
+}
@@ -0,0 +1,401 @@
+use std::cmp;
+use std::io::prelude::*;
+
+use message::{Header, Prefix, MAX_VARINT_LEN};
+use server::ConsumeError;
+use stream::StreamingStream;
+use trace::Spans;
+use {Server, Stream};
+use super::{fill, handle, parse_frame, truncated, Accepted, Error, NextResult, Session};
+use super::protocol::Input;
+
+type StreamError<S> = Error<<S as Server>::AuthErr, <<S as Server>::Stream as Stream>::PushErr>;
+
+/// Skips the rest of a frame of `size` bytes, `found` of which were read.
+fn discard<R: Read, A, P>(unread: &mut Vec<u8>,
+                          reader: &mut R,
+                          mut found: usize,
+                          size: usize)
+                          -> Result<(), Error<A, P>> {
+    let mut buf = [0_u8; 512];
+    while found < size {
+        let wanted = cmp::min(buf.len(), size - found);
+        let n = try!(fill(unread, reader, &mut buf[..wanted]));
+        found += n;
+        if n < wanted {
+            return Err(truncated(found, size));
+        }
+    }
+    Ok(())
+}
+
+/// Copies the payload of a frame of `size` bytes, `found` of which were
+/// the header, into the stream it is for. Whatever goes wrong, the rest of
+/// the frame is skipped where possible and nothing partial is committed.
+fn stream_payload<S, R>(server: &mut S,
+                        unread: &mut Vec<u8>,
+                        reader: &mut R,
+                        header: &Header,
+                        found: usize,
+                        size: usize,
+                        chunk: usize)
+                        -> Result<(), StreamError<S>>
+    where S: Server,
+          S::Stream: StreamingStream,
+          R: Read
+{
+    let stream = match server.resolve(header.token, header.id) {
+        Ok(stream) => stream,
+        Err(e) => return discard(unread, reader, found, size).and(Err(e.into())),
+    };
+    let mut writer = match stream.begin(header.timestamp, (size - found) as u64) {
+        Ok(writer) => writer,
+        Err(e) => {
+            return discard(unread, reader, found, size).and(Err(ConsumeError::Push(e).into()))
+        }
+    };
+
+    let mut buf = vec![0; chunk];
+    let mut copied = found;
+    while copied < size {
+        let wanted = cmp::min(chunk, size - copied);
+        let n = match fill(unread, reader, &mut buf[..wanted]) {
+            Ok(n) => n,
+            Err(e) => {
+                stream.abort(writer);
+                return Err(e.into());
+            }
+        };
+        copied += n;
+        if n < wanted {
+            stream.abort(writer);
+            return Err(truncated(copied, size));
+        }
+        if let Err(e) = writer.write_all(&buf[..n]) {
+            stream.abort(writer);
+            return discard(unread, reader, copied, size).and(Err(Error::Sink(e)));
+        }
+    }
+    stream.commit(writer).map_err(|e| ConsumeError::Push(e).into())
+}
+
+impl<'a, S: 'a + Server, R: Read> Session<'a, S, R>
+    where S::Stream: StreamingStream
+{
+    /// Makes `next_streaming` hand frames larger than `threshold` bytes to
+    /// their stream `chunk` bytes at a time instead of buffering them. A
+    /// `chunk` of zero is taken as one.
+    pub fn set_streaming(&mut self, threshold: usize, chunk: usize) {
+        self.streaming_threshold = threshold;
+        self.streaming_chunk = cmp::max(chunk, 1);
+    }
+
+    /// Like `next`, but streams large frames as `set_streaming` says. The
+    /// header of such a frame is read first, the stream found with
+    /// `Server::resolve`, and the payload copied into it; a frame cut short
+    /// aborts the payload rather than committing part of it.
+    pub fn next_streaming(&mut self) -> Option<NextResult<S>> {
+        if let Err(e) = self.read_preamble() {
+            return Some(Err(e));
+        }
+        if let Err(e) = self.skip_rest() {
+            return Some(Err(e.into()));
+        }
+        let mut prefix = [0_u8; MAX_VARINT_LEN];
+        let mut size;
+        loop {
+            let n = match self.read_prefix(&mut prefix) {
+                Err(e) => return Some(Err(e.into())),
+                Ok(0) => return None,
+                Ok(n) => n,
+            };
+            match self.framing.decode(&prefix[..n]) {
+                Prefix::Partial(_) => return self.cut_short(Error::EofInMessageSize).map(Err),
+                Prefix::BadVarint => return Some(Err(Error::BadVarint)),
+                Prefix::Whole { size: whole, len } => {
+                    self.frame_prefix_len = len;
+                    size = whole;
+                }
+            }
+            if !self.skip_zero_frame(size) {
+                break;
+            }
+        }
+        if let Some(e) = self.oversized(size) {
+            return Some(Err(e));
+        }
+        if size > self.streaming_threshold {
+            return self.stream_frame(size);
+        }
+
+        self.buffer.clear();
+        self.buffer.resize(size, 0);
+        match fill(&mut self.unread, &mut self.reader, &mut self.buffer) {
+            Err(e) => Some(Err(e.into())),
+            Ok(found) if found < size && !self.counted_itself(found, size) => {
+                self.cut_short(truncated(found, size)).map(Err)
+            }
+            Ok(found) => {
+                if found < size {
+                    self.repairs += 1;
+                    self.buffer.truncate(found);
+                }
+                self.frame_read(found);
+                let parsed = parse_frame(self.strictness,
+                                         self.capture_window,
+                                         self.frame_prefix_len,
+                                         &self.buffer,
+                                         &mut Spans::off());
+                let input = self.frame_input(&self.buffer, &parsed);
+                if let Err(e) = super::admit(&mut self.protocol, input) {
+                    return Some(Err(e));
+                }
+                let result = handle(&mut *self.server,
+                                    &mut self.timestamp,
+                                    parsed,
+                                    &mut Spans::off());
+                self.dispatched(&result);
+                Some(result)
+            }
+        }
+    }
+
+    fn stream_frame(&mut self, size: usize) -> Option<NextResult<S>> {
+        let mut head = vec![];
+        loop {
+            let wanted = cmp::min(Header::extent(&head), size);
+            if head.len() == wanted {
+                break;
+            }
+            let start = head.len();
+            head.resize(wanted, 0);
+            match fill(&mut self.unread, &mut self.reader, &mut head[start..]) {
+                Err(e) => return Some(Err(e.into())),
+                Ok(n) if start + n < wanted => {
+                    return self.cut_short(truncated(start + n, size)).map(Err)
+                }
+                Ok(_) => {}
+            }
+        }
+        let header = match Header::parse_diagnostic(&head, self.capture_window) {
+            Ok((header, _)) => header,
+            Err(e) => {
+                self.frame_read(size);
+                return Some(Err(Error::Parse {
+                    error: e,
+                    prefix_len: self.frame_prefix_len,
+                }));
+            }
+        };
+
+        let input = if self.is_control(&header) { Input::Control } else { Input::Data };
+        let admitted = self.admit(input)
+                           .and_then(|()| header.check(self.strictness).map_err(Into::into));
+        let result = match admitted {
+            Err(e) => discard(&mut self.unread, &mut self.reader, head.len(), size).and(Err(e)),
+            Ok(()) => {
+                stream_payload(&mut *self.server,
+                               &mut self.unread,
+                               &mut self.reader,
+                               &header,
+                               head.len(),
+                               size,
+                               self.streaming_chunk)
+            }
+        };
+        match result {
+            Err(e @ Error::Truncated { .. }) => self.cut_short(e).map(Err),
+            Err(e @ Error::Read(_)) => Some(Err(e)),
+            result => {
+                self.frame_read(size);
+                self.dispatched(&result);
+                if result.is_ok() {
+                    self.timestamp = Some(header.timestamp);
+                }
+                Some(result.map(|()| Accepted::streamed(&header, size - head.len())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::iter;
+    use std::time::Duration;
+
+    use message::Framing;
+    use server;
+    use session::{Accepted, Error, ZeroFrame};
+    use stream::StreamingStream;
+    use test_support::*;
+    use {test_support, Session, Stream};
+
+    /// Records every write as a separate chunk.
+    #[derive(Debug, Default)]
+    struct ChunkSink {
+        ts: Duration,
+        chunks: Vec<Vec<u8>>,
+    }
+
+    impl Write for ChunkSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.chunks.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Chunked {
+        pushed: Vec<Vec<u8>>,
+        committed: Vec<(Duration, Vec<Vec<u8>>)>,
+        aborted: usize,
+    }
+
+    impl Stream for Chunked {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+            self.pushed.push(payload.to_vec());
+            Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(())
+        }
+    }
+
+    impl StreamingStream for Chunked {
+        type Writer = ChunkSink;
+        fn begin(&mut self, ts: Duration, _: u64) -> Result<Self::Writer, Self::PushErr> {
+            Ok(ChunkSink { ts: ts, chunks: vec![] })
+        }
+
+        fn commit(&mut self, writer: Self::Writer) -> Result<(), Self::PushErr> {
+            self.committed.push((writer.ts, writer.chunks));
+            Ok(())
+        }
+
+        fn abort(&mut self, _: Self::Writer) {
+            self.aborted += 1;
+        }
+    }
+
+    fn server() -> test_support::server::Ok<Chunked> {
+        test_support::server::Ok(iter::once((b"id".to_vec(), Chunked::default())).collect())
+    }
+
+    #[test]
+    fn large_payload_in_chunks() {
+        let payload: Vec<u8> = (0..23).collect();
+        let mut input = frame(b"t", b"id", 7, b"small");
+        input.extend(frame(b"t", b"id", 7, &payload));
+        let mut server = server();
+        {
+            let mut session = Session::new(&mut server, &input as &[_]);
+            session.set_streaming(20, 5);
+            assert_match!(Some(Ok(ref accepted)) if accepted.id == b"id", session.next_streaming());
+            assert_eq!(Some(Accepted {
+                           id: b"id".to_vec(),
+                           timestamp: Duration::from_millis(7),
+                           payload_len: 23,
+                           ack: None,
+                       }),
+                       session.next_streaming().map(Result::unwrap));
+            assert_match!(None, session.next_streaming());
+        }
+        let stream = &server.0[&b"id"[..]];
+        assert_eq!(vec![b"small".to_vec()], stream.pushed);
+        assert_eq!(1, stream.committed.len());
+        let (ts, ref chunks) = stream.committed[0];
+        assert_eq!(Duration::from_millis(7), ts);
+        assert_eq!(vec![5, 5, 5, 5, 3], chunks.iter().map(Vec::len).collect::<Vec<_>>());
+        assert_eq!(payload, chunks.concat());
+    }
+
+    #[test]
+    fn streamed_header_offset_counts_the_framing_prefix() {
+        // A token size of 40 with 30 bytes of token.
+        let mut body = vec![0, 40];
+        body.extend_from_slice(&[b't'; 30]);
+        for &framing in &[Framing::U16, Framing::U32, Framing::Varint] {
+            let prefix_len = framing.prefix_len(body.len());
+            let mut input = vec![0; prefix_len];
+            framing.write_size(&mut input, body.len());
+            input.extend_from_slice(&body);
+            let mut server = server();
+            let mut session = Session::with_framing(&mut server, &input as &[_], framing);
+            session.set_streaming(20, 8);
+            match session.next_streaming() {
+                Some(Err(ref e @ Error::Parse { .. })) => {
+                    assert_eq!(Some(prefix_len + 2), e.frame_offset())
+                }
+                other => panic!("{:?} under {:?}", other, framing),
+            }
+        }
+    }
+
+    #[test]
+    fn zero_chunk_taken_as_one() {
+        let input = frame(b"t", b"id", 7, &[1; 21]);
+        let mut server = server();
+        {
+            let mut session = Session::new(&mut server, &input as &[_]);
+            session.set_streaming(20, 0);
+            assert_match!(Some(Ok(_)), session.next_streaming());
+        }
+        let (_, ref chunks) = server.0[&b"id"[..]].committed[0];
+        assert_eq!(vec![1; 21], chunks.iter().map(Vec::len).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn truncation_aborts() {
+        let mut input = frame(b"t", b"id", 7, &[1; 40]);
+        input.truncate(input.len() - 12);
+        let mut server = server();
+        {
+            let mut session = Session::new(&mut server, &input as &[_]);
+            session.set_streaming(20, 8);
+            assert_match!(Some(Err(Error::Truncated { found: 43, remaining: 12 })),
+                          session.next_streaming());
+        }
+        let stream = &server.0[&b"id"[..]];
+        assert_eq!(1, stream.aborted);
+        assert!(stream.committed.is_empty());
+    }
+
+    #[test]
+    fn unknown_id_skips_frame() {
+        let mut input = frame(b"t", b"id", 7, &[1; 40]);
+        input.extend(frame(b"t", b"id", 7, &[2; 30]));
+        let finder = iter::once((b"other".to_vec(), Chunked::default())).collect();
+        let mut server = test_support::server::Ok(finder);
+        let mut session = Session::new(&mut server, &input as &[_]);
+        session.set_streaming(20, 8);
+        assert_match!(Some(Err(Error::Consume(server::ConsumeError::MissingId))),
+                      session.next_streaming());
+        assert_match!(Some(Err(Error::Consume(server::ConsumeError::MissingId))),
+                      session.next_streaming());
+        assert_match!(None, session.next_streaming());
+    }
+
+    #[test]
+    fn skips_zero_frames() {
+        let mut input = vec![0, 0];
+        input.extend(frame(b"t", b"id", 7, &[1; 40]));
+        input.extend(&[0, 0, 0, 0]);
+        let mut server = server();
+        let mut session = Session::new(&mut server, &input as &[_]);
+        session.set_streaming(20, 8);
+        session.set_zero_frame(ZeroFrame::Heartbeat);
+        assert_match!(Some(Ok(ref accepted)) if accepted.id == b"id", session.next_streaming());
+        assert_match!(None, session.next_streaming());
+        assert_eq!(3, session.heartbeats());
+    }
+}
@@ -0,0 +1,412 @@
+//! Shaping how fast sessions read frame bodies, by token buckets that hold
+//! a byte per token. Length prefixes are never throttled, so a starved
+//! session still sees where its frames start.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use Clock;
+
+const NANOS_PER_SEC: u64 = 1000000000;
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * NANOS_PER_SEC + d.subsec_nanos() as u64
+}
+
+fn from_nanos(n: u64) -> Duration {
+    Duration::new(n / NANOS_PER_SEC, (n % NANOS_PER_SEC) as u32)
+}
+
+/// Tokens refill at `bytes_per_sec` up to `burst`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BucketSpec {
+    pub bytes_per_sec: u64,
+    pub burst: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    spec: BucketSpec,
+    tokens: u64,
+    /// When the tokens were last topped up; time since then short of a
+    /// whole token is carried over.
+    last: Duration,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Duration) {
+        if now <= self.last {
+            return;
+        }
+        let elapsed = nanos(now - self.last);
+        let rate = self.spec.bytes_per_sec;
+        let room = self.spec.burst - self.tokens;
+        if elapsed >= room.saturating_mul(NANOS_PER_SEC) / rate {
+            self.tokens = self.spec.burst;
+            self.last = now;
+            return;
+        }
+        let added = elapsed * rate / NANOS_PER_SEC;
+        self.tokens += added;
+        self.last += from_nanos((added * NANOS_PER_SEC + rate - 1) / rate);
+    }
+
+    /// How long from `now` until there are `n` tokens, or the bucket is
+    /// full if it holds fewer.
+    fn wait_for(&self, n: u64, now: Duration) -> Duration {
+        let n = cmp::min(n, self.spec.burst);
+        if self.tokens >= n {
+            return Duration::from_millis(0);
+        }
+        let rate = self.spec.bytes_per_sec;
+        let needed = ((n - self.tokens) * NANOS_PER_SEC + rate - 1) / rate;
+        let ready = self.last + from_nanos(needed);
+        if ready > now {
+            ready - now
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+}
+
+/// A token bucket, full to start with. Its clones share its tokens, so one
+/// can limit every session it is given to together.
+#[derive(Clone)]
+pub struct Throttle {
+    bucket: Arc<Mutex<Bucket>>,
+    clock: Arc<Clock + Send + Sync>,
+}
+
+impl Throttle {
+    /// Panics if the rate or the burst is zero.
+    pub fn new<C: Clock + Send + Sync + 'static>(spec: BucketSpec, clock: C) -> Self {
+        assert!(spec.bytes_per_sec > 0, "bucket must refill");
+        assert!(spec.burst > 0, "bucket must hold a token");
+        let now = clock.now();
+        Throttle {
+            bucket: Arc::new(Mutex::new(Bucket {
+                spec: spec,
+                tokens: spec.burst,
+                last: now,
+            })),
+            clock: Arc::new(clock),
+        }
+    }
+
+    pub fn spec(&self) -> BucketSpec {
+        self.bucket.lock().unwrap().spec
+    }
+
+    /// The tokens there are now.
+    pub fn available(&self) -> u64 {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(self.clock.now());
+        bucket.tokens
+    }
+
+    fn wait_for(&self, n: u64) -> Duration {
+        let now = self.clock.now();
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(now);
+        bucket.wait_for(n, now)
+    }
+
+    /// Takes up to `n` tokens; another session may have taken some since
+    /// they were counted.
+    fn take(&self, n: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = bucket.tokens.saturating_sub(n);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThrottleStats {
+    /// Bytes of frame bodies read, a token each.
+    pub tokens_consumed: u64,
+    /// Time spent asleep waiting for tokens.
+    pub throttled_wait: Duration,
+    /// Times `try_next` found no tokens and returned `NotReady`.
+    pub not_ready: u64,
+}
+
+/// The throttles a session reads under: a global one shared with other
+/// sessions, its own, or both, in which case the tighter wins.
+pub struct Throttling {
+    throttles: Vec<Throttle>,
+    max_sleep: Duration,
+    sleep: Box<FnMut(Duration) + Send>,
+    stats: ThrottleStats,
+}
+
+impl Throttling {
+    pub fn new(global: Option<Throttle>, per_session: Option<Throttle>) -> Self {
+        Throttling {
+            throttles: global.into_iter().chain(per_session).collect(),
+            max_sleep: Duration::from_millis(100),
+            sleep: Box::new(thread::sleep),
+            stats: ThrottleStats::default(),
+        }
+    }
+
+    /// The longest a session sleeps at a time for want of tokens, after
+    /// which it reads whatever there is. Defaults to 100ms.
+    pub fn set_max_sleep(&mut self, max_sleep: Duration) {
+        self.max_sleep = max_sleep;
+    }
+
+    /// How to sleep, for tests with a fake clock; `thread::sleep` by
+    /// default.
+    pub fn set_sleeper<F: FnMut(Duration) + Send + 'static>(&mut self, sleep: F) {
+        self.sleep = Box::new(sleep);
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        self.stats
+    }
+
+    fn available(&self) -> u64 {
+        self.throttles.iter().map(Throttle::available).min().unwrap_or(u64::max_value())
+    }
+
+    /// How many of `wanted` bytes may be read now, without sleeping.
+    pub fn try_allow(&mut self, wanted: usize) -> usize {
+        let allowed = cmp::min(self.available(), wanted as u64) as usize;
+        if allowed == 0 && wanted > 0 {
+            self.stats.not_ready += 1;
+        }
+        allowed
+    }
+
+    /// How many of `wanted` bytes may be read, sleeping for tokens a
+    /// `max_sleep` at a time until there is at least one.
+    pub fn allow(&mut self, wanted: usize) -> usize {
+        loop {
+            let available = self.available();
+            if available > 0 || wanted == 0 {
+                return cmp::min(available, wanted as u64) as usize;
+            }
+            let wait = self.throttles
+                           .iter()
+                           .map(|throttle| throttle.wait_for(wanted as u64))
+                           .max()
+                           .unwrap_or(Duration::from_millis(0));
+            let wait = cmp::min(wait, self.max_sleep);
+            (self.sleep)(wait);
+            self.stats.throttled_wait += wait;
+        }
+    }
+
+    /// Accounts for `n` bytes read.
+    pub fn consumed(&mut self, n: usize) {
+        for throttle in &self.throttles {
+            throttle.take(n as u64);
+        }
+        self.stats.tokens_consumed += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::Finder;
+    use session::{Session, TryNext};
+    use test_support;
+    use test_support::frame;
+    use {util, Clock};
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// Records the time of every read and how much it read.
+    struct Timed<R> {
+        inner: R,
+        clock: SharedClock,
+        reads: Arc<Mutex<Vec<(Duration, usize)>>>,
+    }
+
+    impl<R: Read> Read for Timed<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = try!(self.inner.read(buf));
+            self.reads.lock().unwrap().push((self.clock.now(), n));
+            Ok(n)
+        }
+    }
+
+    fn server() -> test_support::server::Ok<test_support::stream::Ok> {
+        let mut finder = Finder::new();
+        finder.insert(b"id".to_vec(), test_support::stream::Ok);
+        test_support::server::Ok(finder)
+    }
+
+    /// A frame whose body is `len` bytes.
+    fn body_of(len: usize) -> Vec<u8> {
+        frame(b"t", b"id", 1000, &vec![7; len - 15])
+    }
+
+    fn spec(bytes_per_sec: u64, burst: u64) -> BucketSpec {
+        BucketSpec {
+            bytes_per_sec: bytes_per_sec,
+            burst: burst,
+        }
+    }
+
+    /// Reads `bytes` through a session throttled as given, returning each
+    /// read after the first frame's prefix as (millis since start, size),
+    /// every sleep and the stats.
+    fn run(bytes: Vec<u8>,
+           clock: &SharedClock,
+           mut throttling: Throttling)
+           -> (Vec<(u64, usize)>, Vec<Duration>, ThrottleStats) {
+        let start = clock.now();
+        let sleeps = Arc::new(Mutex::new(vec![]));
+        let (sleeper_clock, slept) = (clock.clone(), sleeps.clone());
+        throttling.set_sleeper(move |d| {
+            slept.lock().unwrap().push(d);
+            sleeper_clock.advance(d);
+        });
+        let reads = Arc::new(Mutex::new(vec![]));
+        let reader = Timed {
+            inner: Cursor::new(bytes),
+            clock: clock.clone(),
+            reads: reads.clone(),
+        };
+        let mut server = server();
+        let mut session = Session::new(&mut server, reader);
+        session.set_throttling(throttling);
+        for result in session.by_ref() {
+            result.unwrap();
+        }
+        let stats = session.throttle_stats();
+        let reads = reads.lock()
+                         .unwrap()
+                         .iter()
+                         .skip(1)
+                         .filter(|&&(_, n)| n > 0)
+                         .map(|&(at, n)| (util::millis(at - start), n))
+                         .collect();
+        let sleeps = sleeps.lock().unwrap().clone();
+        (reads, sleeps, stats)
+    }
+
+    #[test]
+    fn reads_at_the_rate() {
+        let clock = SharedClock::new(millis(5000));
+        let mut throttling = Throttling::new(Some(Throttle::new(spec(100, 50), clock.clone())),
+                                             None);
+        throttling.set_max_sleep(millis(1000));
+        let (reads, sleeps, stats) = run(body_of(150), &clock, throttling);
+        // The fresh bucket's burst is read at once.
+        assert_eq!(vec![(0, 50), (500, 50), (1000, 50)], reads);
+        assert_eq!(vec![millis(500), millis(500)], sleeps);
+        assert_eq!(ThrottleStats {
+                       tokens_consumed: 150,
+                       throttled_wait: millis(1000),
+                       not_ready: 0,
+                   },
+                   stats);
+    }
+
+    #[test]
+    fn sleeps_at_most_max_sleep() {
+        let clock = SharedClock::new(millis(0));
+        let mut throttling = Throttling::new(Some(Throttle::new(spec(100, 50), clock.clone())),
+                                             None);
+        throttling.set_max_sleep(millis(200));
+        let (reads, sleeps, _) = run(body_of(150), &clock, throttling);
+        assert_eq!(vec![(0, 50), (200, 20), (400, 20), (600, 20), (800, 20), (1000, 20)],
+                   reads);
+        assert_eq!(vec![millis(200); 5], sleeps);
+    }
+
+    #[test]
+    fn burst_caps_idle_refill() {
+        let clock = SharedClock::new(millis(0));
+        let throttle = Throttle::new(spec(100, 50), clock.clone());
+        assert_eq!(50, throttle.available());
+        let mut throttling = Throttling::new(Some(throttle.clone()), None);
+        throttling.allow(50);
+        throttling.consumed(50);
+        assert_eq!(0, throttle.available());
+        clock.advance(millis(15));
+        assert_eq!(1, throttle.available());
+        clock.advance(millis(5));
+        assert_eq!(2, throttle.available());
+        clock.advance(millis(10000));
+        assert_eq!(50, throttle.available());
+    }
+
+    #[test]
+    fn tighter_limit_wins() {
+        let clock = SharedClock::new(millis(0));
+        let global = Throttle::new(spec(1000, 1000), clock.clone());
+        let own = Throttle::new(spec(10, 10), clock.clone());
+        let mut throttling = Throttling::new(Some(global.clone()), Some(own));
+        throttling.set_max_sleep(millis(5000));
+        let (reads, sleeps, _) = run(body_of(30), &clock, throttling);
+        assert_eq!(vec![(0, 10), (1000, 10), (2000, 10)], reads);
+        assert_eq!(vec![millis(1000), millis(1000)], sleeps);
+
+        let clock = SharedClock::new(millis(0));
+        let global = Throttle::new(spec(10, 10), clock.clone());
+        let own = Throttle::new(spec(1000, 1000), clock.clone());
+        let mut throttling = Throttling::new(Some(global.clone()), Some(own));
+        throttling.set_max_sleep(millis(5000));
+        let (reads, _, _) = run(body_of(30), &clock, throttling);
+        assert_eq!(vec![(0, 10), (1000, 10), (2000, 10)], reads);
+        assert_eq!(0, global.available());
+    }
+
+    #[test]
+    fn try_next_does_not_sleep() {
+        let clock = SharedClock::new(millis(0));
+        let throttle = Throttle::new(spec(10, 10), clock.clone());
+        let mut throttling = Throttling::new(None, Some(throttle));
+        throttling.set_sleeper(|_| panic!("try_next slept"));
+        let mut server = server();
+        let bytes = body_of(30);
+        let mut session = Session::new(&mut server, Cursor::new(bytes));
+        session.set_throttling(throttling);
+        let mut polls = 0;
+        loop {
+            match session.try_next() {
+                TryNext::NotReady => {
+                    polls += 1;
+                    clock.advance(millis(1000));
+                }
+                TryNext::Ready(result) => {
+                    result.unwrap();
+                    break;
+                }
+                TryNext::Closed => panic!("closed before the frame"),
+            }
+        }
+        assert_eq!(2, polls);
+        assert_match!(TryNext::Closed, session.try_next());
+        let stats = session.throttle_stats();
+        assert_eq!(30, stats.tokens_consumed);
+        assert_eq!(2, stats.not_ready);
+        assert_eq!(Duration::from_millis(0), stats.throttled_wait);
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket must refill")]
+    fn zero_rate() {
+        Throttle::new(spec(0, 10), SharedClock::new(millis(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket must hold a token")]
+    fn zero_burst() {
+        Throttle::new(spec(10, 0), SharedClock::new(millis(0)));
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::prelude::*;
+use std::time::Duration;
+
+use {Clock, Server};
+use super::{NextResult, Session};
+
+/// The most bucket boundaries a `LatencyHistogram` takes.
+pub const MAX_BOUNDS: usize = 15;
+
+/// Counts of latencies by bucket, kept without allocating. Bucket `i`
+/// holds latencies under boundary `i` and not under the one before; the
+/// last bucket holds the rest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    bounds: [Duration; MAX_BOUNDS],
+    len: usize,
+    counts: [u64; MAX_BOUNDS + 1],
+}
+
+impl LatencyHistogram {
+    /// Panics unless `bounds` ascend and number at most `MAX_BOUNDS`.
+    pub fn new(bounds: &[Duration]) -> Self {
+        assert!(bounds.len() <= MAX_BOUNDS,
+                "at most {} bucket boundaries",
+                MAX_BOUNDS);
+        assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]),
+                "bucket boundaries must ascend");
+        let mut histogram = LatencyHistogram {
+            bounds: [Duration::from_millis(0); MAX_BOUNDS],
+            len: bounds.len(),
+            counts: [0; MAX_BOUNDS + 1],
+        };
+        histogram.bounds[..bounds.len()].copy_from_slice(bounds);
+        histogram
+    }
+
+    pub fn bounds(&self) -> &[Duration] {
+        &self.bounds[..self.len]
+    }
+
+    /// One more count than there are boundaries.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts[..self.len + 1]
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = self.bounds().iter().position(|&bound| latency < bound).unwrap_or(self.len);
+        self.counts[bucket] += 1;
+    }
+}
+
+/// An item of a `TimedSession`. `latency` is only for consumed messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timed<T> {
+    pub item: T,
+    pub latency: Option<Duration>,
+}
+
+/// How long after its header timestamp a message arrived. A timestamp from
+/// the future counts as no latency at all, unless it is more than
+/// `plausible` away from `arrival` either way, in which case there is no
+/// telling.
+pub fn latency(arrival: Duration, timestamp: Duration, plausible: Duration) -> Option<Duration> {
+    match arrival.checked_sub(timestamp) {
+        Some(latency) if latency <= plausible => Some(latency),
+        Some(_) => None,
+        None if timestamp - arrival <= plausible => Some(Duration::from_millis(0)),
+        None => None,
+    }
+}
+
+/// A session whose consumed messages are timed against a clock as they
+/// arrive.
+pub struct TimedSession<'a, C, S: 'a, R> {
+    session: Session<'a, S, R>,
+    clock: C,
+    plausible: Duration,
+    histogram: LatencyHistogram,
+}
+
+impl<'a, S: 'a, R> Session<'a, S, R> {
+    /// Times consumed messages by `clock`, counting plausible latencies, as
+    /// `latency` says, in `histogram`.
+    pub fn timed<C: Clock>(self,
+                           clock: C,
+                           plausible: Duration,
+                           histogram: LatencyHistogram)
+                           -> TimedSession<'a, C, S, R> {
+        TimedSession {
+            session: self,
+            clock: clock,
+            plausible: plausible,
+            histogram: histogram,
+        }
+    }
+}
+
+impl<'a, C, S: 'a, R> TimedSession<'a, C, S, R> {
+    pub fn latency_histogram(&self) -> &LatencyHistogram {
+        &self.histogram
+    }
+
+    pub fn get_ref(&self) -> &Session<'a, S, R> {
+        &self.session
+    }
+
+    pub fn get_mut(&mut self) -> &mut Session<'a, S, R> {
+        &mut self.session
+    }
+
+    pub fn into_inner(self) -> Session<'a, S, R> {
+        self.session
+    }
+}
+
+impl<'a, C: Clock, S: 'a + Server, R: Read> Iterator for TimedSession<'a, C, S, R> {
+    type Item = Timed<NextResult<S>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.session.next().map(|item| {
+            let latency = match (&item, self.session.last_timestamp()) {
+                (&Ok(_), Some(timestamp)) => latency(self.clock.now(), timestamp, self.plausible),
+                _ => None,
+            };
+            if let Some(latency) = latency {
+                self.histogram.record(latency);
+            }
+            Timed {
+                item: item,
+                latency: latency,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::iter;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use test_support::frame;
+    use {test_support, Session};
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn latencies() {
+        let cap = ms(1000);
+        assert_eq!(Some(ms(250)), latency(ms(5000), ms(4750), cap));
+        assert_eq!(Some(ms(0)), latency(ms(5000), ms(5400), cap));
+        assert_eq!(Some(ms(1000)), latency(ms(5000), ms(4000), cap));
+        assert_eq!(None, latency(ms(5000), ms(3999), cap));
+        assert_eq!(None, latency(ms(5000), ms(6001), cap));
+    }
+
+    /// Advances the clock by the scripted delay before each frame is read.
+    struct Delayed<'c> {
+        clock: &'c ManualClock,
+        frames: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl<'c> Read for Delayed<'c> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.frames.is_empty() {
+                return Ok(0);
+            }
+            let (delay, frame) = self.frames.remove(0);
+            self.clock.advance(ms(delay));
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(frame.len())
+        }
+    }
+
+    #[test]
+    fn scripted_histogram() {
+        let clock = ManualClock::new(ms(10000));
+        // (delay before arrival, timestamp): arrivals at 10000, 10100, ...
+        let script = [(0, 9990), (100, 10090), (100, 9700), (100, 5000), (100, 10500),
+                      (100, 7000), (100, 10600)];
+        let mut frames = vec![];
+        for (i, &(delay, millis)) in script.iter().enumerate() {
+            if i == 3 {
+                // Arriving alongside the one before, for a stream not there.
+                let bytes = frame(b"t", b"unknown", 10200, b"");
+                frames.push((0, bytes[..2].to_vec()));
+                frames.push((0, bytes[2..].to_vec()));
+            }
+            let bytes = frame(b"t", b"id", millis, b"");
+            frames.push((delay, bytes[..2].to_vec()));
+            frames.push((0, bytes[2..].to_vec()));
+        }
+
+        let finder = iter::once((b"id".to_vec(), test_support::stream::Ok)).collect();
+        let mut server = test_support::server::Ok(finder);
+        let reader = Delayed {
+            clock: &clock,
+            frames: frames,
+        };
+        let mut session = Session::new(&mut server, reader)
+                              .timed(&clock,
+                                     ms(2000),
+                                     LatencyHistogram::new(&[ms(50), ms(500), ms(1000)]));
+        let latencies: Vec<_> = session.by_ref()
+                                       .map(|timed| (timed.item.is_ok(), timed.latency))
+                                       .collect();
+        assert_eq!(vec![(true, Some(ms(10))),
+                        (true, Some(ms(10))),
+                        (true, Some(ms(500))),
+                        (false, None),
+                        (true, None),
+                        (true, Some(ms(0))),
+                        (true, None),
+                        (true, Some(ms(0)))],
+                   latencies);
+        assert_eq!(&[4, 0, 1, 0][..], session.latency_histogram().counts());
+    }
+}
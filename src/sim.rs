@@ -0,0 +1,553 @@
+//! Runs a population of devices against a server stack in process, on a
+//! clock that only the simulation moves, for deciding what one instance
+//! can take before standing up real devices. Every frame goes through the
+//! real encoding and a real `Session`, one per device for the whole run as
+//! a connection would be; only the network is left out.
+//!
+//! The same seed and configuration always make the same report.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+use std::rc::Rc;
+use std::time::Duration;
+
+use clock::SharedClock;
+use message::Header;
+use server::{AuthError, AuthResult, ConsumeError, Consumed, DryRunOutcome, TokenServer};
+use session::{Error, LatencyHistogram, TryNext};
+use stream::Pressure;
+use trace::Spans;
+use {util, Clock, Message, Server, Session, Stream};
+
+/// A deterministic xorshift generator; not for anything but simulations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero would stay zero.
+        Rng { state: seed ^ 0x9e37_79b9_7f4a_7c15 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `low..=high`.
+    pub fn between(&mut self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        match (high - low).checked_add(1) {
+            Some(span) => low + self.next_u64() % span,
+            None => self.next_u64(),
+        }
+    }
+
+    /// True `per_mille` times in a thousand.
+    pub fn chance(&mut self, per_mille: u32) -> bool {
+        per_mille > 0 && self.between(0, 999) < per_mille as u64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    Fixed(u64),
+    /// Inclusive at both ends.
+    Uniform {
+        low: u64,
+        high: u64,
+    },
+}
+
+impl Distribution {
+    pub fn sample(&self, rng: &mut Rng) -> u64 {
+        match *self {
+            Distribution::Fixed(n) => n,
+            Distribution::Uniform { low, high } => rng.between(low, high),
+        }
+    }
+}
+
+/// How often, in a thousand messages, a device misbehaves each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Misbehavior {
+    /// The message is sent twice.
+    pub duplicate: u32,
+    /// The message is stamped a millisecond before the device's last.
+    pub out_of_order: u32,
+    /// The frame's token size runs past the frame, so it will not parse.
+    pub malformed: u32,
+}
+
+/// The devices. Device `i` sends for the ID `id(i)` under the token
+/// `token(i)`; register their streams with `register`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Population {
+    pub devices: usize,
+    /// Devices share tokens round robin.
+    pub tokens: usize,
+    /// Milliseconds from one message of a device to its next.
+    pub interval: Distribution,
+    pub payload_len: Distribution,
+    pub misbehavior: Misbehavior,
+}
+
+impl Population {
+    pub fn token(&self, device: usize) -> Vec<u8> {
+        format!("token-{}", device % cmp::max(self.tokens, 1)).into_bytes()
+    }
+
+    pub fn id(&self, device: usize) -> Vec<u8> {
+        format!("device-{}", device).into_bytes()
+    }
+
+    /// Registers every device's stream, made by `make` from its ID.
+    pub fn register<T, F>(&self, server: &mut TokenServer<T>, mut make: F)
+        where T: Stream,
+              F: FnMut(&[u8]) -> T
+    {
+        for device in 0..self.devices {
+            let id = self.id(device);
+            let stream = make(&id);
+            server.add_token(&self.token(device)).insert(id, stream);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub population: Population,
+    /// How long to simulate.
+    pub duration: Duration,
+    /// How often each device's connection is read: every message sent in
+    /// a tick arrives at its end.
+    pub tick: Duration,
+    pub latency_bounds: Vec<Duration>,
+    /// How many of the IDs with the most messages consumed to report.
+    pub top_n: usize,
+}
+
+/// Why frames did not come to a consumed message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rejections {
+    pub invalid_token: u64,
+    pub missing_id: u64,
+    /// By each wrapper's reason.
+    pub rejected: BTreeMap<&'static str, u64>,
+    pub stream_cap: u64,
+    pub push: u64,
+    /// Frames that would not parse.
+    pub malformed: u64,
+    pub other: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationReport {
+    pub elapsed: Duration,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub consumed: u64,
+    pub rejections: Rejections,
+    /// Of consumed messages, from their timestamp to the end of their tick.
+    pub latency: LatencyHistogram,
+    /// The most bytes one connection delivered in one tick, which its
+    /// session reads through, and the most all of them did.
+    pub peak_connection_bytes: u64,
+    pub peak_tick_bytes: u64,
+    /// By messages consumed, most first, then by ID.
+    pub top_ids: Vec<(Vec<u8>, u64)>,
+}
+
+impl SimulationReport {
+    /// Messages consumed per simulated second.
+    pub fn throughput(&self) -> u64 {
+        match util::millis(self.elapsed) {
+            0 => 0,
+            millis => self.consumed * 1000 / millis,
+        }
+    }
+}
+
+struct Device {
+    next_at: Duration,
+    last: Option<Duration>,
+}
+
+/// What a device has sent over its connection that its session has yet to
+/// read; reading it when empty would block.
+#[derive(Clone, Default)]
+struct Feed(Rc<RefCell<Vec<u8>>>);
+
+impl Read for Feed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut sent = self.0.borrow_mut();
+        if sent.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing sent"));
+        }
+        let n = cmp::min(buf.len(), sent.len());
+        for (to, from) in buf.iter_mut().zip(sent.drain(..n)) {
+            *to = from;
+        }
+        Ok(n)
+    }
+}
+
+/// The stack, lent to one device's session at a time, so that every
+/// device keeps its own session with the one stack.
+struct Lent<'a, S: 'a>(Option<&'a mut S>);
+
+impl<'a, S> Lent<'a, S> {
+    fn get(&mut self) -> &mut S {
+        self.0.as_mut().map(|server| &mut **server).expect("lent to the session reading")
+    }
+}
+
+impl<'a, S: Server> Server for Lent<'a, S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.get().auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        self.get().consume_parts_traced(token, id, timestamp, payload, spans)
+    }
+
+    fn consume_parts_pressed(&mut self,
+                             token: &[u8],
+                             id: &[u8],
+                             timestamp: Duration,
+                             payload: &[u8],
+                             spans: &mut Spans)
+                             -> (Consumed<Self>, Pressure) {
+        self.get().consume_parts_pressed(token, id, timestamp, payload, spans)
+    }
+
+    fn resolve(&mut self,
+               token: &[u8],
+               id: &[u8])
+               -> Result<&mut Self::Stream,
+                         ConsumeError<Self::AuthErr, <Self::Stream as Stream>::PushErr>> {
+        self.get().resolve(token, id)
+    }
+
+    fn pressure(&mut self, token: &[u8], id: &[u8]) -> Pressure {
+        self.get().pressure(token, id)
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.get().dry_run_parts(token, id, timestamp, payload)
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        self.get().backfill_parts(token, id, timestamp, payload)
+    }
+}
+
+/// A population and the stack it sends to. `clock` is the one whatever in
+/// the stack is timed reads; the simulation moves it to the end of each
+/// tick before its frames are read.
+pub struct Simulation<S> {
+    config: SimConfig,
+    server: S,
+    clock: SharedClock,
+}
+
+impl<S: Server> Simulation<S> {
+    pub fn new(config: SimConfig, server: S, clock: SharedClock) -> Self {
+        Simulation {
+            config: config,
+            server: server,
+            clock: clock,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+
+    /// Runs for the configured duration from the clock's present.
+    pub fn run(&mut self) -> SimulationReport {
+        let mut rng = Rng::new(self.config.seed);
+        let population = self.config.population.clone();
+        let start = self.clock.now();
+        let mut devices = Vec::with_capacity(population.devices);
+        for _ in 0..population.devices {
+            devices.push(Device {
+                next_at: start + millis(population.interval.sample(&mut rng)),
+                last: None,
+            });
+        }
+        let mut report = SimulationReport {
+            elapsed: self.config.duration,
+            frames_sent: 0,
+            bytes_sent: 0,
+            consumed: 0,
+            rejections: Rejections::default(),
+            latency: LatencyHistogram::new(&self.config.latency_bounds),
+            peak_connection_bytes: 0,
+            peak_tick_bytes: 0,
+            top_ids: vec![],
+        };
+        let mut hits = BTreeMap::new();
+        let feeds: Vec<_> = (0..population.devices).map(|_| Feed::default()).collect();
+        let mut slots: Vec<_> = feeds.iter().map(|_| Lent(None)).collect();
+        let mut sessions: Vec<_> = slots.iter_mut()
+                                        .zip(&feeds)
+                                        .map(|(slot, feed)| Session::new(slot, feed.clone()))
+                                        .collect();
+        let mut server = Some(&mut self.server);
+        let end = start + self.config.duration;
+        let mut tick_start = start;
+        while tick_start < end {
+            let tick_end = cmp::min(tick_start + self.config.tick, end);
+            self.clock.set(tick_end);
+            let mut tick_bytes = 0;
+            for (i, device) in devices.iter_mut().enumerate() {
+                let (token, id) = (population.token(i), population.id(i));
+                let bytes = population.send(device, &token, &id, tick_end, &mut rng, &mut report);
+                tick_bytes += bytes.len() as u64;
+                report.peak_connection_bytes = cmp::max(report.peak_connection_bytes,
+                                                        bytes.len() as u64);
+                if !bytes.is_empty() {
+                    feeds[i].0.borrow_mut().extend(bytes);
+                    let session = &mut sessions[i];
+                    session.server_mut().0 = server.take();
+                    read(session, tick_end, &mut report, &mut hits);
+                    server = session.server_mut().0.take();
+                }
+            }
+            report.bytes_sent += tick_bytes;
+            report.peak_tick_bytes = cmp::max(report.peak_tick_bytes, tick_bytes);
+            tick_start = tick_end;
+        }
+        let mut top: Vec<_> = hits.into_iter().collect();
+        top.sort_by(|a, b| {
+            match b.1.cmp(&a.1) {
+                Ordering::Equal => a.0.cmp(&b.0),
+                ordering => ordering,
+            }
+        });
+        top.truncate(self.config.top_n);
+        report.top_ids = top;
+        report
+    }
+}
+
+/// Reads what a connection's session has been sent in a tick.
+fn read<S: Server>(session: &mut Session<Lent<S>, Feed>,
+                   now: Duration,
+                   report: &mut SimulationReport,
+                   hits: &mut BTreeMap<Vec<u8>, u64>) {
+    loop {
+        match session.try_next() {
+            TryNext::Ready(Ok(accepted)) => {
+                report.consumed += 1;
+                *hits.entry(accepted.id).or_insert(0) += 1;
+                if let Some(timestamp) = session.last_timestamp() {
+                    report.latency.record(now - cmp::min(now, timestamp));
+                }
+            }
+            TryNext::Ready(Err(e)) => rejected(&mut report.rejections, e),
+            TryNext::NotReady | TryNext::Closed => return,
+        }
+    }
+}
+
+impl Population {
+    /// The frames `device` sends before `until`, encoded.
+    fn send(&self,
+            device: &mut Device,
+            token: &[u8],
+            id: &[u8],
+            until: Duration,
+            rng: &mut Rng,
+            report: &mut SimulationReport)
+            -> Vec<u8> {
+        let mut bytes = vec![];
+        while device.next_at < until {
+            let mut timestamp = device.next_at;
+            if let Some(last) = device.last {
+                if rng.chance(self.misbehavior.out_of_order) && last > millis(0) {
+                    timestamp = last - millis(1);
+                }
+            }
+            let payload: Vec<u8> = (0..self.payload_len.sample(rng))
+                                       .map(|_| rng.next_u64() as u8)
+                                       .collect();
+            let mut frame = encode(token, id, timestamp, &payload);
+            if rng.chance(self.misbehavior.malformed) {
+                // The token size, claiming more than the frame holds.
+                frame[2] = 0xff;
+                frame[3] = 0xff;
+            }
+            let copies = if rng.chance(self.misbehavior.duplicate) { 2 } else { 1 };
+            for _ in 0..copies {
+                bytes.extend_from_slice(&frame);
+                report.frames_sent += 1;
+            }
+            device.last = Some(cmp::max(timestamp, device.last.unwrap_or(timestamp)));
+            device.next_at = device.next_at + millis(cmp::max(self.interval.sample(rng), 1));
+        }
+        bytes
+    }
+}
+
+fn millis(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+fn encode(token: &[u8], id: &[u8], timestamp: Duration, payload: &[u8]) -> Vec<u8> {
+    let msg = Message {
+        header: Header {
+            token: token,
+            id: id,
+            timestamp: timestamp,
+        },
+        payload: payload,
+    };
+    msg.to_frame().expect("frames a simulation makes fit")
+}
+
+fn rejected<A, P>(rejections: &mut Rejections, e: Error<A, P>) {
+    match e {
+        Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)) => {
+            rejections.invalid_token += 1
+        }
+        Error::Consume(ConsumeError::MissingId) => rejections.missing_id += 1,
+        Error::Consume(ConsumeError::Rejected(reason)) => {
+            *rejections.rejected.entry(reason).or_insert(0) += 1
+        }
+        Error::Consume(ConsumeError::StreamCapExceeded { .. }) => rejections.stream_cap += 1,
+        Error::Consume(ConsumeError::ExpiredPayload { .. }) => {
+            *rejections.rejected.entry("expired").or_insert(0) += 1
+        }
+        Error::Consume(ConsumeError::Push(_)) => rejections.push += 1,
+        Error::Parse { .. } | Error::Nonconforming(_) => rejections.malformed += 1,
+        _ => rejections.other += 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::{Dedup, HighWaterMark, TokenServer};
+    use stream::FileStream;
+
+    type Stack = Dedup<HighWaterMark<TokenServer<FileStream<Vec<u8>>>>>;
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn simulate(config: SimConfig) -> SimulationReport {
+        let mut tokens = TokenServer::new();
+        config.population.register(&mut tokens, |_| FileStream::new(vec![]));
+        let clock = SharedClock::new(millis(0));
+        let server = Dedup::new(HighWaterMark::new(tokens), 16);
+        let mut sim: Simulation<Stack> = Simulation::new(config, server, clock);
+        sim.run()
+    }
+
+    #[test]
+    fn same_seed_same_report() {
+        let config = SimConfig {
+            seed: 7,
+            population: Population {
+                devices: 50,
+                tokens: 5,
+                interval: Distribution::Uniform { low: 200, high: 800 },
+                payload_len: Distribution::Uniform { low: 0, high: 200 },
+                misbehavior: Misbehavior {
+                    duplicate: 50,
+                    out_of_order: 50,
+                    malformed: 20,
+                },
+            },
+            duration: Duration::from_secs(5),
+            tick: millis(250),
+            latency_bounds: vec![millis(50), millis(100), millis(200)],
+            top_n: 5,
+        };
+        let report = simulate(config.clone());
+        assert_eq!(report, simulate(config.clone()));
+        assert!(report.consumed > 0);
+        assert!(report.rejections.malformed > 0);
+        assert!(report.rejections.rejected["duplicate"] > 0);
+        assert!(report.rejections.rejected["stale timestamp"] > 0);
+        assert_eq!(5, report.top_ids.len());
+        assert!(report != simulate(SimConfig { seed: 8, ..config }));
+    }
+
+    #[test]
+    fn tiny_population_by_hand() {
+        let report = simulate(SimConfig {
+            seed: 1,
+            population: Population {
+                devices: 2,
+                tokens: 2,
+                interval: Distribution::Fixed(100),
+                payload_len: Distribution::Fixed(3),
+                misbehavior: Misbehavior { duplicate: 1000, ..Misbehavior::default() },
+            },
+            duration: Duration::from_secs(1),
+            tick: Duration::from_secs(1),
+            latency_bounds: vec![millis(500)],
+            top_n: 10,
+        });
+        // Each device sends at 100, 200, ..., 900 ms, every frame twice; a
+        // frame is 2 + 2 + 7 + 2 + 8 + 8 + 3 = 32 bytes.
+        assert_eq!(36, report.frames_sent);
+        assert_eq!(18, report.consumed);
+        assert_eq!(18, report.throughput());
+        assert_eq!(vec![("duplicate", 18)],
+                   report.rejections.rejected.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>());
+        assert_eq!(0, report.rejections.malformed + report.rejections.invalid_token);
+        assert_eq!(36 * 32, report.bytes_sent);
+        assert_eq!(18 * 32, report.peak_connection_bytes);
+        assert_eq!(36 * 32, report.peak_tick_bytes);
+        // Latencies of 100 to 400 ms, then 500 to 900.
+        assert_eq!(&[8, 10], report.latency.counts());
+        assert_eq!(vec![(b"device-0".to_vec(), 9), (b"device-1".to_vec(), 9)],
+                   report.top_ids);
+    }
+}
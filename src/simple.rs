@@ -0,0 +1,468 @@
+//! One call for the common case: listen on an address, let each of a set
+//! of tokens store under its own ID prefixes, write each ID's records to a
+//! file under a directory, and hand every error to a callback.
+//!
+//! The `ServerAdmin` of the `Handle` registers and removes tokens and IDs
+//! while it serves; a token it registers that the config gives no prefixes
+//! stores under whatever IDs its policy allows. Only a policy's tokens and
+//! IDs apply here, not its caps, permissions or retention.
+//!
+//! `run` only assembles this crate's parts: an `AffinityPool` of servers
+//! that provision a `FileStream` from a `FileStreamFactory` for each new
+//! ID, fed by a thread per connection. To change a default it picks, put
+//! those parts together yourself, starting from the constants here.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use config::ServerConfig;
+use metrics::{MetricsRegistry, Render};
+use pool::{AffinityPool, Connection, Handled};
+use server::{AuthError, AuthResult, ConsumeError, ConsumeResult, Consumed, Finder, Policy,
+             ServerAdmin, Snapshot, TokenPolicy};
+use stream::{FileStream, FileStreamFactory, StorageLayout};
+use trace::Spans;
+use {Server, Stream};
+
+/// Worker threads, each storing the IDs that hash to it.
+pub const DEFAULT_WORKERS: usize = 4;
+/// Frames queued for each worker before connections sending to it wait.
+pub const DEFAULT_QUEUE_LEN: usize = 64;
+pub const DEFAULT_LAYOUT: StorageLayout = StorageLayout::Fanout {
+    levels: 2,
+    width: 2,
+};
+/// How many seconds a connection may send nothing before it is closed.
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// How often the accepting thread looks for a connection when there was
+/// none, and so how long it may take to see that it is to stop.
+const ACCEPT_POLL_MILLIS: u64 = 10;
+
+pub struct SimpleConfig {
+    pub listen: SocketAddr,
+    /// The ID prefixes each token may store under. An empty prefix allows
+    /// any ID; a token with no prefixes may store nothing.
+    pub tokens: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    /// Created if need be.
+    pub data_dir: PathBuf,
+    /// The longest message, without its length prefix, to take. A longer
+    /// one closes its connection. Without it, any frame is taken.
+    pub max_frame_size: Option<usize>,
+    /// Called, from whichever thread saw it, with each message that could
+    /// not be stored, its error prefixed with the address of the peer that
+    /// sent it, and each connection that ended in error.
+    pub on_error: Box<FnMut(io::Error) + Send>,
+}
+
+#[derive(Debug)]
+pub enum SimpleError {
+    DataDir(io::Error),
+    Bind(io::Error),
+}
+
+impl Display for SimpleError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SimpleError::DataDir(ref e) => write!(f, "cannot create data directory: {}", e),
+            SimpleError::Bind(ref e) => write!(f, "cannot listen: {}", e),
+        }
+    }
+}
+
+impl error::Error for SimpleError {
+    fn description(&self) -> &str {
+        match *self {
+            SimpleError::DataDir(_) => "cannot create data directory",
+            SimpleError::Bind(_) => "cannot listen",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SimpleError::DataDir(ref e) | SimpleError::Bind(ref e) => Some(e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub accepted: u64,
+    /// Connections still open.
+    pub active: u64,
+    pub stored: u64,
+    /// Messages refused or whose write failed.
+    pub failed: u64,
+    /// Frames without a whole header.
+    pub malformed: u64,
+    /// Frames over the maximum size, each of which closed its connection.
+    pub oversized: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// As of when every frame read had been stored or failed.
+    pub stats: ServerStats,
+    /// Connections still open when the grace period ran out, and closed.
+    pub forced: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicUsize,
+    active: AtomicUsize,
+    stored: AtomicUsize,
+    failed: AtomicUsize,
+    malformed: AtomicUsize,
+    oversized: AtomicUsize,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ServerStats {
+        let get = |n: &AtomicUsize| n.load(Ordering::SeqCst) as u64;
+        ServerStats {
+            accepted: get(&self.accepted),
+            active: get(&self.active),
+            stored: get(&self.stored),
+            failed: get(&self.failed),
+            malformed: get(&self.malformed),
+            oversized: get(&self.oversized),
+        }
+    }
+}
+
+struct Shared {
+    stopping: AtomicBool,
+    counters: Counters,
+    on_error: Mutex<Box<FnMut(io::Error) + Send>>,
+    /// Clones of the open connections, by number, to close when the grace
+    /// period runs out.
+    open: Mutex<HashMap<usize, TcpStream>>,
+    /// The connections, by number, whose threads are done and not yet
+    /// joined.
+    finished: Mutex<Vec<usize>>,
+}
+
+impl Shared {
+    fn report(&self, e: io::Error) {
+        (&mut *self.on_error.lock().unwrap())(e);
+    }
+}
+
+/// A worker's server: the tokens' finders, provisioned from the factory
+/// for IDs under their prefixes and the admin's policy.
+struct Files {
+    prefixes: Arc<HashMap<Vec<u8>, Vec<Vec<u8>>>>,
+    finders: HashMap<Vec<u8>, Finder<FileStream<File>>>,
+    factory: FileStreamFactory,
+    shared: Arc<Shared>,
+    admin: ServerAdmin,
+    epoch: u64,
+    current: Arc<Snapshot>,
+}
+
+impl Files {
+    /// Takes up the newest snapshot if it is not already in force, closing
+    /// the files of the streams it no longer allows.
+    fn refresh(&mut self) {
+        let (epoch, current) = self.admin.load();
+        if epoch == self.epoch {
+            return;
+        }
+        self.epoch = epoch;
+        self.current = current;
+        let tokens = &self.current.policy.tokens;
+        self.finders.retain(|token, _| tokens.contains_key(token));
+        for (token, finder) in &mut self.finders {
+            if let Some(&TokenPolicy { ids: Some(ref ids), .. }) = tokens.get(token) {
+                finder.retain(|id, _| ids.contains(id));
+            }
+        }
+    }
+
+    fn store(&mut self,
+             token: &[u8],
+             id: &[u8],
+             timestamp: Duration,
+             payload: &[u8])
+             -> ConsumeResult<::Void, io::Error> {
+        self.refresh();
+        let ids = match self.current.policy.tokens.get(token) {
+            None => return Err(ConsumeError::Auth(AuthError::InvalidToken)),
+            Some(policy) => &policy.ids,
+        };
+        let allowed = self.prefixes
+                          .get(token)
+                          .map_or(true, |prefixes| prefixes.iter().any(|p| id.starts_with(p)));
+        if !allowed {
+            return Err(ConsumeError::Rejected("ID not allowed for token"));
+        }
+        if ids.as_ref().map_or(false, |ids| !ids.contains(id)) {
+            return Err(ConsumeError::MissingId);
+        }
+        let finder = self.finders.entry(token.to_vec()).or_insert_with(HashMap::new);
+        if !finder.contains_key(id) {
+            let stream = try!(self.factory.open(id).map_err(ConsumeError::Push));
+            finder.insert(id.to_vec(), stream);
+        }
+        finder.get_mut(id).unwrap().push(timestamp, payload).map_err(ConsumeError::Push)
+    }
+}
+
+impl Server for Files {
+    type Stream = FileStream<File>;
+    type AuthErr = ::Void;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.refresh();
+        if !self.current.policy.tokens.contains_key(token) {
+            return Err(AuthError::InvalidToken);
+        }
+        Ok(self.finders.entry(token.to_vec()).or_insert_with(HashMap::new))
+    }
+
+    type ConsumeOk = ();
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            _: &mut Spans)
+                            -> Consumed<Self> {
+        let result = self.store(token, id, timestamp, payload);
+        let counters = &self.shared.counters;
+        match result {
+            Ok(()) => counters.stored.fetch_add(1, Ordering::SeqCst),
+            Err(_) => counters.failed.fetch_add(1, Ordering::SeqCst),
+        };
+        result
+    }
+}
+
+/// Hands every frame of `socket` to the pool until it ends, is closed, or
+/// sends a frame over `max`.
+fn serve(socket: TcpStream,
+         connection: Connection,
+         max: usize,
+         shared: &Shared)
+         -> io::Result<()> {
+    let mut connection = connection;
+    let stats = try!(connection.read_capped(socket, max));
+    shared.counters.malformed.fetch_add(stats.malformed as usize, Ordering::SeqCst);
+    if let Some(len) = stats.oversized {
+        shared.counters.oversized.fetch_add(1, Ordering::SeqCst);
+        let e = format!("frame of {} bytes exceeds maximum of {}", len, max);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    if stats.truncated {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"));
+    }
+    Ok(())
+}
+
+/// What the accepting thread leaves for `Handle::shutdown` to finish.
+struct Accepted {
+    pool: AffinityPool<Files>,
+    connections: HashMap<usize, JoinHandle<()>>,
+}
+
+/// Sets the idle timeout, and returns a clone to close the socket by.
+fn prepare(socket: &TcpStream) -> io::Result<TcpStream> {
+    // Some platforms have an accepted socket inherit the listener's mode.
+    try!(socket.set_nonblocking(false));
+    try!(socket.set_read_timeout(Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS))));
+    socket.try_clone()
+}
+
+/// Joins the threads of the connections that have ended.
+fn reap(connections: &mut HashMap<usize, JoinHandle<()>>, shared: &Shared) {
+    let finished = mem::replace(&mut *shared.finished.lock().unwrap(), vec![]);
+    for number in finished {
+        if let Some(connection) = connections.remove(&number) {
+            let _ = connection.join();
+        }
+    }
+}
+
+fn accept(listener: TcpListener,
+          pool: AffinityPool<Files>,
+          max: usize,
+          shared: Arc<Shared>)
+          -> Accepted {
+    let mut connections = HashMap::new();
+    if let Err(e) = listener.set_nonblocking(true) {
+        shared.report(e);
+    }
+    let mut number = 0;
+    loop {
+        reap(&mut connections, &shared);
+        let (socket, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            // Connections made before the stop are still taken.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if shared.stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                continue;
+            }
+            Err(e) => {
+                shared.report(e);
+                continue;
+            }
+        };
+        let clone = match prepare(&socket) {
+            Ok(clone) => clone,
+            Err(e) => {
+                shared.report(e);
+                continue;
+            }
+        };
+        number += 1;
+        shared.counters.accepted.fetch_add(1, Ordering::SeqCst);
+        shared.counters.active.fetch_add(1, Ordering::SeqCst);
+        shared.open.lock().unwrap().insert(number, clone);
+        let connection = pool.connection_from(peer);
+        let shared = shared.clone();
+        connections.insert(number,
+                           thread::spawn(move || {
+                               if let Err(e) = serve(socket, connection, max, &shared) {
+                                   shared.report(e);
+                               }
+                               shared.open.lock().unwrap().remove(&number);
+                               shared.counters.active.fetch_sub(1, Ordering::SeqCst);
+                               shared.finished.lock().unwrap().push(number);
+                           }));
+    }
+    Accepted {
+        pool: pool,
+        connections: connections,
+    }
+}
+
+/// Starts serving as `config` says, on threads of its own.
+pub fn run(config: SimpleConfig) -> Result<Handle, SimpleError> {
+    try!(fs::create_dir_all(&config.data_dir).map_err(SimpleError::DataDir));
+    let listener = try!(TcpListener::bind(config.listen).map_err(SimpleError::Bind));
+    let mut addr = try!(listener.local_addr().map_err(SimpleError::Bind));
+    if addr.ip().is_unspecified() {
+        addr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+    }
+    let shared = Arc::new(Shared {
+        stopping: AtomicBool::new(false),
+        counters: Counters::default(),
+        on_error: Mutex::new(config.on_error),
+        open: Mutex::new(HashMap::new()),
+        finished: Mutex::new(vec![]),
+    });
+    let mut policy = Policy::default();
+    for token in config.tokens.keys() {
+        policy.tokens.insert(token.clone(), TokenPolicy::default());
+    }
+    let admin = ServerAdmin::new(ServerConfig::default(), policy)
+                    .expect("a policy without stream caps is valid");
+    let (epoch, current) = admin.load();
+    let prefixes = Arc::new(config.tokens);
+    let factory = FileStreamFactory::new(&config.data_dir, DEFAULT_LAYOUT);
+    let servers = (0..DEFAULT_WORKERS)
+                      .map(|_| {
+                          Files {
+                              prefixes: prefixes.clone(),
+                              finders: HashMap::new(),
+                              factory: factory.clone(),
+                              shared: shared.clone(),
+                              admin: admin.clone(),
+                              epoch: epoch,
+                              current: current.clone(),
+                          }
+                      })
+                      .collect();
+    let mut pool = AffinityPool::new(servers, DEFAULT_QUEUE_LEN);
+    let reporting = shared.clone();
+    pool.set_handler(move |handled: Handled<Files>| {
+        if let (Some(peer), Err(e)) = (handled.label, handled.item) {
+            reporting.report(io::Error::new(e.io_kind(), format!("{}: {}", peer, e)));
+        }
+    });
+    let max = config.max_frame_size.unwrap_or(u16::max_value() as usize);
+    let accepting = shared.clone();
+    Ok(Handle {
+        addr: addr,
+        shared: shared,
+        admin: admin,
+        acceptor: thread::spawn(move || accept(listener, pool, max, accepting)),
+    })
+}
+
+/// A server started by `run`, which serves until shut down. It may be
+/// moved to and used from any thread.
+pub struct Handle {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    admin: ServerAdmin,
+    acceptor: JoinHandle<Accepted>,
+}
+
+impl Handle {
+    /// Where it listens, for connecting to; an unspecified address is
+    /// given as the loopback one.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Publishes to every worker, each taking a publish up at its next
+    /// message.
+    pub fn admin(&self) -> &ServerAdmin {
+        &self.admin
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        self.shared.counters.snapshot()
+    }
+
+    /// Has `registry` render `stats` afresh for each scrape, under
+    /// `prefix`, for as long as the registry is kept.
+    pub fn register_metrics(&self, registry: &mut MetricsRegistry, prefix: &str) {
+        let shared = self.shared.clone();
+        registry.register(prefix,
+                          move |prefix, out| shared.counters.snapshot().render_into(prefix, out));
+    }
+
+    /// Stops taking connections, waits up to `grace` for the open ones to
+    /// end, closes any left, and returns once every frame read has been
+    /// stored or failed.
+    pub fn shutdown(self, grace: Duration) -> ShutdownReport {
+        let Handle { shared, acceptor, .. } = self;
+        shared.stopping.store(true, Ordering::SeqCst);
+        let accepted = acceptor.join().unwrap();
+
+        let start = Instant::now();
+        while shared.counters.active.load(Ordering::SeqCst) > 0 && start.elapsed() < grace {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let open = mem::replace(&mut *shared.open.lock().unwrap(), HashMap::new());
+        for socket in open.values() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        for (_, connection) in accepted.connections {
+            let _ = connection.join();
+        }
+        accepted.pool.join();
+        ShutdownReport {
+            stats: shared.counters.snapshot(),
+            forced: open.len() as u64,
+        }
+    }
+}
@@ -0,0 +1,20 @@
+//! The status codes that answer a frame, one table for both the acks a
+//! `client::ReliableReporter` reads and the responses to control frames.
+//!
+//! 0 is success; codes from 1 to 127 are transient failures, worth
+//! resending, and from 128 up permanent ones. Codes are stable.
+
+pub const OK: u8 = 0;
+/// The stream had pushes on their way.
+pub const BUSY: u8 = 1;
+/// The stream could not be extracted, but is still registered.
+pub const EXTRACT_FAILED: u8 = 2;
+/// The token may not do what was asked, or is not registered.
+pub const UNAUTHORIZED: u8 = 128;
+pub const MISSING_ID: u8 = 129;
+pub const UNKNOWN_CONTROL: u8 = 130;
+
+/// Whether a failure with `code` may succeed if resent.
+pub fn is_retryable(code: u8) -> bool {
+    code != OK && code < 128
+}
@@ -0,0 +1,290 @@
+//! Records gzipped before they reach the inner stream. Each record is
+//! written as a `FileStream` writes it, and once enough are buffered, they
+//! are compressed into a gzip member of their own and pushed to the inner
+//! stream as one payload, stamped with the first record's timestamp. The
+//! payloads concatenated are a multi-member gzip stream, such as
+//! `MultiGzDecoder` reads, of records a `RecordReader` reads.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use util::millis;
+use Stream;
+use super::Pressure;
+use super::file::{COMMITTED, RECORD_HEADER_LEN, RECORD_OVERHEAD};
+
+#[derive(Debug)]
+pub enum CompressError<E> {
+    Compress(io::Error),
+    Push(E),
+}
+
+impl<E: Display> Display for CompressError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CompressError::Compress(ref e) => write!(f, "cannot compress records: {}", e),
+            CompressError::Push(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for CompressError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            CompressError::Compress(_) => "cannot compress records",
+            CompressError::Push(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            CompressError::Compress(ref e) => Some(e),
+            CompressError::Push(ref e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressedExtractError<P, E> {
+    /// What was buffered could not be pushed, so the inner stream was not
+    /// extracted.
+    Flush(CompressError<P>),
+    Extract(E),
+}
+
+impl<P: Display, E: Display> Display for CompressedExtractError<P, E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CompressedExtractError::Flush(ref e) => write!(f, "cannot flush records: {}", e),
+            CompressedExtractError::Extract(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<P: error::Error, E: error::Error> error::Error for CompressedExtractError<P, E> {
+    fn description(&self) -> &str {
+        match *self {
+            CompressedExtractError::Flush(_) => "cannot flush records",
+            CompressedExtractError::Extract(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            CompressedExtractError::Flush(ref e) => Some(e),
+            CompressedExtractError::Extract(ref e) => Some(e),
+        }
+    }
+}
+
+/// Buffers records in a gzip member until `threshold` bytes of them are
+/// written, then pushes the member to the inner stream before buffering
+/// the next record.
+pub struct Compressed<S> {
+    stream: S,
+    level: Compression,
+    threshold: usize,
+    /// The member being written, the timestamp of its first record, and
+    /// the bytes of records written to it.
+    member: Option<(GzEncoder<Vec<u8>>, Duration, usize)>,
+    /// A finished member the inner stream has yet to take.
+    pending: Option<(Duration, Vec<u8>)>,
+}
+
+impl<S: Stream> Compressed<S> {
+    pub fn new(stream: S, threshold: usize) -> Self {
+        Compressed::with_level(stream, threshold, Compression::Default)
+    }
+
+    pub fn with_level(stream: S, threshold: usize, level: Compression) -> Self {
+        Compressed {
+            stream: stream,
+            level: level,
+            threshold: threshold,
+            member: None,
+            pending: None,
+        }
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Bytes of records written since the last member was finished.
+    pub fn buffered(&self) -> usize {
+        self.member.as_ref().map_or(0, |&(_, _, len)| len)
+    }
+
+    /// Finishes the member being written, if any, and pushes it to the
+    /// inner stream after any earlier member it has yet to take. A member
+    /// the inner stream refuses is kept to push again.
+    pub fn flush(&mut self) -> Result<(), CompressError<S::PushErr>> {
+        try!(self.push_pending());
+        if let Some((encoder, ts, _)) = self.member.take() {
+            let member = try!(encoder.finish().map_err(CompressError::Compress));
+            self.pending = Some((ts, member));
+            try!(self.push_pending());
+        }
+        Ok(())
+    }
+
+    fn push_pending(&mut self) -> Result<(), CompressError<S::PushErr>> {
+        if let Some((ts, member)) = self.pending.take() {
+            if let Err(e) = self.stream.push(ts, &member) {
+                self.pending = Some((ts, member));
+                return Err(CompressError::Push(e));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream> Stream for Compressed<S> {
+    type PushErr = CompressError<S::PushErr>;
+    /// Fails with nothing buffered if the records already buffered reach
+    /// the threshold and cannot be pushed.
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        if payload.len() as u64 > u32::max_value() as u64 {
+            return Err(CompressError::Compress(io::Error::new(io::ErrorKind::InvalidInput,
+                                                              "payload too large for a record")));
+        }
+        if self.buffered() >= self.threshold || self.pending.is_some() {
+            try!(self.flush());
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        BigEndian::write_u64(&mut header[..8], millis(ts));
+        BigEndian::write_u32(&mut header[8..], payload.len() as u32);
+        let level = self.level;
+        let &mut (ref mut encoder, _, ref mut len) =
+            self.member.get_or_insert_with(|| (GzEncoder::new(vec![], level), ts, 0));
+        try!(encoder.write_all(&header)
+                    .and_then(|()| encoder.write_all(payload))
+                    .and_then(|()| encoder.write_all(&[COMMITTED]))
+                    .map_err(CompressError::Compress));
+        *len += RECORD_OVERHEAD + payload.len();
+        Ok(())
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.stream.is_busy()
+    }
+
+    type Extract = S::Extract;
+    type ExtractErr = CompressedExtractError<S::PushErr, S::ExtractErr>;
+    /// Flushes, then extracts the inner stream. Either failing leaves the
+    /// stream as it was, less what was flushed.
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let mut compressed = self;
+        if let Err(e) = compressed.flush() {
+            return Err((compressed, CompressedExtractError::Flush(e)));
+        }
+        let Compressed { stream, level, threshold, .. } = compressed;
+        stream.extract().map_err(|(stream, e)| {
+            (Compressed {
+                stream: stream,
+                level: level,
+                threshold: threshold,
+                member: None,
+                pending: None,
+            },
+             CompressedExtractError::Extract(e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+    use stream::file::{Index, RecordReader};
+    use test_support::stream::ScriptedStream;
+    use Stream;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// The records in `members`, decompressed.
+    fn records(members: &[(Duration, Vec<u8>)]) -> Vec<(Duration, Vec<u8>)> {
+        let gzipped: Vec<u8> = members.iter().flat_map(|&(_, ref member)| member.clone()).collect();
+        let mut bytes = vec![];
+        MultiGzDecoder::new(&gzipped[..]).unwrap().read_to_end(&mut bytes).unwrap();
+        RecordReader::new(Cursor::new(bytes), Index::default())
+            .range(ms(0), Duration::from_secs(u64::max_value()))
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut compressed = Compressed::new(ScriptedStream::<(), ()>::default(), 100);
+        let pushed: Vec<_> = (0..20_u8).map(|i| (ms(i as u64), vec![i; i as usize])).collect();
+        for &(ts, ref payload) in &pushed {
+            compressed.push(ts, payload).unwrap();
+        }
+        let members = compressed.extract().ok().unwrap();
+        assert!(members.len() > 1);
+        assert_eq!(pushed, records(&members));
+        // Each member is stamped with its first record's timestamp.
+        let mut first = 0;
+        for &(ts, ref member) in &members {
+            assert_eq!(ms(first), ts);
+            first += records(&[(ts, member.clone())]).len() as u64;
+        }
+    }
+
+    #[test]
+    fn refused_flushes_keep_the_member() {
+        let inner = ScriptedStream::<_, ()>::new(vec![Err("full"), Ok(())]);
+        let mut compressed = Compressed::new(inner, 1);
+        compressed.push(ms(1), b"one").unwrap();
+        assert_match!(Err(CompressError::Push("full")), compressed.push(ms(2), b"two"));
+        assert!(compressed.get_ref().pushed().is_empty());
+        assert_eq!(0, compressed.buffered());
+        compressed.push(ms(3), b"three").unwrap();
+        let members = compressed.extract().ok().unwrap();
+        assert_eq!(vec![(ms(1), b"one".to_vec()), (ms(3), b"three".to_vec())],
+                   records(&members));
+    }
+
+    #[test]
+    fn failed_extraction_returns_the_stream() {
+        let mut inner = ScriptedStream::<(), _>::default();
+        inner.set_extract_script(vec![Err("busy")]);
+        let mut compressed = Compressed::new(inner, 1000);
+        compressed.push(ms(1), b"kept").unwrap();
+        let compressed = match compressed.extract() {
+            Err((compressed, CompressedExtractError::Extract("busy"))) => compressed,
+            _ => panic!("extraction should have failed"),
+        };
+        assert_eq!(0, compressed.buffered());
+        assert_eq!(vec![(ms(1), b"kept".to_vec())], records(compressed.get_ref().pushed()));
+        let members = compressed.extract().ok().unwrap();
+        assert_eq!(vec![(ms(1), b"kept".to_vec())], records(&members));
+    }
+}
@@ -0,0 +1,665 @@
+//! Payloads stored once however many streams push them, each stream keeping
+//! only a fixed-size reference.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use server::protection::fingerprint;
+use Stream;
+use super::Pressure;
+
+/// How many bytes a `ContentHandle` takes as a reference record.
+pub const REFERENCE_LEN: usize = 16;
+
+/// Names a payload in a `ContentStore`: its hash and length, and which of
+/// the payloads sharing that hash it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ContentHandle {
+    pub hash: u64,
+    pub len: u32,
+    pub slot: u32,
+}
+
+impl ContentHandle {
+    pub fn to_bytes(&self) -> [u8; REFERENCE_LEN] {
+        let mut bytes = [0_u8; REFERENCE_LEN];
+        BigEndian::write_u64(&mut bytes[..8], self.hash);
+        BigEndian::write_u32(&mut bytes[8..12], self.len);
+        BigEndian::write_u32(&mut bytes[12..], self.slot);
+        bytes
+    }
+
+    /// `None` unless `bytes` is exactly a reference record.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != REFERENCE_LEN {
+            return None;
+        }
+        Some(ContentHandle {
+            hash: BigEndian::read_u64(&bytes[..8]),
+            len: BigEndian::read_u32(&bytes[8..12]),
+            slot: BigEndian::read_u32(&bytes[12..]),
+        })
+    }
+}
+
+/// A change to a `ContentStore`, as a backing that persists one logs it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexEvent {
+    Stored {
+        handle: ContentHandle,
+        location: u64,
+    },
+    Shared(ContentHandle),
+    Released(ContentHandle),
+}
+
+/// Where a `ContentStore` keeps payload bytes.
+pub trait Backing {
+    /// Keeps `payload`, returning where to `load` it from.
+    fn store(&mut self, payload: &[u8]) -> io::Result<u64>;
+
+    fn load(&mut self, location: u64, len: u32) -> io::Result<Vec<u8>>;
+
+    /// Lets go of a payload no longer referenced.
+    fn discard(&mut self, _location: u64) {}
+
+    /// Notes a change to the store, for `replay` to restore.
+    fn record(&mut self, _event: IndexEvent) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Every change `record`ed so far, in order.
+    fn replay(&mut self) -> io::Result<Vec<IndexEvent>> {
+        Ok(vec![])
+    }
+}
+
+/// Keeps payloads in memory, freeing each once released.
+#[derive(Debug, Default)]
+pub struct MemoryBacking {
+    payloads: HashMap<u64, Vec<u8>>,
+    next: u64,
+}
+
+impl Backing for MemoryBacking {
+    fn store(&mut self, payload: &[u8]) -> io::Result<u64> {
+        let location = self.next;
+        self.next += 1;
+        self.payloads.insert(location, payload.to_vec());
+        Ok(location)
+    }
+
+    fn load(&mut self, location: u64, _: u32) -> io::Result<Vec<u8>> {
+        self.payloads
+            .get(&location)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no payload at location"))
+    }
+
+    fn discard(&mut self, location: u64) {
+        self.payloads.remove(&location);
+    }
+}
+
+const EVENT_LEN: usize = 25;
+
+/// Appends payloads to a data file and logs every change to an index file,
+/// so that a store reopened over both picks up where it left off. Released
+/// payloads stay in the data file.
+#[derive(Debug)]
+pub struct FileBacking<F> {
+    data: F,
+    index: F,
+}
+
+impl<F: Read + Write + Seek> FileBacking<F> {
+    pub fn new(data: F, index: F) -> Self {
+        FileBacking {
+            data: data,
+            index: index,
+        }
+    }
+
+    /// The data file and the index file.
+    pub fn into_inner(self) -> (F, F) {
+        (self.data, self.index)
+    }
+}
+
+impl<F: Read + Write + Seek> Backing for FileBacking<F> {
+    fn store(&mut self, payload: &[u8]) -> io::Result<u64> {
+        let location = try!(self.data.seek(SeekFrom::End(0)));
+        try!(self.data.write_all(payload));
+        Ok(location)
+    }
+
+    fn load(&mut self, location: u64, len: u32) -> io::Result<Vec<u8>> {
+        try!(self.data.seek(SeekFrom::Start(location)));
+        let mut payload = vec![0; len as usize];
+        try!(self.data.read_exact(&mut payload));
+        Ok(payload)
+    }
+
+    fn record(&mut self, event: IndexEvent) -> io::Result<()> {
+        let mut bytes = [0_u8; EVENT_LEN];
+        let (kind, handle, location) = match event {
+            IndexEvent::Stored { handle, location } => (0, handle, location),
+            IndexEvent::Shared(handle) => (1, handle, 0),
+            IndexEvent::Released(handle) => (2, handle, 0),
+        };
+        bytes[0] = kind;
+        bytes[1..17].copy_from_slice(&handle.to_bytes());
+        BigEndian::write_u64(&mut bytes[17..], location);
+        try!(self.index.seek(SeekFrom::End(0)));
+        self.index.write_all(&bytes)
+    }
+
+    /// A partial event at the end of the index, from a write cut short, is
+    /// ignored.
+    fn replay(&mut self) -> io::Result<Vec<IndexEvent>> {
+        try!(self.index.seek(SeekFrom::Start(0)));
+        let mut index = vec![];
+        try!(self.index.read_to_end(&mut index));
+        let mut events = vec![];
+        for bytes in index.chunks(EVENT_LEN).filter(|bytes| bytes.len() == EVENT_LEN) {
+            let handle = ContentHandle::from_bytes(&bytes[1..17]).unwrap();
+            events.push(match bytes[0] {
+                0 => {
+                    IndexEvent::Stored {
+                        handle: handle,
+                        location: BigEndian::read_u64(&bytes[17..]),
+                    }
+                }
+                1 => IndexEvent::Shared(handle),
+                2 => IndexEvent::Released(handle),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown index event")),
+            });
+        }
+        Ok(events)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    slot: u32,
+    len: u32,
+    location: u64,
+    refs: u64,
+}
+
+struct Inner<B> {
+    backing: B,
+    /// Every payload by hash, with a slot apiece for those whose hashes
+    /// collide.
+    entries: HashMap<u64, Vec<Entry>>,
+    hasher: fn(&[u8]) -> u64,
+}
+
+impl<B: Backing> Inner<B> {
+    fn apply(&mut self, event: IndexEvent) {
+        match event {
+            IndexEvent::Stored { handle, location } => {
+                self.entries.entry(handle.hash).or_insert_with(Vec::new).push(Entry {
+                    slot: handle.slot,
+                    len: handle.len,
+                    location: location,
+                    refs: 1,
+                });
+            }
+            IndexEvent::Shared(handle) => {
+                if let Some(entry) = self.entry(handle) {
+                    entry.refs += 1;
+                }
+            }
+            IndexEvent::Released(handle) => {
+                let gone = match self.entry(handle) {
+                    None => return,
+                    Some(entry) => {
+                        entry.refs -= 1;
+                        if entry.refs > 0 {
+                            return;
+                        }
+                        entry.location
+                    }
+                };
+                self.backing.discard(gone);
+                let empty = {
+                    let slots = self.entries.get_mut(&handle.hash).unwrap();
+                    slots.retain(|entry| entry.slot != handle.slot);
+                    slots.is_empty()
+                };
+                if empty {
+                    self.entries.remove(&handle.hash);
+                }
+            }
+        }
+    }
+
+    fn entry(&mut self, handle: ContentHandle) -> Option<&mut Entry> {
+        self.entries.get_mut(&handle.hash).and_then(|slots| {
+            slots.iter_mut().find(|entry| entry.slot == handle.slot && entry.len == handle.len)
+        })
+    }
+}
+
+fn default_hash(payload: &[u8]) -> u64 {
+    fingerprint(&[payload])
+}
+
+/// Stores each distinct payload once, counting its references. Payloads
+/// are told apart by hash, then length, then every byte, so a hash
+/// collision never shares one payload's bytes with another.
+///
+/// Shared between streams behind an `Arc`.
+pub struct ContentStore<B> {
+    inner: Mutex<Inner<B>>,
+}
+
+impl ContentStore<MemoryBacking> {
+    pub fn in_memory() -> Self {
+        ContentStore::from_backing(MemoryBacking::default())
+    }
+}
+
+impl<B: Backing> ContentStore<B> {
+    /// Opens a store over `backing`, restoring whatever it replays.
+    pub fn open(backing: B) -> io::Result<Self> {
+        let store = ContentStore::from_backing(backing);
+        {
+            let mut inner = store.inner.lock().unwrap();
+            for event in try!(inner.backing.replay()) {
+                inner.apply(event);
+            }
+        }
+        Ok(store)
+    }
+
+    fn from_backing(backing: B) -> Self {
+        ContentStore {
+            inner: Mutex::new(Inner {
+                backing: backing,
+                entries: HashMap::new(),
+                hasher: default_hash,
+            }),
+        }
+    }
+
+    /// Hashes payloads with `hasher` instead of FNV-1a. Panics unless the
+    /// store is empty.
+    pub fn set_hasher(&self, hasher: fn(&[u8]) -> u64) {
+        let mut inner = self.inner.lock().unwrap();
+        assert!(inner.entries.is_empty(), "cannot rehash a store's payloads");
+        inner.hasher = hasher;
+    }
+
+    /// Stores `payload`, or takes another reference to identical bytes
+    /// already stored.
+    pub fn put(&self, payload: &[u8]) -> io::Result<ContentHandle> {
+        if payload.len() > u32::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "payload too large to reference"));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let hash = (inner.hasher)(payload);
+        let len = payload.len() as u32;
+        let candidates: Vec<_> = inner.entries.get(&hash).map_or(vec![], |slots| slots.clone());
+        for entry in &candidates {
+            if entry.len == len && try!(inner.backing.load(entry.location, len)) == payload {
+                let handle = ContentHandle {
+                    hash: hash,
+                    len: len,
+                    slot: entry.slot,
+                };
+                try!(inner.backing.record(IndexEvent::Shared(handle)));
+                inner.apply(IndexEvent::Shared(handle));
+                return Ok(handle);
+            }
+        }
+        let slot = (0..).find(|slot| candidates.iter().all(|entry| entry.slot != *slot)).unwrap();
+        let handle = ContentHandle {
+            hash: hash,
+            len: len,
+            slot: slot,
+        };
+        let location = try!(inner.backing.store(payload));
+        let event = IndexEvent::Stored {
+            handle: handle,
+            location: location,
+        };
+        try!(inner.backing.record(event));
+        inner.apply(event);
+        Ok(handle)
+    }
+
+    /// The payload `handle` names, if it is still referenced.
+    pub fn get(&self, handle: ContentHandle) -> io::Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let location = match inner.entry(handle) {
+            None => return Ok(None),
+            Some(entry) => entry.location,
+        };
+        inner.backing.load(location, handle.len).map(Some)
+    }
+
+    /// Drops a reference, letting go of the payload with the last. Returns
+    /// whether `handle` was referenced.
+    pub fn release(&self, handle: ContentHandle) -> io::Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entry(handle).is_none() {
+            return Ok(false);
+        }
+        try!(inner.backing.record(IndexEvent::Released(handle)));
+        inner.apply(IndexEvent::Released(handle));
+        Ok(true)
+    }
+
+    /// How many references `handle` has.
+    pub fn refs(&self, handle: ContentHandle) -> u64 {
+        self.inner.lock().unwrap().entry(handle).map_or(0, |entry| entry.refs)
+    }
+
+    /// How many distinct payloads are stored.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes of every distinct payload stored, counted once each.
+    pub fn stored_bytes(&self) -> u64 {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .flat_map(|slots| slots.iter().map(|entry| entry.len as u64))
+            .sum()
+    }
+
+    pub fn into_backing(self) -> B {
+        self.inner.into_inner().unwrap().backing
+    }
+}
+
+#[derive(Debug)]
+pub enum ReferencingError<E> {
+    Store(io::Error),
+    Push(E),
+}
+
+impl<E: Display> Display for ReferencingError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ReferencingError::Store(ref e) => write!(f, "content store: {}", e),
+            ReferencingError::Push(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for ReferencingError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            ReferencingError::Store(ref e) => e.description(),
+            ReferencingError::Push(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ReferencingError::Store(ref e) => Some(e),
+            ReferencingError::Push(ref e) => Some(e),
+        }
+    }
+}
+
+/// The references an extracted `Referencing` stream held, to be released
+/// once its extract no longer needs the payloads.
+pub struct Release<B> {
+    store: Arc<ContentStore<B>>,
+    handles: Vec<ContentHandle>,
+}
+
+impl<B: Backing> Release<B> {
+    pub fn handles(&self) -> &[ContentHandle] {
+        &self.handles
+    }
+
+    pub fn release(self) -> io::Result<()> {
+        for &handle in &self.handles {
+            try!(self.store.release(handle));
+        }
+        Ok(())
+    }
+}
+
+/// Stores each payload in a `ContentStore` and pushes only its reference
+/// record, `REFERENCE_LEN` bytes, to the inner stream.
+pub struct Referencing<S, B> {
+    stream: S,
+    store: Arc<ContentStore<B>>,
+    handles: Vec<ContentHandle>,
+}
+
+impl<S, B> Referencing<S, B> {
+    pub fn new(stream: S, store: Arc<ContentStore<B>>) -> Self {
+        Referencing {
+            stream: stream,
+            store: store,
+            handles: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// The inner stream. Its references stay held.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream, B: Backing> Stream for Referencing<S, B> {
+    type PushErr = ReferencingError<S::PushErr>;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        let handle = try!(self.store.put(payload).map_err(ReferencingError::Store));
+        match self.stream.push(ts, &handle.to_bytes()) {
+            Ok(()) => {
+                self.handles.push(handle);
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.store.release(handle);
+                Err(ReferencingError::Push(e))
+            }
+        }
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.stream.is_busy()
+    }
+
+    type Extract = (S::Extract, Release<B>);
+    type ExtractErr = S::ExtractErr;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let (store, handles) = (self.store, self.handles);
+        match self.stream.extract() {
+            Ok(extract) => {
+                Ok((extract,
+                    Release {
+                    store: store,
+                    handles: handles,
+                }))
+            }
+            Err((stream, e)) => {
+                Err((Referencing {
+                    stream: stream,
+                    store: store,
+                    handles: handles,
+                },
+                     e))
+            }
+        }
+    }
+}
+
+/// Turns reference records back into the payloads they name.
+pub struct Rehydrate<'a, B: 'a, I> {
+    store: &'a ContentStore<B>,
+    records: I,
+}
+
+/// Rehydrates `records`, as read back from a stream behind `Referencing`.
+pub fn rehydrate<B, I>(store: &ContentStore<B>, records: I) -> Rehydrate<B, I::IntoIter>
+    where B: Backing,
+          I: IntoIterator<Item = (Duration, Vec<u8>)>
+{
+    Rehydrate {
+        store: store,
+        records: records.into_iter(),
+    }
+}
+
+impl<'a, B, I> Iterator for Rehydrate<'a, B, I>
+    where B: Backing,
+          I: Iterator<Item = (Duration, Vec<u8>)>
+{
+    type Item = io::Result<(Duration, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next().map(|(ts, record)| {
+            let handle = match ContentHandle::from_bytes(&record) {
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "not a reference record")),
+                Some(handle) => handle,
+            };
+            match try!(self.store.get(handle)) {
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "payload released")),
+                Some(payload) => Ok((ts, payload)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use test_support::stream::ScriptedStream;
+    use Stream;
+
+    type Inner = ScriptedStream<::Void, ::Void>;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn shared_across_ids() {
+        let store = Arc::new(ContentStore::in_memory());
+        let mut a = Referencing::new(Inner::default(), store.clone());
+        let mut b = Referencing::new(Inner::default(), store.clone());
+        let snapshot = vec![7_u8; 1000];
+        a.push(secs(1), &snapshot).unwrap();
+        assert_eq!(1000, store.stored_bytes());
+        b.push(secs(1), &snapshot).unwrap();
+        b.push(secs(2), b"other").unwrap();
+        assert_eq!((2, 1005), (store.len(), store.stored_bytes()));
+        assert_eq!(REFERENCE_LEN, a.get_ref().pushed()[0].1.len());
+        assert_eq!(a.get_ref().pushed()[0].1, b.get_ref().pushed()[0].1);
+
+        let (records, _) = b.extract().map_err(|(_, e)| e).unwrap();
+        let payloads: Vec<_> = rehydrate(&store, records).map(Result::unwrap).collect();
+        assert_eq!(vec![(secs(1), snapshot.clone()), (secs(2), b"other".to_vec())], payloads);
+    }
+
+    #[test]
+    fn released_on_extract() {
+        let store = Arc::new(ContentStore::in_memory());
+        let mut a = Referencing::new(Inner::default(), store.clone());
+        let mut b = Referencing::new(Inner::default(), store.clone());
+        a.push(secs(1), b"shared").unwrap();
+        b.push(secs(1), b"shared").unwrap();
+        let handle = ContentHandle::from_bytes(&a.get_ref().pushed()[0].1).unwrap();
+        assert_eq!(2, store.refs(handle));
+
+        let (_, release) = a.extract().map_err(|(_, e)| e).unwrap();
+        release.release().unwrap();
+        assert_eq!(1, store.refs(handle));
+        assert_eq!(Some(b"shared".to_vec()), store.get(handle).unwrap());
+
+        let (records, release) = b.extract().map_err(|(_, e)| e).unwrap();
+        release.release().unwrap();
+        assert!(store.is_empty());
+        assert_eq!(io::ErrorKind::NotFound,
+                   rehydrate(&store, records).next().unwrap().unwrap_err().kind());
+        let store = Arc::try_unwrap(store).ok().unwrap();
+        assert!(store.into_backing().payloads.is_empty());
+    }
+
+    #[test]
+    fn collisions_are_not_shared() {
+        fn collide(_: &[u8]) -> u64 {
+            42
+        }
+        let store = ContentStore::in_memory();
+        store.set_hasher(collide);
+        let abc = store.put(b"abc").unwrap();
+        let abd = store.put(b"abd").unwrap();
+        let long = store.put(b"abcd").unwrap();
+        assert_eq!((0, 1, 2), (abc.slot, abd.slot, long.slot));
+        assert_eq!(abc, store.put(b"abc").unwrap());
+        assert_eq!((3, 2), (store.len(), store.refs(abc)));
+        assert_eq!(Some(b"abd".to_vec()), store.get(abd).unwrap());
+
+        // A freed slot is reused, and a later slot still found.
+        assert!(store.release(abd).unwrap());
+        assert!(!store.release(abd).unwrap());
+        assert_eq!(1, store.put(b"xyz").unwrap().slot);
+        assert_eq!(long, store.put(b"abcd").unwrap());
+    }
+
+    #[test]
+    fn file_store_reopens() {
+        let backing = FileBacking::new(Cursor::new(vec![]), Cursor::new(vec![]));
+        let store = ContentStore::open(backing).unwrap();
+        let a = store.put(b"first").unwrap();
+        let b = store.put(b"second").unwrap();
+        store.put(b"first").unwrap();
+        store.release(b).unwrap();
+        let (data, index) = store.into_backing().into_inner();
+        let data_len = data.get_ref().len();
+
+        // A write of the index cut short is ignored.
+        let mut index = index.into_inner();
+        index.push(0);
+        let store = ContentStore::open(FileBacking::new(data, Cursor::new(index))).unwrap();
+        assert_eq!((1, 2), (store.len(), store.refs(a)));
+        assert_eq!(Some(b"first".to_vec()), store.get(a).unwrap());
+        assert_eq!(None, store.get(b).unwrap());
+        assert_eq!(a, store.put(b"first").unwrap());
+        let (data, _) = store.into_backing().into_inner();
+        assert_eq!(data_len, data.get_ref().len());
+    }
+}
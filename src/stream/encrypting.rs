@@ -0,0 +1,279 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use Stream;
+use super::Pressure;
+
+/// Seals payloads before they are stored and opens them when read back.
+/// The crate leaves the choice of cryptography to the implementor.
+pub trait PayloadCipher {
+    type Err;
+
+    /// Written before every sealed payload to identify the cipher and key.
+    fn id(&self) -> u16;
+
+    /// Appends the sealed `plaintext` to `out`.
+    fn seal(&mut self, ts: Duration, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), Self::Err>;
+
+    /// Appends the opened `sealed` to `out`.
+    fn open(&mut self, ts: Duration, sealed: &[u8], out: &mut Vec<u8>) -> Result<(), Self::Err>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncryptError<C, P> {
+    Cipher(C),
+    Push(P),
+}
+
+impl<C: Display, P: Display> Display for EncryptError<C, P> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            EncryptError::Cipher(ref e) => write!(f, "cannot seal payload: {}", e),
+            EncryptError::Push(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<C: error::Error, P: error::Error> error::Error for EncryptError<C, P> {
+    fn description(&self) -> &str {
+        match *self {
+            EncryptError::Cipher(_) => "cannot seal payload",
+            EncryptError::Push(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            EncryptError::Cipher(ref e) => Some(e),
+            EncryptError::Push(ref e) => Some(e),
+        }
+    }
+}
+
+/// Pushes payloads sealed by `C`, each prefixed with the cipher's
+/// big-endian ID, into the inner stream.
+pub struct Encrypting<S, C> {
+    stream: S,
+    cipher: C,
+    sealed: Vec<u8>,
+}
+
+impl<S: Stream, C: PayloadCipher> Encrypting<S, C> {
+    pub fn new(stream: S, cipher: C) -> Self {
+        Encrypting {
+            stream: stream,
+            cipher: cipher,
+            sealed: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> (S, C) {
+        (self.stream, self.cipher)
+    }
+}
+
+impl<S: Stream, C: PayloadCipher> Stream for Encrypting<S, C> {
+    type PushErr = EncryptError<C::Err, S::PushErr>;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        self.sealed.clear();
+        self.sealed.extend_from_slice(&[0, 0]);
+        BigEndian::write_u16(&mut self.sealed, self.cipher.id());
+        try!(self.cipher.seal(ts, payload, &mut self.sealed).map_err(EncryptError::Cipher));
+        self.stream.push(ts, &self.sealed).map_err(EncryptError::Push)
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.stream.is_busy()
+    }
+
+    type Extract = S::Extract;
+    type ExtractErr = S::ExtractErr;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let Encrypting { stream, cipher, sealed } = self;
+        stream.extract().map_err(|(stream, e)| {
+            (Encrypting {
+                stream: stream,
+                cipher: cipher,
+                sealed: sealed,
+            },
+             e)
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecryptError<E> {
+    /// The record is too short to hold a cipher ID.
+    MissingCipherId,
+    CipherMismatch {
+        expected: u16,
+        found: u16,
+    },
+    Cipher(E),
+}
+
+impl<E: Display> Display for DecryptError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DecryptError::MissingCipherId => f.write_str("missing cipher ID"),
+            DecryptError::CipherMismatch { expected, found } => {
+                write!(f, "sealed by cipher {}; expected cipher {}", found, expected)
+            }
+            DecryptError::Cipher(ref e) => write!(f, "cannot open payload: {}", e),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for DecryptError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            DecryptError::MissingCipherId => "missing cipher ID",
+            DecryptError::CipherMismatch { .. } => "cipher ID mismatch",
+            DecryptError::Cipher(_) => "cannot open payload",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            DecryptError::Cipher(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Opens records written by `Encrypting` with the same cipher.
+pub struct Decrypting<C>(pub C);
+
+impl<C: PayloadCipher> Decrypting<C> {
+    /// Checks and strips the cipher ID, then appends the opened payload to
+    /// `out`.
+    pub fn open(&mut self,
+                ts: Duration,
+                record: &[u8],
+                out: &mut Vec<u8>)
+                -> Result<(), DecryptError<C::Err>> {
+        if record.len() < 2 {
+            return Err(DecryptError::MissingCipherId);
+        }
+        let expected = self.0.id();
+        let found = BigEndian::read_u16(record);
+        if found != expected {
+            return Err(DecryptError::CipherMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+        self.0.open(ts, &record[2..], out).map_err(DecryptError::Cipher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use test_support::stream::XorTestCipher;
+    use test_support::*;
+    use Stream;
+
+    #[derive(Debug, Default)]
+    struct Recording(Vec<(Duration, Vec<u8>)>);
+
+    impl Stream for Recording {
+        type PushErr = ::Void;
+        fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+            self.0.push((ts, payload.to_owned()));
+            Ok(())
+        }
+
+        type Extract = Vec<(Duration, Vec<u8>)>;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(self.0)
+        }
+    }
+
+    /// Seals like `XorTestCipher` until its budget of payloads runs out.
+    struct Exhaustible {
+        inner: XorTestCipher,
+        left: usize,
+    }
+
+    impl PayloadCipher for Exhaustible {
+        type Err = ();
+        fn id(&self) -> u16 {
+            self.inner.id()
+        }
+
+        fn seal(&mut self, ts: Duration, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+            if self.left == 0 {
+                return Err(());
+            }
+            self.left -= 1;
+            self.inner.seal(ts, plaintext, out).map_err(::absurd)
+        }
+
+        fn open(&mut self, ts: Duration, sealed: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+            self.inner.open(ts, sealed, out).map_err(::absurd)
+        }
+    }
+
+    quickcheck_test! {
+    round_trip(records: Vec<(u64, Vec<u8>)>, id: u16, key: u8; bool) {
+        let cipher = XorTestCipher { id: id, key: key };
+        let mut stream = Encrypting::new(Recording::default(), cipher.clone());
+        for &(millis, ref payload) in &records {
+            stream.push(Duration::from_millis(millis), payload).unwrap();
+        }
+        let stored = stream.extract().ok().unwrap();
+        let mut decrypting = Decrypting(cipher);
+        stored.len() == records.len() &&
+        stored.iter().zip(&records).all(|(&(ts, ref record), &(millis, ref payload))| {
+            let mut opened = vec![];
+            decrypting.open(ts, record, &mut opened).unwrap();
+            ts == Duration::from_millis(millis) && &opened == payload &&
+            (key == 0 || payload.is_empty() || &record[2..] != &payload[..])
+        })
+    }}
+
+    #[test]
+    fn cipher_failure_mid_sequence() {
+        let cipher = Exhaustible {
+            inner: XorTestCipher { id: 1, key: 0x5a },
+            left: 2,
+        };
+        let mut stream = Encrypting::new(Recording::default(), cipher);
+        let ts = Duration::from_millis(0);
+        assert_eq!(Ok(()), stream.push(ts, b"one"));
+        assert_eq!(Ok(()), stream.push(ts, b"two"));
+        assert_eq!(Err(EncryptError::Cipher(())), stream.push(ts, b"three"));
+        assert_eq!(2, stream.get_ref().0.len());
+    }
+
+    #[test]
+    fn cipher_id_mismatch() {
+        let mut stream = Encrypting::new(Recording::default(), XorTestCipher { id: 1, key: 7 });
+        stream.push(Duration::from_millis(0), b"payload").unwrap();
+        let stored = stream.extract().ok().unwrap();
+        let mut decrypting = Decrypting(XorTestCipher { id: 2, key: 7 });
+        let err = decrypting.open(stored[0].0, &stored[0].1, &mut vec![]).unwrap_err();
+        assert_eq!(DecryptError::CipherMismatch {
+                       expected: 2,
+                       found: 1,
+                   },
+                   err);
+        assert_eq!(Err(DecryptError::MissingCipherId),
+                   decrypting.open(stored[0].0, &[0], &mut vec![]));
+    }
+}
@@ -0,0 +1,881 @@
+//! One container for whatever a stream extracts, so that an archive can
+//! hold extracts of every kind and be read back without knowing which
+//! streams they came from. Each envelope is:
+//!
+//! | field    | encoding                                                 |
+//! |----------|----------------------------------------------------------|
+//! | magic    | `SVXE`                                                   |
+//! | version  | one byte, `VERSION`                                      |
+//! | kind     | one byte, `ExtractKind::tag`                             |
+//! | records  | eight bytes, `u64::MAX` if unknown                       |
+//! | label    | a two-byte length, then that many bytes, such as an ID   |
+//! | metadata | a two-byte count, then per entry a one-byte key length,  |
+//! |          | the key in UTF-8 and an eight-byte value                 |
+//! | body     | an eight-byte length, then that many bytes               |
+//!
+//! Numbers are big-endian. The body of `Records` is in `FileStream`'s
+//! record format; the body of `Text` is the text as written. Envelopes
+//! are written back to back, with nothing between them, to make an archive.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{Backing, Index, Range, ReadError, ReassemblyStats, RecordReader, Release,
+            SequenceStats};
+
+pub const MAGIC: [u8; 4] = *b"SVXE";
+pub const VERSION: u8 = 1;
+
+const UNKNOWN: u64 = ::std::u64::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExtractKind {
+    /// Timestamped payloads, as a `FileStream` stores them.
+    Records,
+    /// Lines for reading, as a `TextLog` writes them.
+    Text,
+}
+
+impl ExtractKind {
+    pub fn tag(self) -> u8 {
+        match self {
+            ExtractKind::Records => 1,
+            ExtractKind::Text => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(ExtractKind::Records),
+            2 => Some(ExtractKind::Text),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ExtractKind {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            ExtractKind::Records => "records",
+            ExtractKind::Text => "text",
+        })
+    }
+}
+
+/// What an extract holds, as far as can be told without reading it all.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtractDescriptor {
+    pub kind: ExtractKind,
+    /// Records, or lines of text.
+    pub records: Option<u64>,
+    /// Of the body.
+    pub bytes: Option<u64>,
+    /// Whatever else the stream counted, such as a wrapper's stats.
+    pub metadata: Vec<(String, u64)>,
+}
+
+impl ExtractDescriptor {
+    pub fn new(kind: ExtractKind) -> Self {
+        ExtractDescriptor {
+            kind: kind,
+            records: None,
+            bytes: None,
+            metadata: vec![],
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.metadata.iter().find(|&&(ref k, _)| k == key).map(|&(_, value)| value)
+    }
+}
+
+impl Display for ExtractDescriptor {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        try!(self.kind.fmt(f));
+        if let Some(records) = self.records {
+            try!(write!(f, ", {} records", records));
+        }
+        if let Some(bytes) = self.bytes {
+            try!(write!(f, ", {} bytes", bytes));
+        }
+        for &(ref key, value) in &self.metadata {
+            try!(write!(f, ", {}={}", key, value));
+        }
+        Ok(())
+    }
+}
+
+/// An extract that can be put in an envelope.
+pub trait ExtractEnvelope {
+    /// For the envelope to be written, `bytes` must be known.
+    fn describe(&self) -> ExtractDescriptor;
+
+    /// Writes the body alone, returning its length.
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64>;
+
+    /// Writes the envelope, unlabeled, returning its length.
+    fn write_portable<W: Write>(&self, w: W) -> io::Result<u64>
+        where Self: Sized
+    {
+        write_envelope(w, &[], &self.describe(), self)
+    }
+}
+
+/// Writes `extract` in an envelope labeled `label` and described by
+/// `descriptor`, which is normally its own with metadata added, returning
+/// the envelope's length.
+pub fn write_envelope<E, W>(w: W,
+                            label: &[u8],
+                            descriptor: &ExtractDescriptor,
+                            extract: &E)
+                            -> io::Result<u64>
+    where E: ExtractEnvelope,
+          W: Write
+{
+    let mut w = w;
+    let bytes = match descriptor.bytes {
+        Some(bytes) => bytes,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "extract of unknown size")),
+    };
+    if label.len() > u16::max_value() as usize ||
+       descriptor.metadata.len() > u16::max_value() as usize ||
+       descriptor.metadata.iter().any(|&(ref key, _)| key.len() > u8::max_value() as usize) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "envelope field too long"));
+    }
+    let mut header = MAGIC.to_vec();
+    header.push(VERSION);
+    header.push(descriptor.kind.tag());
+    push_u64(&mut header, descriptor.records.unwrap_or(UNKNOWN));
+    push_u16(&mut header, label.len() as u16);
+    header.extend_from_slice(label);
+    push_u16(&mut header, descriptor.metadata.len() as u16);
+    for &(ref key, value) in &descriptor.metadata {
+        header.push(key.len() as u8);
+        header.extend_from_slice(key.as_bytes());
+        push_u64(&mut header, value);
+    }
+    push_u64(&mut header, bytes);
+    try!(w.write_all(&header));
+    let written = try!(extract.write_body(&mut w));
+    if written != bytes {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "body not as long as described"));
+    }
+    Ok(header.len() as u64 + bytes)
+}
+
+fn push_u16(bytes: &mut Vec<u8>, n: u16) {
+    let mut buf = [0_u8; 2];
+    BigEndian::write_u16(&mut buf, n);
+    bytes.extend_from_slice(&buf);
+}
+
+fn push_u64(bytes: &mut Vec<u8>, n: u64) {
+    let mut buf = [0_u8; 8];
+    BigEndian::write_u64(&mut buf, n);
+    bytes.extend_from_slice(&buf);
+}
+
+/// Every record of `data`, from its start, as `RecordReader` reads it.
+fn all_records<R: Read + Seek>(data: &mut RecordReader<R>) -> Range<R> {
+    data.range(Duration::from_secs(0), Duration::from_secs(u64::max_value()))
+}
+
+/// The records in `data`, if it ends at the end of one. A voided record is
+/// not counted.
+fn count_records<R: Read + Seek>(data: R) -> io::Result<Option<u64>> {
+    let mut records = 0;
+    for record in all_records(&mut RecordReader::new(data, Index::default())) {
+        match record {
+            Ok(_) => records += 1,
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(_) => return Ok(None),
+        }
+    }
+    Ok(Some(records))
+}
+
+fn copy_file<W: Write>(file: &File, w: &mut W) -> io::Result<u64> {
+    let mut file = file;
+    try!(file.seek(SeekFrom::Start(0)));
+    io::copy(&mut file, w)
+}
+
+fn describe_records(data: &[u8], sidecar: Option<&[u8]>) -> ExtractDescriptor {
+    let mut descriptor = ExtractDescriptor::new(ExtractKind::Records);
+    descriptor.records = count_records(io::Cursor::new(data)).unwrap_or(None);
+    descriptor.bytes = Some(data.len() as u64);
+    if let Some(sidecar) = sidecar {
+        descriptor.metadata.push(("index_entries".to_owned(), sidecar.len() as u64 / 16));
+    }
+    descriptor
+}
+
+fn describe_text(text: &[u8], lines: u64) -> ExtractDescriptor {
+    let mut descriptor = ExtractDescriptor::new(ExtractKind::Text);
+    descriptor.records = Some(lines);
+    descriptor.bytes = Some(text.len() as u64);
+    descriptor
+}
+
+fn write_bytes<W: Write>(bytes: &[u8], w: &mut W) -> io::Result<u64> {
+    try!(w.write_all(bytes));
+    Ok(bytes.len() as u64)
+}
+
+/// A `FileStream`'s data, and its index sidecar, which is counted but not
+/// kept: it can be rebuilt from the data.
+impl ExtractEnvelope for (Vec<u8>, Option<Vec<u8>>) {
+    fn describe(&self) -> ExtractDescriptor {
+        describe_records(&self.0, self.1.as_ref().map(|sidecar| &sidecar[..]))
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        write_bytes(&self.0, w)
+    }
+}
+
+impl ExtractEnvelope for (io::Cursor<Vec<u8>>, Option<io::Cursor<Vec<u8>>>) {
+    fn describe(&self) -> ExtractDescriptor {
+        describe_records(self.0.get_ref(),
+                         self.1.as_ref().map(|sidecar| &sidecar.get_ref()[..]))
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        write_bytes(self.0.get_ref(), w)
+    }
+}
+
+/// A `FileStream`'s data and sidecar as borrowed, to describe and write
+/// without taking them.
+impl<'a> ExtractEnvelope for (&'a [u8], Option<&'a [u8]>) {
+    fn describe(&self) -> ExtractDescriptor {
+        describe_records(self.0, self.1)
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        write_bytes(self.0, w)
+    }
+}
+
+/// The data must have been opened for reading as well, unlike a
+/// `FileStreamFactory`'s, which only appends.
+impl ExtractEnvelope for (File, Option<File>) {
+    fn describe(&self) -> ExtractDescriptor {
+        let mut descriptor = ExtractDescriptor::new(ExtractKind::Records);
+        descriptor.records = count_records(&self.0).unwrap_or(None);
+        descriptor.bytes = self.0.metadata().ok().map(|metadata| metadata.len());
+        if let Some(ref sidecar) = self.1 {
+            if let Ok(metadata) = sidecar.metadata() {
+                descriptor.metadata.push(("index_entries".to_owned(), metadata.len() / 16));
+            }
+        }
+        descriptor
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        copy_file(&self.0, w)
+    }
+}
+
+/// A `TextLog`'s writer and its count of lines.
+impl ExtractEnvelope for (Vec<u8>, u64) {
+    fn describe(&self) -> ExtractDescriptor {
+        describe_text(&self.0, self.1)
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        write_bytes(&self.0, w)
+    }
+}
+
+impl ExtractEnvelope for (io::Cursor<Vec<u8>>, u64) {
+    fn describe(&self) -> ExtractDescriptor {
+        describe_text(self.0.get_ref(), self.1)
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        write_bytes(self.0.get_ref(), w)
+    }
+}
+
+impl<'a> ExtractEnvelope for (&'a [u8], u64) {
+    fn describe(&self) -> ExtractDescriptor {
+        describe_text(self.0, self.1)
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        write_bytes(self.0, w)
+    }
+}
+
+impl ExtractEnvelope for (File, u64) {
+    fn describe(&self) -> ExtractDescriptor {
+        let mut descriptor = ExtractDescriptor::new(ExtractKind::Text);
+        descriptor.records = Some(self.1);
+        descriptor.bytes = self.0.metadata().ok().map(|metadata| metadata.len());
+        descriptor
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        copy_file(&self.0, w)
+    }
+}
+
+/// A `Referencing` stream's, as what it wrapped extracted, counting the
+/// references to be released.
+impl<E: ExtractEnvelope, B: Backing> ExtractEnvelope for (E, Release<B>) {
+    fn describe(&self) -> ExtractDescriptor {
+        let mut descriptor = self.0.describe();
+        descriptor.metadata.push(("references".to_owned(), self.1.handles().len() as u64));
+        descriptor
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        self.0.write_body(w)
+    }
+}
+
+impl<E: ExtractEnvelope> ExtractEnvelope for (E, ReassemblyStats) {
+    fn describe(&self) -> ExtractDescriptor {
+        let stats = &self.1;
+        let mut descriptor = self.0.describe();
+        for &(key, value) in &[("reassembly.completed", stats.completed),
+                               ("reassembly.evicted", stats.evicted),
+                               ("reassembly.aborted", stats.aborted),
+                               ("reassembly.duplicate_bytes", stats.duplicate_bytes),
+                               ("reassembly.staged_bytes", stats.staged_bytes),
+                               ("reassembly.uploads", stats.uploads as u64)] {
+            descriptor.metadata.push((key.to_owned(), value));
+        }
+        descriptor
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        self.0.write_body(w)
+    }
+}
+
+impl<E: ExtractEnvelope> ExtractEnvelope for (E, SequenceStats) {
+    fn describe(&self) -> ExtractDescriptor {
+        let stats = &self.1;
+        let mut descriptor = self.0.describe();
+        for &(key, value) in &[("sequence.gaps", stats.gaps),
+                               ("sequence.missing", stats.missing),
+                               ("sequence.regressions", stats.regressions),
+                               ("sequence.unparseable", stats.unparseable),
+                               ("sequence.wraparounds", stats.wraparounds)] {
+            descriptor.metadata.push((key.to_owned(), value));
+        }
+        descriptor
+    }
+
+    fn write_body<W: Write>(&self, w: &mut W) -> io::Result<u64> {
+        self.0.write_body(w)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortableBody {
+    Records(Vec<(Duration, Vec<u8>)>),
+    Text(Vec<u8>),
+}
+
+/// An envelope as read back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortableExtract {
+    pub label: Vec<u8>,
+    /// With `bytes` always known.
+    pub descriptor: ExtractDescriptor,
+    pub body: PortableBody,
+}
+
+#[derive(Debug)]
+pub enum PortableErrorKind {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    /// The input ended within the envelope.
+    Truncated,
+    /// A metadata key is not UTF-8.
+    BadKey,
+    /// A record runs past the end of the body.
+    BadRecord,
+    /// The body holds a different number of records than described.
+    RecordCount {
+        described: u64,
+        found: u64,
+    },
+}
+
+/// What is wrong with an envelope, and where, counting from the start of
+/// the input.
+#[derive(Debug)]
+pub struct PortableError {
+    pub offset: u64,
+    pub kind: PortableErrorKind,
+}
+
+impl Display for PortableError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self.kind {
+            PortableErrorKind::Io(ref e) => try!(e.fmt(f)),
+            PortableErrorKind::BadMagic => try!(f.write_str("not an extract envelope")),
+            PortableErrorKind::UnsupportedVersion(version) => {
+                try!(write!(f, "unsupported envelope version {}", version))
+            }
+            PortableErrorKind::UnknownKind(tag) => try!(write!(f, "unknown extract kind {}", tag)),
+            PortableErrorKind::Truncated => try!(f.write_str("truncated envelope")),
+            PortableErrorKind::BadKey => try!(f.write_str("metadata key not UTF-8")),
+            PortableErrorKind::BadRecord => try!(f.write_str("record runs past the body")),
+            PortableErrorKind::RecordCount { described, found } => {
+                try!(write!(f, "{} records described but {} found", described, found))
+            }
+        }
+        write!(f, " at byte {}", self.offset)
+    }
+}
+
+impl error::Error for PortableError {
+    fn description(&self) -> &str {
+        match self.kind {
+            PortableErrorKind::Io(ref e) => e.description(),
+            PortableErrorKind::BadMagic => "not an extract envelope",
+            PortableErrorKind::UnsupportedVersion(_) => "unsupported envelope version",
+            PortableErrorKind::UnknownKind(_) => "unknown extract kind",
+            PortableErrorKind::Truncated => "truncated envelope",
+            PortableErrorKind::BadKey => "metadata key not UTF-8",
+            PortableErrorKind::BadRecord => "record runs past the body",
+            PortableErrorKind::RecordCount { .. } => "wrong number of records",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match self.kind {
+            PortableErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the one envelope at the start of `r`.
+pub fn read_portable<R: Read>(r: R) -> Result<PortableExtract, PortableError> {
+    let mut reader = PortableReader::new(r);
+    match reader.next() {
+        Some(result) => result,
+        None => {
+            Err(PortableError {
+                offset: 0,
+                kind: PortableErrorKind::Truncated,
+            })
+        }
+    }
+}
+
+/// The envelopes of an archive, one after another, up to the first one
+/// that cannot be read.
+pub struct PortableReader<R> {
+    reader: R,
+    offset: u64,
+    failed: bool,
+}
+
+impl<R: Read> PortableReader<R> {
+    pub fn new(reader: R) -> Self {
+        PortableReader {
+            reader: reader,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// Where the next envelope starts.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn error(&self, kind: PortableErrorKind) -> PortableError {
+        PortableError {
+            offset: self.offset,
+            kind: kind,
+        }
+    }
+
+    /// Fills `buf`, or fails at where the input ended.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), PortableError> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.reader.read(&mut buf[n..]) {
+                Ok(0) => {
+                    self.offset += n as u64;
+                    return Err(self.error(PortableErrorKind::Truncated));
+                }
+                Ok(k) => n += k,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.offset += n as u64;
+                    return Err(self.error(PortableErrorKind::Io(e)));
+                }
+            }
+        }
+        self.offset += n as u64;
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, PortableError> {
+        let mut buf = [0_u8; 1];
+        try!(self.read_exact(&mut buf));
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, PortableError> {
+        let mut buf = [0_u8; 2];
+        try!(self.read_exact(&mut buf));
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, PortableError> {
+        let mut buf = [0_u8; 8];
+        try!(self.read_exact(&mut buf));
+        Ok(BigEndian::read_u64(&buf))
+    }
+
+    /// Reads `len` bytes, only allocating for as many as there are.
+    fn read_vec(&mut self, len: u64) -> Result<Vec<u8>, PortableError> {
+        let mut bytes = vec![];
+        let read = (&mut self.reader).take(len).read_to_end(&mut bytes);
+        self.offset += bytes.len() as u64;
+        match read {
+            Err(e) => Err(self.error(PortableErrorKind::Io(e))),
+            Ok(n) if (n as u64) < len => Err(self.error(PortableErrorKind::Truncated)),
+            Ok(_) => Ok(bytes),
+        }
+    }
+
+    /// The rest of an envelope whose magic's first byte, `first`, was read.
+    fn envelope(&mut self, first: u8) -> Result<PortableExtract, PortableError> {
+        let start = self.offset - 1;
+        let mut magic = [first, 0, 0, 0];
+        try!(self.read_exact(&mut magic[1..]));
+        if magic != MAGIC {
+            return Err(PortableError {
+                offset: start,
+                kind: PortableErrorKind::BadMagic,
+            });
+        }
+        let version = try!(self.read_u8());
+        if version != VERSION {
+            self.offset -= 1;
+            return Err(self.error(PortableErrorKind::UnsupportedVersion(version)));
+        }
+        let tag = try!(self.read_u8());
+        let kind = match ExtractKind::from_tag(tag) {
+            Some(kind) => kind,
+            None => {
+                self.offset -= 1;
+                return Err(self.error(PortableErrorKind::UnknownKind(tag)));
+            }
+        };
+        let mut descriptor = ExtractDescriptor::new(kind);
+        descriptor.records = match try!(self.read_u64()) {
+            UNKNOWN => None,
+            records => Some(records),
+        };
+        let label_len = try!(self.read_u16());
+        let label = try!(self.read_vec(label_len as u64));
+        for _ in 0..try!(self.read_u16()) {
+            let key_len = try!(self.read_u8());
+            let at = self.offset;
+            let key = try!(self.read_vec(key_len as u64));
+            let key = match String::from_utf8(key) {
+                Ok(key) => key,
+                Err(_) => {
+                    return Err(PortableError {
+                        offset: at,
+                        kind: PortableErrorKind::BadKey,
+                    })
+                }
+            };
+            let value = try!(self.read_u64());
+            descriptor.metadata.push((key, value));
+        }
+        let len = try!(self.read_u64());
+        descriptor.bytes = Some(len);
+        let body_start = self.offset;
+        let bytes = try!(self.read_vec(len));
+        let body = match kind {
+            ExtractKind::Text => PortableBody::Text(bytes),
+            ExtractKind::Records => {
+                let records = try!(parse_records(&bytes).map_err(|at| {
+                    PortableError {
+                        offset: body_start + at as u64,
+                        kind: PortableErrorKind::BadRecord,
+                    }
+                }));
+                if let Some(described) = descriptor.records {
+                    if described != records.len() as u64 {
+                        return Err(PortableError {
+                            offset: body_start,
+                            kind: PortableErrorKind::RecordCount {
+                                described: described,
+                                found: records.len() as u64,
+                            },
+                        });
+                    }
+                }
+                PortableBody::Records(records)
+            }
+        };
+        Ok(PortableExtract {
+            label: label,
+            descriptor: descriptor,
+            body: body,
+        })
+    }
+}
+
+/// The records of a body, or the offset of the first that runs past it.
+fn parse_records(bytes: &[u8]) -> Result<Vec<(Duration, Vec<u8>)>, usize> {
+    let mut records = vec![];
+    for record in all_records(&mut RecordReader::new(io::Cursor::new(bytes), Index::default())) {
+        match record {
+            Ok(record) => records.push(record),
+            Err(ReadError::Truncated { offset }) => return Err(offset as usize),
+            // Nothing else fails a read from memory without an index.
+            Err(_) => return Err(0),
+        }
+    }
+    Ok(records)
+}
+
+impl<R: Read> Iterator for PortableReader<R> {
+    type Item = Result<PortableExtract, PortableError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut first = [0_u8; 1];
+        loop {
+            match self.reader.read(&mut first) {
+                Ok(0) => return None,
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(self.error(PortableErrorKind::Io(e))));
+                }
+            }
+        }
+        self.offset += 1;
+        let result = self.envelope(first[0]);
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use stream::{ContentStore, FileStream, PreviewMode, Referencing, SequenceTracker, TextLog};
+    use stream::sequence::be_prefix;
+    use Stream;
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn records() -> Vec<(Duration, Vec<u8>)> {
+        vec![(millis(1), b"one".to_vec()), (millis(2), vec![]), (millis(3), vec![7; 300])]
+    }
+
+    fn file_stream() -> FileStream<Vec<u8>> {
+        let mut stream = FileStream::with_index(vec![], vec![], 2);
+        for &(ts, ref payload) in &records() {
+            stream.push(ts, payload).unwrap();
+        }
+        stream
+    }
+
+    fn round_trip<E: ExtractEnvelope>(extract: &E) -> PortableExtract {
+        let mut bytes = vec![];
+        let written = extract.write_portable(&mut bytes).unwrap();
+        assert_eq!(bytes.len() as u64, written);
+        let read = read_portable(&bytes[..]).unwrap();
+        assert_eq!(extract.describe(), read.descriptor);
+        read
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let extract = file_stream().extract().map_err(|_| ()).unwrap();
+        let read = round_trip(&extract);
+        assert_eq!(Some(3), read.descriptor.records);
+        assert_eq!(Some(2), read.descriptor.get("index_entries"));
+        assert_eq!(PortableBody::Records(records()), read.body);
+
+        let mut stream = FileStream::new(Cursor::new(vec![]));
+        stream.push(millis(5), b"five").unwrap();
+        let read = round_trip(&stream.extract().map_err(|_| ()).unwrap());
+        assert_eq!(PortableBody::Records(vec![(millis(5), b"five".to_vec())]), read.body);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut log = TextLog::new(vec![], PreviewMode::Utf8Lossy(8));
+        log.push(millis(1), b"hello").unwrap();
+        log.push(millis(2), b"there").unwrap();
+        let extract = log.extract().map_err(|_| ()).unwrap();
+        let read = round_trip(&extract);
+        assert_eq!(ExtractKind::Text, read.descriptor.kind);
+        assert_eq!(Some(2), read.descriptor.records);
+        assert_eq!(PortableBody::Text(extract.0.clone()), read.body);
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let path = env::temp_dir().join("sousveillance-envelope-files");
+        let file = OpenOptions::new()
+                       .read(true)
+                       .write(true)
+                       .create(true)
+                       .truncate(true)
+                       .open(&path)
+                       .unwrap();
+        let mut stream = FileStream::new(file);
+        for &(ts, ref payload) in &records() {
+            stream.push(ts, payload).unwrap();
+        }
+        let extract = stream.extract().map_err(|_| ()).unwrap();
+        assert_eq!(PortableBody::Records(records()), round_trip(&extract).body);
+        let _ = ::std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wrappers_add_metadata() {
+        let mut tracker = SequenceTracker::new(FileStream::new(vec![]), be_prefix);
+        tracker.push(millis(1), &[0, 0, 0, 1]).unwrap();
+        tracker.push(millis(2), &[0, 0, 0, 3]).unwrap();
+        let read = round_trip(&tracker.extract().map_err(|_| ()).unwrap());
+        assert_eq!(Some(1), read.descriptor.get("sequence.gaps"));
+        assert_eq!(Some(1), read.descriptor.get("sequence.missing"));
+        assert_eq!(Some(2), read.descriptor.records);
+
+        let store = Arc::new(ContentStore::in_memory());
+        let mut referencing = Referencing::new(FileStream::new(vec![]), store);
+        referencing.push(millis(1), &[9; 100]).unwrap();
+        referencing.push(millis(2), &[9; 100]).unwrap();
+        let read = round_trip(&referencing.extract().map_err(|_| ()).unwrap());
+        assert_eq!(Some(2), read.descriptor.get("references"));
+        assert_eq!(Some(2), read.descriptor.records);
+    }
+
+    #[test]
+    fn archive_of_every_kind() {
+        let mut archive = vec![];
+        let records = file_stream().extract().map_err(|_| ()).unwrap();
+        write_envelope(&mut archive, b"cam", &records.describe(), &records).unwrap();
+        let text = (b"line\n".to_vec(), 1);
+        write_envelope(&mut archive, b"log", &text.describe(), &text).unwrap();
+        let empty = (vec![], None);
+        empty.write_portable(&mut archive).unwrap();
+
+        let mut reader = PortableReader::new(&archive[..]);
+        let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert_eq!(archive.len() as u64, reader.offset());
+        let labels: Vec<_> = read.iter().map(|extract| &extract.label[..]).collect();
+        assert_eq!(vec![&b"cam"[..], b"log", b""], labels);
+        assert_eq!(PortableBody::Text(b"line\n".to_vec()), read[1].body);
+        assert_eq!(PortableBody::Records(vec![]), read[2].body);
+        assert_eq!("records, 0 records, 0 bytes", read[2].descriptor.to_string());
+    }
+
+    fn archive() -> (Vec<u8>, usize) {
+        let mut archive = vec![];
+        let text = (b"text".to_vec(), 1);
+        text.write_portable(&mut archive).unwrap();
+        let second = archive.len();
+        file_stream().extract().map_err(|_| ()).unwrap().write_portable(&mut archive).unwrap();
+        (archive, second)
+    }
+
+    fn error_at(bytes: &[u8]) -> (u64, PortableErrorKind) {
+        let mut errors = PortableReader::new(bytes).filter_map(Result::err);
+        let error = errors.next().unwrap();
+        assert!(errors.next().is_none());
+        (error.offset, error.kind)
+    }
+
+    #[test]
+    fn corrupt_archives_report_offsets() {
+        let (archive, second) = archive();
+        let mut bytes = archive.clone();
+        bytes[second] = b'X';
+        assert_match!((o, PortableErrorKind::BadMagic) if o == second as u64, error_at(&bytes));
+
+        let mut bytes = archive.clone();
+        bytes[second + 4] = 9;
+        assert_match!((o, PortableErrorKind::UnsupportedVersion(9)) if o == second as u64 + 4,
+                      error_at(&bytes));
+
+        let mut bytes = archive.clone();
+        bytes[second + 5] = 0;
+        assert_match!((o, PortableErrorKind::UnknownKind(0)) if o == second as u64 + 5,
+                      error_at(&bytes));
+
+        let (o, kind) = error_at(&archive[..archive.len() - 1]);
+        assert_eq!(archive.len() as u64 - 1, o);
+        assert_match!(PortableErrorKind::Truncated, kind);
+
+        // The first record's length, claiming more than the body.
+        let mut bytes = archive.clone();
+        let body = archive.len() - records().iter().map(|r| 13 + r.1.len()).sum::<usize>();
+        bytes[body + 8] = 0xff;
+        assert_match!((o, PortableErrorKind::BadRecord) if o == body as u64, error_at(&bytes));
+
+        // The described count, one more than there are.
+        let mut bytes = archive.clone();
+        bytes[second + 13] += 1;
+        assert_match!((o, PortableErrorKind::RecordCount { described: 4, found: 3 })
+                          if o == body as u64,
+                      error_at(&bytes));
+        let e = read_portable(&bytes[second..]).unwrap_err();
+        assert_eq!(format!("4 records described but 3 found at byte {}", body - second),
+                   e.to_string());
+    }
+
+    #[test]
+    fn unknown_size_is_refused() {
+        struct Unsized;
+        impl ExtractEnvelope for Unsized {
+            fn describe(&self) -> ExtractDescriptor {
+                ExtractDescriptor::new(ExtractKind::Text)
+            }
+
+            fn write_body<W: Write>(&self, _: &mut W) -> io::Result<u64> {
+                Ok(0)
+            }
+        }
+        let mut bytes = vec![];
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   Unsized.write_portable(&mut bytes).unwrap_err().kind());
+        assert!(bytes.is_empty());
+    }
+}
@@ -0,0 +1,1503 @@
+//! Records on disk: each is an eight-byte big-endian timestamp in
+//! milliseconds, a four-byte big-endian length, the payload, then a status
+//! byte, `COMMITTED` for a record pushed whole. An index
+//! records the timestamp and offset of every so many records, sixteen bytes
+//! apiece, so that a time range can be read without scanning the whole file.
+//!
+//! A stream can also seal what it has written with a trailer, a record
+//! whose length is `TRAILER_SENTINEL` and whose forty bytes hold, each as a
+//! big-endian `u64`:
+//!
+//! | field           | value                                             |
+//! |-----------------|---------------------------------------------------|
+//! | records         | data records before it, whatever trailers between |
+//! | payload bytes   | of those records                                  |
+//! | first, last     | timestamps of the first and last of them in file  |
+//! |                 | order, in milliseconds, `u64::MAX` if none        |
+//! | hash            | FNV-1a over every byte before the trailer         |
+//!
+//! The timestamp of a trailer is zero. A stream reopened to append after a
+//! trailer writes a new one that supersedes it; readers skip trailers.
+//!
+//! A push writes its record's header, the intent, then the payload, and
+//! commits the record with its status byte. As the data is only ever
+//! appended to, the status byte comes last rather than being patched into
+//! the header. A push that fails partway through its record leaves the
+//! rest of it owing, and before writing anything else, the stream writes
+//! that rest with the status byte `VOIDED`. Readers skip a record whose
+//! status byte is not `COMMITTED`, so a failed push is never read back. A
+//! crash can cut a file short anywhere, leaving the record it cuts into
+//! uncommitted, and `intact_len` says where to truncate it to. A trailer
+//! has no status byte; one cut short by a failed write is finished before
+//! the next.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::Duration;
+use std::{cmp, mem, thread};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use util::{fill, fnv, millis, FNV_BASIS};
+use Stream;
+use super::Pressure;
+
+/// The bytes of a record before its payload.
+pub const RECORD_HEADER_LEN: usize = 12;
+/// The bytes of a record but its payload: the header, and the status byte
+/// after the payload.
+pub const RECORD_OVERHEAD: usize = RECORD_HEADER_LEN + 1;
+/// The status byte of a record pushed whole. Any other voids the record.
+pub const COMMITTED: u8 = 1;
+/// The status byte a failed push leaves.
+const VOIDED: u8 = 0;
+const ENTRY_LEN: usize = 16;
+
+/// The length that marks a trailer, which no payload can have.
+pub const TRAILER_SENTINEL: u32 = ::std::u32::MAX;
+/// Of a trailer, after its header.
+pub const TRAILER_LEN: usize = 40;
+const TRAILER_RECORD_LEN: u64 = (RECORD_HEADER_LEN + TRAILER_LEN) as u64;
+const NO_TIMESTAMP: u64 = ::std::u64::MAX;
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// The file ends within the record at `offset`.
+    Truncated {
+        offset: u64,
+    },
+    /// The index entry does not point at a record with its timestamp; the
+    /// range is read from an earlier entry instead.
+    CorruptIndex {
+        entry: usize,
+        offset: u64,
+    },
+    /// None of the entries the fallback may check, back from a corrupt
+    /// one, checks out, so the range is not read.
+    FallbackExhausted {
+        checked: usize,
+    },
+}
+
+impl ReadError {
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match *self {
+            ReadError::Io(ref e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ReadError::Io(ref e) => e.fmt(f),
+            ReadError::Truncated { offset } => write!(f, "truncated record at offset {}", offset),
+            ReadError::CorruptIndex { entry, offset } => {
+                write!(f,
+                       "index entry {} does not match the record at offset {}",
+                       entry,
+                       offset)
+            }
+            ReadError::FallbackExhausted { checked } => {
+                write!(f, "none of {} index entries checks out", checked)
+            }
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn description(&self) -> &str {
+        match *self {
+            ReadError::Io(ref e) => e.description(),
+            ReadError::Truncated { .. } => "truncated record",
+            ReadError::CorruptIndex { .. } => "corrupt index entry",
+            ReadError::FallbackExhausted { .. } => "no index entry checks out",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ReadError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What a trailer holds: the sums of the records before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trailer {
+    pub records: u64,
+    pub payload_bytes: u64,
+    pub first: Option<Duration>,
+    pub last: Option<Duration>,
+    pub hash: u64,
+}
+
+impl Trailer {
+    /// For an empty file.
+    pub fn new() -> Self {
+        Trailer {
+            records: 0,
+            payload_bytes: 0,
+            first: None,
+            last: None,
+            hash: FNV_BASIS,
+        }
+    }
+
+    fn add(&mut self, ts: Duration, payload_len: usize) {
+        self.records += 1;
+        self.payload_bytes += payload_len as u64;
+        if self.first.is_none() {
+            self.first = Some(ts);
+        }
+        self.last = Some(ts);
+    }
+
+    fn encode(&self) -> [u8; RECORD_HEADER_LEN + TRAILER_LEN] {
+        let mut bytes = [0_u8; RECORD_HEADER_LEN + TRAILER_LEN];
+        BigEndian::write_u32(&mut bytes[8..RECORD_HEADER_LEN], TRAILER_SENTINEL);
+        let stamp = |ts: Option<Duration>| ts.map_or(NO_TIMESTAMP, millis);
+        for (i, &n) in [self.records,
+                        self.payload_bytes,
+                        stamp(self.first),
+                        stamp(self.last),
+                        self.hash]
+                           .iter()
+                           .enumerate() {
+            BigEndian::write_u64(&mut bytes[RECORD_HEADER_LEN + i * 8..], n);
+        }
+        bytes
+    }
+
+    fn decode(body: &[u8]) -> Self {
+        let field = |i: usize| BigEndian::read_u64(&body[i * 8..]);
+        let stamp = |n: u64| if n == NO_TIMESTAMP { None } else { Some(Duration::from_millis(n)) };
+        Trailer {
+            records: field(0),
+            payload_bytes: field(1),
+            first: stamp(field(2)),
+            last: stamp(field(3)),
+            hash: field(4),
+        }
+    }
+
+    /// The first field in which `other` differs.
+    fn differs(&self, other: &Trailer) -> Option<TrailerField> {
+        if self.records != other.records {
+            Some(TrailerField::Records)
+        } else if self.payload_bytes != other.payload_bytes {
+            Some(TrailerField::PayloadBytes)
+        } else if self.first != other.first {
+            Some(TrailerField::First)
+        } else if self.last != other.last {
+            Some(TrailerField::Last)
+        } else if self.hash != other.hash {
+            Some(TrailerField::Hash)
+        } else {
+            None
+        }
+    }
+}
+
+enum Item {
+    Record(Duration, Vec<u8>),
+    Trailer(Trailer),
+    /// A record left uncommitted, with the length of its payload.
+    Voided(usize),
+}
+
+impl Item {
+    fn len(&self) -> u64 {
+        match *self {
+            Item::Record(_, ref payload) => record_len(payload.len()),
+            Item::Trailer(_) => TRAILER_RECORD_LEN,
+            Item::Voided(len) => record_len(len),
+        }
+    }
+}
+
+/// Reads the item at `offset`, or `None` at the end of the input.
+/// Reads the item at `offset` of data `end` bytes long. A record said to
+/// run past the end is cut short, and nothing is allocated for it.
+fn read_item<R: Read>(reader: &mut R, offset: u64, end: u64) -> Result<Option<Item>, ReadError> {
+    let mut header = [0_u8; RECORD_HEADER_LEN];
+    match try!(fill(reader, &mut header)) {
+        0 => return Ok(None),
+        RECORD_HEADER_LEN => {}
+        _ => return Err(ReadError::Truncated { offset: offset }),
+    }
+    let len = BigEndian::read_u32(&header[8..]);
+    if len == TRAILER_SENTINEL {
+        let mut body = [0_u8; TRAILER_LEN];
+        if try!(fill(reader, &mut body)) < TRAILER_LEN {
+            return Err(ReadError::Truncated { offset: offset });
+        }
+        return Ok(Some(Item::Trailer(Trailer::decode(&body))));
+    }
+    if offset + record_len(len as usize) > end {
+        return Err(ReadError::Truncated { offset: offset });
+    }
+    let ts = Duration::from_millis(BigEndian::read_u64(&header[..8]));
+    let mut payload = vec![0; len as usize + 1];
+    if try!(fill(reader, &mut payload)) < payload.len() {
+        return Err(ReadError::Truncated { offset: offset });
+    }
+    if payload.pop() != Some(COMMITTED) {
+        return Ok(Some(Item::Voided(len as usize)));
+    }
+    Ok(Some(Item::Record(ts, payload)))
+}
+
+/// Reads the records from `offset` on, skipping trailers and voided
+/// records.
+struct Records<'a, R: 'a> {
+    data: &'a mut R,
+    offset: u64,
+    /// The length of the data.
+    end: u64,
+}
+
+impl<'a, R: Read> Records<'a, R> {
+    fn new(data: &'a mut R, offset: u64, end: u64) -> Self {
+        Records {
+            data: data,
+            offset: offset,
+            end: end,
+        }
+    }
+
+    /// The next record and its offset, or `None` at the end of the input.
+    fn next_record(&mut self) -> Result<Option<(u64, Duration, Vec<u8>)>, ReadError> {
+        loop {
+            let at = self.offset;
+            match try!(read_item(self.data, at, self.end)) {
+                None => return Ok(None),
+                Some(item) => {
+                    self.offset += item.len();
+                    if let Item::Record(ts, payload) = item {
+                        return Ok(Some((at, ts, payload)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How much of `data` holds whole items, up to one a crash cut short at
+/// the end. A file is to be truncated to this before a stream appends to
+/// it.
+pub fn intact_len<R: Read + Seek>(data: &mut R) -> Result<u64, ReadError> {
+    let end = try!(data.seek(SeekFrom::End(0)));
+    try!(data.seek(SeekFrom::Start(0)));
+    let mut offset = 0;
+    loop {
+        match read_item(data, offset, end) {
+            Ok(None) | Err(ReadError::Truncated { .. }) => return Ok(offset),
+            Ok(Some(item)) => offset += item.len(),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Of a record with a payload of `len` bytes.
+pub fn record_len(len: usize) -> u64 {
+    (RECORD_OVERHEAD + len) as u64
+}
+
+/// The timestamp and offset of every so many records.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Index {
+    entries: Vec<(Duration, u64)>,
+}
+
+impl Index {
+    /// Indexes every `every`th record of `data`, starting with the first.
+    /// Panics if `every` is zero.
+    pub fn build<R: Read + Seek>(data: R, every: u64) -> Result<Self, ReadError> {
+        assert!(every > 0, "index must have an entry every so many records");
+        let mut data = data;
+        let end = try!(data.seek(SeekFrom::End(0)));
+        let offset = try!(data.seek(SeekFrom::Start(0)));
+        let mut index = Index::default();
+        let mut records = Records::new(&mut data, offset, end);
+        let mut n = 0;
+        while let Some((at, ts, _)) = try!(records.next_record()) {
+            if n % every == 0 {
+                index.entries.push((ts, at));
+            }
+            n += 1;
+        }
+        Ok(index)
+    }
+
+    /// Reads an index as `FileStream` writes it. A partial entry at the end,
+    /// from a write cut short, is ignored.
+    pub fn read<R: Read>(sidecar: R) -> io::Result<Self> {
+        let mut sidecar = sidecar;
+        let mut index = Index::default();
+        let mut entry = [0_u8; ENTRY_LEN];
+        while try!(fill(&mut sidecar, &mut entry)) == ENTRY_LEN {
+            index.entries.push((Duration::from_millis(BigEndian::read_u64(&entry[..8])),
+                                BigEndian::read_u64(&entry[8..])));
+        }
+        Ok(index)
+    }
+
+    pub fn write_to<W: Write>(&self, sidecar: W) -> io::Result<()> {
+        let mut sidecar = sidecar;
+        for &(ts, offset) in &self.entries {
+            try!(write_entry(&mut sidecar, ts, offset));
+        }
+        Ok(())
+    }
+
+    /// (timestamp, offset) in file order.
+    pub fn entries(&self) -> &[(Duration, u64)] {
+        &self.entries
+    }
+}
+
+fn entry(ts: Duration, offset: u64) -> [u8; ENTRY_LEN] {
+    let mut entry = [0_u8; ENTRY_LEN];
+    BigEndian::write_u64(&mut entry[..8], millis(ts));
+    BigEndian::write_u64(&mut entry[8..], offset);
+    entry
+}
+
+fn write_entry<W: Write>(sidecar: &mut W, ts: Duration, offset: u64) -> io::Result<()> {
+    sidecar.write_all(&entry(ts, offset))
+}
+
+/// Whether an error is worth retrying a write after: whether it would
+/// block, timed out or was interrupted, as on a full pipe or a slow
+/// network filesystem.
+pub fn is_transient(kind: io::ErrorKind) -> bool {
+    match kind {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => true,
+        _ => false,
+    }
+}
+
+/// How a `FileStream` retries a write that fails transiently: from where
+/// it left off, so nothing is written twice, until it has tried
+/// `attempts` times in all for a push, sleeping `backoff` before each
+/// retry. Any other error fails the push at once.
+pub struct Retry {
+    attempts: u32,
+    backoff: Duration,
+    transient: fn(io::ErrorKind) -> bool,
+    sleep: Box<FnMut(Duration) + Send>,
+}
+
+impl Retry {
+    /// Panics if `attempts` is zero.
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        assert!(attempts > 0, "a write must be attempted");
+        Retry {
+            attempts: attempts,
+            backoff: backoff,
+            transient: is_transient,
+            sleep: Box::new(thread::sleep),
+        }
+    }
+
+    /// Which errors to retry after, instead of those `is_transient` picks,
+    /// as where a full disk is expected to be cleared.
+    pub fn set_transient(&mut self, transient: fn(io::ErrorKind) -> bool) {
+        self.transient = transient;
+    }
+
+    /// Sleeps with `sleep` instead, to run on a test's clock.
+    pub fn set_sleeper<F: FnMut(Duration) + Send + 'static>(&mut self, sleep: F) {
+        self.sleep = Box::new(sleep);
+    }
+}
+
+impl fmt::Debug for Retry {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Retry")
+         .field("attempts", &self.attempts)
+         .field("backoff", &self.backoff)
+         .finish()
+    }
+}
+
+/// What became of a `FileStream`'s writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Writes retried after a transient failure.
+    pub retries: u64,
+    /// Records cut short by a failed push, and so voided.
+    pub voided: u64,
+}
+
+/// Appends records to `data`, and with `with_index`, an index entry to a
+/// sidecar every so many records. An entry the sidecar fails to take does
+/// not fail the push; it is owed, and written with the next or on flush.
+#[derive(Debug)]
+pub struct FileStream<W> {
+    data: W,
+    index: Option<(W, u64)>,
+    offset: u64,
+    records: u64,
+    /// Bytes of records pushed since the last flush.
+    unflushed: u64,
+    high_watermark: Option<(u64, Duration)>,
+    /// The sums so far, and every how many records to write a trailer.
+    trailer: Option<(Trailer, Option<u64>)>,
+    retry: Option<Retry>,
+    /// What a failed write left unwritten, to write before anything else.
+    owed: Vec<u8>,
+    /// Index entries, or what is left of one, a failed sidecar write left
+    /// unwritten.
+    index_owed: Vec<u8>,
+    stats: WriteStats,
+}
+
+impl<W: Write> FileStream<W> {
+    pub fn new(data: W) -> Self {
+        FileStream {
+            data: data,
+            index: None,
+            offset: 0,
+            records: 0,
+            unflushed: 0,
+            high_watermark: None,
+            trailer: None,
+            retry: None,
+            owed: vec![],
+            index_owed: vec![],
+            stats: WriteStats::default(),
+        }
+    }
+
+    /// For `data` that already holds `offset` bytes of records, as a file
+    /// opened to append.
+    pub fn appending(data: W, offset: u64) -> Self {
+        FileStream { offset: offset, ..FileStream::new(data) }
+    }
+
+    /// For `data` that already holds `offset` bytes of records ending in a
+    /// trailer, as a file opened to append, taking up its sums. `sums` are
+    /// as `RecordReader::verify` reports them for a verified file.
+    pub fn resuming(data: W, offset: u64, sums: Trailer) -> Self {
+        FileStream {
+            offset: offset,
+            trailer: Some((sums, None)),
+            ..FileStream::new(data)
+        }
+    }
+
+    /// Panics if `every` is zero.
+    pub fn with_index(data: W, sidecar: W, every: u64) -> Self {
+        assert!(every > 0, "index must have an entry every so many records");
+        FileStream { index: Some((sidecar, every)), ..FileStream::new(data) }
+    }
+
+    /// Once `bytes` of records are pushed without a flush, asks to be fed
+    /// more slowly, by `suggested_delay` a push, until flushed. For data
+    /// that buffers its writes, such as a `BufWriter`.
+    pub fn set_high_watermark(&mut self, bytes: u64, suggested_delay: Duration) {
+        self.high_watermark = Some((bytes, suggested_delay));
+    }
+
+    /// Writes a trailer on extraction, and with `every`, after every so many
+    /// records this stream pushes. The trailer covers only what is pushed
+    /// from now on unless the stream was made by `resuming`, so call this
+    /// before the first push. Panics if `every` is zero.
+    pub fn set_trailer(&mut self, every: Option<u64>) {
+        assert!(every != Some(0), "trailer must be written every so many records");
+        let sums = self.trailer.map_or_else(Trailer::new, |(sums, _)| sums);
+        self.trailer = Some((sums, every));
+    }
+
+    /// Retries writes that fail transiently as `retry` says; by default,
+    /// none are.
+    pub fn set_retry(&mut self, retry: Option<Retry>) {
+        self.retry = retry;
+    }
+
+    pub fn write_stats(&self) -> WriteStats {
+        self.stats
+    }
+
+    /// Writes a trailer now, if trailers are set, as a point to verify up
+    /// to. A trailer cut short is finished before the next write.
+    pub fn write_trailer(&mut self) -> io::Result<()> {
+        try!(self.settle());
+        let bytes = match self.trailer {
+            None => return Ok(()),
+            Some((ref sums, _)) => sums.encode(),
+        };
+        match self.write_retrying(&bytes, &mut 0) {
+            Ok(()) => Ok(()),
+            Err((written, e)) => {
+                self.owed = bytes[written..].to_vec();
+                Err(e)
+            }
+        }
+    }
+
+    /// The offset the next record will be written at, once anything owed
+    /// is written.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes what a failed write left owing, so that the records are
+    /// framed whole again.
+    fn settle(&mut self) -> io::Result<()> {
+        if self.owed.is_empty() {
+            return Ok(());
+        }
+        let owed = mem::replace(&mut self.owed, vec![]);
+        match self.write_retrying(&owed, &mut 0) {
+            Ok(()) => Ok(()),
+            Err((written, e)) => {
+                self.owed = owed[written..].to_vec();
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the index entries owed to the sidecar.
+    fn settle_index(&mut self) -> io::Result<()> {
+        let sidecar = match self.index {
+            Some((ref mut sidecar, _)) => sidecar,
+            None => return Ok(()),
+        };
+        while !self.index_owed.is_empty() {
+            match sidecar.write(&self.index_owed) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write index entry"))
+                }
+                Ok(n) => {
+                    self.index_owed.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes`, retrying as `retry` allows, where `failures` are
+    /// those so far of the same push. What is written is counted and hashed
+    /// whether or not it all is; on failure, how much was is returned too.
+    fn write_retrying(&mut self,
+                      bytes: &[u8],
+                      failures: &mut u32)
+                      -> Result<(), (usize, io::Error)> {
+        let mut written = 0;
+        let mut failure = None;
+        while written < bytes.len() {
+            match self.data.write(&bytes[written..]) {
+                Ok(0) => {
+                    failure = Some(io::Error::new(io::ErrorKind::WriteZero,
+                                                  "failed to write whole record"));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    *failures += 1;
+                    match self.retry {
+                        Some(ref mut retry) if *failures < retry.attempts &&
+                                               (retry.transient)(e.kind()) => {
+                            self.stats.retries += 1;
+                            (retry.sleep)(retry.backoff);
+                        }
+                        _ => {
+                            failure = Some(e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.offset += written as u64;
+        self.unflushed += written as u64;
+        if let Some((ref mut sums, _)) = self.trailer {
+            sums.hash = fnv(sums.hash, &bytes[..written]);
+        }
+        match failure {
+            None => Ok(()),
+            Some(e) => Err((written, e)),
+        }
+    }
+
+    /// Owes the rest of a record cut short after `written` bytes, with the
+    /// status byte voiding it. The status byte itself is never written when
+    /// the rest is not.
+    fn void(&mut self, header: &[u8], payload: &[u8], written: usize) {
+        if written == 0 {
+            return;
+        }
+        let mut owed = header[cmp::min(written, RECORD_HEADER_LEN)..].to_vec();
+        let body = cmp::max(written, RECORD_HEADER_LEN) - RECORD_HEADER_LEN;
+        owed.extend_from_slice(&payload[body..]);
+        owed.push(VOIDED);
+        self.owed = owed;
+        self.stats.voided += 1;
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.data
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.data
+    }
+
+    /// The data, and the sidecar if there is one.
+    pub fn into_inner(self) -> (W, Option<W>) {
+        (self.data, self.index.map(|(sidecar, _)| sidecar))
+    }
+
+    /// Flushes the data and the sidecar, after any index entries owed.
+    /// Extracting does this too.
+    pub fn flush(&mut self) -> io::Result<()> {
+        try!(self.data.flush());
+        try!(self.settle_index());
+        if let Some((ref mut sidecar, _)) = self.index {
+            try!(sidecar.flush());
+        }
+        self.unflushed = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write> Stream for FileStream<W> {
+    type PushErr = io::Error;
+    /// Fails with nothing read back if the record is cut short, whatever
+    /// was written of it.
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        if payload.len() as u64 >= TRAILER_SENTINEL as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "payload too large for a record"));
+        }
+        try!(self.settle());
+        let start = self.offset;
+        let mut header = [0_u8; RECORD_HEADER_LEN];
+        BigEndian::write_u64(&mut header[..8], millis(ts));
+        BigEndian::write_u32(&mut header[8..], payload.len() as u32);
+        let mut failures = 0;
+        let written = self.write_retrying(&header, &mut failures)
+                          .and_then(|()| {
+                              self.write_retrying(payload, &mut failures)
+                                  .map_err(|(n, e)| (RECORD_HEADER_LEN + n, e))
+                          })
+                          .and_then(|()| {
+                              self.write_retrying(&[COMMITTED], &mut failures)
+                                  .map_err(|(n, e)| (RECORD_HEADER_LEN + payload.len() + n, e))
+                          });
+        if let Err((written, e)) = written {
+            self.void(&header, payload, written);
+            return Err(e);
+        }
+        if let Some((_, every)) = self.index {
+            if self.records % every == 0 {
+                self.index_owed.extend_from_slice(&entry(ts, start));
+            }
+        }
+        self.records += 1;
+        let due = match self.trailer {
+            None => false,
+            Some((ref mut sums, every)) => {
+                sums.add(ts, payload.len());
+                every.map_or(false, |every| self.records % every == 0)
+            }
+        };
+        // The record is stored whatever becomes of its index entry and the
+        // trailer, which are owed if they fail.
+        let _ = self.settle_index();
+        if due {
+            let _ = self.write_trailer();
+        }
+        Ok(())
+    }
+
+    fn pressure(&self) -> Pressure {
+        match self.high_watermark {
+            Some((bytes, suggested_delay)) if self.unflushed >= bytes => {
+                Pressure::SlowDown { suggested_delay: suggested_delay }
+            }
+            _ => Pressure::None,
+        }
+    }
+
+    type Extract = (W, Option<W>);
+    type ExtractErr = io::Error;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let mut stream = self;
+        match stream.write_trailer().and_then(|()| stream.flush()) {
+            Ok(()) => Ok(stream.into_inner()),
+            Err(e) => Err((stream, e)),
+        }
+    }
+}
+
+/// Reads the records in a time range through an `Index`.
+///
+/// Timestamps may be out of order by up to the slack: a range starts from
+/// the last entry at least the slack before its start and ends at the first
+/// record at least the slack after its end.
+pub struct RecordReader<R> {
+    data: R,
+    index: Index,
+    slack: Duration,
+    max_fallback: usize,
+}
+
+/// How many entries a range checks, back from the one it would start
+/// from, before giving up on a corrupt index.
+pub const DEFAULT_MAX_FALLBACK: usize = 8;
+
+impl<R: Read + Seek> RecordReader<R> {
+    pub fn new(data: R, index: Index) -> Self {
+        RecordReader {
+            data: data,
+            index: index,
+            slack: Duration::from_secs(0),
+            max_fallback: DEFAULT_MAX_FALLBACK,
+        }
+    }
+
+    pub fn set_slack(&mut self, slack: Duration) {
+        self.slack = slack;
+    }
+
+    /// Bounds how many index entries a range checks, and so how far back
+    /// from its start it may read, at `entries`, at least one.
+    pub fn set_max_fallback(&mut self, entries: usize) {
+        self.max_fallback = cmp::max(entries, 1);
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.data
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.data
+    }
+
+    pub fn into_inner(self) -> R {
+        self.data
+    }
+
+    /// The records stamped from `from` inclusive to `to` exclusive, in file
+    /// order. If the index entry to start from is corrupt, the first item is
+    /// a `ReadError::CorruptIndex` and the range is read from the nearest
+    /// earlier entry that checks out, or else from the start of the file.
+    /// Only so many entries are checked: if none of them checks out and
+    /// there are earlier ones, the range ends with
+    /// `ReadError::FallbackExhausted` instead.
+    pub fn range(&mut self, from: Duration, to: Duration) -> Range<R> {
+        let target = if from > self.slack {
+            from - self.slack
+        } else {
+            Duration::from_secs(0)
+        };
+        let entries = &self.index.entries;
+        // The number of entries at or before `target`, so long as the
+        // entries are in order to within the slack.
+        let (mut lo, mut hi) = (0, entries.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if entries[mid].0 <= target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let mut warning = None;
+        let (end, mut start) = match self.data.seek(SeekFrom::End(0)) {
+            Ok(end) => (end, Ok(0)),
+            Err(e) => (0, Err(ReadError::Io(e))),
+        };
+        if start.is_ok() {
+            let checked = cmp::min(lo, self.max_fallback);
+            if lo > checked {
+                start = Err(ReadError::FallbackExhausted { checked: checked });
+            }
+            for i in (lo - checked..lo).rev() {
+                match check_entry(&mut self.data, end, entries, i) {
+                    Ok(true) => {
+                        start = Ok(entries[i].1);
+                        break;
+                    }
+                    Ok(false) => {
+                        if warning.is_none() {
+                            warning = Some(ReadError::CorruptIndex {
+                                entry: i,
+                                offset: entries[i].1,
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        start = Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let (offset, error) = match start {
+            Ok(offset) => {
+                match self.data.seek(SeekFrom::Start(offset)) {
+                    Ok(offset) => (offset, None),
+                    Err(e) => (0, Some(ReadError::Io(e))),
+                }
+            }
+            Err(e) => (0, Some(e)),
+        };
+        Range {
+            records: Records::new(&mut self.data, offset, end),
+            from: from,
+            to: to,
+            end: to + self.slack,
+            warning: warning,
+            error: error,
+            done: false,
+        }
+    }
+
+    /// Reads the whole file, checking its last trailer against what comes
+    /// before it. Earlier trailers are superseded and not checked.
+    pub fn verify(&mut self) -> VerifyReport {
+        let mut report = VerifyReport {
+            integrity: Integrity::NoTrailer,
+            superseded: 0,
+            incomplete: 0,
+            sums: Trailer::new(),
+        };
+        let end = match self.data.seek(SeekFrom::End(0)) {
+            Ok(end) => end,
+            Err(e) => {
+                report.integrity = Integrity::Io(e);
+                return report;
+            }
+        };
+        if let Err(e) = self.data.seek(SeekFrom::Start(0)) {
+            report.integrity = Integrity::Io(e);
+            return report;
+        }
+        let mut data = Hashing {
+            inner: &mut self.data,
+            hash: FNV_BASIS,
+        };
+        // The last trailer, where it ends, and what it should hold.
+        let mut last: Option<(Trailer, u64, Trailer)> = None;
+        let mut offset = 0;
+        let mut truncated = None;
+        loop {
+            let before = data.hash;
+            match read_item(&mut data, offset, end) {
+                Ok(None) => break,
+                Ok(Some(Item::Record(ts, payload))) => {
+                    report.sums.add(ts, payload.len());
+                    offset += record_len(payload.len());
+                }
+                Ok(Some(Item::Voided(len))) => {
+                    report.incomplete += 1;
+                    offset += record_len(len);
+                }
+                Ok(Some(Item::Trailer(found))) => {
+                    if last.is_some() {
+                        report.superseded += 1;
+                    }
+                    offset += TRAILER_RECORD_LEN;
+                    last = Some((found, offset, Trailer { hash: before, ..report.sums }));
+                }
+                Err(ReadError::Truncated { offset }) => {
+                    truncated = Some(offset);
+                    report.incomplete += 1;
+                    break;
+                }
+                Err(e) => {
+                    report.integrity = Integrity::Io(match e {
+                        ReadError::Io(e) => e,
+                        e => io::Error::new(io::ErrorKind::Other, e),
+                    });
+                    return report;
+                }
+            }
+        }
+        report.sums.hash = data.hash;
+
+        report.integrity = match last {
+            None => {
+                truncated.map_or(Integrity::NoTrailer,
+                                 |offset| Integrity::Truncated { offset: offset })
+            }
+            Some((found, end, expected)) => {
+                match expected.differs(&found) {
+                    Some(field) => {
+                        Integrity::Mismatch {
+                            field: field,
+                            offset: end - TRAILER_RECORD_LEN,
+                        }
+                    }
+                    None => {
+                        match self.data.seek(SeekFrom::End(0)) {
+                            Ok(len) if len > end => {
+                                Integrity::TrailingGarbage {
+                                    offset: end,
+                                    len: len - end,
+                                }
+                            }
+                            Ok(_) => Integrity::Verified,
+                            Err(e) => Integrity::Io(e),
+                        }
+                    }
+                }
+            }
+        };
+        report
+    }
+}
+
+/// What `RecordReader::verify` found.
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub integrity: Integrity,
+    /// Trailers before the last.
+    pub superseded: u64,
+    /// Records voided by a failed push, and a record or trailer a crash
+    /// cut short at the end.
+    pub incomplete: u64,
+    /// The sums of everything read, for `FileStream::resuming` to take up
+    /// once the file is verified.
+    pub sums: Trailer,
+}
+
+#[derive(Debug)]
+pub enum Integrity {
+    /// The last trailer matches, and nothing follows it.
+    Verified,
+    /// There is no trailer, as when the stream was never extracted.
+    NoTrailer,
+    /// The trailer at `offset` is wrong about `field`.
+    Mismatch { field: TrailerField, offset: u64 },
+    /// The last trailer matches, but `len` bytes written after it at
+    /// `offset` are not covered by one.
+    TrailingGarbage { offset: u64, len: u64 },
+    /// The file ends partway through the record at `offset`, with no
+    /// trailer before it.
+    Truncated { offset: u64 },
+    Io(io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailerField {
+    Records,
+    PayloadBytes,
+    First,
+    Last,
+    Hash,
+}
+
+/// Hashes whatever is read through it.
+struct Hashing<'a, R: 'a> {
+    inner: &'a mut R,
+    hash: u64,
+}
+
+impl<'a, R: Read> Read for Hashing<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        self.hash = fnv(self.hash, &buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Whether entry `i` is after the one before it and points at a record of
+/// its timestamp.
+fn check_entry<R: Read + Seek>(data: &mut R,
+                               end: u64,
+                               entries: &[(Duration, u64)],
+                               i: usize)
+                               -> Result<bool, ReadError> {
+    let (ts, offset) = entries[i];
+    if i > 0 && entries[i - 1].1 >= offset {
+        return Ok(false);
+    }
+    if offset >= end {
+        return Ok(false);
+    }
+    try!(data.seek(SeekFrom::Start(offset)));
+    match read_item(data, offset, end) {
+        Ok(Some(Item::Record(found, _))) => Ok(found == ts),
+        Ok(Some(_)) | Ok(None) | Err(ReadError::Truncated { .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The records of a `RecordReader::range`.
+pub struct Range<'a, R: 'a> {
+    records: Records<'a, R>,
+    from: Duration,
+    to: Duration,
+    end: Duration,
+    warning: Option<ReadError>,
+    error: Option<ReadError>,
+    done: bool,
+}
+
+impl<'a, R: Read> Iterator for Range<'a, R> {
+    type Item = Result<(Duration, Vec<u8>), ReadError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(warning) = self.warning.take() {
+            return Some(Err(warning));
+        }
+        if let Some(e) = self.error.take() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        while !self.done {
+            match self.records.next_record() {
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                Ok(Some((_, ts, payload))) => {
+                    if ts >= self.end {
+                        self.done = true;
+                    } else if self.from <= ts && ts < self.to {
+                        return Some(Ok((ts, payload)));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use Stream;
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// Writes a record stamped with each of `stamps`, indexing every third.
+    fn file(stamps: &[u64]) -> (Vec<u8>, Index) {
+        let mut stream = FileStream::with_index(vec![], vec![], 3);
+        for (i, &ts) in stamps.iter().enumerate() {
+            stream.push(millis(ts), format!("record {}", i).as_bytes()).unwrap();
+        }
+        let (data, sidecar) = stream.extract().unwrap();
+        (data, Index::read(&sidecar.unwrap()[..]).unwrap())
+    }
+
+    fn brute_force(data: &[u8], from: Duration, to: Duration) -> Vec<(Duration, Vec<u8>)> {
+        let mut reader = data;
+        let mut records = Records::new(&mut reader, 0, data.len() as u64);
+        let mut found = vec![];
+        while let Some((_, ts, payload)) = records.next_record().unwrap() {
+            if from <= ts && ts < to {
+                found.push((ts, payload));
+            }
+        }
+        found
+    }
+
+    fn query(reader: &mut RecordReader<Cursor<Vec<u8>>>,
+             from: u64,
+             to: u64)
+             -> Vec<(Duration, Vec<u8>)> {
+        reader.range(millis(from), millis(to)).map(Result::unwrap).collect()
+    }
+
+    const RANGES: &'static [(u64, u64)] = &[(0, 10000), (0, 0), (500, 500), (5000, 6000),
+                                             (100, 101), (230, 470), (0, 250), (880, 10000)];
+
+    #[test]
+    fn monotonic() {
+        let stamps: Vec<_> = (0..30).map(|i| i * 40).collect();
+        let (data, index) = file(&stamps);
+        assert_eq!(Index::build(Cursor::new(&data), 3).unwrap(), index);
+        assert_eq!(10, index.entries().len());
+        let mut reader = RecordReader::new(Cursor::new(data.clone()), index);
+        for &(from, to) in RANGES {
+            assert_eq!(brute_force(&data, millis(from), millis(to)),
+                       query(&mut reader, from, to),
+                       "{}..{}",
+                       from,
+                       to);
+        }
+        assert_eq!(30, query(&mut reader, 0, 10000).len());
+        assert!(query(&mut reader, 5000, 6000).is_empty());
+    }
+
+    #[test]
+    fn out_of_order_within_slack() {
+        let stamps: Vec<_> = (0..30)
+                                 .map(|i| i * 40 + [0, 70, 10, 90, 30][i as usize % 5])
+                                 .collect();
+        let (data, index) = file(&stamps);
+        let mut reader = RecordReader::new(Cursor::new(data.clone()), index);
+        reader.set_slack(millis(100));
+        for &(from, to) in RANGES {
+            assert_eq!(brute_force(&data, millis(from), millis(to)),
+                       query(&mut reader, from, to),
+                       "{}..{}",
+                       from,
+                       to);
+        }
+    }
+
+    #[test]
+    fn corrupt_entry() {
+        let stamps: Vec<_> = (0..30).map(|i| i * 40).collect();
+        let (data, index) = file(&stamps);
+        let mut sidecar = vec![];
+        index.write_to(&mut sidecar).unwrap();
+        // Entry 5 points at record 15, stamped 600ms; move it into record 14.
+        sidecar[5 * 16 + 15] -= 3;
+        let corrupt = Index::read(&sidecar[..]).unwrap();
+        let offset = corrupt.entries()[5].1;
+        let mut reader = RecordReader::new(Cursor::new(data.clone()), corrupt);
+
+        let mut results = reader.range(millis(630), millis(700));
+        match results.next() {
+            Some(Err(ReadError::CorruptIndex { entry: 5, offset: o })) => assert_eq!(offset, o),
+            _ => panic!("expected a corrupt entry"),
+        }
+        let rest: Vec<_> = results.map(Result::unwrap).collect();
+        assert_eq!(brute_force(&data, millis(630), millis(700)), rest);
+        assert_eq!(query(&mut reader, 0, 100), brute_force(&data, millis(0), millis(100)));
+    }
+
+    #[test]
+    fn fallback_is_bounded() {
+        let stamps: Vec<_> = (0..30).map(|i| i * 40).collect();
+        let (data, index) = file(&stamps);
+        let mut sidecar = vec![];
+        index.write_to(&mut sidecar).unwrap();
+        // Entries 4 and 5, at records 12 and 15, are a millisecond off.
+        sidecar[4 * 16 + 7] ^= 1;
+        sidecar[5 * 16 + 7] ^= 1;
+        let corrupt = Index::read(&sidecar[..]).unwrap();
+        let mut reader = RecordReader::new(Cursor::new(data.clone()), corrupt);
+        reader.set_max_fallback(2);
+
+        let results: Vec<_> = reader.range(millis(630), millis(700)).collect();
+        assert_eq!(2, results.len());
+        assert_match!(&Err(ReadError::CorruptIndex { entry: 5, .. }), &results[0]);
+        assert_match!(&Err(ReadError::FallbackExhausted { checked: 2 }), &results[1]);
+        reader.set_max_fallback(3);
+        let mut results = reader.range(millis(630), millis(700));
+        assert_match!(Some(Err(ReadError::CorruptIndex { entry: 5, .. })), results.next());
+        let rest: Vec<_> = results.map(Result::unwrap).collect();
+        assert_eq!(brute_force(&data, millis(630), millis(700)), rest);
+    }
+
+    #[test]
+    fn length_past_the_end() {
+        let (mut data, index) = file(&[0, 10]);
+        // The second record, at 21 bytes in, says it holds 2 GiB.
+        data[21 + 8] = 0x7f;
+        let mut reader = RecordReader::new(Cursor::new(data), index);
+        let results: Vec<_> = reader.range(millis(0), millis(100)).collect();
+        assert_eq!(2, results.len());
+        assert_match!(&Err(ReadError::Truncated { offset: 21 }), &results[1]);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let (mut data, index) = file(&[0, 10, 20]);
+        data.pop();
+        let mut reader = RecordReader::new(Cursor::new(data), index);
+        let results: Vec<_> = reader.range(millis(0), millis(100)).collect();
+        assert_eq!(3, results.len());
+        assert_match!(&Err(ReadError::Truncated { offset: 42 }), &results[2]);
+    }
+
+    fn sealed(stamps: &[u64]) -> Vec<u8> {
+        let mut stream = FileStream::new(vec![]);
+        stream.set_trailer(None);
+        for &ts in stamps {
+            stream.push(millis(ts), format!("at {}", ts).as_bytes()).unwrap();
+        }
+        stream.extract().unwrap().0
+    }
+
+    fn verify(data: Vec<u8>) -> VerifyReport {
+        RecordReader::new(Cursor::new(data), Index::default()).verify()
+    }
+
+    #[test]
+    fn verifies_trailer() {
+        let data = sealed(&[10, 30, 20]);
+        let report = verify(data.clone());
+        assert_match!(Integrity::Verified, report.integrity);
+        assert_eq!(0, report.superseded);
+        assert_eq!((3, Some(millis(10)), Some(millis(20))),
+                   (report.sums.records, report.sums.first, report.sums.last));
+        assert_eq!(brute_force(&data, millis(0), millis(100)).len(), 3);
+        let index = Index::build(Cursor::new(&data), 1).unwrap();
+        assert_eq!(3, index.entries().len());
+        let mut reader = RecordReader::new(Cursor::new(data), index);
+        assert_eq!(vec![millis(30)],
+                   query(&mut reader, 25, 100).into_iter().map(|r| r.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut data = sealed(&[10, 20]);
+        // The second payload.
+        data[record_len(5) as usize + RECORD_HEADER_LEN] ^= 1;
+        assert_match!(Integrity::Mismatch { field: TrailerField::Hash, offset: 36 },
+                      verify(data).integrity);
+
+        let mut data = sealed(&[10, 20]);
+        let len = data.len();
+        data.truncate(len - 10);
+        assert_match!(Integrity::Truncated { offset: 36 }, verify(data).integrity);
+
+        let mut stream = FileStream::new(vec![]);
+        stream.push(millis(0), b"never sealed").unwrap();
+        assert_match!(Integrity::NoTrailer, verify(stream.into_inner().0).integrity);
+
+        let mut data = sealed(&[10]);
+        data.extend_from_slice(&[0; 12]);
+        assert_match!(Integrity::TrailingGarbage { len: 12, .. }, verify(data).integrity);
+    }
+
+    #[test]
+    fn resumes_after_trailer() {
+        let data = sealed(&[10, 20]);
+        let report = verify(data.clone());
+        let offset = data.len() as u64;
+        let mut stream = FileStream::resuming(data, offset, report.sums);
+        stream.set_trailer(Some(2));
+        for ts in 30..33 {
+            stream.push(millis(ts), b"more").unwrap();
+        }
+        let report = verify(stream.extract().unwrap().0);
+        assert_match!(Integrity::Verified, report.integrity);
+        assert_eq!((2, 5), (report.superseded, report.sums.records));
+    }
+
+    /// Takes writes until `at` bytes are written, then fails the next
+    /// `times` with `kind`.
+    #[derive(Debug)]
+    struct Failing {
+        written: Vec<u8>,
+        at: usize,
+        times: u32,
+        kind: io::ErrorKind,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = self.written.len();
+            if len == self.at && self.times > 0 {
+                self.times -= 1;
+                return Err(io::Error::new(self.kind, "scripted"));
+            }
+            let n = if len < self.at { cmp::min(buf.len(), self.at - len) } else { buf.len() };
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const A: &'static [u8] = b"first";
+    const B: &'static [u8] = b"second";
+    const C: &'static [u8] = b"third";
+
+    fn failing(at: usize, times: u32, kind: io::ErrorKind) -> FileStream<Failing> {
+        let mut stream = FileStream::new(Failing {
+            written: vec![],
+            at: at,
+            times: times,
+            kind: kind,
+        });
+        stream.set_trailer(None);
+        stream
+    }
+
+    /// The payloads read before the first error.
+    fn readable(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = data;
+        let mut records = Records::new(&mut reader, 0, data.len() as u64);
+        let mut found = vec![];
+        while let Ok(Some((_, _, payload))) = records.next_record() {
+            found.push(payload);
+        }
+        found
+    }
+
+    /// Every offset within B, where pushed after A.
+    fn within_b() -> ::std::ops::Range<usize> {
+        let start = record_len(A.len()) as usize;
+        start..start + record_len(B.len()) as usize
+    }
+
+    #[test]
+    fn voids_a_push_cut_short_anywhere() {
+        for at in within_b() {
+            for &times in &[1, 2] {
+                let mut stream = failing(at, times, io::ErrorKind::Other);
+                stream.push(millis(1), A).unwrap();
+                assert_eq!(io::ErrorKind::Other, stream.push(millis(2), B).unwrap_err().kind());
+                // A second failure is met finishing B, before C is begun.
+                if stream.push(millis(3), C).is_err() {
+                    assert_eq!(2, times);
+                    stream.push(millis(4), C).unwrap();
+                }
+                let voided = (at > within_b().start) as u64;
+                assert_eq!(voided, stream.write_stats().voided);
+
+                let data = stream.extract().unwrap().0.written;
+                assert_eq!(vec![A.to_vec(), C.to_vec()], readable(&data), "failing at {}", at);
+                let report = verify(data.clone());
+                assert_match!(Integrity::Verified, report.integrity);
+                assert_eq!((voided, 2), (report.incomplete, report.sums.records));
+                assert_eq!(2, Index::build(Cursor::new(&data), 1).unwrap().entries().len());
+            }
+        }
+    }
+
+    #[test]
+    fn retries_transient_failures_from_where_they_left_off() {
+        for at in within_b() {
+            for &(times, stored) in &[(2, true), (3, false)] {
+                let sleeps = Arc::new(Mutex::new(vec![]));
+                let slept = sleeps.clone();
+                let mut retry = Retry::new(3, millis(5));
+                retry.set_sleeper(move |d| slept.lock().unwrap().push(d));
+                let mut stream = failing(at, times, io::ErrorKind::WouldBlock);
+                stream.set_retry(Some(retry));
+                stream.push(millis(1), A).unwrap();
+                assert_eq!(stored, stream.push(millis(2), B).is_ok(), "failing at {}", at);
+                stream.push(millis(3), C).unwrap();
+                assert_eq!(vec![millis(5); 2], *sleeps.lock().unwrap());
+                assert_eq!(2, stream.write_stats().retries);
+
+                let data = stream.extract().unwrap().0.written;
+                let expected: Vec<_> = if stored { vec![A, B, C] } else { vec![A, C] };
+                assert_eq!(expected, readable(&data));
+                assert_match!(Integrity::Verified, verify(data).integrity);
+            }
+        }
+
+        assert!(is_transient(io::ErrorKind::Interrupted));
+        let mut stream = failing(0, 1, io::ErrorKind::PermissionDenied);
+        stream.set_retry(Some(Retry::new(3, millis(5))));
+        assert!(stream.push(millis(1), A).is_err());
+        stream.push(millis(2), B).unwrap();
+        assert_eq!(WriteStats::default(), stream.write_stats());
+        assert_eq!(vec![B.to_vec()], readable(&stream.into_inner().0.written));
+    }
+
+    #[test]
+    fn owes_index_entries_the_sidecar_fails() {
+        let data = Failing {
+            written: vec![],
+            at: ::std::usize::MAX,
+            times: 0,
+            kind: io::ErrorKind::Other,
+        };
+        let sidecar = Failing {
+            written: vec![],
+            at: ENTRY_LEN + 5,
+            times: 3,
+            kind: io::ErrorKind::Other,
+        };
+        let mut stream = FileStream::with_index(data, sidecar, 1);
+        stream.set_trailer(None);
+        stream.push(millis(1), A).unwrap();
+        stream.push(millis(2), B).unwrap();
+        stream.push(millis(3), C).unwrap();
+        let stream = match stream.extract() {
+            Err((stream, e)) => {
+                assert_eq!(io::ErrorKind::Other, e.kind());
+                stream
+            }
+            Ok(_) => panic!("the sidecar should still be failing"),
+        };
+        let (data, sidecar) = stream.extract().unwrap();
+        let report = verify(data.written.clone());
+        assert_match!(Integrity::Verified, report.integrity);
+        assert_eq!(3, report.sums.records);
+        assert_eq!(Index::build(Cursor::new(&data.written), 1).unwrap().entries(),
+                   Index::read(&sidecar.unwrap().written[..]).unwrap().entries());
+    }
+
+    #[test]
+    fn verify_counts_what_a_crash_cut_short() {
+        let b = within_b();
+        let mut stream = failing(b.start + 5, 1, io::ErrorKind::Other);
+        stream.push(millis(1), A).unwrap();
+        stream.push(millis(2), B).unwrap_err();
+        stream.push(millis(3), C).unwrap();
+        let data = stream.extract().unwrap().0.written;
+        let c_end = b.end + record_len(C.len()) as usize;
+
+        for len in 0..data.len() + 1 {
+            let (incomplete, expected): (u64, Vec<&[u8]>) = match len {
+                0 => (0, vec![]),
+                n if n < b.start => (1, vec![]),
+                n if n == b.start => (0, vec![A]),
+                // However B is cut, it is never read back.
+                n if n <= b.end => (1, vec![A]),
+                n if n < c_end => (2, vec![A]),
+                n if n == c_end => (1, vec![A, C]),
+                n if n < data.len() => (2, vec![A, C]),
+                _ => (1, vec![A, C]),
+            };
+            let cut = &data[..len];
+            assert_eq!(expected, readable(cut), "cut to {}", len);
+            assert_eq!(incomplete, verify(cut.to_vec()).incomplete, "cut to {}", len);
+
+            let intact = intact_len(&mut Cursor::new(cut)).unwrap() as usize;
+            assert_eq!(expected, readable(&cut[..intact]), "cut to {}", len);
+            match verify(cut[..intact].to_vec()).integrity {
+                Integrity::Verified | Integrity::NoTrailer => {}
+                other => panic!("cut to {}, then to {}: {:?}", len, intact, other),
+            }
+        }
+    }
+
+    #[test]
+    fn slows_down_past_high_watermark() {
+        let mut stream = FileStream::new(vec![]);
+        stream.set_high_watermark(30, millis(5));
+        stream.push(millis(0), b"sixteen bytes...").unwrap();
+        assert_eq!(Pressure::None, stream.pressure());
+        stream.push(millis(1), b"sixteen bytes...").unwrap();
+        assert_eq!(Pressure::SlowDown { suggested_delay: millis(5) }, stream.pressure());
+        stream.flush().unwrap();
+        assert_eq!(Pressure::None, stream.pressure());
+    }
+}
@@ -0,0 +1,368 @@
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use Clock;
+use super::{DeadlineError, DeadlineExtract, DrainReport, Pressure, Stream, StreamingStream};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GuardedError<E> {
+    /// Pushes were still intended; the stream was handed back untouched.
+    Busy {
+        intents: usize,
+    },
+    Extract(E),
+}
+
+impl<E> GuardedError<E> {
+    pub fn is_busy(&self) -> bool {
+        match *self {
+            GuardedError::Busy { .. } => true,
+            GuardedError::Extract(_) => false,
+        }
+    }
+}
+
+impl<E: Display> Display for GuardedError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            GuardedError::Busy { intents } => {
+                write!(f, "stream busy with {} push intents", intents)
+            }
+            GuardedError::Extract(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for GuardedError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            GuardedError::Busy { .. } => "stream busy",
+            GuardedError::Extract(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            GuardedError::Busy { .. } => None,
+            GuardedError::Extract(ref e) => Some(e),
+        }
+    }
+}
+
+/// Says a push to a `Guarded` stream is on its way until dropped.
+#[derive(Debug)]
+pub struct PushIntent(Arc<AtomicUsize>);
+
+impl PushIntent {
+    pub fn end(self) {}
+}
+
+impl Drop for PushIntent {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A stream that refuses to be extracted while a push to it is intended,
+/// so that whoever looked it up to push to it does not find it gone.
+///
+/// Intents are counted atomically, so one can be held on another thread
+/// than the stream.
+#[derive(Debug, Default)]
+pub struct Guarded<S> {
+    stream: S,
+    intents: Arc<AtomicUsize>,
+}
+
+impl<S> Guarded<S> {
+    pub fn new(stream: S) -> Self {
+        Guarded {
+            stream: stream,
+            intents: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Holds off extraction until the intent is dropped. `push`,
+    /// `push_owned` and `begin` take one for as long as they run, or the
+    /// payload is being written; take one yourself before looking the
+    /// stream up to push to it from elsewhere, and end it once the push is
+    /// done.
+    pub fn begin_push_intent(&self) -> PushIntent {
+        self.intents.fetch_add(1, Ordering::SeqCst);
+        PushIntent(self.intents.clone())
+    }
+
+    pub fn push_intents(&self) -> usize {
+        self.intents.load(Ordering::SeqCst)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream> Stream for Guarded<S> {
+    type PushErr = S::PushErr;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        let _intent = self.begin_push_intent();
+        self.stream.push(ts, payload)
+    }
+
+    fn push_owned(&mut self, ts: Duration, payload: Vec<u8>) -> Result<(), Self::PushErr> {
+        let _intent = self.begin_push_intent();
+        self.stream.push_owned(ts, payload)
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.push_intents() > 0 || self.stream.is_busy()
+    }
+
+    type Extract = S::Extract;
+    type ExtractErr = GuardedError<S::ExtractErr>;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let intents = self.push_intents();
+        if intents > 0 {
+            return Err((self, GuardedError::Busy { intents: intents }));
+        }
+        let guard = self.intents;
+        self.stream.extract().map_err(|(stream, e)| {
+            (Guarded {
+                stream: stream,
+                intents: guard,
+            },
+             GuardedError::Extract(e))
+        })
+    }
+}
+
+impl<S: DeadlineExtract> DeadlineExtract for Guarded<S> {
+    fn extract_by<C: Clock>(self,
+                            clock: &C,
+                            deadline: Duration)
+                            -> Result<Self::Extract, (Self, DeadlineError<Self::ExtractErr>)> {
+        let intents = self.push_intents();
+        if intents > 0 {
+            return Err((self, DeadlineError::Extract(GuardedError::Busy { intents: intents })));
+        }
+        let guard = self.intents;
+        self.stream.extract_by(clock, deadline).map_err(|(stream, e)| {
+            let e = match e {
+                DeadlineError::TimedOut { waited } => DeadlineError::TimedOut { waited: waited },
+                DeadlineError::Extract(e) => DeadlineError::Extract(GuardedError::Extract(e)),
+            };
+            (Guarded {
+                stream: stream,
+                intents: guard,
+            },
+             e)
+        })
+    }
+}
+
+/// A payload in progress, which holds off extraction until it is committed
+/// or aborted.
+pub struct GuardedWriter<W> {
+    writer: W,
+    _intent: PushIntent,
+}
+
+impl<W: Write> Write for GuardedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<S: StreamingStream> StreamingStream for Guarded<S> {
+    type Writer = GuardedWriter<S::Writer>;
+    fn begin(&mut self, ts: Duration, total_len: u64) -> Result<Self::Writer, Self::PushErr> {
+        let intent = self.begin_push_intent();
+        self.stream.begin(ts, total_len).map(|writer| {
+            GuardedWriter {
+                writer: writer,
+                _intent: intent,
+            }
+        })
+    }
+
+    fn commit(&mut self, writer: Self::Writer) -> Result<(), Self::PushErr> {
+        self.stream.commit(writer.writer)
+    }
+
+    fn abort(&mut self, writer: Self::Writer) {
+        self.stream.abort(writer.writer)
+    }
+}
+
+impl<S: Stream> DrainReport<Guarded<S>> {
+    /// Takes the IDs that failed only for being busy out of `failed`; they
+    /// are still registered, to be drained again later.
+    pub fn take_deferred(&mut self) -> Vec<Vec<u8>> {
+        let (deferred, failed) = self.failed.drain(..).partition(|&(_, ref e)| e.is_busy());
+        self.failed = failed;
+        deferred.into_iter().map(|(id, _)| id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::prelude::*;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use server::{Reaper, TokenServer};
+    use stream::drain_by;
+    use test_support::stream as mocks;
+    use Server;
+
+    /// Extracts everything pushed to it.
+    #[derive(Debug, Default)]
+    struct Recording(Vec<Vec<u8>>);
+
+    impl Stream for Recording {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, payload: &[u8]) -> Result<(), ::Void> {
+            self.0.push(payload.to_vec());
+            Ok(())
+        }
+
+        type Extract = Vec<Vec<u8>>;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, ::Void)> {
+            Ok(self.0)
+        }
+    }
+
+    impl StreamingStream for Recording {
+        type Writer = Vec<u8>;
+        fn begin(&mut self, _: Duration, _: u64) -> Result<Vec<u8>, ::Void> {
+            Ok(vec![])
+        }
+
+        fn commit(&mut self, writer: Vec<u8>) -> Result<(), ::Void> {
+            self.0.push(writer);
+            Ok(())
+        }
+
+        fn abort(&mut self, _: Vec<u8>) {}
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn reap_defers_busy_streams() {
+        let a = Guarded::new(Recording::default());
+        // A push to "a" is on its way from before the reaper runs until
+        // after.
+        let intent = a.begin_push_intent();
+        let mut tokens = TokenServer::new();
+        tokens.add_token(b"t").insert(b"a".to_vec(), a);
+        tokens.add_token(b"t").insert(b"b".to_vec(), Guarded::new(Recording::default()));
+        let mut server = Reaper::new(tokens);
+        assert!(server.consume_parts(b"t", b"a", secs(1), b"one").is_ok());
+        assert!(server.consume_parts(b"t", b"b", secs(1), b"one").is_ok());
+
+        let mut report = server.reap(secs(5), secs(10));
+        assert_eq!(vec![(b"t".to_vec(), b"a".to_vec())], report.take_deferred());
+        assert!(report.failed.is_empty());
+        assert_eq!(vec![(b"t".to_vec(), b"b".to_vec(), vec![b"one".to_vec()])],
+                   report.reaped);
+        assert!(server.consume_parts(b"t", b"a", secs(11), b"two").is_ok());
+        intent.end();
+
+        let mut report = server.reap(secs(5), secs(20));
+        assert!(report.take_deferred().is_empty());
+        assert_eq!(vec![(b"t".to_vec(), b"a".to_vec(), vec![b"one".to_vec(), b"two".to_vec()])],
+                   report.reaped);
+    }
+
+    #[test]
+    fn drain_defers_busy_streams() {
+        let clock = ManualClock::new(secs(0));
+        let mut streams: HashMap<_, _> = vec![(b"a".to_vec(), Guarded::new(mocks::Ok)),
+                                              (b"b".to_vec(), Guarded::new(mocks::Ok))]
+                                             .into_iter()
+                                             .collect();
+        let intents = (streams[&b"a"[..]].begin_push_intent(),
+                       streams[&b"a"[..]].begin_push_intent());
+        let mut report = drain_by(&mut streams, &clock, secs(1), secs(10));
+        assert_eq!(vec![b"a".to_vec()], report.take_deferred());
+        assert_eq!(vec![b"b".to_vec()],
+                   report.extracted.into_iter().map(|(id, _)| id).collect::<Vec<_>>());
+        assert_eq!(2, streams[&b"a"[..]].push_intents());
+        drop(intents);
+        assert!(streams.remove(&b"a"[..]).unwrap().extract().is_ok());
+    }
+
+    /// Notes how many intents its guard held at each push.
+    #[derive(Debug, Default)]
+    struct Counting {
+        intents: Option<Arc<AtomicUsize>>,
+        seen: Vec<usize>,
+    }
+
+    impl Stream for Counting {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ::Void> {
+            self.seen.push(self.intents.as_ref().unwrap().load(Ordering::SeqCst));
+            Ok(())
+        }
+
+        type Extract = Vec<usize>;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, ::Void)> {
+            Ok(self.seen)
+        }
+    }
+
+    #[test]
+    fn push_holds_intent() {
+        let mut stream = Guarded::new(Counting::default());
+        stream.stream.intents = Some(stream.intents.clone());
+        stream.push(secs(1), b"one").unwrap();
+        let intent = stream.begin_push_intent();
+        stream.push_owned(secs(2), b"two".to_vec()).unwrap();
+        drop(intent);
+        assert_eq!(0, stream.push_intents());
+        assert_eq!(Ok(vec![1, 2]), stream.extract().map_err(|(_, e)| e));
+    }
+
+    #[test]
+    fn writer_holds_intent() {
+        let mut stream = Guarded::new(Recording::default());
+        let mut writer = stream.begin(secs(1), 2).unwrap();
+        writer.write_all(b"hi").unwrap();
+        let mut stream = match stream.extract() {
+            Err((stream, GuardedError::Busy { intents: 1 })) => stream,
+            _ => panic!("expected busy"),
+        };
+        stream.commit(writer).unwrap();
+        assert_eq!(0, stream.push_intents());
+        assert_eq!(Ok(vec![b"hi".to_vec()]), stream.extract().map_err(|(_, e)| e));
+    }
+}
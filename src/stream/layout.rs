@@ -0,0 +1,503 @@
+//! Where `FileStream` data files live under a root directory: one per ID,
+//! named by the ID in lowercase hex with a `.rec` extension, either all in
+//! the root or fanned out into directories by a hash of the ID.
+//!
+//! `migrate_layout` moves files from one layout to another. Before each
+//! move, it writes the move to a journal in the root, by writing a
+//! temporary file and renaming it into place, so a migration cut short can
+//! be run again to finish the move it was in the middle of.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use server::protection::fingerprint;
+use super::file::{intact_len, FileStream, Index, ReadError};
+
+const EXTENSION: &'static str = "rec";
+const JOURNAL: &'static str = "migration.journal";
+const JOURNAL_TMP: &'static str = "migration.journal.tmp";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()).collect()
+}
+
+/// The ID a data file is named for.
+fn id_of(path: &Path) -> Option<Vec<u8>> {
+    if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+        return None;
+    }
+    path.file_stem().and_then(|stem| stem.to_str()).and_then(unhex)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageLayout {
+    /// Every file in the root.
+    Flat,
+    /// Files `levels` directories down, each named by the next `width` hex
+    /// digits of a 64-bit hash of the ID, lowest byte first, as its low
+    /// bits spread short IDs best.
+    Fanout {
+        levels: usize,
+        width: usize,
+    },
+}
+
+impl StorageLayout {
+    /// Where the file for `id` belongs. Panics if a fanout needs more than
+    /// the hash's 16 hex digits.
+    pub fn path(&self, root: &Path, id: &[u8]) -> PathBuf {
+        let mut path = root.to_path_buf();
+        if let StorageLayout::Fanout { levels, width } = *self {
+            assert!(levels * width <= 16, "fanout needs more than 16 hex digits");
+            let hash = format!("{:016x}", fingerprint(&[id]).swap_bytes());
+            for level in 0..levels {
+                path.push(&hash[level * width..(level + 1) * width]);
+            }
+        }
+        path.push(format!("{}.{}", hex(id), EXTENSION));
+        path
+    }
+}
+
+/// Opens the `FileStream` for an ID under a root, where the layout puts it.
+#[derive(Clone, Debug)]
+pub struct FileStreamFactory {
+    root: PathBuf,
+    layout: StorageLayout,
+    previous: Option<StorageLayout>,
+}
+
+impl FileStreamFactory {
+    pub fn new<P: AsRef<Path>>(root: P, layout: StorageLayout) -> Self {
+        FileStreamFactory {
+            root: root.as_ref().to_path_buf(),
+            layout: layout,
+            previous: None,
+        }
+    }
+
+    /// While set, files are also looked for where `previous` put them, for
+    /// use while a migration from it is under way. New files always go
+    /// where the current layout puts them.
+    pub fn set_transition(&mut self, previous: Option<StorageLayout>) {
+        self.previous = previous;
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layout(&self) -> StorageLayout {
+        self.layout
+    }
+
+    /// The existing file for `id`, if any.
+    pub fn locate(&self, id: &[u8]) -> Option<PathBuf> {
+        let current = self.layout.path(&self.root, id);
+        if current.is_file() {
+            return Some(current);
+        }
+        self.previous
+            .map(|previous| previous.path(&self.root, id))
+            .and_then(|path| if path.is_file() { Some(path) } else { None })
+    }
+
+    /// A stream appending to the file for `id`, created if need be, and cut
+    /// back to its `intact_len` if a crash left it cut short.
+    pub fn open(&self, id: &[u8]) -> io::Result<FileStream<File>> {
+        let path = match self.locate(id) {
+            Some(path) => path,
+            None => {
+                let path = self.layout.path(&self.root, id);
+                if let Some(parent) = path.parent() {
+                    try!(fs::create_dir_all(parent));
+                }
+                path
+            }
+        };
+        let mut file = try!(OpenOptions::new().read(true).append(true).create(true).open(&path));
+        let len = try!(intact_len(&mut file).map_err(|e| {
+            match e {
+                ReadError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e),
+            }
+        }));
+        if len < try!(file.metadata()).len() {
+            try!(file.set_len(len));
+        }
+        Ok(FileStream::appending(file, len))
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Io(io::Error),
+    /// The journal does not name a move.
+    Journal,
+    /// The records of the file for the ID read differently after the move.
+    Changed {
+        id: Vec<u8>,
+    },
+}
+
+impl From<io::Error> for MigrateError {
+    fn from(e: io::Error) -> Self {
+        MigrateError::Io(e)
+    }
+}
+
+impl Display for MigrateError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            MigrateError::Io(ref e) => e.fmt(f),
+            MigrateError::Journal => f.write_str("migration journal names no move"),
+            MigrateError::Changed { ref id } => {
+                write!(f, "records for ID {} changed in the move", hex(id))
+            }
+        }
+    }
+}
+
+impl error::Error for MigrateError {
+    fn description(&self) -> &str {
+        match *self {
+            MigrateError::Io(ref e) => e.description(),
+            MigrateError::Journal => "migration journal names no move",
+            MigrateError::Changed { .. } => "records changed in the move",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            MigrateError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// IDs by what a migration did with their files, in path order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub moved: Vec<Vec<u8>>,
+    /// Already where the new layout puts them.
+    pub skipped: Vec<Vec<u8>>,
+    /// Left where they were, their records not framed properly.
+    pub corrupt: Vec<Vec<u8>>,
+    /// Left where they were, a file for the same ID being in the new
+    /// layout already.
+    pub conflicts: Vec<Vec<u8>>,
+}
+
+/// Reads every record of the file, or says why not.
+fn verify(path: &Path) -> Result<Index, ReadError> {
+    Index::build(try!(File::open(path)), 1)
+}
+
+fn files_under(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.is_dir() {
+            try!(files_under(&path, files));
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the empty directories from `dir` up to but excluding `root`.
+fn prune(root: &Path, dir: Option<&Path>) {
+    let mut dir = dir;
+    while let Some(d) = dir {
+        if d == root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+fn write_journal(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    let tmp = root.join(JOURNAL_TMP);
+    {
+        let mut journal = try!(File::create(&tmp));
+        try!(write!(journal, "{}\n{}\n", from.display(), to.display()));
+        try!(journal.sync_all());
+    }
+    fs::rename(&tmp, root.join(JOURNAL))
+}
+
+fn read_journal(root: &Path) -> Result<Option<(PathBuf, PathBuf)>, MigrateError> {
+    let mut contents = String::new();
+    match File::open(root.join(JOURNAL)) {
+        Ok(mut journal) => {
+            try!(journal.read_to_string(&mut contents));
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut lines = contents.lines();
+    match (lines.next(), lines.next(), lines.next()) {
+        (Some(from), Some(to), None) => Ok(Some((PathBuf::from(from), PathBuf::from(to)))),
+        _ => Err(MigrateError::Journal),
+    }
+}
+
+/// Moves the file for `id`, checking its records read the same after.
+fn move_file(root: &Path, id: Vec<u8>, from: &Path, to: &Path, report: &mut MigrationReport)
+             -> Result<(), MigrateError> {
+    let before = match verify(from) {
+        Ok(index) => index,
+        Err(ReadError::Io(e)) => return Err(e.into()),
+        Err(_) => {
+            report.corrupt.push(id);
+            return Ok(());
+        }
+    };
+    if let Some(parent) = to.parent() {
+        try!(fs::create_dir_all(parent));
+    }
+    try!(fs::rename(from, to));
+    prune(root, from.parent());
+    match verify(to) {
+        Ok(ref after) if *after == before => {}
+        _ => return Err(MigrateError::Changed { id: id }),
+    }
+    report.moved.push(id);
+    Ok(())
+}
+
+/// Finishes the move a journal names, if it was not done, returning where
+/// the file went if it was.
+fn finish_journaled(root: &Path, report: &mut MigrationReport)
+                    -> Result<Option<PathBuf>, MigrateError> {
+    let (from, to) = match try!(read_journal(root)) {
+        Some(journaled) => journaled,
+        None => return Ok(None),
+    };
+    let id = try!(id_of(&from).ok_or(MigrateError::Journal));
+    let finished = if from.is_file() && !to.exists() {
+        try!(move_file(root, id, &from, &to, report));
+        Some(to)
+    } else {
+        None
+    };
+    try!(fs::remove_file(root.join(JOURNAL)));
+    Ok(finished)
+}
+
+/// The migration, stopped as if by a crash once `crash_after` files have
+/// moved: after journaling the next move, before making it.
+fn migrate(root: &Path,
+           from: StorageLayout,
+           to: StorageLayout,
+           report: &mut MigrationReport,
+           crash_after: Option<usize>)
+           -> Result<(), MigrateError> {
+    let finished = try!(finish_journaled(root, report));
+    let mut files = vec![];
+    try!(files_under(root, &mut files));
+    files.sort();
+    let mut moves = 0;
+    for path in files {
+        let id = match id_of(&path) {
+            Some(id) => id,
+            None => continue,
+        };
+        let target = to.path(root, &id);
+        if Some(&path) == finished.as_ref() {
+            continue;
+        } else if path == target {
+            report.skipped.push(id);
+        } else if path != from.path(root, &id) {
+            continue;
+        } else if target.exists() {
+            report.conflicts.push(id);
+        } else {
+            try!(write_journal(root, &path, &target));
+            if crash_after == Some(moves) {
+                return Ok(());
+            }
+            try!(move_file(root, id, &path, &target, report));
+            try!(fs::remove_file(root.join(JOURNAL)));
+            moves += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Moves every file under `root` from where `from` puts it to where `to`
+/// does, once its records are found to be framed properly, and fills
+/// `report` with what became of each. Files in neither layout are left
+/// alone. If a previous run was cut short, its last move is finished
+/// first.
+pub fn migrate_layout(root: &Path,
+                      from: StorageLayout,
+                      to: StorageLayout,
+                      report: &mut MigrationReport)
+                      -> Result<(), MigrateError> {
+    migrate(root, from, to, report, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use Stream;
+
+    const FANOUT: StorageLayout = StorageLayout::Fanout {
+        levels: 2,
+        width: 2,
+    };
+
+    /// An empty directory to work in, particular to the test.
+    fn scratch(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("sousveillance-layout-{}", name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn read(path: &Path) -> Vec<u8> {
+        let mut bytes = vec![];
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Writes a healthy file for each of `healthy` and a truncated one for
+    /// each of `corrupt`, in the flat layout, returning the healthy files'
+    /// bytes.
+    fn populate(root: &Path, healthy: &[Vec<u8>], corrupt: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let factory = FileStreamFactory::new(root, StorageLayout::Flat);
+        for id in healthy {
+            let mut stream = factory.open(id).unwrap();
+            for i in 0..3 {
+                stream.push(Duration::from_millis(i), id).unwrap();
+            }
+        }
+        for id in corrupt {
+            let mut file = File::create(StorageLayout::Flat.path(root, id)).unwrap();
+            file.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 100, 1, 2, 3]).unwrap();
+        }
+        healthy.iter().map(|id| read(&StorageLayout::Flat.path(root, id))).collect()
+    }
+
+    #[test]
+    fn paths() {
+        let root = Path::new("/data");
+        assert_eq!(Path::new("/data/0aff.rec"),
+                   StorageLayout::Flat.path(root, b"\x0a\xff"));
+        assert_eq!(Path::new("/data/.rec"), StorageLayout::Flat.path(root, b""));
+        let path = FANOUT.path(root, b"\x0a\xff");
+        let hash = format!("{:016x}", fingerprint(&[b"\x0a\xff"]).swap_bytes());
+        assert_eq!(Path::new("/data").join(&hash[..2]).join(&hash[2..4]).join("0aff.rec"),
+                   path);
+        assert_eq!(Some(b"\x0a\xff".to_vec()), id_of(&path));
+        assert_eq!(None, id_of(Path::new("/data/0af.rec")));
+        assert_eq!(None, id_of(Path::new("/data/0aff.txt")));
+    }
+
+    #[test]
+    fn resumes_after_crash() {
+        let root = scratch("resume");
+        let healthy: Vec<_> = (0..6_u8).map(|i| vec![i, 0xaa]).collect();
+        let corrupt = vec![b"bad".to_vec(), b"worse".to_vec()];
+        let before = populate(&root, &healthy, &corrupt);
+
+        let mut first = MigrationReport::default();
+        migrate(&root, StorageLayout::Flat, FANOUT, &mut first, Some(2)).unwrap();
+        assert_eq!(2, first.moved.len());
+        assert!(root.join(JOURNAL).is_file());
+
+        let mut factory = FileStreamFactory::new(&root, FANOUT);
+        factory.set_transition(Some(StorageLayout::Flat));
+        for id in &healthy {
+            assert!(factory.locate(id).is_some());
+        }
+        factory.set_transition(None);
+        assert_eq!(2, healthy.iter().filter(|id| factory.locate(id).is_some()).count());
+
+        let mut second = MigrationReport::default();
+        migrate_layout(&root, StorageLayout::Flat, FANOUT, &mut second).unwrap();
+        assert!(!root.join(JOURNAL).exists());
+        assert_eq!(4, second.moved.len());
+        let mut skipped = second.skipped.clone();
+        skipped.sort();
+        let mut moved_first = first.moved.clone();
+        moved_first.sort();
+        assert_eq!(moved_first, skipped);
+        let mut corrupt_found = second.corrupt.clone();
+        corrupt_found.sort();
+        assert_eq!(corrupt, corrupt_found);
+        assert!(second.conflicts.is_empty());
+
+        for (id, bytes) in healthy.iter().zip(before) {
+            assert!(!StorageLayout::Flat.path(&root, id).exists());
+            assert_eq!(bytes, read(&FANOUT.path(&root, id)));
+            assert_eq!(Some(FANOUT.path(&root, id)), factory.locate(id));
+        }
+        for id in &corrupt {
+            assert!(StorageLayout::Flat.path(&root, id).is_file());
+        }
+
+        let mut third = MigrationReport::default();
+        migrate_layout(&root, StorageLayout::Flat, FANOUT, &mut third).unwrap();
+        assert!(third.moved.is_empty());
+        assert_eq!(healthy.len(), third.skipped.len());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn back_to_flat_and_conflicts() {
+        let root = scratch("flat");
+        let ids = vec![b"a".to_vec(), b"b".to_vec()];
+        populate(&root, &ids, &[]);
+        let mut report = MigrationReport::default();
+        migrate_layout(&root, StorageLayout::Flat, FANOUT, &mut report).unwrap();
+
+        // A stream opened at the old layout mid-transition leaves two files.
+        File::create(StorageLayout::Flat.path(&root, b"a")).unwrap();
+        let mut report = MigrationReport::default();
+        migrate_layout(&root, FANOUT, StorageLayout::Flat, &mut report).unwrap();
+        assert_eq!(vec![b"b".to_vec()], report.moved);
+        assert_eq!(vec![b"a".to_vec()], report.skipped);
+        assert_eq!(vec![b"a".to_vec()], report.conflicts);
+        assert!(!FANOUT.path(&root, b"b").parent().unwrap().exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn appends_to_located_file() {
+        let root = scratch("append");
+        populate(&root, &[b"id".to_vec()], &[]);
+        let mut factory = FileStreamFactory::new(&root, FANOUT);
+        factory.set_transition(Some(StorageLayout::Flat));
+        let mut stream = factory.open(b"id").unwrap();
+        let offset = stream.offset();
+        stream.push(Duration::from_millis(3), b"id").unwrap();
+        drop(stream);
+        let path = StorageLayout::Flat.path(&root, b"id");
+        assert_eq!(offset + 15, read(&path).len() as u64);
+        assert!(!FANOUT.path(&root, b"id").exists());
+        assert_eq!(4, verify(&path).unwrap().entries().len());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+use Clock;
+
+#[cfg(feature = "gzip")]
+pub use self::compressed::{Compressed, CompressedExtractError, CompressError};
+pub use self::content::{Backing, ContentHandle, ContentStore, FileBacking, IndexEvent,
+                        MemoryBacking, Referencing, ReferencingError, Rehydrate, Release,
+                        REFERENCE_LEN, rehydrate};
+pub use self::encrypting::{Decrypting, Encrypting, PayloadCipher};
+pub use self::envelope::{ExtractDescriptor, ExtractEnvelope, ExtractKind, PortableBody,
+                         PortableError, PortableErrorKind, PortableExtract, PortableReader,
+                         read_portable, write_envelope};
+pub use self::file::{intact_len, is_transient, FileStream, Index, Integrity, Range, ReadError,
+                     RecordReader, Retry, Trailer, TrailerField, VerifyReport, WriteStats,
+                     COMMITTED, TRAILER_LEN, TRAILER_SENTINEL};
+pub use self::guarded::{Guarded, GuardedError, GuardedWriter, PushIntent};
+pub use self::layout::{FileStreamFactory, MigrateError, MigrationReport, StorageLayout,
+                       migrate_layout};
+pub use self::reassembly::{Outcome, ReassemblyError, ReassemblyLimits, ReassemblyStats,
+                           Reassembler};
+pub use self::rotating::RotatingFileStream;
+pub use self::sequence::{Anomaly, SequenceStats, SequenceTracker, Wraparound};
+pub use self::snapshot::{SnapshotInfo, SnapshotStream};
+pub use self::split::{SplitError, SubRecordSplit};
+pub use self::stack::{Conflict, StackBuilder, StackError, UndeclaredWrapper, Wrapper,
+                      WrapperTraits};
+pub use self::text::{PreviewMode, TextLine, TextLog};
+pub use self::vec::{CapacityExceeded, VecStream};
+
+#[cfg(feature = "gzip")]
+pub mod compressed;
+pub mod content;
+pub mod encrypting;
+pub mod envelope;
+pub mod file;
+pub mod guarded;
+pub mod layout;
+pub mod reassembly;
+pub mod rotating;
+pub mod sequence;
+pub mod snapshot;
+pub mod split;
+pub mod stack;
+pub mod text;
+pub mod vec;
+
+/// A stream's hint to whoever feeds it about how much more it can take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pressure {
+    None,
+    /// Keeping up only just; waiting about this long before the next push
+    /// would help.
+    SlowDown {
+        suggested_delay: Duration,
+    },
+    /// Falling behind; nothing more should be pushed until resumed.
+    Stop,
+}
+
+pub trait Stream {
+    type PushErr;
+    fn push(&mut self, Duration, &[u8]) -> Result<(), Self::PushErr>;
+
+    /// `push`, for callers done with the payload, so that streams keeping
+    /// it need not copy it. Pushes a borrow of it unless overridden;
+    /// wrappers that pass it on unchanged should override this too.
+    fn push_owned(&mut self, ts: Duration, payload: Vec<u8>) -> Result<(), Self::PushErr> {
+        self.push(ts, &payload)
+    }
+
+    /// How pressed the stream is after its last push. Never pressed unless
+    /// overridden; wrappers should pass their inner stream's on.
+    fn pressure(&self) -> Pressure {
+        Pressure::None
+    }
+
+    /// Whether someone means to push to the stream, so that it should not
+    /// be extracted or moved. Never busy unless overridden; wrappers should
+    /// pass their inner stream's on.
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    type Extract;
+    type ExtractErr;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)>;
+}
+
+/// Streams that take a payload a piece at a time, for payloads too large
+/// to buffer whole.
+pub trait StreamingStream: Stream {
+    type Writer: Write;
+
+    /// Starts a payload of `total_len` bytes stamped `ts`.
+    fn begin(&mut self, ts: Duration, total_len: u64) -> Result<Self::Writer, Self::PushErr>;
+
+    /// Stores what was written to `writer`, as `push` would have.
+    fn commit(&mut self, writer: Self::Writer) -> Result<(), Self::PushErr>;
+
+    /// Discards what was written to `writer`.
+    fn abort(&mut self, writer: Self::Writer);
+}
+
+/// Streams that can stage a record and later store or discard it, so that
+/// a record for several streams can reach all of them or none. `prepare`
+/// should do whatever can fail, so that a commit after every stream has
+/// prepared does not; one that fails anyway leaves the record stored in
+/// some of the streams.
+pub trait TransactionalStream: Stream {
+    type Prepared;
+    fn prepare(&mut self, ts: Duration, payload: &[u8]) -> Result<Self::Prepared, Self::PushErr>;
+    fn commit(&mut self, prepared: Self::Prepared) -> Result<(), Self::PushErr>;
+    fn rollback(&mut self, prepared: Self::Prepared);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeadlineError<E> {
+    TimedOut {
+        waited: Duration,
+    },
+    Extract(E),
+}
+
+/// Streams whose extraction can be bounded by a deadline, as read from a
+/// `Clock`. On failure, the stream is handed back just like `extract`.
+pub trait DeadlineExtract: Stream {
+    fn extract_by<C: Clock>(self,
+                            clock: &C,
+                            deadline: Duration)
+                            -> Result<Self::Extract, (Self, DeadlineError<Self::ExtractErr>)>;
+}
+
+pub type FoundResult<S> = Result<
+    <S as Stream>::Extract, <S as Stream>::ExtractErr>;
+pub trait Finder {
+    type Stream: Stream;
+    fn extract(&mut self, &[u8]) -> Option<FoundResult<Self::Stream>>;
+}
+
+impl<V: Stream> Finder for HashMap<Vec<u8>, V> {
+    type Stream = V;
+    fn extract(&mut self, key: &[u8]) -> Option<FoundResult<Self::Stream>> {
+        self.remove(key)
+            .map(V::extract)
+            .map(|result| {
+                result.map_err(|(stream, err)| {
+                    self.insert(key.to_owned(), stream);
+                    err
+                })
+            })
+    }
+}
+
+pub struct DrainReport<S: Stream> {
+    pub extracted: Vec<(Vec<u8>, S::Extract)>,
+    pub timed_out: Vec<(Vec<u8>, Duration)>,
+    pub failed: Vec<(Vec<u8>, S::ExtractErr)>,
+    /// IDs never attempted because the overall budget ran out.
+    pub skipped: Vec<Vec<u8>>,
+}
+
+impl<S> DrainReport<S>
+    where S: Stream,
+          S::Extract: ExtractEnvelope
+{
+    /// Writes each extract in an envelope labeled with its ID, returning
+    /// how many bytes were written.
+    pub fn write_portable<W: Write>(&self, w: W) -> io::Result<u64> {
+        let mut w = w;
+        let mut written = 0;
+        for &(ref id, ref extract) in &self.extracted {
+            written += try!(write_envelope(&mut w, id, &extract.describe(), extract));
+        }
+        Ok(written)
+    }
+}
+
+/// Extracts every stream, in ID order, giving each at most `per_stream` and
+/// all of them together at most `budget`. Streams that time out or fail are
+/// reinserted.
+pub fn drain_by<S, C>(streams: &mut HashMap<Vec<u8>, S>,
+                      clock: &C,
+                      per_stream: Duration,
+                      budget: Duration)
+                      -> DrainReport<S>
+    where S: DeadlineExtract,
+          C: Clock
+{
+    let mut report = DrainReport {
+        extracted: vec![],
+        timed_out: vec![],
+        failed: vec![],
+        skipped: vec![],
+    };
+    let end = clock.now() + budget;
+    let mut ids: Vec<_> = streams.keys().cloned().collect();
+    ids.sort();
+    for id in ids {
+        let now = clock.now();
+        if now >= end {
+            report.skipped.push(id);
+            continue;
+        }
+        let deadline = if end - now < per_stream {
+            end
+        } else {
+            now + per_stream
+        };
+        let stream = streams.remove(&id).unwrap();
+        match stream.extract_by(clock, deadline) {
+            Ok(extract) => report.extracted.push((id, extract)),
+            Err((stream, err)) => {
+                streams.insert(id.clone(), stream);
+                match err {
+                    DeadlineError::TimedOut { waited } => report.timed_out.push((id, waited)),
+                    DeadlineError::Extract(e) => report.failed.push((id, e)),
+                }
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use test_support::stream as mocks;
+    use test_support::*;
+    use Clock;
+
+    /// Moves forward by a fixed step every time it is read.
+    struct Ticking {
+        now: Cell<Duration>,
+        step: Duration,
+    }
+    impl Clock for Ticking {
+        fn now(&self) -> Duration {
+            let now = self.now.get();
+            self.now.set(now + self.step);
+            now
+        }
+    }
+
+    fn millis(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn drain_attributes_timeouts() {
+        let clock = ManualClock::new(millis(1000));
+        let mut streams: HashMap<_, _> = vec![(b"fast".to_vec(), mocks::Slow(millis(10))),
+                                              (b"slow".to_vec(), mocks::Slow(millis(500))),
+                                              (b"exact".to_vec(), mocks::Slow(millis(100)))]
+                                             .into_iter()
+                                             .collect();
+        let report = drain_by(&mut streams, &clock, millis(100), millis(1000));
+        let extracted: Vec<_> = report.extracted.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(vec![b"exact".to_vec(), b"fast".to_vec()], extracted);
+        assert_eq!(vec![(b"slow".to_vec(), millis(100))], report.timed_out);
+        assert!(report.failed.is_empty());
+        assert!(report.skipped.is_empty());
+        assert_eq!(vec![&b"slow"[..]],
+                   streams.keys().map(|id| &id[..]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drain_reports_failures_separately() {
+        let clock = ManualClock::new(millis(0));
+        let mut streams: HashMap<_, _> = vec![(b"a".to_vec(), mocks::Broken),
+                                              (b"b".to_vec(), mocks::Broken)]
+                                             .into_iter()
+                                             .collect();
+        let report = drain_by(&mut streams, &clock, millis(100), millis(1000));
+        assert!(report.extracted.is_empty());
+        assert!(report.timed_out.is_empty());
+        assert_eq!(vec![(b"a".to_vec(), ()), (b"b".to_vec(), ())], report.failed);
+        assert_eq!(2, streams.len());
+    }
+
+    #[test]
+    fn drain_budget_caps_deadlines_and_skips() {
+        let clock = Ticking {
+            now: Cell::new(millis(0)),
+            step: millis(20),
+        };
+        let mut streams: HashMap<_, _> = (0..4_u8)
+                                             .map(|i| (vec![i], mocks::Slow(millis(1000))))
+                                             .collect();
+        // The drain reads the clock at 0 to start and then before each
+        // stream, and each stream reads it once more.
+        let report = drain_by(&mut streams, &clock, millis(50), millis(100));
+        assert_eq!(vec![(vec![0], millis(30)), (vec![1], millis(20))],
+                   report.timed_out);
+        assert_eq!(vec![vec![2], vec![3]], report.skipped);
+        assert_eq!(4, streams.len());
+    }
+
+    quickcheck_test! {
+    drain_ok_removes_all(ids: HashSet<Vec<u8>>; bool) {
+        let clock = ManualClock::new(millis(0));
+        let mut streams: HashMap<_, _> = ids.iter().cloned().map(|id| (id, mocks::Ok)).collect();
+        let report = drain_by(&mut streams, &clock, millis(1), millis(1));
+        streams.is_empty() && report.extracted.len() == ids.len()
+    }}
+
+    quickcheck_test! {
+    missing_id(missing_id: Vec<u8>, present_ids: HashSet<Vec<u8>>; TestResult) {
+        if present_ids.contains(&missing_id) {
+            TestResult::discard()
+        } else {
+            let mut streams: HashMap<_, _> = present_ids.into_iter()
+                .map(|id| (id, mocks::Ok))
+                .collect();
+            test_result_match!(None, streams.extract(&missing_id))
+        }
+    }}
+
+    quickcheck_test! {
+    extract_error(id_to_lookup: Vec<u8>, other_ids: HashSet<Vec<u8>>; TestResult) {
+        let mut ids = other_ids;
+        ids.insert(id_to_lookup.clone());
+
+        let mut streams: HashMap<_, _> = ids.into_iter()
+            .map(|id| (id, mocks::Broken))
+            .collect();
+        test_result_match!(Some(Err(_)), streams.extract(&id_to_lookup))
+    }}
+
+    quickcheck_test! {
+    extract_error_reinserts(id_to_lookup: Vec<u8>, other_ids: HashSet<Vec<u8>>;
+                            TestResult) {
+        let mut ids = other_ids;
+        ids.insert(id_to_lookup.clone());
+
+        let mut streams: HashMap<_, _> = ids.into_iter()
+            .map(|id| (id, mocks::Broken))
+            .collect();
+        streams.extract(&id_to_lookup);
+        test_result_match!(Some(&mocks::Broken), streams.get(&id_to_lookup))
+    }}
+
+    quickcheck_test! {
+    extract_ok(id_to_lookup: Vec<u8>, other_ids: HashSet<Vec<u8>>; TestResult) {
+        let mut ids = other_ids;
+        ids.insert(id_to_lookup.clone());
+
+        let mut streams: HashMap<_, _> = ids.into_iter()
+            .map(|id| (id, mocks::Ok))
+            .collect();
+        test_result_match!(Some(Ok(_)), streams.extract(&id_to_lookup))
+    }}
+
+    quickcheck_test! {
+    extract_ok_removes(id_to_lookup: Vec<u8>, other_ids: HashSet<Vec<u8>>; TestResult) {
+        let mut ids = other_ids;
+        ids.insert(id_to_lookup.clone());
+
+        let mut streams: HashMap<_, _> = ids.into_iter()
+            .map(|id| (id, mocks::Ok))
+            .collect();
+        streams.extract(&id_to_lookup);
+        test_result_match!(None, streams.get(&id_to_lookup))
+    }}
+
+    #[test]
+    fn owned_pushes_are_handed_over() {
+        let mut streams: HashMap<_, _> =
+            vec![(b"id".to_vec(), Guarded::new(mocks::ScriptedStream::<(), ()>::default()))]
+                .into_iter()
+                .collect();
+        let payload = b"owned".to_vec();
+        let at = payload.as_ptr();
+        streams.get_mut(&b"id"[..]).unwrap().push_owned(millis(1), payload).unwrap();
+        let pushed = streams[&b"id"[..]].get_ref().pushed();
+        assert_eq!(vec![(millis(1), b"owned".to_vec())], pushed);
+        assert_eq!(at, pushed[0].1.as_ptr());
+
+        assert_eq!(Err(()), mocks::Broken.push_owned(millis(3), b"borrowed".to_vec()));
+    }
+}
@@ -0,0 +1,791 @@
+//! Payloads sent a chunk at a time, so that a device that loses its
+//! connection mid-upload resends only the chunks that never arrived.
+//!
+//! Every payload starts with extensions. One without an `upload-id`
+//! attribute is stored as is; otherwise what follows its extensions is a
+//! chunk of the upload with that ID, placed by its attributes:
+//!
+//! | Attribute      | Value                                                 |
+//! |----------------|-------------------------------------------------------|
+//! | `upload-id`    | Any bytes, not reused within `max_idle` of finishing  |
+//! | `total-length` | `u64` big-endian; on the initial chunk                |
+//! | `offset`       | `u64` big-endian; where the chunk goes, 0 if absent   |
+//! | `abort`        | Any value; the upload is discarded, and the chunk too |
+//!
+//! Each stream holds one ID's records, so uploads need only be told apart
+//! by upload ID. Chunks that come after their upload finished, by
+//! completing or by an abort, are dropped.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use callback::{Calls, Local, Sendable};
+use message::{AttributeLimits, Attributes, ExtensionError, ExtensionRegistry, Extensions};
+use {Clock, Stream};
+use super::Pressure;
+
+pub const UPLOAD_ID: &'static [u8] = b"upload-id";
+pub const TOTAL_LENGTH: &'static [u8] = b"total-length";
+pub const OFFSET: &'static [u8] = b"offset";
+pub const ABORT: &'static [u8] = b"abort";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReassemblyLimits {
+    /// Bytes staged across every unfinished upload, counting those in
+    /// gaps yet to arrive.
+    pub max_staged_bytes: u64,
+    pub max_uploads: usize,
+    /// How long an upload may go without a chunk before it is evicted, and
+    /// how long a finished one's late chunks are dropped.
+    pub max_idle: Duration,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        ReassemblyLimits {
+            max_staged_bytes: 16 << 20,
+            max_uploads: 8,
+            max_idle: Duration::from_secs(600),
+        }
+    }
+}
+
+/// What became of an upload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Stored whole, as one record of `len` bytes.
+    Completed {
+        upload_id: Vec<u8>,
+        len: u64,
+    },
+    /// Discarded after going `max_idle` without a chunk.
+    Evicted {
+        upload_id: Vec<u8>,
+        staged: u64,
+    },
+    Aborted {
+        upload_id: Vec<u8>,
+        staged: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    pub completed: u64,
+    pub evicted: u64,
+    pub aborted: u64,
+    /// Chunk bytes dropped for having been staged already, or for coming
+    /// after their upload finished.
+    pub duplicate_bytes: u64,
+    /// Bytes staged now.
+    pub staged_bytes: u64,
+    /// Unfinished uploads now.
+    pub uploads: usize,
+}
+
+#[derive(Debug)]
+pub enum ReassemblyError<E> {
+    Extensions(ExtensionError),
+    /// A `u64` attribute whose value is not eight bytes.
+    BadAttribute {
+        key: &'static str,
+    },
+    /// A chunk ends past its upload's total length.
+    OutOfBounds {
+        offset: u64,
+        len: u64,
+        total: u64,
+    },
+    /// A chunk gives another total length than the upload's.
+    LengthMismatch {
+        total: u64,
+        got: u64,
+    },
+    StagingFull {
+        needed: u64,
+        max: u64,
+    },
+    TooManyUploads {
+        max: usize,
+    },
+    Push(E),
+}
+
+impl<E: Display> Display for ReassemblyError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ReassemblyError::Extensions(ref e) => e.fmt(f),
+            ReassemblyError::BadAttribute { key } => write!(f, "malformed {} attribute", key),
+            ReassemblyError::OutOfBounds { offset, len, total } => {
+                write!(f,
+                       "chunk of {} bytes at offset {} overruns upload of {} bytes",
+                       len,
+                       offset,
+                       total)
+            }
+            ReassemblyError::LengthMismatch { total, got } => {
+                write!(f, "upload of {} bytes given as {} bytes", total, got)
+            }
+            ReassemblyError::StagingFull { needed, max } => {
+                write!(f, "staging {} more bytes would exceed {} bytes", needed, max)
+            }
+            ReassemblyError::TooManyUploads { max } => {
+                write!(f, "too many unfinished uploads; at most {}", max)
+            }
+            ReassemblyError::Push(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for ReassemblyError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            ReassemblyError::Extensions(ref e) => e.description(),
+            ReassemblyError::BadAttribute { .. } => "malformed attribute",
+            ReassemblyError::OutOfBounds { .. } => "chunk overruns upload",
+            ReassemblyError::LengthMismatch { .. } => "upload length mismatch",
+            ReassemblyError::StagingFull { .. } => "staging full",
+            ReassemblyError::TooManyUploads { .. } => "too many unfinished uploads",
+            ReassemblyError::Push(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ReassemblyError::Extensions(ref e) => Some(e),
+            ReassemblyError::Push(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn u64_attribute<E>(attributes: &Attributes,
+                    key: &'static [u8])
+                    -> Result<Option<u64>, ReassemblyError<E>> {
+    match attributes.get(key) {
+        None => Ok(None),
+        Some(value) if value.len() == 8 => Ok(Some(BigEndian::read_u64(value))),
+        Some(_) => {
+            Err(ReassemblyError::BadAttribute {
+                key: ::std::str::from_utf8(key).unwrap(),
+            })
+        }
+    }
+}
+
+struct Upload {
+    /// The initial chunk's timestamp, once it has come.
+    ts: Option<Duration>,
+    total: Option<u64>,
+    data: Vec<u8>,
+    /// The ranges of `data` received, sorted and neither overlapping nor
+    /// touching.
+    received: Vec<(u64, u64)>,
+    last_active: Duration,
+}
+
+impl Upload {
+    fn staged(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.total {
+            Some(0) => true,
+            Some(total) => self.received == [(0, total)],
+            None => false,
+        }
+    }
+
+    /// Copies the parts of `chunk`, placed at `offset`, not yet received,
+    /// returning how many bytes those were.
+    fn fill(&mut self, offset: u64, chunk: &[u8]) -> u64 {
+        if chunk.is_empty() {
+            return 0;
+        }
+        let end = offset + chunk.len() as u64;
+        if (self.data.len() as u64) < end {
+            self.data.resize(end as usize, 0);
+        }
+        let mut fresh = 0;
+        let mut at = offset;
+        let mut merged = (offset, end);
+        let mut received = Vec::with_capacity(self.received.len() + 1);
+        {
+            let mut copy = |from: u64, to: u64, data: &mut Vec<u8>| {
+                data[from as usize..to as usize]
+                    .copy_from_slice(&chunk[(from - offset) as usize..(to - offset) as usize]);
+                fresh += to - from;
+            };
+            for &(start, stop) in &self.received {
+                if stop < offset || start > end {
+                    received.push((start, stop));
+                    continue;
+                }
+                if start > at {
+                    copy(at, start, &mut self.data);
+                }
+                at = cmp::max(at, stop);
+                merged = (cmp::min(merged.0, start), cmp::max(merged.1, stop));
+            }
+            if at < end {
+                copy(at, end, &mut self.data);
+            }
+        }
+        received.push(merged);
+        received.sort();
+        self.received = received;
+        fresh
+    }
+}
+
+/// A stream that stages each upload's chunks and pushes the upload to the
+/// inner stream once every byte has come, as one record stamped with the
+/// initial chunk's timestamp.
+///
+/// Chunks may come in any order, and again: bytes already staged are kept
+/// as they first came. If the inner stream fails the completing push, the
+/// upload stays staged, and resending any of its chunks tries again.
+/// Unfinished uploads are discarded on extraction.
+///
+/// Its outcome callback need not be `Send` unless it is over `Sendable`,
+/// as `new_send` makes it.
+pub struct Reassembler<S, C, K: Calls<Outcome> = Local> {
+    stream: S,
+    clock: C,
+    limits: ReassemblyLimits,
+    registry: ExtensionRegistry,
+    uploads: HashMap<Vec<u8>, Upload>,
+    /// When each recently finished upload finished.
+    finished: HashMap<Vec<u8>, Duration>,
+    stats: ReassemblyStats,
+    on_outcome: Option<Box<K::FnMut>>,
+}
+
+impl<S: Stream, C: Clock> Reassembler<S, C> {
+    pub fn new(stream: S, clock: C, limits: ReassemblyLimits) -> Self {
+        Reassembler::without_callbacks(stream, clock, limits)
+    }
+
+    pub fn set_outcome_callback<F: FnMut(Outcome) + 'static>(&mut self, callback: F) {
+        self.on_outcome = Some(Box::new(callback));
+    }
+}
+
+impl<S: Stream, C: Clock> Reassembler<S, C, Sendable> {
+    /// A stream that is `Send` whenever its inner stream and clock are.
+    pub fn new_send(stream: S, clock: C, limits: ReassemblyLimits) -> Self {
+        Reassembler::without_callbacks(stream, clock, limits)
+    }
+
+    pub fn set_outcome_callback<F: FnMut(Outcome) + Send + 'static>(&mut self, callback: F) {
+        self.on_outcome = Some(Box::new(callback));
+    }
+}
+
+impl<S: Stream, C: Clock, K: Calls<Outcome>> Reassembler<S, C, K> {
+    fn without_callbacks(stream: S, clock: C, limits: ReassemblyLimits) -> Self {
+        Reassembler {
+            stream: stream,
+            clock: clock,
+            limits: limits,
+            registry: ExtensionRegistry::new(),
+            uploads: HashMap::new(),
+            finished: HashMap::new(),
+            stats: ReassemblyStats::default(),
+            on_outcome: None,
+        }
+    }
+
+    /// How to treat unknown extension bits; all are rejected by default.
+    pub fn set_registry(&mut self, registry: ExtensionRegistry) {
+        self.registry = registry;
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        ReassemblyStats {
+            staged_bytes: self.staged_bytes(),
+            uploads: self.uploads.len(),
+            ..self.stats
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Evicts every upload that has gone `max_idle` without a chunk, and
+    /// forgets those finished as long ago. Each push does so first.
+    pub fn evict_idle(&mut self) {
+        let now = self.clock.now();
+        let max_idle = self.limits.max_idle;
+        let idle = |since: Duration| now >= since && now - since >= max_idle;
+        let stale: Vec<_> = self.finished
+                                .iter()
+                                .filter(|&(_, &finished_at)| idle(finished_at))
+                                .map(|(upload_id, _)| upload_id.clone())
+                                .collect();
+        for upload_id in stale {
+            self.finished.remove(&upload_id);
+        }
+        let mut idle: Vec<_> = self.uploads
+                                   .iter()
+                                   .filter(|&(_, upload)| idle(upload.last_active))
+                                   .map(|(upload_id, _)| upload_id.clone())
+                                   .collect();
+        idle.sort();
+        for upload_id in idle {
+            let upload = self.uploads.remove(&upload_id).unwrap();
+            self.stats.evicted += 1;
+            self.report(Outcome::Evicted {
+                upload_id: upload_id,
+                staged: upload.staged(),
+            });
+        }
+    }
+
+    fn staged_bytes(&self) -> u64 {
+        self.uploads.values().map(Upload::staged).sum()
+    }
+
+    fn report(&mut self, outcome: Outcome) {
+        if let Some(ref mut callback) = self.on_outcome {
+            (**callback)(outcome);
+        }
+    }
+
+    /// Checks that a chunk fits its upload and the limits, returning how
+    /// many bytes staging it takes.
+    fn check(&self,
+             upload_id: &[u8],
+             total: Option<u64>,
+             offset: u64,
+             len: u64)
+             -> Result<u64, ReassemblyError<S::PushErr>> {
+        let upload = self.uploads.get(upload_id);
+        let (staged, total) = match upload {
+            None => {
+                if self.uploads.len() >= self.limits.max_uploads {
+                    return Err(ReassemblyError::TooManyUploads { max: self.limits.max_uploads });
+                }
+                (0, total)
+            }
+            Some(upload) => {
+                match (upload.total, total) {
+                    (Some(total), Some(got)) if total != got => {
+                        return Err(ReassemblyError::LengthMismatch {
+                            total: total,
+                            got: got,
+                        })
+                    }
+                    (known, given) => (upload.staged(), known.or(given)),
+                }
+            }
+        };
+        let end = offset.checked_add(len);
+        if let Some(total) = total {
+            if total > self.limits.max_staged_bytes {
+                return Err(ReassemblyError::StagingFull {
+                    needed: total,
+                    max: self.limits.max_staged_bytes,
+                });
+            }
+            if end.map_or(true, |end| end > total) {
+                return Err(ReassemblyError::OutOfBounds {
+                    offset: offset,
+                    len: len,
+                    total: total,
+                });
+            }
+            // The total may only now be known, after later chunks.
+            if let Some(&(start, stop)) = upload.and_then(|upload| upload.received.last()) {
+                if stop > total {
+                    return Err(ReassemblyError::OutOfBounds {
+                        offset: start,
+                        len: stop - start,
+                        total: total,
+                    });
+                }
+            }
+        }
+        let needed = match end {
+            Some(end) => end.saturating_sub(staged),
+            None => u64::max_value(),
+        };
+        if needed > self.limits.max_staged_bytes - self.staged_bytes() {
+            return Err(ReassemblyError::StagingFull {
+                needed: needed,
+                max: self.limits.max_staged_bytes,
+            });
+        }
+        Ok(needed)
+    }
+
+    fn try_complete(&mut self, upload_id: &[u8]) -> Result<(), ReassemblyError<S::PushErr>> {
+        if !self.uploads.get(upload_id).map_or(false, Upload::is_complete) {
+            return Ok(());
+        }
+        {
+            let upload = &self.uploads[upload_id];
+            try!(self.stream
+                     .push(upload.ts.unwrap(), &upload.data)
+                     .map_err(ReassemblyError::Push));
+        }
+        let upload = self.uploads.remove(upload_id).unwrap();
+        self.finished.insert(upload_id.to_vec(), self.clock.now());
+        self.stats.completed += 1;
+        self.report(Outcome::Completed {
+            upload_id: upload_id.to_vec(),
+            len: upload.staged(),
+        });
+        Ok(())
+    }
+}
+
+impl<S: Stream, C: Clock, K: Calls<Outcome>> Stream for Reassembler<S, C, K> {
+    type PushErr = ReassemblyError<S::PushErr>;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        self.evict_idle();
+        let (_, attributes, chunk) =
+            try!(Extensions::parse_limited(payload, &self.registry, &AttributeLimits::default())
+                     .map_err(ReassemblyError::Extensions));
+        let upload_id = match attributes.get(UPLOAD_ID) {
+            None => return self.stream.push(ts, payload).map_err(ReassemblyError::Push),
+            Some(upload_id) => upload_id,
+        };
+        if attributes.get(ABORT).is_some() {
+            // Chunks sent before the abort may yet come after it.
+            self.finished.insert(upload_id.to_vec(), self.clock.now());
+            if let Some(upload) = self.uploads.remove(upload_id) {
+                self.stats.aborted += 1;
+                self.report(Outcome::Aborted {
+                    upload_id: upload_id.to_vec(),
+                    staged: upload.staged(),
+                });
+            }
+            return Ok(());
+        }
+        if self.finished.contains_key(upload_id) {
+            self.stats.duplicate_bytes += chunk.len() as u64;
+            return Ok(());
+        }
+        let total = try!(u64_attribute(&attributes, TOTAL_LENGTH));
+        let offset = try!(u64_attribute(&attributes, OFFSET)).unwrap_or(0);
+        try!(self.check(upload_id, total, offset, chunk.len() as u64));
+
+        let now = self.clock.now();
+        let fresh = {
+            let upload = self.uploads.entry(upload_id.to_vec()).or_insert_with(|| {
+                Upload {
+                    ts: None,
+                    total: None,
+                    data: vec![],
+                    received: vec![],
+                    last_active: now,
+                }
+            });
+            upload.last_active = now;
+            if upload.total.is_none() && total.is_some() {
+                upload.total = total;
+                upload.ts = Some(ts);
+            }
+            upload.fill(offset, chunk)
+        };
+        self.stats.duplicate_bytes += chunk.len() as u64 - fresh;
+        self.try_complete(upload_id)
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.stream.is_busy()
+    }
+
+    type Extract = (S::Extract, ReassemblyStats);
+    type ExtractErr = S::ExtractErr;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let stats = self.stats();
+        let Reassembler { stream, clock, limits, registry, uploads, finished, stats: own,
+                          on_outcome } = self;
+        match stream.extract() {
+            Ok(extract) => Ok((extract, stats)),
+            Err((stream, e)) => {
+                Err((Reassembler {
+                    stream: stream,
+                    clock: clock,
+                    limits: limits,
+                    registry: registry,
+                    uploads: uploads,
+                    finished: finished,
+                    stats: own,
+                    on_outcome: on_outcome,
+                },
+                     e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use message::Extensions;
+    use test_support::stream::ScriptedStream;
+    use Stream;
+
+    type Tested<'c> = Reassembler<ScriptedStream<(), ()>, &'c ManualClock>;
+
+    fn be(n: u64) -> Vec<u8> {
+        let mut bytes = vec![0; 8];
+        BigEndian::write_u64(&mut bytes, n);
+        bytes
+    }
+
+    /// A payload of extensions holding `attrs`, then `data`.
+    fn framed(attrs: &[(&[u8], Vec<u8>)], data: &[u8]) -> Vec<u8> {
+        let mut section = vec![attrs.len() as u8];
+        for &(key, ref value) in attrs {
+            section.push(key.len() as u8);
+            section.extend_from_slice(key);
+            section.extend_from_slice(&[(value.len() >> 8) as u8, value.len() as u8]);
+            section.extend_from_slice(value);
+        }
+        let extensions = Extensions { attrs: Some(&section), ..Extensions::default() };
+        let mut payload = vec![0; extensions.encoded_len()];
+        extensions.write_into(&mut payload).unwrap();
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    fn initial(upload_id: &[u8], total: u64, data: &[u8]) -> Vec<u8> {
+        framed(&[(UPLOAD_ID, upload_id.to_vec()), (TOTAL_LENGTH, be(total))], data)
+    }
+
+    fn continuation(upload_id: &[u8], offset: u64, data: &[u8]) -> Vec<u8> {
+        framed(&[(UPLOAD_ID, upload_id.to_vec()), (OFFSET, be(offset))], data)
+    }
+
+    fn abort(upload_id: &[u8]) -> Vec<u8> {
+        framed(&[(UPLOAD_ID, upload_id.to_vec()), (ABORT, vec![])], b"")
+    }
+
+    fn original(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    fn reassembler(clock: &ManualClock) -> (Tested, Rc<RefCell<Vec<Outcome>>>) {
+        let outcomes = Rc::new(RefCell::new(vec![]));
+        let mut reassembler = Reassembler::new(ScriptedStream::default(),
+                                               clock,
+                                               ReassemblyLimits {
+                                                   max_staged_bytes: 4096,
+                                                   max_uploads: 2,
+                                                   max_idle: Duration::from_secs(60),
+                                               });
+        let seen = outcomes.clone();
+        reassembler.set_outcome_callback(move |outcome| seen.borrow_mut().push(outcome));
+        (reassembler, outcomes)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    quickcheck_test! {
+    reassembles_exactly(data: Vec<u8>, cuts: Vec<(u16, u16)>, initial_at: usize; bool) {
+        let clock = ManualClock::new(ms(0));
+        let (mut reassembler, outcomes) = reassembler(&clock);
+        let len = data.len() as u64;
+        // Chunks covering every byte, overlapping and repeated, in any order.
+        let mut chunks: Vec<_> = cuts.iter()
+                                     .map(|&(a, b)| {
+                                         let a = a as u64 % (len + 1);
+                                         let b = b as u64 % (len + 1);
+                                         (cmp::min(a, b), cmp::max(a, b))
+                                     })
+                                     .collect();
+        chunks.extend((0..(len + 9) / 10).map(|i| (i * 10, cmp::min(len, i * 10 + 10))));
+        let at = initial_at % (chunks.len() + 1);
+        let mut result = Ok(());
+        for (i, &(start, stop)) in chunks.iter().enumerate() {
+            if i == at {
+                result = result.and(reassembler.push(ms(5), &initial(b"u", len, b"")));
+            }
+            let chunk = &data[start as usize..stop as usize];
+            result = result.and(reassembler.push(ms(10 + i as u64),
+                                                 &continuation(b"u", start, chunk)));
+        }
+        if at == chunks.len() {
+            result = result.and(reassembler.push(ms(5), &initial(b"u", len, b"")));
+        }
+        let stats = reassembler.stats();
+        let (pushed, _) = reassembler.extract().map_err(|_| ()).unwrap();
+        result.is_ok() && pushed == vec![(ms(5), data.clone())] && stats.completed == 1 &&
+        stats.uploads == 0 && stats.staged_bytes == 0 &&
+        *outcomes.borrow() == vec![Outcome::Completed { upload_id: b"u".to_vec(), len: len }]
+    }}
+
+    #[test]
+    fn out_of_order_duplicate_and_overlapping_chunks() {
+        let clock = ManualClock::new(ms(0));
+        let (mut reassembler, outcomes) = reassembler(&clock);
+        let data = original(1000);
+        reassembler.push(ms(1), &continuation(b"u", 600, &data[600..])).unwrap();
+        reassembler.push(ms(2), &continuation(b"u", 600, &data[600..])).unwrap();
+        reassembler.push(ms(3), &continuation(b"u", 200, &data[200..700])).unwrap();
+        assert!(reassembler.get_ref().pushed().is_empty());
+        reassembler.push(ms(4), &initial(b"u", 1000, &data[..300])).unwrap();
+
+        assert_eq!(&[(ms(4), data.clone())][..], reassembler.get_ref().pushed());
+        let stats = reassembler.stats();
+        assert_eq!(1, stats.completed);
+        assert_eq!(400 + 100 + 100, stats.duplicate_bytes);
+        assert_eq!(0, stats.staged_bytes);
+        assert_eq!(vec![Outcome::Completed {
+                            upload_id: b"u".to_vec(),
+                            len: 1000,
+                        }],
+                   *outcomes.borrow());
+
+        // A late duplicate is dropped, until the upload ID may be reused.
+        reassembler.push(ms(5), &continuation(b"u", 0, &data[..10])).unwrap();
+        assert_eq!(1, reassembler.get_ref().pushed().len());
+        assert_eq!((0, 610), (reassembler.stats().uploads, reassembler.stats().duplicate_bytes));
+        clock.advance(Duration::from_secs(60));
+        reassembler.push(ms(6), &continuation(b"u", 0, &data[..10])).unwrap();
+        assert_eq!(1, reassembler.stats().uploads);
+    }
+
+    #[test]
+    fn evicts_stalled_upload() {
+        let clock = ManualClock::new(ms(0));
+        let (mut reassembler, outcomes) = reassembler(&clock);
+        let data = original(100);
+        reassembler.push(ms(0), &initial(b"stalled", 100, &data[..40])).unwrap();
+        clock.advance(Duration::from_secs(30));
+        reassembler.push(ms(1), &initial(b"active", 100, &data[..40])).unwrap();
+        clock.advance(Duration::from_secs(30));
+        reassembler.push(ms(2), &continuation(b"active", 40, &data[40..60])).unwrap();
+
+        assert_eq!(vec![Outcome::Evicted {
+                            upload_id: b"stalled".to_vec(),
+                            staged: 40,
+                        }],
+                   *outcomes.borrow());
+        let stats = reassembler.stats();
+        assert_eq!((1, 1, 60), (stats.evicted, stats.uploads, stats.staged_bytes));
+
+        // What is left of the evicted upload starts over, short its start.
+        reassembler.push(ms(3), &continuation(b"stalled", 40, &data[40..])).unwrap();
+        reassembler.push(ms(4), &continuation(b"active", 60, &data[60..])).unwrap();
+        assert_eq!(&[(ms(1), data.clone())][..], reassembler.get_ref().pushed());
+        assert_eq!(1, reassembler.stats().uploads);
+    }
+
+    #[test]
+    fn abort_discards() {
+        let clock = ManualClock::new(ms(0));
+        let (mut reassembler, outcomes) = reassembler(&clock);
+        let data = original(100);
+        reassembler.push(ms(0), &initial(b"u", 100, &data[..50])).unwrap();
+        reassembler.push(ms(1), &abort(b"u")).unwrap();
+        reassembler.push(ms(2), &abort(b"never")).unwrap();
+        reassembler.push(ms(3), &continuation(b"u", 50, &data[50..])).unwrap();
+
+        assert!(reassembler.get_ref().pushed().is_empty());
+        assert_eq!(vec![Outcome::Aborted {
+                            upload_id: b"u".to_vec(),
+                            staged: 50,
+                        }],
+                   *outcomes.borrow());
+        let stats = reassembler.stats();
+        assert_eq!((1, 0, 0, 50),
+                   (stats.aborted, stats.completed, stats.uploads, stats.duplicate_bytes));
+    }
+
+    #[test]
+    fn interleaved_uploads() {
+        let clock = ManualClock::new(ms(0));
+        let (mut one, _) = reassembler(&clock);
+        let (mut two, _) = reassembler(&clock);
+        let (a, b, c) = (original(300), original(500), original(200));
+        // Two uploads on one ID's stream, and one on another's under the
+        // same upload ID, chunk by chunk in turn.
+        one.push(ms(1), &initial(b"a", 300, &a[..100])).unwrap();
+        two.push(ms(2), &initial(b"a", 200, &c[..100])).unwrap();
+        one.push(ms(3), &initial(b"b", 500, &b[..250])).unwrap();
+        one.push(ms(4), &continuation(b"a", 100, &a[100..200])).unwrap();
+        two.push(ms(5), &continuation(b"a", 100, &c[100..])).unwrap();
+        one.push(ms(6), &continuation(b"b", 250, &b[250..])).unwrap();
+        one.push(ms(7), &continuation(b"a", 200, &a[200..])).unwrap();
+        one.push(ms(8), &framed(&[], b"plain")).unwrap();
+
+        assert_eq!(&[(ms(3), b.clone()), (ms(1), a.clone()), (ms(8), framed(&[], b"plain"))][..],
+                   one.get_ref().pushed());
+        assert_eq!(&[(ms(2), c.clone())][..], two.get_ref().pushed());
+    }
+
+    #[test]
+    fn refuses_what_does_not_fit() {
+        let clock = ManualClock::new(ms(0));
+        let (mut reassembler, _) = reassembler(&clock);
+        let data = original(3000);
+        assert_match!(Err(ReassemblyError::StagingFull { needed: 5000, max: 4096 }),
+                      reassembler.push(ms(0), &initial(b"big", 5000, b"")));
+        reassembler.push(ms(0), &initial(b"a", 3000, &data[..2000])).unwrap();
+        assert_match!(Err(ReassemblyError::StagingFull { needed: 2500, max: 4096 }),
+                      reassembler.push(ms(0), &continuation(b"b", 0, &data[..2500])));
+        reassembler.push(ms(0), &continuation(b"b", 0, &data[..10])).unwrap();
+        assert_match!(Err(ReassemblyError::TooManyUploads { max: 2 }),
+                      reassembler.push(ms(0), &continuation(b"c", 0, b"x")));
+        assert_match!(Err(ReassemblyError::OutOfBounds { offset: 2999, len: 2, total: 3000 }),
+                      reassembler.push(ms(0), &continuation(b"a", 2999, b"xy")));
+        assert_match!(Err(ReassemblyError::LengthMismatch { total: 3000, got: 2000 }),
+                      reassembler.push(ms(0), &initial(b"a", 2000, b"")));
+        assert_match!(Err(ReassemblyError::OutOfBounds { offset: 0, len: 10, total: 5 }),
+                      reassembler.push(ms(0), &initial(b"b", 5, b"")));
+        assert_match!(Err(ReassemblyError::BadAttribute { key: "offset" }),
+                      reassembler.push(ms(0),
+                                       &framed(&[(UPLOAD_ID, b"a".to_vec()), (OFFSET, vec![1])],
+                                               b"")));
+        assert_match!(Err(ReassemblyError::Extensions(_)), reassembler.push(ms(0), b"\x00"));
+        reassembler.push(ms(0), &continuation(b"a", 2000, &data[2000..])).unwrap();
+        assert_eq!(&[(ms(0), data)][..], reassembler.get_ref().pushed());
+    }
+
+    #[test]
+    fn failed_completion_retries() {
+        let clock = ManualClock::new(ms(0));
+        let mut reassembler = Reassembler::new(ScriptedStream::<(), ()>::new(vec![Err(())]),
+                                               &clock,
+                                               ReassemblyLimits::default());
+        let data = original(20);
+        reassembler.push(ms(1), &initial(b"u", 20, &data[..10])).unwrap();
+        assert_match!(Err(ReassemblyError::Push(())),
+                      reassembler.push(ms(2), &continuation(b"u", 10, &data[10..])));
+        assert_eq!(1, reassembler.stats().uploads);
+        reassembler.push(ms(3), &continuation(b"u", 10, &data[10..])).unwrap();
+        assert_eq!(&[(ms(1), data)][..], reassembler.get_ref().pushed());
+    }
+}
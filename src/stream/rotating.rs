@@ -0,0 +1,270 @@
+//! Records split over numbered segment files in a directory, so that a
+//! long capture is never one file growing without bound. Segments are
+//! named by their number, eight decimal digits, with a `.rec` extension,
+//! and each holds records as a `FileStream` writes them.
+//!
+//! A segment is sealed before the next is created: whatever it owes is
+//! written, and it is flushed and synced to disk. A crash can so only cut
+//! short the last segment, which `intact_len` says where to truncate. A
+//! segment whose sync fails is synced again before anything else is done.
+
+use std::cmp;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use Stream;
+use super::file::{record_len, FileStream};
+
+const EXTENSION: &'static str = "rec";
+
+/// The number a segment file is named for.
+fn segment_number(path: &Path) -> Option<u64> {
+    if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+        return None;
+    }
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| if stem.len() == 8 { stem.parse().ok() } else { None })
+}
+
+/// Appends records to the segment files in a directory, starting a new
+/// segment once the current one would grow past a limit.
+#[derive(Debug)]
+pub struct RotatingFileStream {
+    dir: PathBuf,
+    max_segment_bytes: u64,
+    /// The number of the next segment to create.
+    next: u64,
+    current: Option<FileStream<File>>,
+    /// A sealed segment whose sync failed, and is owed.
+    unsynced: Option<File>,
+    sync: fn(&File) -> io::Result<()>,
+    segments: Vec<PathBuf>,
+}
+
+impl RotatingFileStream {
+    /// A stream writing segments to `dir`, created if need be, numbered on
+    /// from any segments already there. No segment is created until the
+    /// first push.
+    pub fn new<P: AsRef<Path>>(dir: P, max_segment_bytes: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+        let mut next = 0;
+        for entry in try!(fs::read_dir(&dir)) {
+            if let Some(number) = segment_number(&try!(entry).path()) {
+                next = cmp::max(next, number + 1);
+            }
+        }
+        Ok(RotatingFileStream {
+            dir: dir,
+            max_segment_bytes: max_segment_bytes,
+            next: next,
+            current: None,
+            unsynced: None,
+            sync: File::sync_all,
+            segments: vec![],
+        })
+    }
+
+    pub fn max_segment_bytes(&self) -> u64 {
+        self.max_segment_bytes
+    }
+
+    /// The segments this stream created, in order, the last of them the
+    /// one being written.
+    pub fn segments(&self) -> &[PathBuf] {
+        &self.segments
+    }
+
+    /// Writes what the current segment owes, then flushes and syncs it.
+    /// It is kept current if settling it fails, and its file kept to sync
+    /// on the next seal if syncing it does.
+    fn seal(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.current.take() {
+            match stream.extract() {
+                Ok((file, _)) => self.unsynced = Some(file),
+                Err((stream, e)) => {
+                    self.current = Some(stream);
+                    return Err(e);
+                }
+            }
+        }
+        let file = match self.unsynced.take() {
+            None => return Ok(()),
+            Some(file) => file,
+        };
+        match (self.sync)(&file) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.unsynced = Some(file);
+                Err(e)
+            }
+        }
+    }
+
+    fn open_next(&mut self) -> io::Result<()> {
+        let path = self.dir.join(format!("{:08}.{}", self.next, EXTENSION));
+        let file = try!(OpenOptions::new().write(true).create_new(true).open(&path));
+        self.next += 1;
+        self.segments.push(path);
+        self.current = Some(FileStream::new(file));
+        Ok(())
+    }
+}
+
+impl Stream for RotatingFileStream {
+    type PushErr = io::Error;
+    /// Starts a new segment first if the record would take the current one
+    /// past the limit. A record over the limit on its own is written to a
+    /// segment of its own.
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        let len = record_len(payload.len());
+        let roll = match self.current {
+            None => true,
+            Some(ref stream) => {
+                stream.offset() > 0 && stream.offset() + len > self.max_segment_bytes
+            }
+        };
+        if roll {
+            try!(self.seal());
+            try!(self.open_next());
+        }
+        self.current.as_mut().unwrap().push(ts, payload)
+    }
+
+    type Extract = Vec<PathBuf>;
+    type ExtractErr = io::Error;
+    /// Seals the current segment, and hands over the paths of every segment
+    /// this stream created, in order.
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let mut stream = self;
+        match stream.seal() {
+            Ok(()) => Ok(stream.segments),
+            Err(e) => Err((stream, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::*;
+    use stream::file::{intact_len, Index, RecordReader};
+    use Stream;
+
+    /// An empty directory to work in, particular to the test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("sousveillance-rotating-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn records(path: &Path) -> Vec<(Duration, Vec<u8>)> {
+        let file = File::open(path).unwrap();
+        RecordReader::new(file, Index::default())
+            .range(Duration::from_secs(0), Duration::from_secs(u64::max_value()))
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn len(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn rolls_over_before_the_limit() {
+        let dir = scratch("limit");
+        let mut stream = RotatingFileStream::new(&dir, 40).unwrap();
+        // Records of 23, 23, 63, 18 and 18 bytes.
+        let payloads: [&[u8]; 5] = [&[1; 10], &[2; 10], &[3; 50], &[4; 5], &[5; 5]];
+        for (i, payload) in payloads.iter().enumerate() {
+            stream.push(Duration::from_millis(i as u64), payload).unwrap();
+        }
+        let segments = stream.extract().unwrap();
+        let names: Vec<_> = segments.iter()
+                                    .map(|path| path.file_name().unwrap().to_str().unwrap())
+                                    .collect();
+        assert_eq!(vec!["00000000.rec", "00000001.rec", "00000002.rec", "00000003.rec"], names);
+        assert_eq!(vec![23, 23, 63, 36], segments.iter().map(|path| len(path)).collect::<Vec<_>>());
+        let payloads_in = |path| records(path).into_iter().map(|(_, p)| p).collect::<Vec<_>>();
+        assert_eq!(vec![payloads[3].to_vec(), payloads[4].to_vec()], payloads_in(&segments[3]));
+        for path in &segments {
+            assert_eq!(len(path), intact_len(&mut File::open(path).unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn keeps_order_across_segments() {
+        let dir = scratch("order");
+        let mut stream = RotatingFileStream::new(&dir, 100).unwrap();
+        let pushed: Vec<_> = (0..200_u8)
+                                 .map(|i| {
+                                     (Duration::from_millis(i as u64), vec![i; i as usize % 97])
+                                 })
+                                 .collect();
+        for &(ts, ref payload) in &pushed {
+            stream.push(ts, payload).unwrap();
+        }
+        let segments = stream.extract().unwrap();
+        assert!(segments.len() > 1);
+        for path in &segments {
+            let records = records(path);
+            assert!(len(path) <= 100 || records.len() == 1);
+        }
+        let read: Vec<_> = segments.iter().flat_map(|path| records(path)).collect();
+        assert_eq!(pushed, read);
+    }
+
+    fn failing_sync(_: &File) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "sync failed"))
+    }
+
+    #[test]
+    fn owes_a_failed_sync() {
+        let dir = scratch("sync");
+        let mut stream = RotatingFileStream::new(&dir, 20).unwrap();
+        stream.push(Duration::from_millis(1), b"first").unwrap();
+        stream.sync = failing_sync;
+        for _ in 0..2 {
+            let e = stream.push(Duration::from_millis(2), b"second").unwrap_err();
+            assert_eq!(io::ErrorKind::Other, e.kind());
+            assert_eq!(1, stream.segments().len());
+        }
+        stream.sync = File::sync_all;
+        stream.push(Duration::from_millis(2), b"second").unwrap();
+        assert_eq!(2, stream.segments().len());
+
+        stream.sync = failing_sync;
+        let (stream, _) = stream.extract().unwrap_err();
+        let (mut stream, e) = stream.extract().unwrap_err();
+        assert_eq!(io::ErrorKind::Other, e.kind());
+        stream.sync = File::sync_all;
+        let segments = stream.extract().unwrap();
+        assert_eq!(vec![(Duration::from_millis(2), b"second".to_vec())], records(&segments[1]));
+    }
+
+    #[test]
+    fn numbers_on_from_existing_segments() {
+        let dir = scratch("existing");
+        let mut first = RotatingFileStream::new(&dir, 20).unwrap();
+        first.push(Duration::from_millis(1), b"first").unwrap();
+        first.push(Duration::from_millis(2), b"second").unwrap();
+        assert_eq!(2, first.extract().unwrap().len());
+
+        let mut second = RotatingFileStream::new(&dir, 20).unwrap();
+        assert!(second.segments().is_empty());
+        second.push(Duration::from_millis(3), b"third").unwrap();
+        let segments = second.extract().unwrap();
+        assert_eq!(vec![dir.join("00000002.rec")], segments);
+        assert_eq!(vec![(Duration::from_millis(3), b"third".to_vec())], records(&segments[0]));
+    }
+}
@@ -0,0 +1,356 @@
+//! Tracking the sequence numbers devices put in their payloads, to notice
+//! lost messages and resets.
+
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use callback::{Calls, Local, Sendable};
+
+use super::{Pressure, Stream};
+
+/// Reads a sequence number from a payload.
+pub type Extractor = fn(&[u8]) -> Option<u32>;
+
+/// The sequence number as the payload's first four bytes, big-endian.
+pub fn be_prefix(payload: &[u8]) -> Option<u32> {
+    if payload.len() < 4 {
+        None
+    } else {
+        Some(BigEndian::read_u32(payload))
+    }
+}
+
+/// What to make of 0 after `u32::MAX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wraparound {
+    /// The sequence carries on from 0.
+    Continue,
+    /// The device was reset, as for any other lower number.
+    Regression,
+}
+
+impl Default for Wraparound {
+    fn default() -> Self {
+        Wraparound::Continue
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    /// Sequence numbers from `expected` up to but excluding `got` never came.
+    Gap {
+        expected: u32,
+        got: u32,
+        missing_count: u32,
+    },
+    /// `got` is not after `last`, as when a device restarts its count or
+    /// sends a message twice.
+    Regression {
+        last: u32,
+        got: u32,
+    },
+    /// The payload holds no sequence number.
+    Unparseable,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    pub gaps: u64,
+    /// Sequence numbers missed across every gap.
+    pub missing: u64,
+    pub regressions: u64,
+    pub unparseable: u64,
+    /// Times the sequence carried on from 0 after `u32::MAX`.
+    pub wraparounds: u64,
+}
+
+/// A stream that checks each payload's sequence number follows the last
+/// one stored, and otherwise stores it just the same.
+///
+/// Only payloads the inner stream stored count, so a push retried after a
+/// failure is not taken for a regression.
+///
+/// Its anomaly callback need not be `Send` unless it is over `Sendable`,
+/// as `new_send` makes it.
+pub struct SequenceTracker<S, K: Calls<Anomaly> = Local> {
+    stream: S,
+    extractor: Extractor,
+    wraparound: Wraparound,
+    last: Option<u32>,
+    stats: SequenceStats,
+    on_anomaly: Option<Box<K::FnMut>>,
+}
+
+impl<S> SequenceTracker<S> {
+    pub fn new(stream: S, extractor: Extractor) -> Self {
+        SequenceTracker::without_callbacks(stream, extractor)
+    }
+
+    pub fn set_anomaly_callback<F: FnMut(Anomaly) + 'static>(&mut self, callback: F) {
+        self.on_anomaly = Some(Box::new(callback));
+    }
+}
+
+impl<S> SequenceTracker<S, Sendable> {
+    /// A stream that is `Send` whenever its inner stream is.
+    pub fn new_send(stream: S, extractor: Extractor) -> Self {
+        SequenceTracker::without_callbacks(stream, extractor)
+    }
+
+    pub fn set_anomaly_callback<F: FnMut(Anomaly) + Send + 'static>(&mut self, callback: F) {
+        self.on_anomaly = Some(Box::new(callback));
+    }
+}
+
+impl<S, K: Calls<Anomaly>> SequenceTracker<S, K> {
+    fn without_callbacks(stream: S, extractor: Extractor) -> Self {
+        SequenceTracker {
+            stream: stream,
+            extractor: extractor,
+            wraparound: Wraparound::default(),
+            last: None,
+            stats: SequenceStats::default(),
+            on_anomaly: None,
+        }
+    }
+
+    pub fn set_wraparound(&mut self, wraparound: Wraparound) {
+        self.wraparound = wraparound;
+    }
+
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+
+    /// The last sequence number stored.
+    pub fn last(&self) -> Option<u32> {
+        self.last
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn check(&mut self, got: Option<u32>) {
+        let anomaly = match (self.last, got) {
+            (_, None) => {
+                self.stats.unparseable += 1;
+                Some(Anomaly::Unparseable)
+            }
+            (None, Some(_)) => None,
+            (Some(last), Some(got)) => {
+                let wraps = last == u32::max_value() && self.wraparound == Wraparound::Continue;
+                let expected = if wraps {
+                    Some(0)
+                } else {
+                    last.checked_add(1)
+                };
+                match expected {
+                    Some(expected) if got == expected => {
+                        if wraps {
+                            self.stats.wraparounds += 1;
+                        }
+                        None
+                    }
+                    Some(expected) if got > expected => {
+                        self.stats.gaps += 1;
+                        self.stats.missing += (got - expected) as u64;
+                        Some(Anomaly::Gap {
+                            expected: expected,
+                            got: got,
+                            missing_count: got - expected,
+                        })
+                    }
+                    _ => {
+                        self.stats.regressions += 1;
+                        Some(Anomaly::Regression {
+                            last: last,
+                            got: got,
+                        })
+                    }
+                }
+            }
+        };
+        if got.is_some() {
+            self.last = got;
+        }
+        if let (Some(anomaly), Some(ref mut callback)) = (anomaly, self.on_anomaly.as_mut()) {
+            (**callback)(anomaly);
+        }
+    }
+}
+
+impl<S: Stream, K: Calls<Anomaly>> Stream for SequenceTracker<S, K> {
+    type PushErr = S::PushErr;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        try!(self.stream.push(ts, payload));
+        let got = (self.extractor)(payload);
+        self.check(got);
+        Ok(())
+    }
+
+    fn push_owned(&mut self, ts: Duration, payload: Vec<u8>) -> Result<(), Self::PushErr> {
+        let got = (self.extractor)(&payload);
+        try!(self.stream.push_owned(ts, payload));
+        self.check(got);
+        Ok(())
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.stream.is_busy()
+    }
+
+    type Extract = (S::Extract, SequenceStats);
+    type ExtractErr = S::ExtractErr;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let SequenceTracker { stream, extractor, wraparound, last, stats, on_anomaly } = self;
+        match stream.extract() {
+            Ok(extract) => Ok((extract, stats)),
+            Err((stream, e)) => {
+                Err((SequenceTracker {
+                    stream: stream,
+                    extractor: extractor,
+                    wraparound: wraparound,
+                    last: last,
+                    stats: stats,
+                    on_anomaly: on_anomaly,
+                },
+                     e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use test_support::stream::ScriptedStream;
+    use Stream;
+
+    fn payload(seq: u32) -> Vec<u8> {
+        let mut payload = vec![0; 4];
+        BigEndian::write_u32(&mut payload, seq);
+        payload.extend_from_slice(b"reading");
+        payload
+    }
+
+    /// Feeds every payload through a tracker, returning the anomalies it
+    /// reported, its stats and how many payloads the inner stream stored,
+    /// checking they are the payloads fed.
+    fn track(wraparound: Wraparound, payloads: &[Vec<u8>]) -> (Vec<Anomaly>, SequenceStats, usize) {
+        let anomalies = Rc::new(RefCell::new(vec![]));
+        let mut tracker = SequenceTracker::new(ScriptedStream::<(), ()>::default(), be_prefix);
+        tracker.set_wraparound(wraparound);
+        let seen = anomalies.clone();
+        tracker.set_anomaly_callback(move |anomaly| seen.borrow_mut().push(anomaly));
+        for (i, payload) in payloads.iter().enumerate() {
+            tracker.push(Duration::from_millis(i as u64), payload).unwrap();
+        }
+        let (pushed, stats) = tracker.extract().map_err(|_| ()).unwrap();
+        let stored: Vec<_> = pushed.into_iter().map(|(_, payload)| payload).collect();
+        assert_eq!(payloads, &stored[..]);
+        let stored = stored.len();
+        let anomalies = anomalies.borrow().clone();
+        (anomalies, stats, stored)
+    }
+
+    #[test]
+    fn gap_and_regression() {
+        let payloads: Vec<_> = vec![7, 8, 11, 12, 3, 4].into_iter().map(payload).collect();
+        let (anomalies, stats, stored) = track(Wraparound::Continue, &payloads);
+        assert_eq!(vec![Anomaly::Gap {
+                            expected: 9,
+                            got: 11,
+                            missing_count: 2,
+                        },
+                        Anomaly::Regression {
+                            last: 12,
+                            got: 3,
+                        }],
+                   anomalies);
+        assert_eq!(SequenceStats {
+                       gaps: 1,
+                       missing: 2,
+                       regressions: 1,
+                       unparseable: 0,
+                       wraparounds: 0,
+                   },
+                   stats);
+        assert_eq!(6, stored);
+    }
+
+    #[test]
+    fn duplicate_is_regression() {
+        let payloads: Vec<_> = vec![1, 1].into_iter().map(payload).collect();
+        assert_eq!(vec![Anomaly::Regression { last: 1, got: 1 }],
+                   track(Wraparound::Continue, &payloads).0);
+        let max = u32::max_value();
+        let payloads: Vec<_> = vec![max, max].into_iter().map(payload).collect();
+        assert_eq!(vec![Anomaly::Regression { last: max, got: max }],
+                   track(Wraparound::Regression, &payloads).0);
+    }
+
+    #[test]
+    fn wraparound_policies() {
+        let max = u32::max_value();
+        let payloads: Vec<_> = vec![max - 1, max, 0, 1].into_iter().map(payload).collect();
+
+        let (anomalies, stats, stored) = track(Wraparound::Continue, &payloads);
+        assert!(anomalies.is_empty());
+        assert_eq!(1, stats.wraparounds);
+        assert_eq!(0, stats.regressions);
+        assert_eq!(4, stored);
+
+        let (anomalies, stats, stored) = track(Wraparound::Regression, &payloads);
+        assert_eq!(vec![Anomaly::Regression { last: max, got: 0 }], anomalies);
+        assert_eq!(0, stats.wraparounds);
+        assert_eq!(1, stats.regressions);
+        assert_eq!(4, stored);
+
+        let payloads: Vec<_> = vec![max, 2].into_iter().map(payload).collect();
+        assert_eq!(vec![Anomaly::Gap {
+                            expected: 0,
+                            got: 2,
+                            missing_count: 2,
+                        }],
+                   track(Wraparound::Continue, &payloads).0);
+    }
+
+    #[test]
+    fn unparseable() {
+        let payloads = vec![payload(1), b"abc".to_vec(), vec![], payload(2)];
+        let (anomalies, stats, stored) = track(Wraparound::Continue, &payloads);
+        assert_eq!(vec![Anomaly::Unparseable, Anomaly::Unparseable], anomalies);
+        assert_eq!(2, stats.unparseable);
+        assert_eq!(0, stats.gaps);
+        assert_eq!(4, stored);
+    }
+
+    #[test]
+    fn failed_push_is_not_tracked() {
+        let inner = ScriptedStream::<(), ()>::new(vec![Ok(()), Err(())]);
+        let mut tracker = SequenceTracker::new(inner, be_prefix);
+        tracker.push(Duration::from_millis(0), &payload(1)).unwrap();
+        assert!(tracker.push(Duration::from_millis(1), &payload(2)).is_err());
+        tracker.push(Duration::from_millis(2), &payload(2)).unwrap();
+        assert_eq!(SequenceStats::default(), tracker.stats());
+        assert_eq!(Some(2), tracker.last());
+    }
+}
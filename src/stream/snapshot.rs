@@ -0,0 +1,150 @@
+//! Copying out what a live stream holds without extracting it. A snapshot
+//! is an envelope body, as `ExtractEnvelope::write_body` would write for
+//! the stream's extract, so that `server::snapshot` can put snapshots in
+//! envelopes and `PortableReader` read them back like extracts.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+
+use super::{ExtractDescriptor, ExtractEnvelope, FileStream, Guarded, SequenceTracker, TextLog};
+
+/// What a snapshot wrote, with `bytes` always known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub descriptor: ExtractDescriptor,
+}
+
+/// A stream whose contents can be read through a shared borrow.
+pub trait SnapshotStream {
+    /// Whether a snapshot can be taken now; a stream that cannot is
+    /// skipped rather than snapshotted.
+    fn can_snapshot(&self) -> bool {
+        true
+    }
+
+    /// Writes the stream's contents as an envelope body, leaving the
+    /// stream as it was.
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo>;
+}
+
+fn write_snapshot<E: ExtractEnvelope, W: Write>(extract: &E, w: W) -> io::Result<SnapshotInfo> {
+    let mut w = w;
+    let descriptor = extract.describe();
+    let written = try!(extract.write_body(&mut w));
+    Ok(SnapshotInfo {
+        descriptor: ExtractDescriptor { bytes: Some(written), ..descriptor },
+    })
+}
+
+/// Stands in for an extract with a descriptor already worked out, for a
+/// wrapper's `ExtractEnvelope` impl to add its metadata to.
+struct Described(ExtractDescriptor);
+
+impl ExtractEnvelope for Described {
+    fn describe(&self) -> ExtractDescriptor {
+        self.0.clone()
+    }
+
+    fn write_body<W: Write>(&self, _: &mut W) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+/// Without the index sidecar, which can be rebuilt from the data.
+impl SnapshotStream for FileStream<Vec<u8>> {
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+        write_snapshot(&(&self.get_ref()[..], None), w)
+    }
+}
+
+impl SnapshotStream for FileStream<Cursor<Vec<u8>>> {
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+        write_snapshot(&(&self.get_ref().get_ref()[..], None), w)
+    }
+}
+
+impl SnapshotStream for TextLog<Vec<u8>> {
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+        write_snapshot(&(&self.get_ref()[..], self.lines()), w)
+    }
+}
+
+impl SnapshotStream for TextLog<Cursor<Vec<u8>>> {
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+        write_snapshot(&(&self.get_ref().get_ref()[..], self.lines()), w)
+    }
+}
+
+/// With the stats as `sequence.*` metadata.
+impl<S: SnapshotStream> SnapshotStream for SequenceTracker<S> {
+    fn can_snapshot(&self) -> bool {
+        self.get_ref().can_snapshot()
+    }
+
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+        let info = try!(self.get_ref().snapshot(w));
+        Ok(SnapshotInfo { descriptor: (Described(info.descriptor), self.stats()).describe() })
+    }
+}
+
+/// Not while a push is intended, which could land partway through, and
+/// with how many pushes were intended as `intents` otherwise.
+impl<S: SnapshotStream> SnapshotStream for Guarded<S> {
+    fn can_snapshot(&self) -> bool {
+        self.push_intents() == 0 && self.get_ref().can_snapshot()
+    }
+
+    fn snapshot<W: Write>(&self, w: W) -> io::Result<SnapshotInfo> {
+        let mut info = try!(self.get_ref().snapshot(w));
+        info.descriptor.metadata.push(("intents".to_owned(), self.push_intents() as u64));
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use stream::{ExtractKind, PreviewMode, SequenceStats};
+    use stream::sequence::be_prefix;
+    use Stream;
+
+    #[test]
+    fn snapshots_match_extracts() {
+        let mut stream = SequenceTracker::new(FileStream::new(vec![]), be_prefix);
+        for (i, &n) in [1_u32, 3].iter().enumerate() {
+            let payload = [0, 0, 0, n as u8];
+            stream.push(Duration::from_millis(i as u64), &payload).unwrap();
+        }
+        let mut body = vec![];
+        let info = stream.snapshot(&mut body).unwrap();
+
+        let stats = stream.stats();
+        assert_eq!(SequenceStats { gaps: 1, missing: 1, ..SequenceStats::default() }, stats);
+        let extract = (stream.into_inner().into_inner(), stats);
+        assert_eq!(extract.describe(), info.descriptor);
+        assert_eq!(Some(2), info.descriptor.records);
+        let mut extracted = vec![];
+        extract.write_body(&mut extracted).unwrap();
+        assert_eq!(extracted, body);
+    }
+
+    #[test]
+    fn guarded_mid_push_is_not_snapshotted() {
+        let mut stream = Guarded::new(TextLog::new(vec![], PreviewMode::default()));
+        stream.push(Duration::from_millis(0), b"line").unwrap();
+        let intent = stream.begin_push_intent();
+        assert!(!stream.can_snapshot());
+        intent.end();
+        assert!(stream.can_snapshot());
+
+        let mut body = vec![];
+        let info = stream.snapshot(&mut body).unwrap();
+        assert_eq!(ExtractKind::Text, info.descriptor.kind);
+        assert_eq!(Some(1), info.descriptor.records);
+        assert_eq!(Some(body.len() as u64), info.descriptor.bytes);
+        assert_eq!(Some(0), info.descriptor.get("intents"));
+    }
+}
@@ -0,0 +1,176 @@
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use message::payload::{SubRecordError, SubRecords, Width};
+use Stream;
+use super::Pressure;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SplitError<P> {
+    /// Nothing was pushed.
+    Malformed(SubRecordError),
+    /// The sub-records before `index` were pushed.
+    Push {
+        index: usize,
+        error: P,
+    },
+}
+
+impl<P: Display> Display for SplitError<P> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SplitError::Malformed(ref e) => e.fmt(f),
+            SplitError::Push { index, ref error } => write!(f, "sub-record {}: {}", index, error),
+        }
+    }
+}
+
+impl<P: error::Error> error::Error for SplitError<P> {
+    fn description(&self) -> &str {
+        match *self {
+            SplitError::Malformed(ref e) => e.description(),
+            SplitError::Push { ref error, .. } => error.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SplitError::Malformed(ref e) => Some(e),
+            SplitError::Push { ref error, .. } => Some(error),
+        }
+    }
+}
+
+/// Pushes each sub-record of a payload to the inner stream on its own, all
+/// with the payload's timestamp. A payload with malformed framing is
+/// rejected whole.
+pub struct SubRecordSplit<S> {
+    stream: S,
+    width: Width,
+}
+
+impl<S: Stream> SubRecordSplit<S> {
+    pub fn new(stream: S, width: Width) -> Self {
+        SubRecordSplit {
+            stream: stream,
+            width: width,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream> Stream for SubRecordSplit<S> {
+    type PushErr = SplitError<S::PushErr>;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        if let Some(Err(e)) = SubRecords::new(payload, self.width).find(Result::is_err) {
+            return Err(SplitError::Malformed(e));
+        }
+        for (index, record) in SubRecords::new(payload, self.width).enumerate() {
+            try!(self.stream.push(ts, record.unwrap()).map_err(|e| {
+                SplitError::Push {
+                    index: index,
+                    error: e,
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn pressure(&self) -> Pressure {
+        self.stream.pressure()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.stream.is_busy()
+    }
+
+    type Extract = S::Extract;
+    type ExtractErr = S::ExtractErr;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let width = self.width;
+        self.stream.extract().map_err(|(stream, e)| (SubRecordSplit::new(stream, width), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use message::payload::{SubRecordError, SubRecordWriter, Width};
+    use Stream;
+
+    #[derive(Debug, Default)]
+    struct Recording(Vec<(Duration, Vec<u8>)>);
+
+    impl Stream for Recording {
+        type PushErr = &'static str;
+        fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+            if payload == b"fail" {
+                return Err("refused");
+            }
+            self.0.push((ts, payload.to_owned()));
+            Ok(())
+        }
+
+        type Extract = Vec<(Duration, Vec<u8>)>;
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(self.0)
+        }
+    }
+
+    fn payload(records: &[&[u8]]) -> Vec<u8> {
+        let mut payload = vec![];
+        {
+            let mut writer = SubRecordWriter::to_vec(&mut payload, Width::U16);
+            for record in records {
+                writer.push(record).unwrap();
+            }
+        }
+        payload
+    }
+
+    #[test]
+    fn splits_with_shared_timestamp() {
+        let mut stream = SubRecordSplit::new(Recording::default(), Width::U16);
+        let ts = Duration::from_millis(5);
+        assert_eq!(Ok(()), stream.push(ts, &payload(&[b"a", b"", b"bc"])));
+        assert_eq!(vec![(ts, b"a".to_vec()), (ts, vec![]), (ts, b"bc".to_vec())],
+                   stream.extract().ok().unwrap());
+    }
+
+    #[test]
+    fn truncated_last_record() {
+        let mut stream = SubRecordSplit::new(Recording::default(), Width::U16);
+        let mut truncated = payload(&[b"a", b"bcd"]);
+        truncated.pop();
+        assert_eq!(Err(SplitError::Malformed(SubRecordError::TruncatedRecord {
+                       offset: 3,
+                       len: 3,
+                       remaining: 2,
+                   })),
+                   stream.push(Duration::from_millis(1), &truncated));
+        assert!(stream.get_ref().0.is_empty());
+    }
+
+    #[test]
+    fn push_failure_index() {
+        let mut stream = SubRecordSplit::new(Recording::default(), Width::U16);
+        assert_eq!(Err(SplitError::Push {
+                       index: 1,
+                       error: "refused",
+                   }),
+                   stream.push(Duration::from_millis(1), &payload(&[b"a", b"fail", b"b"])));
+        assert_eq!(1, stream.get_ref().0.len());
+    }
+}
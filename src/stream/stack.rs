@@ -0,0 +1,493 @@
+//! Checking that the wrappers of a stream are in an order that works.
+//! Some orders store wrong data without failing a single push, so each of
+//! this crate's wrappers declares what it does with payloads, and a
+//! `StackBuilder` refuses a stack with a wrapper outside, that is pushed
+//! to before, another it conflicts with:
+//!
+//! | outside                  | inside                      | conflict      |
+//! |--------------------------|-----------------------------|---------------|
+//! | `transforms_payload`:    | `requires_plaintext`:       | `Plaintext`   |
+//! | `Compressed`,            | `Referencing`, which would  |               |
+//! | `Encrypting`,            | hash ciphertext and never   |               |
+//! | `Referencing`            | share a payload;            |               |
+//! |                          | `Reassembler`,              |               |
+//! |                          | `SubRecordSplit`,           |               |
+//! |                          | `SequenceTracker`           |               |
+//! | `reorders`: `Reassembler`| `requires_order`:           | `Reordered`   |
+//! |                          | `SequenceTracker`, which    |               |
+//! |                          | would count regressions     |               |
+//! | `drops`, and not         | `requires_order`            | `Dropped`     |
+//! | `reorders`: none yet     |                             |               |
+//! | `splits`:                | `aggregates`: `Reassembler`,| `Split`       |
+//! | `SubRecordSplit`         | which would take each       |               |
+//! |                          | sub-record for a chunk      |               |
+//! | anything                 | `outermost`: `Guarded`,     | `NotOutermost`|
+//! |                          | whose intents could not be  |               |
+//! |                          | taken                       |               |
+//!
+//! Pairs are checked however far apart they are, and only the first
+//! conflict of a pair is reported. `StackBuilder::allow` lets a pair
+//! through for a stack that is meant to be that way.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use Clock;
+#[cfg(feature = "gzip")]
+use super::Compressed;
+use super::{Backing, Encrypting, Guarded, PayloadCipher, Reassembler, Referencing,
+            SequenceTracker, Stream, SubRecordSplit};
+
+/// What a wrapper does with what is pushed to it, on its way to the
+/// stream it wraps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WrapperTraits {
+    pub name: &'static str,
+    /// Pushes something other than the payloads, or parts or wholes of
+    /// them, that it is given.
+    pub transforms_payload: bool,
+    /// Pushes records in another order than it was given them.
+    pub reorders: bool,
+    /// Pushes nothing for some payloads it takes.
+    pub drops: bool,
+    /// Pushes one record for several payloads.
+    pub aggregates: bool,
+    /// Pushes several records for one payload.
+    pub splits: bool,
+    /// Reads the payloads, as devices send them.
+    pub requires_plaintext: bool,
+    /// Takes records out of order, or missing, for an anomaly.
+    pub requires_order: bool,
+    /// Only does its job where it is pushed to first.
+    pub outermost: bool,
+}
+
+/// A wrapper that does nothing of note.
+const PLAIN: WrapperTraits = WrapperTraits {
+    name: "",
+    transforms_payload: false,
+    reorders: false,
+    drops: false,
+    aggregates: false,
+    splits: false,
+    requires_plaintext: false,
+    requires_order: false,
+    outermost: false,
+};
+
+pub const COMPRESSED: WrapperTraits = WrapperTraits {
+    name: "Compressed",
+    transforms_payload: true,
+    // Packs payloads together, but reads none, so pieces of them do as well
+    // as wholes.
+    aggregates: false,
+    ..PLAIN
+};
+
+pub const ENCRYPTING: WrapperTraits = WrapperTraits {
+    name: "Encrypting",
+    transforms_payload: true,
+    ..PLAIN
+};
+
+pub const GUARDED: WrapperTraits = WrapperTraits {
+    name: "Guarded",
+    outermost: true,
+    ..PLAIN
+};
+
+pub const REASSEMBLER: WrapperTraits = WrapperTraits {
+    name: "Reassembler",
+    reorders: true,
+    drops: true,
+    aggregates: true,
+    requires_plaintext: true,
+    ..PLAIN
+};
+
+pub const REFERENCING: WrapperTraits = WrapperTraits {
+    name: "Referencing",
+    transforms_payload: true,
+    requires_plaintext: true,
+    ..PLAIN
+};
+
+pub const SEQUENCE_TRACKER: WrapperTraits = WrapperTraits {
+    name: "SequenceTracker",
+    requires_plaintext: true,
+    requires_order: true,
+    ..PLAIN
+};
+
+pub const SUB_RECORD_SPLIT: WrapperTraits = WrapperTraits {
+    name: "SubRecordSplit",
+    splits: true,
+    requires_plaintext: true,
+    ..PLAIN
+};
+
+/// Every wrapper of this crate, by name.
+pub const DECLARED: [WrapperTraits; 7] = [COMPRESSED,
+                                          ENCRYPTING,
+                                          GUARDED,
+                                          REASSEMBLER,
+                                          REFERENCING,
+                                          SEQUENCE_TRACKER,
+                                          SUB_RECORD_SPLIT];
+
+/// A name no wrapper of this crate goes by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UndeclaredWrapper(pub String);
+
+impl Display for UndeclaredWrapper {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "no wrapper is declared as {}", self.0)
+    }
+}
+
+impl error::Error for UndeclaredWrapper {
+    fn description(&self) -> &str {
+        "undeclared wrapper"
+    }
+}
+
+/// The traits of the wrapper named `name`, as `StackBuilder::allow` names
+/// them.
+pub fn declared(name: &str) -> Result<WrapperTraits, UndeclaredWrapper> {
+    DECLARED.iter()
+            .find(|traits| traits.name == name)
+            .cloned()
+            .ok_or_else(|| UndeclaredWrapper(name.to_owned()))
+}
+
+/// A stream wrapper whose traits are declared.
+pub trait Wrapper: Stream {
+    fn traits() -> WrapperTraits;
+}
+
+#[cfg(feature = "gzip")]
+impl<S: Stream> Wrapper for Compressed<S> {
+    fn traits() -> WrapperTraits {
+        COMPRESSED
+    }
+}
+
+impl<S: Stream, C: PayloadCipher> Wrapper for Encrypting<S, C> {
+    fn traits() -> WrapperTraits {
+        ENCRYPTING
+    }
+}
+
+impl<S: Stream> Wrapper for Guarded<S> {
+    fn traits() -> WrapperTraits {
+        GUARDED
+    }
+}
+
+impl<S: Stream, C: Clock> Wrapper for Reassembler<S, C> {
+    fn traits() -> WrapperTraits {
+        REASSEMBLER
+    }
+}
+
+impl<S: Stream, B: Backing> Wrapper for Referencing<S, B> {
+    fn traits() -> WrapperTraits {
+        REFERENCING
+    }
+}
+
+impl<S: Stream> Wrapper for SequenceTracker<S> {
+    fn traits() -> WrapperTraits {
+        SEQUENCE_TRACKER
+    }
+}
+
+impl<S: Stream> Wrapper for SubRecordSplit<S> {
+    fn traits() -> WrapperTraits {
+        SUB_RECORD_SPLIT
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// The inner wrapper would read what the outer made of the payloads.
+    Plaintext,
+    /// The inner wrapper would take the outer's reordering for anomalies.
+    Reordered,
+    /// The inner wrapper would take what the outer drops for lost.
+    Dropped,
+    /// The inner wrapper would aggregate the outer's pieces, not payloads.
+    Split,
+    /// The inner wrapper must be outermost.
+    NotOutermost,
+}
+
+impl Conflict {
+    fn between(outer: &WrapperTraits, inner: &WrapperTraits) -> Option<Self> {
+        if outer.transforms_payload && inner.requires_plaintext {
+            Some(Conflict::Plaintext)
+        } else if outer.reorders && inner.requires_order {
+            Some(Conflict::Reordered)
+        } else if outer.drops && inner.requires_order {
+            Some(Conflict::Dropped)
+        } else if outer.splits && inner.aggregates {
+            Some(Conflict::Split)
+        } else if inner.outermost {
+            Some(Conflict::NotOutermost)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            Conflict::Plaintext => "the inner one would not see plaintext payloads",
+            Conflict::Reordered => "the inner one would take reordered records for anomalies",
+            Conflict::Dropped => "the inner one would take dropped records for lost",
+            Conflict::Split => "the inner one would aggregate pieces of payloads",
+            Conflict::NotOutermost => "the inner one must be outermost",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackError {
+    pub outer: &'static str,
+    pub inner: &'static str,
+    pub conflict: Conflict,
+}
+
+impl Display for StackError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} outside {}: {}", self.outer, self.inner, self.conflict)
+    }
+}
+
+impl error::Error for StackError {
+    fn description(&self) -> &str {
+        "incompatible stream wrappers"
+    }
+}
+
+/// Wraps a stream a wrapper at a time, innermost first, for `build` to
+/// check the order of.
+pub struct StackBuilder<S> {
+    stream: S,
+    /// Innermost first.
+    layers: Vec<WrapperTraits>,
+    allowed: Vec<(&'static str, &'static str)>,
+}
+
+impl<S: Stream> StackBuilder<S> {
+    pub fn new(stream: S) -> Self {
+        StackBuilder {
+            stream: stream,
+            layers: vec![],
+            allowed: vec![],
+        }
+    }
+
+    /// Wraps the stack so far with what `wrap` makes of it.
+    pub fn wrap<W, F>(self, wrap: F) -> StackBuilder<W>
+        where W: Wrapper,
+              F: FnOnce(S) -> W
+    {
+        let mut layers = self.layers;
+        layers.push(W::traits());
+        StackBuilder {
+            stream: wrap(self.stream),
+            layers: layers,
+            allowed: self.allowed,
+        }
+    }
+
+    /// Lets the wrapper named `outer` be outside the one named `inner`
+    /// whatever their conflict.
+    pub fn allow(mut self, outer: &'static str, inner: &'static str) -> Self {
+        self.allowed.push((outer, inner));
+        self
+    }
+
+    /// The wrappers so far, outermost first.
+    pub fn layers(&self) -> Vec<&'static str> {
+        self.layers.iter().rev().map(|traits| traits.name).collect()
+    }
+
+    /// The stack, unless a wrapper conflicts with one it is outside of
+    /// that it was not allowed to be.
+    pub fn build(self) -> Result<S, StackError> {
+        for (i, inner) in self.layers.iter().enumerate() {
+            for outer in &self.layers[i + 1..] {
+                if self.allowed.contains(&(outer.name, inner.name)) {
+                    continue;
+                }
+                if let Some(conflict) = Conflict::between(outer, inner) {
+                    return Err(StackError {
+                        outer: outer.name,
+                        inner: inner.name,
+                        conflict: conflict,
+                    });
+                }
+            }
+        }
+        Ok(self.stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use message::payload::Width;
+    use stream::sequence::be_prefix;
+    use stream::{ContentStore, FileStream, MemoryBacking, ReassemblyLimits};
+    use test_support::stream::XorTestCipher;
+
+    fn base() -> StackBuilder<FileStream<Vec<u8>>> {
+        StackBuilder::new(FileStream::new(vec![]))
+    }
+
+    fn cipher() -> XorTestCipher {
+        XorTestCipher { id: 1, key: 0x5a }
+    }
+
+    fn store() -> Arc<ContentStore<MemoryBacking>> {
+        Arc::new(ContentStore::in_memory())
+    }
+
+    fn reassembler<S: Stream>(stream: S) -> Reassembler<S, ManualClock> {
+        Reassembler::new(stream,
+                         ManualClock::new(Duration::from_millis(0)),
+                         ReassemblyLimits::default())
+    }
+
+    fn conflict<S>(built: Result<S, StackError>) -> (&'static str, &'static str, Conflict) {
+        match built {
+            Ok(_) => panic!("built"),
+            Err(e) => (e.outer, e.inner, e.conflict),
+        }
+    }
+
+    #[test]
+    fn incompatible_pairs() {
+        let ciphertext = base().wrap(|s| Referencing::new(s, store()))
+                               .wrap(|s| Encrypting::new(s, cipher()))
+                               .build();
+        assert_eq!(("Encrypting", "Referencing", Conflict::Plaintext),
+                   conflict(ciphertext));
+        let references = base().wrap(|s| SequenceTracker::new(s, be_prefix))
+                               .wrap(|s| Referencing::new(s, store()))
+                               .build();
+        assert_eq!(("Referencing", "SequenceTracker", Conflict::Plaintext),
+                   conflict(references));
+        let reordered = base().wrap(|s| SequenceTracker::new(s, be_prefix))
+                              .wrap(reassembler)
+                              .build();
+        assert_eq!(("Reassembler", "SequenceTracker", Conflict::Reordered),
+                   conflict(reordered));
+        let split = base().wrap(reassembler).wrap(|s| SubRecordSplit::new(s, Width::U16)).build();
+        assert_eq!(("SubRecordSplit", "Reassembler", Conflict::Split), conflict(split));
+        let guarded = base().wrap(Guarded::new)
+                            .wrap(|s| SequenceTracker::new(s, be_prefix))
+                            .build();
+        assert_eq!(("SequenceTracker", "Guarded", Conflict::NotOutermost),
+                   conflict(guarded));
+
+        let e = StackError {
+            outer: "Reassembler",
+            inner: "SequenceTracker",
+            conflict: Conflict::Dropped,
+        };
+        assert_eq!("Reassembler outside SequenceTracker: the inner one would take dropped \
+                    records for lost",
+                   e.to_string());
+    }
+
+    #[test]
+    fn conflicts_are_found_however_far_apart() {
+        let far = base().wrap(|s| SequenceTracker::new(s, be_prefix))
+                        .wrap(|s| SubRecordSplit::new(s, Width::U8))
+                        .wrap(|s| Encrypting::new(s, cipher()))
+                        .build();
+        assert_eq!(("Encrypting", "SequenceTracker", Conflict::Plaintext),
+                   conflict(far));
+        let sampler = WrapperTraits {
+            name: "Sampler",
+            drops: true,
+            ..PLAIN
+        };
+        assert_eq!(Some(Conflict::Dropped),
+                   Conflict::between(&sampler, &declared("SequenceTracker").unwrap()));
+    }
+
+    #[test]
+    fn deep_stacks_build() {
+        let stack = base().wrap(|s| Encrypting::new(s, cipher()))
+                          .wrap(|s| Referencing::new(s, store()))
+                          .wrap(|s| SequenceTracker::new(s, be_prefix))
+                          .wrap(|s| SubRecordSplit::new(s, Width::U8))
+                          .wrap(Guarded::new);
+        assert_eq!(vec!["Guarded",
+                        "SubRecordSplit",
+                        "SequenceTracker",
+                        "Referencing",
+                        "Encrypting"],
+                   stack.layers());
+        assert!(stack.build().is_ok());
+        assert!(base().wrap(|s| SubRecordSplit::new(s, Width::U16))
+                      .wrap(reassembler)
+                      .wrap(Guarded::new)
+                      .build()
+                      .is_ok());
+        assert!(base().build().is_ok());
+    }
+
+    #[test]
+    fn allowed_pairs_build() {
+        let stack = base().wrap(|s| SequenceTracker::new(s, be_prefix))
+                          .wrap(reassembler)
+                          .allow("Reassembler", "SequenceTracker");
+        assert!(stack.build().is_ok());
+
+        // Allowing one pair leaves the others checked.
+        let stack = base().wrap(|s| SequenceTracker::new(s, be_prefix))
+                          .wrap(reassembler)
+                          .wrap(|s| Encrypting::new(s, cipher()))
+                          .allow("Reassembler", "SequenceTracker");
+        assert_eq!(("Encrypting", "SequenceTracker", Conflict::Plaintext),
+                   conflict(stack.build()));
+    }
+
+    /// Each wrapper type, which must implement `Wrapper` by a declaration
+    /// of its own.
+    #[test]
+    fn every_wrapper_is_declared() {
+        type Inner = FileStream<Vec<u8>>;
+        let mut listed = vec![Encrypting::<Inner, XorTestCipher>::traits(),
+                              Guarded::<Inner>::traits(),
+                              Reassembler::<Inner, ManualClock>::traits(),
+                              Referencing::<Inner, MemoryBacking>::traits(),
+                              SequenceTracker::<Inner>::traits(),
+                              SubRecordSplit::<Inner>::traits()];
+        #[cfg(feature = "gzip")]
+        listed.push(Compressed::<Inner>::traits());
+        listed.sort_by_key(|traits| traits.name);
+        let declared: Vec<_> = DECLARED.iter()
+                                       .cloned()
+                                       .filter(|traits| {
+                                           cfg!(feature = "gzip") || traits.name != "Compressed"
+                                       })
+                                       .collect();
+        assert_eq!(declared, listed);
+        for traits in &DECLARED {
+            assert_eq!(Ok(*traits), super::declared(traits.name));
+        }
+        assert_eq!(Err(UndeclaredWrapper("Sampler".to_owned())), super::declared("Sampler"));
+    }
+}
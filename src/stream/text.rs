@@ -0,0 +1,251 @@
+//! Records as lines of text, for reading without tooling: the timestamp in
+//! milliseconds, the payload's length and, depending on the mode, a preview
+//! of the payload, as in `ts_millis=1000 len=5 preview=hello`.
+//!
+//! A preview never holds a line break: in UTF-8 previews, backslashes and
+//! control characters are escaped, and hex previews cannot hold either.
+
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use util::millis;
+use Stream;
+
+/// How much of each payload to show, and how.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreviewMode {
+    /// The first so many bytes, in lowercase hex.
+    HexPrefix(usize),
+    /// The first so many bytes, decoded as UTF-8 with invalid sequences
+    /// replaced, and escaped.
+    Utf8Lossy(usize),
+    /// No preview field at all.
+    None,
+}
+
+impl Default for PreviewMode {
+    fn default() -> Self {
+        PreviewMode::HexPrefix(16)
+    }
+}
+
+/// Escapes `s` so that it fits on one line.
+fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+                let _ = write!(out, "\\u{{{:x}}}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// The fixed fields of a line, and its preview as written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextLine {
+    pub timestamp: Duration,
+    pub len: u64,
+    pub preview: Option<String>,
+}
+
+pub struct TextLog<W> {
+    writer: W,
+    mode: PreviewMode,
+    lines: u64,
+    line: String,
+}
+
+impl<W: Write> TextLog<W> {
+    pub fn new(writer: W, mode: PreviewMode) -> Self {
+        TextLog {
+            writer: writer,
+            mode: mode,
+            lines: 0,
+            line: String::new(),
+        }
+    }
+
+    /// How many lines have been written.
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl TextLog<()> {
+    /// Parses a line, with or without its line break. The preview is
+    /// returned as written, escapes and all.
+    pub fn parse_line(line: &str) -> Option<TextLine> {
+        let line = line.trim_right_matches(|c| c == '\n' || c == '\r');
+        let mut fields = line.splitn(3, ' ');
+        let timestamp = match fields.next().and_then(|f| field(f, "ts_millis=")) {
+            Some(millis) => Duration::from_millis(millis),
+            None => return None,
+        };
+        let len = match fields.next().and_then(|f| field(f, "len=")) {
+            Some(len) => len,
+            None => return None,
+        };
+        let preview = match fields.next() {
+            Some(f) if f.starts_with("preview=") => Some(f["preview=".len()..].to_owned()),
+            Some(_) => return None,
+            None => None,
+        };
+        Some(TextLine {
+            timestamp: timestamp,
+            len: len,
+            preview: preview,
+        })
+    }
+}
+
+fn field(f: &str, name: &str) -> Option<u64> {
+    if f.starts_with(name) {
+        f[name.len()..].parse().ok()
+    } else {
+        None
+    }
+}
+
+impl<W: Write> Stream for TextLog<W> {
+    type PushErr = io::Error;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        self.line.clear();
+        let _ = write!(self.line, "ts_millis={} len={}", millis(ts), payload.len());
+        match self.mode {
+            PreviewMode::HexPrefix(n) => {
+                self.line.push_str(" preview=");
+                for byte in payload.iter().take(n) {
+                    let _ = write!(self.line, "{:02x}", byte);
+                }
+            }
+            PreviewMode::Utf8Lossy(n) => {
+                self.line.push_str(" preview=");
+                let prefix = &payload[..::std::cmp::min(n, payload.len())];
+                escape(&String::from_utf8_lossy(prefix), &mut self.line);
+            }
+            PreviewMode::None => {}
+        }
+        self.line.push('\n');
+        try!(self.writer.write_all(self.line.as_bytes()));
+        self.lines += 1;
+        Ok(())
+    }
+
+    /// The writer, flushed, and how many lines were written to it.
+    type Extract = (W, u64);
+    type ExtractErr = io::Error;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let mut stream = self;
+        match stream.writer.flush() {
+            Ok(()) => Ok((stream.writer, stream.lines)),
+            Err(e) => Err((stream, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use std::time::Duration;
+
+    use super::*;
+    use Stream;
+
+    const MODES: [PreviewMode; 5] = [PreviewMode::HexPrefix(4),
+                                     PreviewMode::HexPrefix(64),
+                                     PreviewMode::Utf8Lossy(4),
+                                     PreviewMode::Utf8Lossy(64),
+                                     PreviewMode::None];
+
+    fn log(mode: PreviewMode, payloads: &[&[u8]]) -> (Vec<u8>, u64) {
+        let mut log = TextLog::new(vec![], mode);
+        for (i, payload) in payloads.iter().enumerate() {
+            log.push(Duration::from_millis(1000 * i as u64), payload).unwrap();
+        }
+        log.extract().map_err(|(_, e)| e).unwrap()
+    }
+
+    #[test]
+    fn one_line_per_record() {
+        let payloads: [&[u8]; 5] = [b"line one\nline two\r\n",
+                                    b"\xff\xfe not utf-8 \xc3",
+                                    b"",
+                                    b"back\\slash\x00\x1b[31m",
+                                    "\u{2028}separator".as_bytes()];
+        for &mode in &MODES {
+            let (bytes, lines) = log(mode, &payloads);
+            assert_eq!(payloads.len() as u64, lines);
+            let text = String::from_utf8(bytes).unwrap();
+            let parsed: Vec<_> = text.lines().map(|l| TextLog::parse_line(l).unwrap()).collect();
+            assert_eq!(payloads.len(), parsed.len(), "{:?}: {:?}", mode, text);
+            for (i, (line, payload)) in parsed.iter().zip(payloads.iter()).enumerate() {
+                assert_eq!(Duration::from_millis(1000 * i as u64), line.timestamp);
+                assert_eq!(payload.len() as u64, line.len);
+                assert_eq!(mode == PreviewMode::None, line.preview.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn previews() {
+        let payloads: [&[u8]; 3] = [b"a\nb\\c", b"\xffok", b""];
+        let previews = |mode| -> Vec<_> {
+            let (bytes, _) = log(mode, &payloads);
+            bytes.lines().map(|l| TextLog::parse_line(&l.unwrap()).unwrap().preview).collect()
+        };
+        assert_eq!(vec![Some("610a".to_owned()), Some("ff6f".to_owned()), Some("".to_owned())],
+                   previews(PreviewMode::HexPrefix(2)));
+        assert_eq!(vec![Some("a\\nb\\\\c".to_owned()),
+                        Some("\u{fffd}ok".to_owned()),
+                        Some("".to_owned())],
+                   previews(PreviewMode::Utf8Lossy(16)));
+        assert_eq!(vec![None, None, None], previews(PreviewMode::None));
+    }
+
+    #[test]
+    fn parse_rejects_other_lines() {
+        assert_eq!(Some(TextLine {
+                       timestamp: Duration::from_millis(5),
+                       len: 0,
+                       preview: None,
+                   }),
+                   TextLog::parse_line("ts_millis=5 len=0\n"));
+        assert_eq!(None, TextLog::parse_line(""));
+        assert_eq!(None, TextLog::parse_line("ts_millis=5"));
+        assert_eq!(None, TextLog::parse_line("len=0 ts_millis=5"));
+        assert_eq!(None, TextLog::parse_line("ts_millis=5 len=x"));
+        assert_eq!(None, TextLog::parse_line("ts_millis=5 len=0 other=1"));
+    }
+
+    #[test]
+    fn many_lines() {
+        let mut log = TextLog::new(vec![], PreviewMode::Utf8Lossy(8));
+        for i in 0..5000_u64 {
+            let payload = format!("record {}\n", i);
+            log.push(Duration::from_millis(i), payload.as_bytes()).unwrap();
+        }
+        let (bytes, lines) = log.extract().map_err(|(_, e)| e).unwrap();
+        assert_eq!(5000, lines);
+        assert_eq!(5000, bytes.lines().count());
+        assert_eq!(5000, bytes.iter().filter(|&&b| b == b'\n').count());
+    }
+}
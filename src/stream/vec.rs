@@ -0,0 +1,167 @@
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use Stream;
+use super::TransactionalStream;
+
+/// A bounded `VecStream` already holds as many records as it may.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityExceeded {
+    pub capacity: usize,
+}
+
+impl Display for CapacityExceeded {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "stream full at {} records", self.capacity)
+    }
+}
+
+impl error::Error for CapacityExceeded {
+    fn description(&self) -> &str {
+        "stream full"
+    }
+}
+
+/// Keeps every record in memory, in push order, and hands them all over
+/// on extraction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VecStream {
+    records: Vec<(Duration, Vec<u8>)>,
+    capacity: Option<usize>,
+}
+
+impl VecStream {
+    pub fn new() -> Self {
+        VecStream::default()
+    }
+
+    /// A stream refusing pushes once it holds `capacity` records.
+    pub fn bounded(capacity: usize) -> Self {
+        VecStream {
+            records: vec![],
+            capacity: Some(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// What was pushed so far, in push order.
+    pub fn records(&self) -> &[(Duration, Vec<u8>)] {
+        &self.records
+    }
+
+    fn check_capacity(&self) -> Result<(), CapacityExceeded> {
+        match self.capacity {
+            Some(capacity) if self.records.len() >= capacity => {
+                Err(CapacityExceeded { capacity: capacity })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Stream for VecStream {
+    type PushErr = CapacityExceeded;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        try!(self.check_capacity());
+        self.records.push((ts, payload.to_vec()));
+        Ok(())
+    }
+
+    fn push_owned(&mut self, ts: Duration, payload: Vec<u8>) -> Result<(), Self::PushErr> {
+        try!(self.check_capacity());
+        self.records.push((ts, payload));
+        Ok(())
+    }
+
+    type Extract = Vec<(Duration, Vec<u8>)>;
+    type ExtractErr = ::Void;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        Ok(self.records)
+    }
+}
+
+/// Checks the capacity on `prepare`, so that `commit` only stores the
+/// staged record.
+impl TransactionalStream for VecStream {
+    type Prepared = (Duration, Vec<u8>);
+    fn prepare(&mut self, ts: Duration, payload: &[u8]) -> Result<Self::Prepared, Self::PushErr> {
+        try!(self.check_capacity());
+        Ok((ts, payload.to_vec()))
+    }
+
+    fn commit(&mut self, prepared: Self::Prepared) -> Result<(), Self::PushErr> {
+        self.push_owned(prepared.0, prepared.1)
+    }
+
+    fn rollback(&mut self, _: Self::Prepared) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::time::Duration;
+
+    use super::*;
+    use Stream;
+    use stream::TransactionalStream;
+
+    fn records(pushes: &[(u64, Vec<u8>)]) -> Vec<(Duration, Vec<u8>)> {
+        pushes.iter()
+              .map(|&(millis, ref payload)| (Duration::from_millis(millis), payload.clone()))
+              .collect()
+    }
+
+    quickcheck_test! {
+    extracts_in_push_order(pushes: Vec<(u64, Vec<u8>)>, owned: bool; bool) {
+        let mut stream = VecStream::new();
+        for (ts, payload) in records(&pushes) {
+            if owned {
+                stream.push_owned(ts, payload).unwrap();
+            } else {
+                stream.push(ts, &payload).unwrap();
+            }
+        }
+        stream.len() == pushes.len() && stream.is_empty() == pushes.is_empty() &&
+        stream.extract().ok() == Some(records(&pushes))
+    }}
+
+    quickcheck_test! {
+    bounded_keeps_the_first(pushes: Vec<(u64, Vec<u8>)>, capacity: u8; bool) {
+        let capacity = capacity as usize % 8;
+        let mut stream = VecStream::bounded(capacity);
+        let refused = records(&pushes)
+                          .into_iter()
+                          .filter_map(|(ts, payload)| stream.push(ts, &payload).err())
+                          .collect::<Vec<_>>();
+        let kept = cmp::min(capacity, pushes.len());
+        refused == vec![CapacityExceeded { capacity: capacity }; pushes.len() - kept] &&
+        stream.extract().ok() == Some(records(&pushes[..kept]))
+    }}
+
+    #[test]
+    fn prepare_checks_capacity() {
+        let mut stream = VecStream::bounded(1);
+        let staged = stream.prepare(Duration::from_millis(1), b"staged").unwrap();
+        let at = staged.1.as_ptr();
+        let dropped = stream.prepare(Duration::from_millis(2), b"dropped").unwrap();
+        stream.rollback(dropped);
+        TransactionalStream::commit(&mut stream, staged).unwrap();
+        assert_eq!(at, stream.records()[0].1.as_ptr());
+        assert_eq!(Err(CapacityExceeded { capacity: 1 }),
+                   stream.prepare(Duration::from_millis(3), b"full"));
+        assert_eq!(&[(Duration::from_millis(1), b"staged".to_vec())], stream.records());
+    }
+}
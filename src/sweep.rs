@@ -0,0 +1,164 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use Clock;
+
+struct Activity {
+    last: Mutex<Duration>,
+    swept: AtomicBool,
+}
+
+/// A connection's reader, recording when it last read anything.
+pub struct Tracked<R, C> {
+    reader: R,
+    clock: C,
+    activity: Arc<Activity>,
+}
+
+impl<R, C> Tracked<R, C> {
+    /// Whether the connection ended because `Sweeper::sweep_idle` shut it
+    /// down rather than because the peer closed it.
+    pub fn was_swept(&self) -> bool {
+        self.activity.swept.load(Ordering::SeqCst)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read, C: Clock> Read for Tracked<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.reader.read(buf));
+        if n > 0 {
+            *self.activity.last.lock().unwrap() = self.clock.now();
+        }
+        Ok(n)
+    }
+}
+
+/// Shuts down connections that have gone quiet, so that the reads blocking
+/// their sessions return and the sessions end.
+pub struct Sweeper {
+    connections: Vec<(TcpStream, Arc<Activity>)>,
+}
+
+impl Sweeper {
+    pub fn new() -> Self {
+        Sweeper { connections: vec![] }
+    }
+
+    /// Starts tracking `stream`, which counts as active as of now.
+    pub fn track<C: Clock>(&mut self,
+                           stream: TcpStream,
+                           clock: C)
+                           -> io::Result<Tracked<TcpStream, C>> {
+        let activity = Arc::new(Activity {
+            last: Mutex::new(clock.now()),
+            swept: AtomicBool::new(false),
+        });
+        self.connections.push((try!(stream.try_clone()), activity.clone()));
+        Ok(Tracked {
+            reader: stream,
+            clock: clock,
+            activity: activity,
+        })
+    }
+
+    /// How many connections are tracked and not yet swept. One whose
+    /// `Tracked` has been dropped still counts until the next sweep.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Shuts down every connection that has read nothing for more than
+    /// `older_than` before `now`, returning how many. Connections whose
+    /// `Tracked` has been dropped are forgotten.
+    pub fn sweep_idle(&mut self, older_than: Duration, now: Duration) -> usize {
+        let mut swept = 0;
+        self.connections.retain(|&(ref stream, ref activity)| {
+            if Arc::strong_count(activity) == 1 {
+                return false;
+            }
+            if *activity.last.lock().unwrap() + older_than >= now {
+                return true;
+            }
+            activity.swept.store(true, Ordering::SeqCst);
+            let _ = stream.shutdown(Shutdown::Both);
+            swept += 1;
+            false
+        });
+        swept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::iter;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use test_support::frame;
+    use {test_support, Clock, Session};
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Duration>>);
+    impl Clock for Shared {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn sweeps_only_idle_connections() {
+        let clock = Shared(Arc::new(Mutex::new(Duration::from_millis(0))));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients: Vec<_> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        let mut sweeper = Sweeper::new();
+        let (tx, rx) = mpsc::channel();
+        let handlers: Vec<_> = (0..3)
+                                   .map(|_| {
+                                       let (conn, _) = listener.accept().unwrap();
+                                       let mut reader = sweeper.track(conn, clock.clone()).unwrap();
+                                       let tx = tx.clone();
+                                       thread::spawn(move || {
+                                           let finder = iter::once((b"id".to_vec(),
+                                                                    test_support::stream::Ok))
+                                                            .collect();
+                                           let mut server = test_support::server::Ok(finder);
+                                           for item in Session::new(&mut server, &mut reader) {
+                                               tx.send(item.is_ok()).unwrap();
+                                           }
+                                           reader.was_swept()
+                                       })
+                                   })
+                                   .collect();
+        assert_eq!(3, sweeper.len());
+
+        *clock.0.lock().unwrap() = Duration::from_millis(100);
+        clients[0].write_all(&frame(b"t", b"id", 1, b"")).unwrap();
+        assert_eq!(Ok(true), rx.recv());
+
+        assert_eq!(2, sweeper.sweep_idle(Duration::from_millis(50), Duration::from_millis(120)));
+        assert_eq!(1, sweeper.len());
+        clients.clear();
+        // Connections are accepted in whatever order they arrive, so which
+        // handler had the active one is not known.
+        let mut swept: Vec<_> = handlers.into_iter().map(|h| h.join().unwrap()).collect();
+        swept.sort();
+        assert_eq!(vec![false, true, true], swept);
+        assert_eq!(0, sweeper.sweep_idle(Duration::from_millis(0), Duration::from_millis(1000)));
+        assert_eq!(0, sweeper.len());
+    }
+}
@@ -0,0 +1,176 @@
+//! Helpers for testing `Server`s and `Stream`s, in this crate and, with
+//! the `test-support` feature, downstream.
+//!
+//! A property test of a custom stream, pushed to through a session:
+//!
+//! ```
+//! extern crate sousveillance_server;
+//!
+//! use std::collections::HashMap;
+//! use std::time::Duration;
+//!
+//! use sousveillance_server::test_support::server::CountingServer;
+//! use sousveillance_server::test_support::{frame, quickcheck, server, TestResult};
+//! use sousveillance_server::{Session, Stream};
+//!
+//! /// Keeps the total length of what was pushed.
+//! #[derive(Default)]
+//! struct Total(usize);
+//!
+//! impl Stream for Total {
+//!     type PushErr = ();
+//!     fn push(&mut self, _: Duration, payload: &[u8]) -> Result<(), ()> {
+//!         self.0 += payload.len();
+//!         Ok(())
+//!     }
+//!
+//!     type Extract = usize;
+//!     type ExtractErr = ();
+//!     fn extract(self) -> Result<usize, (Self, ())> {
+//!         Ok(self.0)
+//!     }
+//! }
+//!
+//! fn totals_every_payload(payloads: Vec<Vec<u8>>) -> TestResult {
+//!     let mut finder = HashMap::new();
+//!     finder.insert(b"id".to_vec(), Total::default());
+//!     let mut server = CountingServer::new(server::Ok(finder));
+//!     let capture: Vec<u8> = payloads.iter()
+//!                                    .flat_map(|p| frame(b"token", b"id", 1000, p))
+//!                                    .collect();
+//!     let consumed = Session::new(&mut server, &capture[..]).filter(|r| r.is_ok()).count();
+//!     let expected = payloads.iter().map(Vec::len).sum::<usize>();
+//!     let server::Ok(mut finder) = server.into_inner();
+//!     TestResult::from_bool(consumed == payloads.len() &&
+//!                           finder.remove(&b"id"[..]).unwrap().extract().ok() ==
+//!                           Some(expected))
+//! }
+//!
+//! fn main() {
+//!     quickcheck(totals_every_payload as fn(Vec<Vec<u8>>) -> TestResult);
+//! }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+
+use message::Header;
+
+pub use quickcheck::{quickcheck, Arbitrary, Gen, TestResult};
+
+pub trait ToBytes {
+    type Bytes;
+    fn to_bytes(self) -> Self::Bytes;
+}
+
+impl ToBytes for u16 {
+    type Bytes = [u8; 2];
+    fn to_bytes(self) -> Self::Bytes {
+        let mut bytes = [0_u8; 2];
+        BigEndian::write_u16(&mut bytes, self);
+        bytes
+    }
+}
+
+impl ToBytes for u64 {
+    type Bytes = [u8; 8];
+    fn to_bytes(self) -> Self::Bytes {
+        let mut bytes = [0_u8; 8];
+        BigEndian::write_u64(&mut bytes, self);
+        bytes
+    }
+}
+
+/// The encoded header stamped `millis`, through `Header::write_into`.
+pub fn header(token: &[u8], id: &[u8], millis: u64) -> Vec<u8> {
+    let header = Header {
+        token: token,
+        id: id,
+        timestamp: ::std::time::Duration::from_millis(millis),
+    };
+    let mut bytes = vec![0; header.encoded_len()];
+    header.write_into(&mut bytes).expect("header too large to encode");
+    bytes
+}
+
+/// The encoded message.
+pub fn message(token: &[u8], id: &[u8], millis: u64, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = header(token, id, millis);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// The message as a session reads it, after its two-byte length.
+pub fn frame(token: &[u8], id: &[u8], millis: u64, payload: &[u8]) -> Vec<u8> {
+    let message = message(token, id, millis, payload);
+    assert!(message.len() <= u16::max_value() as usize,
+            "message too large for a frame");
+    let mut bytes = (message.len() as u16).to_bytes().to_vec();
+    bytes.extend(message);
+    bytes
+}
+
+pub trait IntoCopyIterator: IntoIterator {
+    fn into_copy_iter(self) -> ::std::iter::Cloned<Self::IntoIter>;
+}
+
+impl<'a, T: 'a + Copy, I: IntoIterator<Item=&'a T>> IntoCopyIterator for I {
+    fn into_copy_iter(self) -> ::std::iter::Cloned<Self::IntoIter> {
+        self.into_iter().cloned()
+    }
+}
+
+#[macro_export]
+macro_rules! quickcheck_test {
+    ($test_name:ident ($($param_name:ident: $param_type:ty),+; $return_type:ty)
+     $body:block) => {
+        #[test]
+        fn $test_name() {
+            fn $test_name($($param_name: $param_type),+) -> $return_type
+                $body
+            $crate::test_support::quickcheck(
+                $test_name as fn($($param_type),+) -> $return_type);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! assert_match {
+    ($p:pat, $e:expr) => {
+        match $e {
+            $p => {},
+            bad => panic!("assertion failed: expected {}; got {:?}", stringify!($p), bad),
+        }
+    };
+    ($p:pat if $c:expr, $e:expr) => {
+        match $e {
+            $p if $c => {},
+            bad => panic!("assertion failed: expected {} if {}; got {:?}",
+                          stringify!($p),
+                          stringify!($c),
+                          bad),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! test_result_match {
+    ($p:pat, $e:expr) => {
+        match $e {
+            $p => $crate::test_support::TestResult::passed(),
+            bad => $crate::test_support::TestResult::error(
+                format!("expected {}; got {:?}", stringify!($p), bad)),
+        }
+    };
+    ($p:pat if $c:expr, $e:expr) => {
+        match $e {
+            $p if $c => $crate::test_support::TestResult::passed(),
+            bad => $crate::test_support::TestResult::error(
+                format!("expected {} if {}; got {:?}",
+                        stringify!($p), stringify!($c), bad)),
+        }
+    }
+}
+
+// After the macros, so that these can use them.
+pub mod server;
+pub mod stream;
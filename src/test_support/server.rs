@@ -0,0 +1,268 @@
+//! Mock servers.
+
+use std::time::Duration;
+
+use server::{AuthError, AuthResult, Consumed, DryRunOutcome, Finder};
+use trace::Spans;
+use {Server, Stream};
+use super::stream::Impossible;
+
+pub struct Unreachable;
+impl Server for Unreachable {
+    type Stream = Impossible;
+    type AuthErr = ::Void;
+    fn auth(&mut self, _: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        unreachable!();
+    }
+
+    type ConsumeOk = ();
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            _: &mut Spans)
+                            -> Consumed<Self> {
+        self.push_parts(token, id, timestamp, payload)
+    }
+}
+
+pub struct RefuseToAuth;
+impl Server for RefuseToAuth {
+    type Stream = Impossible;
+    type AuthErr = ::Void;
+    fn auth(&mut self, _: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        Err(AuthError::InvalidToken)
+    }
+
+    type ConsumeOk = ();
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            _: &mut Spans)
+                            -> Consumed<Self> {
+        self.push_parts(token, id, timestamp, payload)
+    }
+}
+
+pub struct CannotAuth;
+impl Server for CannotAuth {
+    type Stream = Impossible;
+    type AuthErr = ();
+    fn auth(&mut self, _: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        Err(AuthError::Other(()))
+    }
+
+    type ConsumeOk = ();
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            _: &mut Spans)
+                            -> Consumed<Self> {
+        self.push_parts(token, id, timestamp, payload)
+    }
+}
+
+pub struct Ok<S>(pub Finder<S>);
+impl<S: Stream> Server for Ok<S> {
+    type Stream = S;
+    type AuthErr = ::Void;
+    fn auth(&mut self, _: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        Result::Ok(&mut self.0)
+    }
+
+    type ConsumeOk = ();
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            _: &mut Spans)
+                            -> Consumed<Self> {
+        self.push_parts(token, id, timestamp, payload)
+    }
+}
+
+/// A `consume_parts` or `backfill_parts` call that a `CountingServer` saw.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumeCall {
+    pub token: Vec<u8>,
+    pub id: Vec<u8>,
+    pub timestamp: Duration,
+    pub payload: Vec<u8>,
+    pub backfill: bool,
+    /// Whether the inner server stored the message.
+    pub stored: bool,
+}
+
+/// Records every message consumed through it, in order.
+pub struct CountingServer<S> {
+    server: S,
+    calls: Vec<ConsumeCall>,
+}
+
+impl<S> CountingServer<S> {
+    pub fn new(server: S) -> Self {
+        CountingServer {
+            server: server,
+            calls: vec![],
+        }
+    }
+
+    pub fn calls(&self) -> &[ConsumeCall] {
+        &self.calls
+    }
+
+    /// How many of the calls the inner server stored.
+    pub fn stored(&self) -> usize {
+        self.calls.iter().filter(|call| call.stored).count()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.server
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> S {
+        self.server
+    }
+
+    fn record(&mut self,
+              token: &[u8],
+              id: &[u8],
+              timestamp: Duration,
+              payload: &[u8],
+              backfill: bool,
+              stored: bool) {
+        self.calls.push(ConsumeCall {
+            token: token.to_vec(),
+            id: id.to_vec(),
+            timestamp: timestamp,
+            payload: payload.to_vec(),
+            backfill: backfill,
+            stored: stored,
+        });
+    }
+}
+
+impl<S: Server> Server for CountingServer<S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = S::ConsumeOk;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        let result = self.server.consume_parts_traced(token, id, timestamp, payload, spans);
+        self.record(token, id, timestamp, payload, false, result.is_ok());
+        result
+    }
+
+    fn backfill_parts(&mut self,
+                      token: &[u8],
+                      id: &[u8],
+                      timestamp: Duration,
+                      payload: &[u8])
+                      -> Consumed<Self> {
+        let result = self.server.backfill_parts(token, id, timestamp, payload);
+        self.record(token, id, timestamp, payload, true, result.is_ok());
+        result
+    }
+
+    fn dry_run_parts(&mut self,
+                     token: &[u8],
+                     id: &[u8],
+                     timestamp: Duration,
+                     payload: &[u8])
+                     -> DryRunOutcome<Self::AuthErr> {
+        self.server.dry_run_parts(token, id, timestamp, payload)
+    }
+}
+
+/// Acknowledges each message its inner server stores with how many it
+/// stored before.
+pub struct Numbering<S> {
+    server: S,
+    stored: u64,
+}
+
+impl<S> Numbering<S> {
+    pub fn new(server: S) -> Self {
+        Numbering {
+            server: server,
+            stored: 0,
+        }
+    }
+}
+
+impl<S: Server> Server for Numbering<S> {
+    type Stream = S::Stream;
+    type AuthErr = S::AuthErr;
+    fn auth(&mut self, token: &[u8]) -> AuthResult<Self::Stream, Self::AuthErr> {
+        self.server.auth(token)
+    }
+
+    type ConsumeOk = u64;
+    fn consume_parts_traced(&mut self,
+                            token: &[u8],
+                            id: &[u8],
+                            timestamp: Duration,
+                            payload: &[u8],
+                            spans: &mut Spans)
+                            -> Consumed<Self> {
+        try!(self.server.consume_parts_traced(token, id, timestamp, payload, spans));
+        self.stored += 1;
+        Result::Ok(self.stored - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use std::time::Duration;
+
+    use super::*;
+    use server::ConsumeError;
+    use test_support::stream::ScriptedStream;
+    use Server;
+
+    #[test]
+    fn counts_scripted_pushes() {
+        let stream = ScriptedStream::<_, ()>::new(vec![Result::Ok(()), Err("full")]);
+        let mut server = CountingServer::new(Ok(iter::once((b"id".to_vec(), stream)).collect()));
+        let ts = Duration::from_secs(1);
+        assert!(server.consume_parts(b"t", b"id", ts, b"one").is_ok());
+        assert_match!(Err(ConsumeError::Push("full")),
+                      server.consume_parts(b"t", b"id", ts, b"two"));
+        assert!(server.backfill_parts(b"t", b"id", ts, b"three").is_ok());
+        assert_match!(Err(ConsumeError::MissingId),
+                      server.consume_parts(b"t", b"nope", ts, b"four"));
+
+        assert_eq!(4, server.calls().len());
+        assert_eq!(2, server.stored());
+        assert_eq!((b"two".to_vec(), false, false),
+                   (server.calls()[1].payload.clone(),
+                    server.calls()[1].backfill,
+                    server.calls()[1].stored));
+        assert!(server.calls()[2].backfill);
+        let Ok(mut finder) = server.into_inner();
+        let stream = finder.remove(&b"id"[..]).unwrap();
+        assert_eq!(0, stream.remaining());
+        assert_eq!(Result::Ok(vec![(ts, b"one".to_vec()), (ts, b"three".to_vec())]),
+                   stream.extract().map_err(|(_, e)| e));
+    }
+}
@@ -0,0 +1,196 @@
+//! Mock streams.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use stream::{DeadlineError, DeadlineExtract, PayloadCipher};
+use {Clock, Stream};
+
+/// A stream that cannot exist, for servers that never hand one out.
+pub struct Impossible(pub ::Void);
+impl Default for Impossible {
+    fn default() -> Self {
+        unreachable!()
+    }
+}
+impl Stream for Impossible {
+    type PushErr = ::Void;
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+        self.0.absurd()
+    }
+
+    type Extract = ::Void;
+    type ExtractErr = ::Void;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        self.0.absurd()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Broken;
+impl Stream for Broken {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+        Err(())
+    }
+
+    type Extract = ::Void;
+    type ExtractErr = ();
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        Err((self, ()))
+    }
+}
+
+impl DeadlineExtract for Broken {
+    fn extract_by<C: Clock>(self,
+                            _: &C,
+                            _: Duration)
+                            -> Result<Self::Extract, (Self, DeadlineError<Self::ExtractErr>)> {
+        Err((self, DeadlineError::Extract(())))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Ok;
+impl Stream for Ok {
+    type PushErr = ::Void;
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+        Result::Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ::Void;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        Result::Ok(())
+    }
+}
+
+impl DeadlineExtract for Ok {
+    fn extract_by<C: Clock>(self,
+                            _: &C,
+                            _: Duration)
+                            -> Result<Self::Extract, (Self, DeadlineError<Self::ExtractErr>)> {
+        Result::Ok(())
+    }
+}
+
+/// XORs every byte with `key`, which is no protection at all.
+#[derive(Clone, Debug)]
+pub struct XorTestCipher {
+    pub id: u16,
+    pub key: u8,
+}
+impl PayloadCipher for XorTestCipher {
+    type Err = ::Void;
+    fn id(&self) -> u16 {
+        self.id
+    }
+
+    fn seal(&mut self, _: Duration, plaintext: &[u8], out: &mut Vec<u8>)
+            -> Result<(), Self::Err> {
+        out.extend(plaintext.iter().map(|b| b ^ self.key));
+        Result::Ok(())
+    }
+
+    fn open(&mut self, ts: Duration, sealed: &[u8], out: &mut Vec<u8>)
+            -> Result<(), Self::Err> {
+        self.seal(ts, sealed, out)
+    }
+}
+
+/// Takes the given time to extract.
+#[derive(Debug)]
+pub struct Slow(pub Duration);
+impl Stream for Slow {
+    type PushErr = ::Void;
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+        Result::Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ::Void;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        Result::Ok(())
+    }
+}
+impl DeadlineExtract for Slow {
+    fn extract_by<C: Clock>(self,
+                            clock: &C,
+                            deadline: Duration)
+                            -> Result<Self::Extract, (Self, DeadlineError<Self::ExtractErr>)> {
+        let now = clock.now();
+        if now + self.0 <= deadline {
+            Result::Ok(())
+        } else if deadline > now {
+            Err((self, DeadlineError::TimedOut { waited: deadline - now }))
+        } else {
+            Err((self, DeadlineError::TimedOut { waited: Duration::from_millis(0) }))
+        }
+    }
+}
+
+/// Follows a script: each push takes the next scripted result, and is
+/// kept if that is `Ok`, without a copy if it was owned. Pushes past the end of the script succeed.
+/// Extracting takes the next result of its own script likewise, and hands
+/// over what was kept.
+#[derive(Debug)]
+pub struct ScriptedStream<P, E> {
+    pushes: VecDeque<Result<(), P>>,
+    extracts: VecDeque<Result<(), E>>,
+    pushed: Vec<(Duration, Vec<u8>)>,
+}
+
+impl<P, E> ScriptedStream<P, E> {
+    pub fn new(pushes: Vec<Result<(), P>>) -> Self {
+        ScriptedStream {
+            pushes: pushes.into_iter().collect(),
+            extracts: VecDeque::new(),
+            pushed: vec![],
+        }
+    }
+
+    pub fn set_extract_script(&mut self, extracts: Vec<Result<(), E>>) {
+        self.extracts = extracts.into_iter().collect();
+    }
+
+    /// What was kept so far, in push order.
+    pub fn pushed(&self) -> &[(Duration, Vec<u8>)] {
+        &self.pushed
+    }
+
+    /// How many scripted pushes are left.
+    pub fn remaining(&self) -> usize {
+        self.pushes.len()
+    }
+}
+
+impl<P, E> Default for ScriptedStream<P, E> {
+    fn default() -> Self {
+        ScriptedStream::new(vec![])
+    }
+}
+
+impl<P, E> Stream for ScriptedStream<P, E> {
+    type PushErr = P;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        try!(self.pushes.pop_front().unwrap_or(Result::Ok(())));
+        self.pushed.push((ts, payload.to_vec()));
+        Result::Ok(())
+    }
+
+    fn push_owned(&mut self, ts: Duration, payload: Vec<u8>) -> Result<(), Self::PushErr> {
+        try!(self.pushes.pop_front().unwrap_or(Result::Ok(())));
+        self.pushed.push((ts, payload));
+        Result::Ok(())
+    }
+
+    type Extract = Vec<(Duration, Vec<u8>)>;
+    type ExtractErr = E;
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        let mut stream = self;
+        match stream.extracts.pop_front() {
+            Some(Err(e)) => Err((stream, e)),
+            _ => Result::Ok(stream.pushed),
+        }
+    }
+}
@@ -0,0 +1,564 @@
+//! Timing each message through a session, phase by phase, for finding
+//! where tail latency goes. A session with tracing on keeps a `Recorder`
+//! of its last few `MessageTrace`s; each is a flat list of spans, nested by
+//! depth under one `message` span:
+//!
+//! | span          | covers                                              |
+//! |---------------|-----------------------------------------------------|
+//! | `read_prefix` | reading the length prefix, waiting on the peer too  |
+//! | `read_body`   | reading the rest of the frame                       |
+//! | `parse`       | parsing and checking the header                     |
+//! | `consume`     | the server's `consume_parts_traced`, in which       |
+//! |               | `TokenServer` adds `auth`, `route` and `push`, and  |
+//! |               | wrappers their own spans by name                    |
+//!
+//! Spans past `MAX_SPANS` a message are dropped. A recorder allocates only
+//! when tracing is turned on; one that is off does nothing but check so.
+
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+use Clock;
+
+/// The most spans a message keeps.
+pub const MAX_SPANS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub name: &'static str,
+    pub start: Duration,
+    pub duration: Duration,
+    /// How many spans enclose it: zero for `message`.
+    pub depth: usize,
+}
+
+/// The spans of the message being traced, for the consume path to add to.
+pub struct Spans {
+    clock: Option<Arc<Clock + Send + Sync>>,
+    spans: Vec<Span>,
+    /// The indices of the spans begun and not yet ended.
+    open: Vec<usize>,
+    /// Spans begun since `spans` filled up, and not yet ended.
+    dropped: usize,
+}
+
+impl Spans {
+    /// Records nothing, and allocates nothing.
+    pub fn off() -> Self {
+        Spans {
+            clock: None,
+            spans: Vec::new(),
+            open: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    fn on(clock: Arc<Clock + Send + Sync>) -> Self {
+        Spans {
+            clock: Some(clock),
+            spans: Vec::with_capacity(MAX_SPANS),
+            open: Vec::with_capacity(MAX_SPANS),
+            dropped: 0,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.clock.is_some()
+    }
+
+    /// Starts a span inside whichever was begun last and not ended.
+    pub fn begin(&mut self, name: &'static str) {
+        let now = match self.clock {
+            None => return,
+            Some(ref clock) => clock.now(),
+        };
+        if self.spans.len() == MAX_SPANS {
+            self.dropped += 1;
+            return;
+        }
+        self.open.push(self.spans.len());
+        self.spans.push(Span {
+            name: name,
+            start: now,
+            duration: Duration::from_millis(0),
+            depth: self.open.len() - 1,
+        });
+    }
+
+    /// Ends the span begun last.
+    pub fn end(&mut self) {
+        let now = match self.clock {
+            None => return,
+            Some(ref clock) => clock.now(),
+        };
+        if self.dropped > 0 {
+            self.dropped -= 1;
+            return;
+        }
+        if let Some(i) = self.open.pop() {
+            let span = &mut self.spans[i];
+            span.duration = now - cmp::min(now, span.start);
+        }
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    fn clear(&mut self) {
+        self.spans.clear();
+        self.open.clear();
+        self.dropped = 0;
+    }
+}
+
+/// What became of a traced message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Consumed,
+    Failed(io::ErrorKind),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageTrace {
+    spans: Vec<Span>,
+    pub outcome: Outcome,
+}
+
+impl MessageTrace {
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// The first span of this name.
+    pub fn span(&self, name: &str) -> Option<&Span> {
+        self.spans.iter().find(|span| span.name == name)
+    }
+}
+
+/// The last so many messages' traces.
+pub struct Recorder {
+    current: Spans,
+    traces: Vec<MessageTrace>,
+    capacity: usize,
+    /// Where the next trace goes once `traces` is full.
+    next: usize,
+}
+
+impl Recorder {
+    pub fn off() -> Self {
+        Recorder {
+            current: Spans::off(),
+            traces: Vec::new(),
+            capacity: 0,
+            next: 0,
+        }
+    }
+
+    /// Keeps the last `capacity` traces, timed by `clock`, allocating
+    /// room for all of them now.
+    pub fn new(capacity: usize, clock: Arc<Clock + Send + Sync>) -> Self {
+        Recorder {
+            current: Spans::on(clock),
+            traces: (0..capacity)
+                        .map(|_| {
+                            MessageTrace {
+                                spans: Vec::with_capacity(MAX_SPANS),
+                                outcome: Outcome::Consumed,
+                            }
+                        })
+                        .collect(),
+            capacity: capacity,
+            next: 0,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.current.is_on() && self.capacity > 0
+    }
+
+    /// Starts tracing a message, dropping whatever was begun since the last
+    /// one was finished.
+    pub fn start(&mut self) {
+        if self.is_on() {
+            self.current.clear();
+            self.current.begin("message");
+        }
+    }
+
+    pub fn spans(&mut self) -> &mut Spans {
+        &mut self.current
+    }
+
+    /// Ends every span still open and keeps the trace.
+    pub fn finish(&mut self, outcome: Outcome) {
+        if !self.is_on() || self.current.spans.is_empty() {
+            return;
+        }
+        while !self.current.open.is_empty() || self.current.dropped > 0 {
+            self.current.end();
+        }
+        let slot = self.next % self.capacity;
+        let trace = &mut self.traces[slot];
+        trace.spans.clear();
+        trace.spans.extend_from_slice(&self.current.spans);
+        trace.outcome = outcome;
+        self.next += 1;
+        self.current.clear();
+    }
+
+    /// Drops the trace begun, for a frame that never came.
+    pub fn abandon(&mut self) {
+        self.current.clear();
+    }
+
+    /// The traces kept, oldest first.
+    pub fn recent(&self) -> Vec<&MessageTrace> {
+        let kept = cmp::min(self.next, self.capacity);
+        (self.next - kept..self.next).map(|i| &self.traces[i % self.capacity]).collect()
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000
+}
+
+fn write_json_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    try!(w.write_all(b"\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(w.write_all(b"\\\"")),
+            '\\' => try!(w.write_all(b"\\\\")),
+            c if (c as u32) < 0x20 => try!(write!(w, "\\u{:04x}", c as u32)),
+            c => try!(write!(w, "{}", c)),
+        }
+    }
+    w.write_all(b"\"")
+}
+
+/// Writes `traces` as a Chrome trace-event JSON array, one complete event
+/// per span in microseconds, each message's on its own thread row and its
+/// `message` span carrying the outcome.
+pub fn write_chrome_trace<W: Write>(w: W, traces: &[&MessageTrace]) -> io::Result<()> {
+    let mut w = w;
+    try!(w.write_all(b"["));
+    let mut first = true;
+    for (tid, trace) in traces.iter().enumerate() {
+        for span in trace.spans() {
+            if !first {
+                try!(w.write_all(b","));
+            }
+            first = false;
+            try!(w.write_all(b"{\"name\":"));
+            try!(write_json_str(&mut w, span.name));
+            try!(write!(w,
+                        ",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{}",
+                        micros(span.start),
+                        micros(span.duration),
+                        tid + 1));
+            if span.depth == 0 {
+                let outcome = match trace.outcome {
+                    Outcome::Consumed => "consumed".to_owned(),
+                    Outcome::Failed(kind) => format!("failed: {:?}", kind),
+                };
+                try!(w.write_all(b",\"args\":{\"outcome\":"));
+                try!(write_json_str(&mut w, &outcome));
+                try!(w.write_all(b"}"));
+            }
+            try!(w.write_all(b"}"));
+        }
+    }
+    w.write_all(b"]")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::SharedClock;
+    use server::{Dedup, HighWaterMark, TokenServer};
+    use test_support::frame;
+    use test_support::server::CountingServer;
+    use util::millis;
+    use {Server, Session, Stream};
+
+    /// Takes 3 ms a push.
+    struct Slow(SharedClock);
+
+    impl Stream for Slow {
+        type PushErr = ::Void;
+        fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Self::PushErr> {
+            self.0.advance(Duration::from_millis(3));
+            Ok(())
+        }
+
+        type Extract = ();
+        type ExtractErr = ::Void;
+        fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+            Ok(())
+        }
+    }
+
+    /// Takes 1 ms a read.
+    struct Ticking {
+        bytes: Cursor<Vec<u8>>,
+        clock: SharedClock,
+    }
+
+    impl Read for Ticking {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.clock.advance(Duration::from_millis(1));
+            self.bytes.read(buf)
+        }
+    }
+
+    fn server(clock: &SharedClock) -> TokenServer<Slow> {
+        let mut server = TokenServer::new();
+        server.add_token(b"token").insert(b"id".to_vec(), Slow(clock.clone()));
+        server
+    }
+
+    fn shape(trace: &MessageTrace) -> Vec<(&'static str, u64, u64, usize)> {
+        trace.spans()
+             .iter()
+             .map(|span| (span.name, millis(span.start), millis(span.duration), span.depth))
+             .collect()
+    }
+
+    #[test]
+    fn session_spans_nest_and_stop_at_a_failure() {
+        let clock = SharedClock::new(Duration::from_millis(0));
+        let mut server = server(&clock);
+        let mut bytes = frame(b"token", b"id", 0, b"payload");
+        bytes.extend(frame(b"forged", b"id", 0, b"payload"));
+        let mut session = Session::new(&mut server,
+                                       Ticking {
+                                           bytes: Cursor::new(bytes),
+                                           clock: clock.clone(),
+                                       });
+        session.set_tracing(4, Arc::new(clock.clone()));
+        assert_eq!(2, session.by_ref().count());
+
+        let traces = session.recent_traces();
+        assert_eq!(2, traces.len());
+        assert_eq!(vec![("message", 0, 5, 0),
+                        ("read_prefix", 0, 1, 1),
+                        ("read_body", 1, 1, 1),
+                        ("parse", 2, 0, 1),
+                        ("consume", 2, 3, 1),
+                        ("auth", 2, 0, 2),
+                        ("route", 2, 0, 2),
+                        ("push", 2, 3, 2)],
+                   shape(traces[0]));
+        assert_eq!(Outcome::Consumed, traces[0].outcome);
+        assert_eq!(vec![("message", 5, 2, 0),
+                        ("read_prefix", 5, 1, 1),
+                        ("read_body", 6, 1, 1),
+                        ("parse", 7, 0, 1),
+                        ("consume", 7, 0, 1),
+                        ("auth", 7, 0, 2)],
+                   shape(traces[1]));
+        assert_eq!(Outcome::Failed(io::ErrorKind::PermissionDenied), traces[1].outcome);
+    }
+
+    #[test]
+    fn wrappers_add_their_spans() {
+        let clock = SharedClock::new(Duration::from_millis(0));
+        let mut server = Dedup::new(HighWaterMark::new(server(&clock)), 8);
+        let mut recorder = Recorder::new(1, Arc::new(clock.clone()));
+        recorder.start();
+        assert!(server.consume_parts_traced(b"token",
+                                            b"id",
+                                            Duration::from_millis(0),
+                                            b"",
+                                            recorder.spans())
+                      .is_ok());
+        recorder.finish(Outcome::Consumed);
+        assert_eq!(vec![("message", 0, 3, 0),
+                        ("dedup", 0, 0, 1),
+                        ("high_water_mark", 0, 0, 1),
+                        ("auth", 0, 0, 1),
+                        ("route", 0, 0, 1),
+                        ("push", 0, 3, 1)],
+                   shape(recorder.recent()[0]));
+    }
+
+    #[test]
+    fn wrappers_without_spans_pass_them_on() {
+        let clock = SharedClock::new(Duration::from_millis(0));
+        let mut server = CountingServer::new(Dedup::new(server(&clock), 8));
+        let mut recorder = Recorder::new(1, Arc::new(clock.clone()));
+        recorder.start();
+        assert!(server.consume_parts_traced(b"token",
+                                            b"id",
+                                            Duration::from_millis(0),
+                                            b"",
+                                            recorder.spans())
+                      .is_ok());
+        recorder.finish(Outcome::Consumed);
+        assert_eq!(vec![("message", 0, 3, 0),
+                        ("dedup", 0, 0, 1),
+                        ("auth", 0, 0, 1),
+                        ("route", 0, 0, 1),
+                        ("push", 0, 3, 1)],
+                   shape(recorder.recent()[0]));
+        assert_eq!(1, server.calls().len());
+    }
+
+    #[test]
+    fn keeps_the_last_traces_and_spans() {
+        let clock = SharedClock::new(Duration::from_millis(0));
+        let mut recorder = Recorder::new(2, Arc::new(clock.clone()));
+        for i in 0..3 {
+            recorder.start();
+            for _ in 0..i * MAX_SPANS {
+                recorder.spans().begin("nested");
+            }
+            clock.advance(Duration::from_millis(1));
+            recorder.finish(Outcome::Consumed);
+        }
+        recorder.start();
+        recorder.abandon();
+
+        let recent = recorder.recent();
+        assert_eq!(2, recent.len());
+        assert_eq!(MAX_SPANS, recent[0].spans().len());
+        assert_eq!(Some(&Span {
+                       name: "message",
+                       start: Duration::from_millis(1),
+                       duration: Duration::from_millis(1),
+                       depth: 0,
+                   }),
+                   recent[0].span("message"));
+        assert_eq!(MAX_SPANS - 1, recent[1].spans().last().unwrap().depth);
+        assert_eq!(Duration::from_millis(2), recent[1].spans()[0].start);
+    }
+
+    #[test]
+    fn off_records_nothing() {
+        let clock = SharedClock::new(Duration::from_millis(0));
+        let mut server = server(&clock);
+        let bytes = frame(b"token", b"id", 0, b"");
+        let mut session = Session::new(&mut server, &bytes[..]);
+        assert_eq!(1, session.by_ref().count());
+        assert!(session.recent_traces().is_empty());
+        assert_eq!(0, Spans::off().spans.capacity());
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Num(u64),
+        Str(String),
+        Arr(Vec<Json>),
+        Obj(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn get(&self, key: &str) -> Option<&Json> {
+            match *self {
+                Json::Obj(ref fields) => fields.iter().find(|f| f.0 == key).map(|f| &f.1),
+                _ => None,
+            }
+        }
+    }
+
+    /// Just enough JSON for a Chrome trace.
+    fn parse(s: &mut ::std::iter::Peekable<::std::str::Chars>) -> Json {
+        match s.next().unwrap() {
+            '[' => {
+                let mut items = vec![];
+                while s.peek() != Some(&']') {
+                    items.push(parse(s));
+                    if s.peek() == Some(&',') {
+                        s.next();
+                    }
+                }
+                s.next();
+                Json::Arr(items)
+            }
+            '{' => {
+                let mut fields = vec![];
+                while s.peek() != Some(&'}') {
+                    let key = match parse(s) {
+                        Json::Str(key) => key,
+                        other => panic!("key {:?}", other),
+                    };
+                    assert_eq!(Some(':'), s.next());
+                    fields.push((key, parse(s)));
+                    if s.peek() == Some(&',') {
+                        s.next();
+                    }
+                }
+                s.next();
+                Json::Obj(fields)
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match s.next().unwrap() {
+                        '"' => return Json::Str(string),
+                        '\\' => string.push(s.next().unwrap()),
+                        c => string.push(c),
+                    }
+                }
+            }
+            c => {
+                let mut n = c.to_digit(10).unwrap() as u64;
+                while let Some(d) = s.peek().and_then(|c| c.to_digit(10)) {
+                    n = n * 10 + d as u64;
+                    s.next();
+                }
+                Json::Num(n)
+            }
+        }
+    }
+
+    #[test]
+    fn chrome_trace_matches_spans() {
+        let clock = SharedClock::new(Duration::from_millis(0));
+        let mut recorder = Recorder::new(2, Arc::new(clock.clone()));
+        for &(outcome, micros) in &[(Outcome::Consumed, 1500),
+                                    (Outcome::Failed(io::ErrorKind::NotFound), 20)] {
+            recorder.start();
+            recorder.spans().begin("re\"ad");
+            clock.advance(Duration::new(0, micros * 1000));
+            recorder.spans().end();
+            recorder.finish(outcome);
+        }
+        let mut out = vec![];
+        write_chrome_trace(&mut out, &recorder.recent()).unwrap();
+        let json = parse(&mut String::from_utf8(out).unwrap().chars().peekable());
+
+        let events = match json {
+            Json::Arr(events) => events,
+            other => panic!("not an array: {:?}", other),
+        };
+        let spans: Vec<_> = recorder.recent()
+                                    .into_iter()
+                                    .enumerate()
+                                    .flat_map(|(i, trace)| {
+                                        trace.spans().iter().map(move |span| (i + 1, span))
+                                    })
+                                    .collect();
+        assert_eq!(spans.len(), events.len());
+        for (event, &(tid, span)) in events.iter().zip(&spans) {
+            assert_eq!(Some(&Json::Str(span.name.to_owned())), event.get("name"));
+            assert_eq!(Some(&Json::Str("X".to_owned())), event.get("ph"));
+            assert_eq!(Some(&Json::Num(micros(span.start))), event.get("ts"));
+            assert_eq!(Some(&Json::Num(micros(span.duration))), event.get("dur"));
+            assert_eq!(Some(&Json::Num(tid as u64)), event.get("tid"));
+        }
+        assert_eq!(Some(&Json::Num(1500)), events[1].get("dur"));
+        assert_eq!(Some(&Json::Str("failed: NotFound".to_owned())),
+                   events[2].get("args").and_then(|args| args.get("outcome")));
+        assert_eq!(None, events[3].get("args"));
+    }
+}
@@ -1,2 +1,162 @@
-#[derive(Debug, PartialEq, Eq)]
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::prelude::*;
+use std::time::Duration;
+
+/// An error that cannot happen, for servers and streams that never fail.
+///
+/// Being uninhabited, a `Void` can be turned into anything, so handling one
+/// takes no `unreachable!`:
+///
+/// ```
+/// use std::time::Duration;
+/// use sousveillance_server::{Infallible, Stream, Void};
+///
+/// struct Counter(u64);
+///
+/// impl Stream for Counter {
+///     type PushErr = Void;
+///     fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), Void> {
+///         self.0 += 1;
+///         Ok(())
+///     }
+///
+///     type Extract = u64;
+///     type ExtractErr = Void;
+///     fn extract(self) -> Result<u64, (Self, Void)> {
+///         Ok(self.0)
+///     }
+/// }
+///
+/// let mut counter = Counter(0);
+/// counter.push(Duration::from_secs(1), b"x").infallible();
+/// assert_eq!(1, counter.extract().map_err(|(_, e)| e).infallible());
+/// ```
+///
+/// An error that can only hold a `Void` in some variants still needs
+/// matching, but those arms are `absurd`:
+///
+/// ```
+/// use sousveillance_server::{absurd, Server, Void};
+/// use sousveillance_server::server::{AuthError, ConsumeError, TokenServer};
+/// use sousveillance_server::stream::FileStream;
+///
+/// let mut server: TokenServer<FileStream<Vec<u8>>> = TokenServer::new();
+/// let outcome = match server.consume_parts(b"t", b"a", Default::default(), b"x") {
+///     Ok(_) => "stored",
+///     Err(ConsumeError::Auth(AuthError::Other(e))) => absurd(e),
+///     Err(ConsumeError::Auth(AuthError::InvalidToken)) => "unknown token",
+///     Err(_) => "not stored",
+/// };
+/// assert_eq!("unknown token", outcome);
+///
+/// // Errors over `Void` convert to `io::Error` like any other.
+/// let e: Result<(), ConsumeError<Void, Void>> = Err(ConsumeError::MissingId);
+/// assert!(e.map_err(std::io::Error::from).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Void { }
+
+impl Void {
+    /// Being `Copy`, a `Void` behind a reference is `absurd` too.
+    pub fn absurd<T>(self) -> T {
+        match self { }
+    }
+}
+
+/// Turns a `Void` into whatever type is needed, for `map_err` and the like.
+pub fn absurd<T>(v: Void) -> T {
+    v.absurd()
+}
+
+impl Display for Void {
+    fn fmt(&self, _: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self { }
+    }
+}
+
+impl error::Error for Void {
+    fn description(&self) -> &str {
+        match *self { }
+    }
+}
+
+impl From<Void> for io::Error {
+    fn from(v: Void) -> Self {
+        v.absurd()
+    }
+}
+
+/// A result that cannot be an error.
+pub trait Infallible {
+    type Ok;
+    fn infallible(self) -> Self::Ok;
+}
+
+impl<T> Infallible for Result<T, Void> {
+    type Ok = T;
+    fn infallible(self) -> T {
+        match self {
+            Ok(t) => t,
+            Err(v) => v.absurd(),
+        }
+    }
+}
+
+/// The offset basis of 64-bit FNV-1a.
+pub const FNV_BASIS: u64 = 0xcbf29ce484222325;
+
+/// Folds `bytes` into an FNV-1a `hash`. Unlike the standard hasher it is
+/// the same in every build, so what it hashes can be persisted.
+pub fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// A duration in whole milliseconds, as headers and most records keep it.
+pub fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Reads until `buf` is full or the input ends, returning how much was read.
+pub fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error;
+    use std::io;
+
+    use super::*;
+    use server::ConsumeError;
+    use session;
+
+    fn is_error<E: error::Error + Send + Sync + 'static>() {}
+
+    #[test]
+    fn errors_over_void_typecheck() {
+        is_error::<Void>();
+        is_error::<ConsumeError<Void, Void>>();
+        is_error::<session::Error<Void, Void>>();
+        let _: fn(Void) -> io::Error = io::Error::from;
+        let _: fn(session::Error<Void, Void>) -> io::Error = io::Error::from;
+    }
+
+    #[test]
+    fn infallible() {
+        let ok: Result<_, Void> = Ok(3);
+        assert_eq!(3, ok.infallible());
+        let _: fn(Void) -> String = absurd;
+    }
+}
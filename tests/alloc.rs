@@ -0,0 +1,147 @@
+//! Checks that a session turning away malformed frames allocates nothing
+//! once warmed up. Run with `cargo test --features alloc-audit`; the
+//! counting allocator it installs sees the whole test binary, so this file
+//! holds one test only.
+#![cfg(feature = "alloc-audit")]
+
+extern crate sousveillance_server;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use sousveillance_server::message::Strictness;
+use sousveillance_server::server::{AuthError, ConsumeError, TokenServer};
+use sousveillance_server::session::Error;
+use sousveillance_server::{Session, Stream};
+
+struct Counting;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+struct Discard;
+
+impl Stream for Discard {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ();
+    fn extract(self) -> Result<(), (Self, ())> {
+        Ok(())
+    }
+}
+
+/// Hands out one scripted chunk per read, so short reads can be staged.
+struct Scripted {
+    chunks: Vec<Vec<u8>>,
+    next: usize,
+}
+
+impl Read for Scripted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let chunk = match self.chunks.get(self.next) {
+            Some(chunk) => chunk,
+            None => return Ok(0),
+        };
+        self.next += 1;
+        let n = cmp::min(buf.len(), chunk.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+/// One of each malformed frame, and the error it should come to.
+fn malformed() -> Vec<(Vec<Vec<u8>>, &'static str)> {
+    vec![
+        // A token size longer than the frame.
+        (vec![vec![0, 3], vec![0, 5, b'x']], "parse"),
+        // A frame cut short by an end of input that more follows.
+        (vec![vec![0, 20], vec![1, 2, 3], vec![]], "truncated"),
+        // An empty token, which strictness refuses.
+        (vec![vec![0, 13], vec![0, 0, 0, 1, b'i', 0, 0, 0, 0, 0, 0, 0, 1]], "nonconforming"),
+        // A token the server does not know.
+        (vec![vec![0, 14], vec![0, 1, b'x', 0, 1, b'i', 0, 0, 0, 0, 0, 0, 0, 1]], "auth"),
+    ]
+}
+
+#[test]
+fn malformed_frames_allocate_nothing() {
+    const FRAMES: usize = 10000;
+    let kinds = malformed();
+    let mut chunks = vec![];
+    let mut expected = vec![];
+    for i in 0..FRAMES {
+        let (ref frame, kind) = kinds[i % kinds.len()];
+        chunks.extend(frame.iter().cloned());
+        expected.push(kind);
+    }
+    // Half a size prefix, which is only an error at the end of the input.
+    chunks.push(vec![0]);
+    expected.push("one byte");
+
+    let mut server = TokenServer::<Discard>::new();
+    server.add_token(b"t");
+    let mut session = Session::new(&mut server,
+                                   Scripted {
+                                       chunks: chunks,
+                                       next: 0,
+                                   });
+    session.set_strictness(Strictness::Strict);
+
+    let mut kinds = Vec::with_capacity(expected.len());
+    // The first frames size the session's buffer.
+    for _ in 0..malformed().len() {
+        kinds.push(session.next());
+    }
+    COUNTING.store(true, Ordering::SeqCst);
+    for _ in kinds.len()..expected.len() {
+        kinds.push(session.next());
+    }
+    COUNTING.store(false, Ordering::SeqCst);
+    assert_eq!(0, ALLOCATIONS.load(Ordering::SeqCst));
+
+    assert!(session.next().is_none());
+    for (item, &kind) in kinds.iter().zip(&expected) {
+        let matched = match (kind, item) {
+            ("parse", &Some(Err(Error::Parse { ref error, .. }))) => error.diagnostic.is_none(),
+            ("one byte", &Some(Err(Error::EofInMessageSize))) => true,
+            ("truncated", &Some(Err(Error::Truncated { found: 3, remaining: 17 }))) => true,
+            ("nonconforming", &Some(Err(Error::Nonconforming(_)))) => true,
+            ("auth", &Some(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken))))) => {
+                true
+            }
+            _ => false,
+        };
+        assert!(matched, "expected {}; got {:?}", kind, item);
+    }
+}
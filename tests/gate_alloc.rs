@@ -0,0 +1,100 @@
+//! Checks that a session behind a token gate never allocates room for a
+//! frame whose token it refuses. Run with `cargo test --features
+//! alloc-audit`; like tests/alloc.rs, this file holds one test only, as its
+//! allocator sees the whole test binary.
+#![cfg(feature = "alloc-audit")]
+
+extern crate sousveillance_server;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use sousveillance_server::server::{AuthError, ConsumeError, TokenServer};
+use sousveillance_server::session::Error;
+use sousveillance_server::test_support::frame;
+use sousveillance_server::{Session, Stream};
+
+struct Largest;
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+static LARGEST: AtomicUsize = AtomicUsize::new(0);
+
+fn watch(size: usize) {
+    if WATCHING.load(Ordering::SeqCst) && size > LARGEST.load(Ordering::SeqCst) {
+        LARGEST.store(size, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for Largest {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        watch(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        watch(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Largest = Largest;
+
+struct Discard;
+
+impl Stream for Discard {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ();
+    fn extract(self) -> Result<(), (Self, ())> {
+        Ok(())
+    }
+}
+
+#[test]
+fn refused_frames_allocate_no_frame() {
+    const FRAMES: usize = 100;
+    const BODY: usize = 60000;
+    let mut bytes = vec![];
+    for i in 0..FRAMES {
+        // Unknown tokens, and tokens too long to be looked up at all.
+        let token: &[u8] = if i % 2 == 0 { b"x" } else { &[b'y'; 1000] };
+        bytes.extend(frame(token, b"i", 1, &[0; BODY]));
+    }
+    bytes.extend(frame(b"t", b"i", 1, &[0; 10]));
+
+    let mut server = TokenServer::<Discard>::new();
+    server.add_token(b"t").insert(b"i".to_vec(), Discard);
+    let mut session = Session::new(&mut server, Cursor::new(bytes));
+    session.set_token_gate(64);
+
+    let mut items = Vec::with_capacity(FRAMES + 1);
+    WATCHING.store(true, Ordering::SeqCst);
+    for _ in 0..FRAMES {
+        items.push(session.next());
+    }
+    WATCHING.store(false, Ordering::SeqCst);
+    assert!(LARGEST.load(Ordering::SeqCst) < 1024,
+            "allocated {} bytes",
+            LARGEST.load(Ordering::SeqCst));
+
+    for item in items {
+        match item {
+            Some(Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)))) => {}
+            _ => panic!("expected the token to be refused; got {:?}", item),
+        }
+    }
+    assert!(session.next().unwrap().is_ok());
+    assert!(session.next().is_none());
+    assert_eq!(FRAMES as u64, session.refused_at_gate());
+}
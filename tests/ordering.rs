@@ -0,0 +1,84 @@
+//! Holds the pool to the order devices depend on: each ID's frames reach
+//! its stream in the order its connection sent them, however the workers
+//! share themselves between connections.
+
+extern crate byteorder;
+extern crate sousveillance_server;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use sousveillance_server::pool::{AffinityPool, Budget, Fairness};
+use sousveillance_server::server::{Contract, OrderingChecker, OrderingViolation, Sequencing,
+                                   TokenServer};
+use sousveillance_server::stream::sequence::be_prefix;
+use sousveillance_server::test_support::frame;
+use sousveillance_server::Stream;
+
+struct Discard;
+
+impl Stream for Discard {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ();
+    fn extract(self) -> Result<(), (Self, ())> {
+        Ok(())
+    }
+}
+
+#[test]
+fn pool_keeps_each_ids_frames_in_order() {
+    let violations = Arc::new(Mutex::new(vec![]));
+    let servers = (0..3)
+                      .map(|_| {
+                          let mut server = TokenServer::new_send();
+                          server.add_token(b"t");
+                          server.set_factory(b"t", |_| Discard);
+                          let mut checker = OrderingChecker::new(server,
+                                                                 &[Contract::PerIdFifo],
+                                                                 Sequencing::Extracted(be_prefix));
+                          let violations = violations.clone();
+                          checker.set_on_violation(move |v: &OrderingViolation| {
+                              violations.lock().unwrap().push(v.clone())
+                          });
+                          checker
+                      })
+                      .collect();
+    let pool = AffinityPool::with_fairness(servers, 4, Fairness::new(Budget::Messages(2)));
+
+    // Each connection sends for IDs of its own, interleaved.
+    let senders: Vec<_> = (0..4_u8)
+                              .map(|c| {
+                                  let mut connection = pool.connection();
+                                  let mut capture = vec![];
+                                  for seq in 0..200_u32 {
+                                      let id = [b'a' + c, b'0' + (seq % 5) as u8];
+                                      // Numbered in its payload, per ID.
+                                      let mut numbered = [0; 4];
+                                      BigEndian::write_u32(&mut numbered, seq / 5);
+                                      capture.extend(frame(b"t", &id, 1, &numbered));
+                                  }
+                                  thread::spawn(move || connection.read_from(&capture[..]).unwrap())
+                              })
+                              .collect();
+    for sender in senders {
+        assert_eq!(200, sender.join().unwrap().dispatched);
+    }
+
+    let results = pool.join();
+    let checked: u64 = results.iter().map(|&(ref checker, _)| checker.counts().checked).sum();
+    assert_eq!(800, checked);
+    for &(ref checker, ref stats) in &results {
+        assert_eq!(0, checker.counts().violations(Contract::PerIdFifo));
+        assert_eq!(0, checker.counts().unsequenced);
+        assert_eq!(0, stats.out_of_order);
+    }
+    assert_eq!(Vec::<OrderingViolation>::new(), *violations.lock().unwrap());
+}
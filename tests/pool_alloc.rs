@@ -0,0 +1,115 @@
+//! Checks that routing frames through an `AffinityPool` allocates nothing
+//! for the IDs of frames short enough to be held inline, nor for longer IDs
+//! a connection interns, where otherwise each of their frames costs an
+//! allocation. Like tests/alloc.rs, this file holds one test only, as its
+//! allocator sees the whole test binary.
+#![cfg(feature = "alloc-audit")]
+
+extern crate sousveillance_server;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use sousveillance_server::id::INLINE;
+use sousveillance_server::pool::AffinityPool;
+use sousveillance_server::server::TokenServer;
+use sousveillance_server::session::IdInterner;
+use sousveillance_server::test_support::message;
+use sousveillance_server::Stream;
+
+struct Counting;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+struct Discard;
+
+impl Stream for Discard {
+    type PushErr = ();
+    fn push(&mut self, _: Duration, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    type Extract = ();
+    type ExtractErr = ();
+    fn extract(self) -> Result<(), (Self, ())> {
+        Ok(())
+    }
+}
+
+/// Routes `frames` frames over IDs `id_len` long through a two-worker pool,
+/// interning them if `intern` is set, returning the allocations made while
+/// doing so.
+fn allocations(id_len: usize, frames: usize, intern: bool) -> usize {
+    let ids: Vec<Vec<u8>> = (0..4_u8).map(|i| vec![b'a' + i; id_len]).collect();
+    let servers = (0..2)
+                      .map(|_| {
+                          let mut server = TokenServer::new_send();
+                          server.add_token(b"t");
+                          server.set_factory(b"t", |_| Discard);
+                          server
+                      })
+                      .collect();
+    let pool = AffinityPool::new(servers, 64);
+    let mut connection = pool.connection();
+    if intern {
+        connection.set_interner(Some(IdInterner::new(16)));
+    }
+    // Each ID's stream and queue entries are made by its first frame.
+    for id in &ids {
+        connection.dispatch(message(b"t", id, 1, b"x")).unwrap();
+    }
+    let messages: Vec<_> = (0..frames)
+                               .map(|i| message(b"t", &ids[i % ids.len()], 1, b"x"))
+                               .collect();
+
+    ALLOCATIONS.store(0, Ordering::SeqCst);
+    COUNTING.store(true, Ordering::SeqCst);
+    for message in messages {
+        connection.dispatch(message).unwrap();
+    }
+    drop(connection);
+    let results = pool.join();
+    COUNTING.store(false, Ordering::SeqCst);
+
+    let consumed: u64 = results.iter().map(|&(_, ref stats)| stats.consumed).sum();
+    assert_eq!((frames + ids.len()) as u64, consumed);
+    ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+#[test]
+fn inline_ids_route_without_allocating() {
+    const FRAMES: usize = 20000;
+    let inline = allocations(INLINE, FRAMES, false);
+    let interned = allocations(INLINE + 1, FRAMES, true);
+    let spilled = allocations(INLINE + 1, FRAMES, false);
+    // What is left is the queues' doing, as a thread waiting on one sets
+    // itself up to be woken, whatever the IDs.
+    assert!(inline < FRAMES / 4, "{} allocations", inline);
+    assert!(interned < FRAMES / 4, "{} allocations", interned);
+    assert!(spilled >= FRAMES, "{} allocations", spilled);
+}
@@ -0,0 +1,94 @@
+//! Orderings that features used to check each in their own way, now all
+//! refused by `session::Protocol` with the one error.
+
+extern crate sousveillance_server;
+
+use std::io::Cursor;
+use std::time::Duration;
+
+use sousveillance_server::clock::ManualClock;
+use sousveillance_server::server::admin::CONTROL_ID;
+use sousveillance_server::server::TokenServer;
+use sousveillance_server::session::protocol::{Input, State};
+use sousveillance_server::session::{Drain, DrainOutcome, Error, PeerInfo, PreamblePolicy,
+                                    ShutdownToken};
+use sousveillance_server::stream::FileStream;
+use sousveillance_server::test_support::frame;
+use sousveillance_server::Session;
+
+fn token_server() -> TokenServer<FileStream<Vec<u8>>> {
+    let mut server = TokenServer::new();
+    server.add_token(b"t").insert(b"cam".to_vec(), FileStream::new(vec![]));
+    server
+}
+
+fn violation<T, A, P>(item: Option<Result<T, Error<A, P>>>) -> Option<(State, Input)> {
+    match item {
+        Some(Err(Error::ProtocolViolation { state, received })) => Some((state, received)),
+        _ => None,
+    }
+}
+
+#[test]
+fn data_before_preamble() {
+    let mut server = token_server();
+    let mut session = Session::new(&mut server, Cursor::new(frame(b"t", b"cam", 1, b"x")));
+    session.set_preamble_policy(PreamblePolicy::Required);
+    assert_eq!(Some((State::Initial, Input::Data)), violation(session.next()));
+    assert!(session.next().is_none());
+
+    let peer = PeerInfo {
+        version: 1,
+        name: b"client".to_vec(),
+        strictness: None,
+    };
+    let mut bytes = peer.to_bytes();
+    bytes.extend(frame(b"t", b"cam", 1, b"x"));
+    let mut server = token_server();
+    let mut session = Session::new(&mut server, Cursor::new(bytes));
+    session.set_preamble_policy(PreamblePolicy::Required);
+    assert!(session.next().unwrap().is_ok());
+    assert_eq!(State::Authenticated, session.protocol_state());
+}
+
+#[test]
+fn control_before_auth() {
+    let bytes: Vec<_> = frame(b"t", CONTROL_ID, 1, &[1])
+                            .into_iter()
+                            .chain(frame(b"t", b"cam", 1, b"x"))
+                            .chain(frame(b"t", CONTROL_ID, 1, &[1]))
+                            .collect();
+    let mut server = token_server();
+    let mut session = Session::new(&mut server, Cursor::new(bytes));
+    session.set_control_id(CONTROL_ID);
+    assert_eq!(Some((State::Initial, Input::Control)), violation(session.next()));
+    assert!(session.next().unwrap().is_ok());
+    // Once authenticated, a control frame is dispatched like any other,
+    // and this server has no stream for it.
+    assert_eq!(None, violation(session.next()));
+}
+
+#[test]
+fn frame_after_drain() {
+    let bytes: Vec<_> = frame(b"t", b"cam", 1, b"x")
+                            .into_iter()
+                            .chain(frame(b"t", b"cam", 1, b"y"))
+                            .collect();
+    let mut server = token_server();
+    let token = ShutdownToken::new();
+    let clock = ManualClock::new(Duration::from_secs(0));
+    let session = Session::new(&mut server, Cursor::new(bytes));
+    let mut draining = session.draining(token.clone(), &clock);
+    match draining.next() {
+        Some(Drain::Item(Ok(_))) => {}
+        _ => panic!("expected the first frame"),
+    }
+    token.signal();
+    match draining.next() {
+        Some(Drain::Drained(DrainOutcome::Idle)) => {}
+        _ => panic!("expected to drain"),
+    }
+    let mut session = draining.into_inner();
+    assert_eq!(State::Draining, session.protocol_state());
+    assert_eq!(Some((State::Draining, Input::Data)), violation(session.next()));
+}
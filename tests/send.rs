@@ -0,0 +1,135 @@
+//! Every adapter and wrapper is `Send` when what it wraps is, so that a
+//! session configured on one thread can run on another. These only need to
+//! compile: each function is generic, so a wrapper that stops being `Send`
+//! for some `Send` parameters fails the build here rather than at a caller.
+//! Components that keep callbacks are checked over `Sendable`, as the
+//! default `Local` ones take callbacks that need not be `Send`.
+
+extern crate sousveillance_server;
+
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use sousveillance_server::callback::Sendable;
+use sousveillance_server::client::ReliableReporter;
+use sousveillance_server::clock::{ManualClock, SharedClock, SystemClock};
+use sousveillance_server::message::scan::{Discarding, Seeking};
+use sousveillance_server::pool::{AffinityPool, Connection};
+use sousveillance_server::server::{Accounting, AuthAudit, Dedup, HighWaterMark,
+                                   OrderingChecker, Reaper, Shadowed, TokenServer};
+use sousveillance_server::session::deferred::Parking;
+use sousveillance_server::session::{Backoff, DrainingSession, LabeledSession, Protocol,
+                                    ReadAhead, ShutdownToken, Throttle, Throttling,
+                                    TimedSession};
+#[cfg(feature = "gzip")]
+use sousveillance_server::session::CompressedReplay;
+use sousveillance_server::simple::Handle;
+use sousveillance_server::stream::{ContentStore, Encrypting, FileBacking, FileStream,
+                                   FileStreamFactory, Guarded, MemoryBacking, PayloadCipher,
+                                   Reassembler, Referencing, SequenceTracker, SubRecordSplit,
+                                   TextLog};
+use sousveillance_server::sweep::{Sweeper, Tracked};
+use sousveillance_server::{Clock, Server, Session, Stream};
+
+fn assert_send<T: Send>() {}
+
+fn servers<S: Server + Send, T: Stream + Send, C: Clock + Send>() {
+    assert_send::<TokenServer<T, Sendable>>();
+    assert_send::<Accounting<C, S, Sendable>>();
+    assert_send::<AuthAudit<C, S>>();
+    assert_send::<Dedup<S>>();
+    assert_send::<OrderingChecker<S>>();
+    assert_send::<HighWaterMark<S>>();
+    assert_send::<Reaper<S>>();
+    assert_send::<Shadowed<C, S, S>>();
+}
+
+/// A pool moves streams between its workers as it is resized.
+fn pools<S>()
+    where S: Server + Send,
+          S::Stream: Send
+{
+    assert_send::<AffinityPool<S>>();
+}
+
+fn streams<T: Stream + Send, W: Write + Send, C: Clock + Send, P: PayloadCipher + Send>() {
+    assert_send::<FileStream<W>>();
+    assert_send::<TextLog<W>>();
+    assert_send::<Encrypting<T, P>>();
+    assert_send::<Guarded<T>>();
+    assert_send::<Reassembler<T, C, Sendable>>();
+    assert_send::<SequenceTracker<T, Sendable>>();
+    assert_send::<SubRecordSplit<T>>();
+    assert_send::<Referencing<T, MemoryBacking>>();
+    assert_send::<Referencing<T, FileBacking<File>>>();
+    assert_send::<ContentStore<MemoryBacking>>();
+    assert_send::<FileStreamFactory>();
+}
+
+fn readers<R: Read + Send, C: Clock + Send>() {
+    assert_send::<ReadAhead<R>>();
+    assert_send::<Seeking<R>>();
+    assert_send::<Discarding<R>>();
+    assert_send::<Tracked<R, C>>();
+    #[cfg(feature = "gzip")]
+    assert_send::<CompressedReplay<R>>();
+}
+
+fn sessions<S: Server + Send + 'static, R: Read + Send, C: Clock + Send>() {
+    assert_send::<Session<'static, S, R>>();
+    assert_send::<DrainingSession<'static, C, S, R>>();
+    assert_send::<TimedSession<'static, C, S, R>>();
+    assert_send::<LabeledSession<'static, u64, S, R>>();
+    assert_send::<Throttle>();
+    assert_send::<Throttling>();
+    assert_send::<Backoff>();
+    assert_send::<Parking>();
+    assert_send::<Protocol>();
+    assert_send::<ShutdownToken>();
+}
+
+fn clients<W: Write + Send, R: Read + Send, C: Clock + Send>() {
+    assert_send::<ReliableReporter<W, R, C, Sendable>>();
+    assert_send::<Connection>();
+    assert_send::<Sweeper>();
+    assert_send::<Handle>();
+}
+
+#[test]
+fn with_send_parameters() {
+    type Files = TokenServer<FileStream<File>, Sendable>;
+    servers::<Files, FileStream<File>, SystemClock>();
+    servers::<Files, FileStream<Vec<u8>>, ManualClock>();
+    pools::<Files>();
+    readers::<TcpStream, SharedClock>();
+    readers::<Cursor<Vec<u8>>, ManualClock>();
+    sessions::<Files, TcpStream, SystemClock>();
+    sessions::<Reaper<HighWaterMark<Files>>, ReadAhead<TcpStream>, SharedClock>();
+    clients::<TcpStream, TcpStream, SystemClock>();
+}
+
+#[test]
+fn streams_with_send_parameters() {
+    struct Identity;
+
+    impl PayloadCipher for Identity {
+        type Err = ();
+        fn id(&self) -> u16 {
+            0
+        }
+
+        fn seal(&mut self, _: Duration, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+            out.extend_from_slice(plaintext);
+            Ok(())
+        }
+
+        fn open(&mut self, _: Duration, sealed: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+            out.extend_from_slice(sealed);
+            Ok(())
+        }
+    }
+
+    streams::<FileStream<File>, File, SystemClock, Identity>();
+}
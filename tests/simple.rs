@@ -0,0 +1,172 @@
+//! Runs `simple::run` end to end on an ephemeral port.
+
+extern crate sousveillance_server;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use sousveillance_server::client::ReliableReporter;
+use sousveillance_server::clock::ManualClock;
+use sousveillance_server::metrics::MetricsRegistry;
+use sousveillance_server::server::TokenPolicy;
+use sousveillance_server::simple;
+use sousveillance_server::simple::{ServerStats, ShutdownReport, SimpleConfig};
+use sousveillance_server::stream::{Index, RecordReader};
+
+fn scratch(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("sousveillance-simple-{}", name));
+    let _ = fs::remove_dir_all(&root);
+    root
+}
+
+fn config(data_dir: &Path, errors: &Arc<Mutex<Vec<io::ErrorKind>>>) -> SimpleConfig {
+    let mut tokens = HashMap::new();
+    tokens.insert(b"alice".to_vec(), vec![b"cam-".to_vec()]);
+    tokens.insert(b"bob".to_vec(), vec![vec![]]);
+    let errors = errors.clone();
+    SimpleConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        tokens: tokens,
+        data_dir: data_dir.to_path_buf(),
+        max_frame_size: Some(64),
+        on_error: Box::new(move |e| errors.lock().unwrap().push(e.kind())),
+    }
+}
+
+/// The timestamps and payloads of a data file's records.
+fn records(root: &Path, id: &[u8]) -> Vec<(u64, Vec<u8>)> {
+    let file = File::open(simple::DEFAULT_LAYOUT.path(root, id)).unwrap();
+    RecordReader::new(file, Index::default())
+        .range(Duration::from_secs(0), Duration::from_secs(u64::max_value()))
+        .map(|record| {
+            let (timestamp, payload) = record.unwrap();
+            (millis(timestamp), payload)
+        })
+        .collect()
+}
+
+/// A duration in whole milliseconds, as the crate's private `util::millis`
+/// gives it.
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+fn reporter(addr: SocketAddr, token: &[u8]) -> ReliableReporter<TcpStream, io::Empty, ManualClock> {
+    let clock = ManualClock::new(Duration::from_secs(0));
+    ReliableReporter::new(token, TcpStream::connect(addr).unwrap(), io::empty(), clock, 1 << 20)
+}
+
+#[test]
+fn stores_exactly_what_was_sent() {
+    let root = scratch("stores");
+    let errors = Arc::new(Mutex::new(vec![]));
+    let handle = simple::run(config(&root, &errors)).unwrap();
+    let addr = handle.local_addr();
+
+    let alice = thread::spawn(move || {
+        let mut alice = reporter(addr, b"alice");
+        for &(id, millis, payload) in &[(&b"cam-1"[..], 1, &b"a1"[..]),
+                                        (b"cam-2", 2, b"a2"),
+                                        (b"door", 3, b"refused"),
+                                        (b"cam-1", 4, b"a3")] {
+            alice.report(id, Duration::from_millis(millis), payload).unwrap();
+        }
+    });
+    let bob = thread::spawn(move || {
+        let mut bob = reporter(addr, b"bob");
+        for millis in 0..20 {
+            bob.report(b"door", Duration::from_millis(millis), format!("b{}", millis).as_bytes())
+               .unwrap();
+        }
+    });
+    alice.join().unwrap();
+    bob.join().unwrap();
+
+    // Shut down from another thread than the one that started it.
+    let report = thread::spawn(move || handle.shutdown(Duration::from_secs(10))).join().unwrap();
+    assert_eq!(ShutdownReport {
+                   stats: ServerStats {
+                       accepted: 2,
+                       active: 0,
+                       stored: 23,
+                       failed: 1,
+                       malformed: 0,
+                       oversized: 0,
+                   },
+                   forced: 0,
+               },
+               report);
+    assert_eq!(vec![io::ErrorKind::InvalidInput], *errors.lock().unwrap());
+    assert_eq!(vec![(1, b"a1".to_vec()), (4, b"a3".to_vec())], records(&root, b"cam-1"));
+    assert_eq!(vec![(2, b"a2".to_vec())], records(&root, b"cam-2"));
+    let door: Vec<_> = (0..20)
+                           .map(|millis| (millis, format!("b{}", millis).into_bytes()))
+                           .collect();
+    assert_eq!(door, records(&root, b"door"));
+}
+
+#[test]
+fn closes_oversized_and_lingering_connections() {
+    let root = scratch("closes");
+    let errors = Arc::new(Mutex::new(vec![]));
+    let handle = simple::run(config(&root, &errors)).unwrap();
+    let addr = handle.local_addr();
+
+    reporter(addr, b"bob").report(b"big", Duration::from_millis(1), &[0; 100]).unwrap();
+    let mut lingering = TcpStream::connect(addr).unwrap();
+    let settled = ServerStats {
+        accepted: 2,
+        active: 1,
+        oversized: 1,
+        ..ServerStats::default()
+    };
+    while handle.stats() != settled {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut registry = MetricsRegistry::new();
+    handle.register_metrics(&mut registry, "simple");
+    let scraped = registry.render();
+    assert!(scraped.contains("\nsimple_active_connections 1\n"), "{}", scraped);
+    assert!(scraped.contains("\nsimple_oversized_total 1\n"), "{}", scraped);
+
+    let report = handle.shutdown(Duration::from_millis(10));
+    assert_eq!(1, report.forced);
+    assert_eq!(0, report.stats.active);
+    assert_eq!(0, lingering.read(&mut [0; 1]).unwrap());
+    assert_eq!(vec![io::ErrorKind::InvalidData], *errors.lock().unwrap());
+    assert!(!simple::DEFAULT_LAYOUT.path(&root, b"big").exists());
+}
+
+#[test]
+fn takes_up_what_the_admin_publishes() {
+    let root = scratch("admin");
+    let errors = Arc::new(Mutex::new(vec![]));
+    let handle = simple::run(config(&root, &errors)).unwrap();
+    let addr = handle.local_addr();
+
+    let gate = Some(vec![b"gate".to_vec()].into_iter().collect());
+    handle.admin()
+          .add_token(b"carol", TokenPolicy { ids: gate, ..TokenPolicy::default() })
+          .unwrap();
+    handle.admin().remove_token(b"alice").unwrap();
+    let mut carol = reporter(addr, b"carol");
+    carol.report(b"gate", Duration::from_millis(1), b"c1").unwrap();
+    carol.report(b"door", Duration::from_millis(2), b"refused").unwrap();
+    reporter(addr, b"alice").report(b"cam-1", Duration::from_millis(3), b"refused").unwrap();
+    drop(carol);
+
+    let report = handle.shutdown(Duration::from_secs(10));
+    assert_eq!(1, report.stats.stored);
+    assert_eq!(2, report.stats.failed);
+    assert_eq!(vec![(1, b"c1".to_vec())], records(&root, b"gate"));
+    assert!(!simple::DEFAULT_LAYOUT.path(&root, b"cam-1").exists());
+}
@@ -0,0 +1,290 @@
+//! Drives a composed server stack with hours of seeded, fault-injected
+//! virtual traffic and checks that every message is accounted for. Run with
+//! `cargo test --features soak`; set `SOAK_SEEDS` to a comma-separated list
+//! to replay particular seeds.
+#![cfg(feature = "soak")]
+
+extern crate sousveillance_server;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::rc::Rc;
+use std::time::Duration;
+
+use sousveillance_server::server::{AuthError, CapPolicy, ConsumeError, Reaper, TokenServer};
+use sousveillance_server::session::Error;
+use sousveillance_server::stream::{Decrypting, Encrypting, PayloadCipher};
+use sousveillance_server::test_support::frame;
+use sousveillance_server::{Session, Stream};
+
+/// xorshift64*, so that a seed replays the same run on any platform.
+#[derive(Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// True `per_mille` times in a thousand.
+    fn chance(&mut self, per_mille: u64) -> bool {
+        self.below(1000) < per_mille
+    }
+}
+
+struct Xor(u8);
+
+impl PayloadCipher for Xor {
+    type Err = ();
+    fn id(&self) -> u16 {
+        7
+    }
+
+    fn seal(&mut self, _: Duration, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+        out.extend(plaintext.iter().map(|b| b ^ self.0));
+        Ok(())
+    }
+
+    fn open(&mut self, _: Duration, sealed: &[u8], out: &mut Vec<u8>) -> Result<(), ()> {
+        out.extend(sealed.iter().map(|b| b ^ self.0));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Fault {
+    Transient,
+    Permanent,
+}
+
+type Records = Vec<(Duration, Vec<u8>)>;
+
+/// A sink that fails pushes and extractions as its shared generator says,
+/// and once permanently failed stays failed.
+struct Faulty {
+    faults: Rc<RefCell<Rng>>,
+    broken: bool,
+    stored: Records,
+}
+
+impl Stream for Faulty {
+    type PushErr = Fault;
+    fn push(&mut self, ts: Duration, payload: &[u8]) -> Result<(), Self::PushErr> {
+        if self.broken {
+            return Err(Fault::Permanent);
+        }
+        let mut faults = self.faults.borrow_mut();
+        if faults.chance(2) {
+            self.broken = true;
+            return Err(Fault::Permanent);
+        }
+        if faults.chance(20) {
+            return Err(Fault::Transient);
+        }
+        self.stored.push((ts, payload.to_vec()));
+        Ok(())
+    }
+
+    type Extract = Records;
+    type ExtractErr = ();
+    fn extract(self) -> Result<Self::Extract, (Self, Self::ExtractErr)> {
+        if self.faults.borrow_mut().chance(50) {
+            Err((self, ()))
+        } else {
+            Ok(self.stored)
+        }
+    }
+}
+
+type Stack = Reaper<TokenServer<Encrypting<Faulty, Xor>>>;
+
+const TOKENS: [&'static [u8]; 2] = [b"alpha", b"beta"];
+const KEY: u8 = 0x5c;
+
+fn stack(faults: &Rc<RefCell<Rng>>, archive: &Rc<RefCell<Vec<Records>>>) -> Stack {
+    let mut server = TokenServer::new();
+    for (i, token) in TOKENS.iter().enumerate() {
+        server.add_token(token);
+        let faults = faults.clone();
+        server.set_factory(token, move |_: &[u8]| {
+            Encrypting::new(Faulty {
+                                faults: faults.clone(),
+                                broken: false,
+                                stored: vec![],
+                            },
+                            Xor(KEY))
+        });
+        let policy = if i == 0 { CapPolicy::EvictIdle } else { CapPolicy::Reject };
+        server.set_stream_cap(token, 12, policy);
+    }
+    let archive = archive.clone();
+    server.set_eviction_callback(move |_: &[u8], _: &[u8], result| {
+        if let Ok(records) = result {
+            archive.borrow_mut().push(records);
+        }
+    });
+    Reaper::new(server)
+}
+
+/// What a frame should come to, as far as the generator knows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Expect {
+    Stored,
+    InvalidToken,
+    Truncated,
+}
+
+#[derive(Default)]
+struct Tally {
+    frames: u64,
+    acked: BTreeMap<u64, (u64, Duration)>,
+    rejected: u64,
+    errored: u64,
+    truncated: u64,
+}
+
+macro_rules! check {
+    ($seed:expr, $cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            panic!("soak invariant violated with seed {}: {}", $seed, format!($($arg)+));
+        }
+    };
+}
+
+fn soak(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let faults = Rc::new(RefCell::new(Rng::new(!seed)));
+    let archive = Rc::new(RefCell::new(vec![]));
+    let mut server = stack(&faults, &archive);
+    let mut tally = Tally::default();
+    let mut now = 1_000_000_u64;
+    let mut seq = 0_u64;
+
+    for _ in 0..2000 {
+        let mut bytes = vec![];
+        let mut expected = vec![];
+        let mut last: Option<(Vec<u8>, Expect, u64, u64)> = None;
+        for _ in 0..1 + rng.below(40) {
+            if let (true, Some((frame, expect, seq, ts))) = (rng.chance(50), last.clone()) {
+                bytes.extend_from_slice(&frame);
+                expected.push((expect, seq, ts));
+                continue;
+            }
+            now += rng.below(200);
+            let ts = if rng.chance(50) { now - rng.below(60_000) } else { now };
+            let (token, expect): (&[u8], _) = if rng.chance(50) {
+                (b"gamma", Expect::InvalidToken)
+            } else {
+                (TOKENS[rng.below(2) as usize], Expect::Stored)
+            };
+            let id = format!("camera-{}", rng.below(30));
+            let mut payload: Vec<u8> = (0..8).rev().map(|i| (seq >> (8 * i)) as u8).collect();
+            payload.extend((0..rng.below(64)).map(|_| rng.next() as u8));
+            let frame = frame(token, id.as_bytes(), ts, &payload);
+            bytes.extend_from_slice(&frame);
+            expected.push((expect, seq, ts));
+            last = Some((frame, expect, seq, ts));
+            seq += 1;
+        }
+        if rng.chance(30) {
+            let mut oversize = frame(TOKENS[0], b"camera-0", now, &[0; 100]);
+            oversize.truncate(50);
+            bytes.extend(oversize);
+            expected.push((Expect::Truncated, seq, now));
+            seq += 1;
+        }
+
+        let results: Vec<_> = Session::new(&mut server, &bytes[..]).collect();
+        check!(seed,
+               results.len() == expected.len(),
+               "{} results for {} frames",
+               results.len(),
+               expected.len());
+        for (result, &(expect, seq, ts)) in results.into_iter().zip(&expected) {
+            tally.frames += 1;
+            match (expect, result) {
+                (Expect::Stored, Ok(_)) => {
+                    let acked = tally.acked.entry(seq).or_insert((0, Duration::from_millis(ts)));
+                    acked.0 += 1;
+                }
+                (Expect::Stored, Err(Error::Consume(ConsumeError::StreamCapExceeded { .. }))) => {
+                    tally.rejected += 1
+                }
+                (Expect::Stored, Err(Error::Consume(ConsumeError::Push(_)))) => tally.errored += 1,
+                (Expect::InvalidToken,
+                 Err(Error::Consume(ConsumeError::Auth(AuthError::InvalidToken)))) => {
+                    tally.rejected += 1
+                }
+                (Expect::Truncated, Err(Error::Truncated { .. })) => tally.truncated += 1,
+                (expect, result) => {
+                    check!(seed, false, "frame {} expected {:?}; got {:?}", seq, expect, result)
+                }
+            }
+        }
+
+        now += if rng.chance(20) { 3_600_000 + rng.below(7_200_000) } else { rng.below(5_000) };
+        if rng.chance(100) {
+            let report = server.reap(Duration::from_millis(1_800_000), Duration::from_millis(now));
+            archive.borrow_mut().extend(report.reaped.into_iter().map(|(_, _, records)| records));
+        }
+    }
+
+    let far = Duration::from_millis(now + 86_400_000);
+    for attempt in 0.. {
+        let report = server.reap(Duration::from_millis(0), far);
+        archive.borrow_mut().extend(report.reaped.into_iter().map(|(_, _, records)| records));
+        if report.failed.is_empty() {
+            break;
+        }
+        check!(seed, attempt < 1000, "streams still failing to extract");
+    }
+
+    check!(seed,
+           tally.frames ==
+           tally.acked.values().map(|&(n, _)| n).sum::<u64>() + tally.rejected + tally.errored +
+           tally.truncated,
+           "counters do not reconcile");
+    check!(seed, now - 1_000_000 > 3_600_000, "less than an hour of traffic");
+
+    let mut stored = BTreeMap::new();
+    let mut decrypting = Decrypting(Xor(KEY));
+    for records in archive.borrow().iter() {
+        for &(ts, ref record) in records {
+            let mut opened = vec![];
+            check!(seed, decrypting.open(ts, record, &mut opened).is_ok(), "unopenable record");
+            let seq = opened[..8].iter().fold(0, |seq, &b| seq << 8 | b as u64);
+            let entry = stored.entry(seq).or_insert((0, ts));
+            entry.0 += 1;
+            check!(seed, entry.1 == ts, "frame {} stored with two timestamps", seq);
+        }
+    }
+    for (seq, acked) in &tally.acked {
+        check!(seed, stored.get(seq) == Some(acked), "frame {} acked but not stored", seq);
+    }
+    for seq in stored.keys() {
+        check!(seed, tally.acked.contains_key(seq), "frame {} stored but never acked", seq);
+    }
+}
+
+#[test]
+fn soak_seeds() {
+    let seeds: Vec<u64> = match env::var("SOAK_SEEDS") {
+        Ok(seeds) => seeds.split(',').map(|s| s.trim().parse().unwrap()).collect(),
+        Err(_) => (1..9).collect(),
+    };
+    for seed in seeds {
+        soak(seed);
+    }
+}